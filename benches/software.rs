@@ -0,0 +1,209 @@
+//! ソフトウェアで行う前処理と後処理のベンチマーク
+//!
+//! YOLOのIPを使わず、乱数で作った合成データで計測するため、ボードがなくても実行できます。
+//!
+//! ```sh
+//! cargo bench --bench software
+//! ```
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use image::{DynamicImage, RgbImage};
+
+use yolo_v3_tiny_zynq::detection_result::{DetectionData, LetterboxSpace};
+use yolo_v3_tiny_zynq::img_proc;
+use yolo_v3_tiny_zynq::nms;
+use yolo_v3_tiny_zynq::postprocess::{self, OutputLayout};
+
+/// クラス数
+const CLS_NUM: usize = 7;
+/// YOLO層の出力の分割数
+const FOLDS: usize = 8;
+/// 1回の出力のチャネル数
+const FOLD_CH: usize = 32;
+/// 1つのアンカーボックスあたりのチャネル数 (x, y, w, h, 物体確率, クラス確率80)
+const STRIDE: usize = 85;
+
+/// 再現性のある合成データを作るための乱数 (xorshift32)
+struct Rng(u32);
+
+impl Rng {
+    fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    /// [0, 1) の乱数
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+/// YOLO層の出力 (分割数 * グリッド数 * グリッド数 * 32、固定小数点) を作ります。
+///
+/// # Args
+/// * `grid_num` - グリッドの数
+/// * `obj_rate` - 物体確率が高いアンカーボックスの割合
+/// * `rng` - 乱数
+fn yolo_output(grid_num: usize, obj_rate: f32, rng: &mut Rng) -> Vec<i16> {
+    let cells = grid_num * grid_num;
+    let mut out = vec![0i16; FOLDS * cells * FOLD_CH];
+    for cell in 0..cells {
+        for ch in 0..FOLDS * FOLD_CH {
+            // シグモイドを適用済みの値 (0.0-1.0) とする
+            let mut v = rng.next_f32();
+            if ch % STRIDE == 4 && ch < STRIDE * 3 {
+                v = if rng.next_f32() < obj_rate {
+                    0.5 + v / 2.
+                } else {
+                    v / 10.
+                };
+            }
+            let (fold, k) = (ch / FOLD_CH, ch % FOLD_CH);
+            out[cells * FOLD_CH * fold + FOLD_CH * cell + k] = (v * 256.) as i16;
+        }
+    }
+    out
+}
+
+/// 一部が重なり合うバウンディングボックスを作ります。
+///
+/// # Args
+/// * `n` - バウンディングボックスの数
+/// * `rng` - 乱数
+fn boxes(n: usize, rng: &mut Rng) -> Vec<DetectionData<LetterboxSpace>> {
+    // 物体の周りに候補が集まるように、いくつかの中心の近くに配置する
+    let centers: Vec<(f32, f32)> = (0..n.div_ceil(20))
+        .map(|_| (rng.next_f32() * 416., rng.next_f32() * 416.))
+        .collect();
+    (0..n)
+        .map(|i| {
+            let (cx, cy) = centers[i % centers.len()];
+            let cx = cx + (rng.next_f32() - 0.5) * 20.;
+            let cy = cy + (rng.next_f32() - 0.5) * 20.;
+            let w = 10. + rng.next_f32() * 60.;
+            let h = 10. + rng.next_f32() * 60.;
+            DetectionData::new(
+                (rng.next_u32() % CLS_NUM as u32) as u8,
+                cx - w / 2.,
+                cy - h / 2.,
+                cx + w / 2.,
+                cy + h / 2.,
+                rng.next_f32(),
+            )
+        })
+        .collect()
+}
+
+/// テスト用の画像を作ります。
+fn image(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([x as u8, y as u8, (x ^ y) as u8])
+    }))
+}
+
+fn bench_postprocess(c: &mut Criterion) {
+    let mut rng = Rng(0x1234_5678);
+    let layout = OutputLayout::default();
+    let mut group = c.benchmark_group("post_process");
+    // 物体がない場合は、チャネルの並べ替えとデコードの時間になる
+    for obj_rate in [0., 0.01, 0.1] {
+        let out13 = yolo_output(13, obj_rate, &mut rng);
+        let out26 = yolo_output(26, obj_rate, &mut rng);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(obj_rate),
+            &(out13, out26),
+            |b, (out13, out26)| {
+                b.iter(|| {
+                    postprocess::post_process(
+                        black_box(out13),
+                        black_box(out26),
+                        CLS_NUM,
+                        &layout,
+                        0.2,
+                        0.1,
+                    )
+                })
+            },
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("post_process_fixed");
+    for obj_rate in [0.01, 0.1] {
+        let out13 = yolo_output(13, obj_rate, &mut rng);
+        let out26 = yolo_output(26, obj_rate, &mut rng);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(obj_rate),
+            &(out13, out26),
+            |b, (out13, out26)| {
+                b.iter(|| {
+                    postprocess::post_process_fixed(
+                        black_box(out13),
+                        black_box(out26),
+                        CLS_NUM,
+                        &layout,
+                        0.2,
+                        0.1,
+                    )
+                })
+            },
+        );
+    }
+    group.finish();
+
+    let out13 = yolo_output(13, 0.01, &mut rng);
+    let out26 = yolo_output(26, 0.01, &mut rng);
+    c.bench_function("post_process_top_k", |b| {
+        b.iter(|| {
+            postprocess::post_process_top_k(
+                black_box(&out13),
+                black_box(&out26),
+                CLS_NUM,
+                &layout,
+                0.2,
+                0.1,
+                3,
+            )
+        })
+    });
+}
+
+fn bench_nms(c: &mut Criterion) {
+    let mut rng = Rng(0x9e37_79b9);
+    let mut group = c.benchmark_group("nms_process");
+    for n in [100, 1000, 5000] {
+        let bb = boxes(n, &mut rng);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &bb, |b, bb| {
+            b.iter(|| nms::nms_process(black_box(bb), CLS_NUM, 0.2, 0.1))
+        });
+    }
+    group.finish();
+
+    let bb = boxes(1000, &mut rng);
+    c.bench_function("nms/1000", |b| b.iter(|| nms::nms(black_box(&bb), 0.1)));
+    c.bench_function("nms_grid/1000", |b| {
+        b.iter(|| nms::nms_grid(black_box(&bb), 0.1))
+    });
+}
+
+fn bench_letterbox(c: &mut Criterion) {
+    let mut group = c.benchmark_group("letterbox");
+    for (w, h) in [(640, 480), (1280, 720), (1920, 1080)] {
+        let img = image(w, h);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{}x{}", w, h)),
+            &img,
+            |b, img| b.iter(|| img_proc::letterbox(black_box(img), 416, 0)),
+        );
+    }
+    let img = image(1280, 720);
+    group.bench_function("1280x720_rotate90", |b| {
+        b.iter(|| img_proc::letterbox(black_box(&img), 416, 90))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_postprocess, bench_nms, bench_letterbox);
+criterion_main!(benches);
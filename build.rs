@@ -0,0 +1,11 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/detection.proto");
+        if std::env::var_os("PROTOC").is_none() {
+            let protoc = protoc_bin_vendored::protoc_bin_path().unwrap();
+            std::env::set_var("PROTOC", protoc);
+        }
+        tonic_build::compile_protos("proto/detection.proto").unwrap();
+    }
+}
@@ -0,0 +1,36 @@
+use anyhow::{Context, Result};
+
+use yolo_v3_tiny_zynq::anchors::{box_sizes, compute_anchors};
+use yolo_v3_tiny_zynq::ground_truth::GroundTruth;
+use yolo_v3_tiny_zynq::img_proc::list_images;
+
+/// YOLO形式のデータセット (画像と同じ名前の .txt ラベル) からアンカーボックスを求める
+///
+/// cargo run --example anchors -- <画像のディレクトリ> [ラベルのディレクトリ]
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let img_dir = args
+        .next()
+        .context("usage: anchors <image dir> [label dir]")?;
+    let label_dir = args.next().unwrap_or_else(|| img_dir.clone());
+
+    let mut sizes = vec![];
+    for img_path in list_images(&img_dir)? {
+        let label_path = std::path::Path::new(&label_dir)
+            .join(img_path.file_name().unwrap())
+            .with_extension("txt");
+        if !label_path.exists() {
+            continue;
+        }
+        let (width, height) = image::image_dimensions(&img_path)?;
+        let gt = GroundTruth::from_yolo_txt(&label_path, width, height)?;
+        sizes.extend(box_sizes(&gt, width, height, 416));
+    }
+
+    let anchors = compute_anchors(&sizes)?;
+    println!("{} boxes, avg IoU {:.3}", sizes.len(), anchors.avg_iou);
+    println!("{}", anchors);
+    println!("13x13: {:?}", anchors.head13());
+    println!("26x26: {:?}", anchors.head26());
+    Ok(())
+}
@@ -1,7 +1,6 @@
 use anyhow::{bail, Context, Result};
 use std::sync::mpsc;
 use std::thread;
-use std::time::Instant;
 
 use image::DynamicImage;
 use v4l::buffer::Type;
@@ -9,7 +8,8 @@ use v4l::io::{mmap::Stream, traits::CaptureStream};
 use v4l::video::Capture;
 use v4l::{Device, FourCC};
 
-use yolo_v3_tiny_zynq::img_proc::draw_bbox;
+use yolo_v3_tiny_zynq::render::Renderer;
+use yolo_v3_tiny_zynq::telemetry::TelemetryMeter;
 use yolo_v3_tiny_zynq::yolov3_tiny::YoloV3Tiny;
 
 fn main() -> Result<()> {
@@ -29,21 +29,21 @@ fn main() -> Result<()> {
     // ./out ディレクトリを作成
     std::fs::create_dir_all("./out")?;
 
-    for _ in 0..10 {
-        let start = Instant::now();
+    // FPSなどを出力画像に描画する
+    let renderer = Renderer::default();
+    let mut meter = TelemetryMeter::new();
 
+    for _ in 0..10 {
         // カメラ画像を読み込む
         let img = loader.receive()?;
         // YOLOの処理を開始
-        let result = yolo.start_with_img_proc(&img, 90)?;
-
-        let end = start.elapsed();
-        let t = end.as_secs_f64() * 1000.0;
-        println!("Processing time:{:.03}ms, {:.1}FPS", t, 1000. / t);
+        let frame = yolo.detect_frame(&img, 90)?;
+        let telemetry = meter.record(&frame);
 
         // BBox描画のためDynamicImageを回転してRGB画像に変換
         let mut rgb_img = img.rotate90().to_rgb8();
-        draw_bbox(&mut rgb_img, &result, 20., 4.);
+        renderer.draw(&mut rgb_img, &frame.detections);
+        renderer.draw_telemetry(&mut rgb_img, &telemetry);
 
         // 画像を保存
         rgb_img.save(format!("./out/out.png"))?;
@@ -1,7 +1,9 @@
 use anyhow::Result;
 use std::time::Instant;
 
-use yolo_v3_tiny_zynq::img_proc::{draw_bbox, letterbox_img_with_patial_enlargement};
+use yolo_v3_tiny_zynq::img_proc::letterbox_img_with_patial_enlargement;
+use yolo_v3_tiny_zynq::render::Renderer;
+use yolo_v3_tiny_zynq::telemetry::TelemetryMeter;
 use yolo_v3_tiny_zynq::yolov3_tiny::YoloV3Tiny;
 
 fn main() -> Result<()> {
@@ -22,6 +24,9 @@ fn main() -> Result<()> {
     // テスト画像を読み込む
     let img = image::open("examples/pe_sample.png")?;
 
+    let renderer = Renderer::default();
+    let mut meter = TelemetryMeter::new();
+
     let start = Instant::now();
 
     // YOLOとプロットに画像を使い回すため，事前に回転させる
@@ -36,7 +41,9 @@ fn main() -> Result<()> {
         crop_y,
         crop_w,
         crop_h,
+        true,
     )?;
+    let telemetry = meter.record_detections(yolo.frame_id(), start.elapsed(), &result);
 
     // 画像を変形してBBox描画 (事前に回転しているため，rotate_enはfalse)
     let mut rgb_img = letterbox_img_with_patial_enlargement(
@@ -48,11 +55,8 @@ fn main() -> Result<()> {
         crop_w,
         crop_h,
     );
-    draw_bbox(&mut rgb_img, &result, 20., 4.);
-
-    let end = start.elapsed();
-    let t = end.as_secs_f64() * 1000.0;
-    println!("Processing time:{:.03}ms, {:.1}FPS", t, 1000. / t);
+    renderer.draw(&mut rgb_img, &result);
+    renderer.draw_telemetry(&mut rgb_img, &telemetry);
 
     // 画像を保存
     rgb_img.save("./out/out.png")?;
@@ -1,7 +1,7 @@
 use anyhow::Result;
-use std::time::Instant;
 
-use yolo_v3_tiny_zynq::img_proc::draw_bbox;
+use yolo_v3_tiny_zynq::render::{RenderStyle, Renderer};
+use yolo_v3_tiny_zynq::telemetry::TelemetryMeter;
 use yolo_v3_tiny_zynq::yolov3_tiny::YoloV3Tiny;
 
 fn main() -> Result<()> {
@@ -14,21 +14,22 @@ fn main() -> Result<()> {
     // テスト画像を読み込む
     let test_img = image::open("examples/t19.jpg")?;
 
-    let start = Instant::now();
-
+    let renderer = Renderer::new(RenderStyle {
+        font_size: 20.,
+        line_thickness: 6.,
+        ..Default::default()
+    })?;
+    let mut meter = TelemetryMeter::new();
 
     // YOLOの処理を開始
-    let result = yolo.start_with_img_proc(&test_img, 0)?;
+    let frame = yolo.detect_frame(&test_img, 0)?;
+    let telemetry = meter.record(&frame);
+    println!("{:?}", frame.detections);
 
-    // BBox描画のためDynamicImageをRGB画像に変換
+    // BBox描画のためDynamicImageをRGB画像に変換し、処理時間などを画像に描画
     let mut rgb_img = test_img.to_rgb8();
-    draw_bbox(&mut rgb_img, &result, 20., 6.);
-
-
-    let end = start.elapsed();
-    let t = end.as_secs_f64() * 1000.0;
-    println!("{:?}", result);
-    println!("Processing time:{:.03}ms, {:.1}FPS", t, 1000. / t);
+    renderer.draw(&mut rgb_img, &frame.detections);
+    renderer.draw_telemetry(&mut rgb_img, &telemetry);
 
     // 画像を保存
     std::fs::create_dir_all("./out")?;
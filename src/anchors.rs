@@ -0,0 +1,158 @@
+//! 正解データのバウンディングボックスからアンカーボックスを求めるモジュール
+//!
+//! IoUを距離 (1 - IoU) とするk-meansで6つのアンカーボックスを求めます。
+//! 後処理と同じく、13×13のヘッドは4-6番目、26×26のヘッドは2-4番目のアンカーボックスを使います
+//! (darknetのyolov3-tiny.cfgの `mask` と同じ割り当て)。
+
+use std::fmt;
+
+use anyhow::{bail, Result};
+
+use crate::ground_truth::GroundTruth;
+
+/// アンカーボックスの数
+pub const ANCHOR_NUM: usize = 6;
+
+/// k-meansの最大の反復回数
+const MAX_ITERATIONS: usize = 300;
+
+/// k-meansで求めたアンカーボックス
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Anchors {
+    /// アンカーボックスの幅と高さ (YOLOの入力の座標系)。面積の小さい順に並びます
+    pub sizes: [[f32; 2]; ANCHOR_NUM],
+    /// 各バウンディングボックスと最も近いアンカーボックスのIoUの平均
+    pub avg_iou: f32,
+}
+
+impl Anchors {
+    /// 13×13のヘッドで使うアンカーボックスを返します。
+    pub fn head13(&self) -> [[f32; 2]; 3] {
+        [self.sizes[3], self.sizes[4], self.sizes[5]]
+    }
+
+    /// 26×26のヘッドで使うアンカーボックスを返します。
+    pub fn head26(&self) -> [[f32; 2]; 3] {
+        [self.sizes[1], self.sizes[2], self.sizes[3]]
+    }
+}
+
+/// darknetの設定ファイルと同じ形式 (`anchors = 10,14,  23,27, ...`) で出力します。
+impl fmt::Display for Anchors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pairs: Vec<String> = self
+            .sizes
+            .iter()
+            .map(|[w, h]| format!("{},{}", w.round(), h.round()))
+            .collect();
+        write!(f, "anchors = {}", pairs.join(",  "))
+    }
+}
+
+/// 中心を揃えた2つのボックスのIoUを返します。
+fn size_iou(a: [f32; 2], b: [f32; 2]) -> f32 {
+    let inter = a[0].min(b[0]) * a[1].min(b[1]);
+    let union = a[0] * a[1] + b[0] * b[1] - inter;
+    if union <= 0. {
+        0.
+    } else {
+        inter / union
+    }
+}
+
+/// 最もIoUの大きいアンカーボックスのインデックスとIoUを返します。
+fn nearest(size: [f32; 2], centroids: &[[f32; 2]]) -> (usize, f32) {
+    centroids
+        .iter()
+        .map(|&c| size_iou(size, c))
+        .enumerate()
+        .fold(
+            (0, -1.),
+            |best, (i, iou)| if iou > best.1 { (i, iou) } else { best },
+        )
+}
+
+/// バウンディングボックスの大きさからアンカーボックスを求めます。
+///
+/// 初期値は面積の分位点から選ぶため、同じ入力には常に同じ結果を返します。
+///
+/// # Args
+/// * `sizes` - バウンディングボックスの幅と高さ (YOLOの入力の座標系)
+///
+/// # Return
+/// * アンカーボックス。大きさの異なるボックスが6つ未満の場合はエラー
+pub fn compute_anchors(sizes: &[(f32, f32)]) -> Result<Anchors> {
+    let mut boxes: Vec<[f32; 2]> = sizes
+        .iter()
+        .filter(|(w, h)| *w > 0. && *h > 0.)
+        .map(|&(w, h)| [w, h])
+        .collect();
+    boxes.sort_by(|a, b| (a[0] * a[1]).total_cmp(&(b[0] * b[1])));
+    let mut distinct = boxes.clone();
+    distinct.dedup();
+    if distinct.len() < ANCHOR_NUM {
+        bail!(
+            "At least {} distinct box sizes are needed, but {} were given",
+            ANCHOR_NUM,
+            distinct.len()
+        );
+    }
+
+    let mut centroids: Vec<[f32; 2]> = (0..ANCHOR_NUM)
+        .map(|i| distinct[(2 * i + 1) * distinct.len() / (2 * ANCHOR_NUM)])
+        .collect();
+    let mut assignment = vec![usize::MAX; boxes.len()];
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (a, &b) in assignment.iter_mut().zip(&boxes) {
+            let (i, _) = nearest(b, &centroids);
+            if *a != i {
+                *a = i;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+
+        let mut sums = [[0f32; 3]; ANCHOR_NUM];
+        for (&a, b) in assignment.iter().zip(&boxes) {
+            sums[a][0] += b[0];
+            sums[a][1] += b[1];
+            sums[a][2] += 1.;
+        }
+        for (c, [w, h, n]) in centroids.iter_mut().zip(sums) {
+            // 割り当てのないアンカーボックスは前回の値のままにする
+            if n > 0. {
+                *c = [w / n, h / n];
+            }
+        }
+    }
+
+    centroids.sort_by(|a, b| (a[0] * a[1]).total_cmp(&(b[0] * b[1])));
+    let avg_iou = boxes.iter().map(|&b| nearest(b, &centroids).1).sum::<f32>() / boxes.len() as f32;
+    let mut anchors = [[0.; 2]; ANCHOR_NUM];
+    anchors.copy_from_slice(&centroids);
+    Ok(Anchors {
+        sizes: anchors,
+        avg_iou,
+    })
+}
+
+/// 正解データのバウンディングボックスの大きさを、レターボックス後のYOLOの入力の座標系で返します。
+///
+/// # Args
+/// * `gt` - 1枚の画像の正解データ
+/// * `width` - 画像の幅
+/// * `height` - 画像の高さ
+/// * `input_size` - YOLOの入力サイズ (416)
+///
+/// # Return
+/// * バウンディングボックスの幅と高さ
+pub fn box_sizes(gt: &GroundTruth, width: u32, height: u32, input_size: u32) -> Vec<(f32, f32)> {
+    let scale = input_size as f32 / width.max(height).max(1) as f32;
+    gt.boxes
+        .iter()
+        .map(|b| (b.width() * scale, b.height() * scale))
+        .collect()
+}
@@ -0,0 +1,101 @@
+//! `ndarray` の配列とIPの入出力データを相互に変換するモジュール
+//!
+//! `ndarray` フィーチャを有効にした場合のみ利用できます。
+//! IPのデータはチャネルが分割 (fold) されて並んでいるため、高さ・幅・チャネルの順 (HWC) の配列に並べ替えます。
+
+use anyhow::{ensure, Result};
+use ndarray::{Array3, ArrayView3};
+
+use crate::detection_result::{DetectionData, LetterboxSpace};
+use crate::features::FeatureMap;
+use crate::layer_group::LayerGroup;
+use crate::yolov3_tiny::YoloV3Tiny;
+
+/// HWCの配列を、レイヤーグループの入力データ (分割されたチャネルの並び) に変換します。
+///
+/// # Args
+/// * `view` - 形状が (高さ, 幅, チャネル数) の配列。最初のレイヤーグループでは0-255の画素値、
+///   それ以外ではQ8.8の固定小数点数を渡してください
+/// * `layer` - 入力先のレイヤーグループ
+///
+/// # Return
+/// * レイヤーグループの入力データ。形状が合わない場合はエラー
+pub fn pack_input<T: Copy + Into<i16>>(
+    view: ArrayView3<T>,
+    layer: &LayerGroup,
+) -> Result<Vec<i16>> {
+    let (height, width) = (layer.input_height as usize, layer.input_width as usize);
+    let fold_ch = layer.input_ch as usize;
+    let folds = layer.input_fold_factor as usize;
+    // 1回の転送で扱うチャネル数は4の倍数に切り上げられる
+    let slot = layer.input_size as usize / (width * height);
+    ensure!(
+        view.dim() == (height, width, fold_ch * folds),
+        "Input shape {:?} does not match ({}, {}, {})",
+        view.dim(),
+        height,
+        width,
+        fold_ch * folds
+    );
+
+    let mut data = vec![0; layer.input_size as usize * folds];
+    for j in 0..folds {
+        for y in 0..height {
+            for x in 0..width {
+                let base = ((j * height + y) * width + x) * slot;
+                for k in 0..fold_ch {
+                    data[base + k] = view[[y, x, j * fold_ch + k]].into();
+                }
+            }
+        }
+    }
+    Ok(data)
+}
+
+impl FeatureMap {
+    /// 形状が (高さ, 幅, チャネル数) の配列に変換します。
+    pub fn to_array(&self) -> Array3<f32> {
+        self.clone().into()
+    }
+}
+
+impl From<FeatureMap> for Array3<f32> {
+    fn from(map: FeatureMap) -> Self {
+        Array3::from_shape_vec((map.height, map.width, map.channels), map.data)
+            .expect("FeatureMap data length matches its shape")
+    }
+}
+
+impl YoloV3Tiny {
+    /// HWCの配列を入力として処理を行います。
+    ///
+    /// # Args
+    /// * `view` - 形状が (416, 416, 3) の、レターボックス済みの画素値 (0-255)
+    ///
+    /// # Return
+    /// * 物体検出結果 (レターボックスの座標系)
+    pub fn start_with_array<T: Copy + Into<i16>>(
+        &mut self,
+        view: ArrayView3<T>,
+    ) -> Result<Vec<DetectionData<LetterboxSpace>>> {
+        let input_data = pack_input(view, &self.layer_groups()[0])?;
+        self.start(&input_data)
+    }
+
+    /// HWCの配列を入力として、指定したレイヤーグループの出力を配列で返します。
+    ///
+    /// # Args
+    /// * `view` - 形状が (416, 416, 3) の、レターボックス済みの画素値 (0-255)
+    /// * `grp_idx` - 出力を取り出すレイヤーグループのインデックス (0-13)
+    ///
+    /// # Return
+    /// * 形状が (高さ, 幅, チャネル数) の特徴マップ
+    pub fn extract_features_array<T: Copy + Into<i16>>(
+        &mut self,
+        view: ArrayView3<T>,
+        grp_idx: usize,
+    ) -> Result<Array3<f32>> {
+        let input_data = pack_input(view, &self.layer_groups()[0])?;
+        Ok(self.extract_features(&input_data, grp_idx)?.into())
+    }
+}
@@ -0,0 +1,180 @@
+//! 矢印信号の向きを、点灯部の形のテンプレートマッチングで判定するモジュール
+//!
+//! 灯器の領域のうち明るいピクセルを点灯部とし、点灯部を囲む矩形を `GRID` × `GRID` のマスに分けた
+//! 2値のパターンを、左・直進・右の矢印と円形 (矢印でない灯器) のテンプレートと比較します。
+//! 点灯部を囲む矩形に合わせて比較するため、灯器の大きさや縦横比によらず判定できます。
+
+use image::RgbImage;
+
+use crate::detection_result::SubState;
+use crate::region::Region;
+
+/// テンプレートの1辺のマスの数
+const GRID: usize = 16;
+
+/// 点灯部とみなすピクセルの最小の数。これより少ない場合は形を判定しない
+const MIN_LIT_PIXELS: usize = 16;
+
+/// 2値のパターン (行優先)
+type Pattern = [bool; GRID * GRID];
+
+/// テンプレートの形
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    Arrow(SubState),
+    Circle,
+}
+
+/// 矢印信号の向きを判定する構造体
+#[derive(Debug, Clone)]
+pub struct ArrowClassifier {
+    /// 点灯部とみなす明るさ (領域内の最大の明るさに対する割合)
+    lit_ratio: f64,
+    /// 矢印と判定するテンプレートとの一致度 (IoU) の下限
+    min_score: f64,
+    templates: Vec<(Shape, Pattern)>,
+}
+
+impl Default for ArrowClassifier {
+    fn default() -> Self {
+        Self::new(0.6, 0.5)
+    }
+}
+
+impl ArrowClassifier {
+    /// 新しい `ArrowClassifier` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `lit_ratio` - 点灯部とみなす明るさ (領域内の最大の明るさに対する割合、0.0-1.0)
+    /// * `min_score` - 矢印と判定するテンプレートとの一致度 (IoU、0.0-1.0) の下限
+    pub fn new(lit_ratio: f64, min_score: f64) -> Self {
+        let templates = vec![
+            (
+                Shape::Arrow(SubState::ArrowLeft),
+                pattern(|u, v| right_arrow(1. - u, v)),
+            ),
+            (
+                Shape::Arrow(SubState::ArrowStraight),
+                pattern(|u, v| right_arrow(1. - v, u)),
+            ),
+            (Shape::Arrow(SubState::ArrowRight), pattern(right_arrow)),
+            (
+                Shape::Circle,
+                pattern(|u, v| (u - 0.5).hypot(v - 0.5) <= 0.5),
+            ),
+        ];
+        Self {
+            lit_ratio,
+            min_score,
+            templates,
+        }
+    }
+
+    /// 点灯部とみなす明るさの割合を返します。
+    pub fn lit_ratio(&self) -> f64 {
+        self.lit_ratio
+    }
+
+    /// 矢印と判定する一致度の下限を返します。
+    pub fn min_score(&self) -> f64 {
+        self.min_score
+    }
+
+    /// 灯器の領域の点灯部の形から、矢印信号の向きを判定します。
+    ///
+    /// # Args
+    /// * `img` - 検出結果の座標系に対応する画像
+    /// * `region` - 灯器の領域
+    ///
+    /// # Return
+    /// * 矢印の向き。点灯部が小さい場合、円形に最も近い場合、一致度が下限に満たない場合はNone
+    pub fn classify(&self, img: &RgbImage, region: &Region) -> Option<SubState> {
+        let (x0, y0) = region.start;
+        let x1 = region.end.0.min(img.width());
+        let y1 = region.end.1.min(img.height());
+        if x0 >= x1 || y0 >= y1 {
+            return None;
+        }
+
+        let brightness = |x: u32, y: u32| *img.get_pixel(x, y).0.iter().max().unwrap();
+        let max_v = (y0..y1)
+            .flat_map(|y| (x0..x1).map(move |x| (x, y)))
+            .map(|(x, y)| brightness(x, y))
+            .max()?;
+        if max_v == 0 {
+            return None;
+        }
+        let threshold = (max_v as f64 * self.lit_ratio).ceil() as u8;
+        let lit: Vec<(u32, u32)> = (y0..y1)
+            .flat_map(|y| (x0..x1).map(move |x| (x, y)))
+            .filter(|&(x, y)| region.is_in((x, y)) && brightness(x, y) >= threshold)
+            .collect();
+        if lit.len() < MIN_LIT_PIXELS {
+            return None;
+        }
+
+        // 点灯部を囲む矩形をGRID×GRIDのマスに分け、半分以上が点灯しているマスを点灯とする
+        let lx0 = lit.iter().map(|p| p.0).min()?;
+        let ly0 = lit.iter().map(|p| p.1).min()?;
+        let lw = lit.iter().map(|p| p.0).max()? - lx0 + 1;
+        let lh = lit.iter().map(|p| p.1).max()? - ly0 + 1;
+        let cell = |p: u32, origin: u32, len: u32| ((p - origin) as usize * GRID) / len as usize;
+        let mut lit_count = [0u32; GRID * GRID];
+        for &(x, y) in &lit {
+            lit_count[cell(x, lx0, lw) + cell(y, ly0, lh) * GRID] += 1;
+        }
+        let mut observed = [false; GRID * GRID];
+        for gy in 0..GRID {
+            for gx in 0..GRID {
+                // マスに含まれるピクセルの数
+                let span = |g: usize, len: u32| {
+                    let len = len as usize;
+                    (((g + 1) * len).div_ceil(GRID) - (g * len).div_ceil(GRID)).max(1)
+                };
+                let n = (span(gx, lw) * span(gy, lh)) as u32;
+                observed[gx + gy * GRID] = lit_count[gx + gy * GRID] * 2 >= n;
+            }
+        }
+
+        let (shape, score) = self
+            .templates
+            .iter()
+            .map(|(shape, t)| (*shape, iou(&observed, t)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        match shape {
+            Shape::Arrow(s) if score >= self.min_score => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// 単位正方形上の形から、マスの中心が形に含まれるかのパターンを作ります。
+fn pattern(inside: impl Fn(f64, f64) -> bool) -> Pattern {
+    let mut p = [false; GRID * GRID];
+    for (i, cell) in p.iter_mut().enumerate() {
+        let u = ((i % GRID) as f64 + 0.5) / GRID as f64;
+        let v = ((i / GRID) as f64 + 0.5) / GRID as f64;
+        *cell = inside(u, v);
+    }
+    p
+}
+
+/// 単位正方形に収まる右向きの矢印に点が含まれるかを返します。
+fn right_arrow(u: f64, v: f64) -> bool {
+    // 軸
+    let shaft = u <= 0.5 && (0.35..=0.65).contains(&v);
+    // 先端の三角形 (0.45, 0), (1, 0.5), (0.45, 1)
+    let head = u >= 0.45 && (v - 0.5).abs() <= 0.5 * (1. - u) / 0.55;
+    shaft || head
+}
+
+/// 2つのパターンのIoUを求めます。
+fn iou(a: &Pattern, b: &Pattern) -> f64 {
+    let inter = a.iter().zip(b).filter(|(x, y)| **x && **y).count();
+    let union = a.iter().zip(b).filter(|(x, y)| **x || **y).count();
+    if union == 0 {
+        0.
+    } else {
+        inter as f64 / union as f64
+    }
+}
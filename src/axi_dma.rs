@@ -0,0 +1,344 @@
+//! u-dma-bufのバッファを使ってAXI DMAをダイレクトレジスタモードで操作するモジュール
+//!
+//! 送受信するデータはキャッシュを有効にしてmmapしたu-dma-bufのバッファに置き、転送の前後で明示的に同期します。
+//! バッファの前半を送信 (MM2S) に、後半を受信 (S2MM) に使います。
+//!
+//! ハードウェア情報のDMAのエントリに、レジスタを公開しているUIOデバイス名 (`uio`) と
+//! u-dma-bufのデバイス名 (`udmabuf`) が記録されている場合にu-dma-bufを使います。
+//! `udmabuf` が記録されていない場合は、従来どおりxipdriver-rsの `axidma` で転送します。
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Context, Result};
+use xipdriver_rs::axidma;
+
+use crate::udmabuf::{self, SyncDirection, UdmaBuf};
+use crate::uio::{self, Registers};
+
+/// MM2S_DMACR
+const MM2S_DMACR: usize = 0x00;
+/// MM2S_DMASR
+const MM2S_DMASR: usize = 0x04;
+/// MM2S_SA
+const MM2S_SA: usize = 0x18;
+/// MM2S_SA_MSB
+const MM2S_SA_MSB: usize = 0x1c;
+/// MM2S_LENGTH
+const MM2S_LENGTH: usize = 0x28;
+/// S2MM_DMACR
+const S2MM_DMACR: usize = 0x30;
+/// S2MM_DMASR
+const S2MM_DMASR: usize = 0x34;
+/// S2MM_DA
+const S2MM_DA: usize = 0x48;
+/// S2MM_DA_MSB
+const S2MM_DA_MSB: usize = 0x4c;
+/// S2MM_LENGTH
+const S2MM_LENGTH: usize = 0x58;
+
+/// DMACRのRun/Stopビット
+const DMACR_RS: u32 = 0x1;
+/// DMACRのResetビット (両方のチャネルをリセットする)
+const DMACR_RESET: u32 = 0x4;
+/// DMASRのHaltedビット
+const DMASR_HALTED: u32 = 0x1;
+/// DMASRのIdleビット
+const DMASR_IDLE: u32 = 0x2;
+/// DMASRのエラーを示すビット (DMAIntErr, DMASlvErr, DMADecErr)
+const DMASR_ERR_MASK: u32 = 0x70;
+
+/// DMAのチャネル
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Channel {
+    Mm2s,
+    S2mm,
+}
+
+impl Channel {
+    /// DMACRのオフセットを返します。
+    fn dmacr(self) -> usize {
+        match self {
+            Channel::Mm2s => MM2S_DMACR,
+            Channel::S2mm => S2MM_DMACR,
+        }
+    }
+
+    /// DMASRのオフセットを返します。
+    fn dmasr(self) -> usize {
+        match self {
+            Channel::Mm2s => MM2S_DMASR,
+            Channel::S2mm => S2MM_DMASR,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Channel::Mm2s => "MM2S",
+            Channel::S2mm => "S2MM",
+        }
+    }
+}
+
+/// u-dma-bufのバッファでレジスタを直接操作して転送するDMA
+struct UdmaBufDma {
+    regs: Registers,
+    buf: UdmaBuf,
+    /// MM2Sの転送が完了していない可能性があるか
+    mm2s_busy: Cell<bool>,
+    /// S2MMの転送が完了していない可能性があるか
+    s2mm_busy: Cell<bool>,
+}
+
+impl UdmaBufDma {
+    /// 送信と受信のそれぞれに使えるバッファの大きさ (バイト) を返します。
+    fn half_len(&self) -> usize {
+        // 受信の先頭を64バイト境界に揃える
+        (self.buf.len() / 2) & !63
+    }
+
+    /// 転送の先頭アドレスを設定します。
+    fn set_addr(&mut self, lsb: usize, msb: usize, addr: u64) {
+        self.regs.write(lsb, addr as u32);
+        // 32ビットのアドレス空間で構成したDMAにはMSBのレジスタがない
+        if addr >> 32 != 0 {
+            self.regs.write(msb, (addr >> 32) as u32);
+        }
+    }
+}
+
+/// DMAの転送の実装
+enum Backend {
+    /// u-dma-buf (ハードウェア情報に `udmabuf` がある場合)
+    UdmaBuf(UdmaBufDma),
+    /// xipdriver-rsの `axidma`。受信は `start_read` の中で完了まで待ち、受信したデータを保持します
+    Xip {
+        dma: axidma::AxiDma,
+        received: Option<Vec<i16>>,
+    },
+}
+
+/// u-dma-bufのバッファ、またはxipdriver-rsで転送するAXI DMA
+pub(crate) struct AxiDma {
+    name: String,
+    backend: Backend,
+}
+
+impl AxiDma {
+    /// ハードウェア情報に記録されたDMAを開きます。
+    ///
+    /// `udmabuf` のエントリがある場合はレジスタとu-dma-bufのバッファを開き、ない場合はxipdriver-rsのDMAを使います。
+    ///
+    /// # Args
+    /// * `info` - DMAのハードウェア情報
+    /// * `name` - DMAの名前 (例: `/yolo/axi_dma_0`)
+    pub(crate) fn new(info: &serde_json::Value, name: &str) -> Result<Self> {
+        let backend = match info["udmabuf"].as_str() {
+            Some(buf_name) => {
+                let regs = Registers::open(&uio::device_path(info, "uio", name)?, 0x100)?;
+                let buf = UdmaBuf::open(buf_name.trim_start_matches("/dev/"))?;
+                Backend::UdmaBuf(UdmaBufDma {
+                    regs,
+                    buf,
+                    mm2s_busy: Cell::new(false),
+                    s2mm_busy: Cell::new(false),
+                })
+            }
+            None => Backend::Xip {
+                dma: axidma::AxiDma::new(info).with_context(|| format!("Can't open {}", name))?,
+                received: None,
+            },
+        };
+        Ok(Self {
+            name: name.to_string(),
+            backend,
+        })
+    }
+
+    /// 1回の転送で送受信できる要素の数の上限を、u-dma-bufのバッファの大きさから返します。
+    ///
+    /// # 返り値
+    /// * 要素の数。xipdriver-rsのDMAの場合はバッファによる上限がないためNone
+    pub(crate) fn buffer_len(&self) -> Option<usize> {
+        match &self.backend {
+            Backend::UdmaBuf(d) => Some(d.half_len() / 2),
+            Backend::Xip { .. } => None,
+        }
+    }
+
+    /// 両方のチャネルを起動します。
+    pub(crate) fn start(&mut self) {
+        match &mut self.backend {
+            Backend::UdmaBuf(d) => {
+                for ch in [Channel::Mm2s, Channel::S2mm] {
+                    let cr = d.regs.read(ch.dmacr());
+                    d.regs.write(ch.dmacr(), cr | DMACR_RS);
+                }
+            }
+            Backend::Xip { dma, .. } => dma.start(),
+        }
+    }
+
+    /// 両方のチャネルを停止します。転送中のデータは破棄されます。
+    pub(crate) fn stop(&mut self) {
+        match &mut self.backend {
+            Backend::UdmaBuf(d) => {
+                for ch in [Channel::Mm2s, Channel::S2mm] {
+                    let cr = d.regs.read(ch.dmacr());
+                    d.regs.write(ch.dmacr(), cr & !DMACR_RS);
+                }
+                d.mm2s_busy.set(false);
+                d.s2mm_busy.set(false);
+            }
+            Backend::Xip { dma, received } => {
+                dma.stop();
+                *received = None;
+            }
+        }
+    }
+
+    /// DMAをソフトリセットします。両方のチャネルは停止し、転送中のデータは破棄されます。
+    ///
+    /// xipdriver-rsのDMAはソフトリセットできないため、停止だけを行います。
+    ///
+    /// # Args
+    /// * `timeout` - リセットの完了を待つ最大の時間
+    ///
+    /// # 返り値
+    /// * Result。時間内にリセットが完了しなかった場合はエラー
+    pub(crate) fn reset(&mut self, timeout: Duration) -> Result<()> {
+        let d = match &mut self.backend {
+            Backend::UdmaBuf(d) => d,
+            Backend::Xip { .. } => {
+                self.stop();
+                return Ok(());
+            }
+        };
+        d.regs.write(MM2S_DMACR, DMACR_RESET);
+        d.mm2s_busy.set(false);
+        d.s2mm_busy.set(false);
+        let start = Instant::now();
+        while d.regs.read(MM2S_DMACR) & DMACR_RESET != 0 {
+            if start.elapsed() > timeout {
+                bail!("{} reset timed out", self.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// チャネルの転送が完了しているかを返します。
+    ///
+    /// # 返り値
+    /// * 完了している (または転送していない) 場合はtrue。DMAがエラーで止まった場合はエラー
+    fn is_idle(&self, ch: Channel) -> Result<bool> {
+        let d = match &self.backend {
+            Backend::UdmaBuf(d) => d,
+            Backend::Xip { dma, .. } => {
+                return match ch {
+                    Channel::Mm2s => dma.is_mm2s_idle(),
+                    Channel::S2mm => dma.is_s2mm_idle(),
+                };
+            }
+        };
+        let busy = match ch {
+            Channel::Mm2s => &d.mm2s_busy,
+            Channel::S2mm => &d.s2mm_busy,
+        };
+        let status = d.regs.read(ch.dmasr());
+        if status & DMASR_ERR_MASK != 0 {
+            bail!("{} {} error: status={:#010x}", self.name, ch.name(), status);
+        }
+        if busy.get() && status & (DMASR_IDLE | DMASR_HALTED) != 0 {
+            busy.set(false);
+        }
+        Ok(!busy.get())
+    }
+
+    /// MM2Sの転送が完了しているかを返します。
+    pub(crate) fn is_mm2s_idle(&self) -> Result<bool> {
+        self.is_idle(Channel::Mm2s)
+    }
+
+    /// S2MMの転送が完了しているかを返します。
+    pub(crate) fn is_s2mm_idle(&self) -> Result<bool> {
+        self.is_idle(Channel::S2mm)
+    }
+
+    /// データをバッファに書き込み、MM2Sの転送を開始します。完了は待ちません。
+    ///
+    /// # Args
+    /// * `data` - 送信するデータ (1回の転送の最大の長さ以下)
+    pub(crate) fn write(&mut self, data: &[i16]) -> Result<()> {
+        // 転送中のデータを上書きしないよう、前の転送は完了している必要がある
+        ensure!(self.is_mm2s_idle()?, "{} MM2S is busy", self.name);
+        if data.is_empty() {
+            return Ok(());
+        }
+        let d = match &mut self.backend {
+            Backend::UdmaBuf(d) => d,
+            Backend::Xip { dma, .. } => return dma.write(data),
+        };
+        let size = data.len() * 2;
+        ensure!(
+            size <= d.half_len(),
+            "{} bytes exceed the MM2S buffer of {} ({} bytes)",
+            size,
+            self.name,
+            d.half_len()
+        );
+        d.buf.write_i16(0, data)?;
+        let addr = d.buf.phys_addr();
+        d.set_addr(MM2S_SA, MM2S_SA_MSB, addr);
+        d.mm2s_busy.set(true);
+        // LENGTHの書き込みで転送が始まる
+        d.regs.write(MM2S_LENGTH, size as u32);
+        Ok(())
+    }
+
+    /// S2MMの転送を開始します。u-dma-bufの場合は完了を待ちません。
+    ///
+    /// 完了を `is_s2mm_idle` で確認してから、`finish_read` で受信したデータを読み込みます。
+    /// xipdriver-rsのDMAは受信の完了を待つ転送しかできないため、ここで完了まで待ちます (時間切れはありません)。
+    ///
+    /// # Args
+    /// * `len` - 受信する要素の数 (1回の転送の最大の長さ以下)
+    pub(crate) fn start_read(&mut self, len: usize) -> Result<()> {
+        let d = match &mut self.backend {
+            Backend::UdmaBuf(d) => d,
+            Backend::Xip { dma, received } => {
+                *received = Some(dma.read(len)?);
+                return Ok(());
+            }
+        };
+        let offset = d.half_len();
+        let size = len * 2;
+        ensure!(
+            size <= offset,
+            "{} bytes exceed the S2MM buffer of {} ({} bytes)",
+            size,
+            self.name,
+            offset
+        );
+        // DMAが書き込む前に、CPUのキャッシュに残った内容が書き戻されないようにする
+        udmabuf::sync(d.buf.sysfs(), true, offset, size, SyncDirection::FromDevice)?;
+        let addr = d.buf.phys_addr() + offset as u64;
+        d.set_addr(S2MM_DA, S2MM_DA_MSB, addr);
+        d.s2mm_busy.set(true);
+        d.regs.write(S2MM_LENGTH, size as u32);
+        Ok(())
+    }
+
+    /// 完了したS2MMの転送で受信したデータを読み込みます。
+    ///
+    /// # Args
+    /// * `len` - `start_read` で指定した要素の数
+    pub(crate) fn finish_read(&mut self, len: usize) -> Result<Vec<i16>> {
+        ensure!(self.is_s2mm_idle()?, "{} S2MM is busy", self.name);
+        match &mut self.backend {
+            Backend::UdmaBuf(d) => d.buf.read_i16(d.half_len(), len),
+            Backend::Xip { received, .. } => received
+                .take()
+                .with_context(|| format!("{} has no received data", self.name)),
+        }
+    }
+}
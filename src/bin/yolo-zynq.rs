@@ -0,0 +1,405 @@
+//! YOLOv3-Tiny for Zynq のコマンドラインツール
+//!
+//! Rustのプログラムを書かずに、ボード上で物体検出・処理時間の計測・重みの確認・自己診断を行います。
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use yolo_v3_tiny_zynq::config::Config;
+use yolo_v3_tiny_zynq::detection_result::DetectionData;
+use yolo_v3_tiny_zynq::img_proc::{self, draw_bbox};
+use yolo_v3_tiny_zynq::postprocess::DEFAULT_CLASS_SLOTS;
+use yolo_v3_tiny_zynq::trace::{self, MockBackend};
+use yolo_v3_tiny_zynq::yolov3_tiny::YoloV3Tiny;
+
+const USAGE: &str = "\
+usage: yolo-zynq <command> [options]
+
+commands:
+  detect <image|dir|video>   detect objects and print the results
+  bench [image]              measure the inference time
+  verify-weights <bundle>    check a weights bundle (.tar.gz or .npz) without hardware
+  self-test                  check the switches, DMAs and YOLO IP with built-in data
+  daemon                     run detection on a camera until SIGTERM (needs the gstreamer feature)
+  replay <trace>             replay a register/DMA trace recorded with --trace
+
+options:
+  --config <path>      read the settings from a TOML file (see yolo_v3_tiny_zynq::config)
+  --weights <path>     weights bundle (required without --config)
+  --hwinfo <path>      hardware information [default: /slab/hwinfo.json]
+  --hier <name>        YOLO hierarchy [default: yolo]
+  --cls-num <n>        number of classes [default: 7]
+  --class-slots <n>    class slots per anchor box [default: 80]
+  --obj <threshold>    objectness threshold [default: 0.2]
+  --nms <threshold>    NMS threshold [default: 0.1]
+  --rotate <angle>     rotate the input image (0, 90, 180, 270) [default: 0]
+  --output <path>      save images (or the video) with bounding boxes
+  --frames <n>         number of frames for bench [default: 100]
+  --trace <path>       record register writes and DMA transfers to a file
+  --backend <name>     replay: mock (no hardware) or hardware [default: mock]
+  --source <pipeline>  GStreamer pipeline with `appsink name=sink` for daemon
+                       [default: v4l2src ! videoconvert ! appsink name=sink]
+  --mjpeg <addr>       daemon: serve annotated frames as MJPEG (e.g. 0.0.0.0:8082)
+  --fb <name>          daemon: draw annotated frames on a framebuffer (e.g. fb0)
+  --log-dir <dir>      daemon: write detections to <dir>/detections.csv";
+
+/// 動画として扱う拡張子
+const VIDEO_EXTENSIONS: [&str; 5] = ["mp4", "mkv", "avi", "mov", "webm"];
+
+/// コマンドライン引数
+struct Args {
+    /// サブコマンド
+    command: String,
+    /// `--key value` 形式のオプション
+    options: HashMap<String, String>,
+    /// オプション以外の引数
+    positional: Vec<String>,
+}
+
+impl Args {
+    /// コマンドライン引数を解析します。
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self> {
+        let command = args.next().context(USAGE)?;
+        let mut options = HashMap::new();
+        let mut positional = vec![];
+        while let Some(arg) = args.next() {
+            if let Some(key) = arg.strip_prefix("--") {
+                let value = args
+                    .next()
+                    .with_context(|| format!("--{} needs a value", key))?;
+                options.insert(key.to_string(), value);
+            } else {
+                positional.push(arg);
+            }
+        }
+        Ok(Self {
+            command,
+            options,
+            positional,
+        })
+    }
+
+    /// オプションの値を返します。
+    fn get(&self, key: &str) -> Option<&str> {
+        self.options.get(key).map(String::as_str)
+    }
+
+    /// オプションの値を解析して返します。指定されていない場合は `default` を返します。
+    fn value<T: FromStr>(&self, key: &str, default: T) -> Result<T> {
+        match self.get(key) {
+            Some(v) => v
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid value for --{}: {}", key, v)),
+            None => Ok(default),
+        }
+    }
+
+    /// 位置引数を返します。
+    fn positional(&self, idx: usize, name: &str) -> Result<&str> {
+        self.positional
+            .get(idx)
+            .map(String::as_str)
+            .with_context(|| format!("{} needs <{}>\n\n{}", self.command, name, USAGE))
+    }
+}
+
+/// 設定ファイルまたはオプションから `YoloV3Tiny` を作成します。
+fn open_yolo(args: &Args) -> Result<YoloV3Tiny> {
+    let mut yolo = match args.get("config") {
+        Some(path) => YoloV3Tiny::from_config(&Config::from_toml(path)?)?,
+        None => open_yolo_with_options(args)?,
+    };
+    yolo.set_trace(args.get("trace"))?;
+    Ok(yolo)
+}
+
+/// オプションから `YoloV3Tiny` を作成します。
+fn open_yolo_with_options(args: &Args) -> Result<YoloV3Tiny> {
+    let weights = args
+        .get("weights")
+        .context("Either --config or --weights is required")?;
+    let mut yolo = YoloV3Tiny::uninit(
+        args.get("hwinfo").unwrap_or("/slab/hwinfo.json"),
+        args.get("hier").unwrap_or("yolo"),
+        args.value("cls-num", 7)?,
+        args.value("obj", 0.2)?,
+        args.value("nms", 0.1)?,
+    )?;
+    // 重みの形状は読み込むときの出力の並びで確認されるため、先にクラスの枠の数を設定する
+    if let Some(class_slots) = args.get("class-slots") {
+        let class_slots = class_slots
+            .parse()
+            .context("Invalid value for --class-slots")?;
+        yolo.set_class_slots(class_slots)?;
+    }
+    yolo.init(weights)?;
+    Ok(yolo)
+}
+
+/// 1枚の画像の検出結果を出力します。
+fn print_detections(yolo: &YoloV3Tiny, name: &str, detections: &[DetectionData]) {
+    println!("{}: {} objects", name, detections.len());
+    for d in detections {
+        let class = yolo
+            .class_name(d.class)
+            .map_or_else(|| d.class.to_string(), str::to_string);
+        println!(
+            "  {} {:.3} ({:.0}, {:.0}) - ({:.0}, {:.0})",
+            class, d.confidence, d.x1, d.y1, d.x2, d.y2
+        );
+    }
+}
+
+/// バウンディングボックスを描画した画像を保存します。
+fn save_result(img_path: &Path, detections: &[DetectionData], output: &Path) -> Result<()> {
+    let mut img = image::open(img_path)?.to_rgb8();
+    draw_bbox(&mut img, detections, 20., 6.);
+    img.save(output)
+        .with_context(|| format!("Can't save {}", output.display()))
+}
+
+/// `detect` サブコマンド
+fn detect(args: &Args) -> Result<()> {
+    let target = PathBuf::from(args.positional(0, "image|dir|video")?);
+    let rotate_angle = args.value("rotate", 0)?;
+    let output = args.get("output").map(PathBuf::from);
+    let mut yolo = open_yolo(args)?;
+
+    let is_video = target
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| VIDEO_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()));
+    if target.is_dir() {
+        if let Some(dir) = &output {
+            std::fs::create_dir_all(dir)?;
+        }
+        for (path, detections) in yolo.start_dir(&target, rotate_angle)? {
+            print_detections(&yolo, &path.display().to_string(), &detections);
+            if let Some(dir) = &output {
+                // 拡張子だけが異なる画像を上書きしないよう、元のファイル名に拡張子を付け足す
+                let mut name = path
+                    .file_name()
+                    .context("Image path has no file name")?
+                    .to_os_string();
+                name.push(".png");
+                let out = dir.join(name);
+                save_result(&path, &detections, &out)?;
+            }
+        }
+    } else if is_video {
+        detect_video(&mut yolo, &target, output, rotate_angle)?;
+    } else {
+        let img =
+            image::open(&target).with_context(|| format!("Can't open {}", target.display()))?;
+        let detections = yolo.start_with_img_proc(&img, rotate_angle)?;
+        print_detections(&yolo, &target.display().to_string(), &detections);
+        if let Some(out) = &output {
+            save_result(&target, &detections, out)?;
+        }
+    }
+    Ok(())
+}
+
+/// 動画ファイルを推論し、バウンディングボックスを描画した動画を保存します。
+#[cfg(feature = "gstreamer")]
+fn detect_video(
+    yolo: &mut YoloV3Tiny,
+    input: &Path,
+    output: Option<PathBuf>,
+    rotate_angle: u32,
+) -> Result<()> {
+    use yolo_v3_tiny_zynq::detection_log::LogFormat;
+    use yolo_v3_tiny_zynq::video::VideoProcessor;
+
+    let output = match output {
+        Some(output) => output,
+        None => {
+            let stem = input.file_stem().context("Video path has no file name")?;
+            input.with_file_name(format!("{}_det.mp4", stem.to_string_lossy()))
+        }
+    };
+    let mut processor = VideoProcessor::new();
+    processor
+        .set_rotate_angle(rotate_angle)
+        .set_log_format(Some(LogFormat::Csv));
+    let frames = processor.process(yolo, input, &output)?;
+    println!("{} frames -> {}", frames, output.display());
+    Ok(())
+}
+
+/// 動画ファイルを推論し、バウンディングボックスを描画した動画を保存します。
+#[cfg(not(feature = "gstreamer"))]
+fn detect_video(
+    _yolo: &mut YoloV3Tiny,
+    input: &Path,
+    _output: Option<PathBuf>,
+    _rotate_angle: u32,
+) -> Result<()> {
+    bail!(
+        "{}: video input needs the gstreamer feature",
+        input.display()
+    )
+}
+
+/// `bench` サブコマンド
+fn bench(args: &Args) -> Result<()> {
+    let frames: usize = args.value("frames", 100)?;
+    if frames == 0 {
+        bail!("--frames must be at least 1");
+    }
+    let rotate_angle = args.value("rotate", 0)?;
+    let mut yolo = open_yolo(args)?;
+    let input_size = yolo.layer_groups()[0].input_width;
+    let input_data = match args.positional.first() {
+        Some(path) => {
+            let img = image::open(path).with_context(|| format!("Can't open {}", path))?;
+            img_proc::letterbox(&img, input_size, rotate_angle)
+        }
+        None => vec![0; yolo.layer_groups()[0].input_size as usize],
+    };
+
+    let warmup = yolo.warmup()?;
+    let mut times = Vec::with_capacity(frames);
+    let mut objects = 0;
+    for _ in 0..frames {
+        let start = Instant::now();
+        objects += yolo.start(&input_data)?.len();
+        times.push(start.elapsed());
+    }
+    times.sort();
+
+    let total: Duration = times.iter().sum();
+    let mean = total / frames as u32;
+    let ms = |d: Duration| d.as_secs_f64() * 1000.;
+    println!("warmup: {:.3}ms", ms(warmup));
+    println!(
+        "{} frames: mean {:.3}ms, min {:.3}ms, median {:.3}ms, max {:.3}ms, {:.1}FPS",
+        frames,
+        ms(mean),
+        ms(times[0]),
+        ms(times[frames / 2]),
+        ms(times[frames - 1]),
+        frames as f64 / total.as_secs_f64()
+    );
+    println!("{:.1} objects per frame", objects as f64 / frames as f64);
+    println!("{:?}", yolo.hw_stats());
+    Ok(())
+}
+
+/// `verify-weights` サブコマンド
+fn verify_weights(args: &Args) -> Result<()> {
+    let bundle = args.positional(0, "bundle")?;
+    let (cls_num, class_slots) = match args.get("config") {
+        Some(path) => {
+            let config = Config::from_toml(path)?;
+            (config.model.cls_num()?, config.model.class_slots)
+        }
+        None => (
+            args.value("cls-num", 7)?,
+            args.value("class-slots", DEFAULT_CLASS_SLOTS)?,
+        ),
+    };
+    YoloV3Tiny::verify_weights(bundle, cls_num, class_slots)?;
+    println!(
+        "{}: OK ({} classes, {} class slots)",
+        bundle, cls_num, class_slots
+    );
+    Ok(())
+}
+
+/// `self-test` サブコマンド
+fn self_test(args: &Args) -> Result<()> {
+    let mut yolo = open_yolo(args)?;
+    if let Err(e) = yolo.self_test() {
+        eprintln!("{}", yolo.dump_state());
+        return Err(e);
+    }
+    println!("self-test passed");
+    Ok(())
+}
+
+/// `daemon` サブコマンド
+#[cfg(feature = "gstreamer")]
+fn daemon(args: &Args) -> Result<()> {
+    use yolo_v3_tiny_zynq::daemon::Daemon;
+    use yolo_v3_tiny_zynq::detection_log::{DetectionLogger, LogFormat};
+    use yolo_v3_tiny_zynq::framebuffer::FramebufferRenderer;
+    use yolo_v3_tiny_zynq::gst_pipeline::GstSource;
+    use yolo_v3_tiny_zynq::mjpeg::MjpegServer;
+
+    let mut daemon = Daemon::new();
+    // 初期化中に受け取ったシグナルでも、推論を始めずに停止できるよう最初に登録する
+    daemon.register_signals()?;
+    daemon.set_rotate_angle(args.value("rotate", 0)?);
+    if let Some(addr) = args.get("mjpeg") {
+        daemon.add_sink(MjpegServer::bind(addr)?);
+    }
+    if let Some(name) = args.get("fb") {
+        daemon.add_sink(FramebufferRenderer::open(name)?);
+    }
+    if let Some(dir) = args.get("log-dir") {
+        daemon.add_sink(DetectionLogger::new(dir, "detections", LogFormat::Csv)?);
+    }
+
+    let mut yolo = open_yolo(args)?;
+    let source = GstSource::new(
+        args.get("source")
+            .unwrap_or("v4l2src ! videoconvert ! appsink name=sink"),
+    )?;
+    let stats = daemon.run(&mut yolo, source)?;
+    println!(
+        "{} frames, {} dropped, {} sink errors",
+        stats.frames, stats.dropped, stats.sink_errors
+    );
+    Ok(())
+}
+
+/// `daemon` サブコマンド
+#[cfg(not(feature = "gstreamer"))]
+fn daemon(_args: &Args) -> Result<()> {
+    bail!("daemon needs the gstreamer feature")
+}
+
+/// `replay` サブコマンド
+fn replay(args: &Args) -> Result<()> {
+    let path = args.positional(0, "trace")?;
+    let report = match args.get("backend").unwrap_or("mock") {
+        "mock" => trace::replay(&trace::read_trace(path)?, &mut MockBackend::new())?,
+        "hardware" => open_yolo(args)?.replay_trace(path)?,
+        other => bail!("Unknown backend: {} (mock or hardware)", other),
+    };
+    for m in &report.mismatches {
+        println!(
+            "event {}: recorded `{}`, replayed `{}`",
+            m.index, m.expected, m.actual
+        );
+    }
+    println!(
+        "{}: {} events, {} mismatches",
+        path,
+        report.events,
+        report.mismatches.len()
+    );
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse(std::env::args().skip(1))?;
+    match args.command.as_str() {
+        "detect" => detect(&args),
+        "bench" => bench(&args),
+        "verify-weights" => verify_weights(&args),
+        "self-test" => self_test(&args),
+        "daemon" => daemon(&args),
+        "replay" => replay(&args),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        other => bail!("Unknown command: {}\n\n{}", other, USAGE),
+    }
+}
@@ -0,0 +1,325 @@
+//! カメラの内部パラメータと歪み係数を使って、レンズの歪みを補正するモジュール
+//!
+//! 広角レンズの画像では、画像の端の物体が歪んでバウンディングボックスがずれます。
+//! 推論の前に画像の歪みを補正する (`UndistortMap`) か、推論の後に検出結果の頂点だけを補正
+//! (`CameraCalibration::undistort_detection`) できます。
+//!
+//! 歪みのモデルはOpenCVと同じ (k1, k2, p1, p2, k3) です。パラメータはOpenCVの `FileStorage` が書き出すYAML
+//! (`camera_matrix` と `distortion_coefficients`) か、次のようなTOMLから読み込めます。
+//!
+//! ```toml
+//! fx = 612.3
+//! fy = 611.8
+//! cx = 320.5
+//! cy = 241.2
+//! distortion = [-0.31, 0.09, 0.0005, -0.0002, -0.011]
+//! ```
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use image::{DynamicImage, Rgb, RgbImage};
+use serde::Deserialize;
+
+use crate::detection_result::DetectionData;
+
+/// 歪みを補正するときの反復回数 (OpenCVの `undistortPoints` と同じ)
+const UNDISTORT_ITERATIONS: usize = 5;
+
+/// カメラの内部パラメータと歪み係数
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraCalibration {
+    /// x方向の焦点距離 (ピクセル)
+    pub fx: f32,
+    /// y方向の焦点距離 (ピクセル)
+    pub fy: f32,
+    /// 主点のx座標
+    pub cx: f32,
+    /// 主点のy座標
+    pub cy: f32,
+    /// 歪み係数 (k1, k2, p1, p2, k3)。省略した係数は0
+    #[serde(default, deserialize_with = "deserialize_distortion")]
+    pub distortion: [f32; 5],
+}
+
+/// 4個以上の歪み係数を読み込みます。
+fn deserialize_distortion<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<[f32; 5], D::Error> {
+    let v: Vec<f32> = Vec::deserialize(deserializer)?;
+    distortion_from_slice(&v).map_err(serde::de::Error::custom)
+}
+
+/// 歪み係数の配列を (k1, k2, p1, p2, k3) に変換します。
+fn distortion_from_slice(v: &[f32]) -> Result<[f32; 5]> {
+    ensure!(
+        v.len() >= 4,
+        "At least 4 distortion coefficients are required, but {} are given",
+        v.len()
+    );
+    // OpenCVの有理モデルなどの高次の係数は、0の場合だけ受け付ける
+    ensure!(
+        v.iter().skip(5).all(|&k| k == 0.),
+        "Only (k1, k2, p1, p2, k3) are supported, but {} coefficients are given",
+        v.len()
+    );
+    let mut d = [0.; 5];
+    for (d, k) in d.iter_mut().zip(v) {
+        *d = *k;
+    }
+    Ok(d)
+}
+
+impl CameraCalibration {
+    /// 新しい `CameraCalibration` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `fx`, `fy` - 焦点距離 (ピクセル)
+    /// * `cx`, `cy` - 主点の座標
+    /// * `distortion` - 歪み係数 (k1, k2, p1, p2, k3)
+    pub fn new(fx: f32, fy: f32, cx: f32, cy: f32, distortion: [f32; 5]) -> Self {
+        Self {
+            fx,
+            fy,
+            cx,
+            cy,
+            distortion,
+        }
+    }
+
+    /// ファイルからパラメータを読み込みます。拡張子が `.yaml` または `.yml` の場合はOpenCVのYAML、それ以外はTOMLとして読み込みます。
+    ///
+    /// # Args
+    /// * `path` - ファイルのパス
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).with_context(|| format!("Can't read {}", path.display()))?;
+        let calib = match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => Self::from_opencv_yaml(&text),
+            _ => toml::from_str(&text).map_err(anyhow::Error::from),
+        }
+        .with_context(|| format!("Can't parse {}", path.display()))?;
+        ensure!(
+            calib.fx > 0. && calib.fy > 0.,
+            "Focal lengths must be positive in {}",
+            path.display()
+        );
+        Ok(calib)
+    }
+
+    /// OpenCVの `FileStorage` が書き出したYAMLからパラメータを読み込みます。
+    ///
+    /// `camera_matrix` と `distortion_coefficients` (または `dist_coeffs`) の `!!opencv-matrix` を読み込みます。
+    ///
+    /// # Args
+    /// * `text` - YAMLの文字列
+    pub fn from_opencv_yaml(text: &str) -> Result<Self> {
+        let k = opencv_matrix(text, &["camera_matrix", "K"])?;
+        ensure!(
+            k.len() == 9,
+            "camera_matrix must have 9 elements, got {}",
+            k.len()
+        );
+        let d = opencv_matrix(text, &["distortion_coefficients", "dist_coeffs", "D"])?;
+        Ok(Self::new(
+            k[0],
+            k[4],
+            k[2],
+            k[5],
+            distortion_from_slice(&d)?,
+        ))
+    }
+
+    /// 歪みのない画像の座標を、歪んだ画像の座標に変換します。
+    ///
+    /// # Args
+    /// * `x` - 歪みのない画像のx座標
+    /// * `y` - 歪みのない画像のy座標
+    ///
+    /// # Return
+    /// * 歪んだ画像の座標
+    pub fn distort_point(&self, x: f32, y: f32) -> (f32, f32) {
+        let (xn, yn) = ((x - self.cx) / self.fx, (y - self.cy) / self.fy);
+        let (xd, yd) = self.distort_normalized(xn, yn);
+        (xd * self.fx + self.cx, yd * self.fy + self.cy)
+    }
+
+    /// 歪んだ画像の座標を、歪みのない画像の座標に変換します。
+    ///
+    /// 歪みのモデルは逆関数を解析的に求められないため、OpenCVの `undistortPoints` と同じく反復して求めます。
+    ///
+    /// # Args
+    /// * `x` - 歪んだ画像のx座標
+    /// * `y` - 歪んだ画像のy座標
+    ///
+    /// # Return
+    /// * 歪みのない画像の座標
+    pub fn undistort_point(&self, x: f32, y: f32) -> (f32, f32) {
+        let [k1, k2, p1, p2, k3] = self.distortion;
+        let (xd, yd) = ((x - self.cx) / self.fx, (y - self.cy) / self.fy);
+        let (mut xn, mut yn) = (xd, yd);
+        for _ in 0..UNDISTORT_ITERATIONS {
+            let r2 = xn * xn + yn * yn;
+            let radial = 1. + r2 * (k1 + r2 * (k2 + r2 * k3));
+            let dx = 2. * p1 * xn * yn + p2 * (r2 + 2. * xn * xn);
+            let dy = p1 * (r2 + 2. * yn * yn) + 2. * p2 * xn * yn;
+            xn = (xd - dx) / radial;
+            yn = (yd - dy) / radial;
+        }
+        (xn * self.fx + self.cx, yn * self.fy + self.cy)
+    }
+
+    /// 正規化した座標に歪みを加えます。
+    fn distort_normalized(&self, x: f32, y: f32) -> (f32, f32) {
+        let [k1, k2, p1, p2, k3] = self.distortion;
+        let r2 = x * x + y * y;
+        let radial = 1. + r2 * (k1 + r2 * (k2 + r2 * k3));
+        (
+            x * radial + 2. * p1 * x * y + p2 * (r2 + 2. * x * x),
+            y * radial + p1 * (r2 + 2. * y * y) + 2. * p2 * x * y,
+        )
+    }
+
+    /// 歪んだ画像の検出結果を、歪みのない画像の座標系に変換します。
+    ///
+    /// 画像の歪みを補正せずに推論した場合に使います。バウンディングボックスの頂点と辺の中点を変換し、それらを囲む矩形を返します。
+    ///
+    /// # Args
+    /// * `d` - 歪んだ画像の検出結果
+    ///
+    /// # Return
+    /// * 歪みのない画像の座標系の検出結果
+    pub fn undistort_detection(&self, d: &DetectionData) -> DetectionData {
+        let (cx, cy) = d.center();
+        let points = [
+            (d.x1, d.y1),
+            (cx, d.y1),
+            (d.x2, d.y1),
+            (d.x2, cy),
+            (d.x2, d.y2),
+            (cx, d.y2),
+            (d.x1, d.y2),
+            (d.x1, cy),
+        ]
+        .map(|(x, y)| self.undistort_point(x, y));
+        let (mut x1, mut y1, mut x2, mut y2) = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
+        for (x, y) in points {
+            x1 = x1.min(x);
+            y1 = y1.min(y);
+            x2 = x2.max(x);
+            y2 = y2.max(y);
+        }
+        DetectionData::new(d.class, x1, y1, x2, y2, d.confidence).with_sub_state(d.sub_state)
+    }
+
+    /// 画像の歪みを補正するための対応表を作成します。
+    ///
+    /// # Args
+    /// * `width` - 画像の幅
+    /// * `height` - 画像の高さ
+    pub fn undistort_map(&self, width: u32, height: u32) -> UndistortMap {
+        let map = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| self.distort_point(x as f32, y as f32))
+            .collect();
+        UndistortMap { width, height, map }
+    }
+}
+
+/// OpenCVのYAMLから `!!opencv-matrix` の `data` を読み込みます。
+///
+/// # Args
+/// * `text` - YAMLの文字列
+/// * `names` - 行列の名前の候補
+fn opencv_matrix(text: &str, names: &[&str]) -> Result<Vec<f32>> {
+    let Some(start) = names.iter().find_map(|name| {
+        text.split_inclusive('\n')
+            .scan(0, |offset, line| {
+                let pos = *offset;
+                *offset += line.len();
+                Some((pos, line))
+            })
+            .find(|(_, line)| line.trim_start().starts_with(&format!("{}:", name)))
+            .map(|(pos, _)| pos)
+    }) else {
+        bail!("None of {:?} is found", names);
+    };
+
+    let rest = &text[start..];
+    let data = rest
+        .find("data:")
+        .with_context(|| format!("data of {} is not found", names[0]))?;
+    let open = rest[data..].find('[').context("data must be a list")? + data;
+    let close = rest[open..].find(']').context("data is not closed")? + open;
+    rest[open + 1..close]
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            v.parse::<f32>()
+                .with_context(|| format!("Can't parse {} as a number", v))
+        })
+        .collect()
+}
+
+/// 画像の歪みを補正するための対応表
+///
+/// 補正後の画素ごとに、歪んだ画像の参照する座標を持ちます。動画で同じ大きさの画像を繰り返し補正するときに作り直す必要はありません。
+#[derive(Debug, Clone)]
+pub struct UndistortMap {
+    width: u32,
+    height: u32,
+    /// 補正後の画素ごとの、歪んだ画像の座標 (行優先)
+    map: Vec<(f32, f32)>,
+}
+
+impl UndistortMap {
+    /// 画像の歪みを補正します。画素はバイリニア補間で求め、画像の外を参照する画素は黒にします。
+    ///
+    /// # Args
+    /// * `img` - 歪んだ画像
+    ///
+    /// # Return
+    /// * 歪みを補正した画像。画像の大きさが対応表と異なる場合はエラー
+    pub fn apply(&self, img: &DynamicImage) -> Result<DynamicImage> {
+        ensure!(
+            img.width() == self.width && img.height() == self.height,
+            "The image is {}x{}, but the map is for {}x{}",
+            img.width(),
+            img.height(),
+            self.width,
+            self.height
+        );
+        let src = img.to_rgb8();
+        let (w, h) = (self.width, self.height);
+        let out = RgbImage::from_fn(w, h, |x, y| {
+            let (sx, sy) = self.map[(x + y * w) as usize];
+            sample_bilinear(&src, sx, sy)
+        });
+        Ok(DynamicImage::ImageRgb8(out))
+    }
+}
+
+/// 画像の座標の画素をバイリニア補間で求めます。
+pub(crate) fn sample_bilinear(img: &RgbImage, x: f32, y: f32) -> Rgb<u8> {
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    if !(0. ..=w - 1.).contains(&x) || !(0. ..=h - 1.).contains(&y) {
+        return Rgb([0, 0, 0]);
+    }
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = (
+        (x0 + 1).min(img.width() - 1),
+        (y0 + 1).min(img.height() - 1),
+    );
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let (p00, p10) = (img.get_pixel(x0, y0), img.get_pixel(x1, y0));
+    let (p01, p11) = (img.get_pixel(x0, y1), img.get_pixel(x1, y1));
+    Rgb(std::array::from_fn(|c| {
+        let top = p00[c] as f32 * (1. - fx) + p10[c] as f32 * fx;
+        let bottom = p01[c] as f32 * (1. - fx) + p11[c] as f32 * fx;
+        (top * (1. - fy) + bottom * fy).round() as u8
+    }))
+}
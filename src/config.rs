@@ -0,0 +1,422 @@
+//! TOMLの設定ファイルから実行時のパラメータを読み込むモジュール
+//!
+//! 再コンパイルせずに、ハードウェアの階層・閾値・クラス名・アンカーボックス・バリデータ・デバッグ出力・入力画像の補正を変更できます。
+//! 省略した項目には `YoloV3Tiny::new` と同じ既定値が使われます。
+//!
+//! ```toml
+//! [hardware]
+//! hwinfo_path = "/slab/hwinfo.json"
+//! hierarchy = "yolo"
+//! watchdog_timeout_ms = 500
+//!
+//! [model]
+//! weights = "/slab/weights.tar.gz"
+//! class_names = ["red", "yellow", "blue", "person", "car", "bicycle", "sign"]
+//! obj_threshold = 0.2
+//! nms_threshold = 0.1
+//! anchors = [[10, 14], [23, 27], [37, 58], [81, 82], [135, 169], [344, 319]]
+//! fixed_point_postprocess = true
+//!
+//! [validator]
+//! axis = "horizontal"
+//! lamp_classes = [2, 0]
+//! hue_check = true
+//! red_hue = [330, 30]
+//! lamp_mask = "ellipse"
+//!
+//! [debug]
+//! dir = "/tmp/yolo_debug"
+//! crop_dir = "/tmp/yolo_crops"
+//!
+//! [[enhancement]]
+//! type = "clahe"
+//! clip_limit = 2.0
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::anchors::ANCHOR_NUM;
+use crate::arrow::ArrowClassifier;
+use crate::day_night::DayNightConfig;
+use crate::enhance::{Enhancement, ToneMapping};
+use crate::ground_plane::GroundPlaneConfig;
+use crate::mining::CropSaver;
+use crate::postprocess::{OutputLayout, DEFAULT_ANCHORS, DEFAULT_CLASS_SLOTS};
+use crate::region::{BrightnessMetric, RegionMask};
+use crate::render::RenderStyle;
+use crate::validator::{HueRange, TrafficLightConfig, TrafficLightLayout};
+
+/// 設定ファイルの内容
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// ハードウェアの設定
+    pub hardware: HardwareConfig,
+    /// モデルと後処理の設定
+    pub model: ModelConfig,
+    /// 信号機のバリデータの設定
+    pub validator: ValidatorConfig,
+    /// デバッグ出力の設定
+    pub debug: DebugConfig,
+    /// 8ビットを超える階調の入力画像の変換方法
+    pub tone_mapping: ToneMapping,
+    /// 入力画像の補正処理 (`[[enhancement]]`、記述した順に適用します)
+    pub enhancement: Vec<Enhancement>,
+    /// 昼と夜のプロファイルの切り替えの設定。`DayNightSwitcher::new` で使います
+    pub day_night: Option<DayNightConfig>,
+    /// 地面の射影変換の設定。`GroundPlane::from_config` で使います
+    pub ground_plane: Option<GroundPlaneConfig>,
+    /// 検出結果の描画の設定。`Renderer::new` で使います
+    pub render: RenderStyle,
+}
+
+/// ハードウェアの設定 (`[hardware]`)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HardwareConfig {
+    /// HW情報のパス
+    pub hwinfo_path: String,
+    /// YOLO階層のパス
+    pub hierarchy: String,
+    /// 2つ目のYOLO階層のパス。指定した場合は2つ目のIPを有効にします
+    pub second_hierarchy: Option<String>,
+    /// IPとDMAの完了を待つ最大の時間 (ミリ秒)。省略した場合はウォッチドッグを無効にします
+    pub watchdog_timeout_ms: Option<u64>,
+}
+
+impl Default for HardwareConfig {
+    fn default() -> Self {
+        Self {
+            hwinfo_path: "/slab/hwinfo.json".to_string(),
+            hierarchy: "yolo".to_string(),
+            second_hierarchy: None,
+            watchdog_timeout_ms: None,
+        }
+    }
+}
+
+/// モデルと後処理の設定 (`[model]`)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    /// 重みとバイアスのパス。拡張子が `.npz` の場合はNumPy形式、それ以外はtar.gzとして読み込みます
+    pub weights: PathBuf,
+    /// クラス数。省略した場合は `class_names` の数を使います
+    pub cls_num: Option<usize>,
+    /// クラスIDの順に並べたクラス名
+    pub class_names: Vec<String>,
+    /// 1つのアンカーボックスあたりのクラスの枠の数
+    pub class_slots: usize,
+    /// アンカーボックスの幅と高さ (面積の小さい順)
+    pub anchors: [[f32; 2]; ANCHOR_NUM],
+    /// オブジェクトの閾値
+    pub obj_threshold: f32,
+    /// NMSの閾値
+    pub nms_threshold: f32,
+    /// 後処理を固定小数点数のまま行うか (`YoloV3Tiny::set_fixed_point_postprocess`)
+    pub fixed_point_postprocess: bool,
+    /// シグモイドを後処理で適用するか。YOLOのIPの活性化関数は無効にします (`OutputLayout::software_sigmoid`)
+    pub software_sigmoid: bool,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            weights: PathBuf::new(),
+            cls_num: None,
+            class_names: vec![],
+            class_slots: DEFAULT_CLASS_SLOTS,
+            anchors: DEFAULT_ANCHORS,
+            obj_threshold: 0.2,
+            nms_threshold: 0.1,
+            fixed_point_postprocess: false,
+            software_sigmoid: false,
+        }
+    }
+}
+
+impl ModelConfig {
+    /// クラス数を返します。
+    ///
+    /// # Return
+    /// * `cls_num` とクラス名の数が合わない場合や、どちらも指定されていない場合はエラー
+    pub fn cls_num(&self) -> Result<usize> {
+        match (self.cls_num, self.class_names.len()) {
+            (None, 0) => bail!("Either model.cls_num or model.class_names must be set"),
+            (None, n) => Ok(n),
+            (Some(n), 0) => Ok(n),
+            (Some(n), m) if n == m => Ok(n),
+            (Some(n), m) => bail!("model.cls_num is {}, but {} class names are given", n, m),
+        }
+    }
+
+    /// 出力の並びとアンカーボックスを返します。
+    pub fn layout(&self) -> OutputLayout {
+        OutputLayout {
+            class_slots: self.class_slots,
+            anchors: self.anchors,
+            software_sigmoid: self.software_sigmoid,
+        }
+    }
+}
+
+/// 灯器が並んでいる方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AxisConfig {
+    /// 横に並んでいる
+    Horizontal,
+    /// 縦に並んでいる
+    Vertical,
+}
+
+/// 灯器の領域のうち、輝度と色相を求める範囲の形
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LampMaskConfig {
+    /// 矩形全体
+    Rectangle,
+    /// 矩形に内接する楕円
+    Ellipse,
+    /// 短辺を直径とする円
+    Circle,
+}
+
+/// 灯器の領域の明るさの指標
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BrightnessMetricConfig {
+    /// 明るさの平均
+    Mean,
+    /// 明るさの90パーセンタイル
+    P90,
+    /// 明るさの99パーセンタイル
+    P99,
+    /// 明るさが `bright_threshold` 以上のピクセルの割合
+    BrightFraction,
+}
+
+/// 信号機のバリデータの設定 (`[validator]`)
+///
+/// 省略した項目は `TrafficLightConfig` の既定値のままになります。
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidatorConfig {
+    /// バリデータを使うか
+    pub enabled: bool,
+    /// BBoxの両端を切り落とす割合
+    pub trim_rate: Option<f32>,
+    /// 灯器が並んでいる方向
+    pub axis: Option<AxisConfig>,
+    /// 灯器ごとのクラス (横配置では左から、縦配置では上から順)
+    pub lamp_classes: Option<Vec<u8>>,
+    /// 検証の対象とするクラスの最大値
+    pub max_target_class: Option<u8>,
+    /// 最も明るい領域と他の領域の平均輝度の比の下限
+    pub min_bright_ratio: Option<f64>,
+    /// 最も明るい領域の平均輝度 (0.0-1.0) の下限
+    pub min_absolute_brightness: Option<f64>,
+    /// 点灯色の色相のチェックを行うか
+    pub hue_check: Option<bool>,
+    /// 赤信号の色相の範囲 [下限, 上限] (度)
+    pub red_hue: Option<[f64; 2]>,
+    /// 黄信号の色相の範囲 [下限, 上限] (度)
+    pub yellow_hue: Option<[f64; 2]>,
+    /// 青信号の色相の範囲 [下限, 上限] (度)
+    pub blue_hue: Option<[f64; 2]>,
+    /// 灯器の領域のうち、輝度と色相を求める範囲の形
+    pub lamp_mask: Option<LampMaskConfig>,
+    /// 灯器の領域の明るさの指標。`min_bright_ratio` と `min_absolute_brightness` はこの指標と比較されます
+    pub brightness_metric: Option<BrightnessMetricConfig>,
+    /// `brightness_metric = "bright_fraction"` で明るいとみなすピクセルの明るさ (0.0-1.0、既定は0.8)
+    pub bright_threshold: Option<f64>,
+    /// 矢印信号の向きを判定するクラス。省略した場合は判定しません
+    pub arrow_class: Option<u8>,
+    /// 矢印と判定するテンプレートとの一致度 (IoU) の下限 (既定は0.5)
+    pub arrow_min_score: Option<f64>,
+}
+
+impl Default for ValidatorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            trim_rate: None,
+            axis: None,
+            lamp_classes: None,
+            max_target_class: None,
+            min_bright_ratio: None,
+            min_absolute_brightness: None,
+            hue_check: None,
+            red_hue: None,
+            yellow_hue: None,
+            blue_hue: None,
+            lamp_mask: None,
+            brightness_metric: None,
+            bright_threshold: None,
+            arrow_class: None,
+            arrow_min_score: None,
+        }
+    }
+}
+
+impl ValidatorConfig {
+    /// バリデータの設定を作成します。
+    ///
+    /// # Return
+    /// * バリデータの設定。バリデータを使わない場合はNone。設定の値が範囲外の場合はエラー
+    pub fn traffic_light_config(&self) -> Result<Option<TrafficLightConfig>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let mut config = TrafficLightConfig::default();
+        if let Some(trim_rate) = self.trim_rate {
+            config.set_trim_rate(trim_rate)?;
+        }
+        if self.axis.is_some() || self.lamp_classes.is_some() {
+            let default = config.layout().clone();
+            let lamp_classes = self.lamp_classes.clone().unwrap_or(default.lamp_classes);
+            let layout = match self.axis {
+                Some(AxisConfig::Vertical) => TrafficLightLayout::vertical(lamp_classes),
+                Some(AxisConfig::Horizontal) => TrafficLightLayout::horizontal(lamp_classes),
+                None => TrafficLightLayout {
+                    axis: default.axis,
+                    lamp_classes,
+                },
+            };
+            config.set_layout(layout)?;
+        }
+        if let Some(max_target_class) = self.max_target_class {
+            config.set_max_target_class(max_target_class);
+        }
+        if let Some(ratio) = self.min_bright_ratio {
+            config.set_min_bright_ratio(ratio)?;
+        }
+        if let Some(brightness) = self.min_absolute_brightness {
+            config.set_min_absolute_brightness(brightness)?;
+        }
+        if let Some(hue_check) = self.hue_check {
+            config.set_hue_check_en(hue_check);
+        }
+        if let Some([min, max]) = self.red_hue {
+            config.set_red_hue(HueRange::new(min, max))?;
+        }
+        if let Some([min, max]) = self.yellow_hue {
+            config.set_yellow_hue(HueRange::new(min, max))?;
+        }
+        if let Some([min, max]) = self.blue_hue {
+            config.set_blue_hue(HueRange::new(min, max))?;
+        }
+        if let Some(mask) = self.lamp_mask {
+            config.set_lamp_mask(match mask {
+                LampMaskConfig::Rectangle => RegionMask::Rectangle,
+                LampMaskConfig::Ellipse => RegionMask::Ellipse,
+                LampMaskConfig::Circle => RegionMask::Circle,
+            });
+        }
+        if let Some(metric) = self.brightness_metric {
+            config.set_brightness_metric(match metric {
+                BrightnessMetricConfig::Mean => BrightnessMetric::Mean,
+                BrightnessMetricConfig::P90 => BrightnessMetric::Percentile(0.9),
+                BrightnessMetricConfig::P99 => BrightnessMetric::Percentile(0.99),
+                BrightnessMetricConfig::BrightFraction => {
+                    BrightnessMetric::BrightFraction(self.bright_threshold.unwrap_or(0.8))
+                }
+            })?;
+        }
+        if let Some(arrow_class) = self.arrow_class {
+            let default = ArrowClassifier::default();
+            let min_score = self.arrow_min_score.unwrap_or(default.min_score());
+            config.set_arrow_classifier(
+                arrow_class,
+                Some(ArrowClassifier::new(default.lit_ratio(), min_score)),
+            );
+        }
+        Ok(Some(config))
+    }
+}
+
+/// デバッグ出力の設定 (`[debug]`)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DebugConfig {
+    /// 中間画像とログを書き出すディレクトリ (`DirSink`)
+    pub dir: Option<PathBuf>,
+    /// 検出結果の切り抜き画像を保存するディレクトリ (`CropSaver`)
+    pub crop_dir: Option<PathBuf>,
+    /// バリデータで除外された候補も保存するか
+    pub crop_save_rejected: Option<bool>,
+    /// 保存する切り抜き画像の幅と高さの最小値
+    pub crop_min_size: Option<u32>,
+    /// バウンディングボックスの周囲に含める余白の割合
+    pub crop_margin: Option<f32>,
+    /// レイヤーグループごとの出力の飽和を数えるか
+    pub saturation_monitor: bool,
+    /// フレームごとの後処理の統計を記録するか
+    pub postprocess_stats: bool,
+    /// 入力データとYOLO層の出力を記録するファイル (`session::SessionRecorder`)
+    pub session: Option<PathBuf>,
+}
+
+impl DebugConfig {
+    /// 切り抜き画像の保存先を作成します。
+    ///
+    /// # Return
+    /// * `crop_dir` を指定しない場合はNone
+    pub fn crop_saver(&self) -> Result<Option<CropSaver>> {
+        let Some(dir) = &self.crop_dir else {
+            return Ok(None);
+        };
+        let mut saver = CropSaver::new(dir)?;
+        if let Some(save_rejected) = self.crop_save_rejected {
+            saver.set_save_rejected(save_rejected);
+        }
+        if let Some(min_size) = self.crop_min_size {
+            saver.set_min_size(min_size);
+        }
+        if let Some(margin) = self.crop_margin {
+            saver.set_margin(margin);
+        }
+        Ok(Some(saver))
+    }
+}
+
+impl Config {
+    /// TOMLの設定ファイルを読み込みます。
+    ///
+    /// # Args
+    /// * `path` - 設定ファイルのパス
+    ///
+    /// # Return
+    /// * 設定。未知の項目がある場合や、重みのパスがない場合はエラー
+    pub fn from_toml<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).with_context(|| format!("Can't read {}", path.display()))?;
+        let config: Self =
+            toml::from_str(&text).with_context(|| format!("Can't parse {}", path.display()))?;
+        if config.model.weights.as_os_str().is_empty() {
+            bail!("model.weights is not set in {}", path.display());
+        }
+        config.model.cls_num()?;
+        Ok(config)
+    }
+
+    /// ウォッチドッグの時間を返します。
+    pub fn watchdog_timeout(&self) -> Option<Duration> {
+        self.hardware.watchdog_timeout_ms.map(Duration::from_millis)
+    }
+
+    /// 描画の設定を返します。`render.class_names` を省略した場合は `model.class_names` を使います。
+    pub fn render_style(&self) -> RenderStyle {
+        let mut style = self.render.clone();
+        if style.class_names.is_empty() {
+            style.class_names = self.model.class_names.clone();
+        }
+        style
+    }
+}
@@ -0,0 +1,393 @@
+//! カメラの画像を推論し続け、結果を出力先に送る常駐モードのモジュール
+//!
+//! SIGTERMとSIGINTを受け取ると、処理中のフレームを終えてからカメラのスレッドを止め、
+//! DMAの転送を待ってパイプラインを停止します。systemdのユニットから起動する場合は
+//! `KillSignal=SIGTERM` (既定値) のままで、`TimeoutStopSec` を推論数フレーム分より長くしてください。
+//! 停止中にもう一度シグナルを受け取った場合は、すぐに終了します。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use image::{DynamicImage, RgbImage};
+use log::{info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+use crate::detection_log::DetectionLogger;
+use crate::detection_result::FrameResult;
+use crate::framebuffer::FramebufferRenderer;
+use crate::img_proc;
+use crate::mjpeg::MjpegServer;
+use crate::yolov3_tiny::YoloV3Tiny;
+
+/// 停止の依頼を確認する間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// 停止するときにカメラのスレッドの終了を待つ最大の時間
+const CAMERA_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 常駐モードの入力になるフレームの取得元
+///
+/// カメラのスレッドで呼び出されるため、`Send` である必要があります。
+pub trait FrameSource: Send {
+    /// 次のフレームを取得します。フレームが届くまでブロックします。
+    ///
+    /// 停止するときにブロックしたまま戻らない場合、カメラのスレッドは待たずに切り離され、
+    /// 取得元は `next_frame` から戻った後に破棄されます。
+    ///
+    /// # Return
+    /// * フレーム。ストリームが終了した場合はNone
+    fn next_frame(&mut self) -> Result<Option<RgbImage>>;
+}
+
+impl<F: FnMut() -> Result<Option<RgbImage>> + Send> FrameSource for F {
+    fn next_frame(&mut self) -> Result<Option<RgbImage>> {
+        self()
+    }
+}
+
+#[cfg(feature = "gstreamer")]
+impl FrameSource for crate::gst_pipeline::GstSource {
+    fn next_frame(&mut self) -> Result<Option<RgbImage>> {
+        crate::gst_pipeline::GstSource::next_frame(self)
+    }
+}
+
+/// 常駐モードの推論結果の出力先
+pub trait FrameSink {
+    /// 1フレーム分の推論結果を送ります。
+    ///
+    /// # Args
+    /// * `img` - 検出結果の座標系に対応するフレーム (回転済み)
+    /// * `result` - 推論結果
+    fn send(&mut self, img: &RgbImage, result: &FrameResult) -> Result<()>;
+
+    /// 停止する前に呼び出されます。バッファに残っているデータを書き出してください。
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&RgbImage, &FrameResult) -> Result<()>> FrameSink for F {
+    fn send(&mut self, img: &RgbImage, result: &FrameResult) -> Result<()> {
+        self(img, result)
+    }
+}
+
+impl FrameSink for MjpegServer {
+    fn send(&mut self, img: &RgbImage, result: &FrameResult) -> Result<()> {
+        self.push(img, &result.detections)
+    }
+}
+
+impl FrameSink for FramebufferRenderer {
+    fn send(&mut self, img: &RgbImage, result: &FrameResult) -> Result<()> {
+        self.render(img, &result.detections)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.clear()
+    }
+}
+
+impl FrameSink for DetectionLogger {
+    fn send(&mut self, _img: &RgbImage, result: &FrameResult) -> Result<()> {
+        self.log(result.frame_id, &result.detections)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl FrameSink for crate::websocket::WsBroadcaster {
+    fn send(&mut self, img: &RgbImage, result: &FrameResult) -> Result<()> {
+        self.broadcast(result.frame_id, &result.detections, Some(img))
+    }
+}
+
+#[cfg(feature = "ros2")]
+impl FrameSink for crate::ros2::Ros2Publisher {
+    fn send(&mut self, img: &RgbImage, result: &FrameResult) -> Result<()> {
+        self.publish(&result.detections, Some(img))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl FrameSink for crate::storage::DetectionStore {
+    fn send(&mut self, _img: &RgbImage, result: &FrameResult) -> Result<()> {
+        self.insert_frame(result.frame_id, &result.detections)
+    }
+}
+
+/// 常駐モードを停止するためのハンドル
+///
+/// クローンしたハンドルは同じ常駐モードを指し、他のスレッドから `stop` を呼び出せます。
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    /// 停止を依頼します。処理中のフレームを終えてから停止します。
+    pub fn stop(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    /// 停止が依頼されているかを返します。
+    pub fn is_stopped(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+/// 常駐モードの統計
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DaemonStats {
+    /// 推論したフレームの数
+    pub frames: u64,
+    /// 推論が追いつかずに捨てたカメラのフレームの数
+    pub dropped: u64,
+    /// 出力先への送信に失敗した回数
+    pub sink_errors: u64,
+}
+
+/// カメラの画像を推論し続け、結果を出力先に送る構造体
+///
+/// カメラは別スレッドで読み続け、推論中に届いたフレームは最新の1枚だけを残します。
+pub struct Daemon {
+    sinks: Vec<Box<dyn FrameSink>>,
+    rotate_angle: u32,
+    stop: StopHandle,
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Daemon {
+    /// 新しい `Daemon` インスタンスを作成します。
+    pub fn new() -> Self {
+        Self {
+            sinks: vec![],
+            rotate_angle: 0,
+            stop: StopHandle::default(),
+        }
+    }
+
+    /// 推論結果の出力先を追加します。
+    pub fn add_sink<S: FrameSink + 'static>(&mut self, sink: S) -> &mut Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// 入力画像の回転角度を設定します。
+    pub fn set_rotate_angle(&mut self, rotate_angle: u32) -> &mut Self {
+        self.rotate_angle = rotate_angle;
+        self
+    }
+
+    /// 停止するためのハンドルを返します。
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// SIGTERMとSIGINTで停止するように、シグナルハンドラを登録します。
+    ///
+    /// 停止の依頼中にもう一度シグナルを受け取った場合は、終了コード1ですぐに終了します。
+    pub fn register_signals(&self) -> Result<&Self> {
+        for sig in [SIGTERM, SIGINT] {
+            // 先に登録した方が先に実行されるため、2回目のシグナルだけが即時終了の条件を満たす
+            signal_hook::flag::register_conditional_shutdown(sig, 1, self.stop.flag.clone())?;
+            signal_hook::flag::register(sig, self.stop.flag.clone())?;
+        }
+        Ok(self)
+    }
+
+    /// 停止が依頼されるか、フレームの取得元が終了するまで推論を続けます。
+    ///
+    /// 停止するときは処理中のフレームを終えてからカメラのスレッドを止め、
+    /// 出力先の `finish` を呼び出し、`YoloV3Tiny::shutdown` でDMAの転送を待ってパイプラインを停止します。
+    ///
+    /// # Args
+    /// * `yolo` - 推論に使う `YoloV3Tiny`
+    /// * `source` - フレームの取得元
+    ///
+    /// # Return
+    /// * 常駐モードの統計。推論かカメラでエラーが起きた場合は、停止処理を行ってからエラーを返します
+    pub fn run<S: FrameSource + 'static>(
+        &mut self,
+        yolo: &mut YoloV3Tiny,
+        mut source: S,
+    ) -> Result<DaemonStats> {
+        if self.sinks.is_empty() {
+            warn!("Daemon has no sinks; detections are discarded");
+        }
+        let slot = Arc::new(FrameSlot::default());
+        let camera = {
+            let stop = self.stop.clone();
+            let slot = slot.clone();
+            thread::spawn(move || {
+                while !stop.is_stopped() {
+                    match source.next_frame() {
+                        Ok(Some(frame)) => slot.put(Ok(frame)),
+                        Ok(None) => break,
+                        Err(e) => {
+                            slot.put(Err(e));
+                            break;
+                        }
+                    }
+                }
+                slot.close();
+                // 取得元はこのスレッドで破棄され、カメラのパイプラインが停止する
+            })
+        };
+
+        info!("Daemon started");
+        let mut stats = DaemonStats::default();
+        let result = self.run_loop(yolo, &slot, &mut stats);
+
+        // 処理中のフレームは終わっているため、カメラのスレッドを止めてからパイプラインを停止する。
+        // フレームが届かずに `next_frame` がブロックしている場合は、待たずにスレッドを切り離す
+        self.stop.stop();
+        if slot.wait_closed(CAMERA_STOP_TIMEOUT) {
+            if camera.join().is_err() {
+                warn!("Camera thread panicked");
+            }
+        } else {
+            warn!("Frame source is blocked; detaching the camera thread");
+        }
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.finish() {
+                warn!("Can't finish a sink: {:#}", e);
+                stats.sink_errors += 1;
+            }
+        }
+        let shutdown = yolo.shutdown();
+        stats.dropped = slot.state.lock().unwrap().dropped;
+        info!(
+            "Daemon stopped: {} frames, {} dropped, {} sink errors",
+            stats.frames, stats.dropped, stats.sink_errors
+        );
+        // 停止処理のエラーで、停止の原因になった推論やカメラのエラーを隠さない
+        if let (Err(_), Err(e)) = (&result, &shutdown) {
+            warn!("Can't shut down the pipeline: {:#}", e);
+        }
+        result?;
+        shutdown?;
+        Ok(stats)
+    }
+
+    /// 停止が依頼されるまでフレームを推論し、出力先に送ります。
+    fn run_loop(
+        &mut self,
+        yolo: &mut YoloV3Tiny,
+        slot: &FrameSlot,
+        stats: &mut DaemonStats,
+    ) -> Result<()> {
+        while !self.stop.is_stopped() {
+            let frame = match slot.take(POLL_INTERVAL) {
+                Some(Some(frame)) => frame?,
+                Some(None) => continue,
+                None => {
+                    info!("Frame source has ended");
+                    return Ok(());
+                }
+            };
+            let img = DynamicImage::ImageRgb8(frame);
+            let result = yolo.detect_frame(&img, self.rotate_angle)?;
+            stats.frames += 1;
+
+            let rotated = match self.rotate_angle {
+                0 => img.into_rgb8(),
+                angle => img_proc::rotate_img(&img, angle).into_rgb8(),
+            };
+            for sink in &mut self.sinks {
+                if let Err(e) = sink.send(&rotated, &result) {
+                    warn!("Can't send frame {}: {:#}", result.frame_id, e);
+                    stats.sink_errors += 1;
+                }
+            }
+        }
+        info!("Stop requested");
+        Ok(())
+    }
+}
+
+/// カメラのスレッドから受け取った最新のフレーム
+#[derive(Default)]
+struct FrameSlotState {
+    /// 推論されていないフレーム
+    frame: Option<Result<RgbImage>>,
+    /// 取得元が終了したか
+    closed: bool,
+    /// 推論されずに上書きされたフレームの数
+    dropped: u64,
+}
+
+/// カメラのスレッドと推論のスレッドで最新のフレームを受け渡す構造体
+#[derive(Default)]
+struct FrameSlot {
+    state: Mutex<FrameSlotState>,
+    cond: Condvar,
+}
+
+impl FrameSlot {
+    /// フレームを置きます。推論されていないフレームがあれば上書きします。
+    fn put(&self, frame: Result<RgbImage>) {
+        let mut state = self.state.lock().unwrap();
+        // エラーは上書きしない
+        if matches!(state.frame, Some(Err(_))) {
+            return;
+        }
+        if state.frame.replace(frame).is_some() {
+            state.dropped += 1;
+        }
+        self.cond.notify_one();
+    }
+
+    /// 取得元が終了したことを通知します。
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.cond.notify_one();
+    }
+
+    /// 取得元が終了するまで待ちます。
+    ///
+    /// # Args
+    /// * `timeout` - 待つ最大の時間
+    ///
+    /// # Return
+    /// * 時間内に終了した場合はtrue
+    fn wait_closed(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .cond
+            .wait_timeout_while(state, timeout, |s| !s.closed)
+            .unwrap();
+        state.closed
+    }
+
+    /// フレームを取り出します。
+    ///
+    /// # Args
+    /// * `timeout` - フレームを待つ最大の時間
+    ///
+    /// # Return
+    /// * フレーム。時間内に届かなかった場合は `Some(None)`、取得元が終了した場合はNone
+    fn take(&self, timeout: Duration) -> Option<Option<Result<RgbImage>>> {
+        let state = self.state.lock().unwrap();
+        let (mut state, _) = self
+            .cond
+            .wait_timeout_while(state, timeout, |s| s.frame.is_none() && !s.closed)
+            .unwrap();
+        match state.frame.take() {
+            Some(frame) => Some(Some(frame)),
+            None if state.closed => None,
+            None => Some(None),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! シーンの明るさから昼と夜のパラメータのプロファイルを切り替えるモジュール
+//!
+//! フレームの平均輝度を指数移動平均で平滑化し、ヒステリシスを持たせた2つの閾値で昼と夜を判定します。
+//! 切り替わったときに、プロファイルの閾値・バリデータの設定・入力画像の補正処理を `YoloV3Tiny::apply_profile` で適用します。
+//! プロファイルで省略した項目には設定ファイルの `[model]`・`[validator]`・`[[enhancement]]` の値が使われます。
+//!
+//! ```toml
+//! [day_night]
+//! night_below = 0.2
+//! day_above = 0.3
+//!
+//! [day_night.night]
+//! obj_threshold = 0.15
+//! enhancement = [{ type = "clahe", clip_limit = 3.0 }]
+//!
+//! [day_night.night.validator]
+//! min_absolute_brightness = 0.3
+//! red_hue = [320, 40]
+//! ```
+//!
+//! ```ignore
+//! let mut switcher = DayNightSwitcher::new(config.day_night.clone().unwrap_or_default())?;
+//! loop {
+//!     let img = camera.capture()?;
+//!     if let Some(mode) = switcher.update(&img) {
+//!         yolo.apply_profile(&config, switcher.profile(mode))?;
+//!     }
+//!     let objs = yolo.start_with_img_proc(&img, 0)?;
+//! }
+//! ```
+
+use std::fmt;
+
+use anyhow::{ensure, Result};
+use image::{DynamicImage, GenericImageView};
+use serde::Deserialize;
+
+use crate::config::ValidatorConfig;
+use crate::enhance::Enhancement;
+
+/// 明るさを求めるときに、縦横それぞれで標本にする画素の数
+const BRIGHTNESS_SAMPLES: u32 = 64;
+
+/// 昼または夜のパラメータのプロファイル。省略した項目は設定ファイルの値を使います
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileConfig {
+    /// オブジェクトの閾値
+    pub obj_threshold: Option<f32>,
+    /// NMSの閾値
+    pub nms_threshold: Option<f32>,
+    /// 信号機のバリデータの設定 (色相の範囲など)
+    pub validator: Option<ValidatorConfig>,
+    /// 入力画像の補正処理
+    pub enhancement: Option<Vec<Enhancement>>,
+}
+
+/// 昼と夜の切り替えの設定 (`[day_night]`)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DayNightConfig {
+    /// 平均輝度 (0.0-1.0) がこの値を下回ると夜に切り替える
+    pub night_below: f32,
+    /// 平均輝度 (0.0-1.0) がこの値を上回ると昼に切り替える
+    pub day_above: f32,
+    /// 平均輝度の指数移動平均の係数 (0.0-1.0)。小さいほどゆっくり追従します
+    pub smoothing: f32,
+    /// 昼のプロファイル
+    pub day: ProfileConfig,
+    /// 夜のプロファイル
+    pub night: ProfileConfig,
+}
+
+impl Default for DayNightConfig {
+    fn default() -> Self {
+        Self {
+            night_below: 0.2,
+            day_above: 0.3,
+            smoothing: 0.1,
+            day: ProfileConfig::default(),
+            night: ProfileConfig::default(),
+        }
+    }
+}
+
+/// シーンの明るさの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SceneMode {
+    /// 昼
+    Day,
+    /// 夜
+    Night,
+}
+
+impl fmt::Display for SceneMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Day => write!(f, "day"),
+            Self::Night => write!(f, "night"),
+        }
+    }
+}
+
+/// フレームの明るさから昼と夜を判定する構造体
+pub struct DayNightSwitcher {
+    config: DayNightConfig,
+    mode: SceneMode,
+    /// 平滑化した平均輝度。まだフレームがない場合はNone
+    brightness: Option<f32>,
+}
+
+impl DayNightSwitcher {
+    /// 新しい `DayNightSwitcher` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `config` - 昼と夜の切り替えの設定
+    ///
+    /// # Return
+    /// * `night_below` が `day_above` 以上の場合や、`smoothing` が (0, 1] の範囲にない場合はエラー
+    pub fn new(config: DayNightConfig) -> Result<Self> {
+        ensure!(
+            config.night_below < config.day_above,
+            "night_below ({}) must be less than day_above ({})",
+            config.night_below,
+            config.day_above
+        );
+        ensure!(
+            config.smoothing > 0. && config.smoothing <= 1.,
+            "smoothing must be in (0, 1], got {}",
+            config.smoothing
+        );
+        Ok(Self {
+            config,
+            mode: SceneMode::Day,
+            brightness: None,
+        })
+    }
+
+    /// 現在の状態を返します。
+    pub fn mode(&self) -> SceneMode {
+        self.mode
+    }
+
+    /// 平滑化した平均輝度 (0.0-1.0) を返します。まだフレームがない場合はNone
+    pub fn brightness(&self) -> Option<f32> {
+        self.brightness
+    }
+
+    /// 状態に対応するプロファイルを返します。
+    pub fn profile(&self, mode: SceneMode) -> &ProfileConfig {
+        match mode {
+            SceneMode::Day => &self.config.day,
+            SceneMode::Night => &self.config.night,
+        }
+    }
+
+    /// フレームの明るさで状態を更新します。
+    ///
+    /// 最初のフレームでは、平均輝度が2つの閾値の中間より暗ければ夜とします。
+    ///
+    /// # Args
+    /// * `img` - フレームの画像
+    ///
+    /// # Return
+    /// * 状態が切り替わった場合 (最初のフレームを含む) は新しい状態、それ以外はNone
+    pub fn update(&mut self, img: &DynamicImage) -> Option<SceneMode> {
+        let current = mean_brightness(img);
+        let Some(prev) = self.brightness else {
+            self.brightness = Some(current);
+            let mid = (self.config.night_below + self.config.day_above) / 2.;
+            self.mode = if current < mid {
+                SceneMode::Night
+            } else {
+                SceneMode::Day
+            };
+            return Some(self.mode);
+        };
+
+        let brightness = prev + (current - prev) * self.config.smoothing;
+        self.brightness = Some(brightness);
+        let next = match self.mode {
+            SceneMode::Day if brightness < self.config.night_below => SceneMode::Night,
+            SceneMode::Night if brightness > self.config.day_above => SceneMode::Day,
+            mode => mode,
+        };
+        if next == self.mode {
+            return None;
+        }
+        self.mode = next;
+        Some(next)
+    }
+
+    /// 状態と平均輝度を初期化します。次のフレームで改めて判定します。
+    pub fn reset(&mut self) {
+        self.mode = SceneMode::Day;
+        self.brightness = None;
+    }
+}
+
+/// 画像の平均輝度 (0.0-1.0) を、格子状に標本にした画素から求めます。
+fn mean_brightness(img: &DynamicImage) -> f32 {
+    let (w, h) = img.dimensions();
+    if w == 0 || h == 0 {
+        return 0.;
+    }
+    let (nx, ny) = (BRIGHTNESS_SAMPLES.min(w), BRIGHTNESS_SAMPLES.min(h));
+    let mut sum = 0.;
+    for j in 0..ny {
+        for i in 0..nx {
+            let [r, g, b, _] = img.get_pixel(i * w / nx, j * h / ny).0;
+            sum += 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+        }
+    }
+    sum / (nx * ny) as f32 / 255.
+}
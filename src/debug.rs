@@ -0,0 +1,126 @@
+//! デバッグ用の画像やログの出力先に関するモジュール
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use image::RgbImage;
+
+/// デバッグ用の画像やログを受け取るためのトレイト
+pub trait DebugSink {
+    /// デバッグ出力が有効かを返します。無効な場合、呼び出し側はデバッグ用データの生成を省略できます。
+    fn is_enabled(&self) -> bool {
+        true
+    }
+
+    /// デバッグ用の画像を出力します。
+    ///
+    /// # Args
+    /// * `name` - 画像の名前
+    /// * `img` - 画像
+    fn save_image(&mut self, name: &str, img: &RgbImage) -> Result<()>;
+
+    /// デバッグ用のログを出力します。
+    ///
+    /// # Args
+    /// * `msg` - ログのメッセージ
+    fn log(&mut self, msg: &str) -> Result<()>;
+}
+
+/// 何も出力しないDebugSink
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DisabledSink;
+
+impl DebugSink for DisabledSink {
+    fn is_enabled(&self) -> bool {
+        false
+    }
+
+    fn save_image(&mut self, _name: &str, _img: &RgbImage) -> Result<()> {
+        Ok(())
+    }
+
+    fn log(&mut self, _msg: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// 指定したディレクトリに画像とログを書き出すDebugSink
+///
+/// 画像は `{通し番号}_{名前}.png` として保存され、ログは `debug.log` に追記されます。
+pub struct DirSink {
+    dir: PathBuf,
+    seq: u64,
+}
+
+impl DirSink {
+    /// 新しい `DirSink` インスタンスを作成します。ディレクトリが存在しない場合は作成します。
+    ///
+    /// # Args
+    /// * `dir` - 出力先のディレクトリ
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, seq: 0 })
+    }
+}
+
+impl DebugSink for DirSink {
+    fn save_image(&mut self, name: &str, img: &RgbImage) -> Result<()> {
+        let path = self.dir.join(format!("{:06}_{}.png", self.seq, name));
+        self.seq += 1;
+        img.save(path)?;
+        Ok(())
+    }
+
+    fn log(&mut self, msg: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("debug.log"))?;
+        writeln!(file, "{}", msg)?;
+        Ok(())
+    }
+}
+
+/// 画像とログをメモリ上に保持するDebugSink
+///
+/// cloneしたインスタンス同士は保持しているデータを共有するため、
+/// `YoloV3Tiny::set_debug_sink` に渡した後もcloneから内容を取得できます。
+#[derive(Default, Clone)]
+pub struct MemorySink {
+    images: Arc<Mutex<Vec<(String, RgbImage)>>>,
+    logs: Arc<Mutex<Vec<String>>>,
+}
+
+impl MemorySink {
+    /// 出力された画像 (名前, 画像) を返します。
+    pub fn images(&self) -> Vec<(String, RgbImage)> {
+        self.images.lock().unwrap().clone()
+    }
+
+    /// 出力されたログを返します。
+    pub fn logs(&self) -> Vec<String> {
+        self.logs.lock().unwrap().clone()
+    }
+
+    /// 保持している画像とログを削除します。
+    pub fn clear(&self) {
+        self.images.lock().unwrap().clear();
+        self.logs.lock().unwrap().clear();
+    }
+}
+
+impl DebugSink for MemorySink {
+    fn save_image(&mut self, name: &str, img: &RgbImage) -> Result<()> {
+        self.images.lock().unwrap().push((name.to_string(), img.clone()));
+        Ok(())
+    }
+
+    fn log(&mut self, msg: &str) -> Result<()> {
+        self.logs.lock().unwrap().push(msg.to_string());
+        Ok(())
+    }
+}
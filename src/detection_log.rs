@@ -0,0 +1,216 @@
+//! フレームごとの検出結果をCSVまたはNDJSON形式でファイルに記録するモジュール
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde_json::json;
+
+use crate::detection_result::DetectionData;
+
+/// ログの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// 1行に1つの検出結果を記録するCSV
+    Csv,
+    /// 1行に1フレーム分の検出結果を記録するJSON (newline-delimited JSON)
+    Ndjson,
+}
+
+impl LogFormat {
+    /// ファイルの拡張子を返します。
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// フレームごとの検出結果をファイルに追記する構造体
+///
+/// ファイルが一定のサイズを超えると `{名前}.1.{拡張子}`, `{名前}.2.{拡張子}`, ... に退避し、新しいファイルに記録します。
+/// `YoloV3Tiny::on_final_detections` に登録して使うことを想定しています。
+pub struct DetectionLogger {
+    dir: PathBuf,
+    name: String,
+    format: LogFormat,
+    max_bytes: u64,
+    max_files: usize,
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+impl DetectionLogger {
+    /// 新しい `DetectionLogger` インスタンスを作成します。ディレクトリが存在しない場合は作成します。
+    ///
+    /// # Args
+    /// * `dir` - 出力先のディレクトリ
+    /// * `name` - ファイル名 (拡張子を除く)
+    /// * `format` - ログの形式
+    pub fn new<P: Into<PathBuf>>(dir: P, name: &str, format: LogFormat) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.{}", name, format.extension()));
+        let (writer, written) = Self::open(&path, format)?;
+        Ok(Self {
+            dir,
+            name: name.to_string(),
+            format,
+            max_bytes: 64 * 1024 * 1024,
+            max_files: 10,
+            writer,
+            written,
+        })
+    }
+
+    /// ファイルを退避する条件を設定します。
+    ///
+    /// # Args
+    /// * `max_bytes` - 1つのファイルの最大サイズ
+    /// * `max_files` - 保持する退避済みファイルの数
+    pub fn set_rotation(&mut self, max_bytes: u64, max_files: usize) -> &mut Self {
+        self.max_bytes = max_bytes;
+        self.max_files = max_files;
+        self
+    }
+
+    /// 1フレーム分の検出結果を記録します。
+    ///
+    /// # Args
+    /// * `frame_id` - フレーム番号
+    /// * `detections` - 検出結果
+    pub fn log(&mut self, frame_id: u64, detections: &[DetectionData]) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs_f64())
+            .unwrap_or(0.);
+
+        let mut text = String::new();
+        match self.format {
+            LogFormat::Csv => {
+                for d in detections {
+                    // 矢印信号の向きなど、判定できなかった場合は空欄にする
+                    let sub_state = d.sub_state.map(|s| s.to_string()).unwrap_or_default();
+                    text += &format!(
+                        "{:.6},{},{},{:.1},{:.1},{:.1},{:.1},{:.4},{}\n",
+                        timestamp,
+                        frame_id,
+                        d.class,
+                        d.x1,
+                        d.y1,
+                        d.x2,
+                        d.y2,
+                        d.confidence,
+                        sub_state
+                    );
+                }
+            }
+            LogFormat::Ndjson => {
+                let line = ndjson_record(timestamp, frame_id, detections);
+                text = format!("{}\n", line);
+            }
+        }
+
+        if self.written > 0 && self.written + text.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.writer.write_all(text.as_bytes())?;
+        self.written += text.len() as u64;
+        Ok(())
+    }
+
+    /// バッファされている内容をファイルに書き出します。
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// 番号付きのファイルのパスを返します。0は記録中のファイルです。
+    fn path(&self, index: usize) -> PathBuf {
+        let ext = self.format.extension();
+        if index == 0 {
+            self.dir.join(format!("{}.{}", self.name, ext))
+        } else {
+            self.dir.join(format!("{}.{}.{}", self.name, index, ext))
+        }
+    }
+
+    /// 記録中のファイルを退避し、新しいファイルを開きます。
+    fn rotate(&mut self) -> Result<()> {
+        self.writer.flush()?;
+
+        let _ = fs::remove_file(self.path(self.max_files));
+        for i in (0..self.max_files).rev() {
+            let from = self.path(i);
+            if from.exists() {
+                fs::rename(&from, self.path(i + 1))?;
+            }
+        }
+        if self.max_files == 0 {
+            let _ = fs::remove_file(self.path(0));
+        }
+
+        let (writer, written) = Self::open(&self.path(0), self.format)?;
+        self.writer = writer;
+        self.written = written;
+        Ok(())
+    }
+
+    /// ファイルを追記モードで開き、新しいファイルの場合はヘッダを書き込みます。
+    fn open(path: &Path, format: LogFormat) -> Result<(BufWriter<File>, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut written = file.metadata()?.len();
+        let mut writer = BufWriter::new(file);
+        if written == 0 && format == LogFormat::Csv {
+            let header = "timestamp,frame,class,x1,y1,x2,y2,confidence,sub_state\n";
+            writer.write_all(header.as_bytes())?;
+            written += header.len() as u64;
+        }
+        Ok((writer, written))
+    }
+}
+
+impl Drop for DetectionLogger {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+/// 1フレーム分の検出結果をNDJSONの1行分のJSONに変換します。
+///
+/// # Args
+/// * `timestamp` - UNIX時間 (秒)
+/// * `frame_id` - フレーム番号
+/// * `detections` - 検出結果
+pub(crate) fn ndjson_record(
+    timestamp: f64,
+    frame_id: u64,
+    detections: &[DetectionData],
+) -> serde_json::Value {
+    let detections: Vec<_> = detections
+        .iter()
+        .map(|d| {
+            let mut v = json!({
+                "class": d.class,
+                "x1": d.x1,
+                "y1": d.y1,
+                "x2": d.x2,
+                "y2": d.y2,
+                "confidence": d.confidence,
+            });
+            // 矢印信号の向きなど、判定できた場合のみ出力する
+            if let Some(sub_state) = d.sub_state {
+                v["sub_state"] = json!(sub_state.to_string());
+            }
+            v
+        })
+        .collect();
+    json!({
+        "timestamp": timestamp,
+        "frame": frame_id,
+        "detections": detections,
+    })
+}
@@ -1,10 +1,51 @@
 //! 物体検出の結果を処理するモジュール
 
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::{Duration, SystemTime};
+
 use anyhow::{anyhow, Result};
 
+use crate::img_proc::{enlargement_slots, CropRect};
+
+/// YOLOの入力データ (レターボックス処理後の画像) の座標系を表すマーカー型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LetterboxSpace;
+
+/// 元の画像の座標系を表すマーカー型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ImageSpace;
+
+/// 元の画像の幅と高さで正規化した [0, 1] の座標系を表すマーカー型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NormalizedSpace;
+
+/// クラスをさらに細かく分類した検出結果の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubState {
+    /// 左折の矢印信号
+    ArrowLeft,
+    /// 直進の矢印信号
+    ArrowStraight,
+    /// 右折の矢印信号
+    ArrowRight,
+}
+
+impl fmt::Display for SubState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ArrowLeft => write!(f, "arrow_left"),
+            Self::ArrowStraight => write!(f, "arrow_straight"),
+            Self::ArrowRight => write!(f, "arrow_right"),
+        }
+    }
+}
+
 /// 送られてきた生の検出結果を保持するための構造体
-#[derive(Debug, Clone, Copy)]
-pub struct DetectionData {
+///
+/// 座標系を型パラメータ `S` で区別し、YOLOの入力データの座標系 (`LetterboxSpace`) の検出結果を
+/// 元の画像の座標系 (`ImageSpace`) のものとして誤って扱うことを防ぎます。
+pub struct DetectionData<S = ImageSpace> {
     /// クラス
     pub class: u8,
     /// バウンディングボックス左上のx
@@ -17,9 +58,152 @@ pub struct DetectionData {
     pub y2: f32,
     /// コンフィデンス
     pub confidence: f32,
+    /// クラスをさらに細かく分類した状態 (矢印信号の向きなど)。分類していない場合はNone
+    pub sub_state: Option<SubState>,
+    /// 座標系
+    space: PhantomData<S>,
 }
 
-impl DetectionData {
+impl<S> Clone for DetectionData<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S> Copy for DetectionData<S> {}
+
+impl<S> fmt::Debug for DetectionData<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("DetectionData");
+        s.field("class", &self.class)
+            .field("x1", &self.x1)
+            .field("y1", &self.y1)
+            .field("x2", &self.x2)
+            .field("y2", &self.y2)
+            .field("confidence", &self.confidence);
+        if let Some(sub_state) = &self.sub_state {
+            s.field("sub_state", sub_state);
+        }
+        s.finish()
+    }
+}
+
+impl<S> DetectionData<S> {
+    /// 新しいDetectionDataを作成します。
+    ///
+    /// # Args
+    ///
+    /// * `class` - クラス
+    /// * `x1`, `y1`, `x2`, `y2` - バウンディングボックスの左上と右下の座標
+    /// * `confidence` - コンフィデンス
+    ///
+    /// # Return
+    /// * 新たなDetectionDataインスタンス
+    pub fn new(class: u8, x1: f32, y1: f32, x2: f32, y2: f32, confidence: f32) -> Self {
+        Self {
+            class,
+            x1,
+            y1,
+            x2,
+            y2,
+            confidence,
+            sub_state: None,
+            space: PhantomData,
+        }
+    }
+
+    /// 座標を変換せずに座標系だけを変更します。
+    ///
+    /// 2つの座標系が一致していることが分かっている場合 (前処理を行わずに入力データを作成した場合など) にのみ使用してください。
+    pub fn assume_space<T>(self) -> DetectionData<T> {
+        DetectionData::new(
+            self.class,
+            self.x1,
+            self.y1,
+            self.x2,
+            self.y2,
+            self.confidence,
+        )
+        .with_sub_state(self.sub_state)
+    }
+
+    /// 状態を設定した検出結果を返します。座標を変換した検出結果に元の状態を引き継ぐときに使います。
+    pub fn with_sub_state(mut self, sub_state: Option<SubState>) -> Self {
+        self.sub_state = sub_state;
+        self
+    }
+
+    /// バウンディングボックスの幅を返します。
+    pub fn width(&self) -> f32 {
+        self.x2 - self.x1
+    }
+
+    /// バウンディングボックスの高さを返します。
+    pub fn height(&self) -> f32 {
+        self.y2 - self.y1
+    }
+
+    /// バウンディングボックスの面積を返します。
+    pub fn area(&self) -> f32 {
+        self.width() * self.height()
+    }
+
+    /// バウンディングボックスの中心座標 (x, y) を返します。
+    pub fn center(&self) -> (f32, f32) {
+        ((self.x1 + self.x2) / 2., (self.y1 + self.y2) / 2.)
+    }
+
+    /// 他の検出結果とのIoU（Intersection over Union）を計算します。
+    ///
+    /// # Args
+    ///
+    /// * `other` - 比較する検出結果
+    ///
+    /// # Return
+    /// * IoUの値（0.0から1.0の範囲）
+    pub fn iou(&self, other: &Self) -> f32 {
+        let dx = self.x2.min(other.x2) - self.x1.max(other.x1);
+        let dy = self.y2.min(other.y2) - self.y1.max(other.y1);
+        let inter_area = dx.max(0.) * dy.max(0.);
+
+        inter_area / (self.area() + other.area() - inter_area)
+    }
+
+    /// 点がバウンディングボックス内にあるかを判定します。
+    ///
+    /// # Args
+    ///
+    /// * `x` - x座標
+    /// * `y` - y座標
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        self.x1 <= x && x <= self.x2 && self.y1 <= y && y <= self.y2
+    }
+
+    /// バウンディングボックスを画像の範囲内に収めます。
+    ///
+    /// # Args
+    ///
+    /// * `width` - 画像の幅
+    /// * `height` - 画像の高さ
+    ///
+    /// # Return
+    /// * 新たなDetectionDataインスタンス
+    pub fn clamp_to(&self, width: u32, height: u32) -> Self {
+        let w = width as f32;
+        let h = height as f32;
+        Self::new(
+            self.class,
+            self.x1.clamp(0., w),
+            self.y1.clamp(0., h),
+            self.x2.clamp(0., w),
+            self.y2.clamp(0., h),
+            self.confidence,
+        )
+        .with_sub_state(self.sub_state)
+    }
+}
+
+impl DetectionData<LetterboxSpace> {
     /// YOLOの結果から新しいDetectionDataを作成します。
     ///
     /// # Args
@@ -38,14 +222,14 @@ impl DetectionData {
         let cw = yolo_result[2];
         let ch = yolo_result[3];
 
-        let nms_box = Self {
-            class: cls_id,
-            x1: cx - cw / 2.,
-            y1: cy - ch / 2.,
-            x2: cx + cw / 2.,
-            y2: cy + ch / 2.,
-            confidence: yolo_result[4],
-        };
+        let nms_box = Self::new(
+            cls_id,
+            cx - cw / 2.,
+            cy - ch / 2.,
+            cx + cw / 2.,
+            cy + ch / 2.,
+            yolo_result[4],
+        );
         if (0. <= nms_box.x1 && nms_box.x1 <= 416.)
             && (0. <= nms_box.y1 && nms_box.y1 <= 416.)
             && (0. <= nms_box.x2 && nms_box.x2 <= 416.)
@@ -64,6 +248,7 @@ impl DetectionData {
     /// * `width` - 画像の幅
     /// * `height` - 画像の高さ
     /// * `rotate_angle` - 回転角度
+    /// * `input_size` - YOLOの入力サイズ
     ///
     /// # Return
     /// * 新たなDetectionDataインスタンス
@@ -73,8 +258,9 @@ impl DetectionData {
         height: u32,
         rotate_angle: u32,
         pad_only_right: bool,
-    ) -> Self {
-        let mut new_d = *self;
+        input_size: u32,
+    ) -> DetectionData {
+        let mut new_d = self.assume_space();
         (new_d.x1, new_d.y1) = point_reverse_transform(
             width,
             height,
@@ -82,6 +268,7 @@ impl DetectionData {
             self.x1,
             self.y1,
             pad_only_right,
+            input_size,
         );
         (new_d.x2, new_d.y2) = point_reverse_transform(
             width,
@@ -90,9 +277,239 @@ impl DetectionData {
             self.x2,
             self.y2,
             pad_only_right,
+            input_size,
         );
         new_d
     }
+
+    /// 部分拡大を行ったYOLOの入力に対する検出結果の座標を元の画像の座標系に戻します。
+    ///
+    /// バウンディングボックスの中心が拡大した領域の配置先にある場合は、対応する切り取り領域の座標に戻します。
+    ///
+    /// # Args
+    ///
+    /// * `width` - 回転後の画像の幅
+    /// * `height` - 回転後の画像の高さ
+    /// * `crops` - 切り取った領域 (回転後の画像の座標系)
+    /// * `input_size` - YOLOの入力サイズ
+    ///
+    /// # Return
+    /// * 新たなDetectionDataインスタンス
+    pub fn reverse_transform_with_crops(
+        &self,
+        width: u32,
+        height: u32,
+        crops: &[CropRect],
+        input_size: u32,
+    ) -> DetectionData {
+        let size = u32::max(width, height);
+        let ratio = input_size as f32 / size as f32;
+
+        // 拡大した領域の配置先は、前処理と同じく四捨五入した縮小後の画像のサイズから求める
+        let resized_w = (width as f32 * ratio).round() as u32;
+        let resized_h = (height as f32 * ratio).round() as u32;
+        let d: DetectionData = self.assume_space();
+        let region = d.enlargement_region(resized_w, resized_h, crops.len());
+        if let EnlargementRegion::Crop(_) = region {
+            return d.crop_to_source(resized_w, resized_h, crops);
+        }
+
+        let mut new_d = d;
+        new_d.x1 /= ratio;
+        new_d.y1 /= ratio;
+        new_d.x2 /= ratio;
+        new_d.y2 /= ratio;
+        new_d
+    }
+}
+
+impl DetectionData {
+    /// 座標を画像の幅と高さで正規化します。
+    ///
+    /// # Args
+    ///
+    /// * `width` - 画像の幅
+    /// * `height` - 画像の高さ
+    ///
+    /// # Return
+    /// * 座標を [0, 1] の範囲に正規化した新たなDetectionDataインスタンス
+    pub fn normalize(&self, width: u32, height: u32) -> DetectionData<NormalizedSpace> {
+        let w = width as f32;
+        let h = height as f32;
+        DetectionData::new(
+            self.class,
+            (self.x1 / w).clamp(0., 1.),
+            (self.y1 / h).clamp(0., 1.),
+            (self.x2 / w).clamp(0., 1.),
+            (self.y2 / h).clamp(0., 1.),
+            self.confidence,
+        )
+        .with_sub_state(self.sub_state)
+    }
+
+    /// 部分拡大を行った画像上でバウンディングボックスの中心がどの領域にあるかを判定します。
+    ///
+    /// # Args
+    ///
+    /// * `width` - 回転後の画像の幅
+    /// * `height` - 回転後の画像の高さ
+    /// * `n_crops` - 切り取った領域の数
+    ///
+    /// # Return
+    /// * バウンディングボックスの中心がある領域
+    pub fn enlargement_region(&self, width: u32, height: u32, n_crops: usize) -> EnlargementRegion {
+        let (cx, cy) = self.center();
+        if cx < width as f32 && cy < height as f32 {
+            return EnlargementRegion::Image;
+        }
+
+        let size = u32::max(width, height);
+        enlargement_slots(width, height, size, n_crops)
+            .iter()
+            .position(|slot| slot.contains(cx, cy))
+            .map_or(EnlargementRegion::Padding, EnlargementRegion::Crop)
+    }
+
+    /// 部分拡大を行った画像上の検出結果の座標を、切り取り前の画像の座標系に戻します。
+    ///
+    /// 拡大した領域内の検出結果は切り取りと縮小・拡大を逆に適用し、それ以外はそのまま返します。
+    ///
+    /// # Args
+    ///
+    /// * `width` - 回転後の画像の幅
+    /// * `height` - 回転後の画像の高さ
+    /// * `crops` - 切り取った領域 (回転後の画像の座標系)
+    ///
+    /// # Return
+    /// * 新たなDetectionDataインスタンス
+    pub fn crop_to_source(&self, width: u32, height: u32, crops: &[CropRect]) -> Self {
+        let i = match self.enlargement_region(width, height, crops.len()) {
+            EnlargementRegion::Crop(i) => i,
+            _ => return *self,
+        };
+        let size = u32::max(width, height);
+        let slot = enlargement_slots(width, height, size, crops.len())[i];
+        let crop = crops[i];
+
+        // 切り取った領域はアスペクト比を保ったまま配置先の左上に縮小・拡大されている
+        let r = f32::min(slot.w as f32 / crop.w as f32, slot.h as f32 / crop.h as f32);
+        Self::new(
+            self.class,
+            crop.x as f32 + (self.x1 - slot.x as f32) / r,
+            crop.y as f32 + (self.y1 - slot.y as f32) / r,
+            crop.x as f32 + (self.x2 - slot.x as f32) / r,
+            crop.y as f32 + (self.y2 - slot.y as f32) / r,
+            self.confidence,
+        )
+        .with_sub_state(self.sub_state)
+    }
+}
+
+/// 部分拡大を行った画像上の領域
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnlargementRegion {
+    /// 元の画像を配置した領域
+    Image,
+    /// 拡大した領域 (切り取り領域のインデックス)
+    Crop(usize),
+    /// どちらにも使われていない余白
+    Padding,
+}
+
+impl DetectionData<NormalizedSpace> {
+    /// 正規化した座標を画像の座標系に戻します。
+    ///
+    /// # Args
+    ///
+    /// * `width` - 画像の幅
+    /// * `height` - 画像の高さ
+    ///
+    /// # Return
+    /// * 新たなDetectionDataインスタンス
+    pub fn denormalize(&self, width: u32, height: u32) -> DetectionData {
+        let w = width as f32;
+        let h = height as f32;
+        DetectionData::new(
+            self.class,
+            self.x1 * w,
+            self.y1 * h,
+            self.x2 * w,
+            self.y2 * h,
+            self.confidence,
+        )
+        .with_sub_state(self.sub_state)
+    }
+}
+
+impl<S> AsRef<DetectionData<S>> for DetectionData<S> {
+    fn as_ref(&self) -> &DetectionData<S> {
+        self
+    }
+}
+
+/// 1フレームの検出結果とメタデータ
+///
+/// ログの記録やトラッキングで、検出結果とフレームの情報を別々に持ち回らずに済むようにまとめたものです。
+#[derive(Debug, Clone)]
+pub struct FrameResult {
+    /// フレーム番号
+    pub frame_id: u64,
+    /// 処理を開始した時刻
+    pub timestamp: SystemTime,
+    /// 検出結果 (元の画像の座標系)
+    pub detections: Vec<DetectionData>,
+    /// 前処理から後処理までにかかった時間
+    pub latency: Duration,
+    /// 入力画像の幅と高さ
+    pub input_size: (u32, u32),
+}
+
+/// クラス候補の上位k個を付加した検出結果を保持するための構造体
+#[derive(Debug, Clone)]
+pub struct DetectionDataExt<S = ImageSpace> {
+    /// 検出結果 (クラスは最もスコアの高いもの)
+    pub data: DetectionData<S>,
+    /// スコアの高い順に並べたクラス候補 (クラスID, スコア)
+    pub candidates: Vec<(u8, f32)>,
+}
+
+impl DetectionDataExt<LetterboxSpace> {
+    /// YOLOの出力した検出結果の座標を元の画像の座標系に戻します。
+    ///
+    /// # Args
+    ///
+    /// * `width` - 画像の幅
+    /// * `height` - 画像の高さ
+    /// * `rotate_angle` - 回転角度
+    /// * `input_size` - YOLOの入力サイズ
+    ///
+    /// # Return
+    /// * 新たなDetectionDataExtインスタンス
+    pub fn reverse_transform(
+        &self,
+        width: u32,
+        height: u32,
+        rotate_angle: u32,
+        pad_only_right: bool,
+        input_size: u32,
+    ) -> DetectionDataExt {
+        DetectionDataExt {
+            data: self.data.reverse_transform(
+                width,
+                height,
+                rotate_angle,
+                pad_only_right,
+                input_size,
+            ),
+            candidates: self.candidates.clone(),
+        }
+    }
+}
+
+impl<S> AsRef<DetectionData<S>> for DetectionDataExt<S> {
+    fn as_ref(&self) -> &DetectionData<S> {
+        &self.data
+    }
 }
 
 /// YOLOの出力した座標を元の画像の座標系に戻します。
@@ -104,6 +521,7 @@ impl DetectionData {
 /// * `rotate_angle` - 回転角度
 /// * `x` - x座標
 /// * `y` - y座標
+/// * `input_size` - YOLOの入力サイズ
 ///
 /// # Return
 /// * 新たな座標 (x, y)
@@ -114,8 +532,9 @@ fn point_reverse_transform(
     x: f32,
     y: f32,
     pad_only_right: bool,
+    input_size: u32,
 ) -> (f32, f32) {
-    let yolo_input_size = 416.;
+    let yolo_input_size = input_size as f32;
 
     let (w, h) = match rotate_angle {
         90 | 270 => (height, width),
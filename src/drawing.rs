@@ -0,0 +1,236 @@
+//! アンチエイリアスをかけた図形を描画するモジュール
+//!
+//! 太さのある線分は、画素の中心から線分までの距離で被覆率を求め、線の色を被覆率の不透明度で重ねます。
+//! 斜めの線や回転した矩形でもギザギザにならないため、エリア・カウントライン・軌跡などの描画に使えます。
+//! 座標は画素の中心を整数とする画像の座標です。
+//!
+//! RGBの画像のほか、透明な背景に描画して元の画像に重ねるためのRGBAの画像にも描画できます。
+
+use image::{ImageBuffer, Pixel, Rgb, Rgba};
+use rusttype::{point, Font, Scale};
+
+/// 描画できる画素の型 (`Rgb<u8>`・`Rgba<u8>`)
+pub trait DrawPixel: Pixel<Subpixel = u8> + 'static {
+    /// 不透明な色の画素を作ります。
+    fn opaque(color: Rgb<u8>) -> Self;
+
+    /// 画素に色を不透明度 `alpha` (0.0-1.0) で重ねます。
+    fn blend_color(&mut self, color: Rgb<u8>, alpha: f32);
+}
+
+impl DrawPixel for Rgb<u8> {
+    fn opaque(color: Rgb<u8>) -> Self {
+        color
+    }
+
+    fn blend_color(&mut self, color: Rgb<u8>, alpha: f32) {
+        for (c, t) in self.0.iter_mut().zip(color.0) {
+            *c = (*c as f32 + (t as f32 - *c as f32) * alpha).round() as u8;
+        }
+    }
+}
+
+impl DrawPixel for Rgba<u8> {
+    fn opaque(color: Rgb<u8>) -> Self {
+        color.to_rgba()
+    }
+
+    /// 透明な背景に重ねた結果が、不透明な画像に直接重ねた結果と同じ色になるように合成します (over演算)。
+    fn blend_color(&mut self, color: Rgb<u8>, alpha: f32) {
+        let dst_a = self.0[3] as f32 / 255.;
+        let out_a = alpha + dst_a * (1. - alpha);
+        if out_a <= 0. {
+            return;
+        }
+        for (c, t) in self.0.iter_mut().zip(color.0) {
+            let v = (t as f32 * alpha + *c as f32 * dst_a * (1. - alpha)) / out_a;
+            *c = v.round().clamp(0., 255.) as u8;
+        }
+        self.0[3] = (out_a * 255.).round() as u8;
+    }
+}
+
+/// 画像上に太さのある線分を、アンチエイリアスをかけて描画します。
+///
+/// # Args
+///
+/// * `img` - 線分を描画する画像 (in-place)
+/// * `start`, `end` - 線分の始点と終点の座標
+/// * `thickness` - 線の太さ
+/// * `color` - 線の色
+pub fn draw_thick_line<P: DrawPixel>(
+    img: &mut ImageBuffer<P, Vec<u8>>,
+    (x1, y1): (f32, f32),
+    (x2, y2): (f32, f32),
+    thickness: f32,
+    color: Rgb<u8>,
+) {
+    let (w, h) = img.dimensions();
+    if w == 0 || h == 0 {
+        return;
+    }
+    let r = thickness / 2.;
+    let clamp_x = |v: f32| v.clamp(0., (w - 1) as f32) as u32;
+    let clamp_y = |v: f32| v.clamp(0., (h - 1) as f32) as u32;
+    let (dx, dy) = (x2 - x1, y2 - y1);
+    let len2 = dx * dx + dy * dy;
+    for y in clamp_y((y1.min(y2) - r - 1.).floor())..=clamp_y((y1.max(y2) + r + 1.).ceil()) {
+        for x in clamp_x((x1.min(x2) - r - 1.).floor())..=clamp_x((x1.max(x2) + r + 1.).ceil()) {
+            let (px, py) = (x as f32, y as f32);
+            let t = if len2 > 0. {
+                (((px - x1) * dx + (py - y1) * dy) / len2).clamp(0., 1.)
+            } else {
+                0.
+            };
+            let dist = (px - x1 - t * dx).hypot(py - y1 - t * dy);
+            let coverage = (r + 0.5 - dist).clamp(0., 1.);
+            if coverage > 0. {
+                img.get_pixel_mut(x, y).blend_color(color, coverage);
+            }
+        }
+    }
+}
+
+/// 画像上に折れ線 (物体の軌跡など) を、アンチエイリアスをかけて描画します。
+///
+/// # Args
+///
+/// * `img` - 折れ線を描画する画像 (in-place)
+/// * `points` - 折れ線の頂点の座標
+/// * `thickness` - 線の太さ
+/// * `color` - 線の色
+pub fn draw_polyline<P: DrawPixel>(
+    img: &mut ImageBuffer<P, Vec<u8>>,
+    points: &[(f32, f32)],
+    thickness: f32,
+    color: Rgb<u8>,
+) {
+    for pair in points.windows(2) {
+        draw_thick_line(img, pair[0], pair[1], thickness, color);
+    }
+}
+
+/// 画像上に多角形の輪郭を、アンチエイリアスをかけて描画します。頂点が2つの場合は線分を描画します。
+///
+/// # Args
+///
+/// * `img` - 多角形を描画する画像 (in-place)
+/// * `polygon` - 多角形の頂点の座標
+/// * `thickness` - 線の太さ
+/// * `color` - 線の色
+pub fn draw_polygon<P: DrawPixel>(
+    img: &mut ImageBuffer<P, Vec<u8>>,
+    polygon: &[(f32, f32)],
+    thickness: f32,
+    color: Rgb<u8>,
+) {
+    draw_polyline(img, polygon, thickness, color);
+    if polygon.len() > 2 {
+        draw_thick_line(
+            img,
+            polygon[polygon.len() - 1],
+            polygon[0],
+            thickness,
+            color,
+        );
+    }
+}
+
+/// 回転した矩形の頂点の座標を求めます。
+///
+/// # Args
+///
+/// * `center` - 矩形の中心の座標
+/// * `size` - 矩形の幅と高さ
+/// * `angle` - 回転角 (度)。画像の座標系 (y軸が下向き) のため、正の値で時計回りに回転します
+///
+/// # Return
+///
+/// * 回転前の左上・右上・右下・左下に対応する頂点の座標
+pub fn rotated_rect_corners(center: (f32, f32), size: (f32, f32), angle: f32) -> [(f32, f32); 4] {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (hw, hh) = (size.0 / 2., size.1 / 2.);
+    [(-hw, -hh), (hw, -hh), (hw, hh), (-hw, hh)]
+        .map(|(x, y)| (center.0 + x * cos - y * sin, center.1 + x * sin + y * cos))
+}
+
+/// 画像上に回転した矩形の輪郭を、アンチエイリアスをかけて描画します。
+///
+/// # Args
+///
+/// * `img` - 矩形を描画する画像 (in-place)
+/// * `center` - 矩形の中心の座標
+/// * `size` - 矩形の幅と高さ
+/// * `angle` - 回転角 (度)。正の値で時計回りに回転します
+/// * `thickness` - 線の太さ
+/// * `color` - 線の色
+pub fn draw_rotated_rect<P: DrawPixel>(
+    img: &mut ImageBuffer<P, Vec<u8>>,
+    center: (f32, f32),
+    size: (f32, f32),
+    angle: f32,
+    thickness: f32,
+    color: Rgb<u8>,
+) {
+    draw_polygon(
+        img,
+        &rotated_rect_corners(center, size, angle),
+        thickness,
+        color,
+    );
+}
+
+/// 画像上にテキストを、グリフの被覆率を不透明度として重ねて描画します。
+///
+/// # Args
+///
+/// * `img` - テキストを描画する画像 (in-place)
+/// * `x`, `y` - テキストの左上の座標
+/// * `scale` - フォントの大きさ
+/// * `font` - フォント
+/// * `text` - テキスト
+/// * `color` - 文字の色
+pub(crate) fn draw_text_blend<P: DrawPixel>(
+    img: &mut ImageBuffer<P, Vec<u8>>,
+    (x, y): (i32, i32),
+    scale: Scale,
+    font: &Font,
+    text: &str,
+    color: Rgb<u8>,
+) {
+    let (w, h) = (img.width() as i32, img.height() as i32);
+    let ascent = font.v_metrics(scale).ascent;
+    for g in font.layout(text, scale, point(0., ascent)) {
+        let Some(bb) = g.pixel_bounding_box() else {
+            continue;
+        };
+        g.draw(|gx, gy, coverage| {
+            let px = x + bb.min.x + gx as i32;
+            let py = y + bb.min.y + gy as i32;
+            if (0..w).contains(&px) && (0..h).contains(&py) {
+                img.get_pixel_mut(px as u32, py as u32)
+                    .blend_color(color, coverage);
+            }
+        });
+    }
+}
+
+/// 画像上の矩形の範囲を、指定した不透明度で塗りつぶします。
+pub(crate) fn fill_rect_blend<P: DrawPixel>(
+    img: &mut ImageBuffer<P, Vec<u8>>,
+    x1: f32,
+    y1: f32,
+    x2: f32,
+    y2: f32,
+    color: Rgb<u8>,
+    alpha: f32,
+) {
+    let (w, h) = img.dimensions();
+    let xs = (x1.max(0.) as u32)..(x2.max(0.) as u32).min(w);
+    let ys = (y1.max(0.) as u32)..(y2.max(0.) as u32).min(h);
+    for y in ys {
+        for x in xs.clone() {
+            img.get_pixel_mut(x, y).blend_color(color, alpha);
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! 暗い画像を補正し、YOLOの入力データに量子化する前に明るさとコントラストを整えるモジュール
+//!
+//! ホワイトバランス・グレースケールの画像の色の変換・ガンマ補正・ヒストグラム平坦化・
+//! CLAHE (コントラスト制限付き適応的ヒストグラム平坦化) を、指定した順に適用します。
+//! ガンマ補正・ヒストグラム平坦化・CLAHEは輝度だけを変換し、画素ごとにRGBを同じ倍率で拡大するため、
+//! 色相は変わりません (信号機のバリデータの色相のチェックに影響しません)。
+//! グレースケールの画像は、補正の前に輝度を3チャンネルに複製したRGBの画像に変換します。
+//! 16ビットや浮動小数点数の画像 (HDRカメラなど) は、補正の前に `ToneMapping` で8ビットに変換します。
+//!
+//! ```toml
+//! [tone_mapping]
+//! type = "reinhard"
+//! key = 0.18
+//!
+//! [[enhancement]]
+//! type = "white_balance"
+//!
+//! [[enhancement]]
+//! type = "gamma"
+//! gamma = 0.6
+//!
+//! [[enhancement]]
+//! type = "clahe"
+//! clip_limit = 2.0
+//! tiles = 8
+//! ```
+
+use std::borrow::Cow;
+
+use anyhow::{ensure, Result};
+use image::{DynamicImage, GrayImage, Luma, RgbImage};
+use serde::Deserialize;
+
+use crate::img_proc;
+
+/// 輝度の階調数
+const LEVELS: usize = 256;
+
+/// 画像の補正処理
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Enhancement {
+    /// ホワイトバランスの補正 (`img_proc::white_balance`)
+    WhiteBalance {
+        /// RGBのゲイン。省略した場合はフレームごとにグレーワールド仮説で求めます
+        #[serde(default)]
+        gains: Option<[f32; 3]>,
+    },
+    /// グレースケールの画像 (IRカメラなど) の輝度を、チャンネルごとの1次関数でRGBに変換します (`img_proc::gray_to_rgb`)。
+    /// カラー画像に適用した場合は、輝度を変換した画像になります
+    GrayMapping {
+        /// RGBごとの輝度の係数
+        gains: [f32; 3],
+        /// RGBごとの切片
+        #[serde(default)]
+        offsets: [f32; 3],
+    },
+    /// ガンマ補正。輝度 (0.0-1.0) を `gamma` 乗します。1より小さい値で暗部が明るくなります
+    Gamma {
+        /// ガンマ値
+        gamma: f32,
+    },
+    /// 画像全体の輝度のヒストグラム平坦化
+    Equalize,
+    /// CLAHE。画像をタイルに分けて平坦化し、ノイズの強調を抑えるためにヒストグラムの高さを制限します
+    Clahe {
+        /// ヒストグラムの高さの上限 (平均の高さに対する倍率)
+        #[serde(default = "default_clip_limit")]
+        clip_limit: f32,
+        /// 縦横それぞれのタイルの数
+        #[serde(default = "default_tiles")]
+        tiles: u32,
+    },
+}
+
+fn default_clip_limit() -> f32 {
+    2.0
+}
+
+fn default_tiles() -> u32 {
+    8
+}
+
+impl Enhancement {
+    /// パラメータが正しいかを確認します。
+    ///
+    /// # Return
+    /// * ゲインやガンマ値が正でない場合、`clip_limit` が1未満の場合、`tiles` が0の場合はエラー
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            Self::Gamma { gamma } => {
+                ensure!(
+                    gamma.is_finite() && gamma > 0.,
+                    "Gamma must be positive, got {}",
+                    gamma
+                );
+            }
+            Self::WhiteBalance { gains } => {
+                if let Some(gains) = gains {
+                    ensure!(
+                        gains.iter().all(|g| g.is_finite() && *g > 0.),
+                        "White balance gains must be positive, got {:?}",
+                        gains
+                    );
+                }
+            }
+            Self::GrayMapping { gains, offsets } => {
+                ensure!(
+                    gains.iter().chain(&offsets).all(|v| v.is_finite()),
+                    "Gray mapping gains and offsets must be finite"
+                );
+            }
+            Self::Equalize => {}
+            Self::Clahe { clip_limit, tiles } => {
+                ensure!(
+                    clip_limit >= 1.,
+                    "CLAHE clip_limit must be at least 1, got {}",
+                    clip_limit
+                );
+                ensure!(tiles > 0, "CLAHE tiles must be positive");
+            }
+        }
+        Ok(())
+    }
+
+    /// 画像を補正します。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    ///
+    /// # Return
+    /// * 補正した画像
+    pub fn apply(&self, img: &RgbImage) -> RgbImage {
+        if let Self::WhiteBalance { gains } = *self {
+            let gains = gains.unwrap_or_else(|| img_proc::gray_world_gains(img));
+            return img_proc::white_balance(img, gains);
+        }
+        if let Self::GrayMapping { gains, offsets } = *self {
+            let luma = GrayImage::from_fn(img.width(), img.height(), |x, y| {
+                Luma([luminance(img.get_pixel(x, y).0)])
+            });
+            return img_proc::gray_to_rgb(&luma, gains, offsets);
+        }
+
+        let luma: Vec<u8> = img.pixels().map(|p| luminance(p.0)).collect();
+        let target = match *self {
+            Self::Gamma { gamma } => {
+                let lut: Vec<u8> = (0..LEVELS)
+                    .map(|v| ((v as f32 / 255.).powf(gamma) * 255.).round() as u8)
+                    .collect();
+                luma.iter().map(|&v| lut[v as usize]).collect()
+            }
+            Self::Equalize => {
+                let lut = equalization_lut(&histogram(luma.iter().copied()));
+                luma.iter().map(|&v| lut[v as usize]).collect()
+            }
+            Self::WhiteBalance { .. } | Self::GrayMapping { .. } => unreachable!(),
+            Self::Clahe { clip_limit, tiles } => {
+                clahe(&luma, img.width(), img.height(), clip_limit, tiles)
+            }
+        };
+        apply_luminance(img, &luma, &target)
+    }
+}
+
+/// 8ビットを超える階調の画像を、8ビットのRGBの画像に変換する方法 (`[tone_mapping]`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ToneMapping {
+    /// 値の範囲全体を線形に8ビットに縮小します
+    #[default]
+    Linear,
+    /// 輝度の下位 `low` と上位 `high` のパーセンタイルの間を線形に0-255に引き伸ばします
+    Percentile {
+        /// 0にする輝度のパーセンタイル (0.0-1.0)
+        #[serde(default = "default_percentile_low")]
+        low: f32,
+        /// 255にする輝度のパーセンタイル (0.0-1.0)
+        #[serde(default = "default_percentile_high")]
+        high: f32,
+    },
+    /// Reinhardのグローバルトーンマッピング。輝度の対数平均を `key` に合わせ、L / (1 + L) で圧縮します
+    Reinhard {
+        /// 対数平均の輝度を合わせる明るさ (0.0-1.0)
+        #[serde(default = "default_reinhard_key")]
+        key: f32,
+    },
+}
+
+fn default_percentile_low() -> f32 {
+    0.01
+}
+
+fn default_percentile_high() -> f32 {
+    0.99
+}
+
+fn default_reinhard_key() -> f32 {
+    0.18
+}
+
+/// パーセンタイルを求めるときのヒストグラムの階級の数
+const TONE_BINS: usize = 4096;
+
+impl ToneMapping {
+    /// パラメータが正しいかを確認します。
+    ///
+    /// # Return
+    /// * パーセンタイルが0.0 <= `low` < `high` <= 1.0 を満たさない場合、`key` が (0, 1] の範囲にない場合はエラー
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            Self::Linear => {}
+            Self::Percentile { low, high } => {
+                ensure!(
+                    (0. ..=1.).contains(&low) && (0. ..=1.).contains(&high) && low < high,
+                    "Percentiles must satisfy 0 <= low < high <= 1, got {} and {}",
+                    low,
+                    high
+                );
+            }
+            Self::Reinhard { key } => {
+                ensure!(
+                    key > 0. && key <= 1.,
+                    "Reinhard key must be in (0, 1], got {}",
+                    key
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// 画像を8ビットのRGBの画像に変換します。8ビットの画像はそのまま返します。
+    ///
+    /// # Args
+    /// * `img` - 入力画像 (`ImageLuma16`、`ImageRgb16` など)
+    ///
+    /// # Return
+    /// * 8ビットの画像
+    pub fn apply<'a>(&self, img: &'a DynamicImage) -> Cow<'a, DynamicImage> {
+        use DynamicImage::*;
+        if matches!(
+            img,
+            ImageLuma8(_) | ImageLumaA8(_) | ImageRgb8(_) | ImageRgba8(_)
+        ) {
+            return Cow::Borrowed(img);
+        }
+        if *self == Self::Linear {
+            return Cow::Owned(ImageRgb8(img.to_rgb8()));
+        }
+
+        // 値は0.0-1.0 (16ビットの場合は65535で割った値)
+        let src = img.to_rgb32f();
+        let luma: Vec<f32> = src
+            .pixels()
+            .map(|p| (0.299 * p[0] + 0.587 * p[1] + 0.114 * p[2]).max(0.))
+            .collect();
+        let tone: Box<dyn Fn(f32) -> f32> = match *self {
+            Self::Linear => unreachable!(),
+            Self::Percentile { low, high } => {
+                let (lo, hi) = percentiles(&luma, low, high);
+                let range = (hi - lo).max(f32::EPSILON);
+                Box::new(move |l| ((l - lo) / range).clamp(0., 1.))
+            }
+            Self::Reinhard { key } => {
+                let n = luma.len().max(1) as f32;
+                let log_mean = (luma.iter().map(|l| (l + 1e-4).ln()).sum::<f32>() / n).exp();
+                Box::new(move |l| {
+                    let scaled = l * key / log_mean;
+                    scaled / (1. + scaled)
+                })
+            }
+        };
+
+        let mut out = RgbImage::new(src.width(), src.height());
+        for ((o, p), &l) in out.pixels_mut().zip(src.pixels()).zip(&luma) {
+            // 輝度の変換の倍率をRGBに掛けて色相を保つ
+            let rgb = if l > 0. {
+                let gain = tone(l) / l;
+                p.0.map(|c| c * gain)
+            } else {
+                [tone(0.); 3]
+            };
+            o.0 = rgb.map(|c| (c * 255.).round().clamp(0., 255.) as u8);
+        }
+        Cow::Owned(ImageRgb8(out))
+    }
+}
+
+/// 輝度の `low` と `high` のパーセンタイルを求めます。
+fn percentiles(luma: &[f32], low: f32, high: f32) -> (f32, f32) {
+    let mut hist = vec![0usize; TONE_BINS];
+    for &l in luma {
+        hist[((l * TONE_BINS as f32) as usize).min(TONE_BINS - 1)] += 1;
+    }
+    let find = |q: f32| {
+        let target = (q * luma.len() as f32).ceil() as usize;
+        let mut cum = 0;
+        for (i, &n) in hist.iter().enumerate() {
+            cum += n;
+            if cum >= target.max(1) {
+                return i as f32 / TONE_BINS as f32;
+            }
+        }
+        1.
+    };
+    (find(low), find(high))
+}
+
+/// トーンマッピングと補正処理を順に適用します。8ビットの画像で補正処理がない場合は入力画像をそのまま返します。
+///
+/// # Args
+/// * `img` - 入力画像
+/// * `tone_mapping` - 8ビットを超える階調の画像の変換方法
+/// * `enhancements` - 補正処理
+///
+/// # Return
+/// * 補正した画像
+pub fn enhance<'a>(
+    img: &'a DynamicImage,
+    tone_mapping: ToneMapping,
+    enhancements: &[Enhancement],
+) -> Cow<'a, DynamicImage> {
+    let img = tone_mapping.apply(img);
+    if enhancements.is_empty() {
+        return img;
+    }
+    let out = enhancements
+        .iter()
+        .fold(img.to_rgb8(), |acc, e| e.apply(&acc));
+    Cow::Owned(DynamicImage::ImageRgb8(out))
+}
+
+/// RGBの輝度 (ITU-R BT.601) を求めます。
+fn luminance([r, g, b]: [u8; 3]) -> u8 {
+    ((77 * r as u32 + 150 * g as u32 + 29 * b as u32 + 128) >> 8) as u8
+}
+
+/// 輝度のヒストグラムを求めます。
+fn histogram(luma: impl Iterator<Item = u8>) -> [u32; LEVELS] {
+    let mut hist = [0; LEVELS];
+    for v in luma {
+        hist[v as usize] += 1;
+    }
+    hist
+}
+
+/// ヒストグラムを平坦化する輝度の変換表を求めます。
+fn equalization_lut(hist: &[u32; LEVELS]) -> [u8; LEVELS] {
+    let total: u32 = hist.iter().sum();
+    let cdf_min = hist.iter().copied().find(|&n| n > 0).unwrap_or(0);
+    let mut lut = [0; LEVELS];
+    if total == cdf_min {
+        // 輝度が1種類しかない場合は変換しない
+        for (v, l) in lut.iter_mut().enumerate() {
+            *l = v as u8;
+        }
+        return lut;
+    }
+    let mut cdf = 0;
+    for (l, &n) in lut.iter_mut().zip(hist) {
+        cdf += n;
+        *l = ((cdf.saturating_sub(cdf_min)) as f32 / (total - cdf_min) as f32 * 255.).round() as u8;
+    }
+    lut
+}
+
+/// CLAHEで補正した輝度を求めます。
+///
+/// タイルごとに高さを制限したヒストグラムから変換表を作り、画素ごとに周囲の4つのタイルの変換結果を双線形補間します。
+fn clahe(luma: &[u8], width: u32, height: u32, clip_limit: f32, tiles: u32) -> Vec<u8> {
+    let (w, h) = (width as usize, height as usize);
+    let tiles_x = (tiles as usize).clamp(1, w.max(1));
+    let tiles_y = (tiles as usize).clamp(1, h.max(1));
+    let tile_range = |i: usize, n: usize, len: usize| (i * len / n)..((i + 1) * len / n);
+
+    let luts: Vec<[u8; LEVELS]> = (0..tiles_y)
+        .flat_map(|ty| (0..tiles_x).map(move |tx| (tx, ty)))
+        .map(|(tx, ty)| {
+            let (xs, ys) = (tile_range(tx, tiles_x, w), tile_range(ty, tiles_y, h));
+            let mut hist = histogram(
+                ys.flat_map(|y| xs.clone().map(move |x| x + y * w))
+                    .map(|i| luma[i]),
+            );
+            let pixels: u32 = hist.iter().sum();
+            clip_histogram(&mut hist, clip_limit * pixels as f32 / LEVELS as f32);
+
+            // CLAHEではタイルの最小の輝度を0に揃えず、累積分布をそのまま使う
+            let mut lut = [0; LEVELS];
+            let mut cdf = 0;
+            for (l, &n) in lut.iter_mut().zip(&hist) {
+                cdf += n;
+                *l = (cdf as f32 / pixels.max(1) as f32 * 255.).round() as u8;
+            }
+            lut
+        })
+        .collect();
+
+    // 画素の位置をタイルの中心を基準とした座標にし、隣接する2つのタイルと補間の重みを求める
+    let neighbors = |p: usize, len: usize, n: usize| {
+        let t = ((p as f32 + 0.5) * n as f32 / len as f32 - 0.5).clamp(0., (n - 1) as f32);
+        let i = (t as usize).min(n - 1);
+        (i, (i + 1).min(n - 1), t - i as f32)
+    };
+    let mut out = Vec::with_capacity(luma.len());
+    for y in 0..h {
+        let (y0, y1, fy) = neighbors(y, h, tiles_y);
+        for x in 0..w {
+            let (x0, x1, fx) = neighbors(x, w, tiles_x);
+            let v = luma[x + y * w] as usize;
+            let at = |tx: usize, ty: usize| luts[tx + ty * tiles_x][v] as f32;
+            let top = at(x0, y0) * (1. - fx) + at(x1, y0) * fx;
+            let bottom = at(x0, y1) * (1. - fx) + at(x1, y1) * fx;
+            out.push((top * (1. - fy) + bottom * fy).round() as u8);
+        }
+    }
+    out
+}
+
+/// ヒストグラムの高さを `limit` で切り詰め、切り詰めた分を全ての階調に均等に分配します。
+fn clip_histogram(hist: &mut [u32; LEVELS], limit: f32) {
+    let limit = (limit.ceil() as u32).max(1);
+    let excess: u32 = hist.iter().map(|&n| n.saturating_sub(limit)).sum();
+    let (share, rest) = (excess / LEVELS as u32, excess as usize % LEVELS);
+    for (v, n) in hist.iter_mut().enumerate() {
+        *n = (*n).min(limit) + share + (v < rest) as u32;
+    }
+}
+
+/// 画素ごとにRGBを同じ倍率で拡大し、輝度を `target` にします。
+fn apply_luminance(img: &RgbImage, luma: &[u8], target: &[u8]) -> RgbImage {
+    let mut out = img.clone();
+    for ((p, &from), &to) in out.pixels_mut().zip(luma).zip(target) {
+        if from == 0 {
+            p.0 = [to; 3];
+            continue;
+        }
+        let gain = to as f32 / from as f32;
+        for c in p.0.iter_mut() {
+            *c = (*c as f32 * gain).round().min(255.) as u8;
+        }
+    }
+    out
+}
@@ -0,0 +1,203 @@
+//! 正解データと検出結果から検出精度 (AP, mAP, PR曲線) を評価するモジュール
+
+use crate::detection_result::DetectionData;
+
+/// PR曲線上の点
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrPoint {
+    /// この点を得るためのコンフィデンスの閾値
+    pub confidence: f32,
+    /// 適合率
+    pub precision: f32,
+    /// 再現率
+    pub recall: f32,
+}
+
+/// クラスごとの評価結果
+#[derive(Debug, Clone)]
+pub struct ClassEval {
+    /// クラスID
+    pub class: u8,
+    /// 正解データの数
+    pub n_ground_truth: usize,
+    /// 検出結果の数
+    pub n_predictions: usize,
+    /// Average Precision。正解データがない場合はNone
+    pub ap: Option<f32>,
+    /// PR曲線
+    pub pr_curve: Vec<PrPoint>,
+}
+
+/// 画像ごとの検出結果と正解データを蓄積し、検出精度を評価する構造体
+pub struct Evaluator {
+    iou_threshold: f32,
+    /// クラスごとの (コンフィデンス, 正解と対応付けられたか)
+    predictions: Vec<Vec<(f32, bool)>>,
+    /// クラスごとの正解データの数
+    n_ground_truth: Vec<usize>,
+}
+
+impl Evaluator {
+    /// 新しい `Evaluator` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `cls_num` - クラスの数
+    /// * `iou_threshold` - 検出結果を正解とみなすIoUの閾値 (mAP@0.5の場合は0.5)
+    pub fn new(cls_num: usize, iou_threshold: f32) -> Self {
+        Self {
+            iou_threshold,
+            predictions: vec![vec![]; cls_num],
+            n_ground_truth: vec![0; cls_num],
+        }
+    }
+
+    /// 蓄積した結果を削除します。
+    pub fn reset(&mut self) {
+        self.predictions.iter_mut().for_each(|p| p.clear());
+        self.n_ground_truth.iter_mut().for_each(|n| *n = 0);
+    }
+
+    /// 1枚の画像の検出結果と正解データを追加します。
+    ///
+    /// 検出結果はコンフィデンスの高い順に、同じクラスの正解データのうちIoUが最大のものと対応付けられます。
+    /// PASCAL VOC・COCOと同様に、その正解データが既に他の検出結果と対応付けられている場合は
+    /// 次にIoUが大きい正解データを探さず、誤検出とします。
+    ///
+    /// # Args
+    /// * `predictions` - 検出結果
+    /// * `ground_truth` - 正解データ (コンフィデンスは使用しません)
+    pub fn add_image(&mut self, predictions: &[DetectionData], ground_truth: &[DetectionData]) {
+        let cls_num = self.n_ground_truth.len();
+        for gt in ground_truth
+            .iter()
+            .filter(|gt| (gt.class as usize) < cls_num)
+        {
+            self.n_ground_truth[gt.class as usize] += 1;
+        }
+
+        let mut sorted: Vec<&DetectionData> = predictions
+            .iter()
+            .filter(|d| (d.class as usize) < cls_num)
+            .collect();
+        sorted.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+
+        let mut matched = vec![false; ground_truth.len()];
+        for d in sorted {
+            let best = ground_truth
+                .iter()
+                .enumerate()
+                .filter(|(_, gt)| gt.class == d.class)
+                .map(|(i, gt)| (i, d.iou(gt)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .filter(|&(i, v)| v >= self.iou_threshold && !matched[i])
+                .map(|(i, _)| i);
+
+            if let Some(i) = best {
+                matched[i] = true;
+            }
+            self.predictions[d.class as usize].push((d.confidence, best.is_some()));
+        }
+    }
+
+    /// 指定したクラスのPR曲線を求めます。
+    ///
+    /// # Args
+    /// * `class` - クラスID
+    ///
+    /// # Return
+    /// * コンフィデンスの閾値を下げていったときのPR曲線
+    pub fn pr_curve(&self, class: u8) -> Vec<PrPoint> {
+        let (Some(predictions), Some(&n_gt)) = (
+            self.predictions.get(class as usize),
+            self.n_ground_truth.get(class as usize),
+        ) else {
+            return vec![];
+        };
+
+        let mut sorted = predictions.clone();
+        sorted.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let mut tp = 0;
+        sorted
+            .iter()
+            .enumerate()
+            .map(|(i, &(confidence, is_tp))| {
+                if is_tp {
+                    tp += 1;
+                }
+                PrPoint {
+                    confidence,
+                    precision: tp as f32 / (i + 1) as f32,
+                    recall: if n_gt == 0 {
+                        0.
+                    } else {
+                        tp as f32 / n_gt as f32
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// 指定したクラスのAverage Precisionを求めます。
+    ///
+    /// 適合率を単調減少に補間したPR曲線の面積 (全点補間) を計算します。
+    ///
+    /// # Args
+    /// * `class` - クラスID
+    ///
+    /// # Return
+    /// * Average Precision。正解データがない場合はNone
+    pub fn average_precision(&self, class: u8) -> Option<f32> {
+        if *self.n_ground_truth.get(class as usize)? == 0 {
+            return None;
+        }
+
+        let curve = self.pr_curve(class);
+        let mut recalls = vec![0.];
+        let mut precisions = vec![0.];
+        for p in curve.iter() {
+            recalls.push(p.recall);
+            precisions.push(p.precision);
+        }
+
+        // 適合率を後ろから単調減少になるように補間
+        for i in (0..precisions.len() - 1).rev() {
+            precisions[i] = f32::max(precisions[i], precisions[i + 1]);
+        }
+
+        let ap = recalls
+            .windows(2)
+            .zip(precisions.iter().skip(1))
+            .map(|(r, p)| (r[1] - r[0]) * p)
+            .sum();
+        Some(ap)
+    }
+
+    /// 正解データのある全てのクラスのAverage Precisionの平均 (mAP) を求めます。
+    ///
+    /// # Return
+    /// * mAP。正解データが1つもない場合は0
+    pub fn mean_average_precision(&self) -> f32 {
+        let aps: Vec<f32> = (0..self.n_ground_truth.len())
+            .filter_map(|class| self.average_precision(class as u8))
+            .collect();
+        if aps.is_empty() {
+            0.
+        } else {
+            aps.iter().sum::<f32>() / aps.len() as f32
+        }
+    }
+
+    /// 全てのクラスの評価結果を返します。
+    pub fn class_evals(&self) -> Vec<ClassEval> {
+        (0..self.n_ground_truth.len())
+            .map(|class| ClassEval {
+                class: class as u8,
+                n_ground_truth: self.n_ground_truth[class],
+                n_predictions: self.predictions[class].len(),
+                ap: self.average_precision(class as u8),
+                pr_curve: self.pr_curve(class as u8),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,116 @@
+//! レイヤーグループの出力を特徴マップとして取り出すモジュール
+//!
+//! バックボーンの途中の出力を浮動小数点数に戻し、分類や再識別などの後段の処理で埋め込みとして使えるようにします。
+
+use anyhow::{bail, Result};
+
+use crate::npy::NpyArray;
+use crate::postprocess::fix2float;
+
+/// 1回の転送で出力されるチャネル数
+const FOLD_CH: usize = 32;
+
+/// 浮動小数点数に戻したレイヤーグループの出力
+///
+/// データは高さ・幅・チャネルの順 (HWC) に並んでいます。
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureMap {
+    /// 幅
+    pub width: usize,
+    /// 高さ
+    pub height: usize,
+    /// チャネル数
+    pub channels: usize,
+    /// 特徴量 (HWC)
+    pub data: Vec<f32>,
+}
+
+impl FeatureMap {
+    /// IPの出力から特徴マップを作成します。
+    ///
+    /// IPの出力は32チャネルごとに分かれているため、チャネルが連続するように並べ替えます。
+    ///
+    /// # Args
+    /// * `output` - レイヤーグループの出力 (固定小数点数)
+    /// * `width` - 出力の幅
+    /// * `height` - 出力の高さ
+    ///
+    /// # Return
+    /// * 特徴マップ。出力の長さが幅と高さに合わない場合はエラー
+    pub fn from_output(output: &[i16], width: usize, height: usize) -> Result<Self> {
+        let area = width * height;
+        if area == 0 || !output.len().is_multiple_of(area * FOLD_CH) {
+            bail!(
+                "Output length {} does not match {}x{}x{}n",
+                output.len(),
+                width,
+                height,
+                FOLD_CH
+            );
+        }
+        let folds = output.len() / (area * FOLD_CH);
+
+        let mut data = Vec::with_capacity(output.len());
+        for i in 0..area {
+            for j in 0..folds {
+                let base = area * FOLD_CH * j + FOLD_CH * i;
+                data.extend(output[base..base + FOLD_CH].iter().map(|&v| fix2float(v)));
+            }
+        }
+        Ok(Self {
+            width,
+            height,
+            channels: folds * FOLD_CH,
+            data,
+        })
+    }
+
+    /// 指定した位置とチャネルの特徴量を返します。
+    ///
+    /// # Args
+    /// * `x` - 横の位置
+    /// * `y` - 縦の位置
+    /// * `c` - チャネル
+    pub fn get(&self, x: usize, y: usize, c: usize) -> Option<f32> {
+        if x >= self.width || y >= self.height || c >= self.channels {
+            return None;
+        }
+        self.data
+            .get((y * self.width + x) * self.channels + c)
+            .copied()
+    }
+
+    /// 位置ごとの特徴量 (チャネル数の長さ) を返します。
+    pub fn pixel(&self, x: usize, y: usize) -> Option<&[f32]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let begin = (y * self.width + x) * self.channels;
+        self.data.get(begin..begin + self.channels)
+    }
+
+    /// 全ての位置で平均した特徴量 (Global Average Pooling) を返します。
+    ///
+    /// # Return
+    /// * チャネル数の長さの埋め込みベクトル
+    pub fn global_average_pool(&self) -> Vec<f32> {
+        let mut sum = vec![0.; self.channels];
+        for pixel in self.data.chunks_exact(self.channels) {
+            for (s, v) in sum.iter_mut().zip(pixel) {
+                *s += v;
+            }
+        }
+        let area = (self.width * self.height).max(1) as f32;
+        sum.iter().map(|s| s / area).collect()
+    }
+
+    /// 形状が (高さ, 幅, チャネル数) のテンソルに変換します。
+    ///
+    /// PyTorchの出力 (チャネル, 高さ, 幅) と比較する場合は、NumPy側で `transpose(2, 0, 1)` してください。
+    pub fn to_npy(&self) -> NpyArray<f32> {
+        NpyArray {
+            shape: vec![self.height, self.width, self.channels],
+            data: self.data.clone(),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! `ffmpeg` コマンドにフレームを送り、動画ファイルにエンコードするモジュール
+//!
+//! `ffmpeg` フィーチャを有効にした場合のみ利用できます。
+//! GStreamerを導入していない環境でも、`ffmpeg` コマンドがあれば描画済みのフレームを記録できます。
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use anyhow::{bail, ensure, Context, Result};
+use image::RgbImage;
+
+/// `ffmpeg` コマンドの標準入力にフレームを送る構造体
+///
+/// 送られるフレームはRGBの生データです。
+///
+/// 例: `-c:v libx264 -preset ultrafast -movflags frag_keyframe+empty_moov`
+pub struct FfmpegSink {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    width: u32,
+    height: u32,
+}
+
+impl FfmpegSink {
+    /// `ffmpeg` コマンドを起動します。
+    ///
+    /// # Args
+    /// * `args` - 出力ファイルの前に渡すエンコーダなどのオプション (空白区切り)
+    /// * `path` - 出力するファイルのパス。既存のファイルは上書きします
+    /// * `width` - フレームの幅
+    /// * `height` - フレームの高さ
+    /// * `fps` - フレームレート
+    pub fn new<P: AsRef<Path>>(
+        args: &str,
+        path: P,
+        width: u32,
+        height: u32,
+        fps: u32,
+    ) -> Result<Self> {
+        let mut child = Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-y"])
+            .args(["-f", "rawvideo", "-pix_fmt", "rgb24"])
+            .args(["-s", &format!("{}x{}", width, height)])
+            .args(["-r", &fps.max(1).to_string()])
+            .args(["-i", "-"])
+            .args(args.split_whitespace())
+            .arg(path.as_ref())
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("Can't start ffmpeg")?;
+        let stdin = child.stdin.take();
+        Ok(Self {
+            child,
+            stdin,
+            width,
+            height,
+        })
+    }
+
+    /// フレームを送ります。
+    ///
+    /// # Args
+    /// * `img` - フレーム。大きさは `new` で指定したものと同じである必要があります
+    pub fn push(&mut self, img: &RgbImage) -> Result<()> {
+        if img.dimensions() != (self.width, self.height) {
+            bail!(
+                "Frame size {:?} differs from the sink size {:?}",
+                img.dimensions(),
+                (self.width, self.height)
+            );
+        }
+        let stdin = self.stdin.as_mut().context("ffmpeg has already finished")?;
+        stdin
+            .write_all(img.as_raw())
+            .context("ffmpeg stopped receiving frames")?;
+        Ok(())
+    }
+
+    /// 標準入力を閉じ、`ffmpeg` が全てのフレームをエンコードして終了するまで待ちます。
+    pub fn finish(&mut self) -> Result<()> {
+        drop(self.stdin.take());
+        let status = self.child.wait()?;
+        ensure!(status.success(), "ffmpeg exited with {}", status);
+        Ok(())
+    }
+}
+
+impl Drop for FfmpegSink {
+    fn drop(&mut self) {
+        if self.stdin.is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
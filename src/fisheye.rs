@@ -0,0 +1,263 @@
+//! 魚眼カメラの画像を、検出器に入力できる歪みの少ない仮想的なビューに展開するモジュール
+//!
+//! 魚眼レンズは等距離射影 (像高 = 焦点距離 × 入射角) とします。光軸からの向きと画角を指定した
+//! 透視投影 (`ViewProjection::Rectilinear`) または正距円筒図法 (`ViewProjection::Equirectangular`) の
+//! ビューを作り、ビューの検出結果を元の魚眼画像の座標系に戻します。
+//! `VirtualView` は `Preprocessor` を実装しているため、`YoloV3Tiny::start_with_preprocessor` にそのまま渡せます。
+//!
+//! ```ignore
+//! let lens = FisheyeLens::new(960., 960., 940., 190.);
+//! let views: Vec<VirtualView> = [-60f32, 60.]
+//!     .iter()
+//!     .map(|&yaw| VirtualView::new(lens, ViewProjection::Rectilinear { yaw, pitch: 0., hfov: 90. }, 640, 480))
+//!     .collect();
+//! for view in &views {
+//!     let objs = yolo.start_with_preprocessor(&img, view)?; // 魚眼画像の座標系
+//! }
+//! ```
+
+use image::{DynamicImage, RgbImage};
+
+use crate::calibration::sample_bilinear;
+use crate::detection_result::{DetectionData, LetterboxSpace};
+use crate::img_proc;
+use crate::preprocess::Preprocessor;
+
+/// 検出結果を魚眼画像に戻すときに、バウンディングボックスの1辺あたりに変換する点の数
+const EDGE_SAMPLES: usize = 8;
+
+/// 等距離射影の魚眼レンズ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FisheyeLens {
+    /// 光軸の位置 (魚眼画像のx座標)
+    pub cx: f32,
+    /// 光軸の位置 (魚眼画像のy座標)
+    pub cy: f32,
+    /// 入射角1ラジアンあたりの像高 (ピクセル)
+    pub focal: f32,
+    /// 写る範囲の入射角の上限 (ラジアン)
+    pub max_theta: f32,
+}
+
+impl FisheyeLens {
+    /// 新しい `FisheyeLens` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `cx`, `cy` - イメージサークルの中心 (魚眼画像の座標系)
+    /// * `radius` - イメージサークルの半径 (ピクセル)
+    /// * `fov` - イメージサークルに写る画角 (度)
+    pub fn new(cx: f32, cy: f32, radius: f32, fov: f32) -> Self {
+        let max_theta = (fov / 2.).to_radians();
+        Self {
+            cx,
+            cy,
+            focal: radius / max_theta,
+            max_theta,
+        }
+    }
+
+    /// カメラの座標系の向き (x: 右, y: 下, z: 光軸) を魚眼画像の座標に変換します。
+    ///
+    /// # Return
+    /// * 魚眼画像の座標。イメージサークルの外になる向きの場合はNone
+    pub fn project(&self, dir: [f32; 3]) -> Option<(f32, f32)> {
+        let [x, y, z] = dir;
+        if x.hypot(y).atan2(z) > self.max_theta {
+            return None;
+        }
+        Some(self.project_unbounded(dir))
+    }
+
+    /// イメージサークルの外も含めて、向きを等距離射影で魚眼画像の座標に変換します。
+    fn project_unbounded(&self, [x, y, z]: [f32; 3]) -> (f32, f32) {
+        let r_xy = x.hypot(y);
+        if r_xy == 0. {
+            return (self.cx, self.cy);
+        }
+        let r = self.focal * r_xy.atan2(z);
+        (self.cx + r * x / r_xy, self.cy + r * y / r_xy)
+    }
+
+    /// 魚眼画像の座標をカメラの座標系の単位ベクトルに変換します。
+    pub fn unproject(&self, x: f32, y: f32) -> [f32; 3] {
+        let (dx, dy) = (x - self.cx, y - self.cy);
+        let r = dx.hypot(dy);
+        if r == 0. {
+            return [0., 0., 1.];
+        }
+        let theta = r / self.focal;
+        let s = theta.sin() / r;
+        [dx * s, dy * s, theta.cos()]
+    }
+}
+
+/// 仮想的なビューの投影方法。角度は全て度で、`yaw` は右、`pitch` は上が正です。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViewProjection {
+    /// 透視投影 (通常のカメラと同じく直線が直線に写る)
+    Rectilinear {
+        /// ビューの中心の水平方向の角度
+        yaw: f32,
+        /// ビューの中心の垂直方向の角度
+        pitch: f32,
+        /// 水平方向の画角
+        hfov: f32,
+    },
+    /// 正距円筒図法 (広い範囲をパノラマとして展開する)
+    Equirectangular {
+        /// ビューの中心の経度
+        yaw: f32,
+        /// ビューの中心の緯度
+        pitch: f32,
+        /// 経度の範囲
+        hfov: f32,
+        /// 緯度の範囲
+        vfov: f32,
+    },
+}
+
+/// 魚眼画像から切り出した仮想的なビュー
+#[derive(Debug, Clone)]
+pub struct VirtualView {
+    lens: FisheyeLens,
+    projection: ViewProjection,
+    width: u32,
+    height: u32,
+    /// ビューの画素の中心ごとの魚眼画像の座標 (行優先)。イメージサークルの外はNone
+    map: Vec<Option<(f32, f32)>>,
+}
+
+impl VirtualView {
+    /// 新しい `VirtualView` インスタンスを作成します。画素の対応表をここで計算します。
+    ///
+    /// # Args
+    /// * `lens` - 魚眼レンズ
+    /// * `projection` - ビューの投影方法
+    /// * `width` - ビューの幅
+    /// * `height` - ビューの高さ
+    pub fn new(lens: FisheyeLens, projection: ViewProjection, width: u32, height: u32) -> Self {
+        let mut view = Self {
+            lens,
+            projection,
+            width,
+            height,
+            map: vec![],
+        };
+        view.map = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| view.to_fisheye_point(x as f32 + 0.5, y as f32 + 0.5))
+            .collect();
+        view
+    }
+
+    /// ビューの幅と高さを返します。
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// ビューの座標のカメラの座標系での向きを求めます。
+    fn direction(&self, u: f32, v: f32) -> [f32; 3] {
+        let (w, h) = (self.width as f32, self.height as f32);
+        match self.projection {
+            ViewProjection::Rectilinear { yaw, pitch, hfov } => {
+                let f = (w / 2.) / (hfov.to_radians() / 2.).tan();
+                let ray = [(u - w / 2.) / f, (v - h / 2.) / f, 1.];
+                rotate(ray, yaw.to_radians(), pitch.to_radians())
+            }
+            ViewProjection::Equirectangular {
+                yaw,
+                pitch,
+                hfov,
+                vfov,
+            } => {
+                let lon = (yaw + (u / w - 0.5) * hfov).to_radians();
+                let lat = (pitch - (v / h - 0.5) * vfov).to_radians();
+                [lat.cos() * lon.sin(), -lat.sin(), lat.cos() * lon.cos()]
+            }
+        }
+    }
+
+    /// ビューの座標を魚眼画像の座標に変換します。
+    ///
+    /// # Return
+    /// * 魚眼画像の座標。イメージサークルの外の場合はNone
+    pub fn to_fisheye_point(&self, u: f32, v: f32) -> Option<(f32, f32)> {
+        self.lens.project(self.direction(u, v))
+    }
+
+    /// 魚眼画像からビューの画像を作ります。イメージサークルの外の画素は黒にします。
+    ///
+    /// # Args
+    /// * `img` - 魚眼画像
+    pub fn render(&self, img: &DynamicImage) -> DynamicImage {
+        let src = img.to_rgb8();
+        let w = self.width;
+        let out = RgbImage::from_fn(w, self.height, |x, y| {
+            match self.map[(x + y * w) as usize] {
+                // 画素の中心の座標から画素のインデックスにする
+                Some((sx, sy)) => sample_bilinear(&src, sx - 0.5, sy - 0.5),
+                None => image::Rgb([0, 0, 0]),
+            }
+        });
+        DynamicImage::ImageRgb8(out)
+    }
+
+    /// ビューの座標系の検出結果を、魚眼画像の座標系に変換します。
+    ///
+    /// 透視投影の直線は魚眼画像では曲線になるため、バウンディングボックスの辺上の点を変換し、それらを囲む矩形を返します。
+    /// イメージサークルの外にはみ出した点も、等距離射影を延長して変換します。
+    ///
+    /// # Args
+    /// * `d` - ビューの座標系の検出結果
+    ///
+    /// # Return
+    /// * 魚眼画像の座標系の検出結果
+    pub fn to_fisheye(&self, d: &DetectionData) -> DetectionData {
+        let n = EDGE_SAMPLES as f32;
+        let (w, h) = (d.width(), d.height());
+        let edges = (0..EDGE_SAMPLES).flat_map(|i| {
+            let t = i as f32 / n;
+            [
+                (d.x1 + w * t, d.y1),
+                (d.x2, d.y1 + h * t),
+                (d.x2 - w * t, d.y2),
+                (d.x1, d.y2 - h * t),
+            ]
+        });
+        let (mut x1, mut y1, mut x2, mut y2) = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
+        for (u, v) in edges {
+            let (x, y) = self.lens.project_unbounded(self.direction(u, v));
+            x1 = x1.min(x);
+            y1 = y1.min(y);
+            x2 = x2.max(x);
+            y2 = y2.max(y);
+        }
+        DetectionData::new(d.class, x1, y1, x2, y2, d.confidence).with_sub_state(d.sub_state)
+    }
+}
+
+/// ベクトルを上に `pitch`、右に `yaw` だけ回転させます (ラジアン)。
+fn rotate([x, y, z]: [f32; 3], yaw: f32, pitch: f32) -> [f32; 3] {
+    let (sp, cp) = pitch.sin_cos();
+    let (y, z) = (y * cp - z * sp, y * sp + z * cp);
+    let (sy, cy) = yaw.sin_cos();
+    [x * cy + z * sy, y, -x * sy + z * cy]
+}
+
+impl Preprocessor for VirtualView {
+    fn prepare(&self, img: &DynamicImage, size: u32) -> Vec<i16> {
+        img_proc::letterbox(&self.render(img), size, 0)
+    }
+
+    /// ビューのレターボックスの座標を魚眼画像の座標系に戻します。
+    fn inverse_transform(
+        &self,
+        d: &DetectionData<LetterboxSpace>,
+        width: u32,
+        height: u32,
+        size: u32,
+    ) -> DetectionData {
+        let in_view = d.reverse_transform(self.width, self.height, 0, false, size);
+        self.to_fisheye(&in_view).clamp_to(width, height)
+    }
+}
@@ -0,0 +1,146 @@
+//! LinuxのFPGA Managerでビットストリームを書き込むモジュール
+//!
+//! `fpga-manager` フィーチャを有効にした場合のみ利用できます。
+//! PLを書き込んでからコントローラを作成するため、起動直後から1つのバイナリでアクセラレータを使えます。
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+
+/// 書き込みが完了したときのFPGA Managerの状態
+const OPERATING: &str = "operating";
+
+/// FPGA Managerでビットストリームを書き込む構造体
+///
+/// Zynqのfpga_managerは `.bit` ではなく、`bootgen` で変換した `.bin` を読み込みます。
+pub struct FpgaManager {
+    sysfs: PathBuf,
+    firmware_dir: PathBuf,
+    flags: u32,
+    timeout: Duration,
+}
+
+impl Default for FpgaManager {
+    fn default() -> Self {
+        Self::new("fpga0")
+    }
+}
+
+impl FpgaManager {
+    /// 新しい `FpgaManager` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `name` - FPGA Managerの名前 (例: `fpga0`)
+    pub fn new(name: &str) -> Self {
+        Self {
+            sysfs: Path::new("/sys/class/fpga_manager").join(name),
+            firmware_dir: PathBuf::from("/lib/firmware"),
+            flags: 0,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// ファームウェアを探すディレクトリを設定します。
+    ///
+    /// このディレクトリにないビットストリームは、書き込む前にコピーされます。
+    pub fn set_firmware_dir<P: AsRef<Path>>(&mut self, firmware_dir: P) -> &mut Self {
+        self.firmware_dir = firmware_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// 書き込みのフラグを設定します (0: 全体の書き込み、1: 部分再構成)。
+    pub fn set_flags(&mut self, flags: u32) -> &mut Self {
+        self.flags = flags;
+        self
+    }
+
+    /// 書き込みの完了を待つ最大の時間を設定します。
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// FPGA Managerの状態 (例: `operating`) を返します。
+    pub fn state(&self) -> Result<String> {
+        let path = self.sysfs.join("state");
+        Ok(fs::read_to_string(&path)
+            .with_context(|| format!("Can't read {}", path.display()))?
+            .trim()
+            .to_string())
+    }
+
+    /// ビットストリームをPLに書き込み、完了するまで待ちます。
+    ///
+    /// # Args
+    /// * `bitstream` - ビットストリーム (`.bin`) のパス
+    pub fn program<P: AsRef<Path>>(&self, bitstream: P) -> Result<()> {
+        let bitstream = bitstream.as_ref();
+        let file_name = bitstream
+            .file_name()
+            .with_context(|| format!("Invalid bitstream path: {}", bitstream.display()))?;
+
+        // fpga_managerはファームウェアのディレクトリからの相対パスしか受け付けない
+        let firmware = self.firmware_dir.join(file_name);
+        if fs::canonicalize(bitstream).ok() != fs::canonicalize(&firmware).ok() {
+            fs::copy(bitstream, &firmware).with_context(|| {
+                format!(
+                    "Can't copy {} to {}",
+                    bitstream.display(),
+                    firmware.display()
+                )
+            })?;
+        }
+
+        info!(
+            "Programming {} with {}",
+            self.sysfs.display(),
+            firmware.display()
+        );
+        self.write("flags", &self.flags.to_string())?;
+        self.write("firmware", &file_name.to_string_lossy())?;
+
+        let start = Instant::now();
+        loop {
+            let state = self.state()?;
+            if state == OPERATING {
+                break;
+            }
+            if state.contains("error") {
+                bail!("FPGA Manager failed to program the PL: {}", state);
+            }
+            if start.elapsed() > self.timeout {
+                bail!("Timed out programming the PL (state: {})", state);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        info!("PL programmed in {:?}", start.elapsed());
+        Ok(())
+    }
+
+    /// ビットストリームを書き込んでから、コントローラを作成します。
+    ///
+    /// # Args
+    /// * `bitstream` - ビットストリーム (`.bin`) のパス
+    /// * `init` - PLの書き込み後に呼び出す、コントローラ (`YoloV3Tiny` など) を作成する関数
+    ///
+    /// # Return
+    /// * `init` が返した値
+    pub fn bring_up<P, F, T>(&self, bitstream: P, init: F) -> Result<T>
+    where
+        P: AsRef<Path>,
+        F: FnOnce() -> Result<T>,
+    {
+        self.program(bitstream)?;
+        init()
+    }
+
+    /// sysfsの属性に書き込みます。
+    fn write(&self, attr: &str, value: &str) -> Result<()> {
+        let path = self.sysfs.join(attr);
+        fs::write(&path, value).with_context(|| format!("Can't write {}", path.display()))
+    }
+}
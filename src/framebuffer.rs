@@ -0,0 +1,164 @@
+//! バウンディングボックスを描画したフレームをLinuxのフレームバッファ (`/dev/fb0` など) に表示するモジュール
+//!
+//! ボードに接続したモニタに、X11や映像の配信を介さずに検出結果を表示できます。
+
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::FileExt;
+
+use anyhow::{bail, Context, Result};
+use image::RgbImage;
+
+use crate::detection_result::DetectionData;
+use crate::img_proc::{draw_bbox, fast_resize};
+
+/// フレームバッファのピクセル形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PixelFormat {
+    /// 16bit (R:5, G:6, B:5)
+    Rgb565,
+    /// 24bit (B, G, R)
+    Bgr888,
+    /// 32bit (B, G, R, X)
+    Bgrx8888,
+}
+
+impl PixelFormat {
+    /// 1ピクセルあたりのバイト数を返します。
+    fn bytes(&self) -> usize {
+        match self {
+            Self::Rgb565 => 2,
+            Self::Bgr888 => 3,
+            Self::Bgrx8888 => 4,
+        }
+    }
+
+    /// RGBのピクセルをこの形式に変換して書き込みます。
+    fn write(&self, dst: &mut [u8], [r, g, b]: [u8; 3]) {
+        match self {
+            Self::Rgb565 => {
+                let v = (u16::from(r) >> 3) << 11 | (u16::from(g) >> 2) << 5 | u16::from(b) >> 3;
+                dst.copy_from_slice(&v.to_le_bytes());
+            }
+            Self::Bgr888 => dst.copy_from_slice(&[b, g, r]),
+            Self::Bgrx8888 => dst.copy_from_slice(&[b, g, r, 0xff]),
+        }
+    }
+}
+
+/// フレームをフレームバッファに表示する構造体
+///
+/// フレームは画面に収まるよう縦横比を保って拡大・縮小し、中央に表示します。
+pub struct FramebufferRenderer {
+    file: File,
+    width: u32,
+    height: u32,
+    stride: usize,
+    format: PixelFormat,
+    buffer: Vec<u8>,
+    font_size: f32,
+    line_thickness: f32,
+}
+
+impl FramebufferRenderer {
+    /// フレームバッファを開きます。
+    ///
+    /// 画面の大きさとピクセル形式は `/sys/class/graphics/{name}/` から取得します。
+    ///
+    /// # Args
+    /// * `name` - フレームバッファのデバイス名 (例: `fb0`)
+    pub fn open(name: &str) -> Result<Self> {
+        let sysfs = format!("/sys/class/graphics/{}", name);
+        let read = |attr: &str| -> Result<String> {
+            let path = format!("{}/{}", sysfs, attr);
+            Ok(fs::read_to_string(&path)
+                .with_context(|| format!("Can't read {}", path))?
+                .trim()
+                .to_string())
+        };
+
+        let size = read("virtual_size")?;
+        let (width, height) = size
+            .split_once(',')
+            .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+            .with_context(|| format!("Invalid virtual_size: {}", size))?;
+        let format = match read("bits_per_pixel")?.as_str() {
+            "16" => PixelFormat::Rgb565,
+            "24" => PixelFormat::Bgr888,
+            "32" => PixelFormat::Bgrx8888,
+            bpp => bail!("Unsupported bits_per_pixel: {}", bpp),
+        };
+        let stride = read("stride")?.parse().context("Invalid stride")?;
+
+        let dev = format!("/dev/{}", name);
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&dev)
+            .with_context(|| format!("Can't open {}", dev))?;
+
+        Ok(Self {
+            file,
+            width,
+            height,
+            stride,
+            format,
+            buffer: vec![0; stride * height as usize],
+            font_size: 14.,
+            line_thickness: 2.,
+        })
+    }
+
+    /// 画面の幅と高さを返します。
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// 描画するラベルの文字の大きさと線の太さを設定します。
+    pub fn set_draw_style(&mut self, font_size: f32, line_thickness: f32) -> &mut Self {
+        self.font_size = font_size;
+        self.line_thickness = line_thickness;
+        self
+    }
+
+    /// 検出結果を描画したフレームを表示します。
+    ///
+    /// # Args
+    /// * `img` - フレーム
+    /// * `detections` - 検出結果 (`img` の座標系)
+    pub fn render(&mut self, img: &RgbImage, detections: &[DetectionData]) -> Result<()> {
+        let mut annotated = img.clone();
+        draw_bbox(
+            &mut annotated,
+            detections,
+            self.font_size,
+            self.line_thickness,
+        );
+        let fitted = if annotated.dimensions() == (self.width, self.height) {
+            annotated
+        } else {
+            fast_resize(&annotated, self.width, self.height)
+        };
+
+        let x_offset = self.width.saturating_sub(fitted.width()) / 2;
+        let y_offset = self.height.saturating_sub(fitted.height()) / 2;
+        let bytes = self.format.bytes();
+        let line_len = fitted.width().min(self.width) as usize * bytes;
+
+        self.buffer.fill(0);
+        for (y, row) in fitted.rows().take(self.height as usize).enumerate() {
+            let start = (y + y_offset as usize) * self.stride + x_offset as usize * bytes;
+            let line = &mut self.buffer[start..start + line_len];
+            for (dst, pixel) in line.chunks_exact_mut(bytes).zip(row) {
+                self.format.write(dst, pixel.0);
+            }
+        }
+        self.file.write_all_at(&self.buffer, 0)?;
+        Ok(())
+    }
+
+    /// 画面を黒で塗りつぶします。
+    pub fn clear(&mut self) -> Result<()> {
+        self.buffer.fill(0);
+        self.file.write_all_at(&self.buffer, 0)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! 検出結果の足元の位置を、地面上の座標 (メートル) に射影するモジュール
+//!
+//! 地面が平面であれば、画像と地面の座標は射影変換 (ホモグラフィ) で対応します。
+//! 地面上の4点の画像の座標と実際の座標から射影変換を求め、物体の位置や物体間の距離を
+//! 鳥瞰図の座標で扱えるようにします。
+//!
+//! ```toml
+//! [ground_plane]
+//! image_points = [[412, 690], [868, 690], [760, 420], [520, 420]]
+//! world_points = [[-1.75, 5], [1.75, 5], [1.75, 20], [-1.75, 20]]
+//! ```
+
+use anyhow::{ensure, Result};
+use serde::Deserialize;
+
+use crate::detection_result::DetectionData;
+
+/// 射影変換の計算で特異とみなすピボットの大きさ
+const SINGULAR_EPS: f64 = 1e-12;
+
+/// 地面の射影変換の設定 (`[ground_plane]`)
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroundPlaneConfig {
+    /// 基準点の画像の座標
+    pub image_points: [[f32; 2]; 4],
+    /// 基準点の地面上の座標 (メートル)
+    pub world_points: [[f32; 2]; 4],
+}
+
+/// 画像の座標と地面上の座標を相互に変換する射影変換
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GroundPlane {
+    /// 画像から地面への射影変換の行列
+    to_world: [[f64; 3]; 3],
+    /// 地面から画像への射影変換の行列
+    to_image: [[f64; 3]; 3],
+}
+
+impl GroundPlane {
+    /// 4組の基準点から射影変換を求めます。
+    ///
+    /// # Args
+    /// * `image_points` - 基準点の画像の座標
+    /// * `world_points` - 基準点の地面上の座標 (メートル)
+    ///
+    /// # Return
+    /// * 3点が同一直線上にあるなど、射影変換が定まらない場合はエラー
+    pub fn from_points(image_points: [[f32; 2]; 4], world_points: [[f32; 2]; 4]) -> Result<Self> {
+        let to_world = homography(&image_points, &world_points)?;
+        let to_image = homography(&world_points, &image_points)?;
+        Ok(Self { to_world, to_image })
+    }
+
+    /// 設定から射影変換を求めます。
+    pub fn from_config(config: &GroundPlaneConfig) -> Result<Self> {
+        Self::from_points(config.image_points, config.world_points)
+    }
+
+    /// 画像の座標を地面上の座標に変換します。
+    ///
+    /// # Return
+    /// * 地面上の座標 (メートル)。地平線より上の点など、地面と交わらない場合はNone
+    pub fn to_world(&self, x: f32, y: f32) -> Option<(f32, f32)> {
+        apply(&self.to_world, x, y)
+    }
+
+    /// 地面上の座標を画像の座標に変換します。
+    ///
+    /// # Return
+    /// * 画像の座標。カメラの後ろの点など、画像に写らない場合はNone
+    pub fn to_image(&self, x: f32, y: f32) -> Option<(f32, f32)> {
+        apply(&self.to_image, x, y)
+    }
+
+    /// 検出結果のバウンディングボックスの下端の中央 (足元) を地面上の座標に変換します。
+    pub fn project_detection(&self, d: &DetectionData) -> Option<(f32, f32)> {
+        let (cx, _) = d.center();
+        self.to_world(cx, d.y2)
+    }
+
+    /// 2つの検出結果の足元の地面上の距離 (メートル) を求めます。
+    pub fn distance(&self, a: &DetectionData, b: &DetectionData) -> Option<f32> {
+        let (ax, ay) = self.project_detection(a)?;
+        let (bx, by) = self.project_detection(b)?;
+        Some((ax - bx).hypot(ay - by))
+    }
+}
+
+/// 射影変換で点を変換します。変換先が無限遠またはカメラの後ろになる場合はNone
+fn apply(h: &[[f64; 3]; 3], x: f32, y: f32) -> Option<(f32, f32)> {
+    let (x, y) = (x as f64, y as f64);
+    let w = h[2][0] * x + h[2][1] * y + h[2][2];
+    if w <= SINGULAR_EPS {
+        return None;
+    }
+    Some((
+        ((h[0][0] * x + h[0][1] * y + h[0][2]) / w) as f32,
+        ((h[1][0] * x + h[1][1] * y + h[1][2]) / w) as f32,
+    ))
+}
+
+/// 4組の対応点から、`src` を `dst` に移す射影変換を求めます (h33 = 1 として8元連立方程式を解きます)。
+///
+/// 基準点の内側が変換後に表になるよう、`src` の基準点の重心で w が正になるように符号を揃えます。
+fn homography(src: &[[f32; 2]; 4], dst: &[[f32; 2]; 4]) -> Result<[[f64; 3]; 3]> {
+    let mut a = [[0f64; 9]; 8];
+    for (i, (s, d)) in src.iter().zip(dst).enumerate() {
+        let (x, y) = (s[0] as f64, s[1] as f64);
+        let (u, v) = (d[0] as f64, d[1] as f64);
+        a[2 * i] = [x, y, 1., 0., 0., 0., -u * x, -u * y, u];
+        a[2 * i + 1] = [0., 0., 0., x, y, 1., -v * x, -v * y, v];
+    }
+
+    // 部分ピボット選択付きのガウスの消去法
+    for col in 0..8 {
+        let pivot = (col..8)
+            .max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))
+            .unwrap();
+        ensure!(
+            a[pivot][col].abs() > SINGULAR_EPS,
+            "The reference points are degenerate (three of them may be collinear)"
+        );
+        a.swap(col, pivot);
+        let pivot_row = a[col];
+        for (_, row) in a.iter_mut().enumerate().filter(|(i, _)| *i != col) {
+            let f = row[col] / pivot_row[col];
+            for (v, p) in row.iter_mut().zip(&pivot_row).skip(col) {
+                *v -= f * p;
+            }
+        }
+    }
+    let p: Vec<f64> = (0..8).map(|i| a[i][8] / a[i][i]).collect();
+    let mut h = [[p[0], p[1], p[2]], [p[3], p[4], p[5]], [p[6], p[7], 1.]];
+
+    let (cx, cy) = src.iter().fold((0., 0.), |(x, y), s| {
+        (x + s[0] as f64 / 4., y + s[1] as f64 / 4.)
+    });
+    if h[2][0] * cx + h[2][1] * cy + h[2][2] < 0. {
+        h = h.map(|r| r.map(|v| -v));
+    }
+    Ok(h)
+}
@@ -0,0 +1,176 @@
+//! 正解データ (アノテーション) を読み込むモジュール
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::detection_result::DetectionData;
+
+/// 1枚の画像の正解データ
+///
+/// 各バウンディングボックスのコンフィデンスは1.0です。
+#[derive(Debug, Clone, Default)]
+pub struct GroundTruth {
+    /// 正解のバウンディングボックス (元の画像の座標系)
+    pub boxes: Vec<DetectionData>,
+}
+
+impl GroundTruth {
+    /// YOLO形式のラベルファイル (`class cx cy w h`、座標は正規化済み) を読み込みます。
+    ///
+    /// # Args
+    /// * `path` - ラベルファイルのパス
+    /// * `width` - 画像の幅
+    /// * `height` - 画像の高さ
+    ///
+    /// # Return
+    /// * 正解データ
+    pub fn from_yolo_txt<P: AsRef<Path>>(path: P, width: u32, height: u32) -> Result<Self> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).with_context(|| format!("Can't read {}", path.display()))?;
+        Self::parse_yolo_txt(&text, width, height)
+            .with_context(|| format!("Invalid label file: {}", path.display()))
+    }
+
+    /// YOLO形式のラベルを解析します。
+    ///
+    /// # Args
+    /// * `text` - ラベルファイルの内容
+    /// * `width` - 画像の幅
+    /// * `height` - 画像の高さ
+    ///
+    /// # Return
+    /// * 正解データ
+    pub fn parse_yolo_txt(text: &str, width: u32, height: u32) -> Result<Self> {
+        let w = width as f32;
+        let h = height as f32;
+
+        let mut boxes = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 5 {
+                bail!("line {}: expected 5 fields, found {}", n + 1, fields.len());
+            }
+
+            let class: u8 = fields[0]
+                .parse()
+                .with_context(|| format!("line {}: invalid class", n + 1))?;
+            let mut v = [0f32; 4];
+            for (i, field) in fields[1..5].iter().enumerate() {
+                v[i] = field
+                    .parse()
+                    .with_context(|| format!("line {}: invalid coordinate", n + 1))?;
+            }
+            let [cx, cy, bw, bh] = v;
+
+            boxes.push(DetectionData::new(
+                class,
+                (cx - bw / 2.) * w,
+                (cy - bh / 2.) * h,
+                (cx + bw / 2.) * w,
+                (cy + bh / 2.) * h,
+                1.,
+            ));
+        }
+        Ok(Self { boxes })
+    }
+
+    /// COCO形式のアノテーションファイルを読み込みます。
+    ///
+    /// カテゴリIDは昇順に並べたときの順番 (0始まり) をクラスIDとします。
+    ///
+    /// # Args
+    /// * `path` - アノテーションファイル (JSON) のパス
+    ///
+    /// # Return
+    /// * 画像のファイル名をキーとした正解データ
+    pub fn load_coco<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Self>> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).with_context(|| format!("Can't read {}", path.display()))?;
+        let json: Value = serde_json::from_str(&text)
+            .with_context(|| format!("Invalid JSON: {}", path.display()))?;
+        Self::parse_coco(&json)
+    }
+
+    /// COCO形式のアノテーションを解析します。
+    ///
+    /// # Args
+    /// * `json` - アノテーションファイルの内容
+    ///
+    /// # Return
+    /// * 画像のファイル名をキーとした正解データ
+    pub fn parse_coco(json: &Value) -> Result<HashMap<String, Self>> {
+        let field = |v: &Value, key: &str| -> Result<Value> {
+            v.get(key)
+                .cloned()
+                .with_context(|| format!("missing field: {}", key))
+        };
+        let as_u64 = |v: &Value, key: &str| -> Result<u64> {
+            field(v, key)?
+                .as_u64()
+                .with_context(|| format!("field is not an integer: {}", key))
+        };
+        let as_array = |v: &Value, key: &str| -> Result<Vec<Value>> {
+            match field(v, key)? {
+                Value::Array(a) => Ok(a),
+                _ => bail!("field is not an array: {}", key),
+            }
+        };
+
+        let category_ids: BTreeSet<u64> = as_array(json, "categories")?
+            .iter()
+            .map(|c| as_u64(c, "id"))
+            .collect::<Result<_>>()?;
+        let class_of: HashMap<u64, u8> = category_ids
+            .iter()
+            .enumerate()
+            .map(|(i, &id)| (id, i as u8))
+            .collect();
+
+        let mut file_names = HashMap::new();
+        let mut gts = HashMap::new();
+        for img in as_array(json, "images")? {
+            let file_name = field(&img, "file_name")?
+                .as_str()
+                .context("field is not a string: file_name")?
+                .to_string();
+            file_names.insert(as_u64(&img, "id")?, file_name.clone());
+            gts.insert(file_name, Self::default());
+        }
+
+        for ann in as_array(json, "annotations")? {
+            let image_id = as_u64(&ann, "image_id")?;
+            let category_id = as_u64(&ann, "category_id")?;
+            let bbox: Vec<f32> = as_array(&ann, "bbox")?
+                .iter()
+                .map(|v| v.as_f64().map(|v| v as f32))
+                .collect::<Option<_>>()
+                .context("bbox is not an array of numbers")?;
+            if bbox.len() != 4 {
+                bail!("bbox must have 4 elements");
+            }
+
+            let file_name = file_names
+                .get(&image_id)
+                .with_context(|| format!("unknown image_id: {}", image_id))?;
+            let class = *class_of
+                .get(&category_id)
+                .with_context(|| format!("unknown category_id: {}", category_id))?;
+            let (x, y, w, h) = (bbox[0], bbox[1], bbox[2], bbox[3]);
+            gts.get_mut(file_name)
+                .unwrap()
+                .boxes
+                .push(DetectionData::new(class, x, y, x + w, y + h, 1.));
+        }
+        Ok(gts)
+    }
+}
@@ -0,0 +1,94 @@
+//! `YoloService` をgRPC経由で利用するためのサーバ
+//!
+//! `grpc` フィーチャを有効にした場合のみ利用できます。スキーマは `proto/detection.proto` です。
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use anyhow::Result;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::service::YoloServiceHandle;
+
+/// `proto/detection.proto` から生成された型
+pub mod proto {
+    tonic::include_proto!("yolo");
+}
+
+use proto::detector_server::{Detector, DetectorServer};
+use proto::{DetectRequest, DetectResponse, Detection};
+
+/// `Detector` サービスの実装
+pub struct GrpcDetector {
+    handle: YoloServiceHandle,
+}
+
+impl GrpcDetector {
+    /// 新しい `GrpcDetector` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `handle` - 推論を依頼する `YoloService` のハンドル
+    pub fn new(handle: YoloServiceHandle) -> Self {
+        Self { handle }
+    }
+
+    /// tonicの `Server` に登録するためのサービスに変換します。
+    pub fn into_service(self) -> DetectorServer<Self> {
+        DetectorServer::new(self)
+    }
+}
+
+#[tonic::async_trait]
+impl Detector for GrpcDetector {
+    async fn detect(
+        &self,
+        request: Request<DetectRequest>,
+    ) -> Result<Response<DetectResponse>, Status> {
+        let req = request.into_inner();
+        let img = image::load_from_memory(&req.image)
+            .map_err(|e| Status::invalid_argument(format!("can't decode image: {}", e)))?;
+        let (width, height) = (img.width(), img.height());
+
+        // 推論が終わるまでブロックするため、非同期ランタイムのスレッドを占有しないようにする
+        let handle = self.handle.clone();
+        let start = Instant::now();
+        let detections = tokio::task::spawn_blocking(move || handle.detect(img, req.rotate_angle))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::internal(format!("{:#}", e)))?;
+        let elapsed = start.elapsed();
+
+        Ok(Response::new(DetectResponse {
+            frame_id: req.frame_id,
+            width,
+            height,
+            inference_ms: elapsed.as_secs_f64() * 1000.,
+            detections: detections
+                .iter()
+                .map(|d| Detection {
+                    class: d.class as u32,
+                    x1: d.x1,
+                    y1: d.y1,
+                    x2: d.x2,
+                    y2: d.y2,
+                    confidence: d.confidence,
+                })
+                .collect(),
+        }))
+    }
+}
+
+/// gRPCサーバを起動します。サーバが停止するまで戻りません。
+///
+/// tokioのランタイム上で呼び出す必要があります。
+///
+/// # Args
+/// * `addr` - 待ち受けるアドレス
+/// * `handle` - 推論を依頼する `YoloService` のハンドル
+pub async fn serve(addr: SocketAddr, handle: YoloServiceHandle) -> Result<()> {
+    Server::builder()
+        .add_service(GrpcDetector::new(handle).into_service())
+        .serve(addr)
+        .await?;
+    Ok(())
+}
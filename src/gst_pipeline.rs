@@ -0,0 +1,207 @@
+//! GStreamerのパイプラインからフレームを取得し、描画済みのフレームをパイプラインに送るモジュール
+//!
+//! `gstreamer` フィーチャを有効にした場合のみ利用できます。
+//! RTSPカメラや動画ファイル、ハードウェアデコーダなど、任意のパイプラインを入出力に利用できます。
+
+use anyhow::{anyhow, bail, Context, Result};
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_app::{AppSink, AppSrc};
+use gstreamer_video::VideoInfo;
+use image::RgbImage;
+
+/// パイプラインを作成し、名前を指定して要素を取り出します。
+fn launch<T: IsA<gst::Element>>(description: &str, name: &str) -> Result<(gst::Pipeline, T)> {
+    gst::init()?;
+    let pipeline = gst::parse::launch(description)?
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| anyhow!("Pipeline description must be a pipeline"))?;
+    let element = pipeline
+        .by_name(name)
+        .with_context(|| format!("Pipeline has no element named '{}'", name))?
+        .downcast::<T>()
+        .map_err(|_| anyhow!("Element '{}' has an unexpected type", name))?;
+    Ok((pipeline, element))
+}
+
+/// バスからEOSまたはエラーのメッセージを待ちます。
+///
+/// # Args
+/// * `pipeline` - パイプライン
+/// * `timeout` - 待つ時間。Noneの場合は無期限
+///
+/// # Return
+/// * EOSを受け取った場合は `true`、タイムアウトした場合は `false`。エラーのメッセージを受け取った場合はエラー
+fn wait_bus(pipeline: &gst::Pipeline, timeout: Option<gst::ClockTime>) -> Result<bool> {
+    let bus = pipeline.bus().context("Pipeline has no bus")?;
+    let Some(msg) =
+        bus.timed_pop_filtered(timeout, &[gst::MessageType::Eos, gst::MessageType::Error])
+    else {
+        return Ok(false);
+    };
+    match msg.view() {
+        gst::MessageView::Error(err) => bail!(
+            "GStreamer error: {} ({})",
+            err.error(),
+            err.debug().unwrap_or_default()
+        ),
+        _ => Ok(true),
+    }
+}
+
+/// GStreamerのパイプラインの `appsink` からフレームを取得する構造体
+///
+/// パイプラインの記述には `appsink name=sink` を含める必要があります。
+/// `appsink` のcapsはRGBに設定されるため、手前に `videoconvert` を置いてください。
+///
+/// 例: `rtspsrc location=rtsp://... ! decodebin ! videoconvert ! appsink name=sink`
+pub struct GstSource {
+    pipeline: gst::Pipeline,
+    appsink: AppSink,
+}
+
+impl GstSource {
+    /// パイプラインを作成し、再生を開始します。
+    ///
+    /// # Args
+    /// * `description` - `gst-launch-1.0` と同じ形式のパイプラインの記述
+    pub fn new(description: &str) -> Result<Self> {
+        let (pipeline, appsink): (_, AppSink) = launch(description, "sink")?;
+        appsink.set_caps(Some(
+            &gst::Caps::builder("video/x-raw")
+                .field("format", "RGB")
+                .build(),
+        ));
+        pipeline.set_state(gst::State::Playing)?;
+        Ok(Self { pipeline, appsink })
+    }
+
+    /// 次のフレームを取得します。フレームが届くまでブロックします。
+    ///
+    /// # Return
+    /// * フレーム。ストリームが終了した場合はNone
+    pub fn next_frame(&mut self) -> Result<Option<RgbImage>> {
+        let sample = match self.appsink.pull_sample() {
+            Ok(sample) => sample,
+            Err(_) if self.appsink.is_eos() => return Ok(None),
+            Err(_) => {
+                wait_bus(&self.pipeline, Some(gst::ClockTime::SECOND))?;
+                bail!("Can't pull a sample from appsink");
+            }
+        };
+
+        let caps = sample.caps().context("Sample has no caps")?;
+        let info = VideoInfo::from_caps(caps)?;
+        let buffer = sample.buffer().context("Sample has no buffer")?;
+        let map = buffer.map_readable()?;
+
+        // 行の末尾にパディングがある場合があるため1行ずつコピーする
+        let (width, height) = (info.width(), info.height());
+        let stride = info.stride()[0] as usize;
+        let row = width as usize * 3;
+        let mut data = Vec::with_capacity(row * height as usize);
+        for line in map.as_slice().chunks(stride).take(height as usize) {
+            data.extend_from_slice(&line[..row]);
+        }
+        RgbImage::from_raw(width, height, data)
+            .context("Frame is smaller than its caps")
+            .map(Some)
+    }
+}
+
+impl Iterator for GstSource {
+    type Item = Result<RgbImage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+impl Drop for GstSource {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+/// GStreamerのパイプラインの `appsrc` にフレームを送る構造体
+///
+/// パイプラインの記述には `appsrc name=src` を含める必要があります。送られるフレームはRGBです。
+///
+/// 例: `appsrc name=src ! videoconvert ! x264enc ! rtph264pay ! udpsink host=... port=5000`
+pub struct GstSink {
+    pipeline: gst::Pipeline,
+    appsrc: AppSrc,
+    width: u32,
+    height: u32,
+    frame_duration: gst::ClockTime,
+    frame_count: u64,
+}
+
+impl GstSink {
+    /// パイプラインを作成し、再生を開始します。
+    ///
+    /// # Args
+    /// * `description` - `gst-launch-1.0` と同じ形式のパイプラインの記述
+    /// * `width` - フレームの幅
+    /// * `height` - フレームの高さ
+    /// * `fps` - フレームレート
+    pub fn new(description: &str, width: u32, height: u32, fps: u32) -> Result<Self> {
+        let (pipeline, appsrc): (_, AppSrc) = launch(description, "src")?;
+        appsrc.set_caps(Some(
+            &gst::Caps::builder("video/x-raw")
+                .field("format", "RGB")
+                .field("width", width as i32)
+                .field("height", height as i32)
+                .field("framerate", gst::Fraction::new(fps as i32, 1))
+                .build(),
+        ));
+        appsrc.set_format(gst::Format::Time);
+        pipeline.set_state(gst::State::Playing)?;
+        Ok(Self {
+            pipeline,
+            appsrc,
+            width,
+            height,
+            frame_duration: gst::ClockTime::from_nseconds(1_000_000_000 / fps.max(1) as u64),
+            frame_count: 0,
+        })
+    }
+
+    /// フレームを送ります。
+    ///
+    /// # Args
+    /// * `img` - フレーム。大きさは `new` で指定したものと同じである必要があります
+    pub fn push(&mut self, img: &RgbImage) -> Result<()> {
+        if img.dimensions() != (self.width, self.height) {
+            bail!(
+                "Frame size {:?} differs from the sink size {:?}",
+                img.dimensions(),
+                (self.width, self.height)
+            );
+        }
+
+        let mut buffer = gst::Buffer::from_mut_slice(img.as_raw().clone());
+        if let Some(buffer) = buffer.get_mut() {
+            let pts = self.frame_duration.nseconds() * self.frame_count;
+            buffer.set_pts(gst::ClockTime::from_nseconds(pts));
+            buffer.set_duration(self.frame_duration);
+        }
+        self.appsrc.push_buffer(buffer)?;
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    /// ストリームの終了を通知し、パイプラインが全てのフレームを処理するまで待ちます。
+    pub fn finish(&mut self) -> Result<()> {
+        self.appsrc.end_of_stream()?;
+        wait_bus(&self.pipeline, gst::ClockTime::NONE)?;
+        self.pipeline.set_state(gst::State::Null)?;
+        Ok(())
+    }
+}
+
+impl Drop for GstSink {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
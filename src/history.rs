@@ -0,0 +1,158 @@
+//! 直近のフレームと検出結果をメモリ上に保持するモジュール
+//!
+//! 異常を検知したときなどに、その直前に何が起きていたかを確認するため、
+//! 直近Nフレーム分の画像と検出結果をリングバッファに保持し、必要なときにだけディスクに書き出します。
+//!
+//! ```ignore
+//! let mut history = FrameHistory::new(30);
+//! loop {
+//!     let result = yolo.start_with_img_proc(&DynamicImage::ImageRgb8(frame.clone()), 0)?;
+//!     history.push(frame_id, frame, &result);
+//!     if anomaly_detected {
+//!         history.dump(format!("/data/events/f{:08}", frame_id))?;
+//!     }
+//! }
+//! ```
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use image::RgbImage;
+
+use crate::detection_log::ndjson_record;
+use crate::detection_result::DetectionData;
+
+/// 保持している1フレーム分の記録
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    /// フレーム番号
+    pub frame_id: u64,
+    /// 記録した時刻
+    pub timestamp: SystemTime,
+    /// フレームの画像
+    pub frame: RgbImage,
+    /// フレームの検出結果 (画像の座標系)
+    pub detections: Vec<DetectionData>,
+}
+
+/// 直近のフレームと検出結果を保持するリングバッファ
+///
+/// 保持するフレーム数が上限に達すると、古いフレームから破棄します。
+pub struct FrameHistory {
+    capacity: usize,
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl FrameHistory {
+    /// 新しい `FrameHistory` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `capacity` - 保持するフレームの最大数
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// 保持するフレームの最大数を設定します。現在の数が上限を超える場合は古いフレームから破棄します。
+    pub fn set_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+        self
+    }
+
+    /// 保持するフレームの最大数を返します。
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// 保持しているフレームの数を返します。
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// フレームを保持していない場合にtrueを返します。
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 保持しているフレームを古い順に返します。
+    pub fn iter(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+
+    /// 最新のフレームを返します。
+    pub fn latest(&self) -> Option<&HistoryEntry> {
+        self.entries.back()
+    }
+
+    /// 1フレーム分の画像と検出結果を追加します。上限に達している場合は最も古いフレームを破棄します。
+    ///
+    /// # Args
+    /// * `frame_id` - フレーム番号
+    /// * `frame` - フレームの画像
+    /// * `detections` - フレームの検出結果 (画像の座標系)
+    pub fn push(&mut self, frame_id: u64, frame: RgbImage, detections: &[DetectionData]) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(HistoryEntry {
+            frame_id,
+            timestamp: SystemTime::now(),
+            frame,
+            detections: detections.to_vec(),
+        });
+    }
+
+    /// 保持しているフレームをすべて破棄します。
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// 保持しているフレームをディレクトリに書き出します。ディレクトリが存在しない場合は作成します。
+    ///
+    /// 画像は `f{フレーム番号}.png` として、検出結果は古い順に `detections.ndjson` に
+    /// `DetectionLogger` のNDJSONと同じ形式で保存します。書き出した後も保持しているフレームは破棄しません。
+    ///
+    /// # Args
+    /// * `dir` - 出力先のディレクトリ
+    ///
+    /// # Return
+    /// * 書き出したフレームの数
+    pub fn dump<P: AsRef<Path>>(&self, dir: P) -> Result<usize> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join("detections.ndjson");
+        let mut writer = BufWriter::new(
+            File::create(&path).with_context(|| format!("Can't create {}", path.display()))?,
+        );
+        for entry in self.entries.iter() {
+            entry
+                .frame
+                .save(dir.join(format!("f{:08}.png", entry.frame_id)))?;
+            let timestamp = entry
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_secs_f64())
+                .unwrap_or(0.);
+            writeln!(
+                writer,
+                "{}",
+                ndjson_record(timestamp, entry.frame_id, &entry.detections)
+            )?;
+        }
+        writer.flush()?;
+        Ok(self.entries.len())
+    }
+}
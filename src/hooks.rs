@@ -0,0 +1,65 @@
+//! 推論処理の各段階で呼び出されるコールバックに関するモジュール
+
+use crate::detection_result::{DetectionData, ImageSpace, LetterboxSpace};
+
+/// フレームの処理開始時に呼び出されるコールバック (フレーム番号)
+pub type FrameStartHook = Box<dyn FnMut(u64)>;
+/// 検出結果が得られたときに呼び出されるコールバック (フレーム番号, 検出結果)
+pub type DetectionsHook<S = ImageSpace> = Box<dyn FnMut(u64, &[DetectionData<S>])>;
+
+/// 登録されたコールバックを保持する構造体
+#[derive(Default)]
+pub struct Hooks {
+    frame_start: Vec<FrameStartHook>,
+    raw_detections: Vec<DetectionsHook<LetterboxSpace>>,
+    final_detections: Vec<DetectionsHook>,
+}
+
+impl Hooks {
+    /// フレームの処理開始時のコールバックを登録します。
+    pub fn add_frame_start(&mut self, hook: FrameStartHook) {
+        self.frame_start.push(hook);
+    }
+
+    /// 後処理直後 (座標変換・検証前) の検出結果に対するコールバックを登録します。
+    pub fn add_raw_detections(&mut self, hook: DetectionsHook<LetterboxSpace>) {
+        self.raw_detections.push(hook);
+    }
+
+    /// 最終的な検出結果に対するコールバックを登録します。
+    pub fn add_final_detections(&mut self, hook: DetectionsHook) {
+        self.final_detections.push(hook);
+    }
+
+    /// 登録されている全てのコールバックを削除します。
+    pub fn clear(&mut self) {
+        self.frame_start.clear();
+        self.raw_detections.clear();
+        self.final_detections.clear();
+    }
+
+    /// フレームの処理開始時のコールバックを呼び出します。
+    pub(crate) fn frame_start(&mut self, frame_id: u64) {
+        for hook in self.frame_start.iter_mut() {
+            hook(frame_id);
+        }
+    }
+
+    /// 後処理直後の検出結果に対するコールバックを呼び出します。
+    pub(crate) fn raw_detections(
+        &mut self,
+        frame_id: u64,
+        detections: &[DetectionData<LetterboxSpace>],
+    ) {
+        for hook in self.raw_detections.iter_mut() {
+            hook(frame_id, detections);
+        }
+    }
+
+    /// 最終的な検出結果に対するコールバックを呼び出します。
+    pub(crate) fn final_detections(&mut self, frame_id: u64, detections: &[DetectionData]) {
+        for hook in self.final_detections.iter_mut() {
+            hook(frame_id, detections);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! `YoloService` をHTTP経由で利用するためのREST APIサーバ
+//!
+//! `http` フィーチャを有効にした場合のみ利用できます。
+//!
+//! * `POST /detect` - リクエストボディの画像 (JPEG, PNGなど) を推論し、検出結果をJSONで返します。
+//!   クエリ文字列 `?rotate=90` で回転角度を指定できます。
+//! * `GET /healthz` - サーバが動作していれば `ok` を返します。
+//! * `GET /metrics` - リクエスト数や推論時間をPrometheusのテキスト形式で返します。
+
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::detection_log::ndjson_record;
+use crate::service::YoloServiceHandle;
+
+/// サーバの統計情報
+#[derive(Default)]
+struct Metrics {
+    /// 受け付けた推論リクエストの数
+    requests: AtomicU64,
+    /// 失敗した推論リクエストの数
+    errors: AtomicU64,
+    /// 検出した物体の数の合計
+    detections: AtomicU64,
+    /// 推論にかかった時間の合計 (マイクロ秒)
+    latency_us: AtomicU64,
+}
+
+/// 推論サービスをREST APIとして公開するHTTPサーバ
+pub struct HttpServer {
+    server: Server,
+    handle: YoloServiceHandle,
+    rotate_angle: u32,
+    max_body_size: usize,
+    threads: usize,
+    metrics: Metrics,
+}
+
+impl HttpServer {
+    /// 指定したアドレスで待ち受けるサーバを作成します。
+    ///
+    /// # Args
+    /// * `addr` - 待ち受けるアドレス (例: `0.0.0.0:8080`)
+    /// * `handle` - 推論を依頼する `YoloService` のハンドル
+    pub fn bind(addr: &str, handle: YoloServiceHandle) -> Result<Self> {
+        let server = Server::http(addr).map_err(|e| anyhow!("Can't bind {}: {}", addr, e))?;
+        Ok(Self {
+            server,
+            handle,
+            rotate_angle: 0,
+            max_body_size: 16 * 1024 * 1024,
+            threads: 2,
+            metrics: Metrics::default(),
+        })
+    }
+
+    /// クエリ文字列で指定されなかった場合の回転角度を設定します。
+    pub fn set_rotate_angle(&mut self, rotate_angle: u32) -> &mut Self {
+        self.rotate_angle = rotate_angle;
+        self
+    }
+
+    /// 受け付けるリクエストボディの最大サイズを設定します。
+    pub fn set_max_body_size(&mut self, max_body_size: usize) -> &mut Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// リクエストを処理するスレッドの数を設定します。
+    ///
+    /// 推論は `YoloService` で順番に処理されますが、画像のデコードや `/healthz` の応答は並行して行われます。
+    pub fn set_threads(&mut self, threads: usize) -> &mut Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// リクエストの処理を開始します。サーバが停止するまで戻りません。
+    pub fn serve(&self) {
+        thread::scope(|s| {
+            for _ in 0..self.threads {
+                s.spawn(|| {
+                    for request in self.server.incoming_requests() {
+                        if let Err(e) = self.handle_request(request) {
+                            log::warn!("HTTP: {}", e);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// 1つのリクエストを処理します。
+    fn handle_request(&self, mut request: Request) -> Result<()> {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+
+        let response = match (request.method(), path) {
+            (Method::Post, "/detect") => match self.detect(&mut request, query) {
+                Ok(body) => json_response(body, 200),
+                Err(e) => {
+                    self.metrics.errors.fetch_add(1, Ordering::Relaxed);
+                    json_response(json!({ "error": format!("{:#}", e) }).to_string(), 400)
+                }
+            },
+            (Method::Get, "/healthz") => Response::from_string("ok"),
+            (Method::Get, "/metrics") => Response::from_string(self.render_metrics()),
+            (_, "/detect" | "/healthz" | "/metrics") => {
+                Response::from_string("method not allowed").with_status_code(405)
+            }
+            _ => Response::from_string("not found").with_status_code(404),
+        };
+        request.respond(response)?;
+        Ok(())
+    }
+
+    /// `POST /detect` の処理
+    ///
+    /// # Return
+    /// * 検出結果のJSON文字列
+    fn detect(&self, request: &mut Request, query: &str) -> Result<String> {
+        let request_id = self.metrics.requests.fetch_add(1, Ordering::Relaxed);
+
+        let mut rotate_angle = self.rotate_angle;
+        for (key, value) in query.split('&').filter_map(|kv| kv.split_once('=')) {
+            if key == "rotate" {
+                rotate_angle = value.parse().context("invalid rotate")?;
+            }
+        }
+
+        let mut body = vec![];
+        request
+            .as_reader()
+            .take(self.max_body_size as u64 + 1)
+            .read_to_end(&mut body)?;
+        if body.len() > self.max_body_size {
+            return Err(anyhow!("request body is too large"));
+        }
+        let img = image::load_from_memory(&body).context("can't decode image")?;
+        let (width, height) = (img.width(), img.height());
+
+        let start = Instant::now();
+        let detections = self.handle.detect(img, rotate_angle)?;
+        let elapsed = start.elapsed();
+
+        self.metrics
+            .latency_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.metrics
+            .detections
+            .fetch_add(detections.len() as u64, Ordering::Relaxed);
+
+        // 検出結果の形式は検出ログと揃え、フレーム番号にはリクエストの通し番号を使う
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs_f64())
+            .unwrap_or(0.);
+        let mut record = ndjson_record(timestamp, request_id, &detections);
+        record["width"] = json!(width);
+        record["height"] = json!(height);
+        record["inference_ms"] = json!(elapsed.as_secs_f64() * 1000.);
+        Ok(record.to_string())
+    }
+
+    /// 統計情報をPrometheusのテキスト形式に変換します。
+    fn render_metrics(&self) -> String {
+        let m = &self.metrics;
+        format!(
+            "# TYPE yolo_requests_total counter\n\
+             yolo_requests_total {}\n\
+             # TYPE yolo_errors_total counter\n\
+             yolo_errors_total {}\n\
+             # TYPE yolo_detections_total counter\n\
+             yolo_detections_total {}\n\
+             # TYPE yolo_inference_seconds_total counter\n\
+             yolo_inference_seconds_total {:.6}\n",
+            m.requests.load(Ordering::Relaxed),
+            m.errors.load(Ordering::Relaxed),
+            m.detections.load(Ordering::Relaxed),
+            m.latency_us.load(Ordering::Relaxed) as f64 / 1e6,
+        )
+    }
+}
+
+/// JSONのレスポンスを作成します。
+fn json_response(body: String, status: u16) -> Response<std::io::Cursor<Vec<u8>>> {
+    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header)
+}
@@ -0,0 +1,271 @@
+//! ハードウェアの状態を取得するモジュール
+//!
+//! 推論結果がおかしいときに、スイッチ・DMA・YOLOのIPのレジスタとレイヤーグループの設定を
+//! まとめて記録し、不具合の報告に添付するために使います。
+
+use std::fmt;
+
+use log::info;
+
+use crate::layer_group::{Activation, PostProcess};
+
+/// YOLOのIPの状態
+#[derive(Debug, Clone)]
+pub struct IpState {
+    /// IPの名前
+    pub name: &'static str,
+    /// 処理が完了しているか
+    pub done: bool,
+    /// アイドル状態か
+    pub idle: bool,
+    /// レジスタの名前と値
+    pub registers: Vec<(&'static str, u32)>,
+}
+
+/// DMAの状態
+#[derive(Debug, Clone)]
+pub struct DmaState {
+    /// DMAの名前
+    pub name: &'static str,
+    /// MM2Sチャネルがアイドル状態か。読み出せなかった場合はNone
+    pub mm2s_idle: Option<bool>,
+    /// S2MMチャネルがアイドル状態か。読み出せなかった場合はNone
+    pub s2mm_idle: Option<bool>,
+    /// 1回の転送の最大の要素数
+    pub max_len: usize,
+}
+
+/// Axi4-Stream Switchの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwitchState {
+    /// スイッチの名前
+    pub name: &'static str,
+    /// 接続しているスレーブポート
+    pub slave: u8,
+    /// 接続しているマスターポート
+    pub master: u8,
+}
+
+/// レイヤーグループの1回のデータ転送
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepState {
+    /// レイヤーグループのインデックス
+    pub grp_idx: usize,
+    /// 出力チャネルのインデックス
+    pub off: u32,
+    /// 入力チャネルのインデックス
+    pub iff: u32,
+}
+
+/// 処理中 (または最後に処理した) レイヤーグループの設定
+#[derive(Debug, Clone)]
+pub struct LayerGroupState {
+    /// レイヤーグループのインデックス
+    pub grp_idx: usize,
+    /// 出力チャネルのインデックス
+    pub off: u32,
+    /// 入力チャネルのインデックス
+    pub iff: u32,
+    /// 入力の幅、高さ、チャネル数
+    pub input: (u32, u32, u32),
+    /// 出力の幅、高さ、チャネル数
+    pub output: (u32, u32, u32),
+    /// 入力と出力の分割数
+    pub fold_factor: (u32, u32),
+    /// 活性化関数
+    pub activation: &'static str,
+    /// ポストプロセス
+    pub post_process: &'static str,
+    /// 畳み込みを行わないか
+    pub conv_disable: bool,
+    /// 重みが読み込まれているか
+    pub has_weights: bool,
+    /// バイアスが読み込まれているか
+    pub has_biases: bool,
+}
+
+/// DMAとIPのエラーの統計
+///
+/// 長時間の運用で値が増え続ける場合は、ハードウェアの経路が劣化している可能性があります。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HwStats {
+    /// DMAの転送に失敗した回数
+    pub dma_errors: u64,
+    /// IPかDMAの完了待ちが時間切れになった回数
+    pub timeouts: u64,
+    /// 時間切れの後にレイヤーグループを再実行した回数
+    pub retries: u64,
+    /// 再実行で回復した回数
+    pub recoveries: u64,
+    /// 回復できずに `Stalled` のエラーを返した回数
+    pub stalls: u64,
+    /// スイッチとDMAをリセットした回数
+    pub resets: u64,
+}
+
+impl std::ops::AddAssign for HwStats {
+    fn add_assign(&mut self, other: Self) {
+        self.dma_errors += other.dma_errors;
+        self.timeouts += other.timeouts;
+        self.retries += other.retries;
+        self.recoveries += other.recoveries;
+        self.stalls += other.stalls;
+        self.resets += other.resets;
+    }
+}
+
+/// レイヤーグループの出力の飽和の統計
+///
+/// 飽和した値が増えている場合は、重みを量子化し直す必要があります。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LayerSaturation {
+    /// 数えた出力の数 (フレーム数)
+    pub frames: u64,
+    /// 正の最大値 (`i16::MAX`) に張り付いた値の数
+    pub positive: u64,
+    /// 負の最小値 (`i16::MIN`) に張り付いた値の数
+    pub negative: u64,
+    /// 出力の値の総数
+    pub total: u64,
+}
+
+impl LayerSaturation {
+    /// 飽和した値の割合を返します。
+    pub fn rate(&self) -> f64 {
+        (self.positive + self.negative) as f64 / self.total.max(1) as f64
+    }
+
+    /// レイヤーグループの出力の飽和した値を数えます。
+    pub(crate) fn count(&mut self, output: &[i16]) {
+        self.frames += 1;
+        self.total += output.len() as u64;
+        for &v in output {
+            if v == i16::MAX {
+                self.positive += 1;
+            } else if v == i16::MIN {
+                self.negative += 1;
+            }
+        }
+    }
+}
+
+impl fmt::Display for LayerSaturation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} / {} values saturated ({:.4}%, +{} / -{}) in {} frame(s)",
+            self.positive + self.negative,
+            self.total,
+            self.rate() * 100.,
+            self.positive,
+            self.negative,
+            self.frames
+        )
+    }
+}
+
+/// ハードウェア全体の状態
+#[derive(Debug, Clone)]
+pub struct HardwareState {
+    /// YOLOのIPの状態
+    pub ips: Vec<IpState>,
+    /// IPのレジスタを次のデータ転送のために設定済みの場合は、その転送。
+    /// Noneの場合、レジスタは処理中 (または最後に処理した) データ転送のものです
+    pub preconfigured: Option<StepState>,
+    /// DMAの状態
+    pub dmas: Vec<DmaState>,
+    /// 最後に設定したスイッチの接続。リセット後で未設定の場合は空
+    pub switches: Vec<SwitchState>,
+    /// 処理中 (または最後に処理した) レイヤーグループ
+    pub layer_group: Option<LayerGroupState>,
+}
+
+impl HardwareState {
+    /// 状態をinfoレベルでログに出力します。
+    pub fn log(&self) {
+        for line in self.to_string().lines() {
+            info!("{}", line);
+        }
+    }
+}
+
+/// 活性化関数の名前を返します。
+pub(crate) fn activation_name(activation: Activation) -> &'static str {
+    match activation {
+        Activation::Linear => "linear",
+        Activation::Leaky => "leaky",
+    }
+}
+
+/// ポストプロセスの名前を返します。
+pub(crate) fn post_process_name(post_process: PostProcess) -> &'static str {
+    match post_process {
+        PostProcess::None => "none",
+        PostProcess::MaxPool => "maxpool",
+        PostProcess::Yolo => "yolo",
+        PostProcess::Upsample => "upsample",
+    }
+}
+
+impl fmt::Display for HardwareState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ip in &self.ips {
+            write!(f, "{}: done={} idle={}", ip.name, ip.done, ip.idle)?;
+            for (name, value) in &ip.registers {
+                write!(f, " {}={:#x}", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        if let Some(step) = &self.preconfigured {
+            writeln!(
+                f,
+                "registers: preconfigured for layer group {} (off={}, iff={})",
+                step.grp_idx, step.off, step.iff
+            )?;
+        }
+
+        let opt = |v: Option<bool>| v.map_or("?".to_string(), |b| b.to_string());
+        for dma in &self.dmas {
+            writeln!(
+                f,
+                "{}: mm2s_idle={} s2mm_idle={} max_len={}",
+                dma.name,
+                opt(dma.mm2s_idle),
+                opt(dma.s2mm_idle),
+                dma.max_len
+            )?;
+        }
+
+        if self.switches.is_empty() {
+            writeln!(f, "switches: not configured")?;
+        }
+        for sw in &self.switches {
+            writeln!(f, "{}: S{} -> M{}", sw.name, sw.slave, sw.master)?;
+        }
+
+        match &self.layer_group {
+            Some(l) => writeln!(
+                f,
+                "layer group {} (off={}, iff={}): {}x{}x{} -> {}x{}x{}, fold={}/{}, \
+                 activation={}, post_process={}, conv_disable={}, weights={}, biases={}",
+                l.grp_idx,
+                l.off,
+                l.iff,
+                l.input.0,
+                l.input.1,
+                l.input.2,
+                l.output.0,
+                l.output.1,
+                l.output.2,
+                l.fold_factor.0,
+                l.fold_factor.1,
+                l.activation,
+                l.post_process,
+                l.conv_disable,
+                l.has_weights,
+                l.has_biases
+            ),
+            None => writeln!(f, "layer group: none"),
+        }
+    }
+}
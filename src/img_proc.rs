@@ -1,14 +1,53 @@
 //! YOLOに関する画像処理モジュール
 
 use fast_image_resize as fr;
-use image::{DynamicImage, Pixel, Rgb, RgbImage};
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma, Rgb, RgbImage, RgbaImage};
 use imageproc::drawing::draw_filled_rect_mut;
-use imageproc::drawing::{draw_text_mut, text_size};
+use imageproc::drawing::draw_text_mut;
 use imageproc::rect::Rect;
-use rusttype::{Font, Scale};
+use rusttype::{point, Font, Scale};
+use std::borrow::Cow;
 use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
 
 use crate::detection_result::DetectionData;
+use crate::drawing::DrawPixel;
+use crate::ground_truth::GroundTruth;
+use crate::preprocess::PatialEnlargement;
+use crate::render::{RenderStyle, Renderer};
+
+/// ディレクトリ内の画像ファイルのパスを取得します。
+///
+/// # Args
+///
+/// * `dir` - 画像のあるディレクトリ
+///
+/// # Return
+///
+/// * ファイル名順に並べた画像ファイル (jpg, jpeg, png, bmp) のパス
+pub fn list_images<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_image = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| {
+                matches!(
+                    ext.to_ascii_lowercase().as_str(),
+                    "jpg" | "jpeg" | "png" | "bmp"
+                )
+            })
+            .unwrap_or(false);
+        if path.is_file() && is_image {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
 
 /// 画像を指定した角度で回転させます。
 ///
@@ -48,7 +87,49 @@ pub fn place_pixels(data: &mut [i16], img: &DynamicImage, size: u32, x_offset: u
     }
 }
 
-fn fast_resize(src_img: &RgbImage, dst_width: u32, dst_height: u32) -> RgbImage {
+/// 画像をRGBの画像として参照します。
+///
+/// グレースケールの画像 (IRカメラなど) は、輝度を3チャンネルに複製したRGBの画像に変換します。
+///
+/// # Args
+///
+/// * `img` - 入力画像
+///
+/// # Return
+///
+/// * RGBの画像。入力がRGBの場合は変換せずに参照します
+pub fn as_rgb(img: &DynamicImage) -> Cow<'_, RgbImage> {
+    match img.as_rgb8() {
+        Some(rgb) => Cow::Borrowed(rgb),
+        None => Cow::Owned(img.to_rgb8()),
+    }
+}
+
+/// グレースケールの画像を、チャンネルごとの1次関数でRGBの画像に変換します。
+///
+/// RGBで学習したモデルにIRカメラの画像を入力するときに、輝度を単に複製するよりも学習データの色の分布に近づけるための変換です。
+/// `gains` と `offsets` は、同じ場面のRGBとIRの画像の対から最小二乗法などで事前に求めます。
+///
+/// # Args
+///
+/// * `img` - グレースケールの画像
+/// * `gains` - RGBごとの輝度の係数
+/// * `offsets` - RGBごとの切片
+///
+/// # Return
+///
+/// * RGBの画像
+pub fn gray_to_rgb(img: &GrayImage, gains: [f32; 3], offsets: [f32; 3]) -> RgbImage {
+    let luts: [[u8; 256]; 3] = std::array::from_fn(|c| {
+        std::array::from_fn(|v| (v as f32 * gains[c] + offsets[c]).round().clamp(0., 255.) as u8)
+    });
+    RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let v = img.get_pixel(x, y)[0] as usize;
+        Rgb([luts[0][v], luts[1][v], luts[2][v]])
+    })
+}
+
+pub(crate) fn fast_resize(src_img: &RgbImage, dst_width: u32, dst_height: u32) -> RgbImage {
     let width = NonZeroU32::new(src_img.width()).unwrap();
     let height = NonZeroU32::new(src_img.height()).unwrap();
 
@@ -92,7 +173,7 @@ fn fast_resize(src_img: &RgbImage, dst_width: u32, dst_height: u32) -> RgbImage
 ///
 /// * リサイズ、回転、パディングを行った画像のピクセルデータ
 pub fn letterbox(img: &DynamicImage, size: u32, rotate_angle: u32) -> Vec<i16> {
-    let resized = DynamicImage::from(fast_resize(img.as_rgb8().unwrap(), size, size));
+    let resized = DynamicImage::from(fast_resize(&as_rgb(img), size, size));
     let rotated = rotate_img(&resized, rotate_angle);
 
     let pad_w = rotated.width().abs_diff(size) / 2;
@@ -129,32 +210,46 @@ pub fn letterbox_with_patial_enlargement(
     crop_w: u32,
     crop_h: u32,
 ) -> Vec<i16> {
-    let resized = DynamicImage::from(fast_resize(img.as_rgb8().unwrap(), size, size));
-    let rotated = rotate_img(&resized, if rotate_en { rotate_angle } else { 0 });
+    let crop = PatialEnlargement::new(rotate_angle, rotate_en, crop_x, crop_y, crop_w, crop_h)
+        .crop_rect(img.width(), img.height());
+    letterbox_with_patial_enlargements(img, size, rotate_angle, rotate_en, &[crop])
+}
 
-    let pad_w = rotated.width().abs_diff(size);
-    let pad_h = rotated.height().abs_diff(size);
+/// 画像をリサイズ・回転し、正方形に整形したYOLO入力データを生成します。画像の複数の領域を拡大し，余白に並べて配置します。
+///
+/// # Args
+///
+/// * `img` - リサイズと回転を行う画像
+/// * `size` - リサイズ後の画像のサイズ
+/// * `rotate_angle` - 回転させる角度
+/// * `rotate_en` - 画像を回転させるか。事前に回転させている場合はfalseを指定してください
+/// * `crops` - 切り取る領域 (回転後の画像の座標系)
+///
+/// # Return
+///
+/// * リサイズ、回転、パディングを行った画像のピクセルデータ
+pub fn letterbox_with_patial_enlargements(
+    img: &DynamicImage,
+    size: u32,
+    rotate_angle: u32,
+    rotate_en: bool,
+    crops: &[CropRect],
+) -> Vec<i16> {
+    let resized = DynamicImage::from(fast_resize(&as_rgb(img), size, size));
+    let rotated = rotate_img(&resized, if rotate_en { rotate_angle } else { 0 });
 
     let mut new_img = vec![0; (size * size * 4) as usize];
     place_pixels(&mut new_img, &rotated, size, 0, 0);
 
-    let crop_x = crop_x.unwrap_or((img.width() - crop_w) / 2);
-    let crop_y = crop_y.unwrap_or((img.height() - crop_h) / 2);
-
-    let (side_w, side_h) = match rotate_angle {
-        90 | 270 => (pad_w, size),
-        _ => (size, pad_h),
-    };
-
-    let crop = img.crop_imm(crop_x, crop_y, crop_w, crop_h);
-    let crop_resized = DynamicImage::from(fast_resize(crop.as_rgb8().unwrap(), side_w, side_h));
-    place_pixels(
-        &mut new_img,
-        &crop_resized,
-        size,
-        size - side_w,
-        size - side_h,
-    );
+    let slots = enlargement_slots(rotated.width(), rotated.height(), size, crops.len());
+    for (crop, slot) in crops.iter().zip(slots.iter()) {
+        if slot.w == 0 || slot.h == 0 {
+            continue;
+        }
+        let cropped = crop_rotated(img, rotate_angle, rotate_en, crop);
+        let crop_resized = DynamicImage::from(fast_resize(&as_rgb(&cropped), slot.w, slot.h));
+        place_pixels(&mut new_img, &crop_resized, size, slot.x, slot.y);
+    }
 
     new_img
 }
@@ -171,7 +266,7 @@ pub fn letterbox_with_patial_enlargement(
 ///
 /// * リサイズ、回転、パディングを行ったRGB画像
 pub fn letterbox_img(img: &DynamicImage, size: u32, rotate_angle: u32) -> RgbImage {
-    let resized = DynamicImage::from(fast_resize(img.as_rgb8().unwrap(), size, size));
+    let resized = DynamicImage::from(fast_resize(&as_rgb(img), size, size));
     let rotated = rotate_img(&resized, rotate_angle);
 
     let pad_w = rotated.width().abs_diff(size) / 2;
@@ -208,47 +303,475 @@ pub fn letterbox_img_with_patial_enlargement(
     crop_y: Option<u32>,
     crop_w: u32,
     crop_h: u32,
+) -> RgbImage {
+    let crop = PatialEnlargement::new(rotate_angle, rotate_en, crop_x, crop_y, crop_w, crop_h)
+        .crop_rect(img.width(), img.height());
+    letterbox_img_with_patial_enlargements(img, rotate_angle, rotate_en, &[crop])
+}
+
+/// 画像を回転し、正方形に整形します。画像の複数の領域を拡大し，余白に並べて配置します。
+///
+/// # Args
+///
+/// * `img` - 回転を行う画像
+/// * `rotate_angle` - 回転させる角度
+/// * `rotate_en` - 画像を回転させるか。事前に回転させている場合はfalseを指定してください
+/// * `crops` - 切り取る領域 (回転後の画像の座標系)
+///
+/// # Return
+///
+/// * 回転、パディングを行った画像
+pub fn letterbox_img_with_patial_enlargements(
+    img: &DynamicImage,
+    rotate_angle: u32,
+    rotate_en: bool,
+    crops: &[CropRect],
 ) -> RgbImage {
     let rotated = rotate_img(img, if rotate_en { rotate_angle } else { 0 });
     let size = u32::max(rotated.width(), rotated.height());
 
-    let pad_w = rotated.width().abs_diff(size);
-    let pad_h = rotated.height().abs_diff(size);
-
     let mut new_img = RgbImage::new(size, size);
     for (x, y, &pixel) in rotated.to_rgb8().enumerate_pixels() {
         new_img.put_pixel(x, y, pixel);
     }
 
-    let crop_x = crop_x.unwrap_or((rotated.width() - crop_w) / 2);
-    let crop_y = crop_y.unwrap_or((rotated.height() - crop_h) / 2);
+    let slots = enlargement_slots(rotated.width(), rotated.height(), size, crops.len());
+    for (crop, slot) in crops.iter().zip(slots.iter()) {
+        if slot.w == 0 || slot.h == 0 {
+            continue;
+        }
+        let cropped = rotated.crop_imm(crop.x, crop.y, crop.w, crop.h);
+        let crop_resized = fast_resize(&as_rgb(&cropped), slot.w, slot.h);
+        for (x, y, &pixel) in crop_resized.enumerate_pixels() {
+            new_img.put_pixel(x + slot.x, y + slot.y, pixel);
+        }
+    }
+    new_img
+}
 
-    let (side_w, side_h) = match rotate_angle {
-        90 | 270 => (pad_w, size),
-        _ => (size, pad_h),
-    };
+/// 部分拡大する矩形領域
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CropRect {
+    /// 左上のx座標
+    pub x: u32,
+    /// 左上のy座標
+    pub y: u32,
+    /// 幅
+    pub w: u32,
+    /// 高さ
+    pub h: u32,
+}
+
+impl CropRect {
+    /// 新しい `CropRect` インスタンスを作成します。
+    pub fn new(x: u32, y: u32, w: u32, h: u32) -> Self {
+        Self { x, y, w, h }
+    }
+
+    /// 点が領域内にあるかを判定します。
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        self.x as f32 <= x
+            && x < (self.x + self.w) as f32
+            && self.y as f32 <= y
+            && y < (self.y + self.h) as f32
+    }
+}
+
+/// 回転後の画像のサイズを返します。
+///
+/// # Args
+///
+/// * `img` - 画像
+/// * `rotate_angle` - 回転させる角度
+/// * `rotate_en` - 画像を回転させるか
+///
+/// # Return
+///
+/// * 回転後の画像のサイズ (幅, 高さ)
+pub fn rotated_size(img: &DynamicImage, rotate_angle: u32, rotate_en: bool) -> (u32, u32) {
+    match rotate_angle {
+        90 | 270 if rotate_en => (img.height(), img.width()),
+        _ => (img.width(), img.height()),
+    }
+}
+
+/// 拡大した領域を配置する余白内の位置を求めます。
+///
+/// 画像が縦長の場合は右側の余白を縦に、それ以外の場合は下側の余白を横に等分して配置します。
+///
+/// # Args
+///
+/// * `content_w` - 正方形に整形する前の画像の幅
+/// * `content_h` - 正方形に整形する前の画像の高さ
+/// * `size` - 正方形に整形した画像のサイズ
+/// * `n` - 拡大する領域の数
+///
+/// # Return
+///
+/// * 拡大した領域ごとの配置先
+pub fn enlargement_slots(content_w: u32, content_h: u32, size: u32, n: usize) -> Vec<CropRect> {
+    if n == 0 {
+        return vec![];
+    }
+    let n = n as u32;
+
+    if content_w < content_h {
+        let slot_h = size / n;
+        (0..n)
+            .map(|i| CropRect::new(content_w, i * slot_h, size - content_w, slot_h))
+            .collect()
+    } else {
+        let slot_w = size / n;
+        (0..n)
+            .map(|i| {
+                CropRect::new(
+                    i * slot_w,
+                    content_h,
+                    slot_w,
+                    size.saturating_sub(content_h),
+                )
+            })
+            .collect()
+    }
+}
+
+/// 2×2のモザイクに並べる画像の最大数
+pub const MOSAIC_TILES: usize = 4;
+
+/// 2×2のモザイクでi番目の画像を配置するタイルを求めます。タイルは左上から行優先で並びます。
+///
+/// # Args
+///
+/// * `size` - モザイクのサイズ
+/// * `i` - 画像のインデックス (`MOSAIC_TILES` 未満)
+///
+/// # Return
+///
+/// * タイルの領域
+pub fn mosaic_tile(size: u32, i: usize) -> CropRect {
+    let half = size / 2;
+    let i = i as u32;
+    CropRect::new(i % 2 * half, i / 2 * half, half, half)
+}
 
-    let crop = rotated.crop_imm(crop_x, crop_y, crop_w, crop_h);
-    let crop_resized = fast_resize(crop.as_rgb8().unwrap(), side_w, side_h);
+/// モザイクのタイルの中で、リサイズした画像を配置する領域を求めます。
+///
+/// タイルの中でアスペクト比を保ったまま縮小し、上下左右に均等なパディングを入れます。
+///
+/// # Args
+///
+/// * `size` - モザイクのサイズ
+/// * `i` - 画像のインデックス
+/// * `width` - 画像の幅
+/// * `height` - 画像の高さ
+///
+/// # Return
+///
+/// * 画像を配置する領域
+pub fn mosaic_content(size: u32, i: usize, width: u32, height: u32) -> CropRect {
+    let tile = mosaic_tile(size, i);
+    // `fast_resize` と同じく四捨五入したサイズに縮小する
+    let ratio = f32::min(tile.w as f32 / width as f32, tile.h as f32 / height as f32);
+    let w = (width as f32 * ratio).round() as u32;
+    let h = (height as f32 * ratio).round() as u32;
+    CropRect::new(tile.x + (tile.w - w) / 2, tile.y + (tile.h - h) / 2, w, h)
+}
 
-    for (x, y, &pixel) in crop_resized.enumerate_pixels() {
-        new_img.put_pixel(x + (size - side_w), y + (size - side_h), pixel);
+/// 最大4枚の画像をそれぞれ縮小し、2×2のモザイクに並べたYOLO入力データを生成します。
+///
+/// 画像が4枚未満の場合、残りのタイルは空になります。
+///
+/// # Args
+///
+/// * `imgs` - 入力画像 (`MOSAIC_TILES` 枚以下)
+/// * `size` - モザイクのサイズ
+///
+/// # Return
+///
+/// * 画像を並べたピクセルデータ
+pub fn mosaic(imgs: &[&DynamicImage], size: u32) -> Vec<i16> {
+    let mut new_img = vec![0; (size * size * 4) as usize];
+    for (i, img) in imgs.iter().enumerate().take(MOSAIC_TILES) {
+        let tile = mosaic_tile(size, i);
+        let resized = DynamicImage::from(fast_resize(&img.to_rgb8(), tile.w, tile.h));
+        let content = mosaic_content(size, i, img.width(), img.height());
+        place_pixels(&mut new_img, &resized, size, content.x, content.y);
     }
     new_img
 }
 
-const COLORS: [[u8; 3]; 10] = [
-    [255, 0, 0],
-    [255, 255, 0],
-    [0, 0, 255],
-    [14, 23, 50],
-    [28, 105, 80],
-    [190, 159, 53],
-    [46, 194, 148],
-    [242, 30, 131],
-    [97, 101, 198],
-    [115, 11, 87],
-];
+/// 回転後の画像の座標系で指定した領域を、回転前の画像から切り取って回転させます。
+///
+/// # Args
+///
+/// * `img` - 回転前の画像
+/// * `rotate_angle` - 回転させる角度
+/// * `rotate_en` - 画像を回転させるか。falseの場合は `img` をそのまま切り取ります
+/// * `crop` - 切り取る領域 (回転後の画像の座標系)
+///
+/// # Return
+///
+/// * 切り取った画像
+fn crop_rotated(
+    img: &DynamicImage,
+    rotate_angle: u32,
+    rotate_en: bool,
+    crop: &CropRect,
+) -> DynamicImage {
+    if !rotate_en {
+        return img.crop_imm(crop.x, crop.y, crop.w, crop.h);
+    }
+
+    let (w, h) = (img.width(), img.height());
+    let c = crop;
+    let cropped = match rotate_angle {
+        90 => img.crop_imm(c.y, h.saturating_sub(c.x + c.w), c.h, c.w),
+        180 => img.crop_imm(
+            w.saturating_sub(c.x + c.w),
+            h.saturating_sub(c.y + c.h),
+            c.w,
+            c.h,
+        ),
+        270 => img.crop_imm(w.saturating_sub(c.y + c.h), c.x, c.h, c.w),
+        _ => img.crop_imm(c.x, c.y, c.w, c.h),
+    };
+    rotate_img(&cropped, rotate_angle)
+}
+
+/// 前景マスクで前景を表す画素値
+pub const FOREGROUND: u8 = 255;
+
+/// 固定カメラの映像から背景を学習し、前景 (動いている物体) のマスクを求める背景モデル
+///
+/// 画素ごとに輝度の平均と分散を持つ単一ガウス分布のモデル (MOGの簡易版) です。
+/// 平均からの差が標準偏差の `threshold` 倍と `min_diff` の両方を超える画素を前景とし、
+/// 背景と判定した画素は学習率 `learning_rate` で、前景と判定した画素はその1/10で平均を更新します。
+/// 前景マスクは、検出する領域の絞り込みや、新しく現れた物体の検出結果の判定に使えます。
+#[derive(Debug, Clone)]
+pub struct BackgroundModel {
+    /// 背景の更新の割合 (0.0-1.0)
+    learning_rate: f32,
+    /// 前景とみなす平均からの差 (標準偏差の倍数)
+    threshold: f32,
+    /// 前景とみなす平均からの差の最小値 (輝度)
+    min_diff: f32,
+    /// 画素ごとの輝度の平均
+    mean: Vec<f32>,
+    /// 画素ごとの輝度の分散
+    var: Vec<f32>,
+    /// 直前のフレームの前景マスク
+    mask: GrayImage,
+}
+
+impl Default for BackgroundModel {
+    fn default() -> Self {
+        Self {
+            learning_rate: 0.05,
+            threshold: 2.5,
+            min_diff: 15.,
+            mean: vec![],
+            var: vec![],
+            mask: GrayImage::new(0, 0),
+        }
+    }
+}
+
+impl BackgroundModel {
+    /// 最初のフレームの分散。学習が進むまで小さなノイズを前景としないように大きめにします
+    const INITIAL_VAR: f32 = 20. * 20.;
+    /// 分散の下限。変化のない画素で分散が0になり、わずかなノイズで前景になることを防ぎます
+    const MIN_VAR: f32 = 4. * 4.;
+    /// 前景と判定した画素の平均を更新する割合 (`learning_rate` に対する倍率)
+    const FOREGROUND_RATE: f32 = 0.1;
+
+    /// 新しい `BackgroundModel` インスタンスを作成します。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 背景の更新の割合を設定します。大きいほど照明の変化に早く追従しますが、止まった物体も早く背景になります。
+    pub fn set_learning_rate(&mut self, learning_rate: f32) -> &mut Self {
+        self.learning_rate = learning_rate.clamp(0., 1.);
+        self
+    }
+
+    /// 前景とみなす平均からの差を、標準偏差の倍数で設定します。
+    pub fn set_threshold(&mut self, threshold: f32) -> &mut Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// 前景とみなす平均からの差の最小値 (輝度) を設定します。
+    pub fn set_min_diff(&mut self, min_diff: f32) -> &mut Self {
+        self.min_diff = min_diff;
+        self
+    }
+
+    /// 学習した背景を破棄します。次のフレームを背景として学習し直します。
+    pub fn reset(&mut self) {
+        self.mean.clear();
+        self.var.clear();
+        self.mask = GrayImage::new(0, 0);
+    }
+
+    /// フレームの前景マスクを求め、背景を更新します。
+    ///
+    /// 最初のフレームや画像のサイズが変わったフレームは、全体を背景として学習し直します。
+    ///
+    /// # Args
+    ///
+    /// * `img` - カメラの画像
+    ///
+    /// # Return
+    ///
+    /// * 前景マスク (前景は `FOREGROUND`、背景は0)
+    pub fn update(&mut self, img: &DynamicImage) -> &GrayImage {
+        let luma = img.to_luma8();
+        if luma.dimensions() != self.mask.dimensions() || self.mean.is_empty() {
+            self.mean = luma.pixels().map(|p| p[0] as f32).collect();
+            self.var = vec![Self::INITIAL_VAR; self.mean.len()];
+            self.mask = GrayImage::new(luma.width(), luma.height());
+            return &self.mask;
+        }
+
+        let a = self.learning_rate;
+        for (i, (p, m)) in luma.pixels().zip(self.mask.pixels_mut()).enumerate() {
+            let diff = p[0] as f32 - self.mean[i];
+            let foreground = diff.abs() > self.min_diff
+                && diff * diff > self.threshold * self.threshold * self.var[i];
+            if foreground {
+                // 止まった物体がいずれ背景になるように、前景の画素もゆっくり更新する
+                *m = Luma([FOREGROUND]);
+                self.mean[i] += a * Self::FOREGROUND_RATE * diff;
+            } else {
+                *m = Luma([0]);
+                self.mean[i] += a * diff;
+                self.var[i] = ((1. - a) * self.var[i] + a * diff * diff).max(Self::MIN_VAR);
+            }
+        }
+        &self.mask
+    }
+
+    /// 直前のフレームの前景マスクを返します。
+    pub fn mask(&self) -> &GrayImage {
+        &self.mask
+    }
+
+    /// 学習した背景の画像を返します。
+    pub fn background(&self) -> GrayImage {
+        let (w, h) = self.mask.dimensions();
+        GrayImage::from_fn(w, h, |x, y| {
+            Luma([self.mean[(x + y * w) as usize].round().clamp(0., 255.) as u8])
+        })
+    }
+
+    /// バウンディングボックスの中の前景の画素の割合を返します。
+    ///
+    /// 割合が小さい検出結果は背景に溶け込んだ静止物体とみなし、新しく現れた物体から除外できます。
+    ///
+    /// # Args
+    ///
+    /// * `d` - 前景マスクと同じ画像の座標系の検出結果
+    ///
+    /// # Return
+    ///
+    /// * 前景の画素の割合 (0.0-1.0)。バウンディングボックスが画像の外にある場合は0
+    pub fn foreground_ratio(&self, d: &DetectionData) -> f32 {
+        let (w, h) = self.mask.dimensions();
+        let d = d.clamp_to(w, h);
+        let (x1, y1) = (d.x1.floor() as u32, d.y1.floor() as u32);
+        let (x2, y2) = (d.x2.ceil() as u32, d.y2.ceil() as u32);
+        let total = (x2 - x1) * (y2 - y1);
+        if total == 0 {
+            return 0.;
+        }
+        let count = (y1..y2)
+            .flat_map(|y| (x1..x2).map(move |x| (x, y)))
+            .filter(|&(x, y)| self.mask.get_pixel(x, y)[0] == FOREGROUND)
+            .count();
+        count as f32 / total as f32
+    }
+
+    /// 前景の画素を全て囲む矩形を返します。部分拡大する領域の候補に使えます。
+    ///
+    /// # Return
+    ///
+    /// * 前景を囲む矩形。前景がない場合はNone
+    pub fn foreground_rect(&self) -> Option<CropRect> {
+        let mut rect: Option<(u32, u32, u32, u32)> = None;
+        for (x, y, p) in self.mask.enumerate_pixels() {
+            if p[0] != FOREGROUND {
+                continue;
+            }
+            let r = rect.get_or_insert((x, y, x, y));
+            *r = (r.0.min(x), r.1.min(y), r.2.max(x), r.3.max(y));
+        }
+        rect.map(|(x1, y1, x2, y2)| CropRect::new(x1, y1, x2 - x1 + 1, y2 - y1 + 1))
+    }
+}
+
+/// ホワイトバランスの補正で使うゲインの上限
+///
+/// 1色しかない場面でグレーワールド仮説が成り立たず、ゲインが極端に大きくなるのを防ぎます。
+pub const MAX_WHITE_BALANCE_GAIN: f32 = 4.0;
+
+/// グレーワールド仮説 (画像全体の平均は無彩色) に基づいて、ホワイトバランスのRGBのゲインを求めます。
+///
+/// 各チャンネルの平均を、3チャンネルの平均に揃えるゲインを返します。
+///
+/// # Args
+///
+/// * `img` - 入力画像
+///
+/// # Return
+///
+/// * RGBのゲイン。`MAX_WHITE_BALANCE_GAIN` で制限します
+pub fn gray_world_gains(img: &RgbImage) -> [f32; 3] {
+    let mut sum = [0u64; 3];
+    for p in img.pixels() {
+        for (s, &c) in sum.iter_mut().zip(&p.0) {
+            *s += c as u64;
+        }
+    }
+    let gray = sum.iter().sum::<u64>() as f32 / 3.;
+    sum.map(|s| {
+        if s == 0 {
+            1.
+        } else {
+            (gray / s as f32).clamp(1. / MAX_WHITE_BALANCE_GAIN, MAX_WHITE_BALANCE_GAIN)
+        }
+    })
+}
+
+/// RGBのチャンネルごとにゲインを掛けて、ホワイトバランスを補正します。
+///
+/// カメラのオートホワイトバランスで色相がずれると、信号機のバリデータの色相のチェックに失敗するため、
+/// 固定のゲイン、または `gray_world_gains` で求めたゲインで補正します。
+///
+/// # Args
+///
+/// * `img` - 入力画像
+/// * `gains` - RGBのゲイン
+///
+/// # Return
+///
+/// * 補正した画像
+pub fn white_balance(img: &RgbImage, gains: [f32; 3]) -> RgbImage {
+    let luts = gains.map(|g| {
+        let mut lut = [0u8; 256];
+        for (v, l) in lut.iter_mut().enumerate() {
+            *l = (v as f32 * g).round().clamp(0., 255.) as u8;
+        }
+        lut
+    });
+    let mut out = img.clone();
+    for p in out.pixels_mut() {
+        for (c, lut) in p.0.iter_mut().zip(&luts) {
+            *c = lut[*c as usize];
+        }
+    }
+    out
+}
 
 /// 画像上に線を描画します。
 ///
@@ -258,8 +781,8 @@ const COLORS: [[u8; 3]; 10] = [
 /// * `x1`, `y1`, `x2`, `y2` - 線の始点と終点の座標
 /// * `thickness` - 線の太さ
 /// * `color` - 線の色
-fn draw_line(
-    img: &mut image::RgbImage,
+pub(crate) fn draw_line<P: DrawPixel>(
+    img: &mut ImageBuffer<P, Vec<u8>>,
     x1: f32,
     y1: f32,
     x2: f32,
@@ -276,7 +799,7 @@ fn draw_line(
     };
 
     let rect = Rect::at(bx as i32, by as i32).of_size(w as u32, h as u32);
-    draw_filled_rect_mut(img, rect, color);
+    draw_filled_rect_mut(img, rect, P::opaque(color));
 }
 
 /// 画像上に矩形を描画します。
@@ -287,8 +810,8 @@ fn draw_line(
 /// * `x1`, `y1`, `x2`, `y2` - 矩形の左上と右下の座標
 /// * `thickness` - 線の太さ
 /// * `color` - 線の色
-fn draw_rect(
-    img: &mut image::RgbImage,
+pub(crate) fn draw_rect<P: DrawPixel>(
+    img: &mut ImageBuffer<P, Vec<u8>>,
     x1: f32,
     y1: f32,
     x2: f32,
@@ -302,6 +825,26 @@ fn draw_rect(
     draw_line(img, x2, y1, x2, y2, thickness, color);
 }
 
+/// テキストを描画したときの幅を、グリフの送り幅から求めます。
+///
+/// 描画される画素の範囲ではなく送り幅で求めるため、日本語などの多バイト文字や末尾の空白も幅に含まれます。
+///
+/// # Args
+///
+/// * `font` - フォント
+/// * `scale` - フォントの大きさ
+/// * `text` - テキスト
+///
+/// # Return
+///
+/// * テキストの幅 (ピクセル)
+pub fn text_width(font: &Font, scale: Scale, text: &str) -> f32 {
+    font.layout(text, scale, point(0., 0.))
+        .last()
+        .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.)
+}
+
 /// 画像上にラベルを描画します。
 ///
 /// # Args
@@ -313,8 +856,8 @@ fn draw_rect(
 /// * `font` - ラベルのフォント
 /// * `font_size` - ラベルのフォントサイズ
 /// * `text` - ラベルに表示するテキスト
-fn draw_label(
-    img: &mut image::RgbImage,
+pub(crate) fn draw_label<P: DrawPixel>(
+    img: &mut ImageBuffer<P, Vec<u8>>,
     x1: f32,
     y1: f32,
     line_thickness: f32,
@@ -329,13 +872,13 @@ fn draw_label(
 
     let pad = 6.;
     let scale = Scale::uniform(label_h);
-    let (text_w, _) = text_size(scale, font, text);
+    let text_w = text_width(font, scale, text).ceil();
     let v_metrics = font.v_metrics(scale);
     let text_h = v_metrics.ascent - v_metrics.descent + v_metrics.line_gap;
 
-    let rect = Rect::at(dx1 as i32, label_y as i32)
-        .of_size((text_w as f32 + pad * 2.) as u32, label_h as u32);
-    draw_filled_rect_mut(img, rect, bg_color);
+    let rect =
+        Rect::at(dx1 as i32, label_y as i32).of_size((text_w + pad * 2.) as u32, label_h as u32);
+    draw_filled_rect_mut(img, rect, P::opaque(bg_color));
 
     let text_y = label_y + (label_h - text_h) / 2.;
 
@@ -346,7 +889,7 @@ fn draw_label(
     };
     draw_text_mut(
         img,
-        text_color,
+        P::opaque(text_color),
         (dx1 + pad) as i32,
         text_y as i32,
         scale,
@@ -357,6 +900,9 @@ fn draw_label(
 
 /// 画像上にバウンディングボックスとラベルを描画します。
 ///
+/// 既定の描画の設定で `Renderer::draw` を呼び出します。
+/// 四隅だけの枠や半透明の塗りつぶしなどを使う場合は `Renderer` を使ってください。
+///
 /// # Args
 ///
 /// * `img` - バウンディングボックスとラベルを描画する画像 (in-place)
@@ -369,22 +915,114 @@ pub fn draw_bbox(
     font_size: f32,
     line_thickness: f32,
 ) {
-    let font = Vec::from(include_bytes!("RobotoMono.ttf") as &[u8]);
-    let font = Font::try_from_vec(font).unwrap();
-    let mut sorted = d_result.to_vec();
-    sorted.sort_by(|a, b| a.confidence.partial_cmp(&b.confidence).unwrap());
+    let style = RenderStyle {
+        font_size: font_size.max(0.),
+        line_thickness: line_thickness.max(0.),
+        ..Default::default()
+    };
+    Renderer::new(style).unwrap().draw(img, d_result);
+}
 
-    for d in sorted.iter() {
-        let color: image::Rgb<u8> = *image::Rgb::from_slice(&COLORS[d.class as usize]);
+/// 透明な背景にバウンディングボックスとラベルを描画した画像を作成します。
+///
+/// 元の画像を変更・複製せずに、映像のパイプラインやWebのキャンバスで元の画像に重ねるときに使います。
+/// 既定の描画の設定で `Renderer::overlay` を呼び出します。
+///
+/// # Args
+///
+/// * `width`, `height` - 元の画像の幅と高さ
+/// * `d_result` - 検出結果の配列
+/// * `font_size` - ラベルのフォントサイズ
+/// * `line_thickness` - バウンディングボックスの線の太さ
+///
+/// # Return
+///
+/// * バウンディングボックスとラベル以外が透明なRGBAの画像
+pub fn bbox_overlay(
+    width: u32,
+    height: u32,
+    d_result: &[DetectionData],
+    font_size: f32,
+    line_thickness: f32,
+) -> RgbaImage {
+    let style = RenderStyle {
+        font_size: font_size.max(0.),
+        line_thickness: line_thickness.max(0.),
+        ..Default::default()
+    };
+    Renderer::new(style)
+        .unwrap()
+        .overlay(width, height, d_result)
+}
 
-        let x1 = d.x1.round();
-        let y1 = d.y1.round();
-        let x2 = d.x2.round();
-        let y2 = d.y2.round();
+/// 画像を複製し、バウンディングボックスとラベルを描画した画像を返します。元の画像は変更しません。
+///
+/// # Args
+///
+/// * `img` - 元の画像
+/// * `d_result` - 検出結果の配列
+/// * `font_size` - ラベルのフォントサイズ
+/// * `line_thickness` - バウンディングボックスの線の太さ
+///
+/// # Return
+///
+/// * バウンディングボックスとラベルを描画した画像
+pub fn draw_bbox_copy(
+    img: &RgbImage,
+    d_result: &[DetectionData],
+    font_size: f32,
+    line_thickness: f32,
+) -> RgbImage {
+    let mut out = img.clone();
+    draw_bbox(&mut out, d_result, font_size, line_thickness);
+    out
+}
 
-        draw_rect(img, x1, y1, x2, y2, line_thickness, color);
+/// グレースケールの画像 (IRカメラなど) をRGBに変換し、検出結果のバウンディングボックスを描画します。
+///
+/// バウンディングボックスはクラスごとの色で描画されます。
+///
+/// # Args
+///
+/// * `img` - グレースケールの画像
+/// * `d_result` - 検出結果
+/// * `font_size` - ラベルのフォントサイズ
+/// * `line_thickness` - バウンディングボックスの線の太さ
+///
+/// # Return
+///
+/// * バウンディングボックスを描画したRGBの画像
+pub fn draw_bbox_gray(
+    img: &GrayImage,
+    d_result: &[DetectionData],
+    font_size: f32,
+    line_thickness: f32,
+) -> RgbImage {
+    let mut rgb = DynamicImage::ImageLuma8(img.clone()).into_rgb8();
+    draw_bbox(&mut rgb, d_result, font_size, line_thickness);
+    rgb
+}
 
-        let text = format!("{}: {:.2}", d.class, d.confidence);
-        draw_label(img, x1, y1, line_thickness, color, &font, font_size, &text);
+/// 画像上に正解データのバウンディングボックスを描画します。
+///
+/// 検出結果と区別できるよう、クラスによらず緑色で描画します。
+///
+/// # Args
+///
+/// * `img` - バウンディングボックスを描画する画像 (in-place)
+/// * `gt` - 正解データ
+/// * `line_thickness` - バウンディングボックスの線の太さ
+pub fn draw_ground_truth(img: &mut image::RgbImage, gt: &GroundTruth, line_thickness: f32) {
+    let color = Rgb([0u8, 255, 0]);
+    for d in gt.boxes.iter() {
+        draw_rect(
+            img,
+            d.x1.round(),
+            d.y1.round(),
+            d.x2.round(),
+            d.y2.round(),
+            line_thickness,
+            color,
+        );
     }
 }
@@ -123,6 +123,17 @@ impl LayerGroup {
             biases: None,
         }
     }
+    /// 全ての入力・出力チャネルの重みの要素数を返します。
+    pub fn weights_len(&self) -> usize {
+        (12 * self.input_ch * self.output_ch * self.input_fold_factor * self.output_fold_factor)
+            as usize
+    }
+
+    /// 全ての出力チャネルのバイアスの要素数を返します。
+    pub fn biases_len(&self) -> usize {
+        (self.output_ch * self.output_fold_factor) as usize
+    }
+
     /// 指定したチャネルにおける重みを取得します。
     ///
     /// # Args
@@ -10,17 +10,87 @@
 //! 4. **後処理**: YOLOの出力を人間が理解しやすい形式に変換します。
 //!
 //! ## Example
-//! ```
+//! ```ignore
 //! let wdir = "examples/weights";  // 重みファイルがあるディレクトリ
 //! let mut yolo = YoloV3Tiny::new("/slab/hwinfo.json", "yolo", 7, 0.2, 0.1, wdir, wdir)?;
 //! let result = yolo.start(&test_img, 0)?;
 //! ```
 
+pub mod anchors;
+#[cfg(feature = "ndarray")]
+pub mod array;
+pub mod arrow;
+pub mod calibration;
+pub mod config;
+pub mod daemon;
+pub mod day_night;
+pub mod framebuffer;
+pub mod history;
 pub mod layer_group;
+pub mod line_counter;
+pub mod mining;
+pub mod mjpeg;
+pub mod nms;
+pub mod npy;
+pub mod palette;
 pub mod postprocess;
+pub mod preprocess;
+pub mod quant;
+#[cfg(any(feature = "gstreamer", feature = "ffmpeg"))]
+pub mod recorder;
+pub mod refine;
+pub mod region;
+pub mod render;
+#[cfg(feature = "ros2")]
+pub mod ros2;
+pub mod scheduler;
+pub mod service;
+pub mod session;
+pub mod sim;
+pub mod smoother;
+pub mod statistics;
+#[cfg(feature = "sqlite")]
+pub mod storage;
+pub mod svg;
 pub mod img_proc;
+pub mod detection_log;
 pub mod detection_result;
+pub mod drawing;
+pub mod debug;
+pub mod enhance;
+pub mod eval;
+pub mod features;
+#[cfg(feature = "ffmpeg")]
+pub mod ffmpeg;
+pub mod fisheye;
+#[cfg(feature = "fpga-manager")]
+pub mod fpga_manager;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod ground_plane;
+pub mod ground_truth;
+#[cfg(feature = "gstreamer")]
+pub mod gst_pipeline;
+pub mod hooks;
+pub mod hw_state;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod telemetry;
+pub mod trace;
+pub mod tracker;
+pub mod traffic_light;
+pub mod tta;
+pub mod udmabuf;
+pub mod validator;
+pub mod vdma;
+#[cfg(feature = "gstreamer")]
+pub mod video;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 pub mod yolov3_tiny;
+pub mod zones;
 
-mod nms;
+mod axi_dma;
+mod self_test;
+mod uio;
 mod yolo;
@@ -0,0 +1,263 @@
+//! 線分を横切った物体を方向別に数えるモジュール
+//!
+//! 追跡中の物体 (`tracker::Tracker`) の位置がフレーム間で線分をまたいだときに、横切った方向ごとに
+//! クラス別の通過数を数えます。交通量の計測などに使います。物体の位置は `zones` と同じく
+//! バウンディングボックスの下端の中央 (足元) で判定します。
+//!
+//! ```ignore
+//! let mut tracker = Tracker::new(0.3, 5);
+//! let mut counter = LineCounter::new();
+//! counter.add_line(CountingLine::new("gate", (0., 300.), (640., 300.)))?;
+//!
+//! let detections = yolo.start_with_img_proc(&img, 0)?;
+//! counter.update(tracker.update(&detections));
+//! for (class, count) in counter.counts("gate").unwrap() {
+//!     println!("class {}: {} -> B, {} -> A", class, count.a_to_b, count.b_to_a);
+//! }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{ensure, Result};
+
+use crate::tracker::TrackedDetection;
+
+/// 物体を数える線分
+///
+/// 始点から終点を見て左側 (画像の座標系) をA側、右側をB側とします。
+#[derive(Debug, Clone)]
+pub struct CountingLine {
+    name: String,
+    /// 始点 (画像の座標系)
+    start: (f32, f32),
+    /// 終点 (画像の座標系)
+    end: (f32, f32),
+    /// 対象のクラス。Noneの場合は全てのクラス
+    classes: Option<Vec<u8>>,
+}
+
+impl CountingLine {
+    /// 新しい `CountingLine` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `name` - 線分の名前
+    /// * `start` - 始点 (画像の座標系)
+    /// * `end` - 終点 (画像の座標系)
+    pub fn new(name: &str, start: (f32, f32), end: (f32, f32)) -> Self {
+        Self {
+            name: name.to_string(),
+            start,
+            end,
+            classes: None,
+        }
+    }
+
+    /// 対象のクラスを設定します。他のクラスの物体は数えません。
+    pub fn set_classes(&mut self, classes: Vec<u8>) -> &mut Self {
+        self.classes = Some(classes);
+        self
+    }
+
+    /// 線分の名前を返します。
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 始点と終点を返します。
+    pub fn endpoints(&self) -> ((f32, f32), (f32, f32)) {
+        (self.start, self.end)
+    }
+
+    /// クラスが対象かを判定します。
+    pub fn accepts(&self, class: u8) -> bool {
+        self.classes.as_ref().is_none_or(|c| c.contains(&class))
+    }
+
+    /// 点が線分を含む直線のどちら側にあるかを返します。
+    ///
+    /// # Return
+    /// * A側は負、B側は正、直線上は0
+    fn side(&self, (x, y): (f32, f32)) -> f32 {
+        let (dx, dy) = (self.end.0 - self.start.0, self.end.1 - self.start.1);
+        dx * (y - self.start.1) - dy * (x - self.start.0)
+    }
+
+    /// 点の移動が線分を横切ったかを判定します。
+    ///
+    /// # Args
+    /// * `from` - 前のフレームの位置
+    /// * `to` - 現在のフレームの位置
+    ///
+    /// # Return
+    /// * 横切った方向。横切っていない場合はNone
+    pub fn crossing(&self, from: (f32, f32), to: (f32, f32)) -> Option<CrossingDirection> {
+        let (s_from, s_to) = (self.side(from), self.side(to));
+        // 直線上の点はA側として扱い、直線上で止まった物体を二重に数えないようにする
+        let (a_from, a_to) = (s_from <= 0., s_to <= 0.);
+        if a_from == a_to {
+            return None;
+        }
+
+        // 移動の線分を含む直線に対して、線分の始点と終点が反対側にあるか
+        let (mx, my) = (to.0 - from.0, to.1 - from.1);
+        let move_side = |(x, y): (f32, f32)| mx * (y - from.1) - my * (x - from.0);
+        if move_side(self.start) * move_side(self.end) > 0. {
+            return None;
+        }
+
+        Some(if a_from {
+            CrossingDirection::AToB
+        } else {
+            CrossingDirection::BToA
+        })
+    }
+}
+
+/// 線分を横切った方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrossingDirection {
+    /// A側からB側
+    AToB,
+    /// B側からA側
+    BToA,
+}
+
+/// 方向別の通過数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineCount {
+    /// A側からB側に横切った数
+    pub a_to_b: usize,
+    /// B側からA側に横切った数
+    pub b_to_a: usize,
+}
+
+/// 線分を横切ったイベント
+#[derive(Debug, Clone)]
+pub struct LineCrossing {
+    /// 線分の名前
+    pub line: String,
+    /// 追跡ID
+    pub track_id: u64,
+    /// クラスID
+    pub class: u8,
+    /// 横切った方向
+    pub direction: CrossingDirection,
+}
+
+/// 登録した線分ごとに、横切った物体をクラス別に数える構造体
+#[derive(Default)]
+pub struct LineCounter {
+    lines: Vec<CountingLine>,
+    /// 線分ごとのクラス別の通過数
+    counts: Vec<BTreeMap<u8, LineCount>>,
+    /// 追跡中の物体の前のフレームの位置
+    positions: HashMap<u64, (f32, f32)>,
+}
+
+impl LineCounter {
+    /// 新しい `LineCounter` インスタンスを作成します。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 線分を追加します。
+    ///
+    /// # Args
+    /// * `line` - 線分
+    ///
+    /// # Return
+    /// * 同じ名前の線分が既にある場合や、始点と終点が同じ場合はエラー
+    pub fn add_line(&mut self, line: CountingLine) -> Result<&mut Self> {
+        ensure!(
+            self.lines.iter().all(|l| l.name != line.name),
+            "Line {} already exists",
+            line.name
+        );
+        ensure!(
+            line.start != line.end,
+            "Line {} has the same start and end points",
+            line.name
+        );
+        self.lines.push(line);
+        self.counts.push(BTreeMap::new());
+        Ok(self)
+    }
+
+    /// 登録した線分を返します。
+    pub fn lines(&self) -> &[CountingLine] {
+        &self.lines
+    }
+
+    /// 線分のクラス別の通過数を返します。
+    ///
+    /// # Return
+    /// * クラスIDごとの通過数。線分が見つからない場合はNone
+    pub fn counts(&self, name: &str) -> Option<&BTreeMap<u8, LineCount>> {
+        let idx = self.lines.iter().position(|l| l.name == name)?;
+        Some(&self.counts[idx])
+    }
+
+    /// 線分の全てのクラスの通過数の合計を返します。
+    pub fn total(&self, name: &str) -> Option<LineCount> {
+        Some(
+            self.counts(name)?
+                .values()
+                .fold(LineCount::default(), |acc, c| LineCount {
+                    a_to_b: acc.a_to_b + c.a_to_b,
+                    b_to_a: acc.b_to_a + c.b_to_a,
+                }),
+        )
+    }
+
+    /// 通過数を0に戻します。追跡中の物体の位置は保持します。
+    pub fn reset_counts(&mut self) {
+        for c in &mut self.counts {
+            c.clear();
+        }
+    }
+
+    /// 1フレーム分の追跡結果で、線分を横切った物体を数えます。
+    ///
+    /// 現在のフレームで検出された物体 (`missed` が0) だけを使い、前に検出されたときの位置からの移動で判定します。
+    ///
+    /// # Args
+    /// * `tracks` - 追跡中の全ての物体 (`Tracker::update` の戻り値)
+    ///
+    /// # Return
+    /// * 現在のフレームで発生した通過 (線分の登録順)
+    pub fn update(&mut self, tracks: &[TrackedDetection]) -> Vec<LineCrossing> {
+        let mut crossings = vec![];
+        for t in tracks.iter().filter(|t| t.missed == 0) {
+            let d = &t.detection;
+            let pos = (d.center().0, d.y2);
+            let Some(prev) = self.positions.insert(t.track_id, pos) else {
+                continue;
+            };
+            for (line, counts) in self.lines.iter().zip(self.counts.iter_mut()) {
+                if !line.accepts(d.class) {
+                    continue;
+                }
+                let Some(direction) = line.crossing(prev, pos) else {
+                    continue;
+                };
+                let count = counts.entry(d.class).or_default();
+                match direction {
+                    CrossingDirection::AToB => count.a_to_b += 1,
+                    CrossingDirection::BToA => count.b_to_a += 1,
+                }
+                crossings.push(LineCrossing {
+                    line: line.name.clone(),
+                    track_id: t.track_id,
+                    class: d.class,
+                    direction,
+                });
+            }
+        }
+
+        // 追跡が終了した物体の位置は削除する
+        self.positions
+            .retain(|id, _| tracks.iter().any(|t| t.track_id == *id));
+        crossings.sort_by_key(|c| self.lines.iter().position(|l| l.name == c.line));
+        crossings
+    }
+}
@@ -0,0 +1,101 @@
+//! 再学習用のデータセットを作るため、検出結果の切り抜き画像を保存するモジュール
+
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use image::{imageops, RgbImage};
+
+use crate::detection_result::DetectionData;
+
+/// 検出結果の切り抜き画像を保存する構造体
+///
+/// 画像は `{出力先}/{ラベル}/f{フレーム番号}_c{クラス}_p{コンフィデンス}_x{x}_y{y}_w{幅}_h{高さ}.png` として保存されます。
+/// ラベルは採用された検出結果が `accepted`、バリデータで除外された信号機の候補が `rejected` です。
+pub struct CropSaver {
+    dir: PathBuf,
+    save_rejected: bool,
+    min_size: u32,
+    margin: f32,
+}
+
+impl CropSaver {
+    /// 新しい `CropSaver` インスタンスを作成します。ディレクトリが存在しない場合は作成します。
+    ///
+    /// # Args
+    /// * `dir` - 出力先のディレクトリ
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            save_rejected: true,
+            min_size: 4,
+            margin: 0.,
+        })
+    }
+
+    /// バリデータで除外された候補も保存するかを設定します。
+    pub fn set_save_rejected(&mut self, save_rejected: bool) -> &mut Self {
+        self.save_rejected = save_rejected;
+        self
+    }
+
+    /// 保存する切り抜き画像の幅と高さの最小値を設定します。
+    pub fn set_min_size(&mut self, min_size: u32) -> &mut Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// バウンディングボックスの周囲に含める余白を、幅と高さに対する割合で設定します。
+    pub fn set_margin(&mut self, margin: f32) -> &mut Self {
+        self.margin = margin.max(0.);
+        self
+    }
+
+    /// バリデータで除外された候補を保存するかを返します。
+    pub fn save_rejected(&self) -> bool {
+        self.save_rejected
+    }
+
+    /// 検出結果の切り抜き画像を保存します。
+    ///
+    /// # Args
+    /// * `img` - 検出結果の座標系に対応する画像
+    /// * `frame_id` - フレーム番号
+    /// * `detections` - 検出結果
+    /// * `label` - 保存先のサブディレクトリ名
+    pub fn save(
+        &self,
+        img: &RgbImage,
+        frame_id: u64,
+        detections: &[DetectionData],
+        label: &str,
+    ) -> Result<()> {
+        let dir = self.dir.join(label);
+        fs::create_dir_all(&dir)?;
+
+        for d in detections {
+            let mx = d.width() * self.margin;
+            let my = d.height() * self.margin;
+            let x1 = (d.x1 - mx).max(0.).round() as u32;
+            let y1 = (d.y1 - my).max(0.).round() as u32;
+            let x2 = ((d.x2 + mx).round() as u32).min(img.width());
+            let y2 = ((d.y2 + my).round() as u32).min(img.height());
+            let (w, h) = (x2.saturating_sub(x1), y2.saturating_sub(y1));
+            // 最小値が0の場合も、空の画像は保存できないため除く
+            if w < self.min_size.max(1) || h < self.min_size.max(1) {
+                continue;
+            }
+
+            let name = format!(
+                "f{:08}_c{}_p{:.2}_x{}_y{}_w{}_h{}.png",
+                frame_id, d.class, d.confidence, x1, y1, w, h
+            );
+            imageops::crop_imm(img, x1, y1, w, h)
+                .to_image()
+                .save(dir.join(name))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! バウンディングボックスを描画したフレームをMJPEG over HTTPで配信するモジュール
+//!
+//! ブラウザやVLCで `http://{ボードのアドレス}:{ポート}/` を開くと、検出結果をリアルタイムに確認できます。
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::RgbImage;
+
+use crate::detection_result::DetectionData;
+use crate::img_proc::draw_bbox;
+
+/// multipartの境界文字列
+const BOUNDARY: &str = "yoloframe";
+
+/// 接続中のクライアント
+type Clients = Arc<Mutex<Vec<TcpStream>>>;
+
+/// フレームを接続中の全てのクライアントにMJPEGで配信する構造体
+///
+/// `bind` で待ち受けを開始すると、接続の受け付けはバックグラウンドのスレッドで行われます。
+/// 送信に失敗したクライアントは切断されたものとして取り除かれます。
+pub struct MjpegServer {
+    clients: Clients,
+    jpeg_quality: u8,
+    font_size: f32,
+    line_thickness: f32,
+}
+
+impl MjpegServer {
+    /// 指定したアドレスで接続の待ち受けを開始します。
+    ///
+    /// # Args
+    /// * `addr` - 待ち受けるアドレス (例: `0.0.0.0:8082`)
+    pub fn bind(addr: &str) -> Result<Self> {
+        let listener = TcpListener::bind(addr).with_context(|| format!("Can't bind {}", addr))?;
+        let clients: Clients = Arc::default();
+
+        let accepted = clients.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // 応答しないクライアントで配信が止まらないようにする
+                let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                match Self::accept(stream) {
+                    Ok(stream) => accepted.lock().unwrap().push(stream),
+                    Err(e) => log::warn!("MJPEG: {}", e),
+                }
+            }
+        });
+
+        Ok(Self {
+            clients,
+            jpeg_quality: 70,
+            font_size: 14.,
+            line_thickness: 2.,
+        })
+    }
+
+    /// リクエストヘッダを読み捨て、レスポンスヘッダを送信します。
+    fn accept(mut stream: TcpStream) -> Result<TcpStream> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+                break;
+            }
+        }
+        write!(
+            stream,
+            "HTTP/1.0 200 OK\r\n\
+             Cache-Control: no-cache\r\n\
+             Connection: close\r\n\
+             Content-Type: multipart/x-mixed-replace; boundary={}\r\n\r\n",
+            BOUNDARY
+        )?;
+        Ok(stream)
+    }
+
+    /// 配信する画像のJPEGの品質 (1-100) を設定します。
+    pub fn set_jpeg_quality(&mut self, jpeg_quality: u8) -> &mut Self {
+        self.jpeg_quality = jpeg_quality.clamp(1, 100);
+        self
+    }
+
+    /// 配信する画像に描画するラベルの文字の大きさと線の太さを設定します。
+    pub fn set_draw_style(&mut self, font_size: f32, line_thickness: f32) -> &mut Self {
+        self.font_size = font_size;
+        self.line_thickness = line_thickness;
+        self
+    }
+
+    /// 接続中のクライアントの数を返します。
+    pub fn client_count(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// 検出結果を描画したフレームを配信します。
+    ///
+    /// # Args
+    /// * `img` - フレーム
+    /// * `detections` - 検出結果 (`img` の座標系)
+    pub fn push(&self, img: &RgbImage, detections: &[DetectionData]) -> Result<()> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return Ok(());
+        }
+
+        let mut annotated = img.clone();
+        draw_bbox(
+            &mut annotated,
+            detections,
+            self.font_size,
+            self.line_thickness,
+        );
+        let mut jpeg = vec![];
+        JpegEncoder::new_with_quality(&mut jpeg, self.jpeg_quality).encode_image(&annotated)?;
+
+        let mut part = format!(
+            "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            BOUNDARY,
+            jpeg.len()
+        )
+        .into_bytes();
+        part.extend_from_slice(&jpeg);
+        part.extend_from_slice(b"\r\n");
+
+        clients.retain_mut(|stream| stream.write_all(&part).is_ok());
+        Ok(())
+    }
+}
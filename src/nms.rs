@@ -1,73 +1,216 @@
+//! 検出結果に対するNon-Maximum Suppression (NMS)のモジュール
+//!
+//! `AsRef<DetectionData>` を実装した任意の型に適用できるため、
+//! 複数の回転やモデルの検出結果を統合する場合などにも利用できます。
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeInclusive;
 
 use crate::detection_result::DetectionData;
 
-/// 2つの検出データ間のIoU（Intersection over Union）を計算します。
+/// `nms` がグリッドで分割したNMS (`nms_grid`) に切り替える検出データの数
+///
+/// 閾値を下げて候補が増えると、全ての組み合わせを比べるNMSが後処理の大半を占めるようになります。
+pub const GRID_NMS_MIN_CANDIDATES: usize = 256;
+
+/// 1つのバウンディングボックスを登録するセルの数の上限 (1辺あたり)。超える場合は全ての候補と比べます
+const GRID_MAX_SPAN: i64 = 8;
+
+/// Non-Maximum Suppression (NMS)を適用して、重複した検出を削除します。クラスは区別しません。
+///
+/// 検出データが `GRID_NMS_MIN_CANDIDATES` 個以上の場合は `nms_grid` を使います。結果は同じです。
 ///
 /// # Args
-/// * `a` - 検出データ1
-/// * `b` - 検出データ2
+/// * `bb` - 検出データの配列
+/// * `nms_threshold` - NMSの閾値
 ///
 /// # Return
-/// * IoUの値（0.0から1.0の範囲）
-fn iou(a: &DetectionData, b: &DetectionData) -> f32 {
-    let dx = a.x2.min(b.x2) - a.x1.max(b.x1);
-    let dy = a.y2.min(b.y2) - a.y1.max(b.y1);
-    let inter_area = (dx * dy).max(0.);
+/// * NMSを適用した後の検出データの配列
+pub fn nms<S, T: AsRef<DetectionData<S>> + Clone>(bb: &[T], nms_threshold: f32) -> Vec<T> {
+    if bb.len() >= GRID_NMS_MIN_CANDIDATES && nms_threshold > 0. {
+        return nms_grid(bb, nms_threshold);
+    }
 
-    let area1 = (a.x2 - a.x1) * (a.y2 - a.y1);
-    let area2 = (b.x2 - b.x1) * (b.y2 - b.y1);
+    let mut detections = bb.to_vec();
+    detections.sort_by(|a, b| b.as_ref().confidence.total_cmp(&a.as_ref().confidence));
 
-    inter_area / (area1 + area2 - inter_area)
+    let mut keep: Vec<T> = vec![];
+    while !detections.is_empty() {
+        let detection = detections.remove(0);
+        detections.retain(|x| detection.as_ref().iou(x.as_ref()) < nms_threshold);
+
+        keep.push(detection);
+    }
+    keep
 }
 
-/// Non-Maximum Suppression (NMS)を適用して、重複した検出を削除します。
+/// 空間をグリッドで分割し、近くのセルにあるバウンディングボックスだけを比べるNMSです。クラスは区別しません。
+///
+/// 採用済みのバウンディングボックスを重なるセルに登録しておき、候補と同じセルにあるものとだけIoUを計算します。
+/// 重ならないバウンディングボックスのIoUは0のため、`nms_threshold` が正であれば `nms` と同じ結果になります。
+/// セルの大きさはバウンディングボックスの幅と高さの平均です。
 ///
 /// # Args
 /// * `bb` - 検出データの配列
-/// * `nms_threshold` - NMSの閾値
+/// * `nms_threshold` - NMSの閾値 (正の値)
 ///
 /// # Return
 /// * NMSを適用した後の検出データの配列
-fn nms(bb: &[DetectionData], nms_threshold: f32) -> Vec<DetectionData> {
+pub fn nms_grid<S, T: AsRef<DetectionData<S>> + Clone>(bb: &[T], nms_threshold: f32) -> Vec<T> {
     let mut detections = bb.to_vec();
-    detections.sort_by(|a, b| b.confidence.partial_cmp(&a.confidence).unwrap());
+    detections.sort_by(|a, b| b.as_ref().confidence.total_cmp(&a.as_ref().confidence));
 
-    let mut keep = vec![];
-    while !detections.is_empty() {
-        let detection = detections.remove(0);
-        keep.push(detection);
+    let sizes: Vec<f32> = detections
+        .iter()
+        .map(|d| d.as_ref().width().max(d.as_ref().height()))
+        .filter(|s| s.is_finite() && *s > 0.)
+        .collect();
+    let cell_size = (sizes.iter().sum::<f32>() / sizes.len().max(1) as f32).max(1.);
+
+    let mut keep: Vec<T> = vec![];
+    // セルごとの採用済みのインデックス
+    let mut grid: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+    // セルに登録できない (大きすぎる・面積がない) 採用済みのインデックス
+    let mut unbucketed: Vec<usize> = vec![];
+    for detection in detections {
+        let d = detection.as_ref();
+        let is_kept = |&i: &usize| d.iou(keep[i].as_ref()) < nms_threshold;
+        let cells = grid_cells(d, cell_size);
+        let suppressed = match cells.clone() {
+            Some((xs, ys)) => {
+                !unbucketed.iter().all(is_kept)
+                    || !xs
+                        .flat_map(|x| ys.clone().map(move |y| (x, y)))
+                        .filter_map(|c| grid.get(&c))
+                        .all(|idx| idx.iter().all(is_kept))
+            }
+            // 面積がない場合はIoUが不定になるため、全ての採用済みのものと比べる
+            None => !(0..keep.len()).all(|i| is_kept(&i)),
+        };
+        if suppressed {
+            continue;
+        }
 
-        detections.retain(|x| iou(&detection, x) < nms_threshold);
+        let i = keep.len();
+        match cells {
+            Some((xs, ys)) => {
+                for x in xs {
+                    for y in ys.clone() {
+                        grid.entry((x, y)).or_default().push(i);
+                    }
+                }
+            }
+            None => unbucketed.push(i),
+        }
+        keep.push(detection);
     }
     keep
 }
 
+/// バウンディングボックスが重なるセルの範囲を返します。
+///
+/// # Return
+/// * x方向とy方向のセルの範囲。幅か高さが正でないか、`GRID_MAX_SPAN` より多くのセルにまたがる場合はNone
+fn grid_cells<S>(
+    d: &DetectionData<S>,
+    cell_size: f32,
+) -> Option<(RangeInclusive<i64>, RangeInclusive<i64>)> {
+    if !(d.width() > 0. && d.height() > 0. && d.area().is_finite()) {
+        return None;
+    }
+    let cell = |v: f32| (v / cell_size).floor() as i64;
+    let (x1, y1, x2, y2) = (cell(d.x1), cell(d.y1), cell(d.x2), cell(d.y2));
+    if x2 - x1 >= GRID_MAX_SPAN || y2 - y1 >= GRID_MAX_SPAN {
+        return None;
+    }
+    Some((x1..=x2, y1..=y2))
+}
+
 /// 検出データをクラスごとに分割し、各クラスにNMSを適用します。
 ///
 /// # Args
 /// * `bb` - 検出データの配列
+/// * `nms_threshold` - NMSの閾値
+///
+/// # Return
+/// * NMSを適用した後の検出データの配列 (クラスIDの昇順)
+pub fn nms_by_class<S, T: AsRef<DetectionData<S>> + Clone>(bb: &[T], nms_threshold: f32) -> Vec<T> {
+    let mut cls: BTreeMap<u8, Vec<T>> = BTreeMap::new();
+    for detection in bb {
+        cls.entry(detection.as_ref().class)
+            .or_default()
+            .push(detection.clone());
+    }
+
+    cls.into_values()
+        .flat_map(|d| nms(&d, nms_threshold))
+        .collect()
+}
+
+/// 複数の検出結果の集合を、集合をまたいだNMSで統合します。
+///
+/// 同じクラスで、別の集合の採用済みの検出データとのIoUが閾値以上のものだけを削除します。
+/// 同じ集合の中の検出データは互いに抑制しないため、それぞれの集合で既にNMSを適用した結果を
+/// 統合するときに、片方だけで検出された近くの物体を残すことができます。
+///
+/// # Args
+/// * `sets` - 検出データの集合 (同じ座標系)
+/// * `nms_threshold` - NMSの閾値
+///
+/// # Return
+/// * 統合した検出データの配列 (コンフィデンスの降順)
+pub fn nms_cross_sets<S, T: AsRef<DetectionData<S>> + Clone>(
+    sets: &[&[T]],
+    nms_threshold: f32,
+) -> Vec<T> {
+    let mut detections: Vec<(usize, &T)> = sets
+        .iter()
+        .enumerate()
+        .flat_map(|(set, bb)| bb.iter().map(move |d| (set, d)))
+        .collect();
+    detections.sort_by(|(_, a), (_, b)| b.as_ref().confidence.total_cmp(&a.as_ref().confidence));
+
+    let mut keep: Vec<(usize, &T)> = vec![];
+    for (set, detection) in detections {
+        let d = detection.as_ref();
+        let suppressed = keep.iter().any(|&(other_set, other)| {
+            let o = other.as_ref();
+            other_set != set && o.class == d.class && o.iou(d) >= nms_threshold
+        });
+        if !suppressed {
+            keep.push((set, detection));
+        }
+    }
+    keep.into_iter().map(|(_, d)| d.clone()).collect()
+}
+
+/// 検出データをコンフィデンスで絞り込んだ後、クラスごとに分割し、各クラスにNMSを適用します。
+///
+/// # Args
+/// * `bb` - 検出データの配列
 /// * `cls_num` - クラスの数
 /// * `obj_threshold` - オブジェクト検出の閾値
 /// * `nms_threshold` - NMSの閾値
 ///
 /// # Return
 /// * NMSを適用した後の検出データの配列
-pub fn nms_process(
-    bb: &[DetectionData],
+pub fn nms_process<S, T: AsRef<DetectionData<S>> + Clone>(
+    bb: &[T],
     cls_num: usize,
     obj_threshold: f32,
     nms_threshold: f32,
-) -> Vec<DetectionData> {
+) -> Vec<T> {
     // クラス別に分割
-    let mut cls: Vec<Vec<DetectionData>> = vec![vec![]; cls_num];
-    for &detection in bb {
-        if detection.confidence > obj_threshold && detection.confidence <= 1.0 {
-            cls[detection.class as usize].push(detection);
+    let mut cls: Vec<Vec<T>> = vec![vec![]; cls_num];
+    for detection in bb {
+        let d = detection.as_ref();
+        if d.confidence > obj_threshold && d.confidence <= 1.0 {
+            cls[d.class as usize].push(detection.clone());
         }
     }
 
     // 各クラスに Non-Maximum Suppression (NMS) を適用し，重なっているBBoxの中でコンフィデンスが最大のものを集める
-    let new_box: Vec<DetectionData> = cls
+    let new_box: Vec<T> = cls
         .into_iter()
         .flat_map(|d| nms(&d, nms_threshold))
         .collect();
@@ -0,0 +1,273 @@
+//! NumPyの `.npy` / `.npz` 形式でテンソルを読み書きするモジュール
+//!
+//! レイヤーグループの入力・出力や重みを書き出し、NumPyやPyTorchのリファレンスと直接比較するために使います。
+//! 対応する型は `i16` (`<i2`) と `f32` (`<f4`) で、C順 (行優先) の配列のみを扱います。
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// `.npy` ファイルの先頭のマジックナンバー
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// ヘッダを含むデータの先頭の境界 (バイト)
+const HEADER_ALIGN: usize = 64;
+
+/// `.npy` に保存できる要素の型
+pub trait NpyElement: Copy {
+    /// NumPyの型の記述子 (例: `<i2`)
+    const DESCR: &'static str;
+    /// 要素のバイト数
+    const SIZE: usize;
+
+    /// リトルエンディアンのバイト列から値を読み込みます。
+    fn from_le_slice(bytes: &[u8]) -> Self;
+
+    /// リトルエンディアンのバイト列として値を書き込みます。
+    fn extend_le(self, buf: &mut Vec<u8>);
+}
+
+impl NpyElement for i16 {
+    const DESCR: &'static str = "<i2";
+    const SIZE: usize = 2;
+
+    fn from_le_slice(bytes: &[u8]) -> Self {
+        i16::from_le_bytes([bytes[0], bytes[1]])
+    }
+
+    fn extend_le(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl NpyElement for f32 {
+    const DESCR: &'static str = "<f4";
+    const SIZE: usize = 4;
+
+    fn from_le_slice(bytes: &[u8]) -> Self {
+        f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn extend_le(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+/// 形状つきのテンソル
+#[derive(Debug, Clone, PartialEq)]
+pub struct NpyArray<T> {
+    /// 形状
+    pub shape: Vec<usize>,
+    /// データ (C順)
+    pub data: Vec<T>,
+}
+
+impl<T: NpyElement> NpyArray<T> {
+    /// 新しい `NpyArray` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `shape` - 形状
+    /// * `data` - データ (C順)
+    ///
+    /// # Return
+    /// * テンソル。データの長さが形状に合わない場合はエラー
+    pub fn new(shape: Vec<usize>, data: Vec<T>) -> Result<Self> {
+        let len: usize = shape.iter().product();
+        ensure!(
+            len == data.len(),
+            "Data length {} does not match shape {:?}",
+            data.len(),
+            shape
+        );
+        Ok(Self { shape, data })
+    }
+
+    /// 1次元のテンソルを作成します。
+    pub fn from_vec(data: Vec<T>) -> Self {
+        Self {
+            shape: vec![data.len()],
+            data,
+        }
+    }
+
+    /// `.npy` 形式で書き込みます。
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        let shape = match self.shape.as_slice() {
+            [n] => format!("({},)", n),
+            s => format!(
+                "({})",
+                s.iter()
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        let mut header = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+            T::DESCR,
+            shape
+        );
+        // マジックナンバー・バージョン・ヘッダ長の10バイトと改行を含めて境界に揃える
+        let pad =
+            (HEADER_ALIGN - (MAGIC.len() + 4 + header.len() + 1) % HEADER_ALIGN) % HEADER_ALIGN;
+        header.extend(std::iter::repeat_n(' ', pad));
+        header.push('\n');
+        let header_len = u16::try_from(header.len()).context("npy header is too long")?;
+
+        let mut buf =
+            Vec::with_capacity(MAGIC.len() + 4 + header.len() + self.data.len() * T::SIZE);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&[1, 0]);
+        buf.extend_from_slice(&header_len.to_le_bytes());
+        buf.extend_from_slice(header.as_bytes());
+        for &v in &self.data {
+            v.extend_le(&mut buf);
+        }
+        writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// `.npy` 形式のデータを読み込みます。
+    ///
+    /// 型が一致しない場合や、Fortran順の配列の場合はエラーを返します。
+    pub fn read<R: Read>(mut reader: R) -> Result<Self> {
+        let mut preamble = [0u8; 8];
+        reader.read_exact(&mut preamble)?;
+        ensure!(&preamble[..6] == MAGIC, "Not a npy file");
+        let header_len = match preamble[6] {
+            1 => {
+                let mut len = [0u8; 2];
+                reader.read_exact(&mut len)?;
+                u16::from_le_bytes(len) as usize
+            }
+            2 | 3 => {
+                let mut len = [0u8; 4];
+                reader.read_exact(&mut len)?;
+                u32::from_le_bytes(len) as usize
+            }
+            v => bail!("Unsupported npy version {}", v),
+        };
+        let mut header = vec![0u8; header_len];
+        reader.read_exact(&mut header)?;
+        let header = String::from_utf8(header).context("npy header is not UTF-8")?;
+
+        let descr = header_value(&header, "descr")?;
+        let descr = descr.trim_matches(|c| c == '\'' || c == '"');
+        ensure!(
+            descr == T::DESCR,
+            "npy dtype {} does not match {}",
+            descr,
+            T::DESCR
+        );
+        ensure!(
+            header_value(&header, "fortran_order")? == "False",
+            "Fortran-ordered npy arrays are not supported"
+        );
+        let shape = header_value(&header, "shape")?
+            .trim_matches(|c| c == '(' || c == ')')
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<usize>().context("Invalid npy shape"))
+            .collect::<Result<Vec<_>>>()?;
+
+        let len: usize = shape.iter().product();
+        let mut bytes = vec![0u8; len * T::SIZE];
+        reader.read_exact(&mut bytes)?;
+        let data = bytes.chunks_exact(T::SIZE).map(T::from_le_slice).collect();
+        Ok(Self { shape, data })
+    }
+
+    /// `.npy` ファイルに保存します。
+    ///
+    /// # Args
+    /// * `path` - 保存先のパス
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("Can't create {}", path.display()))?;
+        self.write(BufWriter::new(file))
+    }
+
+    /// `.npy` ファイルを読み込みます。
+    ///
+    /// # Args
+    /// * `path` - 読み込むファイルのパス
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Can't open {}", path.display()))?;
+        Self::read(BufReader::new(file)).with_context(|| format!("Can't read {}", path.display()))
+    }
+}
+
+/// ヘッダの辞書から指定したキーの値 (文字列のまま) を取り出します。
+fn header_value<'a>(header: &'a str, key: &str) -> Result<&'a str> {
+    let pos = ["'", "\""]
+        .iter()
+        .find_map(|q| header.find(&format!("{q}{key}{q}")))
+        .with_context(|| format!("npy header has no {}", key))?;
+    let rest = header[pos + key.len() + 2..]
+        .trim_start()
+        .strip_prefix(':')
+        .context("Invalid npy header")?
+        .trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')').map(|i| i + 1)
+    } else {
+        rest.find([',', '}'])
+    }
+    .context("Invalid npy header")?;
+    Ok(rest[..end].trim())
+}
+
+/// 複数のテンソルを `.npz` ファイル (`numpy.savez_compressed` 互換) に保存します。
+///
+/// # Args
+/// * `path` - 保存先のパス
+/// * `arrays` - 配列の名前とテンソル。名前には拡張子 `.npy` が付加されます
+pub fn save_npz<P: AsRef<Path>, T: NpyElement>(
+    path: P,
+    arrays: &[(&str, &NpyArray<T>)],
+) -> Result<()> {
+    let path = path.as_ref();
+    let file = File::create(path).with_context(|| format!("Can't create {}", path.display()))?;
+    let mut zip = ZipWriter::new(BufWriter::new(file));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, array) in arrays {
+        zip.start_file(format!("{}.npy", name), options)?;
+        array.write(&mut zip)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// `.npz` ファイルに含まれる全てのテンソルを読み込みます。
+///
+/// # Args
+/// * `path` - 読み込むファイルのパス
+///
+/// # Return
+/// * 配列の名前 (拡張子を除く) とテンソルの組。ファイル内の順に並びます
+pub fn load_npz<P: AsRef<Path>, T: NpyElement>(path: P) -> Result<Vec<(String, NpyArray<T>)>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Can't open {}", path.display()))?;
+    read_npz(BufReader::new(file)).with_context(|| format!("Can't read {}", path.display()))
+}
+
+/// `.npz` 形式のデータから全てのテンソルを読み込みます。
+fn read_npz<R: Read + Seek, T: NpyElement>(reader: R) -> Result<Vec<(String, NpyArray<T>)>> {
+    let mut zip = ZipArchive::new(reader)?;
+    let mut arrays = Vec::with_capacity(zip.len());
+    for i in 0..zip.len() {
+        let entry = zip.by_index(i)?;
+        let name = entry.name();
+        let name = name.strip_suffix(".npy").unwrap_or(name).to_string();
+        let array = NpyArray::read(entry).with_context(|| format!("Can't read array {}", name))?;
+        arrays.push((name, array));
+    }
+    Ok(arrays)
+}
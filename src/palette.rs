@@ -0,0 +1,104 @@
+//! クラスごとの描画の色を管理するモジュール
+//!
+//! 登録した色と既定の10色を優先し、どちらにもないクラスには色相を黄金角ずつずらした色を割り当てます。
+//! 割り当てる色はクラスの番号だけで決まるため、クラス数によらず、実行するたびに同じ色になります。
+
+use image::Rgb;
+
+/// 既定のクラスの色
+pub const DEFAULT_COLORS: [[u8; 3]; 10] = [
+    [255, 0, 0],
+    [255, 255, 0],
+    [0, 0, 255],
+    [14, 23, 50],
+    [28, 105, 80],
+    [190, 159, 53],
+    [46, 194, 148],
+    [242, 30, 131],
+    [97, 101, 198],
+    [115, 11, 87],
+];
+
+/// 色を生成するときの色相の間隔 (黄金角、度)
+const GOLDEN_ANGLE: f64 = 137.507_764;
+
+/// 色を生成するときの彩度
+const GENERATED_SATURATION: f64 = 0.85;
+
+/// 色を生成するときの明度
+const GENERATED_VALUE: f64 = 0.95;
+
+/// クラスごとの色の対応表
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    /// クラスの番号を添字とする色。Noneのクラスは生成した色を使います
+    colors: Vec<Option<[u8; 3]>>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            colors: DEFAULT_COLORS.iter().copied().map(Some).collect(),
+        }
+    }
+}
+
+impl Palette {
+    /// 既定の色に、クラスの番号の順に並べた色を上書きした `Palette` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `colors` - クラス0から順に並べた色
+    pub fn new(colors: &[[u8; 3]]) -> Self {
+        let mut palette = Self::default();
+        for (class, color) in colors.iter().enumerate().take(u8::MAX as usize + 1) {
+            palette.set(class as u8, *color);
+        }
+        palette
+    }
+
+    /// 既定の色を使わず、すべてのクラスに生成した色を使う `Palette` インスタンスを作成します。
+    pub fn generated() -> Self {
+        Self { colors: vec![] }
+    }
+
+    /// クラスの色を登録します。
+    ///
+    /// # Args
+    /// * `class` - クラス
+    /// * `color` - 色 (RGB)
+    pub fn set(&mut self, class: u8, color: [u8; 3]) -> &mut Self {
+        let class = class as usize;
+        if self.colors.len() <= class {
+            self.colors.resize(class + 1, None);
+        }
+        self.colors[class] = Some(color);
+        self
+    }
+
+    /// クラスの色の登録を取り消し、生成した色を使うようにします。
+    pub fn unset(&mut self, class: u8) -> &mut Self {
+        if let Some(c) = self.colors.get_mut(class as usize) {
+            *c = None;
+        }
+        self
+    }
+
+    /// クラスの色を返します。登録されていないクラスは生成した色を返します。
+    pub fn color(&self, class: u8) -> Rgb<u8> {
+        match self.colors.get(class as usize) {
+            Some(Some(c)) => Rgb(*c),
+            _ => generated_color(class),
+        }
+    }
+}
+
+/// クラスの番号から色を生成します。色相をクラスごとに黄金角ずつずらすため、近い番号のクラスは離れた色相になります。
+pub fn generated_color(class: u8) -> Rgb<u8> {
+    let hue = (class as f64 * GOLDEN_ANGLE) % 360.;
+    let rgb = color_space::Rgb::from(color_space::Hsv::new(
+        hue,
+        GENERATED_SATURATION,
+        GENERATED_VALUE,
+    ));
+    Rgb([rgb.r, rgb.g, rgb.b].map(|v| v.round().clamp(0., 255.) as u8))
+}
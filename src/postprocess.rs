@@ -1,9 +1,156 @@
 //! YOLO (You Only Look Once) 物体検出アルゴリズムの出力を後処理するためのモジュール
 
-use crate::detection_result::DetectionData;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Result};
+
+use crate::anchors::ANCHOR_NUM;
+use crate::detection_result::{DetectionData, DetectionDataExt, LetterboxSpace};
 use crate::nms::nms_process;
 
-const ANCHOR_BOX_NUM: usize = 3;
+pub(crate) const ANCHOR_BOX_NUM: usize = 3;
+
+/// 1回の出力のチャネル数
+const FOLD_CH: usize = 32;
+
+/// 重みを書き出すときの、1つのアンカーボックスあたりのクラスの枠の数 (COCOの80クラス)
+///
+/// クラス数が少ない場合も、出力のチャネルはこの枠の数で並んでいます。
+pub const DEFAULT_CLASS_SLOTS: usize = 80;
+
+/// darknetのyolov3-tiny.cfgのアンカーボックス (面積の小さい順)
+pub const DEFAULT_ANCHORS: [[f32; 2]; ANCHOR_NUM] = [
+    [10., 14.],
+    [23., 27.],
+    [37., 58.],
+    [81., 82.],
+    [135., 169.],
+    [344., 319.],
+];
+
+/// YOLO層の出力の並びとアンカーボックス
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputLayout {
+    /// 1つのアンカーボックスあたりのクラスの枠の数
+    pub class_slots: usize,
+    /// アンカーボックスの幅と高さ (YOLOの入力の座標系)。面積の小さい順に並べてください
+    pub anchors: [[f32; 2]; ANCHOR_NUM],
+    /// x, y, 物体確率, クラス確率のシグモイドを後処理で適用するか
+    ///
+    /// YOLOのIPをバイパスしたビットストリームや、IPの活性化関数を無効にする (線形にする) 場合にtrueにします。
+    pub software_sigmoid: bool,
+}
+
+impl Default for OutputLayout {
+    fn default() -> Self {
+        Self {
+            class_slots: DEFAULT_CLASS_SLOTS,
+            anchors: DEFAULT_ANCHORS,
+            software_sigmoid: false,
+        }
+    }
+}
+
+impl OutputLayout {
+    /// 13×13のヘッドで使うアンカーボックス (4-6番目) を返します。
+    pub fn anchors13(&self) -> [[f32; 2]; ANCHOR_BOX_NUM] {
+        [self.anchors[3], self.anchors[4], self.anchors[5]]
+    }
+
+    /// 26×26のヘッドで使うアンカーボックス (2-4番目) を返します。
+    pub fn anchors26(&self) -> [[f32; 2]; ANCHOR_BOX_NUM] {
+        [self.anchors[1], self.anchors[2], self.anchors[3]]
+    }
+
+    /// YOLOのIPに設定する活性化関数のマスクを求めます。
+    ///
+    /// シグモイドを後処理で適用する場合は、IPで二重に適用しないように全てのビットを0にします。
+    ///
+    /// # Args
+    /// * `folds` - 出力の分割数 (1回の出力は32チャネル)
+    ///
+    /// # Return
+    /// * 出力の分割ごとのマスク。チャネルが足りない場合はエラー
+    pub fn active_en_masks(&self, folds: usize) -> Result<Vec<u32>> {
+        let masks = active_en_masks(self.class_slots, ANCHOR_BOX_NUM, folds)?;
+        if self.software_sigmoid {
+            return Ok(vec![0; masks.len()]);
+        }
+        Ok(masks)
+    }
+}
+
+/// 1フレーム分の後処理の統計
+///
+/// 現場で閾値を調整するときに、NMSの前の候補の数や抑制された数、デコードとNMSの時間の内訳を確認できます。
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PostprocessStats {
+    /// 物体の閾値を超えた候補の数 (NMSの前)
+    pub candidates: usize,
+    /// NMSで抑制された数 (インデックスはクラスID)
+    pub suppressed: Vec<usize>,
+    /// デコード (固定小数点数の変換と座標の計算) にかかった時間
+    pub decode_time: Duration,
+    /// NMSにかかった時間
+    pub nms_time: Duration,
+}
+
+impl PostprocessStats {
+    /// NMSの前後の検出結果から統計を作成します。
+    ///
+    /// # Args
+    /// * `candidates` - 物体の閾値を超えた候補のクラスID
+    /// * `detections` - NMSの後に残った検出結果のクラスID
+    /// * `cls_num` - クラスの数
+    /// * `decode_time` - デコードにかかった時間
+    /// * `nms_time` - NMSにかかった時間
+    fn new(
+        candidates: impl Iterator<Item = u8>,
+        detections: impl Iterator<Item = u8>,
+        cls_num: usize,
+        decode_time: Duration,
+        nms_time: Duration,
+    ) -> Self {
+        let mut suppressed = vec![0; cls_num];
+        let mut total = 0;
+        for class in candidates {
+            suppressed[class as usize] += 1;
+            total += 1;
+        }
+        for class in detections {
+            suppressed[class as usize] -= 1;
+        }
+        Self {
+            candidates: total,
+            suppressed,
+            decode_time,
+            nms_time,
+        }
+    }
+
+    /// NMSで抑制された数の合計を返します。
+    pub fn suppressed_total(&self) -> usize {
+        self.suppressed.iter().sum()
+    }
+}
+
+impl fmt::Display for PostprocessStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} candidates, {} suppressed {:?}, decode {:.3} ms, nms {:.3} ms",
+            self.candidates,
+            self.suppressed_total(),
+            self.suppressed,
+            self.decode_time.as_secs_f64() * 1e3,
+            self.nms_time.as_secs_f64() * 1e3
+        )
+    }
+}
+
+/// アンカーボックスごとの出力のうち、シグモイドを適用しない幅と高さのチャネル
+const WH_CH: [usize; 2] = [2, 3];
 
 /// `fix2float`関数は、符号あり[8bits].[8bits]の固定小数点数をf32型の浮動小数点数に変換します
 ///
@@ -12,10 +159,66 @@ const ANCHOR_BOX_NUM: usize = 3;
 ///
 /// # Return
 /// * 入力値を2の8乗で除算したf32型の浮動小数点数
-fn fix2float(input: i16) -> f32 {
+pub(crate) fn fix2float(input: i16) -> f32 {
     input as f32 / 2f32.powi(8)
 }
 
+fn sigmoid(x: f32) -> f32 {
+    1. / (1. + (-x).exp())
+}
+
+/// YOLO層の出力のうち、活性化関数を適用するチャネルにシグモイドを適用します。
+///
+/// # Args
+/// * `arr` - YOLO層の出力 (分割数 * グリッド数 * グリッド数 * 32) (in-place)
+/// * `grid_num` - グリッドの数
+/// * `class_slots` - 1つのアンカーボックスあたりのクラスの枠の数
+fn apply_sigmoid(arr: &mut [f32], grid_num: usize, class_slots: usize) {
+    let stride = 5 + class_slots;
+    let used = stride * ANCHOR_BOX_NUM;
+    for (i, v) in arr.iter_mut().enumerate() {
+        let ch = FOLD_CH * (i / (grid_num * grid_num * FOLD_CH)) + i % FOLD_CH;
+        if ch < used && !WH_CH.contains(&(ch % stride)) {
+            *v = sigmoid(*v);
+        }
+    }
+}
+
+/// YOLO層で活性化関数 (シグモイド) を適用するチャネルのマスクを求めます。
+///
+/// 1つのアンカーボックスの出力は (x, y, w, h, 物体確率, クラス確率...) の順に並び、
+/// 幅と高さ、および使われていないチャネル以外でビットが1になります。
+///
+/// # Args
+/// * `class_slots` - 1つのアンカーボックスあたりのクラスの枠の数
+/// * `anchor_num` - アンカーボックスの数
+/// * `folds` - 出力の分割数 (1回の出力は32チャネル)
+///
+/// # Return
+/// * 出力の分割ごとのマスク。チャネルが足りない場合はエラー
+pub fn active_en_masks(class_slots: usize, anchor_num: usize, folds: usize) -> Result<Vec<u32>> {
+    let stride = 5 + class_slots;
+    let used = stride * anchor_num;
+    ensure!(
+        used <= folds * FOLD_CH,
+        "{} anchors with {} class slots need {} channels, but the output has {}",
+        anchor_num,
+        class_slots,
+        used,
+        folds * FOLD_CH
+    );
+    Ok((0..folds)
+        .map(|j| {
+            (0..FOLD_CH)
+                .filter(|&b| {
+                    let ch = FOLD_CH * j + b;
+                    ch < used && !WH_CH.contains(&(ch % stride))
+                })
+                .fold(0, |mask, b| mask | 1 << b)
+        })
+        .collect())
+}
+
 /// ch_reorder関数は、与えられた配列を再配置します
 ///
 /// # Args
@@ -42,10 +245,17 @@ fn ch_reorder(arr: &[f32], grid_num: usize) -> Vec<f32> {
 /// * `reorder_arr` - 再形成するf32型の配列
 /// * `grid_num` - グリッドの数（配列の再形成に使用）
 /// * `cls_num` - クラスの数（配列の再形成に使用）
+/// * `class_slots` - 1つのアンカーボックスあたりのクラスの枠の数
 ///
 /// # Return
 /// * 再形成された2つのf32型のベクトル (reshape, class)
-fn ch_reshape(reorder_arr: &[f32], grid_num: usize, cls_num: usize) -> (Vec<f32>, Vec<f32>) {
+fn ch_reshape(
+    reorder_arr: &[f32],
+    grid_num: usize,
+    cls_num: usize,
+    class_slots: usize,
+) -> (Vec<f32>, Vec<f32>) {
+    let stride = 5 + class_slots;
     let mut reshape = vec![0.; grid_num * grid_num * 18];
     let mut class = vec![0.; grid_num * grid_num * ANCHOR_BOX_NUM * cls_num];
     let mut cnt_cls = 0;
@@ -53,14 +263,14 @@ fn ch_reshape(reorder_arr: &[f32], grid_num: usize, cls_num: usize) -> (Vec<f32>
     for i in (0..grid_num * grid_num * 18).step_by(18) {
         for j in 0..ANCHOR_BOX_NUM {
             for k in 0..cls_num {
-                class[cnt_cls + j * cls_num + k] = reorder_arr[(i / 18) * 256 + 85 * j + 5 + k];
+                class[cnt_cls + j * cls_num + k] = reorder_arr[(i / 18) * 256 + stride * j + 5 + k];
             }
         }
         cnt_cls += ANCHOR_BOX_NUM * cls_num;
 
         for index in 0..18 {
             let base_index = (i / 18) * 256;
-            let reorder_index = base_index + 85 * (index / 6) + (index % 6);
+            let reorder_index = base_index + stride * (index / 6) + (index % 6);
             let offset = if index % 6 == 5 { 1 } else { 0 };
             reshape[i + index] = reorder_arr[reorder_index + offset];
         }
@@ -111,6 +321,28 @@ fn get_cls_id(cls_concat: &[f32], idx: usize, cls_num: usize) -> u8 {
         - ccnt) as u8
 }
 
+/// `get_top_k_cls`関数は、スコアの高い順に上位k個のクラス候補を取得します
+///
+/// # Args
+/// * `cls_concat` - クラスIDを取得するためのf32型の配列
+/// * `idx` - クラスIDを取得するためのインデックス
+/// * `cls_num` - クラスの数
+/// * `k` - 取得する候補の数
+///
+/// # Return
+/// * スコアの高い順に並べた (クラスID, スコア) のベクトル
+fn get_top_k_cls(cls_concat: &[f32], idx: usize, cls_num: usize, k: usize) -> Vec<(u8, f32)> {
+    let ccnt = idx * cls_num;
+    let mut candidates: Vec<(u8, f32)> = cls_concat[ccnt..ccnt + cls_num]
+        .iter()
+        .enumerate()
+        .map(|(cls_id, &score)| (cls_id as u8, score))
+        .collect();
+    candidates.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    candidates.truncate(k);
+    candidates
+}
+
 /// get_objs関数は、物体を検出します
 ///
 /// # Args
@@ -120,7 +352,11 @@ fn get_cls_id(cls_concat: &[f32], idx: usize, cls_num: usize) -> u8 {
 ///
 /// # Return
 /// * 検出された物体を表すDetectionDataのベクトル
-fn get_objs(grid_concat: &[f32], cls_concat: &[f32], cls_num: usize) -> Vec<DetectionData> {
+fn get_objs(
+    grid_concat: &[f32],
+    cls_concat: &[f32],
+    cls_num: usize,
+) -> Vec<DetectionData<LetterboxSpace>> {
     grid_concat[..(13 * 13 + 26 * 26) * 18]
         .chunks(18 / ANCHOR_BOX_NUM)
         .enumerate()
@@ -130,30 +366,56 @@ fn get_objs(grid_concat: &[f32], cls_concat: &[f32], cls_num: usize) -> Vec<Dete
         .collect()
 }
 
-/// `post_process`関数は、YOLOの出力から物体検出を行います
+/// get_objs_top_k関数は、上位k個のクラス候補を付加して物体を検出します
+///
+/// # Args
+/// * grid_concat - 物体検出を行うためのf32型の配列
+/// * cls_concat - 物体検出を行うためのf32型の配列
+/// * cls_num - クラスの数
+/// * k - クラス候補の数
+///
+/// # Return
+/// * 検出された物体を表すDetectionDataExtのベクトル
+fn get_objs_top_k(
+    grid_concat: &[f32],
+    cls_concat: &[f32],
+    cls_num: usize,
+    k: usize,
+) -> Vec<DetectionDataExt<LetterboxSpace>> {
+    grid_concat[..(13 * 13 + 26 * 26) * 18]
+        .chunks(18 / ANCHOR_BOX_NUM)
+        .enumerate()
+        .flat_map(|(idx, yolo_result)| {
+            let candidates = get_top_k_cls(cls_concat, idx, cls_num, k);
+            DetectionData::new_from_yolo(yolo_result, get_cls_id(cls_concat, idx, cls_num))
+                .map(|data| DetectionDataExt { data, candidates })
+        })
+        .collect()
+}
+
+/// `decode`関数は、YOLOの出力を座標とクラススコアの配列に変換します
 ///
 /// # Args
 /// * `yolo_out_0` - YOLOの出力
 /// * `yolo_out_1` - YOLOの別の出力
 /// * `cls_num` - クラスの数
-/// * `obj_threshold` - 物体検出の閾値
-/// * `nms_threshold` - 非最大抑制（NMS）の閾値
+/// * `layout` - 出力の並びとアンカーボックス
 ///
 /// # Return
-/// * 検出された物体を表すDetectionDataのベクトル
-///
-/// このベクトルは、物体検出の結果を表すデータ構造を含みます
-/// 各DetectionDataは、検出された物体のクラスID、信頼度スコア、およびバウンディングボックスの座標を含みます
-pub fn post_process(
+/// * 13*13検出と26*26検出を結合した配列 (grid_concat, cls_concat)
+fn decode(
     yolo_out_0: &[i16],
     yolo_out_1: &[i16],
     cls_num: usize,
-    obj_threshold: f32,
-    nms_threshold: f32,
-) -> Vec<DetectionData> {
+    layout: &OutputLayout,
+) -> (Vec<f32>, Vec<f32>) {
     // i16 >> f32
-    let arr13: Vec<f32> = yolo_out_0.iter().map(|&val| fix2float(val)).collect();
-    let arr26: Vec<f32> = yolo_out_1.iter().map(|&val| fix2float(val)).collect();
+    let mut arr13: Vec<f32> = yolo_out_0.iter().map(|&val| fix2float(val)).collect();
+    let mut arr26: Vec<f32> = yolo_out_1.iter().map(|&val| fix2float(val)).collect();
+    if layout.software_sigmoid {
+        apply_sigmoid(&mut arr13, 13, layout.class_slots);
+        apply_sigmoid(&mut arr26, 26, layout.class_slots);
+    }
 
     //channel reorder
     //8*13*13*32 >> 13*13*256
@@ -164,19 +426,17 @@ pub fn post_process(
     //channel reshape 256ch >> 255ch
     //13*13*256 >> 13*13*255
     //26*26*256 >> 26*26*255
-    let (mut reshape13, class13) = ch_reshape(&reorder13, 13, cls_num);
-    let (mut reshape26, class26) = ch_reshape(&reorder26, 26, cls_num);
+    let (mut reshape13, class13) = ch_reshape(&reorder13, 13, cls_num, layout.class_slots);
+    let (mut reshape26, class26) = ch_reshape(&reorder26, 26, cls_num, layout.class_slots);
 
     //(座標x,y) (大きさw,h) (物体確率) (class確率80)
     //2+2+1+80 = 85
     //85 * 3(anchorBOXの数) = 255
     //13*13*255, 26*26*255
     //座標と大きさを計算,確率はそのまま
-    //[[[23,27], [37,58], [81,82]], [[81,82], [135,169], [344,319]]]
-    let anchor_box_13 = [[81., 82.], [135., 169.], [344., 319.]];
-    let anchor_box_26 = [[23., 27.], [37., 58.], [81., 82.]];
-    get_anchor_box(&mut reshape13, 13, anchor_box_13);
-    get_anchor_box(&mut reshape26, 26, anchor_box_26);
+    //既定値: [[[23,27], [37,58], [81,82]], [[81,82], [135,169], [344,319]]]
+    get_anchor_box(&mut reshape13, 13, layout.anchors13());
+    get_anchor_box(&mut reshape26, 26, layout.anchors26());
 
     // 13*13検出と26*26検出を結合
     // 13*13*255, 26*26*255 >> (13*13+26*26)*255
@@ -185,9 +445,425 @@ pub fn post_process(
     let mut cls_concat = class13;
     cls_concat.extend(class26);
 
+    (grid_concat, cls_concat)
+}
+
+
+/// `post_process`関数は、YOLOの出力から物体検出を行います
+///
+/// # Args
+/// * `yolo_out_0` - YOLOの出力
+/// * `yolo_out_1` - YOLOの別の出力
+/// * `cls_num` - クラスの数
+/// * `layout` - 出力の並びとアンカーボックス (通常は `OutputLayout::default()`)
+/// * `obj_threshold` - 物体検出の閾値
+/// * `nms_threshold` - 非最大抑制（NMS）の閾値
+///
+/// # Return
+/// * 検出された物体を表すDetectionDataのベクトル
+///
+/// このベクトルは、物体検出の結果を表すデータ構造を含みます
+/// 各DetectionDataは、検出された物体のクラスID、信頼度スコア、およびバウンディングボックスの座標を含みます
+pub fn post_process(
+    yolo_out_0: &[i16],
+    yolo_out_1: &[i16],
+    cls_num: usize,
+    layout: &OutputLayout,
+    obj_threshold: f32,
+    nms_threshold: f32,
+) -> Vec<DetectionData<LetterboxSpace>> {
+    let (grid_concat, cls_concat) = decode(yolo_out_0, yolo_out_1, cls_num, layout);
+
     // ディテクション結果を抽出
     let nms_boxes = get_objs(&grid_concat, &cls_concat, cls_num);
 
     // NMS を適用
     nms_process(&nms_boxes, cls_num, obj_threshold, nms_threshold)
 }
+
+/// `post_process_with_stats`関数は、`post_process` と同じ物体検出を行い、後処理の統計を返します
+///
+/// # Args
+/// * `yolo_out_0` - YOLOの出力
+/// * `yolo_out_1` - YOLOの別の出力
+/// * `cls_num` - クラスの数
+/// * `layout` - 出力の並びとアンカーボックス (通常は `OutputLayout::default()`)
+/// * `obj_threshold` - 物体検出の閾値
+/// * `nms_threshold` - 非最大抑制（NMS）の閾値
+///
+/// # Return
+/// * 検出された物体を表すDetectionDataのベクトルと後処理の統計
+pub fn post_process_with_stats(
+    yolo_out_0: &[i16],
+    yolo_out_1: &[i16],
+    cls_num: usize,
+    layout: &OutputLayout,
+    obj_threshold: f32,
+    nms_threshold: f32,
+) -> (Vec<DetectionData<LetterboxSpace>>, PostprocessStats) {
+    let start = Instant::now();
+    let (grid_concat, cls_concat) = decode(yolo_out_0, yolo_out_1, cls_num, layout);
+    let nms_boxes = get_objs(&grid_concat, &cls_concat, cls_num);
+    let decode_time = start.elapsed();
+
+    let start = Instant::now();
+    let detections = nms_process(&nms_boxes, cls_num, obj_threshold, nms_threshold);
+    let nms_time = start.elapsed();
+
+    // `nms_process` と同じ条件で候補を数える
+    let candidates = nms_boxes
+        .iter()
+        .filter(|d| d.confidence > obj_threshold && d.confidence <= 1.0)
+        .map(|d| d.class);
+    let stats = PostprocessStats::new(
+        candidates,
+        detections.iter().map(|d| d.class),
+        cls_num,
+        decode_time,
+        nms_time,
+    );
+    (detections, stats)
+}
+
+/// `post_process_top_k`関数は、YOLOの出力から上位k個のクラス候補付きで物体検出を行います
+///
+/// # Args
+/// * `yolo_out_0` - YOLOの出力
+/// * `yolo_out_1` - YOLOの別の出力
+/// * `cls_num` - クラスの数
+/// * `layout` - 出力の並びとアンカーボックス (通常は `OutputLayout::default()`)
+/// * `obj_threshold` - 物体検出の閾値
+/// * `nms_threshold` - 非最大抑制（NMS）の閾値
+/// * `k` - 保持するクラス候補の数
+///
+/// # Return
+/// * 検出された物体を表すDetectionDataExtのベクトル
+///
+/// NMSは最もスコアの高いクラスごとに適用されます
+pub fn post_process_top_k(
+    yolo_out_0: &[i16],
+    yolo_out_1: &[i16],
+    cls_num: usize,
+    layout: &OutputLayout,
+    obj_threshold: f32,
+    nms_threshold: f32,
+    k: usize,
+) -> Vec<DetectionDataExt<LetterboxSpace>> {
+    let (grid_concat, cls_concat) = decode(yolo_out_0, yolo_out_1, cls_num, layout);
+
+    // ディテクション結果を抽出
+    let nms_boxes = get_objs_top_k(&grid_concat, &cls_concat, cls_num, k);
+
+    // NMS を適用
+    nms_process(&nms_boxes, cls_num, obj_threshold, nms_threshold)
+}
+
+/// 固定小数点数の小数部のビット数
+const FRAC_BITS: u32 = 8;
+
+/// NMSの閾値の小数部のビット数
+const IOU_FRAC_BITS: u32 = 16;
+
+/// `exp_fix` が表で求める指数の整数部の範囲。小さい方は0、大きい方は入力の外にはみ出すものとして扱います
+const EXP_INT_RANGE: std::ops::RangeInclusive<i32> = -16..=10;
+
+/// 固定小数点数 (符号あり[24bits].[8bits]) のまま計算したバウンディングボックス
+#[derive(Debug, Clone, Copy)]
+struct FixedBox {
+    class: u8,
+    x1: i32,
+    y1: i32,
+    x2: i32,
+    y2: i32,
+    confidence: i16,
+}
+
+impl FixedBox {
+    fn area(&self) -> i64 {
+        i64::from(self.x2 - self.x1) * i64::from(self.y2 - self.y1)
+    }
+
+    /// IoUが閾値未満かを判定します。
+    ///
+    /// # Args
+    /// * `other` - 比較するバウンディングボックス
+    /// * `threshold` - NMSの閾値 (小数部 `IOU_FRAC_BITS` ビットの固定小数点数)
+    fn iou_below(&self, other: &Self, threshold: i64) -> bool {
+        let dx = self.x2.min(other.x2) - self.x1.max(other.x1);
+        let dy = self.y2.min(other.y2) - self.y1.max(other.y1);
+        let inter = i64::from(dx.max(0)) * i64::from(dy.max(0));
+        let union = self.area() + other.area() - inter;
+        // 面積のないバウンディングボックス同士は `post_process` と同じく抑制する
+        (inter << IOU_FRAC_BITS) < threshold * union
+    }
+
+    fn to_detection(self) -> DetectionData<LetterboxSpace> {
+        DetectionData::new(
+            self.class,
+            fix2float_i32(self.x1),
+            fix2float_i32(self.y1),
+            fix2float_i32(self.x2),
+            fix2float_i32(self.y2),
+            fix2float(self.confidence),
+        )
+    }
+}
+
+fn fix2float_i32(input: i32) -> f32 {
+    input as f32 / (1 << FRAC_BITS) as f32
+}
+
+/// 指数関数の表 (小数部 16 ビットの固定小数点数)
+struct ExpTable {
+    /// e^k (kは `EXP_INT_RANGE` の整数)
+    int: Vec<i64>,
+    /// e^(f/256) (fは0-255)
+    frac: Vec<i64>,
+}
+
+fn exp_table() -> &'static ExpTable {
+    static TABLE: std::sync::OnceLock<ExpTable> = std::sync::OnceLock::new();
+    TABLE.get_or_init(|| {
+        let q16 = |v: f64| (v * 65536.).round() as i64;
+        ExpTable {
+            int: EXP_INT_RANGE.map(|k| q16(f64::from(k).exp())).collect(),
+            frac: (0..1 << FRAC_BITS)
+                .map(|f| q16((f64::from(f) / 256.).exp()))
+                .collect(),
+        }
+    })
+}
+
+/// `sigmoid_fix` が表で求める入力の範囲 (固定小数点数)。外側では0または1に飽和します
+const SIGMOID_RANGE: std::ops::Range<i32> = -(8 << FRAC_BITS)..8 << FRAC_BITS;
+
+/// 固定小数点数 (符号あり[8bits].[8bits]) のシグモイドを表で求めます。
+fn sigmoid_fix(v: i16) -> i16 {
+    static TABLE: std::sync::OnceLock<Vec<i16>> = std::sync::OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        SIGMOID_RANGE
+            .map(|q| (sigmoid(fix2float(q as i16)) * (1 << FRAC_BITS) as f32).round() as i16)
+            .collect()
+    });
+    match i32::from(v) {
+        q if q < SIGMOID_RANGE.start => 0,
+        q if q >= SIGMOID_RANGE.end => 1 << FRAC_BITS,
+        q => table[(q - SIGMOID_RANGE.start) as usize],
+    }
+}
+
+/// 固定小数点数 `v` (符号あり[8bits].[8bits]) に対して `scale * e^v` を求めます。
+///
+/// # Args
+/// * `scale` - 固定小数点数 (小数部8ビット)
+/// * `v` - 指数
+///
+/// # Return
+/// * 固定小数点数 (小数部8ビット)。`EXP_INT_RANGE` より大きい場合はNone
+fn exp_fix(scale: i64, v: i16) -> Option<i64> {
+    let table = exp_table();
+    let k = i32::from(v) >> FRAC_BITS;
+    let f = (i32::from(v) & ((1 << FRAC_BITS) - 1)) as usize;
+    if k > *EXP_INT_RANGE.end() {
+        return None;
+    }
+    if k < *EXP_INT_RANGE.start() {
+        return Some(0);
+    }
+    let e_int = table.int[(k - EXP_INT_RANGE.start()) as usize];
+    Some((((scale * e_int) >> 16) * table.frac[f]) >> 16)
+}
+
+/// 1つのヘッドの出力を固定小数点数のままデコードし、物体の閾値を超えるバウンディングボックスを集めます。
+///
+/// # Args
+/// * `out` - YOLO層の出力 (分割数 * グリッド数 * グリッド数 * 32)
+/// * `grid_num` - グリッドの数
+/// * `anchors` - ヘッドのアンカーボックス
+/// * `cls_num` - クラスの数
+/// * `layout` - 出力の並びとアンカーボックス
+/// * `obj_threshold` - 物体の閾値 (小数部8ビットの固定小数点数。この値より大きいものを残します)
+/// * `boxes` - デコードしたバウンディングボックスの追加先
+fn decode_fixed(
+    out: &[i16],
+    grid_num: usize,
+    anchors: [[f32; 2]; ANCHOR_BOX_NUM],
+    cls_num: usize,
+    layout: &OutputLayout,
+    obj_threshold: i32,
+    boxes: &mut Vec<FixedBox>,
+) {
+    let cells = grid_num * grid_num;
+    // `ch_reorder` を行わず、分割された出力から直接読み出す
+    let raw = |cell: usize, ch: usize| {
+        out[cells * FOLD_CH * (ch / FOLD_CH) + FOLD_CH * cell + ch % FOLD_CH]
+    };
+    // 幅と高さ以外のチャネル
+    let at = |cell: usize, ch: usize| {
+        if layout.software_sigmoid {
+            sigmoid_fix(raw(cell, ch))
+        } else {
+            raw(cell, ch)
+        }
+    };
+    let stride = 5 + layout.class_slots;
+    let grid_width = (416 / grid_num) as i32;
+    let anchors = anchors.map(|a| a.map(|v| (v * (1 << FRAC_BITS) as f32).round() as i64));
+    let size: i32 = 416 << FRAC_BITS;
+
+    for cell in 0..cells {
+        let (col, row) = ((cell % grid_num) as i32, (cell / grid_num) as i32);
+        for (j, [aw, ah]) in anchors.iter().enumerate() {
+            let base = stride * j;
+            let confidence = at(cell, base + 4);
+            if i32::from(confidence) <= obj_threshold || i32::from(confidence) > 1 << FRAC_BITS {
+                continue;
+            }
+            let (Some(w), Some(h)) = (
+                exp_fix(*aw, raw(cell, base + 2)),
+                exp_fix(*ah, raw(cell, base + 3)),
+            ) else {
+                continue;
+            };
+            if w > i64::from(size) || h > i64::from(size) {
+                continue;
+            }
+            let (w, h) = (w as i32, h as i32);
+            //rm-sigmoid
+            let cx = grid_width * ((col << FRAC_BITS) + i32::from(at(cell, base)));
+            let cy = grid_width * ((row << FRAC_BITS) + i32::from(at(cell, base + 1)));
+            let (x1, y1) = (cx - w / 2, cy - h / 2);
+            let (x2, y2) = (x1 + w, y1 + h);
+            if x1 < 0 || y1 < 0 || x2 > size || y2 > size {
+                continue;
+            }
+
+            // `get_cls_id` と同じく、最大のスコアが複数ある場合は後ろのクラスを選ぶ
+            let class = (0..cls_num)
+                .max_by_key(|&k| at(cell, base + 5 + k))
+                .unwrap_or(0) as u8;
+            boxes.push(FixedBox {
+                class,
+                x1,
+                y1,
+                x2,
+                y2,
+                confidence,
+            });
+        }
+    }
+}
+
+/// `post_process_fixed`関数は、YOLOの出力を固定小数点数のまま後処理して物体検出を行います
+///
+/// アンカーボックスのデコードとNMSのIoUの比較を整数で行い、浮動小数点数への変換は残った検出結果にだけ行います。
+/// FPUの遅いZynq-7000では、全ての出力を `fix2float` で変換する `post_process` より高速です。
+/// アンカーボックスを1/256ピクセル単位に丸め、指数関数を表で近似するため、
+/// 座標は `post_process` と1/100ピクセル程度異なり、入力の端やNMSの閾値に近い検出結果は残るかどうかが変わることがあります。
+///
+/// # Args
+/// * `yolo_out_0` - YOLOの出力
+/// * `yolo_out_1` - YOLOの別の出力
+/// * `cls_num` - クラスの数
+/// * `layout` - 出力の並びとアンカーボックス (通常は `OutputLayout::default()`)
+/// * `obj_threshold` - 物体検出の閾値
+/// * `nms_threshold` - 非最大抑制（NMS）の閾値
+///
+/// # Return
+/// * 検出された物体を表すDetectionDataのベクトル (`post_process` と同じ順序)
+pub fn post_process_fixed(
+    yolo_out_0: &[i16],
+    yolo_out_1: &[i16],
+    cls_num: usize,
+    layout: &OutputLayout,
+    obj_threshold: f32,
+    nms_threshold: f32,
+) -> Vec<DetectionData<LetterboxSpace>> {
+    post_process_fixed_with_stats(
+        yolo_out_0,
+        yolo_out_1,
+        cls_num,
+        layout,
+        obj_threshold,
+        nms_threshold,
+    )
+    .0
+}
+
+/// `post_process_fixed_with_stats`関数は、`post_process_fixed` と同じ物体検出を行い、後処理の統計を返します
+///
+/// # Args
+/// * `yolo_out_0` - YOLOの出力
+/// * `yolo_out_1` - YOLOの別の出力
+/// * `cls_num` - クラスの数
+/// * `layout` - 出力の並びとアンカーボックス (通常は `OutputLayout::default()`)
+/// * `obj_threshold` - 物体検出の閾値
+/// * `nms_threshold` - 非最大抑制（NMS）の閾値
+///
+/// # Return
+/// * 検出された物体を表すDetectionDataのベクトルと後処理の統計
+pub fn post_process_fixed_with_stats(
+    yolo_out_0: &[i16],
+    yolo_out_1: &[i16],
+    cls_num: usize,
+    layout: &OutputLayout,
+    obj_threshold: f32,
+    nms_threshold: f32,
+) -> (Vec<DetectionData<LetterboxSpace>>, PostprocessStats) {
+    let start = Instant::now();
+    // q > t * 256 と q > floor(t * 256) は整数qに対して等しい
+    let obj_threshold = (obj_threshold * (1 << FRAC_BITS) as f32).floor() as i32;
+    let nms_threshold = (nms_threshold * (1 << IOU_FRAC_BITS) as f32).round() as i64;
+
+    let mut boxes = vec![];
+    for (out, grid_num, anchors) in [
+        (yolo_out_0, 13, layout.anchors13()),
+        (yolo_out_1, 26, layout.anchors26()),
+    ] {
+        decode_fixed(
+            out,
+            grid_num,
+            anchors,
+            cls_num,
+            layout,
+            obj_threshold,
+            &mut boxes,
+        );
+    }
+
+    let decode_time = start.elapsed();
+    let candidates: Vec<u8> = boxes.iter().map(|b| b.class).collect();
+
+    // クラス別に分割
+    let start = Instant::now();
+    let mut cls: Vec<Vec<FixedBox>> = vec![vec![]; cls_num];
+    for b in boxes {
+        cls[b.class as usize].push(b);
+    }
+
+    // 各クラスにNMSを適用する (`nms::nms` と同じく、採用済みのものとのIoUが閾値未満のものだけを残す)
+    let detections: Vec<DetectionData<LetterboxSpace>> = cls
+        .into_iter()
+        .flat_map(|mut d| {
+            d.sort_by_key(|b| std::cmp::Reverse(b.confidence));
+            let mut keep: Vec<FixedBox> = vec![];
+            for b in d {
+                if keep.iter().all(|k| k.iou_below(&b, nms_threshold)) {
+                    keep.push(b);
+                }
+            }
+            keep
+        })
+        .map(FixedBox::to_detection)
+        .collect();
+    let nms_time = start.elapsed();
+
+    let stats = PostprocessStats::new(
+        candidates.into_iter(),
+        detections.iter().map(|d| d.class),
+        cls_num,
+        decode_time,
+        nms_time,
+    );
+    (detections, stats)
+}
@@ -0,0 +1,495 @@
+//! YOLOの入力データを生成する前処理に関するモジュール
+
+use std::borrow::Cow;
+
+use anyhow::{ensure, Result};
+use image::DynamicImage;
+
+use crate::detection_result::{DetectionData, LetterboxSpace};
+use crate::img_proc::{self, CropRect};
+
+/// 画像からYOLOの入力データを生成し、検出結果を元の画像の座標系に戻すためのトレイト
+pub trait Preprocessor {
+    /// 画像からYOLOの入力データを生成します。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    /// * `size` - YOLOの入力サイズ
+    ///
+    /// # Return
+    /// * YOLOの入力データ
+    fn prepare(&self, img: &DynamicImage, size: u32) -> Vec<i16>;
+
+    /// YOLOの出力した検出結果の座標を元の画像の座標系に戻します。
+    ///
+    /// # Args
+    /// * `d` - YOLOの出力した検出結果
+    /// * `width` - 元の画像の幅
+    /// * `height` - 元の画像の高さ
+    /// * `size` - `prepare` に渡したYOLOの入力サイズ
+    ///
+    /// # Return
+    /// * 元の画像の座標系に変換した検出結果
+    fn inverse_transform(
+        &self,
+        d: &DetectionData<LetterboxSpace>,
+        width: u32,
+        height: u32,
+        size: u32,
+    ) -> DetectionData;
+
+    /// `inverse_transform` で戻した座標系に対応する画像を返します。検出結果の切り抜きに使います。
+    ///
+    /// 既定では入力画像をそのまま返します。
+    ///
+    /// # Args
+    /// * `img` - `prepare` に渡した画像
+    fn output_image<'a>(&self, img: &'a DynamicImage) -> Cow<'a, DynamicImage> {
+        Cow::Borrowed(img)
+    }
+}
+
+/// 画像を回転させます。回転させない場合は画像をそのまま返します。
+fn rotated(img: &DynamicImage, rotate_angle: u32, rotate_en: bool) -> Cow<'_, DynamicImage> {
+    match rotate_angle {
+        90 | 180 | 270 if rotate_en => Cow::Owned(img_proc::rotate_img(img, rotate_angle)),
+        _ => Cow::Borrowed(img),
+    }
+}
+
+/// 画像をリサイズ・回転し、上下左右に均等なパディングを入れる前処理
+#[derive(Debug, Clone, Copy)]
+pub struct Letterbox {
+    /// 回転角度
+    pub rotate_angle: u32,
+}
+
+impl Letterbox {
+    /// 新しい `Letterbox` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `rotate_angle` - 回転角度
+    pub fn new(rotate_angle: u32) -> Self {
+        Self { rotate_angle }
+    }
+}
+
+impl Preprocessor for Letterbox {
+    fn prepare(&self, img: &DynamicImage, size: u32) -> Vec<i16> {
+        img_proc::letterbox(img, size, self.rotate_angle)
+    }
+
+    fn inverse_transform(
+        &self,
+        d: &DetectionData<LetterboxSpace>,
+        width: u32,
+        height: u32,
+        size: u32,
+    ) -> DetectionData {
+        d.reverse_transform(width, height, self.rotate_angle, false, size)
+    }
+
+    fn output_image<'a>(&self, img: &'a DynamicImage) -> Cow<'a, DynamicImage> {
+        rotated(img, self.rotate_angle, true)
+    }
+}
+
+/// 画像の一部を拡大し、余白に配置する前処理
+///
+/// 拡大した領域内の検出結果も含め、元の画像 (回転後) の座標系に戻します。
+#[derive(Debug, Clone, Copy)]
+pub struct PatialEnlargement {
+    /// 回転角度
+    pub rotate_angle: u32,
+    /// 画像を回転させるか。事前に回転させている場合はfalse
+    pub rotate_en: bool,
+    /// 切り取り位置のx座標 (Noneのときは画像中央)
+    pub crop_x: Option<u32>,
+    /// 切り取り位置のy座標 (Noneのときは画像中央)
+    pub crop_y: Option<u32>,
+    /// 切り取り幅
+    pub crop_w: u32,
+    /// 切り取り高さ
+    pub crop_h: u32,
+}
+
+impl PatialEnlargement {
+    /// 新しい `PatialEnlargement` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `rotate_angle` - 回転角度
+    /// * `rotate_en` - 画像を回転させるか。事前に回転させている場合はfalseを指定してください
+    /// * `crop_x` - 切り取り位置のx座標 (Noneを指定すると画像中央になります)
+    /// * `crop_y` - 切り取り位置のy座標 (Noneを指定すると画像中央になります)
+    /// * `crop_w` - 切り取り幅
+    /// * `crop_h` - 切り取り高さ
+    pub fn new(
+        rotate_angle: u32,
+        rotate_en: bool,
+        crop_x: Option<u32>,
+        crop_y: Option<u32>,
+        crop_w: u32,
+        crop_h: u32,
+    ) -> Self {
+        Self {
+            rotate_angle,
+            rotate_en,
+            crop_x,
+            crop_y,
+            crop_w,
+            crop_h,
+        }
+    }
+}
+
+impl PatialEnlargement {
+    /// 切り取る領域を回転後の画像の座標系で返します。
+    ///
+    /// # Args
+    /// * `width` - 入力画像の幅
+    /// * `height` - 入力画像の高さ
+    pub fn crop_rect(&self, width: u32, height: u32) -> CropRect {
+        let (width, height) = self.rotated_size(width, height);
+        CropRect::new(
+            self.crop_x.unwrap_or(width.saturating_sub(self.crop_w) / 2),
+            self.crop_y
+                .unwrap_or(height.saturating_sub(self.crop_h) / 2),
+            self.crop_w,
+            self.crop_h,
+        )
+    }
+
+    /// 回転後の画像のサイズを返します。
+    fn rotated_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match self.rotate_angle {
+            90 | 270 if self.rotate_en => (height, width),
+            _ => (width, height),
+        }
+    }
+}
+
+impl Preprocessor for PatialEnlargement {
+    fn prepare(&self, img: &DynamicImage, size: u32) -> Vec<i16> {
+        img_proc::letterbox_with_patial_enlargement(
+            img,
+            size,
+            self.rotate_angle,
+            self.rotate_en,
+            self.crop_x,
+            self.crop_y,
+            self.crop_w,
+            self.crop_h,
+        )
+    }
+
+    fn inverse_transform(
+        &self,
+        d: &DetectionData<LetterboxSpace>,
+        width: u32,
+        height: u32,
+        size: u32,
+    ) -> DetectionData {
+        let crop = self.crop_rect(width, height);
+        let (width, height) = self.rotated_size(width, height);
+        d.reverse_transform_with_crops(width, height, &[crop], size)
+    }
+
+    fn output_image<'a>(&self, img: &'a DynamicImage) -> Cow<'a, DynamicImage> {
+        rotated(img, self.rotate_angle, self.rotate_en)
+    }
+}
+
+/// 画像の複数の領域を拡大し、余白に並べて配置する前処理
+///
+/// 拡大した領域内の検出結果も含め、元の画像 (回転後) の座標系に戻します。
+#[derive(Debug, Clone)]
+pub struct MultiPatialEnlargement {
+    /// 回転角度
+    pub rotate_angle: u32,
+    /// 画像を回転させるか。事前に回転させている場合はfalse
+    pub rotate_en: bool,
+    /// 切り取る領域 (回転後の画像の座標系)
+    pub crops: Vec<CropRect>,
+}
+
+impl MultiPatialEnlargement {
+    /// 新しい `MultiPatialEnlargement` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `rotate_angle` - 回転角度
+    /// * `rotate_en` - 画像を回転させるか。事前に回転させている場合はfalseを指定してください
+    /// * `crops` - 切り取る領域 (回転後の画像の座標系)
+    pub fn new(rotate_angle: u32, rotate_en: bool, crops: Vec<CropRect>) -> Self {
+        Self {
+            rotate_angle,
+            rotate_en,
+            crops,
+        }
+    }
+}
+
+impl Preprocessor for MultiPatialEnlargement {
+    fn prepare(&self, img: &DynamicImage, size: u32) -> Vec<i16> {
+        img_proc::letterbox_with_patial_enlargements(
+            img,
+            size,
+            self.rotate_angle,
+            self.rotate_en,
+            &self.crops,
+        )
+    }
+
+    fn inverse_transform(
+        &self,
+        d: &DetectionData<LetterboxSpace>,
+        width: u32,
+        height: u32,
+        size: u32,
+    ) -> DetectionData {
+        let (width, height) = match self.rotate_angle {
+            90 | 270 if self.rotate_en => (height, width),
+            _ => (width, height),
+        };
+        d.reverse_transform_with_crops(width, height, &self.crops, size)
+    }
+
+    fn output_image<'a>(&self, img: &'a DynamicImage) -> Cow<'a, DynamicImage> {
+        rotated(img, self.rotate_angle, self.rotate_en)
+    }
+}
+
+/// 最大4台のカメラの画像を縮小し、2×2のモザイクに並べる前処理
+///
+/// 1回の推論で複数の視点を監視できます。各カメラの画像の解像度は1/2以下になります。
+/// 検出結果は `demultiplex` でカメラごとの画像の座標系に戻します。
+#[derive(Debug, Clone, Default)]
+pub struct Mosaic {
+    /// カメラごとの画像の幅と高さ (タイルの順)
+    sizes: Vec<(u32, u32)>,
+}
+
+impl Mosaic {
+    /// 新しい `Mosaic` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `sizes` - カメラごとの画像の幅と高さ。タイルは左上から行優先で割り当てます
+    ///
+    /// # Return
+    /// * カメラが `img_proc::MOSAIC_TILES` 台を超える場合や、サイズが0の場合はエラー
+    pub fn new(sizes: Vec<(u32, u32)>) -> Result<Self> {
+        ensure!(
+            sizes.len() <= img_proc::MOSAIC_TILES,
+            "{} cameras are given, but a mosaic has {} tiles",
+            sizes.len(),
+            img_proc::MOSAIC_TILES
+        );
+        ensure!(
+            sizes.iter().all(|&(w, h)| w > 0 && h > 0),
+            "Image sizes must be positive: {:?}",
+            sizes
+        );
+        Ok(Self { sizes })
+    }
+
+    /// 画像からモザイクを作成します。画像の幅と高さから `Mosaic::new` を呼び出します。
+    pub fn from_images(imgs: &[&DynamicImage]) -> Result<Self> {
+        Self::new(imgs.iter().map(|img| (img.width(), img.height())).collect())
+    }
+
+    /// カメラの台数を返します。
+    pub fn cameras(&self) -> usize {
+        self.sizes.len()
+    }
+
+    /// 画像からYOLOの入力データを生成します。
+    ///
+    /// # Args
+    /// * `imgs` - カメラごとの画像 (`new` に渡したサイズと同じ順)
+    /// * `size` - YOLOの入力サイズ
+    ///
+    /// # Return
+    /// * YOLOの入力データ。画像の枚数やサイズが `new` に渡したものと異なる場合はエラー
+    pub fn prepare(&self, imgs: &[&DynamicImage], size: u32) -> Result<Vec<i16>> {
+        let sizes: Vec<(u32, u32)> = imgs.iter().map(|img| (img.width(), img.height())).collect();
+        ensure!(
+            sizes == self.sizes,
+            "Image sizes {:?} do not match the mosaic {:?}",
+            sizes,
+            self.sizes
+        );
+        Ok(img_proc::mosaic(imgs, size))
+    }
+
+    /// モザイク上の検出結果を、どのカメラの検出結果かを求めてそのカメラの画像の座標系に戻します。
+    ///
+    /// バウンディングボックスの中心があるタイルのカメラに割り当て、隣のタイルにはみ出した部分は切り取ります。
+    ///
+    /// # Args
+    /// * `d` - YOLOの出力した検出結果
+    /// * `size` - YOLOの入力サイズ
+    ///
+    /// # Return
+    /// * カメラのインデックスと、そのカメラの画像の座標系の検出結果。
+    ///   中心が画像を配置していない余白やカメラのないタイルにある場合はNone
+    pub fn demultiplex(
+        &self,
+        d: &DetectionData<LetterboxSpace>,
+        size: u32,
+    ) -> Option<(usize, DetectionData)> {
+        let (cx, cy) = d.center();
+        let (i, &(width, height)) = self
+            .sizes
+            .iter()
+            .enumerate()
+            .find(|&(i, _)| img_proc::mosaic_tile(size, i).contains(cx, cy))?;
+        let content = img_proc::mosaic_content(size, i, width, height);
+        if !content.contains(cx, cy) {
+            return None;
+        }
+
+        // 前処理と同じく四捨五入した縮小後のサイズから倍率を求める
+        let rx = content.w as f32 / width as f32;
+        let ry = content.h as f32 / height as f32;
+        let (x0, y0) = (content.x as f32, content.y as f32);
+        let local = DetectionData::<LetterboxSpace>::new(
+            d.class,
+            d.x1 - x0,
+            d.y1 - y0,
+            d.x2 - x0,
+            d.y2 - y0,
+            d.confidence,
+        )
+        .with_sub_state(d.sub_state)
+        .clamp_to(content.w, content.h);
+        Some((
+            i,
+            DetectionData::new(
+                d.class,
+                local.x1 / rx,
+                local.y1 / ry,
+                local.x2 / rx,
+                local.y2 / ry,
+                d.confidence,
+            )
+            .with_sub_state(d.sub_state),
+        ))
+    }
+}
+
+/// 前フレームの検出結果から、部分拡大する領域を自動的に決定する構造体
+///
+/// 小さく検出された物体 (遠くの信号機など) を中心に切り取り位置を設定し、
+/// 次のフレームで高い解像度で再検出できるようにします。
+/// 座標は `letterbox_img_with_patial_enlargement` と同じく回転後の画像の座標系で扱います。
+#[derive(Debug, Clone)]
+pub struct AutoZoom {
+    crop_w: u32,
+    crop_h: u32,
+    max_box_size: f32,
+    max_missed: usize,
+    center: Option<(f32, f32)>,
+    crop: Option<(u32, u32)>,
+    missed: usize,
+}
+
+impl AutoZoom {
+    /// 新しい `AutoZoom` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `crop_w` - 切り取り幅
+    /// * `crop_h` - 切り取り高さ
+    /// * `max_box_size` - 拡大の対象とするバウンディングボックスの最大の辺の長さ (元の画像のピクセル数)
+    pub fn new(crop_w: u32, crop_h: u32, max_box_size: f32) -> Self {
+        Self {
+            crop_w,
+            crop_h,
+            max_box_size,
+            max_missed: 5,
+            center: None,
+            crop: None,
+            missed: 0,
+        }
+    }
+
+    /// 対象が検出されなくなってから切り取り位置を初期位置 (画像中央) に戻すまでのフレーム数を設定します。
+    pub fn set_max_missed(&mut self, max_missed: usize) -> &mut Self {
+        self.max_missed = max_missed;
+        self
+    }
+
+    /// 追跡中の対象を削除し、切り取り位置を初期位置に戻します。
+    pub fn reset(&mut self) {
+        self.center = None;
+        self.crop = None;
+        self.missed = 0;
+    }
+
+    /// 現在の切り取り位置 (x, y) を返します。Noneのときは画像中央です。
+    pub fn crop_pos(&self) -> Option<(u32, u32)> {
+        self.crop
+    }
+
+    /// 現在の切り取り位置で部分拡大を行う前処理を作成します。
+    ///
+    /// # Args
+    /// * `rotate_angle` - 回転角度
+    /// * `rotate_en` - 画像を回転させるか。事前に回転させている場合はfalseを指定してください
+    pub fn preprocessor(&self, rotate_angle: u32, rotate_en: bool) -> PatialEnlargement {
+        PatialEnlargement::new(
+            rotate_angle,
+            rotate_en,
+            self.crop.map(|c| c.0),
+            self.crop.map(|c| c.1),
+            self.crop_w,
+            self.crop_h,
+        )
+    }
+
+    /// 1フレーム分の検出結果から次のフレームの切り取り位置を更新します。
+    ///
+    /// 対象が既にある場合は最も近い小さな物体を、ない場合は最も小さな物体を追跡します。
+    ///
+    /// # Args
+    /// * `detections` - 現在の切り取り位置で部分拡大を行ったときの検出結果 (回転後の画像の座標系)
+    /// * `width` - 回転後の画像の幅
+    /// * `height` - 回転後の画像の高さ
+    pub fn update(&mut self, detections: &[DetectionData], width: u32, height: u32) {
+        let candidates: Vec<&DetectionData> = detections
+            .iter()
+            .filter(|d| f32::max(d.width(), d.height()) <= self.max_box_size)
+            .collect();
+
+        let target = match self.center {
+            Some((cx, cy)) => candidates.iter().min_by(|a, b| {
+                let dist = |d: &DetectionData| {
+                    let (x, y) = d.center();
+                    (x - cx).powi(2) + (y - cy).powi(2)
+                };
+                dist(a).total_cmp(&dist(b))
+            }),
+            None => candidates
+                .iter()
+                .min_by(|a, b| a.area().total_cmp(&b.area())),
+        };
+
+        match target {
+            Some(d) => {
+                let (cx, cy) = d.center();
+                self.center = Some((cx, cy));
+                self.missed = 0;
+
+                let max_x = width.saturating_sub(self.crop_w) as f32;
+                let max_y = height.saturating_sub(self.crop_h) as f32;
+                let x = (cx - self.crop_w as f32 / 2.).clamp(0., max_x);
+                let y = (cy - self.crop_h as f32 / 2.).clamp(0., max_y);
+                self.crop = Some((x as u32, y as u32));
+            }
+            None => {
+                self.missed += 1;
+                if self.missed > self.max_missed {
+                    self.reset();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! 浮動小数点数の重みをIPで使うQ8.8の固定小数点数に量子化するモジュール
+//!
+//! Q8.8で表せる値は ±128 までのため、出力の大きいレイヤーグループでは値が飽和します。
+//! レイヤーグループごとに出力を2のべき乗倍 (スケール) し、次のレイヤーグループの重みで打ち消すことで、
+//! 検出結果を変えずに飽和を抑えます。Leaky ReLU・最大プーリング・アップサンプルは正の定数倍と可換なため、
+//! このスケールは検出結果に影響しません。YOLOの出力のレイヤーグループは後処理がそのまま値を使うため、常に等倍です。
+//!
+//! スケールはキャリブレーション画像を実際にIPで推論し、出力の飽和率を測って決定します。
+
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use image::DynamicImage;
+use log::info;
+use tar::{Builder, Header};
+
+use crate::layer_group::{LayerGroup, PostProcess};
+use crate::npy;
+use crate::postprocess::fix2float;
+use crate::preprocess::{Letterbox, Preprocessor};
+use crate::yolov3_tiny::{layer_sources, YoloV3Tiny};
+
+/// 重みの量子化に必要なレイヤーグループの構成
+#[derive(Debug, Clone, Copy)]
+struct LayerShape {
+    /// 1組の入力・出力チャネルあたりの重みの数
+    weight_block: usize,
+    /// 出力の分割数
+    output_fold: usize,
+    /// 畳み込みを行わないか
+    conv_disable: bool,
+    /// スケールを変えられないか (YOLOの出力)
+    fixed: bool,
+}
+
+/// レイヤーグループごとの量子化の結果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayerQuantReport {
+    /// レイヤーグループのインデックス
+    pub grp_idx: usize,
+    /// 出力のスケールの指数 (出力は `2^scale_exp` 倍される)
+    pub scale_exp: i32,
+    /// 量子化で飽和した重みの割合
+    pub weight_clip_rate: f32,
+    /// 量子化で飽和したバイアスの割合
+    pub bias_clip_rate: f32,
+    /// キャリブレーション画像で出力が飽和した割合 (推定されるクリッピング率)
+    pub output_clip_rate: f32,
+    /// キャリブレーション画像での出力の絶対値の最大値 (スケール後)
+    pub max_abs_output: f32,
+}
+
+/// 量子化の結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantReport {
+    /// レイヤーグループごとの結果
+    pub layers: Vec<LayerQuantReport>,
+    /// キャリブレーションの推論を繰り返した回数
+    pub iterations: usize,
+}
+
+impl QuantReport {
+    /// 結果をinfoレベルでログに出力します。
+    pub fn log(&self) {
+        for line in self.to_string().lines() {
+            info!("{}", line);
+        }
+    }
+}
+
+impl fmt::Display for QuantReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "calibrated in {} iteration(s)", self.iterations)?;
+        for l in &self.layers {
+            writeln!(
+                f,
+                "layer group {:2}: scale=2^{} weight_clip={:.4}% bias_clip={:.4}% output_clip={:.4}% max={:.2}",
+                l.grp_idx,
+                l.scale_exp,
+                l.weight_clip_rate * 100.,
+                l.bias_clip_rate * 100.,
+                l.output_clip_rate * 100.,
+                l.max_abs_output
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// 量子化したレイヤーグループの重みとバイアス、飽和した数
+struct QuantizedLayer {
+    weights: Option<Vec<i16>>,
+    biases: Option<Vec<i16>>,
+    weight_clipped: usize,
+    bias_clipped: usize,
+}
+
+/// キャリブレーションで測ったレイヤーグループの出力の統計
+#[derive(Debug, Clone, Copy, Default)]
+struct OutputStats {
+    clipped: usize,
+    total: usize,
+    max_abs: i16,
+}
+
+impl OutputStats {
+    fn clip_rate(&self) -> f32 {
+        self.clipped as f32 / self.total.max(1) as f32
+    }
+}
+
+/// 浮動小数点数の重みとバイアスをQ8.8に量子化する構造体
+pub struct Quantizer {
+    shapes: Vec<LayerShape>,
+    weights: Vec<Option<Vec<f32>>>,
+    biases: Vec<Option<Vec<f32>>>,
+    exponents: Vec<i32>,
+    target_clip_rate: f32,
+    min_exp: i32,
+    max_exp: i32,
+    max_iterations: usize,
+}
+
+impl Quantizer {
+    /// 新しい `Quantizer` インスタンスを作成します。
+    ///
+    /// 重みとバイアスは、IPに転送する順 (重みのアーカイブと同じ並び) の浮動小数点数で渡してください。
+    ///
+    /// # Args
+    /// * `layers` - レイヤーグループの構成 (`YoloV3Tiny::layer_groups`)
+    /// * `weights` - レイヤーグループごとの重み。畳み込みを行わないレイヤーグループはNone
+    /// * `biases` - レイヤーグループごとのバイアス
+    ///
+    /// # Return
+    /// * 新たな `Quantizer` インスタンス。重みやバイアスの長さが構成に合わない場合はエラー
+    pub fn new(
+        layers: &[LayerGroup],
+        weights: Vec<Option<Vec<f32>>>,
+        biases: Vec<Option<Vec<f32>>>,
+    ) -> Result<Self> {
+        ensure!(
+            weights.len() == layers.len() && biases.len() == layers.len(),
+            "Expected weights and biases for {} layer groups",
+            layers.len()
+        );
+        let shapes: Vec<LayerShape> = layers
+            .iter()
+            .map(|l| LayerShape {
+                weight_block: 12 * (l.input_ch * l.output_ch) as usize,
+                output_fold: l.output_fold_factor as usize,
+                conv_disable: l.conv_disable,
+                fixed: l.post_process_type == PostProcess::Yolo,
+            })
+            .collect();
+        for (i, l) in layers.iter().enumerate() {
+            if let Some(w) = &weights[i] {
+                let len = l.weights_len();
+                ensure!(
+                    w.len() == len,
+                    "weights{} has {} values, expected {}",
+                    i,
+                    w.len(),
+                    len
+                );
+            }
+            if let Some(b) = &biases[i] {
+                let len = l.biases_len();
+                ensure!(
+                    b.len() == len,
+                    "biases{} has {} values, expected {}",
+                    i,
+                    b.len(),
+                    len
+                );
+            }
+        }
+
+        Ok(Self {
+            exponents: vec![0; shapes.len()],
+            shapes,
+            weights,
+            biases,
+            target_clip_rate: 1e-4,
+            min_exp: -6,
+            max_exp: 4,
+            max_iterations: 8,
+        })
+    }
+
+    /// `.npz` ファイルから浮動小数点数の重みとバイアスを読み込みます。
+    ///
+    /// 配列の名前は `weights{n}` と `biases{n}` で、型は `<f4` である必要があります。
+    ///
+    /// # Args
+    /// * `layers` - レイヤーグループの構成 (`YoloV3Tiny::layer_groups`)
+    /// * `path` - 読み込むファイルのパス
+    pub fn from_npz<P: AsRef<Path>>(layers: &[LayerGroup], path: P) -> Result<Self> {
+        let mut weights = vec![None; layers.len()];
+        let mut biases = vec![None; layers.len()];
+        for (name, array) in npy::load_npz::<_, f32>(path)? {
+            let (dst, gnum) = if let Some(n) = name.strip_prefix("weights") {
+                (&mut weights, n)
+            } else if let Some(n) = name.strip_prefix("biases") {
+                (&mut biases, n)
+            } else {
+                bail!("{} is not biases or weights array", name);
+            };
+            let gnum: usize = gnum
+                .parse()
+                .with_context(|| format!("Invalid array name: {}", name))?;
+            *dst.get_mut(gnum)
+                .with_context(|| format!("Layer group {} of {} does not exist", gnum, name))? =
+                Some(array.data);
+        }
+        Self::new(layers, weights, biases)
+    }
+
+    /// 許容する出力の飽和率を設定します (既定: 0.01%)。
+    pub fn set_target_clip_rate(&mut self, rate: f32) -> &mut Self {
+        self.target_clip_rate = rate;
+        self
+    }
+
+    /// スケールの指数の範囲を設定します (既定: -6..=4)。
+    pub fn set_exponent_range(&mut self, min_exp: i32, max_exp: i32) -> &mut Self {
+        self.min_exp = min_exp.min(0);
+        self.max_exp = max_exp.max(0);
+        self
+    }
+
+    /// キャリブレーションの推論を繰り返す最大の回数を設定します。
+    pub fn set_max_iterations(&mut self, max_iterations: usize) -> &mut Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    /// レイヤーグループごとの出力のスケールの指数を返します。
+    pub fn scale_exponents(&self) -> &[i32] {
+        &self.exponents
+    }
+
+    /// キャリブレーション画像を推論して、レイヤーグループごとのスケールを決定します。
+    ///
+    /// 推論の間は `yolo` の重みを一時的に置き換え、終了後に元に戻します。
+    ///
+    /// # Args
+    /// * `yolo` - 推論に使う `YoloV3Tiny`
+    /// * `images` - キャリブレーション画像
+    ///
+    /// # Return
+    /// * 最終的なスケールでの、レイヤーグループごとの飽和率
+    pub fn calibrate(
+        &mut self,
+        yolo: &mut YoloV3Tiny,
+        images: &[DynamicImage],
+    ) -> Result<QuantReport> {
+        if images.is_empty() {
+            bail!("No calibration images");
+        }
+        let size = yolo.layer_groups()[0].input_width;
+        let inputs: Vec<Vec<i16>> = images
+            .iter()
+            .map(|img| Letterbox::new(0).prepare(img, size))
+            .collect();
+
+        let original = yolo.layer_params();
+        let result = self.run_calibration(yolo, &inputs);
+        let restored = yolo.set_layer_params(original);
+        let report = result?;
+        restored?;
+        Ok(report)
+    }
+
+    /// スケールの調整と推論を、スケールが変わらなくなるまで繰り返します。
+    fn run_calibration(
+        &mut self,
+        yolo: &mut YoloV3Tiny,
+        inputs: &[Vec<i16>],
+    ) -> Result<QuantReport> {
+        let mut iteration = 0;
+        loop {
+            iteration += 1;
+            yolo.set_layer_params(
+                self.quantize()
+                    .into_iter()
+                    .map(|q| (q.weights, q.biases))
+                    .collect(),
+            )?;
+            let stats = self.measure(yolo, inputs)?;
+            if iteration >= self.max_iterations || !self.adjust(&stats) {
+                return Ok(self.report(&stats, iteration));
+            }
+            info!(
+                "Calibration iteration {}: scales {:?}",
+                iteration, self.exponents
+            );
+        }
+    }
+
+    /// 全てのキャリブレーション画像を推論し、レイヤーグループごとの出力の統計を求めます。
+    fn measure(&self, yolo: &mut YoloV3Tiny, inputs: &[Vec<i16>]) -> Result<Vec<OutputStats>> {
+        let mut stats = vec![OutputStats::default(); self.shapes.len()];
+        for input in inputs {
+            let outputs = yolo.run_layers(0..=self.shapes.len() - 1, input)?;
+            for (s, output) in stats.iter_mut().zip(&outputs) {
+                s.total += output.len();
+                for &v in output {
+                    if v == i16::MAX || v == i16::MIN {
+                        s.clipped += 1;
+                    }
+                    s.max_abs = s.max_abs.max(v.saturating_abs());
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// 出力の統計からスケールを更新します。
+    ///
+    /// 飽和率が目標を超えたレイヤーグループは縮小し、値域の1/4も使っていないレイヤーグループは
+    /// 精度を上げるために拡大します。
+    ///
+    /// # Return
+    /// * スケールを変更したか
+    fn adjust(&mut self, stats: &[OutputStats]) -> bool {
+        let mut changed = false;
+        for (i, s) in stats.iter().enumerate() {
+            let shape = self.shapes[i];
+            if shape.fixed || shape.conv_disable || self.weights[i].is_none() {
+                continue;
+            }
+            if s.clip_rate() > self.target_clip_rate && self.exponents[i] > self.min_exp {
+                self.exponents[i] -= 1;
+                changed = true;
+            } else if s.clipped == 0 && s.max_abs < i16::MAX / 4 && self.exponents[i] < self.max_exp
+            {
+                self.exponents[i] += 1;
+                if self.quantize_layer(i).weight_clipped > 0 {
+                    self.exponents[i] -= 1;
+                } else {
+                    changed = true;
+                }
+            }
+        }
+        self.propagate();
+        changed
+    }
+
+    /// 畳み込みを行わないレイヤーグループのスケールを、入力のレイヤーグループに合わせます。
+    fn propagate(&mut self) {
+        for i in 0..self.shapes.len() {
+            if self.shapes[i].conv_disable {
+                self.exponents[i] = self.source_exp(i, 0);
+            }
+        }
+    }
+
+    /// 入力チャネルの分割 `iff` を出力したレイヤーグループのスケールの指数を返します。
+    fn source_exp(&self, grp_idx: usize, iff: usize) -> i32 {
+        let mut end = 0;
+        for &src in layer_sources(grp_idx) {
+            end += self.shapes[src].output_fold;
+            if iff < end {
+                return self.exponents[src];
+            }
+        }
+        // 最初のレイヤーグループの入力は画像なので等倍。畳み込みを行わないレイヤーグループは
+        // 入力の分割数が出力と異なるため、最後の入力に合わせる
+        layer_sources(grp_idx)
+            .last()
+            .map_or(0, |&src| self.exponents[src])
+    }
+
+    /// 現在のスケールで全てのレイヤーグループを量子化します。
+    fn quantize(&self) -> Vec<QuantizedLayer> {
+        (0..self.shapes.len())
+            .map(|i| self.quantize_layer(i))
+            .collect()
+    }
+
+    /// 現在のスケールでレイヤーグループの重みとバイアスを量子化します。
+    fn quantize_layer(&self, grp_idx: usize) -> QuantizedLayer {
+        let shape = self.shapes[grp_idx];
+        let exp = self.exponents[grp_idx];
+        let mut weight_clipped = 0;
+        let mut bias_clipped = 0;
+
+        let weights = self.weights[grp_idx].as_ref().map(|w| {
+            let mut q = Vec::with_capacity(w.len());
+            for (n, block) in w.chunks_exact(shape.weight_block).enumerate() {
+                let iff = n / shape.output_fold;
+                let scale = 2f32.powi(exp - self.source_exp(grp_idx, iff));
+                q.extend(block.iter().map(|&v| {
+                    let (fix, clipped) = float2fix(v * scale);
+                    weight_clipped += clipped as usize;
+                    fix
+                }));
+            }
+            q
+        });
+        let biases = self.biases[grp_idx].as_ref().map(|b| {
+            let scale = 2f32.powi(exp);
+            b.iter()
+                .map(|&v| {
+                    let (fix, clipped) = float2fix(v * scale);
+                    bias_clipped += clipped as usize;
+                    fix
+                })
+                .collect()
+        });
+
+        QuantizedLayer {
+            weights,
+            biases,
+            weight_clipped,
+            bias_clipped,
+        }
+    }
+
+    /// 出力の統計と量子化の結果から報告を作成します。
+    fn report(&self, stats: &[OutputStats], iterations: usize) -> QuantReport {
+        let rate = |clipped: usize, data: &Option<Vec<f32>>| {
+            data.as_ref()
+                .map_or(0., |d| clipped as f32 / d.len().max(1) as f32)
+        };
+        let layers = self
+            .quantize()
+            .iter()
+            .zip(stats)
+            .enumerate()
+            .map(|(i, (q, s))| LayerQuantReport {
+                grp_idx: i,
+                scale_exp: self.exponents[i],
+                weight_clip_rate: rate(q.weight_clipped, &self.weights[i]),
+                bias_clip_rate: rate(q.bias_clipped, &self.biases[i]),
+                output_clip_rate: s.clip_rate(),
+                max_abs_output: fix2float(s.max_abs),
+            })
+            .collect();
+        QuantReport { layers, iterations }
+    }
+
+    /// 現在のスケールで量子化した重みとバイアスを、`read_weights_and_biases` で読み込める
+    /// gzipアーカイブに書き出します。
+    ///
+    /// # Args
+    /// * `path` - 保存先のパス
+    pub fn write_bundle<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("Can't create {}", path.display()))?;
+        let mut builder = Builder::new(GzEncoder::new(file, Compression::default()));
+
+        for (i, q) in self.quantize().into_iter().enumerate() {
+            for (name, data) in [("weights", q.weights), ("biases", q.biases)] {
+                let Some(data) = data else {
+                    continue;
+                };
+                let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_le_bytes()).collect();
+                let mut header = Header::new_gnu();
+                header.set_size(bytes.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder.append_data(&mut header, format!("{}{}", name, i), bytes.as_slice())?;
+            }
+        }
+        builder.into_inner()?.finish()?;
+        info!("Wrote quantized weights to {}", path.display());
+        Ok(())
+    }
+}
+
+/// 浮動小数点数をQ8.8の固定小数点数に変換します。
+///
+/// # Return
+/// * 固定小数点数と、飽和したか
+fn float2fix(v: f32) -> (i16, bool) {
+    let q = (v * 256.).round();
+    if q > i16::MAX as f32 {
+        (i16::MAX, true)
+    } else if q < i16::MIN as f32 {
+        (i16::MIN, true)
+    } else {
+        (q as i16, false)
+    }
+}
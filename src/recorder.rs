@@ -0,0 +1,339 @@
+//! 描画済みのフレームを動画ファイル (MP4・WebM) に記録するモジュール
+//!
+//! `gstreamer` または `ffmpeg` フィーチャを有効にした場合のみ利用できます。
+//! エンコードにはGStreamerのパイプラインか `ffmpeg` コマンドを使い、`set_backend` で切り替えられます。
+//! 実地試験での証拠の記録を想定し、一定のフレーム数ごとにファイルを区切り、
+//! `DetectionLogger` と同じく `{名前}.1.{拡張子}`, `{名前}.2.{拡張子}`, ... に退避して古いファイルから削除します。
+//! MP4は断片化して記録するため、電源断などで記録が中断しても途中までの動画を再生できます。
+//!
+//! ```ignore
+//! let mut recorder = VideoRecorder::new("/data/evidence", "cam0", VideoFormat::Mp4, 15)?;
+//! recorder.set_rotation(15 * 60 * 5, 12)?;
+//! loop {
+//!     let frame = source.next_frame()?.unwrap();
+//!     let result = yolo.start_with_img_proc(&DynamicImage::ImageRgb8(frame.clone()), 0)?;
+//!     recorder.record(&frame, &result)?;
+//! }
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use image::RgbImage;
+use serde::Deserialize;
+
+use crate::detection_result::DetectionData;
+#[cfg(feature = "ffmpeg")]
+use crate::ffmpeg::FfmpegSink;
+#[cfg(feature = "gstreamer")]
+use crate::gst_pipeline::GstSink;
+use crate::render::Renderer;
+
+/// 動画ファイルの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoFormat {
+    /// H.264のMP4
+    #[default]
+    Mp4,
+    /// VP8のWebM
+    Webm,
+}
+
+impl VideoFormat {
+    /// ファイルの拡張子を返します。
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Webm => "webm",
+        }
+    }
+
+    /// 既定のエンコーダとマルチプレクサのパイプラインの記述を返します。
+    pub fn encoder(&self) -> &'static str {
+        match self {
+            Self::Mp4 => "x264enc tune=zerolatency ! mp4mux fragment-duration=1000",
+            Self::Webm => "vp8enc deadline=1 ! webmmux",
+        }
+    }
+
+    /// `ffmpeg` コマンドに渡す既定のエンコーダのオプションを返します。
+    pub fn ffmpeg_args(&self) -> &'static str {
+        match self {
+            Self::Mp4 => {
+                "-c:v libx264 -tune zerolatency -pix_fmt yuv420p -movflags frag_keyframe+empty_moov"
+            }
+            Self::Webm => "-c:v libvpx -deadline realtime",
+        }
+    }
+}
+
+/// 動画のエンコードに使う実装
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoBackend {
+    /// GStreamerのパイプライン (`gstreamer` フィーチャ)
+    #[cfg(feature = "gstreamer")]
+    Gstreamer,
+    /// `ffmpeg` コマンド (`ffmpeg` フィーチャ)
+    #[cfg(feature = "ffmpeg")]
+    Ffmpeg,
+}
+
+impl Default for VideoBackend {
+    /// `gstreamer` フィーチャが有効な場合はGStreamer、そうでない場合は `ffmpeg` を返します。
+    fn default() -> Self {
+        #[cfg(feature = "gstreamer")]
+        return Self::Gstreamer;
+        #[cfg(not(feature = "gstreamer"))]
+        return Self::Ffmpeg;
+    }
+}
+
+impl VideoBackend {
+    /// 形式ごとの既定のエンコーダの設定を返します。
+    ///
+    /// # Args
+    /// * `format` - 動画ファイルの形式
+    pub fn encoder(&self, format: VideoFormat) -> &'static str {
+        match self {
+            #[cfg(feature = "gstreamer")]
+            Self::Gstreamer => format.encoder(),
+            #[cfg(feature = "ffmpeg")]
+            Self::Ffmpeg => format.ffmpeg_args(),
+        }
+    }
+}
+
+/// 記録中のファイルにフレームを送るシンク
+enum Sink {
+    #[cfg(feature = "gstreamer")]
+    Gstreamer(GstSink),
+    #[cfg(feature = "ffmpeg")]
+    Ffmpeg(FfmpegSink),
+}
+
+impl Sink {
+    fn push(&mut self, img: &RgbImage) -> Result<()> {
+        match self {
+            #[cfg(feature = "gstreamer")]
+            Self::Gstreamer(sink) => sink.push(img),
+            #[cfg(feature = "ffmpeg")]
+            Self::Ffmpeg(sink) => sink.push(img),
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self {
+            #[cfg(feature = "gstreamer")]
+            Self::Gstreamer(sink) => sink.finish(),
+            #[cfg(feature = "ffmpeg")]
+            Self::Ffmpeg(sink) => sink.finish(),
+        }
+    }
+}
+
+/// 描画済みのフレームを動画ファイルに記録する構造体
+pub struct VideoRecorder {
+    dir: PathBuf,
+    name: String,
+    format: VideoFormat,
+    fps: u32,
+    backend: VideoBackend,
+    encoder: String,
+    max_frames: u64,
+    max_files: usize,
+    renderer: Renderer,
+    sink: Option<Sink>,
+    /// 記録中のファイルのフレームの大きさ
+    size: (u32, u32),
+    /// 記録中のファイルに書き込んだフレームの数
+    frames: u64,
+}
+
+impl VideoRecorder {
+    /// 新しい `VideoRecorder` インスタンスを作成します。ディレクトリが存在しない場合は作成します。
+    ///
+    /// ファイルは最初のフレームを記録するときに作成します。
+    ///
+    /// # Args
+    /// * `dir` - 出力先のディレクトリ
+    /// * `name` - ファイル名 (拡張子を除く)
+    /// * `format` - 動画ファイルの形式
+    /// * `fps` - 動画のフレームレート
+    pub fn new<P: Into<PathBuf>>(
+        dir: P,
+        name: &str,
+        format: VideoFormat,
+        fps: u32,
+    ) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            name: name.to_string(),
+            format,
+            fps: fps.max(1),
+            backend: VideoBackend::default(),
+            encoder: VideoBackend::default().encoder(format).to_string(),
+            max_frames: fps.max(1) as u64 * 60 * 10,
+            max_files: 10,
+            renderer: Renderer::default(),
+            sink: None,
+            size: (0, 0),
+            frames: 0,
+        })
+    }
+
+    /// ファイルを退避する条件を設定します。
+    ///
+    /// # Args
+    /// * `max_frames` - 1つのファイルに記録するフレームの最大数
+    /// * `max_files` - 保持する退避済みファイルの数 (1以上)
+    ///
+    /// # Return
+    /// * Result。`max_files` が0の場合はエラー
+    pub fn set_rotation(&mut self, max_frames: u64, max_files: usize) -> Result<&mut Self> {
+        // 0では記録を終えたファイルを退避できず、そのまま削除してしまう
+        ensure!(max_files > 0, "max_files must be at least 1");
+        self.max_frames = max_frames.max(1);
+        self.max_files = max_files;
+        Ok(self)
+    }
+
+    /// エンコードに使う実装を設定し、エンコーダの設定をその既定値に戻します。次のファイルから適用されます。
+    pub fn set_backend(&mut self, backend: VideoBackend) -> &mut Self {
+        self.backend = backend;
+        self.encoder = backend.encoder(self.format).to_string();
+        self
+    }
+
+    /// エンコーダの設定を変更します。次のファイルから適用されます。
+    ///
+    /// # Args
+    /// * `encoder` - GStreamerの場合はエンコーダとマルチプレクサのパイプラインの記述
+    ///   (例: `"v4l2h264enc ! h264parse ! mp4mux"`)、`ffmpeg` の場合は出力のオプション
+    ///   (例: `"-c:v h264_v4l2m2m -movflags frag_keyframe+empty_moov"`)
+    pub fn set_encoder(&mut self, encoder: &str) -> &mut Self {
+        self.encoder = encoder.to_string();
+        self
+    }
+
+    /// `record` で検出結果を描画するときの設定を設定します。
+    pub fn set_renderer(&mut self, renderer: Renderer) -> &mut Self {
+        self.renderer = renderer;
+        self
+    }
+
+    /// 記録中のファイルのパスを返します。
+    pub fn current_path(&self) -> PathBuf {
+        self.path(0)
+    }
+
+    /// 検出結果を描画したフレームを記録します。元の画像は変更しません。
+    ///
+    /// # Args
+    /// * `img` - フレーム
+    /// * `detections` - フレームの検出結果 (画像の座標系)
+    pub fn record(&mut self, img: &RgbImage, detections: &[DetectionData]) -> Result<()> {
+        let mut annotated = img.clone();
+        self.renderer.draw(&mut annotated, detections);
+        self.push(&annotated)
+    }
+
+    /// 描画済みのフレームを記録します。
+    ///
+    /// 記録中のファイルのフレーム数が上限に達した場合や、フレームの大きさが変わった場合は新しいファイルに記録します。
+    ///
+    /// # Args
+    /// * `img` - 描画済みのフレーム
+    pub fn push(&mut self, img: &RgbImage) -> Result<()> {
+        if self.sink.is_some() && (self.frames >= self.max_frames || img.dimensions() != self.size)
+        {
+            self.finish()?;
+            self.rotate()?;
+        }
+
+        let sink = match self.sink.as_mut() {
+            Some(sink) => sink,
+            None => {
+                let path = self.path(0);
+                if path.exists() {
+                    // 前回の実行で記録したファイルを上書きしないよう退避する
+                    self.rotate()?;
+                }
+                let sink = self
+                    .open_sink(&path, img.width(), img.height())
+                    .with_context(|| format!("Can't create {}", path.display()))?;
+                self.size = img.dimensions();
+                self.frames = 0;
+                self.sink.insert(sink)
+            }
+        };
+        sink.push(img)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// 記録中のファイルを閉じます。次のフレームは新しいファイルに記録します。
+    pub fn finish(&mut self) -> Result<()> {
+        if let Some(mut sink) = self.sink.take() {
+            sink.finish()?;
+        }
+        Ok(())
+    }
+
+    /// 設定した実装で新しいファイルのシンクを作成します。
+    fn open_sink(&self, path: &Path, width: u32, height: u32) -> Result<Sink> {
+        Ok(match self.backend {
+            #[cfg(feature = "gstreamer")]
+            VideoBackend::Gstreamer => Sink::Gstreamer(GstSink::new(
+                &format!(
+                    "appsrc name=src ! videoconvert ! {} ! filesink location=\"{}\"",
+                    self.encoder,
+                    path.display()
+                ),
+                width,
+                height,
+                self.fps,
+            )?),
+            #[cfg(feature = "ffmpeg")]
+            VideoBackend::Ffmpeg => Sink::Ffmpeg(FfmpegSink::new(
+                &self.encoder,
+                path,
+                width,
+                height,
+                self.fps,
+            )?),
+        })
+    }
+
+    /// 番号付きのファイルのパスを返します。0は記録中のファイルです。
+    fn path(&self, index: usize) -> PathBuf {
+        let ext = self.format.extension();
+        if index == 0 {
+            self.dir.join(format!("{}.{}", self.name, ext))
+        } else {
+            self.dir.join(format!("{}.{}.{}", self.name, index, ext))
+        }
+    }
+
+    /// 記録を終えたファイルを退避し、保持する数を超えたファイルを削除します。
+    fn rotate(&mut self) -> Result<()> {
+        let _ = fs::remove_file(self.path(self.max_files));
+        for i in (0..self.max_files).rev() {
+            let from = self.path(i);
+            if from.exists() {
+                fs::rename(&from, self.path(i + 1))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VideoRecorder {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
@@ -0,0 +1,193 @@
+//! 検出結果の切り抜きを拡大して再推論し、クラスとコンフィデンスを補正するモジュール
+//!
+//! 遠くの信号機のように小さく写った物体は、色のヒューリスティックだけではクラスを誤りやすいため、
+//! 周囲を含めて切り抜いた画像をもう一度ネットワークに通し、その結果を元の検出結果に統合します。
+
+use anyhow::Result;
+use image::DynamicImage;
+
+use crate::detection_result::DetectionData;
+use crate::yolov3_tiny::YoloV3Tiny;
+
+/// 2段階目の再推論で検出結果を補正する構造体
+pub struct CropRefiner {
+    classes: Vec<u8>,
+    max_box_size: f32,
+    context: f32,
+    max_crops: usize,
+    weight: f32,
+    min_iou: f32,
+}
+
+impl Default for CropRefiner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CropRefiner {
+    /// 新しい `CropRefiner` インスタンスを作成します。
+    ///
+    /// 既定では信号機のクラス (赤・黄・青) のうち、長辺が64ピクセル以下のものを補正します。
+    pub fn new() -> Self {
+        Self {
+            classes: vec![0, 1, 2],
+            max_box_size: 64.,
+            context: 3.,
+            max_crops: 4,
+            weight: 0.5,
+            min_iou: 0.3,
+        }
+    }
+
+    /// 補正の対象にするクラスを設定します。再推論の結果もこのクラスに限定します。
+    pub fn set_classes(&mut self, classes: Vec<u8>) -> &mut Self {
+        self.classes = classes;
+        self
+    }
+
+    /// 補正の対象にするバウンディングボックスの長辺の最大値 (ピクセル) を設定します。
+    pub fn set_max_box_size(&mut self, max_box_size: f32) -> &mut Self {
+        self.max_box_size = max_box_size;
+        self
+    }
+
+    /// 切り抜く範囲の、バウンディングボックスの長辺に対する倍率を設定します。
+    ///
+    /// 周囲の筐体なども含めるため、1より大きい値を指定してください。
+    pub fn set_context(&mut self, context: f32) -> &mut Self {
+        self.context = context.max(1.);
+        self
+    }
+
+    /// 1フレームで再推論する切り抜きの最大数を設定します。コンフィデンスの低いものから補正します。
+    pub fn set_max_crops(&mut self, max_crops: usize) -> &mut Self {
+        self.max_crops = max_crops;
+        self
+    }
+
+    /// 統合するときの再推論の結果の重み (0-1) を設定します。
+    pub fn set_weight(&mut self, weight: f32) -> &mut Self {
+        self.weight = weight.clamp(0., 1.);
+        self
+    }
+
+    /// 再推論の結果を元の検出結果と対応付けるIoUの最小値を設定します。
+    pub fn set_min_iou(&mut self, min_iou: f32) -> &mut Self {
+        self.min_iou = min_iou;
+        self
+    }
+
+    /// 画像の処理を行い、小さな検出結果を再推論で補正します。
+    ///
+    /// # Args
+    /// * `yolo` - 推論に使う `YoloV3Tiny`
+    /// * `img` - 入力画像
+    /// * `rotate_angle` - 回転角度
+    ///
+    /// # Return
+    /// * 補正した物体検出結果 (回転後の画像の座標系)
+    pub fn start(
+        &self,
+        yolo: &mut YoloV3Tiny,
+        img: &DynamicImage,
+        rotate_angle: u32,
+    ) -> Result<Vec<DetectionData>> {
+        let detections = yolo.start_with_img_proc(img, rotate_angle)?;
+        let rotated = crate::img_proc::rotate_img(img, rotate_angle);
+        self.refine(yolo, &rotated, detections)
+    }
+
+    /// 検出結果のうち対象のものを、切り抜きの再推論で補正します。
+    ///
+    /// 再推論のフレームでは、フックやフレーム番号は更新されません。
+    ///
+    /// # Args
+    /// * `yolo` - 推論に使う `YoloV3Tiny`
+    /// * `img` - 検出結果と同じ座標系の画像
+    /// * `detections` - 1段階目の検出結果
+    ///
+    /// # Return
+    /// * 補正した物体検出結果
+    pub fn refine(
+        &self,
+        yolo: &mut YoloV3Tiny,
+        img: &DynamicImage,
+        mut detections: Vec<DetectionData>,
+    ) -> Result<Vec<DetectionData>> {
+        let mut targets: Vec<usize> = (0..detections.len())
+            .filter(|&i| {
+                let d = &detections[i];
+                self.classes.contains(&d.class) && d.width().max(d.height()) <= self.max_box_size
+            })
+            .collect();
+        targets.sort_by(|&a, &b| {
+            detections[a]
+                .confidence
+                .total_cmp(&detections[b].confidence)
+        });
+        targets.truncate(self.max_crops);
+
+        for i in targets {
+            let d = detections[i];
+            let Some((x, y, w, h)) = self.crop_rect(&d, img.width(), img.height()) else {
+                continue;
+            };
+            let crop = img.crop_imm(x, y, w, h);
+            let orig = DetectionData::new(
+                d.class,
+                d.x1 - x as f32,
+                d.y1 - y as f32,
+                d.x2 - x as f32,
+                d.y2 - y as f32,
+                d.confidence,
+            )
+            .with_sub_state(d.sub_state);
+            let refined = yolo
+                .detect_without_hooks(&crop)?
+                .into_iter()
+                .filter(|r| self.classes.contains(&r.class) && r.iou(&orig) >= self.min_iou)
+                .max_by(|a, b| a.confidence.total_cmp(&b.confidence));
+            if let Some(r) = refined {
+                self.merge(&mut detections[i], &r);
+            }
+        }
+        Ok(detections)
+    }
+
+    /// バウンディングボックスの周囲を含めた、切り抜く範囲を求めます。
+    ///
+    /// # Return
+    /// * 切り抜く範囲 (x, y, 幅, 高さ)。画像の外にある場合はNone
+    fn crop_rect(
+        &self,
+        d: &DetectionData,
+        width: u32,
+        height: u32,
+    ) -> Option<(u32, u32, u32, u32)> {
+        let (cx, cy) = d.center();
+        let half = d.width().max(d.height()) * self.context / 2.;
+        let x1 = (cx - half).max(0.) as u32;
+        let y1 = (cy - half).max(0.) as u32;
+        let x2 = ((cx + half).max(0.) as u32).min(width);
+        let y2 = ((cy + half).max(0.) as u32).min(height);
+        (x2 > x1 && y2 > y1).then_some((x1, y1, x2 - x1, y2 - y1))
+    }
+
+    /// 再推論の結果を元の検出結果に統合します。
+    ///
+    /// クラスごとに `(1 - weight) * 元のコンフィデンス + weight * 再推論のコンフィデンス` を求め、
+    /// 最も高いクラスを採用します。クラスが一致しない場合はコンフィデンスが下がります。
+    fn merge(&self, d: &mut DetectionData, refined: &DetectionData) {
+        let orig_score = (1. - self.weight) * d.confidence;
+        let refined_score = self.weight * refined.confidence;
+        if refined.class == d.class {
+            d.confidence = orig_score + refined_score;
+        } else if refined_score > orig_score {
+            d.class = refined.class;
+            d.confidence = refined_score;
+        } else {
+            d.confidence = orig_score;
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! バウンディングボックス内の領域の解析に関するモジュール
+
+use anyhow::{ensure, Result};
+
+/// 領域の矩形のうち、ピクセルを加算する範囲の形
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RegionMask {
+    /// 矩形全体
+    #[default]
+    Rectangle,
+    /// 矩形に内接する楕円
+    Ellipse,
+    /// 矩形の中心を中心とし、短辺を直径とする円
+    Circle,
+}
+
+/// 領域の明るさの指標
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum BrightnessMetric {
+    /// 明るさの平均
+    #[default]
+    Mean,
+    /// 明るさのパーセンタイル (0.0-1.0)。0.9で90パーセンタイル
+    Percentile(f64),
+    /// 明るさが閾値 (0.0-1.0) 以上のピクセルの割合
+    BrightFraction(f64),
+}
+
+/// 画像上の矩形領域と、その領域内のピクセルの統計量を保持する構造体
+pub struct Region {
+    /// 領域左上の座標
+    pub(crate) start: (u32, u32),
+    /// 領域右下の座標
+    pub(crate) end: (u32, u32),
+    /// ピクセルを加算する範囲の形
+    pub(crate) mask: RegionMask,
+    /// 領域内のピクセルの明るさの合計
+    pub(crate) total_brightness: f64,
+    /// 領域内のピクセルの明るさの二乗の合計
+    pub(crate) total_brightness_sq: f64,
+    /// 領域内のピクセルの明るさ (RGBの最大値) のヒストグラム
+    pub(crate) brightness_hist: [u32; 256],
+    /// 領域内のピクセルのRGB値の合計
+    pub(crate) total_rgb: [f64; 3],
+    /// 領域内のピクセルの彩度の合計
+    pub(crate) total_saturation: f64,
+    /// 領域内のピクセルの彩度の二乗の合計
+    pub(crate) total_saturation_sq: f64,
+    /// 彩度で重み付けした色相の単位ベクトル (cos, sin) の合計
+    pub(crate) hue_vector: (f64, f64),
+    /// 領域内のピクセル数
+    pub(crate) n_pixels: u64,
+}
+
+impl Region {
+    /// 新しい `Region` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `s` - 領域左上の座標
+    /// * `e` - 領域右下の座標
+    ///
+    /// # Return
+    /// * 新たな `Region` インスタンス。座標が負の場合はエラー
+    pub fn new(s: (f32, f32), e: (f32, f32)) -> Result<Self> {
+        let values = [s.0, s.1, e.0, e.1];
+        ensure!(values.iter().all(|f| f.is_sign_positive()), "Coordinates must be positive");
+        let start = (s.0.floor() as u32, s.1.floor() as u32);
+        let end = (e.0.floor() as u32, e.1.floor() as u32);
+        Ok(Self {
+            start,
+            end,
+            mask: RegionMask::Rectangle,
+            total_brightness: 0.0,
+            total_brightness_sq: 0.0,
+            brightness_hist: [0; 256],
+            total_rgb: [0.0; 3],
+            total_saturation: 0.0,
+            total_saturation_sq: 0.0,
+            hue_vector: (0.0, 0.0),
+            n_pixels: 0,
+        })
+    }
+
+    /// 領域の幅を返します。
+    pub fn width(&self) -> u32 {
+        self.end.0.abs_diff(self.start.0)
+    }
+
+    /// 領域の高さを返します。
+    pub fn height(&self) -> u32 {
+        self.end.1.abs_diff(self.start.1)
+    }
+
+    /// ピクセルを加算する範囲の形を設定します。
+    ///
+    /// 信号機の灯器は円形のため、楕円や円にすると矩形の角に写る暗い筐体の影響を減らせます。
+    pub fn set_mask(&mut self, mask: RegionMask) -> &mut Self {
+        self.mask = mask;
+        self
+    }
+
+    /// ピクセルを加算する範囲の形を返します。
+    pub fn mask(&self) -> RegionMask {
+        self.mask
+    }
+
+    /// 指定した座標が領域の内側 (マスクの範囲内) にあるかを返します。
+    pub fn is_in(&self, p: (u32, u32)) -> bool {
+        let in_rect =
+            self.start.0 < p.0 && self.start.1 < p.1 && self.end.0 > p.0 && self.end.1 > p.1;
+        if !in_rect {
+            return false;
+        }
+
+        let (rx, ry) = match self.mask {
+            RegionMask::Rectangle => return true,
+            RegionMask::Ellipse => (self.width() as f64 / 2., self.height() as f64 / 2.),
+            RegionMask::Circle => {
+                let r = self.width().min(self.height()) as f64 / 2.;
+                (r, r)
+            }
+        };
+        if rx <= 0. || ry <= 0. {
+            return false;
+        }
+        // ピクセルの中心で判定する
+        let cx = (self.start.0 + self.end.0) as f64 / 2.;
+        let cy = (self.start.1 + self.end.1) as f64 / 2.;
+        let dx = (p.0 as f64 + 0.5 - cx) / rx;
+        let dy = (p.1 as f64 + 0.5 - cy) / ry;
+        dx * dx + dy * dy <= 1.
+    }
+
+    /// 明るさを加算します。
+    pub fn add_brightness(&mut self, value: f64) {
+        self.total_brightness += value;
+    }
+
+    /// ピクセルを領域に加算します。
+    ///
+    /// # Args
+    /// * `rgb` - ピクセルのRGB値
+    pub fn add_pixel(&mut self, rgb: [u8; 3]) {
+        let hsv = color_space::Hsv::from(color_space::Rgb::new(
+            rgb[0] as f64,
+            rgb[1] as f64,
+            rgb[2] as f64,
+        ));
+        self.add_brightness(hsv.v);
+        self.total_brightness_sq += hsv.v * hsv.v;
+        self.brightness_hist[*rgb.iter().max().unwrap() as usize] += 1;
+        for (total, &c) in self.total_rgb.iter_mut().zip(rgb.iter()) {
+            *total += c as f64;
+        }
+        self.total_saturation += hsv.s;
+        self.total_saturation_sq += hsv.s * hsv.s;
+        // 無彩色に近いピクセルの色相は不安定なため、彩度で重み付けする
+        let (sin, cos) = hsv.h.to_radians().sin_cos();
+        self.hue_vector.0 += hsv.s * cos;
+        self.hue_vector.1 += hsv.s * sin;
+        self.n_pixels += 1;
+    }
+
+    /// 領域内のピクセルの明るさの合計を返します。
+    pub fn total_brightness(&self) -> f64 {
+        self.total_brightness
+    }
+
+    /// 領域内のピクセルの明るさの平均を返します。ピクセルがない場合は0を返します。
+    pub fn mean_brightness(&self) -> f64 {
+        if self.n_pixels == 0 {
+            0.
+        } else {
+            self.total_brightness / self.n_pixels as f64
+        }
+    }
+
+    /// 領域内のピクセルの明るさのパーセンタイルを返します。ピクセルがない場合は0を返します。
+    ///
+    /// 点灯部が領域の一部しか占めない場合でも、暗い筐体に引きずられずに点灯部の明るさを求められます。
+    ///
+    /// # Args
+    /// * `q` - パーセンタイル (0.0-1.0)。0.9で90パーセンタイル
+    ///
+    /// # Return
+    /// * 明るさ (0.0-1.0)
+    pub fn brightness_percentile(&self, q: f64) -> f64 {
+        if self.n_pixels == 0 {
+            return 0.;
+        }
+        let rank = ((q.clamp(0., 1.) * self.n_pixels as f64).ceil() as u64).max(1);
+        let mut count = 0;
+        for (v, &n) in self.brightness_hist.iter().enumerate() {
+            count += n as u64;
+            if count >= rank {
+                return v as f64 / 255.;
+            }
+        }
+        1.
+    }
+
+    /// 領域内のピクセルの明るさの90パーセンタイルを返します。
+    pub fn p90_brightness(&self) -> f64 {
+        self.brightness_percentile(0.9)
+    }
+
+    /// 領域内のピクセルの明るさの99パーセンタイルを返します。
+    pub fn p99_brightness(&self) -> f64 {
+        self.brightness_percentile(0.99)
+    }
+
+    /// 明るさが閾値以上のピクセルの割合を返します。ピクセルがない場合は0を返します。
+    ///
+    /// # Args
+    /// * `threshold` - 明るさの閾値 (0.0-1.0)
+    pub fn bright_fraction(&self, threshold: f64) -> f64 {
+        if self.n_pixels == 0 {
+            return 0.;
+        }
+        let first = (threshold.clamp(0., 1.) * 255.).ceil() as usize;
+        let n: u64 = self.brightness_hist[first..]
+            .iter()
+            .map(|&n| n as u64)
+            .sum();
+        n as f64 / self.n_pixels as f64
+    }
+
+    /// 指標に従って領域の明るさを返します。
+    pub fn brightness(&self, metric: BrightnessMetric) -> f64 {
+        match metric {
+            BrightnessMetric::Mean => self.mean_brightness(),
+            BrightnessMetric::Percentile(q) => self.brightness_percentile(q),
+            BrightnessMetric::BrightFraction(threshold) => self.bright_fraction(threshold),
+        }
+    }
+
+    /// 領域内のピクセルのRGB値の平均を返します。ピクセルがない場合は0を返します。
+    pub fn mean_rgb(&self) -> [f64; 3] {
+        if self.n_pixels == 0 {
+            [0.; 3]
+        } else {
+            self.total_rgb.map(|c| c / self.n_pixels as f64)
+        }
+    }
+
+    /// 領域内のピクセルの明るさの分散を返します。ピクセルがない場合は0を返します。
+    pub fn brightness_variance(&self) -> f64 {
+        variance(
+            self.total_brightness,
+            self.total_brightness_sq,
+            self.n_pixels,
+        )
+    }
+
+    /// 領域内のピクセルの彩度 (0.0-1.0) の平均を返します。ピクセルがない場合は0を返します。
+    pub fn mean_saturation(&self) -> f64 {
+        if self.n_pixels == 0 {
+            0.
+        } else {
+            self.total_saturation / self.n_pixels as f64
+        }
+    }
+
+    /// 領域内のピクセルの彩度の分散を返します。ピクセルがない場合は0を返します。
+    pub fn saturation_variance(&self) -> f64 {
+        variance(
+            self.total_saturation,
+            self.total_saturation_sq,
+            self.n_pixels,
+        )
+    }
+
+    /// 領域内のピクセルの平均色の色相 (度) を返します。
+    pub fn mean_hue(&self) -> f64 {
+        let [r, g, b] = self.mean_rgb();
+        color_space::Hsv::from(color_space::Rgb::new(r, g, b)).h
+    }
+
+    /// 領域内のピクセルの色相 (度) の円周平均を、彩度で重み付けして返します。
+    ///
+    /// 平均色の色相 (`mean_hue`) と異なり、赤 (0度付近) をまたぐ色相や、点灯部と暗い筐体が混ざった領域でも
+    /// 有彩色のピクセルの色相を正しく平均できます。
+    ///
+    /// # Return
+    /// * 色相 (0.0-360.0)。有彩色のピクセルがない場合や、色相が打ち消し合って定まらない場合はNone
+    pub fn circular_mean_hue(&self) -> Option<f64> {
+        let (x, y) = self.hue_vector;
+        if x.hypot(y) <= f64::EPSILON * self.n_pixels.max(1) as f64 {
+            return None;
+        }
+        Some(y.atan2(x).to_degrees().rem_euclid(360.))
+    }
+
+    /// 領域内のピクセルの色相の円周分散 (0.0-1.0) を、彩度で重み付けして返します。
+    ///
+    /// 色相が揃っているほど0に近く、ばらばらなほど1に近くなります。有彩色のピクセルがない場合は1を返します。
+    pub fn hue_variance(&self) -> f64 {
+        if self.total_saturation <= 0. {
+            return 1.;
+        }
+        let (x, y) = self.hue_vector;
+        (1. - x.hypot(y) / self.total_saturation).clamp(0., 1.)
+    }
+}
+
+/// 合計と二乗の合計から分散を求めます。
+fn variance(total: f64, total_sq: f64, n: u64) -> f64 {
+    if n == 0 {
+        return 0.;
+    }
+    let mean = total / n as f64;
+    (total_sq / n as f64 - mean * mean).max(0.)
+}
@@ -0,0 +1,554 @@
+//! 検出結果を画像に重ねて描画するモジュール
+//!
+//! バウンディングボックスの描き方 (枠全体・四隅のみ)、半透明の塗りつぶし、信頼度のバー、
+//! クラスごとの色とラベルを `RenderStyle` で設定します。
+//! クラスの色は `Palette` で管理し、`palette` に指定した色と既定の色のどちらにもないクラスには、
+//! クラスの番号から生成した色を割り当てます。
+//! FPSなどの集計結果 (`Telemetry`) は、`telemetry_corner` で指定した隅に描画します。
+//! 斜めの線や多角形 (エリアやカウントラインなど) は `drawing` モジュールでアンチエイリアスをかけて描画します。
+//!
+//! ラベルは既定では埋め込みのRobotoMonoで描画します。日本語のクラス名を表示する場合は、
+//! `font` にその文字を含むフォント (TTF・OTF、TTCの場合は `font_index` も) を指定してください。
+//!
+//! ```toml
+//! [render]
+//! box_style = { type = "corners", length = 0.25 }
+//! fill_alpha = 0.2
+//! confidence_bar = true
+//! font = "/usr/share/fonts/opentype/noto/NotoSansCJK-Regular.ttc"
+//! class_names = ["赤信号", "黄信号", "青信号"]
+//! telemetry_corner = "top_right"
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use image::{ImageBuffer, Rgb, RgbaImage};
+use log::warn;
+use rusttype::{Font, Scale};
+use serde::Deserialize;
+
+use crate::detection_result::DetectionData;
+use crate::drawing::{self, draw_text_blend, fill_rect_blend, DrawPixel};
+use crate::img_proc::{draw_label, draw_line, draw_rect, text_width};
+use crate::palette::Palette;
+use crate::telemetry::Telemetry;
+
+/// 信頼度のバーの背景色
+const BAR_BACKGROUND: [u8; 3] = [64, 64, 64];
+
+/// 集計結果を描画する位置の画像の端からの距離
+const TELEMETRY_MARGIN: f32 = 8.;
+
+/// 集計結果の背景の不透明度
+const TELEMETRY_BACKGROUND_ALPHA: f32 = 0.6;
+
+/// バウンディングボックスの描き方
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum BoxStyle {
+    /// 枠全体を描画します
+    #[default]
+    Full,
+    /// 四隅だけを描画します
+    Corners {
+        /// 角の線の長さ (バウンディングボックスの短い辺に対する割合、0.0-0.5)
+        #[serde(default = "default_corner_length")]
+        length: f32,
+    },
+}
+
+fn default_corner_length() -> f32 {
+    0.25
+}
+
+/// 画像の隅の位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Corner {
+    /// 左上
+    #[default]
+    TopLeft,
+    /// 右上
+    TopRight,
+    /// 左下
+    BottomLeft,
+    /// 右下
+    BottomRight,
+}
+
+/// 描画の設定 (`[render]`)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderStyle {
+    /// ラベルのフォントサイズ。0の場合はラベルを描画しません
+    pub font_size: f32,
+    /// 線の太さ
+    pub line_thickness: f32,
+    /// バウンディングボックスの描き方
+    pub box_style: BoxStyle,
+    /// バウンディングボックスの内側を塗りつぶす不透明度 (0.0-1.0)。0の場合は塗りつぶしません
+    pub fill_alpha: f32,
+    /// バウンディングボックスの下に信頼度のバーを描画するか
+    pub confidence_bar: bool,
+    /// クラス0から順に並べたクラスごとの色。省略したクラスには既定の色または生成した色を使います
+    pub palette: Vec<[u8; 3]>,
+    /// ラベルに表示するクラス名。省略した場合や足りない場合はクラスの番号を表示します
+    pub class_names: Vec<String>,
+    /// ラベルのフォントファイルのパス。省略した場合は埋め込みのRobotoMonoを使います
+    pub font: Option<PathBuf>,
+    /// フォントファイルがフォントコレクション (TTC) の場合に使うフォントの番号
+    pub font_index: u32,
+    /// FPSなどの集計結果 (`Telemetry`) を描画する隅
+    pub telemetry_corner: Corner,
+    /// 集計結果のフォントサイズ
+    pub telemetry_font_size: f32,
+}
+
+impl Default for RenderStyle {
+    fn default() -> Self {
+        Self {
+            font_size: 20.,
+            line_thickness: 4.,
+            box_style: BoxStyle::Full,
+            fill_alpha: 0.,
+            confidence_bar: false,
+            palette: vec![],
+            class_names: vec![],
+            font: None,
+            font_index: 0,
+            telemetry_corner: Corner::TopLeft,
+            telemetry_font_size: 16.,
+        }
+    }
+}
+
+impl RenderStyle {
+    /// 設定の値の範囲を検証します。
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.font_size >= 0.,
+            "font_size must not be negative, got {}",
+            self.font_size
+        );
+        ensure!(
+            self.telemetry_font_size > 0.,
+            "telemetry_font_size must be positive, got {}",
+            self.telemetry_font_size
+        );
+        ensure!(
+            self.line_thickness >= 0.,
+            "line_thickness must not be negative, got {}",
+            self.line_thickness
+        );
+        ensure!(
+            (0. ..=1.).contains(&self.fill_alpha),
+            "fill_alpha must be in [0, 1], got {}",
+            self.fill_alpha
+        );
+        if let BoxStyle::Corners { length } = self.box_style {
+            ensure!(
+                length > 0. && length <= 0.5,
+                "box_style.length must be in (0, 0.5], got {}",
+                length
+            );
+        }
+        Ok(())
+    }
+}
+
+/// 検出結果を画像に描画する構造体
+pub struct Renderer {
+    style: RenderStyle,
+    font: Font<'static>,
+    palette: Palette,
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self::new(RenderStyle::default()).unwrap()
+    }
+}
+
+impl Renderer {
+    /// 新しい `Renderer` インスタンスを作成します。
+    ///
+    /// # Args
+    ///
+    /// * `style` - 描画の設定
+    ///
+    /// # Return
+    ///
+    /// * 設定の値が範囲外の場合や、フォントファイルを読み込めない場合はエラー
+    pub fn new(style: RenderStyle) -> Result<Self> {
+        style.validate()?;
+        let font = match &style.font {
+            Some(path) => load_font_file(path, style.font_index)?,
+            None => {
+                let font = Vec::from(include_bytes!("RobotoMono.ttf") as &[u8]);
+                Font::try_from_vec(font).unwrap()
+            }
+        };
+        let palette = Palette::new(&style.palette);
+        let renderer = Self {
+            style,
+            font,
+            palette,
+        };
+        renderer.warn_missing_glyphs();
+        Ok(renderer)
+    }
+
+    /// 描画の設定を返します。
+    pub fn style(&self) -> &RenderStyle {
+        &self.style
+    }
+
+    /// 描画の設定を変更します。フォントのパスが変わった場合はフォントを読み込み直します。
+    ///
+    /// クラスの色は `palette` から作り直すため、`set_class_color` で登録した色は取り消されます。
+    ///
+    /// # Return
+    ///
+    /// * 設定の値が範囲外の場合や、フォントファイルを読み込めない場合はエラー
+    pub fn set_style(&mut self, style: RenderStyle) -> Result<&mut Self> {
+        if style.font != self.style.font || style.font_index != self.style.font_index {
+            *self = Self::new(style)?;
+            return Ok(self);
+        }
+        style.validate()?;
+        self.palette = Palette::new(&style.palette);
+        self.style = style;
+        self.warn_missing_glyphs();
+        Ok(self)
+    }
+
+    /// クラスの色の対応表を返します。
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// クラスの色の対応表を設定します。
+    pub fn set_palette(&mut self, palette: Palette) -> &mut Self {
+        self.palette = palette;
+        self
+    }
+
+    /// クラスの色を登録します。
+    ///
+    /// # Args
+    ///
+    /// * `class` - クラス
+    /// * `color` - 色 (RGB)
+    pub fn set_class_color(&mut self, class: u8, color: [u8; 3]) -> &mut Self {
+        self.palette.set(class, color);
+        self
+    }
+
+    /// ラベルのフォントを、フォントデータ (TTF・OTF・TTC) から設定します。
+    ///
+    /// # Args
+    ///
+    /// * `data` - フォントファイルの内容
+    /// * `index` - フォントコレクション (TTC) の場合に使うフォントの番号。それ以外は0
+    ///
+    /// # Return
+    ///
+    /// * フォントとして読み込めない場合はエラー
+    pub fn set_font(&mut self, data: Vec<u8>, index: u32) -> Result<&mut Self> {
+        self.font = Font::try_from_vec_and_index(data, index)
+            .ok_or_else(|| anyhow!("Invalid font data (index {})", index))?;
+        self.style.font = None;
+        self.style.font_index = index;
+        self.warn_missing_glyphs();
+        Ok(self)
+    }
+
+    /// ラベルのフォントを、フォントファイルから設定します。
+    ///
+    /// # Args
+    ///
+    /// * `path` - フォントファイルのパス
+    /// * `index` - フォントコレクション (TTC) の場合に使うフォントの番号。それ以外は0
+    ///
+    /// # Return
+    ///
+    /// * フォントファイルを読み込めない場合はエラー
+    pub fn set_font_file<P: AsRef<Path>>(&mut self, path: P, index: u32) -> Result<&mut Self> {
+        self.font = load_font_file(path.as_ref(), index)?;
+        self.style.font = Some(path.as_ref().to_path_buf());
+        self.style.font_index = index;
+        self.warn_missing_glyphs();
+        Ok(self)
+    }
+
+    /// ラベルのフォントにない文字を返します。空白と制御文字は除きます。
+    pub fn missing_glyphs(&self, text: &str) -> Vec<char> {
+        let mut missing: Vec<char> = text
+            .chars()
+            .filter(|c| !c.is_whitespace() && !c.is_control() && self.font.glyph(*c).id().0 == 0)
+            .collect();
+        missing.dedup();
+        missing
+    }
+
+    /// クラス名にフォントにない文字がある場合に警告を出力します。
+    fn warn_missing_glyphs(&self) {
+        for name in self.style.class_names.iter() {
+            let missing = self.missing_glyphs(name);
+            if !missing.is_empty() {
+                warn!(
+                    "The label font has no glyphs for {:?} in class name {:?}; set render.font to a font that covers them",
+                    missing.iter().collect::<String>(),
+                    name
+                );
+            }
+        }
+    }
+
+    /// クラスの色を返します。
+    pub fn class_color(&self, class: u8) -> Rgb<u8> {
+        self.palette.color(class)
+    }
+
+    /// テキストをラベルのフォントで描画したときの幅を返します。
+    pub(crate) fn text_width(&self, text: &str, font_size: f32) -> f32 {
+        text_width(&self.font, Scale::uniform(font_size), text)
+    }
+
+    /// 検出結果のラベルのテキストを返します。
+    pub(crate) fn label(&self, d: &DetectionData) -> String {
+        let mut text = match self.style.class_names.get(d.class as usize) {
+            Some(name) => format!("{}: {:.2}", name, d.confidence),
+            None => format!("{}: {:.2}", d.class, d.confidence),
+        };
+        if let Some(sub_state) = d.sub_state {
+            text += &format!(" {}", sub_state);
+        }
+        text
+    }
+
+    /// 画像上に検出結果を描画します。信頼度の高い検出結果ほど手前に描画されます。
+    ///
+    /// # Args
+    ///
+    /// * `img` - 検出結果を描画する画像 (in-place)。RGBまたはRGBAの画像
+    /// * `d_result` - 検出結果の配列
+    pub fn draw<P: DrawPixel>(
+        &self,
+        img: &mut ImageBuffer<P, Vec<u8>>,
+        d_result: &[DetectionData],
+    ) {
+        let mut sorted = d_result.to_vec();
+        sorted.sort_by(|a, b| a.confidence.total_cmp(&b.confidence));
+
+        let t = self.style.line_thickness;
+        for d in sorted.iter() {
+            let color = self.class_color(d.class);
+
+            let x1 = d.x1.round();
+            let y1 = d.y1.round();
+            let x2 = d.x2.round();
+            let y2 = d.y2.round();
+
+            if self.style.fill_alpha > 0. {
+                fill_rect_blend(img, x1, y1, x2, y2, color, self.style.fill_alpha);
+            }
+            match self.style.box_style {
+                BoxStyle::Full => draw_rect(img, x1, y1, x2, y2, t, color),
+                BoxStyle::Corners { length } => {
+                    let l = ((x2 - x1).min(y2 - y1) * length).round().max(t);
+                    draw_line(img, x1, y1, x1 + l, y1, t, color);
+                    draw_line(img, x1, y1, x1, y1 + l, t, color);
+                    draw_line(img, x2 - l, y1, x2, y1, t, color);
+                    draw_line(img, x2, y1, x2, y1 + l, t, color);
+                    draw_line(img, x1, y2, x1 + l, y2, t, color);
+                    draw_line(img, x1, y2 - l, x1, y2, t, color);
+                    draw_line(img, x2 - l, y2, x2, y2, t, color);
+                    draw_line(img, x2, y2 - l, x2, y2, t, color);
+                }
+            }
+            if self.style.confidence_bar {
+                // 枠の外側の下に、幅を信頼度に比例させたバーを描画する
+                let h = (t * 1.5).max(4.);
+                let by = y2 - (t / 2.).floor() + t + 1.;
+                let bx1 = x1 - (t / 2.).floor();
+                let bx2 = x2 - (t / 2.).floor() + t;
+                let filled = bx1 + ((bx2 - bx1) * d.confidence.clamp(0., 1.)).round();
+                fill_rect_blend(img, bx1, by, bx2, by + h, Rgb(BAR_BACKGROUND), 1.);
+                fill_rect_blend(img, bx1, by, filled, by + h, color, 1.);
+            }
+
+            if self.style.font_size > 0. {
+                let text = self.label(d);
+                draw_label(
+                    img,
+                    x1,
+                    y1,
+                    t,
+                    color,
+                    &self.font,
+                    self.style.font_size,
+                    &text,
+                );
+            }
+        }
+    }
+
+    /// 透明な背景に検出結果を描画した画像を作成します。
+    ///
+    /// 元の画像を変更・複製せずに、映像のパイプラインやWebのキャンバスで元の画像に重ねるときに使います。
+    /// 元の画像に重ねた結果は、`draw` で元の画像に直接描画した結果と同じになります。
+    ///
+    /// # Args
+    ///
+    /// * `width`, `height` - 元の画像の幅と高さ
+    /// * `d_result` - 検出結果の配列
+    ///
+    /// # Return
+    ///
+    /// * 検出結果以外が透明なRGBAの画像
+    pub fn overlay(&self, width: u32, height: u32, d_result: &[DetectionData]) -> RgbaImage {
+        let mut img = RgbaImage::new(width, height);
+        self.draw(&mut img, d_result);
+        img
+    }
+
+    /// 画像の隅に、FPS・処理時間・フレーム番号・クラスごとの検出数を描画します。
+    ///
+    /// # Args
+    ///
+    /// * `img` - 集計結果を描画する画像 (in-place)
+    /// * `telemetry` - 集計結果
+    pub fn draw_telemetry<P: DrawPixel>(
+        &self,
+        img: &mut ImageBuffer<P, Vec<u8>>,
+        telemetry: &Telemetry,
+    ) {
+        let size = self.style.telemetry_font_size;
+        let scale = Scale::uniform(size);
+        let line_h = (size * 1.25).round();
+        let pad = 6.;
+        let swatch = (size * 0.6).round();
+
+        let mut lines: Vec<(Option<Rgb<u8>>, String)> = vec![
+            (None, format!("FPS {:.1}", telemetry.fps)),
+            (
+                None,
+                format!("latency {:.1} ms", telemetry.latency.as_secs_f64() * 1000.),
+            ),
+            (None, format!("frame {}", telemetry.frame_id)),
+        ];
+        for (&class, &count) in telemetry.class_counts.iter() {
+            let name = match self.style.class_names.get(class as usize) {
+                Some(name) => name.clone(),
+                None => class.to_string(),
+            };
+            lines.push((
+                Some(self.class_color(class)),
+                format!("{}: {}", name, count),
+            ));
+        }
+
+        let text_x = |color: &Option<Rgb<u8>>| match color {
+            Some(_) => pad + swatch + pad / 2.,
+            None => pad,
+        };
+        let box_w = lines
+            .iter()
+            .map(|(c, text)| text_x(c) + text_width(&self.font, scale, text).ceil() + pad)
+            .fold(0., f32::max);
+        let box_h = line_h * lines.len() as f32 + pad * 2.;
+
+        let (w, h) = (img.width() as f32, img.height() as f32);
+        let x0 = match self.style.telemetry_corner {
+            Corner::TopLeft | Corner::BottomLeft => TELEMETRY_MARGIN,
+            Corner::TopRight | Corner::BottomRight => w - TELEMETRY_MARGIN - box_w,
+        }
+        .max(0.);
+        let y0 = match self.style.telemetry_corner {
+            Corner::TopLeft | Corner::TopRight => TELEMETRY_MARGIN,
+            Corner::BottomLeft | Corner::BottomRight => h - TELEMETRY_MARGIN - box_h,
+        }
+        .max(0.);
+        fill_rect_blend(
+            img,
+            x0,
+            y0,
+            x0 + box_w,
+            y0 + box_h,
+            Rgb([0, 0, 0]),
+            TELEMETRY_BACKGROUND_ALPHA,
+        );
+
+        let v_metrics = self.font.v_metrics(scale);
+        let text_h = v_metrics.ascent - v_metrics.descent;
+        for (i, (color, text)) in lines.iter().enumerate() {
+            let y = y0 + pad + line_h * i as f32;
+            if let Some(color) = color {
+                let sy = y + ((line_h - swatch) / 2.).round();
+                fill_rect_blend(
+                    img,
+                    x0 + pad,
+                    sy,
+                    x0 + pad + swatch,
+                    sy + swatch,
+                    *color,
+                    1.,
+                );
+            }
+            draw_text_blend(
+                img,
+                (
+                    (x0 + text_x(color)) as i32,
+                    (y + (line_h - text_h) / 2.) as i32,
+                ),
+                scale,
+                &self.font,
+                text,
+                Rgb([255, 255, 255]),
+            );
+        }
+    }
+
+    /// 画像上にアンチエイリアスをかけた線分を描画します。
+    ///
+    /// # Args
+    ///
+    /// * `img` - 線分を描画する画像 (in-place)
+    /// * `start`, `end` - 線分の始点と終点の座標
+    /// * `color` - 線の色
+    pub fn draw_segment<P: DrawPixel>(
+        &self,
+        img: &mut ImageBuffer<P, Vec<u8>>,
+        start: (f32, f32),
+        end: (f32, f32),
+        color: Rgb<u8>,
+    ) {
+        drawing::draw_thick_line(img, start, end, self.style.line_thickness, color);
+    }
+
+    /// 画像上にアンチエイリアスをかけた多角形の輪郭を描画します。頂点が2つの場合は線分を描画します。
+    ///
+    /// # Args
+    ///
+    /// * `img` - 多角形を描画する画像 (in-place)
+    /// * `polygon` - 多角形の頂点の座標
+    /// * `color` - 線の色
+    pub fn draw_polygon<P: DrawPixel>(
+        &self,
+        img: &mut ImageBuffer<P, Vec<u8>>,
+        polygon: &[(f32, f32)],
+        color: Rgb<u8>,
+    ) {
+        drawing::draw_polygon(img, polygon, self.style.line_thickness, color);
+    }
+}
+
+/// フォントファイルを読み込みます。
+fn load_font_file(path: &Path, index: u32) -> Result<Font<'static>> {
+    let data = fs::read(path).with_context(|| format!("Can't read {}", path.display()))?;
+    Font::try_from_vec_and_index(data, index)
+        .ok_or_else(|| anyhow!("Invalid font file {} (index {})", path.display(), index))
+}
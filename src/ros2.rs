@@ -0,0 +1,155 @@
+//! 検出結果をROS 2のトピックとして配信するモジュール
+//!
+//! `ros2` フィーチャを有効にした場合のみ利用できます。ビルドにはROS 2の環境 (`vision_msgs` を含む) が必要です。
+//!
+//! * 検出結果 - `vision_msgs/Detection2DArray`
+//! * バウンディングボックスを描画した画像 - `sensor_msgs/Image` (`rgb8`)
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use image::RgbImage;
+use r2r::builtin_interfaces::msg::Time;
+use r2r::sensor_msgs::msg::Image;
+use r2r::std_msgs::msg::Header;
+use r2r::vision_msgs::msg::{
+    BoundingBox2D, Detection2D, Detection2DArray, ObjectHypothesis, ObjectHypothesisWithPose,
+    Point2D, Pose2D,
+};
+use r2r::{Node, Publisher, QosProfile};
+
+use crate::detection_result::DetectionData;
+use crate::img_proc::draw_bbox;
+
+/// 検出結果をROS 2のトピックに配信する構造体
+pub struct Ros2Publisher {
+    node: Node,
+    detections: Publisher<Detection2DArray>,
+    image: Option<Publisher<Image>>,
+    frame_id: String,
+    font_size: f32,
+    line_thickness: f32,
+}
+
+impl Ros2Publisher {
+    /// ノードを作成し、検出結果のトピックを配信する準備をします。
+    ///
+    /// # Args
+    /// * `node_name` - ノード名
+    /// * `namespace` - 名前空間
+    /// * `topic` - 検出結果を配信するトピック名
+    pub fn new(node_name: &str, namespace: &str, topic: &str) -> Result<Self> {
+        let ctx = r2r::Context::create()?;
+        let mut node = Node::create(ctx, node_name, namespace)?;
+        let detections = node.create_publisher(topic, QosProfile::default())?;
+        Ok(Self {
+            node,
+            detections,
+            image: None,
+            frame_id: "camera".to_string(),
+            font_size: 14.,
+            line_thickness: 2.,
+        })
+    }
+
+    /// バウンディングボックスを描画した画像を配信するトピックを作成します。
+    ///
+    /// # Args
+    /// * `topic` - 画像を配信するトピック名
+    pub fn enable_image(&mut self, topic: &str) -> Result<()> {
+        self.image = Some(self.node.create_publisher(topic, QosProfile::default())?);
+        Ok(())
+    }
+
+    /// メッセージのヘッダに設定する座標フレームのIDを設定します。
+    pub fn set_frame_id(&mut self, frame_id: &str) -> &mut Self {
+        self.frame_id = frame_id.to_string();
+        self
+    }
+
+    /// 配信する画像に描画するラベルの文字の大きさと線の太さを設定します。
+    pub fn set_draw_style(&mut self, font_size: f32, line_thickness: f32) -> &mut Self {
+        self.font_size = font_size;
+        self.line_thickness = line_thickness;
+        self
+    }
+
+    /// 1フレーム分の検出結果を配信します。
+    ///
+    /// # Args
+    /// * `detections` - 検出結果
+    /// * `img` - 検出結果の座標系に対応する画像。画像のトピックがない場合やNoneの場合は画像を配信しません
+    pub fn publish(&mut self, detections: &[DetectionData], img: Option<&RgbImage>) -> Result<()> {
+        let header = self.header();
+
+        let msg = Detection2DArray {
+            header: header.clone(),
+            detections: detections
+                .iter()
+                .map(|d| {
+                    let (cx, cy) = d.center();
+                    Detection2D {
+                        header: header.clone(),
+                        results: vec![ObjectHypothesisWithPose {
+                            hypothesis: ObjectHypothesis {
+                                class_id: d.class.to_string(),
+                                score: d.confidence as f64,
+                            },
+                            ..Default::default()
+                        }],
+                        bbox: BoundingBox2D {
+                            center: Pose2D {
+                                position: Point2D {
+                                    x: cx as f64,
+                                    y: cy as f64,
+                                },
+                                theta: 0.,
+                            },
+                            size_x: d.width() as f64,
+                            size_y: d.height() as f64,
+                        },
+                        ..Default::default()
+                    }
+                })
+                .collect(),
+        };
+        self.detections.publish(&msg)?;
+
+        if let (Some(publisher), Some(img)) = (&self.image, img) {
+            let mut annotated = img.clone();
+            draw_bbox(
+                &mut annotated,
+                detections,
+                self.font_size,
+                self.line_thickness,
+            );
+            let msg = Image {
+                header,
+                height: annotated.height(),
+                width: annotated.width(),
+                encoding: "rgb8".to_string(),
+                is_bigendian: 0,
+                step: annotated.width() * 3,
+                data: annotated.into_raw(),
+            };
+            publisher.publish(&msg)?;
+        }
+
+        self.node.spin_once(Duration::ZERO);
+        Ok(())
+    }
+
+    /// 現在時刻のヘッダを作成します。
+    fn header(&self) -> Header {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Header {
+            stamp: Time {
+                sec: now.as_secs() as i32,
+                nanosec: now.subsec_nanos(),
+            },
+            frame_id: self.frame_id.clone(),
+        }
+    }
+}
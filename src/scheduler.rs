@@ -0,0 +1,212 @@
+//! 目標FPSを維持するためにフレームの間引きや縮小を行うモジュール
+
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Result};
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+use crate::detection_result::FrameResult;
+
+/// フレームに対する処理の判定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameDecision {
+    /// フレームを処理する (縮小率)
+    Process(f32),
+    /// フレームを読み飛ばす
+    Skip,
+}
+
+/// スケジューラの統計情報
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SchedulerStats {
+    /// 処理したフレーム数
+    pub processed: u64,
+    /// 読み飛ばしたフレーム数
+    pub skipped: u64,
+    /// 縮小して処理したフレーム数
+    pub downscaled: u64,
+    /// 最後に処理したフレームと同じ大きさ (元の大きさか縮小) のフレームの処理時間の指数移動平均
+    pub avg_latency: Duration,
+}
+
+/// 処理時間を計測し、目標FPSを維持するようにフレームを間引く・縮小するスケジューラ
+///
+/// 計測した処理時間が目標のフレーム間隔を超える場合は、縮小が有効なら縮小率を下げ、
+/// それでも間に合わない間は次のフレームの処理が前のフレームの処理時間の分だけ遅れるとみなして読み飛ばします。
+///
+/// YOLOの入力サイズは固定のため、縮小しても処理時間は縮小率に比例して短くなりません。
+/// そのため処理時間は元の大きさのフレームと縮小したフレームで別々に平均し、
+/// 縮小率は元の大きさの処理時間から、読み飛ばしはそのフレームと同じ大きさで計測した処理時間から判定します。
+pub struct AdaptiveScheduler {
+    target_interval: Duration,
+    alpha: f64,
+    downscale_en: bool,
+    min_scale: f32,
+    /// 元の大きさのフレームの処理時間の指数移動平均 (秒)
+    ema_latency: Option<f64>,
+    /// 縮小したフレームの処理時間の指数移動平均 (秒)
+    ema_scaled_latency: Option<f64>,
+    /// 最後に処理すると判定したフレームの縮小率
+    last_scale: f32,
+    last_start: Option<Instant>,
+    stats: SchedulerStats,
+}
+
+impl AdaptiveScheduler {
+    /// 新しい `AdaptiveScheduler` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `target_fps` - 目標FPS
+    ///
+    /// # Return
+    /// * 目標FPSが正の有限の値でない場合はエラー
+    pub fn new(target_fps: f64) -> Result<Self> {
+        ensure!(
+            target_fps.is_finite() && target_fps > 0.,
+            "Target FPS must be positive, got {}",
+            target_fps
+        );
+        Ok(Self {
+            target_interval: Duration::from_secs_f64(1. / target_fps),
+            alpha: 0.2,
+            downscale_en: false,
+            min_scale: 0.5,
+            ema_latency: None,
+            ema_scaled_latency: None,
+            last_scale: 1.,
+            last_start: None,
+            stats: SchedulerStats::default(),
+        })
+    }
+
+    /// 処理時間が目標を超えるときにフレームを縮小するかを設定します。
+    ///
+    /// # Args
+    /// * `downscale_en` - 縮小を行うか
+    /// * `min_scale` - 縮小率の下限
+    pub fn set_downscale(&mut self, downscale_en: bool, min_scale: f32) -> &mut Self {
+        self.downscale_en = downscale_en;
+        self.min_scale = min_scale.clamp(0.01, 1.);
+        self
+    }
+
+    /// 処理時間の指数移動平均の係数を設定します。
+    pub fn set_smoothing(&mut self, alpha: f64) -> &mut Self {
+        self.alpha = alpha.clamp(0., 1.);
+        self
+    }
+
+    /// 統計情報を返します。
+    pub fn stats(&self) -> SchedulerStats {
+        self.stats
+    }
+
+    /// 到着したフレームを処理するかを判定します。
+    ///
+    /// # Args
+    /// * `now` - フレームの到着時刻
+    ///
+    /// # Return
+    /// * フレームに対する処理の判定
+    pub fn next_frame(&mut self, now: Instant) -> FrameDecision {
+        let target = self.target_interval.as_secs_f64();
+        let scale = match self.ema_latency {
+            Some(latency) if self.downscale_en && latency > 0. => {
+                ((target / latency) as f32).clamp(self.min_scale, 1.)
+            }
+            _ => 1.,
+        };
+
+        if let Some(last) = self.last_start {
+            // 縮小しても処理時間が目標の間隔を超える場合は、処理時間の分だけ次のフレームを待つ
+            let expected = if scale < 1. {
+                self.ema_scaled_latency.or(self.ema_latency)
+            } else {
+                self.ema_latency
+            };
+            let interval = Duration::from_secs_f64(target.max(expected.unwrap_or(0.)));
+            if now.duration_since(last) < interval {
+                self.stats.skipped += 1;
+                return FrameDecision::Skip;
+            }
+        }
+        self.last_start = Some(now);
+        self.last_scale = scale;
+        self.stats.processed += 1;
+        if scale < 1. {
+            self.stats.downscaled += 1;
+        }
+        FrameDecision::Process(scale)
+    }
+
+    /// フレームの処理時間を報告します。
+    ///
+    /// 最後に `next_frame` で処理すると判定したフレームの処理時間として、その縮小率の平均に加えます。
+    ///
+    /// # Args
+    /// * `latency` - 処理時間
+    pub fn report_latency(&mut self, latency: Duration) {
+        let latency = latency.as_secs_f64();
+        let ema_latency = if self.last_scale < 1. {
+            &mut self.ema_scaled_latency
+        } else {
+            &mut self.ema_latency
+        };
+        let ema = match *ema_latency {
+            Some(ema) => ema * (1. - self.alpha) + latency * self.alpha,
+            None => latency,
+        };
+        *ema_latency = Some(ema);
+        self.stats.avg_latency = Duration::from_secs_f64(ema);
+    }
+
+    /// `YoloV3Tiny::detect_frame` の結果から、フレームの処理時間を報告します。
+    ///
+    /// # Args
+    /// * `result` - フレームの検出結果
+    pub fn report_frame(&mut self, result: &FrameResult) {
+        self.report_latency(result.latency);
+    }
+
+    /// 判定に従ってフレームを処理し、処理時間を記録します。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    /// * `f` - 縮小後の画像と縮小率を受け取る処理。検出結果の座標は縮小後の画像の座標系になります
+    ///
+    /// # Return
+    /// * 処理結果。フレームを読み飛ばした場合はNone
+    pub fn process<T, F>(&mut self, img: &DynamicImage, f: F) -> Option<Result<T>>
+    where
+        F: FnOnce(&DynamicImage, f32) -> Result<T>,
+    {
+        let start = Instant::now();
+        let scale = match self.next_frame(start) {
+            FrameDecision::Skip => return None,
+            FrameDecision::Process(scale) => scale,
+        };
+
+        let result = if scale < 1. {
+            f(&downscale(img, scale), scale)
+        } else {
+            f(img, 1.)
+        };
+        self.report_latency(start.elapsed());
+        Some(result)
+    }
+}
+
+/// 画像を指定した倍率で縮小します。
+///
+/// # Args
+/// * `img` - 入力画像
+/// * `scale` - 縮小率
+///
+/// # Return
+/// * 縮小した画像
+pub fn downscale(img: &DynamicImage, scale: f32) -> DynamicImage {
+    let w = ((img.width() as f32 * scale).round() as u32).max(1);
+    let h = ((img.height() as f32 * scale).round() as u32).max(1);
+    img.resize_exact(w, h, FilterType::Triangle)
+}
@@ -0,0 +1,272 @@
+//! 組み込みの既知のデータでハードウェアを検査するモジュール
+//!
+//! 読み込んだ重みに依存しない小さなテンソルを1回だけ処理し、出力が期待される結果と一致するかを確認します。
+//! 起動時に実行すると、推論結果がおかしい原因が「重みの誤り」か「ビットストリームやDMAの故障」かを切り分けられます。
+//!
+//! * 最大プーリング - 出力は全て異なる入力の値で、入力の最大値を含む
+//! * アップサンプリング - 出力には入力の各値がちょうど4回ずつ現れる
+//! * 畳み込み - 全て1.0の重みとチャネルごとに異なるバイアスで、ソフトウェアで計算した結果と一致する
+//!
+//! 最大プーリングとアップサンプリングは、データの並びに依存しない性質で判定します。
+
+use std::collections::HashMap;
+
+use anyhow::{bail, ensure, Context, Result};
+use log::info;
+
+use crate::layer_group::{Activation, LayerGroup, PostProcess};
+use crate::yolo::YoloController;
+
+/// 検査の関数
+type SelfTest = fn(&mut YoloController) -> Result<()>;
+
+/// テストテンソルの値の並びを決める乱数の種
+const SEED: u32 = 0x2545_f491;
+
+/// `1..=len` の値を並べ替えたテストテンソルを作ります。
+///
+/// 値は全て異なり、同じ `len` に対しては常に同じテンソルになります。
+fn test_tensor(len: usize) -> Vec<i16> {
+    let mut data: Vec<i16> = (1..=len as i16).collect();
+    let mut state = SEED;
+    for i in (1..len).rev() {
+        // xorshift32
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        data.swap(i, state as usize % (i + 1));
+    }
+    data
+}
+
+/// 最大プーリングのテストです。
+fn test_max_pool(yc: &mut YoloController) -> Result<()> {
+    let mut l = LayerGroup::new(
+        26,
+        26,
+        32,
+        1,
+        13,
+        13,
+        32,
+        1,
+        true,
+        Activation::Linear,
+        PostProcess::MaxPool,
+        2,
+    );
+    let inputs = test_tensor(l.input_size as usize);
+    let expected_len = l.output_size as usize;
+    l.inputs = Some(inputs.clone());
+
+    let outputs = run(yc, l)?;
+    ensure!(
+        outputs.len() == expected_len,
+        "max pool returned {} values, expected {}",
+        outputs.len(),
+        expected_len
+    );
+    let mut sorted = outputs.clone();
+    sorted.sort_unstable();
+    sorted.dedup();
+    ensure!(
+        sorted.len() == outputs.len(),
+        "max pool returned duplicated values"
+    );
+    let max = inputs.iter().max().copied().unwrap_or_default();
+    ensure!(
+        sorted.last() == Some(&max),
+        "max pool output does not contain the maximum input {}",
+        max
+    );
+    ensure!(
+        sorted.first().is_some_and(|&v| v >= 1),
+        "max pool returned a value that is not in the input"
+    );
+    Ok(())
+}
+
+/// アップサンプリングのテストです。
+fn test_upsample(yc: &mut YoloController) -> Result<()> {
+    let mut l = LayerGroup::new(
+        13,
+        13,
+        32,
+        1,
+        26,
+        26,
+        32,
+        1,
+        true,
+        Activation::Linear,
+        PostProcess::Upsample,
+        2,
+    );
+    let inputs = test_tensor(l.input_size as usize);
+    let expected_len = l.output_size as usize;
+    l.inputs = Some(inputs.clone());
+
+    let outputs = run(yc, l)?;
+    ensure!(
+        outputs.len() == expected_len,
+        "upsample returned {} values, expected {}",
+        outputs.len(),
+        expected_len
+    );
+    let mut counts: HashMap<i16, usize> = HashMap::new();
+    for v in outputs {
+        *counts.entry(v).or_default() += 1;
+    }
+    for v in &inputs {
+        let count = counts.remove(v).unwrap_or_default();
+        ensure!(
+            count == 4,
+            "upsample returned {} {} times, expected 4",
+            v,
+            count
+        );
+    }
+    ensure!(
+        counts.is_empty(),
+        "upsample returned {} values that are not in the input",
+        counts.len()
+    );
+    Ok(())
+}
+
+/// 畳み込みのテストの重み (Q8.8の1.0)
+///
+/// 積を固定小数点数に戻すときの丸め方によらず、入力の値がそのまま足し合わされます。
+const CONV_WEIGHT: i16 = 1 << 8;
+
+/// 全ての重みが1.0の3×3の畳み込み (ゼロパディング) をソフトウェアで計算します。
+///
+/// 入力と出力は、IPと同じく行優先の画素ごとにチャネルを並べた固定小数点数です。
+///
+/// # Args
+/// * `inputs` - 入力
+/// * `biases` - 出力チャネルごとのバイアス
+/// * `width` - 入力と出力の幅
+/// * `height` - 入力と出力の高さ
+/// * `input_ch` - 入力のチャネル数
+fn conv_reference(
+    inputs: &[i16],
+    biases: &[i16],
+    width: usize,
+    height: usize,
+    input_ch: usize,
+) -> Vec<i16> {
+    let mut outputs = Vec::with_capacity(width * height * biases.len());
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = 0i32;
+            for sy in y.saturating_sub(1)..(y + 2).min(height) {
+                for sx in x.saturating_sub(1)..(x + 2).min(width) {
+                    let p = (sy * width + sx) * input_ch;
+                    sum += inputs[p..p + input_ch]
+                        .iter()
+                        .map(|&v| v as i32)
+                        .sum::<i32>();
+                }
+            }
+            outputs.extend(biases.iter().map(|&b| (sum + b as i32) as i16));
+        }
+    }
+    outputs
+}
+
+/// 畳み込みのテストです。
+fn test_conv(yc: &mut YoloController) -> Result<()> {
+    let mut l = LayerGroup::new(
+        13,
+        13,
+        32,
+        1,
+        13,
+        13,
+        32,
+        1,
+        false,
+        Activation::Linear,
+        PostProcess::None,
+        2,
+    );
+    // 9画素×32チャネルを足し合わせても飽和しないよう、入力は0-3にする
+    let inputs: Vec<i16> = test_tensor(l.input_size as usize)
+        .into_iter()
+        .map(|v| v % 4)
+        .collect();
+    let biases: Vec<i16> = (1..=l.output_ch as i16).map(|o| o * 16).collect();
+    let expected = conv_reference(
+        &inputs,
+        &biases,
+        l.input_width as usize,
+        l.input_height as usize,
+        l.input_ch as usize,
+    );
+    l.inputs = Some(inputs);
+    l.weights = Some(vec![CONV_WEIGHT; (12 * l.input_ch * l.output_ch) as usize]);
+    l.biases = Some(biases);
+    let output_ch = l.output_ch as usize;
+    let width = l.output_width as usize;
+
+    let outputs = run(yc, l)?;
+    ensure!(
+        outputs.len() == expected.len(),
+        "conv returned {} values, expected {}",
+        outputs.len(),
+        expected.len()
+    );
+    if let Some(pos) = outputs.iter().zip(&expected).position(|(a, b)| a != b) {
+        let pixel = pos / output_ch;
+        bail!(
+            "conv returned {} at (x={}, y={}, ch={}), expected {}",
+            outputs[pos],
+            pixel % width,
+            pixel / width,
+            pos % output_ch,
+            expected[pos]
+        );
+    }
+    Ok(())
+}
+
+/// テスト用のレイヤーグループだけを処理し、出力を返します。
+fn run(yc: &mut YoloController, layer_group: LayerGroup) -> Result<Vec<i16>> {
+    let saved = std::mem::replace(&mut yc.layer_groups, vec![layer_group]);
+    let result = yc.start_layer_processing(0);
+    let tested = std::mem::replace(&mut yc.layer_groups, saved);
+    result?;
+    tested
+        .into_iter()
+        .next()
+        .and_then(|l| l.outputs)
+        .context("Self-test layer group has no outputs")
+}
+
+impl YoloController {
+    /// 組み込みの既知のデータでスイッチ・DMA・YOLOのIPを検査します。
+    ///
+    /// 読み込んだ重みとバイアスは使わず、レイヤーグループの状態も変更しません。
+    /// 各検査の出力は前の検査の出力と異なるため、DMAが出力を書き込んでいない場合も検出できます。
+    ///
+    /// # 返り値
+    /// * Result。いずれかの検査に失敗した場合は、失敗した検査を示すエラー
+    pub fn self_test(&mut self) -> Result<()> {
+        let tests: [(&str, SelfTest); 3] = [
+            ("max pool", test_max_pool),
+            ("upsample", test_upsample),
+            ("conv", test_conv),
+        ];
+        for (name, test) in tests {
+            test(self).with_context(|| {
+                format!(
+                    "Hardware self-test ({}) failed. The bitstream or DMA may be broken",
+                    name
+                )
+            })?;
+            info!("Hardware self-test ({}) passed", name);
+        }
+        Ok(())
+    }
+}
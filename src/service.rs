@@ -0,0 +1,213 @@
+//! YOLOv3-Tinyをワーカースレッドで動かし、複数のスレッドから推論を依頼するためのモジュール
+
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+use anyhow::{anyhow, bail, Context, Result};
+use image::DynamicImage;
+
+use crate::detection_result::{DetectionData, ImageSpace, LetterboxSpace};
+use crate::yolov3_tiny::YoloV3Tiny;
+
+/// 推論結果を受け取るためのReceiver
+pub type DetectionReceiver<S = ImageSpace> = mpsc::Receiver<Result<Vec<DetectionData<S>>>>;
+
+/// ワーカースレッドへの依頼
+enum Job {
+    /// 画像の推論 (画像, 回転角度, 結果の送信先)
+    Image(DynamicImage, u32, mpsc::Sender<Result<Vec<DetectionData>>>),
+    /// 前処理済みの入力データの推論 (入力データ, 結果の送信先)
+    Tensor(
+        Vec<i16>,
+        mpsc::Sender<Result<Vec<DetectionData<LetterboxSpace>>>>,
+    ),
+    /// スレッドの停止
+    Stop,
+}
+
+/// `YoloService` に推論を依頼するためのハンドル
+///
+/// cloneして複数のスレッドから利用できます。
+#[derive(Clone)]
+pub struct YoloServiceHandle {
+    job_tx: mpsc::Sender<Job>,
+}
+
+impl YoloServiceHandle {
+    /// 画像の推論を依頼します。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    /// * `rotate_angle` - 回転角度
+    ///
+    /// # Return
+    /// * 推論結果を受け取るためのReceiver
+    pub fn submit(&self, img: DynamicImage, rotate_angle: u32) -> Result<DetectionReceiver> {
+        let (result_tx, result_rx) = mpsc::channel();
+        self.job_tx
+            .send(Job::Image(img, rotate_angle, result_tx))
+            .map_err(|_| anyhow!("YoloService is stopped"))?;
+        Ok(result_rx)
+    }
+
+    /// 前処理済みの入力データの推論を依頼します。
+    ///
+    /// # Args
+    /// * `input_data` - 入力データ
+    ///
+    /// # Return
+    /// * YOLOの入力データの座標系の推論結果を受け取るためのReceiver
+    pub fn submit_input(&self, input_data: Vec<i16>) -> Result<DetectionReceiver<LetterboxSpace>> {
+        let (result_tx, result_rx) = mpsc::channel();
+        self.job_tx
+            .send(Job::Tensor(input_data, result_tx))
+            .map_err(|_| anyhow!("YoloService is stopped"))?;
+        Ok(result_rx)
+    }
+
+    /// 画像の推論を依頼し、結果が得られるまで待ちます。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    /// * `rotate_angle` - 回転角度
+    ///
+    /// # Return
+    /// * 物体検出結果
+    pub fn detect(&self, img: DynamicImage, rotate_angle: u32) -> Result<Vec<DetectionData>> {
+        self.submit(img, rotate_angle)?
+            .recv()
+            .context("YoloService worker has terminated")?
+    }
+}
+
+/// `YoloV3Tiny` を専用のスレッドで所有し、チャネル経由で推論を受け付けるサービス
+pub struct YoloService {
+    /// スレッドハンドル
+    thread_handle: Option<thread::JoinHandle<()>>,
+    /// 推論を依頼するためのハンドル
+    handle: YoloServiceHandle,
+}
+
+impl YoloService {
+    /// ワーカースレッドを起動します。
+    ///
+    /// `YoloV3Tiny` はスレッド間で移動できないため、ワーカースレッド内で `factory` を呼び出して作成します。
+    ///
+    /// # Args
+    /// * `factory` - `YoloV3Tiny` を作成する関数
+    ///
+    /// # Return
+    /// * 新たな `YoloService` インスタンス。`factory` が失敗した場合はエラー
+    pub fn spawn<F>(factory: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<YoloV3Tiny> + Send + 'static,
+    {
+        let (job_tx, job_rx) = mpsc::channel();
+        let (init_tx, init_rx) = mpsc::channel();
+
+        let thread_handle = thread::spawn(move || {
+            let mut yolo = match factory() {
+                Ok(yolo) => {
+                    let _ = init_tx.send(Ok(()));
+                    yolo
+                }
+                Err(e) => {
+                    let _ = init_tx.send(Err(e));
+                    return;
+                }
+            };
+            Self::run_worker(&mut yolo, job_rx);
+        });
+
+        init_rx
+            .recv()
+            .context("YoloService worker has terminated during initialization")??;
+
+        Ok(Self {
+            thread_handle: Some(thread_handle),
+            handle: YoloServiceHandle { job_tx },
+        })
+    }
+
+    /// ハードウェア情報に含まれる全てのYOLOの階層について、それぞれ推論スレッドを起動します。
+    ///
+    /// 階層ごとに独立した `YoloV3Tiny` が作成されるため、複数のカメラの推論を並列に実行できます。
+    ///
+    /// # Args
+    /// * `hwinfo_path` - HW情報のパス
+    /// * `cls_num` - クラス数
+    /// * `obj_threshold` - オブジェクトの閾値
+    /// * `nms_threshold` - NMSの閾値
+    /// * `weights_path` - 重みとバイアスのパス
+    ///
+    /// # Return
+    /// * 階層の名前順に並べた `YoloService` のベクトル
+    pub fn spawn_all<P: AsRef<Path>>(
+        hwinfo_path: &str,
+        cls_num: usize,
+        obj_threshold: f32,
+        nms_threshold: f32,
+        weights_path: P,
+    ) -> Result<Vec<Self>> {
+        let hiers = YoloV3Tiny::hierarchies(hwinfo_path)?;
+        if hiers.is_empty() {
+            bail!("No YOLO hierarchy is found in {}", hwinfo_path);
+        }
+        hiers
+            .into_iter()
+            .map(|hier| {
+                let hwinfo_path = hwinfo_path.to_string();
+                let weights_path = weights_path.as_ref().to_path_buf();
+                Self::spawn(move || {
+                    YoloV3Tiny::new(
+                        &hwinfo_path,
+                        &hier,
+                        cls_num,
+                        obj_threshold,
+                        nms_threshold,
+                        weights_path,
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// スレッドの中身
+    fn run_worker(yolo: &mut YoloV3Tiny, job_rx: mpsc::Receiver<Job>) {
+        while let Ok(job) = job_rx.recv() {
+            match job {
+                Job::Image(img, rotate_angle, result_tx) => {
+                    let _ = result_tx.send(yolo.start_with_img_proc(&img, rotate_angle));
+                }
+                Job::Tensor(input_data, result_tx) => {
+                    let _ = result_tx.send(yolo.start(&input_data));
+                }
+                Job::Stop => break,
+            }
+        }
+    }
+
+    /// 推論を依頼するためのハンドルを返します。
+    pub fn handle(&self) -> YoloServiceHandle {
+        self.handle.clone()
+    }
+
+    /// ワーカースレッドを停止します。依頼済みの推論は停止前に処理されます。
+    pub fn stop(&mut self) -> Result<()> {
+        if let Some(thread_handle) = self.thread_handle.take() {
+            // ワーカースレッドが既に終了している場合は送信に失敗するが問題ない
+            let _ = self.handle.job_tx.send(Job::Stop);
+            if thread_handle.join().is_err() {
+                bail!("Can't join thread");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for YoloService {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
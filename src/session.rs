@@ -0,0 +1,498 @@
+//! フレームごとの入力データとYOLO層の出力を記録し、オフラインで再生するモジュール
+//!
+//! `YoloV3Tiny::set_session_record` で記録を有効にすると、フレームごとに
+//! 入力データ・13×13と26×26のYOLO層の出力・後処理の設定・後処理の結果をgzipで圧縮したファイルに書き出します。
+//! 現場で記録したファイルを持ち帰り、`replay_session` で後処理に、または `SimBackend` を使う `YoloV3Tiny` に同じ出力を流すことで、
+//! ボードがない環境でも現場と同じ検出結果を再現できます。
+//! `SessionFrame::params` を書き換えてから `SessionFrame::replay` を呼び出すと、閾値などを変えた場合の結果も確認できます。
+//!
+//! ```ignore
+//! // ボード上で記録する
+//! yolo.set_session_record(Some("/data/session.bin.gz"))?;
+//!
+//! // 机上で再生する
+//! let report = session::replay_session("session.bin.gz", ReplayMode::Postprocess)?;
+//! assert!(report.is_reproduced());
+//! ```
+//!
+//! ファイルは次の形式のバイト列 (リトルエンディアン) をgzipで圧縮したものです。
+//!
+//! ```text
+//! ヘッダ:     "YOLOSESS" バージョン(u16)
+//! フレーム:   フレーム番号(u64)
+//!             クラス数(u16) クラスの枠の数(u16) アンカーボックス(f32×12) シグモイドを後処理で適用するか(u8)
+//!             オブジェクトの閾値(f32) NMSの閾値(f32) 固定小数点数で後処理を行うか(u8)
+//!             入力データ・13×13の出力・26×26の出力 (それぞれ 要素数(u32) i16×要素数)
+//!             検出数(u32) 検出結果×検出数 (クラス(u8) x1, y1, x2, y2, コンフィデンス(f32))
+//! ```
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::warn;
+
+use crate::anchors::ANCHOR_NUM;
+use crate::detection_result::{DetectionData, LetterboxSpace};
+use crate::postprocess::{self, OutputLayout};
+use crate::sim::SimBackend;
+use crate::yolov3_tiny::YoloV3Tiny;
+
+/// ファイルの先頭のマジックナンバー
+const MAGIC: &[u8] = b"YOLOSESS";
+
+/// ファイルの形式のバージョン
+const VERSION: u16 = 1;
+
+/// 後処理の設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PostprocessParams {
+    /// クラス数
+    pub cls_num: usize,
+    /// 出力の並びとアンカーボックス
+    pub layout: OutputLayout,
+    /// オブジェクトの閾値
+    pub obj_threshold: f32,
+    /// NMSの閾値
+    pub nms_threshold: f32,
+    /// 後処理を固定小数点数のまま行うか
+    pub fixed_point_postprocess: bool,
+}
+
+impl PostprocessParams {
+    /// YOLO層の出力を後処理します。`YoloV3Tiny` と同じ後処理を行います。
+    ///
+    /// # Args
+    /// * `yolo_out_0` - 13×13のYOLO層の出力
+    /// * `yolo_out_1` - 26×26のYOLO層の出力
+    ///
+    /// # Return
+    /// * YOLOの入力データの座標系の物体検出結果
+    pub fn post_process(
+        &self,
+        yolo_out_0: &[i16],
+        yolo_out_1: &[i16],
+    ) -> Vec<DetectionData<LetterboxSpace>> {
+        let post_process = if self.fixed_point_postprocess {
+            postprocess::post_process_fixed
+        } else {
+            postprocess::post_process
+        };
+        post_process(
+            yolo_out_0,
+            yolo_out_1,
+            self.cls_num,
+            &self.layout,
+            self.obj_threshold,
+            self.nms_threshold,
+        )
+    }
+
+    /// 同じ後処理を行う `SimBackend` を作成します。
+    pub fn sim_backend(&self) -> SimBackend {
+        let mut sim = SimBackend::new(self.cls_num, self.obj_threshold, self.nms_threshold);
+        sim.set_layout(self.layout)
+            .set_fixed_point_postprocess(self.fixed_point_postprocess);
+        sim
+    }
+}
+
+/// 記録した1フレーム分のデータ
+#[derive(Debug, Clone)]
+pub struct SessionFrame {
+    /// フレーム番号
+    pub frame_id: u64,
+    /// 後処理の設定
+    pub params: PostprocessParams,
+    /// 入力データ
+    pub input: Vec<i16>,
+    /// 13×13のYOLO層の出力
+    pub yolo_out_0: Vec<i16>,
+    /// 26×26のYOLO層の出力
+    pub yolo_out_1: Vec<i16>,
+    /// 記録時の後処理の結果 (YOLOの入力データの座標系)
+    pub detections: Vec<DetectionData<LetterboxSpace>>,
+}
+
+impl SessionFrame {
+    /// 記録したYOLO層の出力を `params` の設定で後処理します。
+    ///
+    /// # Return
+    /// * YOLOの入力データの座標系の物体検出結果
+    pub fn replay(&self) -> Vec<DetectionData<LetterboxSpace>> {
+        self.params.post_process(&self.yolo_out_0, &self.yolo_out_1)
+    }
+
+    /// 記録した入力データを、記録したYOLO層の出力を返す `SimBackend` を使う `YoloV3Tiny` で処理します。
+    ///
+    /// # Return
+    /// * YOLOの入力データの座標系の物体検出結果。入力データの長さが合わない場合はエラー
+    pub fn replay_sim(&self) -> Result<Vec<DetectionData<LetterboxSpace>>> {
+        let mut sim = self.params.sim_backend();
+        sim.set_recorded_outputs(Some((self.yolo_out_0.clone(), self.yolo_out_1.clone())));
+        YoloV3Tiny::with_sim(sim).start(&self.input)
+    }
+
+    /// 後処理の結果が記録した結果と完全に一致するかを返します。
+    ///
+    /// # Args
+    /// * `detections` - 比較する検出結果
+    pub fn matches(&self, detections: &[DetectionData<LetterboxSpace>]) -> bool {
+        self.detections.len() == detections.len()
+            && self.detections.iter().zip(detections).all(|(a, b)| {
+                a.class == b.class
+                    && [a.x1, a.y1, a.x2, a.y2, a.confidence]
+                        .iter()
+                        .zip([b.x1, b.y1, b.x2, b.y2, b.confidence])
+                        .all(|(u, v)| u.to_bits() == v.to_bits())
+            })
+    }
+}
+
+/// セッションをファイルに書き出す構造体
+///
+/// 書き込みに失敗した場合は推論を止めないよう、警告を出して以降の記録をやめます。
+/// フレームごとに圧縮したデータをファイルに書き出すため、電源断などで途中で終わったファイルも直前のフレームまで読み込めます。
+pub struct SessionRecorder {
+    writer: Option<GzEncoder<BufWriter<File>>>,
+    /// 記録したフレームの数
+    frames: u64,
+}
+
+impl SessionRecorder {
+    /// セッションを書き出すファイルを作成します。既存のファイルは上書きします。
+    ///
+    /// # Args
+    /// * `path` - セッションのパス
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("Can't create {}", path.display()))?;
+        // 毎フレーム大きな入力データを圧縮するため、圧縮率より速度を優先する
+        let mut writer = GzEncoder::new(BufWriter::new(file), Compression::fast());
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self {
+            writer: Some(writer),
+            frames: 0,
+        })
+    }
+
+    /// 1フレーム分のデータを記録します。
+    ///
+    /// # Args
+    /// * `frame_id` - フレーム番号
+    /// * `params` - 後処理の設定
+    /// * `input` - 入力データ
+    /// * `yolo_out_0` - 13×13のYOLO層の出力
+    /// * `yolo_out_1` - 26×26のYOLO層の出力
+    /// * `detections` - 後処理の結果
+    pub fn record(
+        &mut self,
+        frame_id: u64,
+        params: &PostprocessParams,
+        input: &[i16],
+        yolo_out_0: &[i16],
+        yolo_out_1: &[i16],
+        detections: &[DetectionData<LetterboxSpace>],
+    ) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+
+        let mut buf = Vec::with_capacity(
+            (input.len() + yolo_out_0.len() + yolo_out_1.len()) * 2 + detections.len() * 21 + 128,
+        );
+        buf.extend_from_slice(&frame_id.to_le_bytes());
+        buf.extend_from_slice(&(params.cls_num as u16).to_le_bytes());
+        buf.extend_from_slice(&(params.layout.class_slots as u16).to_le_bytes());
+        for v in params.layout.anchors.iter().flatten() {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.push(params.layout.software_sigmoid as u8);
+        buf.extend_from_slice(&params.obj_threshold.to_le_bytes());
+        buf.extend_from_slice(&params.nms_threshold.to_le_bytes());
+        buf.push(params.fixed_point_postprocess as u8);
+        for data in [input, yolo_out_0, yolo_out_1] {
+            buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            for v in data {
+                buf.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        buf.extend_from_slice(&(detections.len() as u32).to_le_bytes());
+        for d in detections {
+            buf.push(d.class);
+            for v in [d.x1, d.y1, d.x2, d.y2, d.confidence] {
+                buf.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+
+        if let Err(e) = writer.write_all(&buf).and_then(|_| writer.flush()) {
+            warn!("Can't write the session: {}. Recording is disabled", e);
+            self.writer = None;
+            return;
+        }
+        self.frames += 1;
+    }
+
+    /// 記録したフレームの数を返します。
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// gzipの終端を書き込んでファイルを閉じます。以降のフレームは記録しません。
+    pub fn finish(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            writer
+                .finish()
+                .and_then(|mut w| w.flush())
+                .context("Can't finish the session")?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("{:#}", e);
+        }
+    }
+}
+
+/// セッションのファイルからフレームを順に読み込む構造体
+pub struct SessionReader {
+    reader: GzDecoder<BufReader<File>>,
+}
+
+impl SessionReader {
+    /// セッションのファイルを開きます。
+    ///
+    /// # Args
+    /// * `path` - セッションのパス
+    ///
+    /// # Return
+    /// * セッションのファイルでない場合や、バージョンが異なる場合はエラー
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Can't open {}", path.display()))?;
+        let mut reader = GzDecoder::new(BufReader::new(file));
+        let mut header = [0u8; 10];
+        reader
+            .read_exact(&mut header)
+            .with_context(|| format!("Can't read {}", path.display()))?;
+        ensure!(
+            &header[..8] == MAGIC,
+            "{} is not a session file",
+            path.display()
+        );
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        ensure!(
+            version == VERSION,
+            "Unsupported session version {} (expected {})",
+            version,
+            VERSION
+        );
+        Ok(Self { reader })
+    }
+
+    /// 次のフレームを読み込みます。
+    ///
+    /// # Return
+    /// * フレーム。ファイルの終わりに達した場合はNone。
+    ///   記録の途中で終わったファイルは、最後まで書き込まれたフレームまでを返します
+    pub fn next_frame(&mut self) -> Result<Option<SessionFrame>> {
+        let mut first = [0u8; 1];
+        match self.reader.read(&mut first) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                warn!("The session was not finished properly");
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        match self.read_frame(first[0]) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                warn!("The session ends in the middle of a frame");
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// フレーム番号の先頭の1バイトに続くフレームを読み込みます。
+    fn read_frame(&mut self, first: u8) -> io::Result<SessionFrame> {
+        let mut rest = [0u8; 7];
+        self.reader.read_exact(&mut rest)?;
+        let mut id = [first; 8];
+        id[1..].copy_from_slice(&rest);
+        let frame_id = u64::from_le_bytes(id);
+
+        let cls_num = self.read_u16()? as usize;
+        let class_slots = self.read_u16()? as usize;
+        let mut anchors = [[0.; 2]; ANCHOR_NUM];
+        for v in anchors.iter_mut().flatten() {
+            *v = self.read_f32()?;
+        }
+        let software_sigmoid = self.read_u8()? != 0;
+        let obj_threshold = self.read_f32()?;
+        let nms_threshold = self.read_f32()?;
+        let fixed_point_postprocess = self.read_u8()? != 0;
+        let params = PostprocessParams {
+            cls_num,
+            layout: OutputLayout {
+                class_slots,
+                anchors,
+                software_sigmoid,
+            },
+            obj_threshold,
+            nms_threshold,
+            fixed_point_postprocess,
+        };
+
+        let input = self.read_i16_vec()?;
+        let yolo_out_0 = self.read_i16_vec()?;
+        let yolo_out_1 = self.read_i16_vec()?;
+
+        let n = self.read_u32()? as usize;
+        let mut detections = Vec::with_capacity(n);
+        for _ in 0..n {
+            let class = self.read_u8()?;
+            let mut v = [0.; 5];
+            for x in v.iter_mut() {
+                *x = self.read_f32()?;
+            }
+            detections.push(DetectionData::new(class, v[0], v[1], v[2], v[3], v[4]));
+        }
+
+        Ok(SessionFrame {
+            frame_id,
+            params,
+            input,
+            yolo_out_0,
+            yolo_out_1,
+            detections,
+        })
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut b = [0u8; 1];
+        self.reader.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        let mut b = [0u8; 2];
+        self.reader.read_exact(&mut b)?;
+        Ok(u16::from_le_bytes(b))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut b = [0u8; 4];
+        self.reader.read_exact(&mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn read_f32(&mut self) -> io::Result<f32> {
+        let mut b = [0u8; 4];
+        self.reader.read_exact(&mut b)?;
+        Ok(f32::from_le_bytes(b))
+    }
+
+    fn read_i16_vec(&mut self) -> io::Result<Vec<i16>> {
+        let len = self.read_u32()? as usize;
+        let mut bytes = vec![0u8; len * 2];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect())
+    }
+}
+
+impl Iterator for SessionReader {
+    type Item = Result<SessionFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+/// セッションを再生する方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplayMode {
+    /// 記録したYOLO層の出力を後処理する
+    #[default]
+    Postprocess,
+    /// 記録した入力データを、記録したYOLO層の出力を返す `SimBackend` を使う `YoloV3Tiny` で処理する
+    Sim,
+}
+
+/// セッションの再生の結果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionReport {
+    /// 再生したフレームの数
+    pub frames: usize,
+    /// 検出結果が記録と異なったフレームの番号
+    pub mismatched_frames: Vec<u64>,
+}
+
+impl SessionReport {
+    /// 全てのフレームで記録と同じ検出結果になったかを返します。
+    pub fn is_reproduced(&self) -> bool {
+        self.mismatched_frames.is_empty()
+    }
+}
+
+impl fmt::Display for SessionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} frames replayed, {} mismatched",
+            self.frames,
+            self.mismatched_frames.len()
+        )?;
+        if !self.mismatched_frames.is_empty() {
+            let ids: Vec<String> = self
+                .mismatched_frames
+                .iter()
+                .map(|id| id.to_string())
+                .collect();
+            write!(f, " ({})", ids.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// セッションを先頭から順に再生し、検出結果を記録と比較します。
+///
+/// # Args
+/// * `path` - セッションのパス
+/// * `mode` - 再生する方法
+///
+/// # Return
+/// * 再生の結果
+pub fn replay_session<P: AsRef<Path>>(path: P, mode: ReplayMode) -> Result<SessionReport> {
+    let mut report = SessionReport::default();
+    for frame in SessionReader::open(path)? {
+        let frame = frame?;
+        let detections = match mode {
+            ReplayMode::Postprocess => frame.replay(),
+            ReplayMode::Sim => frame
+                .replay_sim()
+                .with_context(|| format!("Frame {}", frame.frame_id))?,
+        };
+        if !frame.matches(&detections) {
+            report.mismatched_frames.push(frame.frame_id);
+        }
+        report.frames += 1;
+    }
+    Ok(report)
+}
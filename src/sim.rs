@@ -0,0 +1,299 @@
+//! ハードウェアなしでYOLOv3-Tinyのパイプラインを実行するためのシミュレーションのモジュール
+//!
+//! `SimBackend` は畳み込みを計算する代わりに、あらかじめ登録した物体 (YOLOの入力データの座標系) を
+//! 検出したときのYOLO層の出力 (レイヤーグループ10と13の出力と同じ並びの固定小数点数) を生成します。
+//! `YoloV3Tiny::with_sim` でIPの代わりに使うと、入力画像の補正・前処理・後処理・バリデータ・コールバックを
+//! ハードウェアの場合と同じ処理で実行できるため、ボードがない環境でもソフトウェアで行う処理の結果を確認できます。
+//!
+//! ```ignore
+//! let mut sim = SimBackend::new(cls_num, obj_threshold, nms_threshold);
+//! sim.add_object(DetectionData::<LetterboxSpace>::new(0, 100., 120., 180., 260., 0.9))?;
+//! let mut yolo = YoloV3Tiny::with_sim(sim);
+//! let objs = yolo.start_with_img_proc(&img, 0)?;
+//! ```
+
+use anyhow::{bail, ensure, Result};
+
+use crate::detection_result::{DetectionData, LetterboxSpace};
+use crate::postprocess::{OutputLayout, ANCHOR_BOX_NUM};
+use crate::session::PostprocessParams;
+
+/// YOLOの入力サイズ
+const INPUT_SIZE: u32 = 416;
+
+/// YOLO層の出力の分割数 (256チャネル)
+const OUTPUT_FOLDS: usize = 8;
+
+/// 1回の出力のチャネル数
+const FOLD_CH: usize = 32;
+
+/// 固定小数点数の小数部のビット数
+const FRAC_BITS: i32 = 8;
+
+/// 物体を出力するYOLO層のセル
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Slot {
+    /// グリッドの数 (13または26)
+    grid_num: usize,
+    /// アンカーボックスのインデックス (ヘッドの中)
+    anchor: usize,
+    /// セルのインデックス (行優先)
+    cell: usize,
+}
+
+/// シグモイドの逆関数の出力の上限。シグモイドを適用すると1/256未満の誤差で0または1になります
+const SIGMOID_SATURATION: f32 = 8.;
+
+/// シグモイドの逆関数を求めます。0と1は `SIGMOID_SATURATION` に飽和させます。
+fn logit(p: f32) -> f32 {
+    (p / (1. - p))
+        .ln()
+        .clamp(-SIGMOID_SATURATION, SIGMOID_SATURATION)
+}
+
+/// 実数を符号あり[8bits].[8bits]の固定小数点数に変換します。
+fn float2fix(v: f32) -> i16 {
+    (v * 2f32.powi(FRAC_BITS))
+        .round()
+        .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+/// YOLOのIPの代わりに、登録した物体を検出した出力を生成する構造体
+#[derive(Debug, Clone)]
+pub struct SimBackend {
+    /// クラス数
+    cls_num: usize,
+    /// 出力の並びとアンカーボックス
+    layout: OutputLayout,
+    /// オブジェクトの閾値
+    obj_threshold: f32,
+    /// NMSの閾値
+    nms_threshold: f32,
+    /// 後処理を固定小数点数のまま行うか
+    fixed_point_postprocess: bool,
+    /// 出力する物体 (YOLOの入力データの座標系)
+    objects: Vec<DetectionData<LetterboxSpace>>,
+    /// 物体から生成する代わりに出力する、記録したYOLO層の出力
+    recorded_outputs: Option<(Vec<i16>, Vec<i16>)>,
+}
+
+impl SimBackend {
+    /// 新しい `SimBackend` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `cls_num` - クラス数
+    /// * `obj_threshold` - オブジェクトの閾値
+    /// * `nms_threshold` - NMSの閾値
+    pub fn new(cls_num: usize, obj_threshold: f32, nms_threshold: f32) -> Self {
+        Self {
+            cls_num,
+            layout: OutputLayout::default(),
+            obj_threshold,
+            nms_threshold,
+            fixed_point_postprocess: false,
+            objects: vec![],
+            recorded_outputs: None,
+        }
+    }
+
+    /// `YoloV3Tiny::with_sim` で使う後処理の設定を返します。
+    pub fn postprocess_params(&self) -> PostprocessParams {
+        PostprocessParams {
+            cls_num: self.cls_num,
+            layout: self.layout,
+            obj_threshold: self.obj_threshold,
+            nms_threshold: self.nms_threshold,
+            fixed_point_postprocess: self.fixed_point_postprocess,
+        }
+    }
+
+    /// 出力の並びとアンカーボックスを設定します。
+    ///
+    /// `YoloV3Tiny` で使う場合は、推論のたびに `YoloV3Tiny` の出力の並びに置き換えられます。
+    pub fn set_layout(&mut self, layout: OutputLayout) -> &mut Self {
+        self.layout = layout;
+        self
+    }
+
+    /// 後処理を固定小数点数のまま行うかを設定します。`YoloV3Tiny::with_sim` で作成したインスタンスの初期値になります。
+    pub fn set_fixed_point_postprocess(&mut self, enable: bool) -> &mut Self {
+        self.fixed_point_postprocess = enable;
+        self
+    }
+
+    /// 出力する物体を追加します。
+    ///
+    /// # Args
+    /// * `object` - 物体のクラス・バウンディングボックス・コンフィデンス (YOLOの入力データの座標系)
+    ///
+    /// # Return
+    /// * クラスがクラス数以上の場合や、バウンディングボックスが入力の外にはみ出す場合はエラー
+    pub fn add_object(&mut self, object: DetectionData<LetterboxSpace>) -> Result<&mut Self> {
+        ensure!(
+            (object.class as usize) < self.cls_num,
+            "Class {} is out of range (cls_num: {})",
+            object.class,
+            self.cls_num
+        );
+        let size = INPUT_SIZE as f32;
+        ensure!(
+            0. <= object.x1 && object.x1 < object.x2 && object.x2 <= size,
+            "x1 {} and x2 {} must satisfy 0 <= x1 < x2 <= {}",
+            object.x1,
+            object.x2,
+            size
+        );
+        ensure!(
+            0. <= object.y1 && object.y1 < object.y2 && object.y2 <= size,
+            "y1 {} and y2 {} must satisfy 0 <= y1 < y2 <= {}",
+            object.y1,
+            object.y2,
+            size
+        );
+        ensure!(
+            (0. ..=1.).contains(&object.confidence),
+            "Confidence {} is out of range [0, 1]",
+            object.confidence
+        );
+        self.objects.push(object);
+        Ok(self)
+    }
+
+    /// 登録した物体を全て削除します。
+    pub fn clear_objects(&mut self) {
+        self.objects.clear();
+    }
+
+    /// 登録した物体を返します。
+    pub fn objects(&self) -> &[DetectionData<LetterboxSpace>] {
+        &self.objects
+    }
+
+    /// 登録した物体から出力を生成する代わりに、記録したYOLO層の出力をそのまま返すように設定します。Noneの場合は生成に戻します。
+    ///
+    /// `session::SessionReader` で読み込んだ出力を設定すると、現場と同じ後処理の結果を再現できます。
+    ///
+    /// # Args
+    /// * `outputs` - 13×13と26×26のYOLO層の出力
+    pub fn set_recorded_outputs(&mut self, outputs: Option<(Vec<i16>, Vec<i16>)>) -> &mut Self {
+        self.recorded_outputs = outputs;
+        self
+    }
+
+    /// 物体を出力するセルとアンカーボックスを選びます。
+    ///
+    /// darknetの学習時と同じく、幅と高さのIoUが最も大きいアンカーボックスを担当にします。
+    fn slot(&self, object: &DetectionData<LetterboxSpace>) -> Slot {
+        let (w, h) = (object.width(), object.height());
+        let (cx, cy) = object.center();
+        let shape_iou = |[aw, ah]: [f32; 2]| {
+            let inter = w.min(aw) * h.min(ah);
+            inter / (w * h + aw * ah - inter)
+        };
+        let heads = [(13, self.layout.anchors13()), (26, self.layout.anchors26())];
+        let (grid_num, anchor, _) = heads
+            .iter()
+            .flat_map(|&(grid_num, anchors)| {
+                anchors
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(j, a)| (grid_num, j, shape_iou(a)))
+            })
+            .fold(
+                (13, 0, f32::MIN),
+                |best, s| if s.2 > best.2 { s } else { best },
+            );
+        let grid_width = INPUT_SIZE as f32 / grid_num as f32;
+        let col = ((cx / grid_width) as usize).min(grid_num - 1);
+        let row = ((cy / grid_width) as usize).min(grid_num - 1);
+        Slot {
+            grid_num,
+            anchor,
+            cell: row * grid_num + col,
+        }
+    }
+
+    /// 入力データからYOLO層の出力を生成します。`YoloV3Tiny::start_processing` から呼び出されます。
+    ///
+    /// # Args
+    /// * `input_data` - 入力データ (長さだけを確認します)
+    ///
+    /// # Return
+    /// * 13×13と26×26のYOLO層の出力 (記録した出力を設定した場合はその出力)。2つの物体が同じセルの同じアンカーボックスに割り当てられた場合はエラー
+    pub fn start_processing(&self, input_data: &[i16]) -> Result<(Vec<i16>, Vec<i16>)> {
+        let expected = (INPUT_SIZE * INPUT_SIZE * 4) as usize;
+        ensure!(
+            input_data.len() == expected,
+            "The input has {} values, but {} are expected",
+            input_data.len(),
+            expected
+        );
+        if let Some(outputs) = &self.recorded_outputs {
+            return Ok(outputs.clone());
+        }
+        let stride = 5 + self.layout.class_slots;
+        ensure!(
+            self.cls_num <= self.layout.class_slots
+                && stride * ANCHOR_BOX_NUM <= OUTPUT_FOLDS * FOLD_CH,
+            "{} classes do not fit in {} class slots",
+            self.cls_num,
+            self.layout.class_slots
+        );
+
+        // シグモイドを後処理で適用する場合は、物体のないセルの物体確率が0に近くなるように負の値で埋める
+        let fill = if self.layout.software_sigmoid {
+            float2fix(-SIGMOID_SATURATION)
+        } else {
+            0
+        };
+        let mut out13 = vec![fill; OUTPUT_FOLDS * 13 * 13 * FOLD_CH];
+        let mut out26 = vec![fill; OUTPUT_FOLDS * 26 * 26 * FOLD_CH];
+        let mut used: Vec<(Slot, usize)> = vec![];
+        for (idx, object) in self.objects.iter().enumerate() {
+            let slot = self.slot(object);
+            if let Some((_, other)) = used.iter().find(|(s, _)| *s == slot) {
+                bail!(
+                    "Objects {} and {} are assigned to the same cell and anchor box",
+                    other,
+                    idx
+                );
+            }
+            used.push((slot, idx));
+
+            let (out, anchors) = match slot.grid_num {
+                13 => (&mut out13, self.layout.anchors13()),
+                _ => (&mut out26, self.layout.anchors26()),
+            };
+            let grid_width = INPUT_SIZE as f32 / slot.grid_num as f32;
+            let (cx, cy) = object.center();
+            let (col, row) = (slot.cell % slot.grid_num, slot.cell / slot.grid_num);
+            let [aw, ah] = anchors[slot.anchor];
+
+            // 後処理の `get_anchor_box` の逆変換
+            let activate = |v: f32| {
+                if self.layout.software_sigmoid {
+                    logit(v)
+                } else {
+                    v
+                }
+            };
+            let base = stride * slot.anchor;
+            let values = [
+                (base, activate(cx / grid_width - col as f32)),
+                (base + 1, activate(cy / grid_width - row as f32)),
+                (base + 2, (object.width() / aw).ln()),
+                (base + 3, (object.height() / ah).ln()),
+                (base + 4, activate(object.confidence)),
+                (base + 5 + object.class as usize, activate(1.)),
+            ];
+
+            // 後処理の `ch_reorder` の逆変換 (分割ごとに全てのセルの32チャネルが並ぶ)
+            let cells = slot.grid_num * slot.grid_num;
+            for (ch, v) in values {
+                let (fold, k) = (ch / FOLD_CH, ch % FOLD_CH);
+                out[cells * FOLD_CH * fold + FOLD_CH * slot.cell + k] = float2fix(v);
+            }
+        }
+        Ok((out13, out26))
+    }
+}
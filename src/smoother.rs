@@ -0,0 +1,76 @@
+//! フレーム間でバウンディングボックスを平滑化するモジュール
+
+use std::collections::HashMap;
+
+use crate::detection_result::DetectionData;
+use crate::tracker::Tracker;
+
+/// 指数移動平均によってバウンディングボックスの揺れを抑える構造体
+///
+/// `Tracker` で前フレームの同じクラスのバウンディングボックスとIoUで対応付け、座標を平滑化します。
+pub struct BoxSmoother {
+    alpha: f32,
+    tracker: Tracker,
+    /// 追跡IDごとの平滑化したバウンディングボックス
+    smoothed: HashMap<u64, DetectionData>,
+}
+
+impl BoxSmoother {
+    /// 新しい `BoxSmoother` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `alpha` - 指数移動平均の係数。1に近いほど現在のフレームの座標を重視します
+    /// * `iou_threshold` - フレーム間で同じ物体とみなすIoUの閾値
+    /// * `max_missed` - 検出されなかったときに追跡を継続するフレーム数
+    pub fn new(alpha: f32, iou_threshold: f32, max_missed: usize) -> Self {
+        Self {
+            alpha: alpha.clamp(0., 1.),
+            tracker: Tracker::new(iou_threshold, max_missed),
+            smoothed: HashMap::new(),
+        }
+    }
+
+    /// 追跡中のバウンディングボックスを全て削除します。
+    pub fn reset(&mut self) {
+        self.tracker.reset();
+        self.smoothed.clear();
+    }
+
+    /// 1フレーム分の検出結果を平滑化します。
+    ///
+    /// # Args
+    /// * `detections` - 1フレーム分の検出結果
+    ///
+    /// # Return
+    /// * 座標を平滑化した検出結果。クラスとコンフィデンスは元の値のままです
+    pub fn smooth(&mut self, detections: &[DetectionData]) -> Vec<DetectionData> {
+        let ids = self.tracker.assign(detections);
+        let a = self.alpha;
+        let smoothed: Vec<DetectionData> = detections
+            .iter()
+            .zip(ids)
+            .map(|(d, id)| {
+                let bbox = match self.smoothed.get(&id) {
+                    Some(prev) => DetectionData::new(
+                        d.class,
+                        prev.x1 * (1. - a) + d.x1 * a,
+                        prev.y1 * (1. - a) + d.y1 * a,
+                        prev.x2 * (1. - a) + d.x2 * a,
+                        prev.y2 * (1. - a) + d.y2 * a,
+                        d.confidence,
+                    )
+                    .with_sub_state(d.sub_state),
+                    None => *d,
+                };
+                self.smoothed.insert(id, bbox);
+                bbox
+            })
+            .collect();
+
+        // 追跡が終わったバウンディングボックスを削除
+        let tracker = &self.tracker;
+        self.smoothed.retain(|id, _| tracker.is_tracked(*id));
+
+        smoothed
+    }
+}
@@ -0,0 +1,233 @@
+//! 一定時間の検出結果をクラス別に集計するモジュール
+//!
+//! 検出数・平均コンフィデンス・平均のバウンディングボックスの大きさ・滞在時間を、直近の時間窓で集計します。
+//! ダッシュボードなどで、生の検出ログを処理し直さずに現在の状況を表示できます。
+//!
+//! ```ignore
+//! let mut tracker = Tracker::new(0.3, 5);
+//! let mut stats = Statistics::new(Duration::from_secs(60));
+//!
+//! let frame = yolo.detect_frame(&img, 0)?;
+//! stats.record_tracks(frame.timestamp, tracker.update(&frame.detections));
+//! println!("{}", stats.to_json());
+//! ```
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+use serde_json::{json, Value};
+
+use crate::detection_result::DetectionData;
+use crate::tracker::TrackedDetection;
+
+/// 1つの検出結果の記録
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    timestamp: SystemTime,
+    class: u8,
+    confidence: f32,
+    width: f32,
+    height: f32,
+}
+
+/// 追跡中または追跡が終了した物体の滞在期間
+#[derive(Debug, Clone, Copy)]
+struct Stay {
+    class: u8,
+    first_seen: SystemTime,
+    last_seen: SystemTime,
+}
+
+impl Stay {
+    fn dwell(&self) -> Duration {
+        self.last_seen
+            .duration_since(self.first_seen)
+            .unwrap_or_default()
+    }
+}
+
+/// クラスごとの集計結果
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClassStats {
+    /// 検出数
+    pub detections: usize,
+    /// 平均コンフィデンス
+    pub mean_confidence: f32,
+    /// バウンディングボックスの平均の幅
+    pub mean_width: f32,
+    /// バウンディングボックスの平均の高さ
+    pub mean_height: f32,
+    /// 追跡した物体の数 (`record_tracks` で記録した場合のみ)
+    pub tracks: usize,
+    /// 追跡した物体の平均の滞在時間 (`record_tracks` で記録した場合のみ)
+    pub mean_dwell: Duration,
+}
+
+/// 直近の時間窓の検出結果をクラス別に集計する構造体
+pub struct Statistics {
+    window: Duration,
+    /// 時間窓の中の検出結果 (時刻順)
+    samples: VecDeque<Sample>,
+    /// 追跡中の物体の滞在期間
+    active: HashMap<u64, Stay>,
+    /// 追跡が終了した物体の滞在期間 (終了した順)
+    finished: VecDeque<Stay>,
+    /// 最後に記録した時刻
+    latest: Option<SystemTime>,
+}
+
+impl Statistics {
+    /// 新しい `Statistics` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `window` - 集計する時間窓の長さ
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+            active: HashMap::new(),
+            finished: VecDeque::new(),
+            latest: None,
+        }
+    }
+
+    /// 集計する時間窓の長さを返します。
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// 記録を全て削除します。
+    pub fn reset(&mut self) {
+        self.samples.clear();
+        self.active.clear();
+        self.finished.clear();
+        self.latest = None;
+    }
+
+    /// 1フレーム分の検出結果を記録します。
+    ///
+    /// # Args
+    /// * `timestamp` - フレームの時刻 (`FrameResult::timestamp`)
+    /// * `detections` - 1フレーム分の検出結果
+    pub fn record(&mut self, timestamp: SystemTime, detections: &[DetectionData]) {
+        self.samples.extend(detections.iter().map(|d| Sample {
+            timestamp,
+            class: d.class,
+            confidence: d.confidence,
+            width: d.width(),
+            height: d.height(),
+        }));
+        self.latest = Some(self.latest.map_or(timestamp, |t| t.max(timestamp)));
+        self.expire();
+    }
+
+    /// 1フレーム分の追跡結果を記録します。検出結果に加えて、物体ごとの滞在時間を集計します。
+    ///
+    /// 滞在時間は最初に検出されてから最後に検出されるまでの時間です。
+    ///
+    /// # Args
+    /// * `timestamp` - フレームの時刻 (`FrameResult::timestamp`)
+    /// * `tracks` - 追跡中の全ての物体 (`Tracker::update` の戻り値)
+    pub fn record_tracks(&mut self, timestamp: SystemTime, tracks: &[TrackedDetection]) {
+        let seen: Vec<DetectionData> = tracks
+            .iter()
+            .filter(|t| t.missed == 0)
+            .map(|t| t.detection)
+            .collect();
+
+        for t in tracks.iter().filter(|t| t.missed == 0) {
+            self.active
+                .entry(t.track_id)
+                .and_modify(|s| s.last_seen = timestamp)
+                .or_insert(Stay {
+                    class: t.detection.class,
+                    first_seen: timestamp,
+                    last_seen: timestamp,
+                });
+        }
+        let ended: Vec<u64> = self
+            .active
+            .keys()
+            .filter(|id| tracks.iter().all(|t| t.track_id != **id))
+            .copied()
+            .collect();
+        for id in ended {
+            if let Some(stay) = self.active.remove(&id) {
+                self.finished.push_back(stay);
+            }
+        }
+
+        self.record(timestamp, &seen);
+    }
+
+    /// 時間窓より古い記録を削除します。
+    fn expire(&mut self) {
+        let Some(start) = self.latest.and_then(|t| t.checked_sub(self.window)) else {
+            return;
+        };
+        while self.samples.front().is_some_and(|s| s.timestamp < start) {
+            self.samples.pop_front();
+        }
+        while self.finished.front().is_some_and(|s| s.last_seen < start) {
+            self.finished.pop_front();
+        }
+    }
+
+    /// 時間窓の中の記録をクラス別に集計します。
+    ///
+    /// # Return
+    /// * クラスIDごとの集計結果
+    pub fn summary(&self) -> BTreeMap<u8, ClassStats> {
+        let mut stats: BTreeMap<u8, ClassStats> = BTreeMap::new();
+        for s in &self.samples {
+            let c = stats.entry(s.class).or_default();
+            c.detections += 1;
+            c.mean_confidence += s.confidence;
+            c.mean_width += s.width;
+            c.mean_height += s.height;
+        }
+        for c in stats.values_mut() {
+            let n = c.detections as f32;
+            c.mean_confidence /= n;
+            c.mean_width /= n;
+            c.mean_height /= n;
+        }
+
+        let mut dwell: BTreeMap<u8, Duration> = BTreeMap::new();
+        for stay in self.finished.iter().chain(self.active.values()) {
+            stats.entry(stay.class).or_default().tracks += 1;
+            *dwell.entry(stay.class).or_default() += stay.dwell();
+        }
+        for (class, total) in dwell {
+            let c = stats.get_mut(&class).unwrap();
+            c.mean_dwell = total / c.tracks as u32;
+        }
+        stats
+    }
+
+    /// 集計結果をJSONに変換します。
+    ///
+    /// # Return
+    /// * `{"window_secs": .., "classes": [{"class": .., "detections": .., ...}]}`
+    pub fn to_json(&self) -> Value {
+        let classes: Vec<Value> = self
+            .summary()
+            .iter()
+            .map(|(class, c)| {
+                json!({
+                    "class": class,
+                    "detections": c.detections,
+                    "mean_confidence": c.mean_confidence,
+                    "mean_width": c.mean_width,
+                    "mean_height": c.mean_height,
+                    "tracks": c.tracks,
+                    "mean_dwell_secs": c.mean_dwell.as_secs_f64(),
+                })
+            })
+            .collect();
+        json!({
+            "window_secs": self.window.as_secs_f64(),
+            "classes": classes,
+        })
+    }
+}
@@ -0,0 +1,163 @@
+//! フレームと検出結果をSQLiteデータベースに保存するモジュール
+//!
+//! `sqlite` フィーチャを有効にした場合のみ利用できます。
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use rusqlite::{params, Connection};
+
+use crate::detection_result::DetectionData;
+
+/// データベースから読み出した検出結果
+#[derive(Debug, Clone)]
+pub struct StoredDetection {
+    /// 記録した時刻 (UNIX時間, 秒)
+    pub timestamp: f64,
+    /// フレーム番号
+    pub frame_id: u64,
+    /// 検出結果
+    pub detection: DetectionData,
+}
+
+/// フレームと検出結果をSQLiteデータベースに保存する構造体
+///
+/// `frames` テーブルにフレームごとの時刻を、`detections` テーブルに検出結果を保存します。
+/// 時刻とクラスにはインデックスが作成されます。
+pub struct DetectionStore {
+    conn: Connection,
+}
+
+impl DetectionStore {
+    /// データベースを開きます。テーブルが存在しない場合は作成します。
+    ///
+    /// # Args
+    /// * `path` - データベースファイルのパス
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// メモリ上にデータベースを作成します。
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    /// 接続に対してテーブルとインデックスを作成します。
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS frames (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                frame_id INTEGER NOT NULL,
+                timestamp REAL NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS detections (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                frame INTEGER NOT NULL REFERENCES frames(id),
+                class INTEGER NOT NULL,
+                x1 REAL NOT NULL,
+                y1 REAL NOT NULL,
+                x2 REAL NOT NULL,
+                y2 REAL NOT NULL,
+                confidence REAL NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS frames_timestamp ON frames(timestamp);
+            CREATE INDEX IF NOT EXISTS detections_class ON detections(class);
+            CREATE INDEX IF NOT EXISTS detections_frame ON detections(frame);",
+        )?;
+        Ok(Self { conn })
+    }
+
+    /// 1フレーム分の検出結果を現在時刻で保存します。
+    ///
+    /// # Args
+    /// * `frame_id` - フレーム番号
+    /// * `detections` - 検出結果
+    pub fn insert_frame(&mut self, frame_id: u64, detections: &[DetectionData]) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs_f64())
+            .unwrap_or(0.);
+        self.insert_frame_at(timestamp, frame_id, detections)
+    }
+
+    /// 1フレーム分の検出結果を指定した時刻で保存します。
+    ///
+    /// # Args
+    /// * `timestamp` - 時刻 (UNIX時間, 秒)
+    /// * `frame_id` - フレーム番号
+    /// * `detections` - 検出結果
+    pub fn insert_frame_at(
+        &mut self,
+        timestamp: f64,
+        frame_id: u64,
+        detections: &[DetectionData],
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO frames (frame_id, timestamp) VALUES (?1, ?2)",
+            params![frame_id as i64, timestamp],
+        )?;
+        let frame = tx.last_insert_rowid();
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO detections (frame, class, x1, y1, x2, y2, confidence)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for d in detections {
+                stmt.execute(params![
+                    frame,
+                    d.class,
+                    d.x1,
+                    d.y1,
+                    d.x2,
+                    d.y2,
+                    d.confidence
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// 指定した期間の検出結果を読み出します。
+    ///
+    /// # Args
+    /// * `from` - 期間の開始時刻 (UNIX時間, 秒)
+    /// * `to` - 期間の終了時刻 (UNIX時間, 秒)
+    /// * `class` - 読み出すクラス。Noneの場合は全てのクラス
+    ///
+    /// # Return
+    /// * 時刻順に並べた検出結果
+    pub fn query(&self, from: f64, to: f64, class: Option<u8>) -> Result<Vec<StoredDetection>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT f.timestamp, f.frame_id, d.class, d.x1, d.y1, d.x2, d.y2, d.confidence
+             FROM detections d JOIN frames f ON d.frame = f.id
+             WHERE f.timestamp BETWEEN ?1 AND ?2 AND (?3 IS NULL OR d.class = ?3)
+             ORDER BY f.timestamp, d.id",
+        )?;
+        let rows = stmt.query_map(params![from, to, class], |row| {
+            Ok(StoredDetection {
+                timestamp: row.get(0)?,
+                frame_id: row.get::<_, i64>(1)? as u64,
+                detection: DetectionData::new(
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                ),
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// 保存されているフレームの数を返します。
+    pub fn frame_count(&self) -> Result<u64> {
+        let n: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM frames", [], |row| row.get(0))?;
+        Ok(n as u64)
+    }
+}
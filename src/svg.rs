@@ -0,0 +1,307 @@
+//! 検出結果をSVGのレイヤとして出力するモジュール
+//!
+//! バウンディングボックス・ラベル・エリア・カウントラインを、元の画像に重ねるSVGとして出力します。
+//! ボードで注釈付きの画像をエンコードし直さずに、記録した静止画にWebのレビューツールで重ねて表示できます。
+//! 色・クラス名・枠の描き方などは `Renderer` の設定に従います。
+//!
+//! ```ignore
+//! let renderer = Renderer::new(config.render_style())?;
+//! let mut svg = SvgOverlay::new(img.width(), img.height());
+//! svg.add_zones(&renderer, monitor.zones(), Rgb([0, 255, 0]))
+//!     .add_detections(&renderer, &result);
+//! svg.save("frame_000123.svg")?;
+//! ```
+
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use image::Rgb;
+
+use crate::detection_result::DetectionData;
+use crate::line_counter::CountingLine;
+use crate::render::{BoxStyle, Renderer};
+use crate::zones::Zone;
+
+/// ラベルのフォント (`font-family`)
+const FONT_FAMILY: &str = "'Roboto Mono', monospace";
+
+/// エリアの内側を塗りつぶす不透明度
+const ZONE_FILL_OPACITY: f32 = 0.15;
+
+/// 信頼度のバーの背景色
+const BAR_BACKGROUND: &str = "rgb(64,64,64)";
+
+/// 1フレーム分のSVGのレイヤ
+#[derive(Debug, Clone, PartialEq)]
+pub struct SvgOverlay {
+    width: u32,
+    height: u32,
+    /// `<svg>` の子要素
+    body: String,
+}
+
+impl SvgOverlay {
+    /// 新しい `SvgOverlay` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `width`, `height` - 重ねる画像の幅と高さ
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            body: String::new(),
+        }
+    }
+
+    /// 検出結果のバウンディングボックスとラベルを追加します。信頼度の高い検出結果ほど手前に描画されます。
+    ///
+    /// # Args
+    /// * `renderer` - 色・クラス名・枠の描き方などの設定
+    /// * `d_result` - 検出結果の配列 (画像の座標系)
+    pub fn add_detections(&mut self, renderer: &Renderer, d_result: &[DetectionData]) -> &mut Self {
+        let style = renderer.style();
+        let t = style.line_thickness;
+        let mut sorted = d_result.to_vec();
+        sorted.sort_by(|a, b| a.confidence.total_cmp(&b.confidence));
+
+        let body = &mut self.body;
+        body.push_str("<g class=\"detections\">\n");
+        for d in sorted.iter() {
+            let color = rgb(renderer.class_color(d.class));
+            let (x1, y1, x2, y2) = (d.x1.round(), d.y1.round(), d.x2.round(), d.y2.round());
+            let _ = write!(
+                body,
+                "<g class=\"detection\" data-class=\"{}\" data-confidence=\"{:.4}\"",
+                d.class, d.confidence
+            );
+            if let Some(sub_state) = d.sub_state {
+                let _ = write!(body, " data-sub-state=\"{}\"", sub_state);
+            }
+            body.push_str(">\n");
+
+            if style.fill_alpha > 0. {
+                let _ = writeln!(
+                    body,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\" fill-opacity=\"{:.3}\"/>",
+                    x1,
+                    y1,
+                    x2 - x1,
+                    y2 - y1,
+                    color,
+                    style.fill_alpha
+                );
+            }
+            match style.box_style {
+                BoxStyle::Full => {
+                    let _ = writeln!(
+                        body,
+                        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\"/>",
+                        x1,
+                        y1,
+                        x2 - x1,
+                        y2 - y1,
+                        color,
+                        t
+                    );
+                }
+                BoxStyle::Corners { length } => {
+                    let l = ((x2 - x1).min(y2 - y1) * length).round().max(t);
+                    let _ = writeln!(
+                        body,
+                        "<path d=\"M{},{}V{}H{} M{},{}H{}V{} M{},{}V{}H{} M{},{}H{}V{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\"/>",
+                        x1, y1 + l, y1, x1 + l,
+                        x2 - l, y1, x2, y1 + l,
+                        x2, y2 - l, y2, x2 - l,
+                        x1 + l, y2, x1, y2 - l,
+                        color,
+                        t
+                    );
+                }
+            }
+            if style.confidence_bar {
+                let h = (t * 1.5).max(4.);
+                let by = y2 + t / 2. + 1.;
+                let bx = x1 - t / 2.;
+                let bw = x2 - x1 + t;
+                let _ = writeln!(
+                    body,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+                    bx, by, bw, h, BAR_BACKGROUND
+                );
+                let _ = writeln!(
+                    body,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{:.1}\" height=\"{}\" fill=\"{}\"/>",
+                    bx,
+                    by,
+                    bw * d.confidence.clamp(0., 1.),
+                    h,
+                    color
+                );
+            }
+            if style.font_size > 0. {
+                let text = renderer.label(d);
+                let pad = 6.;
+                let w = renderer.text_width(&text, style.font_size).ceil() + pad * 2.;
+                let lx = x1 - (t / 2.).floor();
+                let ly = y1 - style.font_size;
+                let bg = renderer.class_color(d.class);
+                let text_color = if (bg[0] as i32 + bg[1] as i32 + bg[2] as i32) < 382 {
+                    "white"
+                } else {
+                    "black"
+                };
+                let _ = writeln!(
+                    body,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+                    lx, ly, w, style.font_size, color
+                );
+                let _ = writeln!(
+                    body,
+                    "<text x=\"{}\" y=\"{}\" fill=\"{}\" font-family=\"{}\" font-size=\"{}\" dominant-baseline=\"central\">{}</text>",
+                    lx + pad,
+                    ly + style.font_size / 2.,
+                    text_color,
+                    FONT_FAMILY,
+                    style.font_size,
+                    escape(&text)
+                );
+            }
+            body.push_str("</g>\n");
+        }
+        body.push_str("</g>\n");
+        self
+    }
+
+    /// エリアの多角形と名前を追加します。
+    ///
+    /// # Args
+    /// * `renderer` - 線の太さとフォントサイズの設定
+    /// * `zones` - エリア
+    /// * `color` - エリアの色
+    pub fn add_zones(&mut self, renderer: &Renderer, zones: &[Zone], color: Rgb<u8>) -> &mut Self {
+        let style = renderer.style();
+        let color = rgb(color);
+        let body = &mut self.body;
+        body.push_str("<g class=\"zones\">\n");
+        for zone in zones {
+            let points: Vec<String> = zone
+                .polygon()
+                .iter()
+                .map(|(x, y)| format!("{:.1},{:.1}", x, y))
+                .collect();
+            let _ = writeln!(
+                body,
+                "<polygon data-name=\"{}\" points=\"{}\" fill=\"{}\" fill-opacity=\"{}\" stroke=\"{}\" stroke-width=\"{}\" stroke-linejoin=\"round\"/>",
+                escape(zone.name()),
+                points.join(" "),
+                color,
+                ZONE_FILL_OPACITY,
+                color,
+                style.line_thickness
+            );
+            if let Some((x, y)) = zone.polygon().first() {
+                write_name(body, (*x, *y), zone.name(), &color, renderer);
+            }
+        }
+        body.push_str("</g>\n");
+        self
+    }
+
+    /// カウントラインの線分と名前を追加します。
+    ///
+    /// # Args
+    /// * `renderer` - 線の太さとフォントサイズの設定
+    /// * `lines` - カウントライン
+    /// * `color` - 線の色
+    pub fn add_counting_lines(
+        &mut self,
+        renderer: &Renderer,
+        lines: &[CountingLine],
+        color: Rgb<u8>,
+    ) -> &mut Self {
+        let style = renderer.style();
+        let color = rgb(color);
+        let body = &mut self.body;
+        body.push_str("<g class=\"counting-lines\">\n");
+        for line in lines {
+            let ((x1, y1), (x2, y2)) = line.endpoints();
+            let _ = writeln!(
+                body,
+                "<line data-name=\"{}\" x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"{}\" stroke-width=\"{}\" stroke-linecap=\"round\"/>",
+                escape(line.name()),
+                x1,
+                y1,
+                x2,
+                y2,
+                color,
+                style.line_thickness
+            );
+            write_name(body, (x1, y1), line.name(), &color, renderer);
+        }
+        body.push_str("</g>\n");
+        self
+    }
+
+    /// SVGをファイルに保存します。
+    ///
+    /// # Args
+    /// * `path` - 保存先のパス
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_string()).with_context(|| format!("Can't write {}", path.display()))
+    }
+}
+
+impl fmt::Display for SvgOverlay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">",
+            w = self.width,
+            h = self.height
+        )?;
+        f.write_str(&self.body)?;
+        writeln!(f, "</svg>")
+    }
+}
+
+/// エリアやカウントラインの名前を、指定した位置の上に追加します。
+fn write_name(body: &mut String, (x, y): (f32, f32), name: &str, color: &str, renderer: &Renderer) {
+    let size = renderer.style().font_size;
+    if size <= 0. {
+        return;
+    }
+    let _ = writeln!(
+        body,
+        "<text x=\"{:.1}\" y=\"{:.1}\" fill=\"{}\" font-family=\"{}\" font-size=\"{}\">{}</text>",
+        x,
+        y - renderer.style().line_thickness,
+        color,
+        FONT_FAMILY,
+        size,
+        escape(name)
+    );
+}
+
+/// SVGの色の表記を返します。
+fn rgb(color: Rgb<u8>) -> String {
+    format!("rgb({},{},{})", color[0], color[1], color[2])
+}
+
+/// XMLの特殊文字をエスケープします。
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,119 @@
+//! フレームごとのFPS・処理時間・フレーム番号・クラスごとの検出数を集計するモジュール
+//!
+//! 集計した `Telemetry` は `Renderer::draw_telemetry` で出力画像の隅に描画できます。
+//!
+//! ```ignore
+//! let mut meter = TelemetryMeter::new();
+//! loop {
+//!     let frame = yolo.detect_frame(&img, 0)?;
+//!     let telemetry = meter.record(&frame);
+//!     renderer.draw(&mut rgb_img, &frame.detections);
+//!     renderer.draw_telemetry(&mut rgb_img, &telemetry);
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::detection_result::{DetectionData, FrameResult};
+
+/// 1フレームの集計結果
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Telemetry {
+    /// フレーム番号
+    pub frame_id: u64,
+    /// フレームレート (指数移動平均)
+    pub fps: f64,
+    /// 処理時間
+    pub latency: Duration,
+    /// クラスごとの検出数
+    pub class_counts: BTreeMap<u8, usize>,
+}
+
+/// フレームの間隔と処理時間を計測し、`Telemetry` を作成する構造体
+pub struct TelemetryMeter {
+    alpha: f64,
+    last_frame: Option<Instant>,
+    ema_interval: Option<f64>,
+}
+
+impl Default for TelemetryMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TelemetryMeter {
+    /// 新しい `TelemetryMeter` インスタンスを作成します。
+    pub fn new() -> Self {
+        Self {
+            alpha: 0.2,
+            last_frame: None,
+            ema_interval: None,
+        }
+    }
+
+    /// フレームの間隔の指数移動平均の係数を設定します。
+    pub fn set_smoothing(&mut self, alpha: f64) -> &mut Self {
+        self.alpha = alpha.clamp(0., 1.);
+        self
+    }
+
+    /// `detect_frame` の処理結果を記録し、集計結果を返します。
+    ///
+    /// FPSは `record` を呼び出した間隔から求めます。最初のフレームでは処理時間から求めます。
+    ///
+    /// # Args
+    /// * `frame` - フレームの処理結果
+    ///
+    /// # Return
+    /// * 集計結果
+    pub fn record(&mut self, frame: &FrameResult) -> Telemetry {
+        self.record_detections(frame.frame_id, frame.latency, &frame.detections)
+    }
+
+    /// `detect_frame` 以外で処理したフレームの検出結果を記録し、集計結果を返します。
+    ///
+    /// # Args
+    /// * `frame_id` - フレーム番号 (`YoloV3Tiny::frame_id`)
+    /// * `latency` - フレームの処理時間
+    /// * `detections` - フレームの検出結果
+    ///
+    /// # Return
+    /// * 集計結果
+    pub fn record_detections<S>(
+        &mut self,
+        frame_id: u64,
+        latency: Duration,
+        detections: &[DetectionData<S>],
+    ) -> Telemetry {
+        let now = Instant::now();
+        let interval = match self.last_frame {
+            Some(last) => now.duration_since(last).as_secs_f64(),
+            None => latency.as_secs_f64(),
+        };
+        let ema = match self.ema_interval {
+            Some(ema) => ema * (1. - self.alpha) + interval * self.alpha,
+            None => interval,
+        };
+        self.ema_interval = Some(ema);
+        self.last_frame = Some(now);
+
+        let mut class_counts = BTreeMap::new();
+        for d in detections {
+            *class_counts.entry(d.class).or_insert(0) += 1;
+        }
+        Telemetry {
+            frame_id,
+            fps: if ema > 0. { 1. / ema } else { 0. },
+            latency,
+            class_counts,
+        }
+    }
+
+    /// FPSの計測を初期化します。
+    pub fn reset(&mut self) {
+        self.last_frame = None;
+        self.ema_interval = None;
+    }
+}
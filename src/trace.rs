@@ -0,0 +1,630 @@
+//! IPのレジスタ書き込みとDMAの転送を記録し、再生するモジュール
+//!
+//! `YoloV3Tiny::set_trace` で記録を有効にすると、推論中のレジスタの書き込みと読み出し・IPのスタート・
+//! スイッチの切り替え・DMAの転送・完了待ちの結果を1行ずつテキストファイルに書き出します。
+//! 現場で起きた不具合のトレースを持ち帰り、`MockBackend` で手順の誤りを調べたり、
+//! `YoloV3Tiny::replay_trace` で机上の実機に同じ手順を流したりできます。
+//!
+//! ```text
+//! step 0 0 0 last
+//! write yolo_conv OUTPUT_CH 16
+//! start yolo_conv
+//! switch 0:0 0:0 0:0
+//! dma_write dma0 432 3c9a51e2
+//! dma_read dma0 43264 5d2e03a7
+//! wait yolo_max_pool ok
+//! read yolo_conv OUTPUT_CH 16
+//! ```
+//!
+//! DMAで転送したデータは記録せず、要素数とチェックサムだけを記録します。
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, ensure, Context, Result};
+use log::warn;
+
+/// YOLOのIPの名前と、スタートする前に設定が必要なレジスタ
+pub const IP_REGISTERS: [(&str, &[&str]); 5] = [
+    (
+        "yolo_conv",
+        &[
+            "OUTPUT_CH",
+            "INPUT_CH",
+            "FOLD_OUTPUT_CH",
+            "FOLD_INPUT_CH",
+            "INPUT_H",
+            "INPUT_W",
+            "REAL_INPUT_H",
+            "FOLD_WIN_AREA",
+        ],
+    ),
+    (
+        "yolo_acc",
+        &["INPUT_H", "INPUT_W", "FOLD_INPUT_CH", "LEAKY", "BIAS_EN"],
+    ),
+    (
+        "yolo_max_pool",
+        &[
+            "OUTPUT_H",
+            "OUTPUT_W",
+            "INPUT_H",
+            "INPUT_W",
+            "INPUT_FOLD_CH",
+            "STRIDE",
+        ],
+    ),
+    ("yolo_yolo", &["ACTIVATE_EN", "INPUT_H", "INPUT_W"]),
+    ("yolo_upsamp", &[]),
+];
+
+/// DMAの名前
+pub const DMA_NAMES: [&str; 2] = ["dma0", "dma1"];
+
+/// トレースの1行 (ハードウェアに対する1回の操作)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// レイヤーグループの1回のデータ転送の開始
+    Step {
+        /// レイヤーグループのインデックス
+        grp_idx: usize,
+        /// 出力チャネルのインデックス
+        off: u32,
+        /// 入力チャネルのインデックス
+        iff: u32,
+        /// 入力チャネルの最後の転送か
+        is_last: bool,
+    },
+    /// IPのレジスタへの書き込み
+    Write {
+        /// IPの名前
+        ip: String,
+        /// レジスタの名前
+        reg: String,
+        /// 書き込んだ値
+        value: u32,
+    },
+    /// IPのレジスタの読み出し
+    Read {
+        /// IPの名前
+        ip: String,
+        /// レジスタの名前
+        reg: String,
+        /// 読み出した値
+        value: u32,
+    },
+    /// IPのスタート
+    Start {
+        /// IPの名前
+        ip: String,
+    },
+    /// Axi4-Stream Switchの切り替え (スイッチ0から順に (スレーブ, マスター))
+    Switch {
+        /// 接続したポート
+        ports: [(u8, u8); 3],
+    },
+    /// DMAの送信 (MM2S)
+    DmaWrite {
+        /// DMAの名前
+        dma: String,
+        /// 送信した要素の数
+        len: usize,
+        /// 送信したデータのチェックサム
+        checksum: u32,
+    },
+    /// DMAの受信 (S2MM)
+    DmaRead {
+        /// DMAの名前
+        dma: String,
+        /// 受信した要素の数
+        len: usize,
+        /// 受信したデータのチェックサム
+        checksum: u32,
+    },
+    /// IPの完了待ちの結果
+    Wait {
+        /// 待ったIPの名前
+        target: String,
+        /// 時間内に完了したか
+        ok: bool,
+    },
+    /// スイッチの切り離しとDMAの再起動
+    Reset,
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Step {
+                grp_idx,
+                off,
+                iff,
+                is_last,
+            } => {
+                write!(f, "step {} {} {}", grp_idx, off, iff)?;
+                if *is_last {
+                    write!(f, " last")?;
+                }
+                Ok(())
+            }
+            Self::Write { ip, reg, value } => write!(f, "write {} {} {}", ip, reg, value),
+            Self::Read { ip, reg, value } => write!(f, "read {} {} {}", ip, reg, value),
+            Self::Start { ip } => write!(f, "start {}", ip),
+            Self::Switch { ports } => {
+                write!(f, "switch")?;
+                for (s, m) in ports {
+                    write!(f, " {}:{}", s, m)?;
+                }
+                Ok(())
+            }
+            Self::DmaWrite { dma, len, checksum } => {
+                write!(f, "dma_write {} {} {:08x}", dma, len, checksum)
+            }
+            Self::DmaRead { dma, len, checksum } => {
+                write!(f, "dma_read {} {} {:08x}", dma, len, checksum)
+            }
+            Self::Wait { target, ok } => {
+                write!(f, "wait {} {}", target, if *ok { "ok" } else { "timeout" })
+            }
+            Self::Reset => write!(f, "reset"),
+        }
+    }
+}
+
+impl FromStr for TraceEvent {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let arg = |i: usize| -> Result<&str> {
+            fields
+                .get(i)
+                .copied()
+                .with_context(|| format!("Missing field {} in \"{}\"", i, s))
+        };
+        let num = |i: usize| -> Result<u64> {
+            let f = arg(i)?;
+            f.parse()
+                .with_context(|| format!("Invalid number \"{}\" in \"{}\"", f, s))
+        };
+        let checksum = |i: usize| -> Result<u32> {
+            let f = arg(i)?;
+            u32::from_str_radix(f, 16)
+                .with_context(|| format!("Invalid checksum \"{}\" in \"{}\"", f, s))
+        };
+
+        let (event, n) = match arg(0)? {
+            "step" => {
+                let is_last = match fields.get(4) {
+                    None => false,
+                    Some(&"last") => true,
+                    Some(f) => bail!("Unknown flag \"{}\" in \"{}\"", f, s),
+                };
+                let event = Self::Step {
+                    grp_idx: num(1)? as usize,
+                    off: num(2)? as u32,
+                    iff: num(3)? as u32,
+                    is_last,
+                };
+                (event, if is_last { 5 } else { 4 })
+            }
+            "write" => {
+                let event = Self::Write {
+                    ip: arg(1)?.to_string(),
+                    reg: arg(2)?.to_string(),
+                    value: num(3)? as u32,
+                };
+                (event, 4)
+            }
+            "read" => {
+                let event = Self::Read {
+                    ip: arg(1)?.to_string(),
+                    reg: arg(2)?.to_string(),
+                    value: num(3)? as u32,
+                };
+                (event, 4)
+            }
+            "start" => (
+                Self::Start {
+                    ip: arg(1)?.to_string(),
+                },
+                2,
+            ),
+            "switch" => {
+                let mut ports = [(0, 0); 3];
+                for (i, port) in ports.iter_mut().enumerate() {
+                    let f = arg(i + 1)?;
+                    let (sl, ma) = f
+                        .split_once(':')
+                        .with_context(|| format!("Invalid port \"{}\" in \"{}\"", f, s))?;
+                    *port = (
+                        sl.parse()
+                            .with_context(|| format!("Invalid port \"{}\" in \"{}\"", f, s))?,
+                        ma.parse()
+                            .with_context(|| format!("Invalid port \"{}\" in \"{}\"", f, s))?,
+                    );
+                }
+                (Self::Switch { ports }, 4)
+            }
+            "dma_write" => {
+                let event = Self::DmaWrite {
+                    dma: arg(1)?.to_string(),
+                    len: num(2)? as usize,
+                    checksum: checksum(3)?,
+                };
+                (event, 4)
+            }
+            "dma_read" => {
+                let event = Self::DmaRead {
+                    dma: arg(1)?.to_string(),
+                    len: num(2)? as usize,
+                    checksum: checksum(3)?,
+                };
+                (event, 4)
+            }
+            "wait" => {
+                let ok = match arg(2)? {
+                    "ok" => true,
+                    "timeout" => false,
+                    f => bail!("Unknown wait result \"{}\" in \"{}\"", f, s),
+                };
+                let event = Self::Wait {
+                    target: arg(1)?.to_string(),
+                    ok,
+                };
+                (event, 3)
+            }
+            "reset" => (Self::Reset, 1),
+            kind => bail!("Unknown trace event \"{}\"", kind),
+        };
+        ensure!(fields.len() == n, "Too many fields in \"{}\"", s);
+        Ok(event)
+    }
+}
+
+/// DMAで転送したデータのチェックサム (リトルエンディアンのバイト列のFNV-1a) を計算します。
+///
+/// # Args
+/// * `data` - 転送したデータ
+pub fn checksum(data: &[i16]) -> u32 {
+    fnv1a(data.iter().flat_map(|v| v.to_le_bytes()))
+}
+
+/// バイト列のFNV-1aハッシュを計算します。
+fn fnv1a<I: IntoIterator<Item = u8>>(bytes: I) -> u32 {
+    bytes
+        .into_iter()
+        .fold(0x811c_9dc5, |h, b| (h ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+/// トレースをファイルに書き出す構造体
+///
+/// 書き込みに失敗した場合は推論を止めないよう、警告を出して以降の記録をやめます。
+pub struct TraceRecorder {
+    writer: Option<BufWriter<File>>,
+    /// 記録したイベントの数
+    events: u64,
+}
+
+impl TraceRecorder {
+    /// トレースを書き出すファイルを作成します。既存のファイルは上書きします。
+    ///
+    /// # Args
+    /// * `path` - トレースのパス
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("Can't create {}", path.display()))?;
+        Ok(Self {
+            writer: Some(BufWriter::new(file)),
+            events: 0,
+        })
+    }
+
+    /// イベントを記録します。
+    ///
+    /// # Args
+    /// * `event` - 記録するイベント
+    pub fn record(&mut self, event: &TraceEvent) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        if let Err(e) = writeln!(writer, "{}", event) {
+            warn!("Can't write the trace: {}. Tracing is disabled", e);
+            self.writer = None;
+            return;
+        }
+        self.events += 1;
+    }
+
+    /// 記録したイベントの数を返します。
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+
+    /// バッファに残っているイベントをファイルに書き出します。
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush().context("Can't flush the trace")?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TraceRecorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("{:#}", e);
+        }
+    }
+}
+
+/// トレースを読み込みます。空行と `#` で始まる行は無視します。
+///
+/// # Args
+/// * `path` - トレースのパス
+///
+/// # Return
+/// * イベントのベクトル。解析できない行がある場合は行番号を含むエラー
+pub fn read_trace<P: AsRef<Path>>(path: P) -> Result<Vec<TraceEvent>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Can't open {}", path.display()))?;
+    let mut events = vec![];
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("Can't read {}", path.display()))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let event = line
+            .parse()
+            .with_context(|| format!("{}:{}", path.display(), i + 1))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// トレースを再生する対象
+pub trait ReplayTarget {
+    /// イベントを1つ再生します。
+    ///
+    /// # Args
+    /// * `event` - 再生するイベント
+    ///
+    /// # Return
+    /// * 完了待ち・レジスタの読み出し・DMAの受信の場合は、実際に観測したイベント。それ以外はNone
+    fn apply(&mut self, event: &TraceEvent) -> Result<Option<TraceEvent>>;
+}
+
+/// 記録と再生で完了待ちの結果やレジスタの値が異なったイベント
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayMismatch {
+    /// トレースの中のイベントのインデックス
+    pub index: usize,
+    /// 記録されたイベント
+    pub expected: TraceEvent,
+    /// 再生で観測したイベント
+    pub actual: TraceEvent,
+}
+
+/// トレースの再生の結果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// 再生したイベントの数
+    pub events: usize,
+    /// 完了待ちの結果やレジスタの値が記録と異なったイベント
+    pub mismatches: Vec<ReplayMismatch>,
+}
+
+impl ReplayReport {
+    /// 記録と同じ結果になったかを返します。
+    pub fn is_reproduced(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// トレースを先頭から順に再生します。
+///
+/// 完了待ちの結果 (時間内に完了したか) とレジスタから読み出した値を記録と比較します。
+/// DMAで送信するデータは記録されていないため、受信したデータのチェックサムは比較しません。
+///
+/// # Args
+/// * `events` - 再生するイベント
+/// * `target` - 再生する対象
+///
+/// # Return
+/// * 再生の結果。再生する対象がエラーを返した場合は、イベントの位置を含むエラー
+pub fn replay<T: ReplayTarget + ?Sized>(
+    events: &[TraceEvent],
+    target: &mut T,
+) -> Result<ReplayReport> {
+    let mut report = ReplayReport::default();
+    for (index, event) in events.iter().enumerate() {
+        let actual = target
+            .apply(event)
+            .with_context(|| format!("Replay failed at event {} ({})", index, event))?;
+        report.events += 1;
+        if let (TraceEvent::Wait { .. } | TraceEvent::Read { .. }, Some(actual)) = (event, actual) {
+            if actual != *event {
+                report.mismatches.push(ReplayMismatch {
+                    index,
+                    expected: event.clone(),
+                    actual,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// ハードウェアの代わりにトレースを再生する、レジスタとDMAのモデル
+///
+/// 演算は行いませんが、次のような手順の誤りをエラーにします。
+/// * 存在しないIP・レジスタ・DMAへの操作
+/// * スタートする前に設定していないレジスタがある
+/// * スタートしていないIPの完了待ち (時間切れとして扱う)
+///
+/// レジスタから読み出す値は最後に書き込んだ値 (書き込んでいない場合は0)、DMAで受信するデータは全て0とします。
+#[derive(Debug, Clone, Default)]
+pub struct MockBackend {
+    /// IPとレジスタごとの最後に書き込んだ値
+    registers: BTreeMap<(String, String), u32>,
+    /// IPごとのスタートした回数
+    starts: HashMap<String, u64>,
+    /// スタートして完了待ちをしていないIP
+    running: Vec<String>,
+    /// 最後に設定したスイッチの接続
+    switch_ports: Option<[(u8, u8); 3]>,
+    /// DMAごとの送信した要素の数
+    written: HashMap<String, usize>,
+    /// DMAごとの受信した要素の数
+    read: HashMap<String, usize>,
+    /// リセットした回数
+    resets: u64,
+}
+
+impl MockBackend {
+    /// 新しい `MockBackend` インスタンスを作成します。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// レジスタに最後に書き込んだ値を返します。
+    ///
+    /// # Args
+    /// * `ip` - IPの名前
+    /// * `reg` - レジスタの名前
+    pub fn register(&self, ip: &str, reg: &str) -> Option<u32> {
+        self.registers
+            .get(&(ip.to_string(), reg.to_string()))
+            .copied()
+    }
+
+    /// IPをスタートした回数を返します。
+    pub fn start_count(&self, ip: &str) -> u64 {
+        self.starts.get(ip).copied().unwrap_or(0)
+    }
+
+    /// 最後に設定したスイッチの接続を返します。リセット後は None です。
+    pub fn switch_ports(&self) -> Option<[(u8, u8); 3]> {
+        self.switch_ports
+    }
+
+    /// DMAで送信した要素の数を返します。
+    pub fn written_len(&self, dma: &str) -> usize {
+        self.written.get(dma).copied().unwrap_or(0)
+    }
+
+    /// DMAで受信した要素の数を返します。
+    pub fn read_len(&self, dma: &str) -> usize {
+        self.read.get(dma).copied().unwrap_or(0)
+    }
+
+    /// リセットした回数を返します。
+    pub fn resets(&self) -> u64 {
+        self.resets
+    }
+
+    /// IPの設定が必要なレジスタを返します。
+    fn ip_registers(ip: &str) -> Result<&'static [&'static str]> {
+        IP_REGISTERS
+            .iter()
+            .find(|(name, _)| *name == ip)
+            .map(|(_, regs)| *regs)
+            .with_context(|| format!("Unknown IP \"{}\"", ip))
+    }
+
+    /// IPにレジスタがあるかを確認します。
+    fn check_register(ip: &str, reg: &str) -> Result<()> {
+        ensure!(
+            Self::ip_registers(ip)?.contains(&reg),
+            "{} has no register \"{}\"",
+            ip,
+            reg
+        );
+        Ok(())
+    }
+
+    /// DMAの名前を確認します。
+    fn check_dma(dma: &str) -> Result<()> {
+        ensure!(DMA_NAMES.contains(&dma), "Unknown DMA \"{}\"", dma);
+        Ok(())
+    }
+}
+
+impl ReplayTarget for MockBackend {
+    fn apply(&mut self, event: &TraceEvent) -> Result<Option<TraceEvent>> {
+        match event {
+            TraceEvent::Step { .. } => Ok(None),
+            TraceEvent::Write { ip, reg, value } => {
+                Self::check_register(ip, reg)?;
+                self.registers.insert((ip.clone(), reg.clone()), *value);
+                Ok(None)
+            }
+            TraceEvent::Read { ip, reg, .. } => {
+                Self::check_register(ip, reg)?;
+                Ok(Some(TraceEvent::Read {
+                    ip: ip.clone(),
+                    reg: reg.clone(),
+                    value: self.register(ip, reg).unwrap_or(0),
+                }))
+            }
+            TraceEvent::Start { ip } => {
+                let unset: Vec<&str> = Self::ip_registers(ip)?
+                    .iter()
+                    .copied()
+                    .filter(|reg| self.register(ip, reg).is_none())
+                    .collect();
+                ensure!(
+                    unset.is_empty(),
+                    "{} is started before setting {}",
+                    ip,
+                    unset.join(", ")
+                );
+                *self.starts.entry(ip.clone()).or_default() += 1;
+                if !self.running.contains(ip) {
+                    self.running.push(ip.clone());
+                }
+                Ok(None)
+            }
+            TraceEvent::Switch { ports } => {
+                self.switch_ports = Some(*ports);
+                Ok(None)
+            }
+            TraceEvent::DmaWrite { dma, len, .. } => {
+                Self::check_dma(dma)?;
+                *self.written.entry(dma.clone()).or_default() += len;
+                Ok(None)
+            }
+            TraceEvent::DmaRead { dma, len, .. } => {
+                Self::check_dma(dma)?;
+                *self.read.entry(dma.clone()).or_default() += len;
+                Ok(Some(TraceEvent::DmaRead {
+                    dma: dma.clone(),
+                    len: *len,
+                    checksum: fnv1a(std::iter::repeat_n(0, len * 2)),
+                }))
+            }
+            TraceEvent::Wait { target, .. } => {
+                Self::ip_registers(target)?;
+                // パイプラインの途中のIPも同じ転送で完了しているものとする
+                let ok = self.running.contains(target);
+                self.running.clear();
+                Ok(Some(TraceEvent::Wait {
+                    target: target.clone(),
+                    ok,
+                }))
+            }
+            TraceEvent::Reset => {
+                self.switch_ports = None;
+                self.running.clear();
+                self.resets += 1;
+                Ok(None)
+            }
+        }
+    }
+}
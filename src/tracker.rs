@@ -0,0 +1,162 @@
+//! フレーム間で検出結果を対応付け、物体ごとに追跡IDを割り当てるモジュール
+//!
+//! 領域への侵入の判定 (`zones`) など、同じ物体の位置の変化を扱う処理で使います。
+
+use crate::detection_result::DetectionData;
+
+/// 追跡IDを割り当てた検出結果
+#[derive(Debug, Clone, Copy)]
+pub struct TrackedDetection {
+    /// 追跡ID
+    pub track_id: u64,
+    /// 最後に検出されたときの検出結果
+    pub detection: DetectionData,
+    /// 連続して検出されなかったフレーム数。現在のフレームで検出された場合は0
+    pub missed: usize,
+}
+
+/// 検出結果を、前フレームのバウンディングボックスのうちIoUが最大のものに貪欲に対応付けます。
+///
+/// 検出結果を順に、まだ対応付けていないバウンディングボックスの中からIoUが閾値以上で最大のものに対応付けます。
+///
+/// # Args
+/// * `prev` - 前フレームのバウンディングボックス
+/// * `detections` - 現在のフレームの検出結果
+/// * `iou_threshold` - 同じ物体とみなすIoUの閾値
+/// * `same_class` - クラスが同じものだけを対応付けるか
+///
+/// # Return
+/// * 検出結果ごとの、対応付けた `prev` のインデックス。対応するものがない場合はNone
+fn match_boxes(
+    prev: &[&DetectionData],
+    detections: &[DetectionData],
+    iou_threshold: f32,
+    same_class: bool,
+) -> Vec<Option<usize>> {
+    let mut matched = vec![false; prev.len()];
+    detections
+        .iter()
+        .map(|d| {
+            let best = prev
+                .iter()
+                .enumerate()
+                .filter(|(i, p)| !matched[*i] && (!same_class || p.class == d.class))
+                .map(|(i, p)| (i, p.iou(d)))
+                .filter(|&(_, v)| v >= iou_threshold)
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(i, _)| i);
+            if let Some(i) = best {
+                matched[i] = true;
+            }
+            best
+        })
+        .collect()
+}
+
+/// 前フレームの同じクラスのバウンディングボックスとIoUで対応付けて物体を追跡する構造体
+pub struct Tracker {
+    iou_threshold: f32,
+    max_missed: usize,
+    same_class: bool,
+    tracks: Vec<TrackedDetection>,
+    next_id: u64,
+}
+
+impl Tracker {
+    /// 新しい `Tracker` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `iou_threshold` - フレーム間で同じ物体とみなすIoUの閾値
+    /// * `max_missed` - 検出されなかったときに追跡を継続するフレーム数
+    pub fn new(iou_threshold: f32, max_missed: usize) -> Self {
+        Self {
+            iou_threshold,
+            max_missed,
+            same_class: true,
+            tracks: vec![],
+            next_id: 0,
+        }
+    }
+
+    /// クラスが異なる検出結果も同じ物体として対応付けるかを設定します。
+    ///
+    /// 信号機の灯色のように、同じ物体のクラスがフレームごとに変わる場合に有効にします。
+    pub fn set_class_agnostic(&mut self, enable: bool) -> &mut Self {
+        self.same_class = !enable;
+        self
+    }
+
+    /// 追跡中の物体を全て削除します。追跡IDは続きから割り当てます。
+    pub fn reset(&mut self) {
+        self.tracks.clear();
+    }
+
+    /// 追跡中の物体を返します。
+    pub fn tracks(&self) -> &[TrackedDetection] {
+        &self.tracks
+    }
+
+    /// 1フレーム分の検出結果で追跡を更新します。
+    ///
+    /// # Args
+    /// * `detections` - 1フレーム分の検出結果
+    ///
+    /// # Return
+    /// * 追跡中の全ての物体。現在のフレームで検出されなかった物体は、最後の検出結果と `missed` を返します
+    pub fn update(&mut self, detections: &[DetectionData]) -> &[TrackedDetection] {
+        self.assign(detections);
+        &self.tracks
+    }
+
+    /// 1フレーム分の検出結果で追跡を更新し、検出結果ごとに割り当てた追跡IDを返します。
+    ///
+    /// 追跡IDごとに状態を持つ処理 (平滑化や点滅の検出など) で使います。
+    ///
+    /// # Args
+    /// * `detections` - 1フレーム分の検出結果
+    ///
+    /// # Return
+    /// * `detections` と同じ順の追跡ID
+    pub fn assign(&mut self, detections: &[DetectionData]) -> Vec<u64> {
+        let prev: Vec<&DetectionData> = self.tracks.iter().map(|t| &t.detection).collect();
+        let matches = match_boxes(&prev, detections, self.iou_threshold, self.same_class);
+        let mut matched = vec![false; self.tracks.len()];
+
+        let ids = detections
+            .iter()
+            .zip(matches)
+            .map(|(d, m)| match m {
+                Some(i) => {
+                    matched[i] = true;
+                    self.tracks[i].detection = *d;
+                    self.tracks[i].track_id
+                }
+                None => {
+                    let track_id = self.next_id;
+                    self.tracks.push(TrackedDetection {
+                        track_id,
+                        detection: *d,
+                        missed: 0,
+                    });
+                    matched.push(true);
+                    self.next_id += 1;
+                    track_id
+                }
+            })
+            .collect();
+
+        // 検出されなかった物体は一定フレーム後に削除
+        for (track, m) in self.tracks.iter_mut().zip(matched.iter()) {
+            track.missed = if *m { 0 } else { track.missed + 1 };
+        }
+        let max_missed = self.max_missed;
+        self.tracks.retain(|t| t.missed <= max_missed);
+
+        ids
+    }
+
+    /// 追跡中の物体の追跡IDかを返します。
+    pub fn is_tracked(&self, track_id: u64) -> bool {
+        self.tracks.iter().any(|t| t.track_id == track_id)
+    }
+}
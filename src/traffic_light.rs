@@ -0,0 +1,318 @@
+//! 信号機の状態をフレーム間で追跡するモジュール
+
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::Result;
+use image::RgbImage;
+
+use crate::detection_result::DetectionData;
+use crate::tracker::Tracker;
+use crate::validator::TrafficLightValidator;
+
+/// 信号機の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalState {
+    /// 赤信号 (クラス0)
+    Red,
+    /// 黄信号 (クラス1)
+    Yellow,
+    /// 青信号 (クラス2)
+    Blue,
+    /// 点滅している (点滅している灯器のクラス)
+    Blinking(u8),
+    /// 信号機が検出されていない
+    Unknown,
+}
+
+impl SignalState {
+    /// クラスIDから信号機の状態を求めます。
+    ///
+    /// # Args
+    /// * `class` - クラスID
+    ///
+    /// # Return
+    /// * 信号機の状態。信号機のクラスでない場合はNone
+    pub fn from_class(class: u8) -> Option<Self> {
+        match class {
+            0 => Some(Self::Red),
+            1 => Some(Self::Yellow),
+            2 => Some(Self::Blue),
+            _ => None,
+        }
+    }
+}
+
+/// 信号機の状態の変化を表す構造体
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateChange {
+    /// 変化前の状態
+    pub from: SignalState,
+    /// 変化後の状態
+    pub to: SignalState,
+    /// 状態が確定したフレーム番号
+    pub frame: u64,
+}
+
+/// フレームごとの信号機の検出結果から、チャタリングを除去した状態の変化を求める状態機械
+///
+/// 新しい状態が `hysteresis` フレーム連続で観測されたときに状態を遷移させます。
+pub struct TrafficLightStateMachine {
+    state: SignalState,
+    candidate: SignalState,
+    candidate_count: usize,
+    hysteresis: usize,
+    frame: u64,
+}
+
+impl TrafficLightStateMachine {
+    /// 新しい `TrafficLightStateMachine` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `hysteresis` - 状態を遷移させるのに必要な連続フレーム数
+    pub fn new(hysteresis: usize) -> Self {
+        Self {
+            state: SignalState::Unknown,
+            candidate: SignalState::Unknown,
+            candidate_count: 0,
+            hysteresis: hysteresis.max(1),
+            frame: 0,
+        }
+    }
+
+    /// 現在の状態を返します。
+    pub fn state(&self) -> SignalState {
+        self.state
+    }
+
+    /// 状態を初期化します。
+    pub fn reset(&mut self) {
+        self.state = SignalState::Unknown;
+        self.candidate = SignalState::Unknown;
+        self.candidate_count = 0;
+        self.frame = 0;
+    }
+
+    /// 1フレーム分の検出結果から観測された信号機の状態を求めます。
+    ///
+    /// 信号機のクラスの検出結果のうち、最もコンフィデンスの高いものを採用します。
+    ///
+    /// # Args
+    /// * `detections` - 1フレーム分の検出結果
+    pub fn observe(detections: &[DetectionData]) -> SignalState {
+        detections
+            .iter()
+            .filter_map(|d| SignalState::from_class(d.class).map(|s| (s, d.confidence)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(s, _)| s)
+            .unwrap_or(SignalState::Unknown)
+    }
+
+    /// 1フレーム分の検出結果で状態を更新します。
+    ///
+    /// # Args
+    /// * `detections` - 検証済みの1フレーム分の検出結果
+    ///
+    /// # Return
+    /// * 状態が遷移した場合は状態の変化
+    pub fn update(&mut self, detections: &[DetectionData]) -> Option<StateChange> {
+        self.update_state(Self::observe(detections))
+    }
+
+    /// 1フレーム分の観測された状態で状態を更新します。
+    ///
+    /// # Args
+    /// * `observed` - 観測された状態
+    ///
+    /// # Return
+    /// * 状態が遷移した場合は状態の変化
+    pub fn update_state(&mut self, observed: SignalState) -> Option<StateChange> {
+        self.frame += 1;
+
+        if observed == self.state {
+            self.candidate = self.state;
+            self.candidate_count = 0;
+            return None;
+        }
+
+        if observed == self.candidate {
+            self.candidate_count += 1;
+        } else {
+            self.candidate = observed;
+            self.candidate_count = 1;
+        }
+
+        if self.candidate_count >= self.hysteresis {
+            let change = StateChange {
+                from: self.state,
+                to: observed,
+                frame: self.frame,
+            };
+            self.state = observed;
+            self.candidate_count = 0;
+            Some(change)
+        } else {
+            None
+        }
+    }
+}
+
+/// 点滅検出の設定
+#[derive(Debug, Clone)]
+pub struct BlinkConfig {
+    /// 輝度の履歴を保持するフレーム数
+    pub window: usize,
+    /// 点滅と判定するのに必要な、履歴内での点灯/消灯の切り替わり回数
+    pub min_toggles: usize,
+    /// 点灯と消灯を区別するのに必要な、履歴内の最大輝度と最小輝度の差
+    pub min_contrast: f64,
+    /// フレーム間で同じ信号機とみなすIoUの閾値
+    pub iou_threshold: f32,
+    /// 検出されなかったときに追跡を継続するフレーム数
+    pub max_missed: usize,
+}
+
+impl Default for BlinkConfig {
+    fn default() -> Self {
+        Self {
+            window: 30,
+            min_toggles: 4,
+            min_contrast: 0.2,
+            iou_threshold: 0.3,
+            max_missed: 5,
+        }
+    }
+}
+
+/// 信号機ごとの点滅の判定結果
+#[derive(Debug, Clone)]
+pub struct BlinkStatus {
+    /// 追跡ID
+    pub track_id: u64,
+    /// 現在のフレームでの検出結果
+    pub detection: DetectionData,
+    /// 点滅している灯器のクラス。点滅していない場合はNone
+    pub blinking_class: Option<u8>,
+}
+
+impl BlinkStatus {
+    /// 判定結果を信号機の状態に変換します。
+    pub fn state(&self) -> SignalState {
+        match self.blinking_class {
+            Some(class) => SignalState::Blinking(class),
+            None => SignalState::from_class(self.detection.class).unwrap_or(SignalState::Unknown),
+        }
+    }
+}
+
+/// 信号機を追跡し、灯器ごとの輝度の履歴から点滅を検出する構造体
+pub struct BlinkDetector {
+    config: BlinkConfig,
+    tracker: Tracker,
+    /// 追跡IDごとの灯器の輝度の履歴
+    histories: HashMap<u64, VecDeque<Vec<f64>>>,
+}
+
+impl BlinkDetector {
+    /// 新しい `BlinkDetector` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `config` - 点滅検出の設定
+    pub fn new(config: BlinkConfig) -> Self {
+        let mut tracker = Tracker::new(config.iou_threshold, config.max_missed);
+        // 灯色が変わっても同じ信号機として追跡する
+        tracker.set_class_agnostic(true);
+        Self {
+            config,
+            tracker,
+            histories: HashMap::new(),
+        }
+    }
+
+    /// 追跡中の信号機を全て削除します。
+    pub fn reset(&mut self) {
+        self.tracker.reset();
+        self.histories.clear();
+    }
+
+    /// 1フレーム分の検出結果で追跡を更新し、点滅を判定します。
+    ///
+    /// # Args
+    /// * `img` - 検出結果の座標系に対応する画像
+    /// * `detections` - 1フレーム分の検出結果
+    /// * `validator` - 灯器の配置と輝度の計算に使うバリデータ
+    ///
+    /// # Return
+    /// * 信号機のクラスの検出結果ごとの点滅の判定結果
+    pub fn update(
+        &mut self,
+        img: &RgbImage,
+        detections: &[DetectionData],
+        validator: &TrafficLightValidator,
+    ) -> Result<Vec<BlinkStatus>> {
+        let targets: Vec<DetectionData> = detections
+            .iter()
+            .filter(|d| d.class <= validator.config.max_target_class())
+            .copied()
+            .collect();
+        // 輝度を求められない場合は、追跡を更新せずにエラーを返す
+        let brightness = targets
+            .iter()
+            .map(|d| validator.lamp_brightness(img, d))
+            .collect::<Result<Vec<_>>>()?;
+        let ids = self.tracker.assign(&targets);
+
+        let mut statuses = vec![];
+        for ((d, brightness), track_id) in targets.into_iter().zip(brightness).zip(ids) {
+            let history = self.histories.entry(track_id).or_default();
+            history.push_back(brightness);
+            while history.len() > self.config.window {
+                history.pop_front();
+            }
+
+            let blinking_class = Self::blinking_lamp(&self.config, history)
+                .and_then(|lamp| validator.config.layout().lamp_classes.get(lamp).copied());
+            statuses.push(BlinkStatus {
+                track_id,
+                detection: d,
+                blinking_class,
+            });
+        }
+
+        // 追跡が終わった信号機の履歴を削除
+        let tracker = &self.tracker;
+        self.histories.retain(|id, _| tracker.is_tracked(*id));
+
+        Ok(statuses)
+    }
+
+    /// 輝度の履歴から点滅している灯器を求めます。
+    ///
+    /// # Args
+    /// * `config` - 点滅検出の設定
+    /// * `history` - 灯器ごとの輝度の履歴
+    ///
+    /// # Return
+    /// * 点滅している灯器のうち、切り替わり回数が最も多いもののインデックス
+    fn blinking_lamp(config: &BlinkConfig, history: &VecDeque<Vec<f64>>) -> Option<usize> {
+        let n_lamps = history.iter().map(|h| h.len()).min().unwrap_or(0);
+
+        (0..n_lamps)
+            .filter_map(|lamp| {
+                let values: Vec<f64> = history.iter().map(|h| h[lamp]).collect();
+                let max = values.iter().cloned().fold(f64::MIN, f64::max);
+                let min = values.iter().cloned().fold(f64::MAX, f64::min);
+                if max - min < config.min_contrast {
+                    return None;
+                }
+
+                let mid = (max + min) / 2.;
+                let toggles = values
+                    .windows(2)
+                    .filter(|w| (w[0] >= mid) != (w[1] >= mid))
+                    .count();
+                (toggles >= config.min_toggles).then_some((lamp, toggles))
+            })
+            .max_by_key(|&(_, toggles)| toggles)
+            .map(|(lamp, _)| lamp)
+    }
+}
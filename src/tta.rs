@@ -0,0 +1,65 @@
+//! 反転・回転した画像の推論結果を統合するTest-Time Augmentation (TTA)のモジュール
+
+use image::DynamicImage;
+
+use crate::detection_result::DetectionData;
+
+/// TTAで使う画像の変換
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Augmentation {
+    /// 変換しない
+    Identity,
+    /// 左右反転
+    FlipHorizontal,
+    /// 上下反転
+    FlipVertical,
+    /// 時計回りに90度回転
+    Rotate90,
+    /// 180度回転
+    Rotate180,
+    /// 時計回りに270度回転
+    Rotate270,
+}
+
+impl Augmentation {
+    /// 画像に変換を適用します。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    ///
+    /// # Return
+    /// * 変換後の画像
+    pub fn apply(&self, img: &DynamicImage) -> DynamicImage {
+        match self {
+            Self::Identity => img.clone(),
+            Self::FlipHorizontal => img.fliph(),
+            Self::FlipVertical => img.flipv(),
+            Self::Rotate90 => img.rotate90(),
+            Self::Rotate180 => img.rotate180(),
+            Self::Rotate270 => img.rotate270(),
+        }
+    }
+
+    /// 変換後の画像の座標系の検出結果を変換前の画像の座標系に戻します。
+    ///
+    /// # Args
+    /// * `d` - 変換後の画像の座標系の検出結果
+    /// * `width` - 変換前の画像の幅
+    /// * `height` - 変換前の画像の高さ
+    ///
+    /// # Return
+    /// * 変換前の画像の座標系の検出結果
+    pub fn invert(&self, d: &DetectionData, width: u32, height: u32) -> DetectionData {
+        let w = width as f32;
+        let h = height as f32;
+        let (x1, y1, x2, y2) = match self {
+            Self::Identity => (d.x1, d.y1, d.x2, d.y2),
+            Self::FlipHorizontal => (w - d.x2, d.y1, w - d.x1, d.y2),
+            Self::FlipVertical => (d.x1, h - d.y2, d.x2, h - d.y1),
+            Self::Rotate90 => (d.y1, h - d.x2, d.y2, h - d.x1),
+            Self::Rotate180 => (w - d.x2, h - d.y2, w - d.x1, h - d.y1),
+            Self::Rotate270 => (w - d.y2, d.x1, w - d.y1, d.x2),
+        };
+        DetectionData::new(d.class, x1, y1, x2, y2, d.confidence).with_sub_state(d.sub_state)
+    }
+}
@@ -0,0 +1,205 @@
+//! [u-dma-buf](https://github.com/ikwzm/udmabuf) で確保した物理的に連続なバッファを扱うモジュール
+//!
+//! バッファはキャッシュを有効にしてmmapするため、DMAとの受け渡しの前後で明示的に同期する必要があります。
+//!
+//! * CPUが書き込んだ内容をDMAで読ませる前 - `sync_for_device`
+//! * DMAが書き込んだ内容をCPUで読む前 - `sync_for_cpu`
+
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use memmap2::{MmapMut, MmapOptions};
+
+/// 同期の方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncDirection {
+    /// 双方向
+    Bidirectional = 0,
+    /// CPUからデバイスへ
+    ToDevice = 1,
+    /// デバイスからCPUへ
+    FromDevice = 2,
+}
+
+/// sysfsの属性を読み込みます。
+fn read_attr(sysfs: &Path, attr: &str) -> Result<String> {
+    let path = sysfs.join(attr);
+    Ok(fs::read_to_string(&path)
+        .with_context(|| format!("Can't read {}", path.display()))?
+        .trim()
+        .to_string())
+}
+
+/// sysfsの属性に書き込みます。
+fn write_attr(sysfs: &Path, attr: &str, value: &str) -> Result<()> {
+    let path = sysfs.join(attr);
+    fs::write(&path, value).with_context(|| format!("Can't write {}", path.display()))
+}
+
+/// バッファの一部のキャッシュを同期します。
+///
+/// # Args
+/// * `sysfs` - バッファのsysfsのディレクトリ
+/// * `for_device` - `true` の場合は `sync_for_device`、`false` の場合は `sync_for_cpu`
+/// * `offset` - 同期する範囲の先頭 (バイト)
+/// * `size` - 同期する範囲の大きさ (バイト)
+/// * `direction` - 同期の方向
+pub(crate) fn sync(
+    sysfs: &Path,
+    for_device: bool,
+    offset: usize,
+    size: usize,
+    direction: SyncDirection,
+) -> Result<()> {
+    write_attr(sysfs, "sync_offset", &offset.to_string())?;
+    write_attr(sysfs, "sync_size", &size.to_string())?;
+    write_attr(sysfs, "sync_direction", &(direction as u8).to_string())?;
+    let attr = if for_device {
+        "sync_for_device"
+    } else {
+        "sync_for_cpu"
+    };
+    write_attr(sysfs, attr, "1")
+}
+
+/// u-dma-bufのバッファ
+pub struct UdmaBuf {
+    name: String,
+    sysfs: PathBuf,
+    map: MmapMut,
+    phys_addr: u64,
+}
+
+impl UdmaBuf {
+    /// バッファを開き、キャッシュを有効にしてmmapします。
+    ///
+    /// # Args
+    /// * `name` - デバイス名 (例: `udmabuf0`)
+    pub fn open(name: &str) -> Result<Self> {
+        let sysfs = Path::new("/sys/class/u-dma-buf").join(name);
+        let phys_addr = read_attr(&sysfs, "phys_addr")?;
+        let phys_addr = u64::from_str_radix(phys_addr.trim_start_matches("0x"), 16)
+            .with_context(|| format!("Invalid phys_addr: {}", phys_addr))?;
+        let size: usize = read_attr(&sysfs, "size")?.parse().context("Invalid size")?;
+        // 同期を明示的に行うため、sync_modeによるキャッシュの無効化を使わない
+        write_attr(&sysfs, "sync_mode", "0")?;
+
+        let dev = format!("/dev/{}", name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&dev)
+            .with_context(|| format!("Can't open {}", dev))?;
+        // SAFETY: バッファはDMAと共有されるため、sync_for_cpu/sync_for_deviceで同期してからアクセスする
+        let map = unsafe { MmapOptions::new().len(size).map_mut(&file) }
+            .with_context(|| format!("Can't map {}", dev))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            sysfs,
+            map,
+            phys_addr,
+        })
+    }
+
+    /// デバイス名を返します。
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// バッファの先頭の物理アドレスを返します。
+    pub fn phys_addr(&self) -> u64 {
+        self.phys_addr
+    }
+
+    /// バッファの大きさ (バイト) を返します。
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// バッファの大きさが0かを返します。
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// バッファの内容を返します。
+    pub fn as_slice(&self) -> &[u8] {
+        &self.map
+    }
+
+    /// バッファの内容を書き換えるためのスライスを返します。
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.map
+    }
+
+    /// DMAが書き込んだ範囲をCPUで読めるよう同期します。
+    ///
+    /// # Args
+    /// * `offset` - 同期する範囲の先頭 (バイト)
+    /// * `size` - 同期する範囲の大きさ (バイト)
+    pub fn sync_for_cpu(&self, offset: usize, size: usize) -> Result<()> {
+        self.check_range(offset, size)?;
+        sync(&self.sysfs, false, offset, size, SyncDirection::FromDevice)
+    }
+
+    /// CPUが書き込んだ範囲をDMAで読めるよう同期します。
+    ///
+    /// # Args
+    /// * `offset` - 同期する範囲の先頭 (バイト)
+    /// * `size` - 同期する範囲の大きさ (バイト)
+    pub fn sync_for_device(&self, offset: usize, size: usize) -> Result<()> {
+        self.check_range(offset, size)?;
+        sync(&self.sysfs, true, offset, size, SyncDirection::ToDevice)
+    }
+
+    /// `i16` のデータをリトルエンディアンで書き込み、DMAで読めるよう同期します。
+    ///
+    /// # Args
+    /// * `offset` - 書き込む位置 (バイト)
+    /// * `data` - 書き込むデータ
+    pub fn write_i16(&mut self, offset: usize, data: &[i16]) -> Result<()> {
+        let size = data.len() * 2;
+        self.check_range(offset, size)?;
+        for (dst, v) in self.map[offset..offset + size]
+            .chunks_exact_mut(2)
+            .zip(data)
+        {
+            dst.copy_from_slice(&v.to_le_bytes());
+        }
+        self.sync_for_device(offset, size)
+    }
+
+    /// DMAが書き込んだ `i16` のデータを同期してから読み込みます。
+    ///
+    /// # Args
+    /// * `offset` - 読み込む位置 (バイト)
+    /// * `len` - 読み込む要素の数
+    pub fn read_i16(&self, offset: usize, len: usize) -> Result<Vec<i16>> {
+        let size = len * 2;
+        self.sync_for_cpu(offset, size)?;
+        Ok(self.map[offset..offset + size]
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect())
+    }
+
+    /// 範囲がバッファに収まっているかを確認します。
+    fn check_range(&self, offset: usize, size: usize) -> Result<()> {
+        if offset.checked_add(size).is_none_or(|end| end > self.len()) {
+            bail!(
+                "Range {}..{} exceeds {} ({} bytes)",
+                offset,
+                offset.saturating_add(size),
+                self.name,
+                self.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// sysfsのディレクトリを返します。
+    pub(crate) fn sysfs(&self) -> &Path {
+        &self.sysfs
+    }
+}
@@ -0,0 +1,65 @@
+//! UIOで公開されたIPのレジスタを扱うモジュール
+
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use memmap2::{MmapMut, MmapOptions};
+
+/// mmapしたレジスタ領域
+pub(crate) struct Registers {
+    map: MmapMut,
+}
+
+impl Registers {
+    /// UIOデバイスのレジスタ領域をmmapします。
+    ///
+    /// # Args
+    /// * `uio` - レジスタを公開しているUIOデバイス (例: `/dev/uio4`)
+    /// * `len` - mmapする大きさ (バイト)
+    pub(crate) fn open(uio: &Path, len: usize) -> Result<Self> {
+        let file = open_rw(uio)?;
+        // SAFETY: レジスタ領域は他のプロセスと共有されることを前提に揮発的にアクセスする
+        let map = unsafe { MmapOptions::new().len(len).map_mut(&file) }
+            .with_context(|| format!("Can't map {}", uio.display()))?;
+        Ok(Self { map })
+    }
+
+    pub(crate) fn read(&self, offset: usize) -> u32 {
+        assert!(offset + 4 <= self.map.len());
+        // SAFETY: 範囲内の4バイト境界のアドレスで、レジスタは揮発的に読む必要がある
+        unsafe { std::ptr::read_volatile(self.map.as_ptr().add(offset) as *const u32) }
+    }
+
+    pub(crate) fn write(&mut self, offset: usize, value: u32) {
+        assert!(offset + 4 <= self.map.len());
+        // SAFETY: 範囲内の4バイト境界のアドレスで、レジスタは揮発的に書く必要がある
+        unsafe { std::ptr::write_volatile(self.map.as_mut_ptr().add(offset) as *mut u32, value) }
+    }
+}
+
+/// ハードウェア情報に記録されたデバイス名 (例: `uio4`) のパスを返します。
+///
+/// # Args
+/// * `info` - IPのハードウェア情報
+/// * `key` - デバイス名のキー (例: `uio`)
+/// * `name` - エラーに含めるIPの名前
+pub(crate) fn device_path(info: &serde_json::Value, key: &str, name: &str) -> Result<PathBuf> {
+    let dev = info[key]
+        .as_str()
+        .with_context(|| format!("{} has no {} entry in the hardware information", name, key))?;
+    Ok(if dev.starts_with('/') {
+        PathBuf::from(dev)
+    } else {
+        Path::new("/dev").join(dev)
+    })
+}
+
+/// デバイスを読み書きできるように開きます。
+pub(crate) fn open_rw(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Can't open {}", path.display()))
+}
@@ -0,0 +1,559 @@
+//! 検出結果の検証・補正を行うモジュール
+
+use anyhow::{ensure, Result};
+use image::RgbImage;
+
+use crate::arrow::ArrowClassifier;
+use crate::detection_result::{DetectionData, SubState};
+use crate::region::{BrightnessMetric, Region, RegionMask};
+
+/// 検出結果を検証・補正するためのトレイト
+pub trait Validator {
+    /// 検出結果を検証し、必要に応じてクラスを補正したり、除外したりします。
+    ///
+    /// # Args
+    /// * `img` - 検出結果の座標系に対応する画像
+    /// * `detections` - 検出結果 (in-place)
+    fn validate(&mut self, img: &RgbImage, detections: &mut Vec<DetectionData>) -> Result<()>;
+}
+
+/// 色相の範囲 (度)
+///
+/// `min` が `max` より大きい場合は、0度をまたぐ範囲として扱います。
+#[derive(Debug, Clone, Copy)]
+pub struct HueRange {
+    /// 範囲の下限
+    pub min: f64,
+    /// 範囲の上限
+    pub max: f64,
+}
+
+impl HueRange {
+    /// 新しい `HueRange` インスタンスを作成します。
+    pub fn new(min: f64, max: f64) -> Self {
+        Self { min, max }
+    }
+
+    /// 範囲の上限と下限が0度から360度の間にあるかを検証します。
+    ///
+    /// # Args
+    /// * `name` - エラーメッセージに表示する設定の名前
+    fn validate(&self, name: &str) -> Result<()> {
+        for v in [self.min, self.max] {
+            ensure!(
+                (0. ..=360.).contains(&v),
+                "{} must be in [0, 360], got [{}, {}]",
+                name,
+                self.min,
+                self.max
+            );
+        }
+        Ok(())
+    }
+
+    /// 色相が範囲内にあるかを返します。
+    pub fn contains(&self, hue: f64) -> bool {
+        if self.min <= self.max {
+            self.min <= hue && hue <= self.max
+        } else {
+            self.min <= hue || hue <= self.max
+        }
+    }
+}
+
+/// 信号機の灯器が並んでいる方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LampAxis {
+    /// 灯器が横に並んでいる (BBoxを列に分割する)
+    Horizontal,
+    /// 灯器が縦に並んでいる (BBoxを行に分割する)
+    Vertical,
+}
+
+/// 信号機の灯器の配置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrafficLightLayout {
+    /// 灯器が並んでいる方向
+    pub axis: LampAxis,
+    /// 灯器ごとのクラス (横配置では左から、縦配置では上から順)
+    pub lamp_classes: Vec<u8>,
+}
+
+impl Default for TrafficLightLayout {
+    fn default() -> Self {
+        Self::horizontal(vec![2, 0])
+    }
+}
+
+impl TrafficLightLayout {
+    /// 灯器が横に並んだ配置を作成します。
+    ///
+    /// # Args
+    /// * `lamp_classes` - 左から順に並べた灯器ごとのクラス
+    pub fn horizontal(lamp_classes: Vec<u8>) -> Self {
+        Self {
+            axis: LampAxis::Horizontal,
+            lamp_classes,
+        }
+    }
+
+    /// 灯器が縦に並んだ配置を作成します。
+    ///
+    /// # Args
+    /// * `lamp_classes` - 上から順に並べた灯器ごとのクラス
+    pub fn vertical(lamp_classes: Vec<u8>) -> Self {
+        Self {
+            axis: LampAxis::Vertical,
+            lamp_classes,
+        }
+    }
+
+    /// 灯器の数を返します。
+    pub fn lamp_count(&self) -> u32 {
+        self.lamp_classes.len() as u32
+    }
+}
+
+/// 信号機のバリデータの設定
+#[derive(Debug, Clone)]
+pub struct TrafficLightConfig {
+    trim_rate: f32,
+    layout: TrafficLightLayout,
+    max_target_class: u8,
+    min_bright_ratio: f64,
+    min_absolute_brightness: f64,
+    hue_check_en: bool,
+    red_hue: HueRange,
+    yellow_hue: HueRange,
+    blue_hue: HueRange,
+    lamp_mask: RegionMask,
+    brightness_metric: BrightnessMetric,
+    arrow_class: u8,
+    arrow_classifier: Option<ArrowClassifier>,
+}
+
+impl Default for TrafficLightConfig {
+    fn default() -> Self {
+        Self {
+            trim_rate: 0.12,
+            layout: TrafficLightLayout::default(),
+            max_target_class: 2,
+            min_bright_ratio: 1.0,
+            min_absolute_brightness: 0.0,
+            hue_check_en: false,
+            red_hue: HueRange::new(330., 30.),
+            yellow_hue: HueRange::new(30., 70.),
+            blue_hue: HueRange::new(140., 220.),
+            lamp_mask: RegionMask::Rectangle,
+            brightness_metric: BrightnessMetric::Mean,
+            arrow_class: 2,
+            arrow_classifier: None,
+        }
+    }
+}
+
+impl TrafficLightConfig {
+    /// BBoxの両端 (灯器が並んでいる方向) を切り落とす割合を設定します。
+    ///
+    /// # Return
+    /// * Result。`trim_rate` が0以上0.5未満でない場合はエラー
+    pub fn set_trim_rate(&mut self, trim_rate: f32) -> Result<&mut Self> {
+        // 両端から0.5ずつ切り落とすと灯器の領域が残らない
+        ensure!(
+            (0. ..0.5).contains(&trim_rate),
+            "trim_rate must be in [0, 0.5), got {}",
+            trim_rate
+        );
+        self.trim_rate = trim_rate;
+        Ok(self)
+    }
+
+    /// 灯器の配置を設定します。
+    ///
+    /// # Return
+    /// * Result。灯器が1つもない場合はエラー
+    pub fn set_layout(&mut self, layout: TrafficLightLayout) -> Result<&mut Self> {
+        ensure!(
+            !layout.lamp_classes.is_empty(),
+            "lamp_classes must not be empty"
+        );
+        self.layout = layout;
+        Ok(self)
+    }
+
+    /// 検証の対象とするクラスの最大値を設定します。
+    pub fn set_max_target_class(&mut self, max_target_class: u8) -> &mut Self {
+        self.max_target_class = max_target_class;
+        self
+    }
+
+    /// 最も明るい領域の平均輝度が、他の領域の平均輝度の何倍以上であるべきかを設定します。
+    ///
+    /// # Return
+    /// * Result。`min_bright_ratio` が負の場合はエラー
+    pub fn set_min_bright_ratio(&mut self, min_bright_ratio: f64) -> Result<&mut Self> {
+        ensure!(
+            min_bright_ratio >= 0.,
+            "min_bright_ratio must not be negative, got {}",
+            min_bright_ratio
+        );
+        self.min_bright_ratio = min_bright_ratio;
+        Ok(self)
+    }
+
+    /// 最も明るい領域の平均輝度 (0.0-1.0) の下限を設定します。
+    ///
+    /// # Return
+    /// * Result。`min_absolute_brightness` が負の場合はエラー
+    pub fn set_min_absolute_brightness(
+        &mut self,
+        min_absolute_brightness: f64,
+    ) -> Result<&mut Self> {
+        ensure!(
+            min_absolute_brightness >= 0.,
+            "min_absolute_brightness must not be negative, got {}",
+            min_absolute_brightness
+        );
+        self.min_absolute_brightness = min_absolute_brightness;
+        Ok(self)
+    }
+
+    /// 点灯色の色相のチェックを行うかを設定します。
+    pub fn set_hue_check_en(&mut self, hue_check_en: bool) -> &mut Self {
+        self.hue_check_en = hue_check_en;
+        self
+    }
+
+    /// 赤信号の色相の範囲を設定します。
+    ///
+    /// # Return
+    /// * Result。範囲が0度から360度の間にない場合はエラー
+    pub fn set_red_hue(&mut self, red_hue: HueRange) -> Result<&mut Self> {
+        red_hue.validate("red_hue")?;
+        self.red_hue = red_hue;
+        Ok(self)
+    }
+
+    /// 黄信号の色相の範囲を設定します。
+    ///
+    /// # Return
+    /// * Result。範囲が0度から360度の間にない場合はエラー
+    pub fn set_yellow_hue(&mut self, yellow_hue: HueRange) -> Result<&mut Self> {
+        yellow_hue.validate("yellow_hue")?;
+        self.yellow_hue = yellow_hue;
+        Ok(self)
+    }
+
+    /// 青信号の色相の範囲を設定します。
+    ///
+    /// # Return
+    /// * Result。範囲が0度から360度の間にない場合はエラー
+    pub fn set_blue_hue(&mut self, blue_hue: HueRange) -> Result<&mut Self> {
+        blue_hue.validate("blue_hue")?;
+        self.blue_hue = blue_hue;
+        Ok(self)
+    }
+
+    /// 灯器ごとの領域のうち、輝度と色相を求める範囲の形を設定します。
+    ///
+    /// `RegionMask::Ellipse` や `RegionMask::Circle` にすると、灯器の周囲の暗い筐体を除いて集計できます。
+    pub fn set_lamp_mask(&mut self, lamp_mask: RegionMask) -> &mut Self {
+        self.lamp_mask = lamp_mask;
+        self
+    }
+
+    /// 灯器ごとの領域の明るさの指標を設定します。
+    ///
+    /// `min_bright_ratio` と `min_absolute_brightness` はこの指標の値と比較されます。
+    ///
+    /// # Return
+    /// * Result。パーセンタイルや閾値が0.0-1.0の範囲にない場合はエラー
+    pub fn set_brightness_metric(
+        &mut self,
+        brightness_metric: BrightnessMetric,
+    ) -> Result<&mut Self> {
+        match brightness_metric {
+            BrightnessMetric::Mean => {}
+            BrightnessMetric::Percentile(p) => ensure!(
+                (0. ..=1.).contains(&p),
+                "brightness percentile must be in [0, 1], got {}",
+                p
+            ),
+            BrightnessMetric::BrightFraction(threshold) => ensure!(
+                (0. ..=1.).contains(&threshold),
+                "bright_threshold must be in [0, 1], got {}",
+                threshold
+            ),
+        }
+        self.brightness_metric = brightness_metric;
+        Ok(self)
+    }
+
+    /// 矢印信号の向きの判定を設定します。
+    ///
+    /// 補正後のクラスが `arrow_class` の場合に、点灯している灯器の形から矢印の向きを判定し、
+    /// 検出結果の `sub_state` に設定します。
+    ///
+    /// # Args
+    /// * `arrow_class` - 矢印信号の判定を行うクラス (通常は青信号)
+    /// * `classifier` - 矢印の向きの判定器。判定しない場合はNone
+    pub fn set_arrow_classifier(
+        &mut self,
+        arrow_class: u8,
+        classifier: Option<ArrowClassifier>,
+    ) -> &mut Self {
+        self.arrow_class = arrow_class;
+        self.arrow_classifier = classifier;
+        self
+    }
+
+    /// BBoxの両端 (灯器が並んでいる方向) を切り落とす割合を返します。
+    pub fn trim_rate(&self) -> f32 {
+        self.trim_rate
+    }
+
+    /// 灯器の配置を返します。
+    pub fn layout(&self) -> &TrafficLightLayout {
+        &self.layout
+    }
+
+    /// 検証の対象とするクラスの最大値を返します。
+    pub fn max_target_class(&self) -> u8 {
+        self.max_target_class
+    }
+
+    /// 最も明るい領域と他の領域の平均輝度の比の下限を返します。
+    pub fn min_bright_ratio(&self) -> f64 {
+        self.min_bright_ratio
+    }
+
+    /// 最も明るい領域の平均輝度の下限を返します。
+    pub fn min_absolute_brightness(&self) -> f64 {
+        self.min_absolute_brightness
+    }
+
+    /// 点灯色の色相のチェックを行うかを返します。
+    pub fn hue_check_en(&self) -> bool {
+        self.hue_check_en
+    }
+
+    /// 灯器ごとの領域のうち、輝度と色相を求める範囲の形を返します。
+    pub fn lamp_mask(&self) -> RegionMask {
+        self.lamp_mask
+    }
+
+    /// 灯器ごとの領域の明るさの指標を返します。
+    pub fn brightness_metric(&self) -> BrightnessMetric {
+        self.brightness_metric
+    }
+
+    /// 矢印信号の判定を行うクラスと判定器を返します。判定しない場合はNone
+    pub fn arrow_classifier(&self) -> Option<(u8, &ArrowClassifier)> {
+        self.arrow_classifier
+            .as_ref()
+            .map(|c| (self.arrow_class, c))
+    }
+
+    /// クラスに対応する色相の範囲を返します。
+    ///
+    /// # Args
+    /// * `class` - クラス (0: 赤, 1: 黄, 2: 青)
+    pub fn hue_range(&self, class: u8) -> Option<HueRange> {
+        match class {
+            0 => Some(self.red_hue),
+            1 => Some(self.yellow_hue),
+            2 => Some(self.blue_hue),
+            _ => None,
+        }
+    }
+}
+
+/// 信号機の点灯位置からクラスを補正するバリデータ
+///
+/// BBoxを灯器の配置に従って灯器の数だけの領域に分割し、最も明るい領域に対応する灯器のクラスに補正します。
+/// デフォルトの配置では横に2分割し、左の領域は2 (青)，右の領域は0 (赤) になります。
+/// 輝度や色相のチェックを満たさない検出結果は除外されます。
+#[derive(Default)]
+pub struct TrafficLightValidator {
+    /// バリデータの設定
+    pub config: TrafficLightConfig,
+}
+
+impl TrafficLightValidator {
+    /// 新しい `TrafficLightValidator` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `config` - バリデータの設定
+    pub fn new(config: TrafficLightConfig) -> Self {
+        Self { config }
+    }
+
+    /// 1つの検出結果について、分割した領域ごとの統計量を求めます。
+    ///
+    /// # Args
+    /// * `img` - 検出結果の座標系に対応する画像
+    /// * `d_data` - 検出結果
+    ///
+    /// # Return
+    /// * 分割した領域のベクトル
+    fn analyze_regions(&self, img: &RgbImage, d_data: &DetectionData) -> Result<Vec<Region>> {
+        let layout = &self.config.layout;
+        let n_regions = layout.lamp_count();
+        let bbox = match layout.axis {
+            LampAxis::Horizontal => {
+                let trim_w: f32 = d_data.width() * self.config.trim_rate;
+                Region::new(
+                    (d_data.x1 + trim_w, d_data.y1),
+                    (d_data.x2 - trim_w, d_data.y2),
+                )?
+            }
+            LampAxis::Vertical => {
+                let trim_h: f32 = d_data.height() * self.config.trim_rate;
+                Region::new(
+                    (d_data.x1, d_data.y1 + trim_h),
+                    (d_data.x2, d_data.y2 - trim_h),
+                )?
+            }
+        };
+        let (region_w, region_h) = match layout.axis {
+            LampAxis::Horizontal => (bbox.width() / n_regions.max(1), bbox.height()),
+            LampAxis::Vertical => (bbox.width(), bbox.height() / n_regions.max(1)),
+        };
+
+        let mut regions = Vec::new();
+        for idx in 0..n_regions {
+            let (start_x, start_y) = match layout.axis {
+                LampAxis::Horizontal => (bbox.start.0 + idx * region_w, bbox.start.1),
+                LampAxis::Vertical => (bbox.start.0, bbox.start.1 + idx * region_h),
+            };
+            let end_x = start_x + region_w;
+            let end_y = start_y + region_h;
+            let mut new_region = Region::new(
+                (start_x as f32, start_y as f32),
+                (end_x as f32, end_y as f32),
+            )?;
+            new_region.set_mask(self.config.lamp_mask);
+            regions.push(new_region);
+        }
+
+        let end_x = bbox.end.0.min(img.width());
+        let end_y = bbox.end.1.min(img.height());
+        for y in bbox.start.1..end_y {
+            for x in bbox.start.0..end_x {
+                let pixel = img.get_pixel(x, y);
+                for region in regions.iter_mut() {
+                    if region.is_in((x, y)) {
+                        region.add_pixel(pixel.0)
+                    }
+                }
+            }
+        }
+        Ok(regions)
+    }
+
+    /// 1つの検出結果について、灯器ごとの明るさを設定した指標 (`set_brightness_metric`、既定は平均) で求めます。
+    ///
+    /// # Args
+    /// * `img` - 検出結果の座標系に対応する画像
+    /// * `d_data` - 検出結果
+    ///
+    /// # Return
+    /// * 灯器の配置順に並べた明るさ (0.0-1.0) のベクトル
+    pub fn lamp_brightness(&self, img: &RgbImage, d_data: &DetectionData) -> Result<Vec<f64>> {
+        Ok(self
+            .analyze_regions(img, d_data)?
+            .iter()
+            .map(|r| r.brightness(self.config.brightness_metric))
+            .collect())
+    }
+
+    /// 1つの検出結果を検証し、補正後のクラスを求めます。
+    ///
+    /// # Args
+    /// * `img` - 検出結果の座標系に対応する画像
+    /// * `d_data` - 検出結果
+    ///
+    /// # Return
+    /// * 補正後のクラスと矢印信号の向き。検証を満たさない場合はNone
+    fn classify(
+        &self,
+        img: &RgbImage,
+        d_data: &DetectionData,
+    ) -> Result<Option<(u8, Option<SubState>)>> {
+        let regions = self.analyze_regions(img, d_data)?;
+        let metric = self.config.brightness_metric;
+        // 平均の場合は、従来通り明るさの合計で最も明るい領域を選ぶ
+        let score = |r: &Region| match metric {
+            BrightnessMetric::Mean => r.total_brightness,
+            _ => r.brightness(metric),
+        };
+
+        let Some((idx, brightest)) = regions
+            .iter()
+            .enumerate()
+            .max_by(|(_, r1), (_, r2)| score(r1).total_cmp(&score(r2)))
+        else {
+            return Ok(None);
+        };
+
+        let bright = brightest.brightness(metric);
+        if bright < self.config.min_absolute_brightness {
+            return Ok(None);
+        }
+
+        let others: Vec<f64> = regions
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != idx)
+            .map(|(_, r)| r.brightness(metric))
+            .collect();
+        if !others.is_empty() {
+            let others_mean = others.iter().sum::<f64>() / others.len() as f64;
+            if bright < others_mean * self.config.min_bright_ratio {
+                return Ok(None);
+            }
+        }
+
+        let class = self.config.layout.lamp_classes[idx];
+
+        if self.config.hue_check_en {
+            if let Some(range) = self.config.hue_range(class) {
+                // 白飛びなどで有彩色のピクセルがない場合は平均色の色相を使う
+                let hue = brightest
+                    .circular_mean_hue()
+                    .unwrap_or_else(|| brightest.mean_hue());
+                if !range.contains(hue) {
+                    return Ok(None);
+                }
+            }
+        }
+
+        let sub_state = match &self.config.arrow_classifier {
+            Some(classifier) if class == self.config.arrow_class => {
+                classifier.classify(img, brightest)
+            }
+            _ => None,
+        };
+        Ok(Some((class, sub_state)))
+    }
+}
+
+impl Validator for TrafficLightValidator {
+    fn validate(&mut self, img: &RgbImage, detections: &mut Vec<DetectionData>) -> Result<()> {
+        let mut validated = Vec::with_capacity(detections.len());
+        for d_data in detections.iter() {
+            if d_data.class > self.config.max_target_class {
+                validated.push(*d_data);
+                continue;
+            }
+            if let Some((class, sub_state)) = self.classify(img, d_data)? {
+                let mut d = *d_data;
+                d.class = class;
+                d.sub_state = sub_state;
+                validated.push(d);
+            }
+        }
+        *detections = validated;
+        Ok(())
+    }
+}
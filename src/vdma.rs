@@ -0,0 +1,298 @@
+//! PLのビデオパイプライン (MIPI CSI → AXI VDMA) からフレームを取得するモジュール
+//!
+//! AXI VDMAのS2MMチャネルを循環モードで動かし、書き込みが完了したフレームバッファを読み出します。
+//! USBカメラやV4L2を経由しないため、低遅延にYOLOの前処理へフレームを渡せます。
+
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use image::RgbImage;
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::udmabuf::{self, SyncDirection, UdmaBuf};
+use crate::uio::{self, open_rw, Registers};
+
+/// PARK_PTR_REG
+const PARK_PTR_REG: usize = 0x28;
+/// S2MM_VDMACR
+const S2MM_VDMACR: usize = 0x30;
+/// S2MM_VDMASR
+const S2MM_VDMASR: usize = 0x34;
+/// S2MM_VSIZE
+const S2MM_VSIZE: usize = 0xa0;
+/// S2MM_HSIZE
+const S2MM_HSIZE: usize = 0xa4;
+/// S2MM_FRMDLY_STRIDE
+const S2MM_FRMDLY_STRIDE: usize = 0xa8;
+/// S2MM_START_ADDRESS1
+const S2MM_START_ADDRESS: usize = 0xac;
+
+/// S2MM_VDMASRのエラーを示すビット
+const S2MM_ERR_MASK: u32 = 0x89f0;
+/// フレームバッファの最大数
+const MAX_FRAMES: usize = 16;
+
+/// フレームバッファ上のピクセル形式 (1ピクセル3バイト)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoFormat {
+    /// R, G, Bの順
+    Rgb,
+    /// B, G, Rの順
+    Bgr,
+    /// G, B, Rの順 (XilinxのAXI4-Stream VideoのRGBの並び)
+    Gbr,
+}
+
+/// フレームバッファの配置
+#[derive(Debug, Clone)]
+pub struct FrameBuffers {
+    /// フレームバッファをmmapするデバイス (`/dev/mem` など)
+    pub device: PathBuf,
+    /// 先頭のフレームバッファの物理アドレス (ページ境界に揃っている必要があります)
+    pub phys_addr: u64,
+    /// `device` をmmapするときのオフセット。`/dev/mem` の場合は物理アドレスと同じです
+    pub offset: u64,
+    /// フレームバッファの数 (2以上、VDMAのフレームストアの数以下)
+    pub count: usize,
+    /// キャッシュを有効にしてmmapする場合の、同期に使うu-dma-bufのsysfsのディレクトリ
+    pub sync: Option<PathBuf>,
+}
+
+impl FrameBuffers {
+    /// `/dev/mem` 上の連続した領域をフレームバッファとして使います。
+    ///
+    /// # Args
+    /// * `phys_addr` - 先頭のフレームバッファの物理アドレス
+    /// * `count` - フレームバッファの数 (2以上)
+    pub fn dev_mem(phys_addr: u64, count: usize) -> Self {
+        Self {
+            device: PathBuf::from("/dev/mem"),
+            phys_addr,
+            offset: phys_addr,
+            count,
+            sync: None,
+        }
+    }
+
+    /// u-dma-bufのバッファをフレームバッファとして使います。
+    ///
+    /// バッファはキャッシュを有効にしてmmapし、フレームを読み出す前に同期します。
+    ///
+    /// # Args
+    /// * `buf` - u-dma-bufのバッファ
+    /// * `count` - フレームバッファの数 (2以上)
+    pub fn udmabuf(buf: &UdmaBuf, count: usize) -> Self {
+        Self {
+            device: Path::new("/dev").join(buf.name()),
+            phys_addr: buf.phys_addr(),
+            offset: 0,
+            count,
+            sync: Some(buf.sysfs().to_path_buf()),
+        }
+    }
+}
+
+/// AXI VDMAからフレームを取得する構造体
+pub struct VdmaCapture {
+    regs: Registers,
+    frames: MmapMut,
+    count: usize,
+    width: u32,
+    height: u32,
+    stride: usize,
+    format: VideoFormat,
+    sync: Option<PathBuf>,
+    last_frame: Option<usize>,
+    timeout: Duration,
+}
+
+impl VdmaCapture {
+    /// VDMAのS2MMチャネルを設定し、取り込みを開始します。
+    ///
+    /// # Args
+    /// * `uio` - VDMAのレジスタを公開しているUIOデバイス (例: `/dev/uio4`)
+    /// * `buffers` - フレームバッファの配置
+    /// * `width` - フレームの幅
+    /// * `height` - フレームの高さ
+    /// * `format` - ピクセル形式
+    pub fn new<P: AsRef<Path>>(
+        uio: P,
+        buffers: &FrameBuffers,
+        width: u32,
+        height: u32,
+        format: VideoFormat,
+    ) -> Result<Self> {
+        // 書き込み中のフレームを読まないよう2つ以上必要
+        if buffers.count < 2 || buffers.count > MAX_FRAMES {
+            bail!("Number of frame buffers must be 2-{}", MAX_FRAMES);
+        }
+
+        let regs = Registers::open(uio.as_ref(), 0x100)?;
+
+        // 行の長さを64バイト境界に揃える
+        let stride = (width as usize * 3 + 63) & !63;
+        let frame_size = stride * height as usize;
+        let frames_file = open_rw(&buffers.device)?;
+        // SAFETY: フレームバッファはVDMAが書き込むため、書き込みの完了したフレームのみを読み出す
+        let frames = unsafe {
+            MmapOptions::new()
+                .offset(buffers.offset)
+                .len(frame_size * buffers.count)
+                .map_mut(&frames_file)
+        }
+        .with_context(|| format!("Can't map {}", buffers.device.display()))?;
+
+        let mut capture = Self {
+            regs,
+            frames,
+            count: buffers.count,
+            width,
+            height,
+            stride,
+            format,
+            sync: buffers.sync.clone(),
+            last_frame: None,
+            timeout: Duration::from_secs(1),
+        };
+        capture.start(buffers.phys_addr, frame_size)?;
+        Ok(capture)
+    }
+
+    /// ハードウェア情報ファイルに記録されたVDMAからフレームを取得します。
+    ///
+    /// VDMAのエントリの `uio` にUIOデバイス名 (例: `uio4`) が記録されている必要があります。
+    ///
+    /// # Args
+    /// * `hwinfo_path` - ハードウェア情報のパス
+    /// * `name` - VDMAの名前 (例: `/video/axi_vdma_0`)
+    /// * `buffers` - フレームバッファの配置
+    /// * `width` - フレームの幅
+    /// * `height` - フレームの高さ
+    /// * `format` - ピクセル形式
+    pub fn from_hwinfo(
+        hwinfo_path: &str,
+        name: &str,
+        buffers: &FrameBuffers,
+        width: u32,
+        height: u32,
+        format: VideoFormat,
+    ) -> Result<Self> {
+        let hw_json = xipdriver_rs::hwinfo::read(hwinfo_path)?;
+        let uio = uio::device_path(&hw_json[name], "uio", name)
+            .with_context(|| format!("Invalid VDMA entry in {}", hwinfo_path))?;
+        Self::new(uio, buffers, width, height, format)
+    }
+
+    /// フレームの取得を待つ最大の時間を設定します。
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// フレームの幅と高さを返します。
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// S2MMチャネルを循環モードで起動します。
+    fn start(&mut self, phys_addr: u64, frame_size: usize) -> Result<()> {
+        // リセット
+        self.regs.write(S2MM_VDMACR, 0x4);
+        let start = Instant::now();
+        while self.regs.read(S2MM_VDMACR) & 0x4 != 0 {
+            if start.elapsed() > self.timeout {
+                bail!("VDMA reset timed out");
+            }
+            thread::sleep(Duration::from_micros(10));
+        }
+
+        for i in 0..self.count {
+            let addr = phys_addr + (frame_size * i) as u64;
+            let addr = u32::try_from(addr).context("Frame buffer must be below 4GiB")?;
+            self.regs.write(S2MM_START_ADDRESS + 4 * i, addr);
+        }
+        // RS=1, Circular_Park=1
+        self.regs.write(S2MM_VDMACR, 0x3);
+        self.regs.write(S2MM_FRMDLY_STRIDE, self.stride as u32);
+        self.regs.write(S2MM_HSIZE, self.width * 3);
+        // VSIZEの書き込みで転送が始まる
+        self.regs.write(S2MM_VSIZE, self.height);
+
+        // 起動前の内容を読まないよう、最初のフレームの書き込みが終わるまで待たせる
+        self.last_frame = Some(self.count - 1);
+        Ok(())
+    }
+
+    /// VDMAが書き込み中のフレームバッファの番号を返します。
+    fn write_frame(&self) -> usize {
+        ((self.regs.read(PARK_PTR_REG) >> 24) & 0x1f) as usize
+    }
+
+    /// 書き込みが完了した最新のフレームを取得します。
+    ///
+    /// 前回取得したフレームから新しいフレームが書き込まれるまで待ちます。
+    ///
+    /// # Return
+    /// * RGBのフレーム
+    pub fn next_frame(&mut self) -> Result<RgbImage> {
+        let start = Instant::now();
+        let frame = loop {
+            let status = self.regs.read(S2MM_VDMASR);
+            if status & S2MM_ERR_MASK != 0 {
+                // エラーのビットは1を書き込むとクリアされる
+                self.regs.write(S2MM_VDMASR, status & S2MM_ERR_MASK);
+                bail!("VDMA S2MM error: status={:#010x}", status);
+            }
+            if status & 0x1 != 0 {
+                bail!("VDMA S2MM is halted");
+            }
+
+            let frame = (self.write_frame() + self.count - 1) % self.count;
+            if self.last_frame != Some(frame) {
+                break frame;
+            }
+            if start.elapsed() > self.timeout {
+                bail!("Timed out waiting for a frame");
+            }
+            thread::sleep(Duration::from_micros(500));
+        };
+        self.last_frame = Some(frame);
+
+        let frame_size = self.stride * self.height as usize;
+        if let Some(sysfs) = &self.sync {
+            udmabuf::sync(
+                sysfs,
+                false,
+                frame * frame_size,
+                frame_size,
+                SyncDirection::FromDevice,
+            )?;
+        }
+        let src = &self.frames[frame * frame_size..(frame + 1) * frame_size];
+        let row = self.width as usize * 3;
+        let mut data = Vec::with_capacity(row * self.height as usize);
+        for line in src.chunks_exact(self.stride) {
+            for p in line[..row].chunks_exact(3) {
+                data.extend_from_slice(&match self.format {
+                    VideoFormat::Rgb => [p[0], p[1], p[2]],
+                    VideoFormat::Bgr => [p[2], p[1], p[0]],
+                    VideoFormat::Gbr => [p[2], p[0], p[1]],
+                });
+            }
+        }
+        RgbImage::from_raw(self.width, self.height, data).context("Invalid frame size")
+    }
+
+    /// S2MMチャネルを停止します。
+    pub fn stop(&mut self) {
+        self.regs.write(S2MM_VDMACR, 0);
+    }
+}
+
+impl Drop for VdmaCapture {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,173 @@
+//! 動画ファイルを推論し、バウンディングボックスを描画した動画と検出結果のファイルを出力するモジュール
+//!
+//! `gstreamer` フィーチャを有効にした場合のみ利用できます。
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use image::DynamicImage;
+
+use crate::detection_log::{DetectionLogger, LogFormat};
+use crate::detection_result::DetectionData;
+use crate::gst_pipeline::{GstSink, GstSource};
+use crate::img_proc::draw_bbox;
+use crate::yolov3_tiny::YoloV3Tiny;
+
+/// 動画ファイルを1フレームずつ推論する構造体
+pub struct VideoProcessor {
+    stride: u64,
+    rotate_angle: u32,
+    fps: u32,
+    encoder: String,
+    log_format: Option<LogFormat>,
+    font_size: f32,
+    line_thickness: f32,
+}
+
+impl Default for VideoProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VideoProcessor {
+    /// 新しい `VideoProcessor` インスタンスを作成します。
+    pub fn new() -> Self {
+        Self {
+            stride: 1,
+            rotate_angle: 0,
+            fps: 30,
+            encoder: "x264enc ! mp4mux".to_string(),
+            log_format: Some(LogFormat::Csv),
+            font_size: 14.,
+            line_thickness: 2.,
+        }
+    }
+
+    /// 推論するフレームの間隔を設定します。
+    ///
+    /// 推論しないフレームには直前の検出結果を描画します。
+    pub fn set_stride(&mut self, stride: u64) -> &mut Self {
+        self.stride = stride.max(1);
+        self
+    }
+
+    /// 推論時の回転角度を設定します。
+    pub fn set_rotate_angle(&mut self, rotate_angle: u32) -> &mut Self {
+        self.rotate_angle = rotate_angle;
+        self
+    }
+
+    /// 出力する動画のフレームレートを設定します。
+    pub fn set_fps(&mut self, fps: u32) -> &mut Self {
+        self.fps = fps;
+        self
+    }
+
+    /// 出力する動画のエンコーダとマルチプレクサをGStreamerのパイプラインの記述で設定します。
+    ///
+    /// 例: `x264enc ! mp4mux`, `jpegenc ! avimux`
+    pub fn set_encoder(&mut self, encoder: &str) -> &mut Self {
+        self.encoder = encoder.to_string();
+        self
+    }
+
+    /// 検出結果のファイルの形式を設定します。Noneの場合は出力しません。
+    pub fn set_log_format(&mut self, log_format: Option<LogFormat>) -> &mut Self {
+        self.log_format = log_format;
+        self
+    }
+
+    /// 描画するラベルの文字の大きさと線の太さを設定します。
+    pub fn set_draw_style(&mut self, font_size: f32, line_thickness: f32) -> &mut Self {
+        self.font_size = font_size;
+        self.line_thickness = line_thickness;
+        self
+    }
+
+    /// 動画ファイルを推論します。
+    ///
+    /// 検出結果のファイルは出力する動画と同じディレクトリに、拡張子を変えた名前で保存されます。
+    ///
+    /// # Args
+    /// * `yolo` - 推論に使う `YoloV3Tiny`
+    /// * `input` - 入力する動画ファイルのパス
+    /// * `output` - 出力する動画ファイルのパス
+    ///
+    /// # Return
+    /// * 処理したフレームの数
+    pub fn process<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        yolo: &mut YoloV3Tiny,
+        input: P,
+        output: Q,
+    ) -> Result<u64> {
+        let input = input.as_ref();
+        let output = output.as_ref();
+
+        let mut source = GstSource::new(&format!(
+            "filesrc location=\"{}\" ! decodebin ! videoconvert ! appsink name=sink sync=false",
+            input.display()
+        ))
+        .with_context(|| format!("Can't open {}", input.display()))?;
+
+        let mut logger = match self.log_format {
+            Some(format) => {
+                let dir = output.parent().unwrap_or(Path::new("."));
+                let name = output
+                    .file_stem()
+                    .context("Output path has no file name")?
+                    .to_string_lossy();
+                Some(DetectionLogger::new(dir, &name, format)?)
+            }
+            None => None,
+        };
+
+        let mut sink: Option<GstSink> = None;
+        let mut detections: Vec<DetectionData> = vec![];
+        let mut frame_id = 0;
+        while let Some(frame) = source.next_frame()? {
+            if frame_id % self.stride == 0 {
+                let img = DynamicImage::ImageRgb8(frame.clone());
+                detections = yolo.start_with_img_proc(&img, self.rotate_angle)?;
+                if let Some(logger) = logger.as_mut() {
+                    logger.log(frame_id, &detections)?;
+                }
+            }
+
+            let sink = match sink.as_mut() {
+                Some(sink) => sink,
+                None => sink.insert(
+                    GstSink::new(
+                        &format!(
+                            "appsrc name=src ! videoconvert ! {} ! filesink location=\"{}\"",
+                            self.encoder,
+                            output.display()
+                        ),
+                        frame.width(),
+                        frame.height(),
+                        self.fps,
+                    )
+                    .with_context(|| format!("Can't create {}", output.display()))?,
+                ),
+            };
+            let mut annotated = frame;
+            draw_bbox(
+                &mut annotated,
+                &detections,
+                self.font_size,
+                self.line_thickness,
+            );
+            sink.push(&annotated)?;
+            frame_id += 1;
+        }
+
+        if let Some(mut sink) = sink {
+            sink.finish()?;
+        }
+        if let Some(mut logger) = logger {
+            logger.flush()?;
+        }
+        Ok(frame_id)
+    }
+}
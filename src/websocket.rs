@@ -0,0 +1,133 @@
+//! フレームごとの検出結果をWebSocketで配信するモジュール
+//!
+//! `websocket` フィーチャを有効にした場合のみ利用できます。
+//!
+//! 接続中のクライアントには、フレームごとに検出結果のJSONをテキストメッセージで送信します。
+//! 画像の配信を有効にした場合は、続けてバウンディングボックスを描画したJPEG画像をバイナリメッセージで送信します。
+
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::RgbImage;
+use tungstenite::{Message, WebSocket};
+
+use crate::detection_log::ndjson_record;
+use crate::detection_result::DetectionData;
+use crate::img_proc::draw_bbox;
+
+/// 接続中のクライアント
+type Clients = Arc<Mutex<Vec<WebSocket<TcpStream>>>>;
+
+/// 検出結果を接続中の全てのクライアントに配信する構造体
+///
+/// `bind` で待ち受けを開始すると、接続の受け付けはバックグラウンドのスレッドで行われます。
+/// 送信に失敗したクライアントは切断されたものとして取り除かれます。
+pub struct WsBroadcaster {
+    clients: Clients,
+    send_frames: bool,
+    jpeg_quality: u8,
+    font_size: f32,
+    line_thickness: f32,
+}
+
+impl WsBroadcaster {
+    /// 指定したアドレスで接続の待ち受けを開始します。
+    ///
+    /// # Args
+    /// * `addr` - 待ち受けるアドレス (例: `0.0.0.0:8081`)
+    pub fn bind(addr: &str) -> Result<Self> {
+        let listener = TcpListener::bind(addr).with_context(|| format!("Can't bind {}", addr))?;
+        let clients: Clients = Arc::default();
+
+        let accepted = clients.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // 応答しないクライアントで配信が止まらないようにする
+                let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                match tungstenite::accept(stream) {
+                    Ok(ws) => accepted.lock().unwrap().push(ws),
+                    Err(e) => log::warn!("WebSocket handshake failed: {}", e),
+                }
+            }
+        });
+
+        Ok(Self {
+            clients,
+            send_frames: false,
+            jpeg_quality: 70,
+            font_size: 14.,
+            line_thickness: 2.,
+        })
+    }
+
+    /// バウンディングボックスを描画した画像も配信するかを設定します。
+    pub fn set_send_frames(&mut self, send_frames: bool) -> &mut Self {
+        self.send_frames = send_frames;
+        self
+    }
+
+    /// 配信する画像のJPEGの品質 (1-100) を設定します。
+    pub fn set_jpeg_quality(&mut self, jpeg_quality: u8) -> &mut Self {
+        self.jpeg_quality = jpeg_quality.clamp(1, 100);
+        self
+    }
+
+    /// 配信する画像に描画するラベルの文字の大きさと線の太さを設定します。
+    pub fn set_draw_style(&mut self, font_size: f32, line_thickness: f32) -> &mut Self {
+        self.font_size = font_size;
+        self.line_thickness = line_thickness;
+        self
+    }
+
+    /// 接続中のクライアントの数を返します。
+    pub fn client_count(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// 1フレーム分の検出結果を配信します。
+    ///
+    /// # Args
+    /// * `frame_id` - フレーム番号
+    /// * `detections` - 検出結果
+    /// * `img` - 検出結果の座標系に対応する画像。画像の配信が無効な場合やNoneの場合は画像を送信しません
+    pub fn broadcast(
+        &self,
+        frame_id: u64,
+        detections: &[DetectionData],
+        img: Option<&RgbImage>,
+    ) -> Result<()> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return Ok(());
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs_f64())
+            .unwrap_or(0.);
+        let mut messages = vec![Message::text(
+            ndjson_record(timestamp, frame_id, detections).to_string(),
+        )];
+
+        if let Some(img) = img.filter(|_| self.send_frames) {
+            let mut annotated = img.clone();
+            draw_bbox(
+                &mut annotated,
+                detections,
+                self.font_size,
+                self.line_thickness,
+            );
+            let mut jpeg = vec![];
+            JpegEncoder::new_with_quality(&mut jpeg, self.jpeg_quality).encode_image(&annotated)?;
+            messages.push(Message::binary(jpeg));
+        }
+
+        clients.retain_mut(|ws| messages.iter().all(|m| ws.send(m.clone()).is_ok()));
+        Ok(())
+    }
+}
@@ -1,20 +1,568 @@
 //! YOLOのモデルをコントロールするモジュール
 
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::fmt;
 use std::fs::File;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{ffi::OsStr, io::Read, path::Path, vec};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use flate2::read::GzDecoder;
 use log::{warn, info};
 use tar::Archive;
 
-use xipdriver_rs::{axidma, axis_switch, yolo};
+use xipdriver_rs::{axis_switch, yolo};
 
+use crate::axi_dma::AxiDma;
+use crate::hw_state::{
+    activation_name, post_process_name, DmaState, HardwareState, HwStats, IpState, LayerGroupState,
+    LayerSaturation, StepState, SwitchState,
+};
 use crate::layer_group::{Activation, LayerGroup, PostProcess};
+use crate::postprocess::{self, ANCHOR_BOX_NUM, DEFAULT_CLASS_SLOTS};
+use crate::trace::{self, ReplayTarget, TraceEvent, TraceRecorder};
 
-const ACTIVE_EN: [u32; 8] = [
-    0xfffffff3, 0xffffffff, 0xfe7fffff, 0xffffffff, 0xffffffff, 0xffffcfff, 0xffffffff, 0x7fffffff,
-];
+/// YOLO層の出力の分割数 (256チャネル)
+const YOLO_OUTPUT_FOLDS: usize = 8;
+
+/// AXI DMAのバッファ長レジスタの幅の最大値 (ハードウェア情報にない場合に使う)
+const MAX_LENGTH_WIDTH: u32 = 26;
+
+/// ハードウェア情報からパラメータを探します。
+///
+/// 別のパラメータの値を誤って使わないよう、IPのエントリの直下で名前が完全に一致するものだけを探します。
+///
+/// # Args
+/// * `info` - IPのハードウェア情報
+/// * `key` - パラメータ名
+///
+/// # 返り値
+/// * パラメータの値。見つからない場合はNone
+fn find_param<'a>(info: &'a serde_json::Value, key: &str) -> Option<&'a serde_json::Value> {
+    info.get(key)
+}
+
+/// DMAの1回の転送で送受信できる最大の要素数 (i16) を求めます。
+///
+/// バッファ長レジスタの幅 (`C_SG_LENGTH_WIDTH`) から最大のバイト数を求め、64バイト単位に切り捨てます。
+///
+/// # Args
+/// * `dma_info` - DMAのハードウェア情報
+fn max_transfer_len(dma_info: &serde_json::Value) -> usize {
+    let width = find_param(dma_info, "C_SG_LENGTH_WIDTH")
+        .and_then(|v| {
+            v.as_u64()
+                .or_else(|| v.as_str().and_then(|s| s.trim().parse().ok()))
+        })
+        .map_or(MAX_LENGTH_WIDTH, |w| w as u32)
+        .clamp(8, MAX_LENGTH_WIDTH);
+    (((1usize << width) - 1) & !63) / 2
+}
+
+/// 完了待ちが時間切れになったIPまたはDMA (`start_layer_processing` の中でのみ使う)
+#[derive(Debug)]
+struct Timeout(&'static str);
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} timed out", self.0)
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// 条件が満たされるまで待ちます。
+///
+/// # Args
+/// * `timeout` - 待つ最大の時間。Noneの場合は時間切れにしない
+/// * `target` - 待っているIPまたはDMAの名前
+/// * `cond` - 完了したかを返す関数
+///
+/// # 返り値
+/// * Result。時間切れの場合は `Timeout` のエラー
+fn wait_until<F: FnMut() -> Result<bool>>(
+    timeout: Option<Duration>,
+    target: &'static str,
+    mut cond: F,
+) -> Result<()> {
+    let start = Instant::now();
+    while !cond()? {
+        if timeout.is_some_and(|t| start.elapsed() > t) {
+            return Err(Timeout(target).into());
+        }
+    }
+    Ok(())
+}
+
+/// 対応しているYOLOのIPのバージョン
+///
+/// ハードウェア情報のVLNV (`vendor:library:name:version`) のバージョンと比較します。
+const SUPPORTED_IP_VERSIONS: &[&str] = &["1.0"];
+
+/// YOLOのIPのバージョンが対応しているものかを確認します。
+///
+/// ハードウェア情報にVLNVがない場合は確認できないため、警告を出して続行します。
+/// IPの名前が一致してバージョンだけが異なる場合は、レジスタの構成が同じ可能性があるため警告を出して続行します。
+///
+/// # Args
+/// * `hw_json` - ハードウェア情報
+/// * `name` - IPの名前
+///
+/// # 返り値
+/// * Result。IPがない場合や、名前が一致しない場合はエラー
+fn check_ip_version(hw_json: &serde_json::Value, name: &str) -> Result<()> {
+    let info = &hw_json[name];
+    if info.is_null() {
+        bail!(
+            "{} is not found in the hardware information. The bitstream may not contain the YOLO accelerator",
+            name
+        );
+    }
+    let Some(vlnv) = find_param(info, "VLNV").and_then(|v| v.as_str()) else {
+        warn!(
+            "{} has no VLNV in the hardware information. Skipping the version check",
+            name
+        );
+        return Ok(());
+    };
+
+    let mut parts = vlnv.rsplitn(3, ':');
+    let version = parts.next().unwrap_or_default();
+    let ip_name = parts.next().unwrap_or_default();
+    // 階層の名前 (例: /yolo/yolo_conv_top_0) からインスタンスの番号を除くとIPの名前になる
+    let expected = name
+        .rsplit('/')
+        .next()
+        .and_then(|n| n.rsplit_once('_'))
+        .map_or("", |(n, _)| n);
+    if ip_name != expected {
+        bail!(
+            "{} is {}, but {} is expected. The bitstream does not match this crate",
+            name,
+            vlnv,
+            expected
+        );
+    }
+    if !SUPPORTED_IP_VERSIONS.contains(&version) {
+        warn!(
+            "{} version {} is not tested with this crate (supported: {}). Results may be wrong",
+            name,
+            version,
+            SUPPORTED_IP_VERSIONS.join(", ")
+        );
+        return Ok(());
+    }
+    info!("{}: {}", name, vlnv);
+    Ok(())
+}
+
+/// データをDMAで送信します。最大の転送長を超える場合は分割して送信します。
+///
+/// # Args
+/// * `dma` - DMA
+/// * `data` - 送信するデータ
+/// * `max_len` - 1回の転送の最大の要素数 (バッファ長レジスタとu-dma-bufのバッファの小さい方)
+/// * `wait` - 最後の転送の完了を待つか
+/// * `timeout` - 転送の完了を待つ最大の時間
+///
+/// # 返り値
+/// * Result。転送に失敗した場合はエラー
+fn dma_write(
+    dma: &mut AxiDma,
+    data: &[i16],
+    max_len: usize,
+    wait: bool,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    for chunk in data.chunks(max_len) {
+        // バッファを上書きしないよう、前の転送 (完了を待たずに返したものを含む) が終わるまで待つ
+        wait_until(timeout, "DMA MM2S", || dma.is_mm2s_idle())?;
+        dma.write(chunk)?;
+    }
+    if wait {
+        wait_until(timeout, "DMA MM2S", || dma.is_mm2s_idle())?;
+    }
+    Ok(())
+}
+
+/// データをDMAで受信します。
+///
+/// IPは出力全体を1つのパケットとして送るため、S2MMの転送は分割せずに出力全体の長さで1回だけ行います。
+/// パケットより短い転送ではDMAがエラーで止まるため、最大の転送長を超える場合は転送せずにエラーを返します。
+///
+/// # Args
+/// * `dma` - DMA
+/// * `len` - 受信する要素の数
+/// * `max_len` - 1回の転送の最大の要素数
+/// * `timeout` - 転送の完了を待つ最大の時間
+///
+/// # 返り値
+/// * 受信したデータを含むVec<i16>のResult。転送に失敗した場合はエラー、時間切れの場合は `Timeout` のエラー
+fn dma_read(
+    dma: &mut AxiDma,
+    len: usize,
+    max_len: usize,
+    timeout: Option<Duration>,
+) -> Result<Vec<i16>> {
+    if len > max_len {
+        bail!(
+            "Output of {} elements exceeds the DMA max transfer length of {} elements. Rebuild the bitstream with a wider buffer length register or enlarge the u-dma-buf",
+            len,
+            max_len
+        );
+    }
+    dma.start_read(len)?;
+    wait_until(timeout, "DMA S2MM", || dma.is_s2mm_idle())?;
+    dma.finish_read(len)
+}
+
+/// レイヤーグループの1回のデータ転送 (出力チャネルと入力チャネルの組) を表す構造体
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct LayerStep {
+    /// レイヤーグループのインデックス
+    grp_idx: usize,
+    /// 出力チャネルのインデックス
+    off: u32,
+    /// 入力チャネルのインデックス
+    iff: u32,
+    /// 入力チャネルの最後の転送か
+    is_last: bool,
+}
+
+/// YOLOのIP
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Ip {
+    Conv,
+    Acc,
+    MaxPool,
+    Yolo,
+    Upsamp,
+}
+
+impl Ip {
+    /// 全てのIP
+    const ALL: [Ip; 5] = [Ip::Conv, Ip::Acc, Ip::MaxPool, Ip::Yolo, Ip::Upsamp];
+
+    /// ログとトレースで使うIPの名前を返します。
+    fn name(self) -> &'static str {
+        match self {
+            Ip::Conv => "yolo_conv",
+            Ip::Acc => "yolo_acc",
+            Ip::MaxPool => "yolo_max_pool",
+            Ip::Yolo => "yolo_yolo",
+            Ip::Upsamp => "yolo_upsamp",
+        }
+    }
+
+    /// 名前からIPを探します。
+    ///
+    /// # 返り値
+    /// * IP。名前が一致しない場合はエラー
+    fn from_name(name: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|ip| ip.name() == name)
+            .with_context(|| format!("Unknown IP \"{}\"", name))
+    }
+
+    /// スタートする前に設定が必要なレジスタの名前を返します。
+    fn registers(self) -> &'static [&'static str] {
+        trace::IP_REGISTERS
+            .iter()
+            .find(|(name, _)| *name == self.name())
+            .map_or(&[], |(_, regs)| *regs)
+    }
+
+    /// 名前からレジスタを探します。
+    ///
+    /// # 返り値
+    /// * レジスタの名前。IPにないレジスタの場合はエラー
+    fn register(self, reg: &str) -> Result<&'static str> {
+        self.registers()
+            .iter()
+            .copied()
+            .find(|r| *r == reg)
+            .with_context(|| format!("{} has no register \"{}\"", self.name(), reg))
+    }
+}
+
+/// プロセス内で使用中のDMAとそのデバイス
+static CLAIMED_DMAS: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// DMAが使用するデバイス (UIOとu-dma-buf) の名前を集めます。
+///
+/// # Args
+/// * `info` - DMAのハードウェア情報
+/// * `ids` - 名前を追加する先
+fn collect_devices(info: &serde_json::Value, ids: &mut Vec<String>) {
+    if let serde_json::Value::Object(map) = info {
+        for (k, v) in map {
+            let k = k.to_ascii_lowercase();
+            match v.as_str() {
+                Some(dev) if k.contains("uio") || k.contains("udmabuf") => {
+                    ids.push(format!("dev:{}", dev.trim_start_matches("/dev/")))
+                }
+                _ => collect_devices(v, ids),
+            }
+        }
+    }
+}
+
+/// プロセス間のロックファイルを置くディレクトリの候補
+const LOCK_DIRS: [&str; 3] = ["/run/lock", "/var/lock", "/tmp"];
+
+/// DMAやデバイスのロックファイルを排他的にロックします。
+///
+/// ロックはファイルを閉じると (プロセスが異常終了した場合も) 解放されます。
+///
+/// # Args
+/// * `id` - DMAまたはデバイスの識別子
+///
+/// # 返り値
+/// * ロックしたファイル。他のプロセスがロックしている場合はエラー
+fn lock_file(id: &str) -> Result<File> {
+    let dir = LOCK_DIRS
+        .iter()
+        .map(Path::new)
+        .find(|d| d.is_dir())
+        .context("No directory for lock files")?;
+    let name: String = id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let path = dir.join(format!("yolo_v3_tiny_zynq.{}.lock", name));
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Can't open {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => bail!(
+            "Device busy: {} is used by another process (lock file: {})",
+            id,
+            path.display()
+        ),
+        Err(std::fs::TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("Can't lock {}", path.display()))
+        }
+    }
+}
+
+/// DMAの所有権
+///
+/// 同じハードウェア情報から複数の `YoloController` を作成したときに、
+/// 1つのDMA (とそのデバイス) を2つ以上のコントローラが操作しないようにします。
+/// 他のプロセスとの間でもロックファイルで排他制御します。
+/// 破棄されると所有権を手放します。
+struct DmaClaim {
+    ids: Vec<String>,
+    /// 他のプロセスに対するロック (閉じると解放される)
+    _locks: Vec<File>,
+}
+
+impl DmaClaim {
+    /// DMAの所有権を取得します。
+    ///
+    /// # Args
+    /// * `hw_json` - ハードウェア情報
+    /// * `names` - DMAの名前
+    ///
+    /// # 返り値
+    /// * 所有権。いずれかのDMAかデバイスが使用中の場合はエラー
+    fn acquire(hw_json: &serde_json::Value, names: &[&str]) -> Result<Self> {
+        let mut ids = vec![];
+        for name in names {
+            if hw_json[*name].is_null() {
+                bail!("{} is not found in the hardware information", name);
+            }
+            ids.push(format!("dma:{}", name));
+            collect_devices(&hw_json[*name], &mut ids);
+        }
+        ids.sort();
+        ids.dedup();
+
+        let mut claimed = CLAIMED_DMAS.lock().unwrap();
+        let claimed = claimed.get_or_insert_with(HashSet::new);
+        if let Some(id) = ids.iter().find(|id| claimed.contains(*id)) {
+            bail!("{} is already used by another YoloController", id);
+        }
+        let locks = ids
+            .iter()
+            .map(|id| lock_file(id))
+            .collect::<Result<Vec<_>>>()?;
+        claimed.extend(ids.iter().cloned());
+        Ok(Self { ids, _locks: locks })
+    }
+}
+
+impl Drop for DmaClaim {
+    fn drop(&mut self) {
+        if let Some(claimed) = CLAIMED_DMAS.lock().unwrap().as_mut() {
+            for id in &self.ids {
+                claimed.remove(id);
+            }
+        }
+    }
+}
+
+/// gzipアーカイブから、レイヤーグループの重みとバイアスデータを読み込みます。
+///
+/// # Args
+/// * `path` - 重みとバイアスデータが格納されているgzipアーカイブへのパス
+/// * `layer_groups` - 読み込み先のレイヤーグループ
+///
+/// # 注意
+/// この関数は各レイヤーグループの重みとバイアスデータを読み込みます。データは16ビット整数として解釈されます。
+/// * ファイル名が "biases" で始まる場合、バイアスデータとして解釈されます。
+/// * ファイル名が "weights" で始まる場合、重みデータとして解釈されます。
+/// * それ以外のファイル名の場合、警告がログに出力され、そのファイルは無視されます。
+pub(crate) fn read_weight_archive<P: AsRef<Path>>(
+    path: P,
+    layer_groups: &mut [LayerGroup],
+) -> Result<()> {
+    let file = File::open(path)?;
+    let mut archive = Archive::new(GzDecoder::new(file));
+
+    for file in archive.entries()? {
+        let mut file = file?;
+        let file_path = file.path()?;
+        let file_name = file_path
+            .file_name()
+            .context("file_name error")?
+            .to_str()
+            .context("to_str error")?
+            .to_string();
+
+        // Skip files that start with '._'
+        if file_name.starts_with("._") {
+            continue;
+        }
+
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)
+            .with_context(|| format!("Can't read {}", file_name))?;
+        if buf.len() % 2 != 0 {
+            bail!("{} has an odd length of {} bytes", file_name, buf.len());
+        }
+        let data: Vec<i16> = buf
+            .chunks_exact(2)
+            .map(|chunk| {
+                let bytes = [chunk[0], chunk[1]];
+                i16::from_le_bytes(bytes)
+            })
+            .collect();
+
+        if let Some(gnum) = file_name.strip_prefix("biases") {
+            let gnum: usize = gnum.parse()?;
+            info!("Loading bias {}", gnum);
+            layer_group(layer_groups, gnum)?.biases = Some(data);
+        } else if let Some(gnum) = file_name.strip_prefix("weights") {
+            let gnum: usize = gnum.parse()?;
+            info!("Loading weight {}", gnum);
+            layer_group(layer_groups, gnum)?.weights = Some(data);
+        } else {
+            warn!("{} is not biases or weights file", file_name);
+        }
+    }
+    Ok(())
+}
+
+/// 読み込み先のレイヤーグループを返します。存在しない場合はエラーを返します。
+fn layer_group(layer_groups: &mut [LayerGroup], gnum: usize) -> Result<&mut LayerGroup> {
+    let num = layer_groups.len();
+    layer_groups
+        .get_mut(gnum)
+        .with_context(|| format!("Layer group {} does not exist ({} groups)", gnum, num))
+}
+
+/// ハードウェア情報に含まれるYOLOの階層を列挙します。
+///
+/// `yolo_conv_top_0` を含む階層を、YOLOのパイプラインとみなします。
+///
+/// # Args
+/// * `hwinfo_path` - ハードウェア情報のパス
+///
+/// # 返り値
+/// * 階層のパス (例: `yolo0`) を名前順に並べたベクトル
+pub(crate) fn yolo_hierarchies(hwinfo_path: &str) -> Result<Vec<String>> {
+    let hw_json = xipdriver_rs::hwinfo::read(hwinfo_path)?;
+    let mut hiers: Vec<String> = hw_json
+        .as_object()
+        .context("Invalid hardware information")?
+        .keys()
+        .filter_map(|k| k.strip_suffix("/yolo_conv_top_0"))
+        .map(|h| h.trim_start_matches('/').to_string())
+        .collect();
+    hiers.sort();
+    Ok(hiers)
+}
+
+/// 推論の中断を依頼するためのハンドル
+///
+/// クローンしたハンドルは同じ推論を指し、他のスレッドから `cancel` を呼び出せます。
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    /// 推論の中断を依頼します。
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    /// 中断が依頼されているかを返します。
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// 中断の依頼を取り消します。
+    pub(crate) fn clear(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+}
+
+/// 推論が中断されたことを表すエラー
+///
+/// `anyhow::Error::downcast_ref` で他のエラーと区別できます。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Inference was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// IPまたはDMAの処理が止まったことを表すエラー
+///
+/// ウォッチドッグはスイッチとDMAをリセットしてレイヤーグループを再実行し、
+/// それでも完了しなかった場合にこのエラーを返します。
+/// コントローラはリセットされているため、次の推論にそのまま使えます。
+#[derive(Debug, Clone)]
+pub struct Stalled {
+    /// 完了しなかったIPまたはDMA
+    pub target: &'static str,
+    /// 処理中だったレイヤーグループのインデックス
+    pub grp_idx: usize,
+    /// 再実行でも完了しなかった時点のハードウェアの状態
+    pub state: HardwareState,
+}
+
+impl fmt::Display for Stalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} stalled in layer group {}", self.target, self.grp_idx)
+    }
+}
+
+impl std::error::Error for Stalled {}
 
 /// YOLOのモデルをコントロールする構造体
 pub struct YoloController {
@@ -25,9 +573,9 @@ pub struct YoloController {
     /// AxisSwitchのインスタンス2
     sw2: axis_switch::AxisSwitch,
     /// AxiDmaのインスタンス0
-    dma0: axidma::AxiDma,
+    dma0: AxiDma,
     /// AxiDmaのインスタンス1
-    dma1: axidma::AxiDma,
+    dma1: AxiDma,
     /// YOLOアクセラレータのインスタンス
     yolo_acc: yolo::Yolo,
     /// YOLO畳み込み層のインスタンス
@@ -38,8 +586,36 @@ pub struct YoloController {
     yolo_yolo: yolo::Yolo,
     /// YOLOアップサンプリング層のインスタンス
     yolo_upsamp: yolo::Yolo,
+    /// DMA0の1回の転送の最大の要素数
+    dma0_max_len: usize,
+    /// DMA1の1回の転送の最大の要素数
+    dma1_max_len: usize,
+    /// レジスタを設定済みのデータ転送
+    preconfigured: Option<LayerStep>,
+    /// 処理中 (または最後に処理した) データ転送
+    current_step: Option<LayerStep>,
+    /// このコントローラで処理しないレイヤーグループ
+    skipped_groups: Vec<usize>,
+    /// 最後に設定したスイッチの接続 (スレーブ, マスター)
+    switch_ports: Cell<Option<[(u8, u8); 3]>>,
     /// レイヤーグループのベクトル
     pub(crate) layer_groups: Vec<LayerGroup>,
+    /// YOLO層で活性化関数を適用するチャネルのマスク (出力の分割ごと)
+    active_en: Vec<u32>,
+    /// 中断の依頼
+    cancel: CancelHandle,
+    /// IPとDMAの完了を待つ最大の時間
+    watchdog_timeout: Option<Duration>,
+    /// DMAとIPのエラーの統計
+    stats: HwStats,
+    /// レイヤーグループごとの出力の飽和の統計。監視しない場合はNone
+    saturation: Option<Vec<LayerSaturation>>,
+    /// レジスタの書き込みとDMAの転送の記録。記録しない場合はNone
+    trace: Option<RefCell<TraceRecorder>>,
+    /// 終了処理を済ませたか
+    shut_down: bool,
+    /// DMAの所有権
+    _dma_claim: DmaClaim,
 }
 
 impl YoloController {
@@ -69,20 +645,50 @@ impl YoloController {
         let yolo_yolo_name = format!("/{}/{}", yolo_hier, "yolo_yolo_top_0");
         let yolo_upsamp_name = format!("/{}/{}", yolo_hier, "yolo_upsamp_top_0");
 
+        // ビットストリームが違う場合に誤った結果を出さないよう、初期化の前にIPのバージョンを確認する
+        for name in [
+            &yolo_acc_name,
+            &yolo_conv_name,
+            &yolo_mp_name,
+            &yolo_yolo_name,
+            &yolo_upsamp_name,
+        ] {
+            check_ip_version(&hw_json, name)?;
+        }
+
         // ハードウェアの構造体を初期化
         let sw0 = axis_switch::AxisSwitch::new(&hw_json[sw0_name])?;
         let sw1 = axis_switch::AxisSwitch::new(&hw_json[sw1_name])?;
         let sw2 = axis_switch::AxisSwitch::new(&hw_json[sw2_name])?;
 
-        let mut dma0 = axidma::AxiDma::new(&hw_json[dma0_name])?;
-        let mut dma1 = axidma::AxiDma::new(&hw_json[dma1_name])?;
+        // 他のコントローラと同じDMAを使わないよう、初期化の前に所有権を取得する
+        let dma_claim = DmaClaim::acquire(&hw_json, &[&dma0_name, &dma1_name])?;
+        let mut dma0 = AxiDma::new(&hw_json[&dma0_name], &dma0_name)?;
+        let mut dma1 = AxiDma::new(&hw_json[&dma1_name], &dma1_name)?;
+        // u-dma-bufの場合は、1回の転送をバッファの半分 (送信用・受信用) に収める
+        let max_len = |dma: &AxiDma, name: &str| {
+            let len = max_transfer_len(&hw_json[name]);
+            dma.buffer_len().map_or(len, |buf_len| len.min(buf_len))
+        };
+        let dma0_max_len = max_len(&dma0, &dma0_name);
+        let dma1_max_len = max_len(&dma1, &dma1_name);
+        info!(
+            "DMA max transfer length: {} / {} elements",
+            dma0_max_len, dma1_max_len
+        );
 
-        let yolo_acc = yolo::Yolo::new(&hw_json[yolo_acc_name])?;
-        let yolo_conv = yolo::Yolo::new(&hw_json[yolo_conv_name])?;
-        let yolo_mp = yolo::Yolo::new(&hw_json[yolo_mp_name])?;
-        let yolo_yolo = yolo::Yolo::new(&hw_json[yolo_yolo_name])?;
-        let yolo_upsamp = yolo::Yolo::new(&hw_json[yolo_upsamp_name])?;
+        let yolo_acc = yolo::Yolo::new(&hw_json[&yolo_acc_name])?;
+        let yolo_conv = yolo::Yolo::new(&hw_json[&yolo_conv_name])?;
+        let yolo_mp = yolo::Yolo::new(&hw_json[&yolo_mp_name])?;
+        let yolo_yolo = yolo::Yolo::new(&hw_json[&yolo_yolo_name])?;
+        let yolo_upsamp = yolo::Yolo::new(&hw_json[&yolo_upsamp_name])?;
 
+        // 前回のプロセスが途中で終了していても、切り離された状態から始める
+        for sw in [&sw0, &sw1, &sw2] {
+            sw.reg_update_disable();
+            sw.disable_all_mi_ports();
+            sw.reg_update_enable();
+        }
         dma0.start();
         dma1.start();
 
@@ -97,10 +703,105 @@ impl YoloController {
             yolo_mp,
             yolo_yolo,
             yolo_upsamp,
+            dma0_max_len,
+            dma1_max_len,
+            preconfigured: None,
+            current_step: None,
+            skipped_groups: vec![],
+            switch_ports: Cell::new(None),
             layer_groups: vec![],
+            active_en: postprocess::active_en_masks(
+                DEFAULT_CLASS_SLOTS,
+                ANCHOR_BOX_NUM,
+                YOLO_OUTPUT_FOLDS,
+            )?,
+            cancel: CancelHandle::default(),
+            watchdog_timeout: Some(Duration::from_secs(1)),
+            stats: HwStats::default(),
+            saturation: None,
+            trace: None,
+            shut_down: false,
+            _dma_claim: dma_claim,
         })
     }
 
+    /// IPのインスタンスを返します。
+    fn ip(&self, ip: Ip) -> &yolo::Yolo {
+        match ip {
+            Ip::Conv => &self.yolo_conv,
+            Ip::Acc => &self.yolo_acc,
+            Ip::MaxPool => &self.yolo_mp,
+            Ip::Yolo => &self.yolo_yolo,
+            Ip::Upsamp => &self.yolo_upsamp,
+        }
+    }
+
+    /// トレースを記録している場合は、イベントを記録します。
+    ///
+    /// # Args
+    /// * `event` - 記録するイベントを作る関数 (記録しない場合は呼び出さない)
+    fn trace<F: FnOnce() -> TraceEvent>(&self, event: F) {
+        if let Some(trace) = &self.trace {
+            trace.borrow_mut().record(&event());
+        }
+    }
+
+    /// IPのレジスタに書き込みます。
+    ///
+    /// # Args
+    /// * `ip` - IP
+    /// * `reg` - レジスタの名前
+    /// * `value` - 書き込む値
+    fn write_reg(&self, ip: Ip, reg: &'static str, value: u32) {
+        self.ip(ip).set(reg, value);
+        self.trace(|| TraceEvent::Write {
+            ip: ip.name().to_string(),
+            reg: reg.to_string(),
+            value,
+        });
+    }
+
+    /// IPのレジスタを読み出します。
+    ///
+    /// # Args
+    /// * `ip` - IP
+    /// * `reg` - レジスタの名前
+    fn read_reg(&self, ip: Ip, reg: &'static str) -> u32 {
+        let value = self.ip(ip).get(reg);
+        self.trace(|| TraceEvent::Read {
+            ip: ip.name().to_string(),
+            reg: reg.to_string(),
+            value,
+        });
+        value
+    }
+
+    /// IPをスタートします。
+    fn start_ip(&self, ip: Ip) {
+        self.ip(ip).start();
+        self.trace(|| TraceEvent::Start {
+            ip: ip.name().to_string(),
+        });
+    }
+
+    /// DMAの送信を記録します。
+    fn trace_dma_write(&self, dma: &str, data: &[i16]) {
+        self.trace(|| TraceEvent::DmaWrite {
+            dma: dma.to_string(),
+            len: data.len(),
+            checksum: trace::checksum(data),
+        });
+    }
+
+    /// DMAの受信を記録します。
+    fn trace_dma_read(&self, dma: &str, data: &[i16]) {
+        self.trace(|| TraceEvent::DmaRead {
+            dma: dma.to_string(),
+            len: data.len(),
+            checksum: trace::checksum(data),
+        });
+    }
+
     /// YOLOの畳み込み層の設定を行います。
     ///
     /// # Args
@@ -130,14 +831,14 @@ impl YoloController {
         real_input_h: u32,
         fold_win_area: u32,
     ) {
-        self.yolo_conv.set("OUTPUT_CH", output_ch);
-        self.yolo_conv.set("INPUT_CH", input_ch);
-        self.yolo_conv.set("FOLD_OUTPUT_CH", fold_output_ch);
-        self.yolo_conv.set("FOLD_INPUT_CH", fold_input_ch);
-        self.yolo_conv.set("INPUT_H", input_h);
-        self.yolo_conv.set("INPUT_W", input_w);
-        self.yolo_conv.set("REAL_INPUT_H", real_input_h);
-        self.yolo_conv.set("FOLD_WIN_AREA", fold_win_area);
+        self.write_reg(Ip::Conv, "OUTPUT_CH", output_ch);
+        self.write_reg(Ip::Conv, "INPUT_CH", input_ch);
+        self.write_reg(Ip::Conv, "FOLD_OUTPUT_CH", fold_output_ch);
+        self.write_reg(Ip::Conv, "FOLD_INPUT_CH", fold_input_ch);
+        self.write_reg(Ip::Conv, "INPUT_H", input_h);
+        self.write_reg(Ip::Conv, "INPUT_W", input_w);
+        self.write_reg(Ip::Conv, "REAL_INPUT_H", real_input_h);
+        self.write_reg(Ip::Conv, "FOLD_WIN_AREA", fold_win_area);
     }
 
     /// YOLOの最大プーリング層の設定を行います。
@@ -166,12 +867,12 @@ impl YoloController {
         input_fold_ch: u32,
         stride: u32,
     ) {
-        self.yolo_mp.set("OUTPUT_H", output_h);
-        self.yolo_mp.set("OUTPUT_W", output_w);
-        self.yolo_mp.set("INPUT_H", input_h);
-        self.yolo_mp.set("INPUT_W", input_w);
-        self.yolo_mp.set("INPUT_FOLD_CH", input_fold_ch);
-        self.yolo_mp.set("STRIDE", stride);
+        self.write_reg(Ip::MaxPool, "OUTPUT_H", output_h);
+        self.write_reg(Ip::MaxPool, "OUTPUT_W", output_w);
+        self.write_reg(Ip::MaxPool, "INPUT_H", input_h);
+        self.write_reg(Ip::MaxPool, "INPUT_W", input_w);
+        self.write_reg(Ip::MaxPool, "INPUT_FOLD_CH", input_fold_ch);
+        self.write_reg(Ip::MaxPool, "STRIDE", stride);
     }
 
     /// YOLOのYOLO層の設定を行います。
@@ -181,9 +882,9 @@ impl YoloController {
     /// * `input_h` - 入力の高さ
     /// * `input_w` - 入力の幅
     fn set_yolo_yolo(&self, active_en: u32, input_h: u32, input_w: u32) {
-        self.yolo_yolo.set("ACTIVATE_EN", active_en);
-        self.yolo_yolo.set("INPUT_H", input_h);
-        self.yolo_yolo.set("INPUT_W", input_w);
+        self.write_reg(Ip::Yolo, "ACTIVATE_EN", active_en);
+        self.write_reg(Ip::Yolo, "INPUT_H", input_h);
+        self.write_reg(Ip::Yolo, "INPUT_W", input_w);
     }
 
     /// YOLOのアキュムレータ層の設定を行います。
@@ -217,11 +918,11 @@ impl YoloController {
         leaky: u32,
         bias_en: u32,
     ) {
-        self.yolo_acc.set("INPUT_H", input_h);
-        self.yolo_acc.set("INPUT_W", input_w);
-        self.yolo_acc.set("FOLD_INPUT_CH", fold_input_ch);
-        self.yolo_acc.set("LEAKY", leaky);
-        self.yolo_acc.set("BIAS_EN", bias_en);
+        self.write_reg(Ip::Acc, "INPUT_H", input_h);
+        self.write_reg(Ip::Acc, "INPUT_W", input_w);
+        self.write_reg(Ip::Acc, "FOLD_INPUT_CH", fold_input_ch);
+        self.write_reg(Ip::Acc, "LEAKY", leaky);
+        self.write_reg(Ip::Acc, "BIAS_EN", bias_en);
     }
 
     /// Axi4-Stream Switchの設定を行います。
@@ -266,6 +967,14 @@ impl YoloController {
         self.sw0.reg_update_enable();
         self.sw1.reg_update_enable();
         self.sw2.reg_update_enable();
+
+        let ports = [
+            (switch_0_s, switch_0_m),
+            (switch_1_s, switch_1_m),
+            (switch_2_s, switch_2_m),
+        ];
+        self.switch_ports.set(Some(ports));
+        self.trace(|| TraceEvent::Switch { ports });
     }
 
     /// 全てのIPをスタートします。
@@ -276,27 +985,80 @@ impl YoloController {
         let l = &self.layer_groups[grp_idx];
         // IPの動作をスタートさせる (まだデータは送ってないので処理はしてない)
         if !l.conv_disable {
-            self.yolo_conv.start();
-            self.yolo_acc.start();
+            self.start_ip(Ip::Conv);
+            self.start_ip(Ip::Acc);
         }
         if l.post_process_type == PostProcess::MaxPool {
-            self.yolo_mp.start();
+            self.start_ip(Ip::MaxPool);
         }
         if l.post_process_type == PostProcess::Yolo {
-            self.yolo_yolo.start();
+            self.start_ip(Ip::Yolo);
         }
         if l.post_process_type == PostProcess::Upsample {
-            self.yolo_upsamp.start();
+            self.start_ip(Ip::Upsamp);
         }
     }
 
-    /// 全てのIPの設定を行います。
+    /// レイヤーグループの1回のデータ転送を返します。
     ///
     /// # Args
     /// * `grp_idx` - レイヤーグループのインデックス
-    /// * `i` - インデックス
-    fn configure_all_ips(&self, grp_idx: usize, i: u32) {
+    /// * `off` - 出力チャネルのインデックス
+    /// * `iff` - 入力チャネルのインデックス
+    fn layer_step(&self, grp_idx: usize, off: u32, iff: u32) -> LayerStep {
+        LayerStep {
+            grp_idx,
+            off,
+            iff,
+            is_last: iff == self.layer_groups[grp_idx].input_fold_factor - 1,
+        }
+    }
+
+    /// 次のデータ転送を返します。
+    ///
+    /// レイヤーグループの最後の転送の場合は、このコントローラで次に処理するレイヤーグループの最初の転送を返します。
+    ///
+    /// # Args
+    /// * `step` - 現在のデータ転送
+    fn next_step(&self, step: LayerStep) -> Option<LayerStep> {
+        let l = &self.layer_groups[step.grp_idx];
+        if !step.is_last {
+            Some(self.layer_step(step.grp_idx, step.off, step.iff + 1))
+        } else if step.off + 1 < l.output_fold_factor {
+            Some(self.layer_step(step.grp_idx, step.off + 1, 0))
+        } else {
+            (step.grp_idx + 1..self.layer_groups.len())
+                .find(|i| !self.skipped_groups.contains(i))
+                .map(|i| self.layer_step(i, 0, 0))
+        }
+    }
+
+    /// このコントローラで処理しないレイヤーグループを設定します。
+    ///
+    /// 処理しないレイヤーグループのレジスタを前もって設定しないために使います。
+    ///
+    /// # Args
+    /// * `groups` - 他のIPで処理するレイヤーグループのインデックス
+    pub(crate) fn set_skipped_groups(&mut self, groups: &[usize]) {
+        self.skipped_groups = groups.to_vec();
+    }
+
+    /// データ転送で使うIPのレジスタを設定します。
+    ///
+    /// 処理中のIPのレジスタを書き換えないよう、前の転送のIPが完了してから呼び出す必要があります。
+    ///
+    /// # Args
+    /// * `step` - データ転送
+    fn set_ip_registers(&self, step: LayerStep) {
+        let grp_idx = step.grp_idx;
         let l = &self.layer_groups[grp_idx];
+        if !step.is_last {
+            // 最後のチャネルではなければ，畳み込みだけを実行
+            self.set_yolo_conv(grp_idx);
+            self.set_yolo_acc(grp_idx, false);
+            return;
+        }
+
         if !l.conv_disable {
             self.set_yolo_conv(grp_idx);
             self.set_yolo_acc(grp_idx, true);
@@ -309,22 +1071,28 @@ impl YoloController {
             }
         }
         if l.post_process_type == PostProcess::Yolo {
-            self.set_yolo_yolo(ACTIVE_EN[i as usize], l.input_height, l.input_width);
+            self.set_yolo_yolo(
+                self.active_en[step.off as usize],
+                l.input_height,
+                l.input_width,
+            );
         }
-        self.set_axis_switch(l.conv_disable, l.post_process_type);
-        self.start_all_ips(grp_idx);
     }
 
-    /// 畳み込みとアキュムレータIPの設定を行います。
+    /// Axi4-Stream Switchを切り替え、データ転送で使うIPをスタートします。
     ///
     /// # Args
-    /// * `grp_idx` - レイヤーグループのインデックス
-    fn configure_conv_and_acc_ips(&self, grp_idx: usize) {
-        self.set_yolo_conv(grp_idx);
-        self.set_yolo_acc(grp_idx, false);
-        self.set_axis_switch(false, PostProcess::None);
-        self.yolo_conv.start();
-        self.yolo_acc.start();
+    /// * `step` - データ転送
+    fn start_ips(&self, step: LayerStep) {
+        if step.is_last {
+            let l = &self.layer_groups[step.grp_idx];
+            self.set_axis_switch(l.conv_disable, l.post_process_type);
+            self.start_all_ips(step.grp_idx);
+        } else {
+            self.set_axis_switch(false, PostProcess::None);
+            self.start_ip(Ip::Conv);
+            self.start_ip(Ip::Acc);
+        }
     }
 
     /// 重みを転送します。
@@ -337,11 +1105,18 @@ impl YoloController {
     /// # 返り値
     /// * Result。転送に失敗した場合はエラー
     fn transfer_weights(&mut self, grp_idx: usize, off: u32, iff: u32) -> Result<()> {
-        // キャッシュは無効なので，Flushはしなくていい (はず)
         let weights = self.layer_groups[grp_idx].get_weights(off, iff)?;
-        self.dma0.write(weights)?;
-        while !self.dma0.is_mm2s_idle()? {}
-        Ok(())
+        let result = dma_write(
+            &mut self.dma0,
+            weights,
+            self.dma0_max_len,
+            true,
+            self.watchdog_timeout,
+        );
+        if result.is_ok() {
+            self.trace_dma_write("dma0", weights);
+        }
+        self.count_dma_error(result)
     }
 
     /// バイアスを転送します。
@@ -354,9 +1129,17 @@ impl YoloController {
     /// * Result。転送に失敗した場合はエラー
     fn transfer_biases(&mut self, grp_idx: usize, off: u32) -> Result<()> {
         let biases = self.layer_groups[grp_idx].get_biases(off)?;
-        self.dma1.write(biases)?;
-        while !self.dma1.is_mm2s_idle()? {}
-        Ok(())
+        let result = dma_write(
+            &mut self.dma1,
+            biases,
+            self.dma1_max_len,
+            true,
+            self.watchdog_timeout,
+        );
+        if result.is_ok() {
+            self.trace_dma_write("dma1", biases);
+        }
+        self.count_dma_error(result)
     }
 
     /// アキュムレータの入力を転送します。
@@ -367,7 +1150,17 @@ impl YoloController {
     /// # 返り値
     /// * Result。転送に失敗した場合はエラー
     fn transfer_acc_input(&mut self, acc_input_buff: &[i16]) -> Result<()> {
-        self.dma1.write(acc_input_buff)
+        let result = dma_write(
+            &mut self.dma1,
+            acc_input_buff,
+            self.dma1_max_len,
+            false,
+            self.watchdog_timeout,
+        );
+        if result.is_ok() {
+            self.trace_dma_write("dma1", acc_input_buff);
+        }
+        self.count_dma_error(result)
     }
 
     /// アキュムレータの出力を転送します。
@@ -378,7 +1171,17 @@ impl YoloController {
     /// # 返り値
     /// * アキュムレータの出力を含むVec<i16>のResult。転送に失敗した場合はエラー
     fn transfer_acc_output(&mut self, grp_idx: usize) -> Result<Vec<i16>> {
-        self.dma0.read(self.layer_groups[grp_idx].acc_size as usize)
+        let len = self.layer_groups[grp_idx].acc_size as usize;
+        let result = dma_read(
+            &mut self.dma0,
+            len,
+            self.dma0_max_len,
+            self.watchdog_timeout,
+        );
+        if let Ok(data) = &result {
+            self.trace_dma_read("dma0", data);
+        }
+        self.count_dma_error(result)
     }
 
     /// 出力を転送します。
@@ -389,8 +1192,17 @@ impl YoloController {
     /// # 返り値
     /// * 出力を含むVec<i16>のResult。転送に失敗した場合はエラー
     fn transfer_output(&mut self, grp_idx: usize) -> Result<Vec<i16>> {
-        self.dma0
-            .read(self.layer_groups[grp_idx].output_size as usize)
+        let len = self.layer_groups[grp_idx].output_size as usize;
+        let result = dma_read(
+            &mut self.dma0,
+            len,
+            self.dma0_max_len,
+            self.watchdog_timeout,
+        );
+        if let Ok(data) = &result {
+            self.trace_dma_read("dma0", data);
+        }
+        self.count_dma_error(result)
     }
 
     /// 入力を転送します。
@@ -403,8 +1215,17 @@ impl YoloController {
     /// * Result。転送に失敗した場合はエラー
     fn transfer_inputs(&mut self, grp_idx: usize, idx: u32) -> Result<()> {
         let inputs = self.layer_groups[grp_idx].get_inputs(idx)?;
-        self.dma0.write(inputs)?;
-        Ok(())
+        let result = dma_write(
+            &mut self.dma0,
+            inputs,
+            self.dma0_max_len,
+            false,
+            self.watchdog_timeout,
+        );
+        if result.is_ok() {
+            self.trace_dma_write("dma0", inputs);
+        }
+        self.count_dma_error(result)
     }
     /// 最後のチャネルデータを転送します。
     ///
@@ -435,8 +1256,6 @@ impl YoloController {
         }
         let output = self.transfer_output(grp_idx)?;
         self.layer_groups[grp_idx].set_outputs(off, output);
-
-        self.wait_ips(grp_idx);
         Ok(())
     }
 
@@ -460,8 +1279,6 @@ impl YoloController {
         self.transfer_inputs(grp_idx, iff)?;
         self.transfer_acc_input(acc_input_buff)?;
         *acc_output_buff = self.transfer_acc_output(grp_idx)?;
-
-        self.wait_acc_ip();
         Ok(())
     }
 
@@ -469,70 +1286,236 @@ impl YoloController {
     ///
     /// # Args
     /// * `grp_idx` - レイヤーグループのインデックス
-    fn wait_ips(&self, grp_idx: usize) {
-        let l = &self.layer_groups[grp_idx];
-        if l.post_process_type == PostProcess::None {
-            while !self.yolo_acc.is_done() {}
-        }
-        if l.post_process_type == PostProcess::MaxPool {
-            while !self.yolo_mp.is_done() {}
-        }
-        if l.post_process_type == PostProcess::Yolo {
-            while !self.yolo_yolo.is_done() {}
-        }
-        if l.post_process_type == PostProcess::Upsample {
-            while !self.yolo_upsamp.is_done() {}
+    ///
+    /// # 返り値
+    /// * Result。時間切れの場合はエラー
+    fn wait_ips(&self, grp_idx: usize) -> Result<()> {
+        match self.layer_groups[grp_idx].post_process_type {
+            PostProcess::None => self.wait_ip(Ip::Acc),
+            PostProcess::MaxPool => self.wait_ip(Ip::MaxPool),
+            PostProcess::Yolo => self.wait_ip(Ip::Yolo),
+            PostProcess::Upsample => self.wait_ip(Ip::Upsamp),
         }
     }
 
     /// アキュムレータIPが完了するまで待ちます。
-    fn wait_acc_ip(&self) {
-        while !self.yolo_acc.is_done() {}
+    fn wait_acc_ip(&self) -> Result<()> {
+        self.wait_ip(Ip::Acc)
+    }
+
+    /// IPが完了するまで待ちます。
+    ///
+    /// # 返り値
+    /// * Result。時間切れの場合はエラー
+    fn wait_ip(&self, ip: Ip) -> Result<()> {
+        let result = wait_until(self.watchdog_timeout, ip.name(), || {
+            Ok(self.ip(ip).is_done())
+        });
+        self.trace(|| TraceEvent::Wait {
+            target: ip.name().to_string(),
+            ok: result.is_ok(),
+        });
+        result
+    }
+
+    /// IPとDMAの完了を待つ最大の時間を設定します。Noneの場合はウォッチドッグを無効にします。
+    pub fn set_watchdog_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.watchdog_timeout = timeout;
+        self
+    }
+
+    /// IPとDMAの完了を待つ最大の時間を返します。
+    pub fn watchdog_timeout(&self) -> Option<Duration> {
+        self.watchdog_timeout
+    }
+
+    /// レジスタの読み書きとDMAの転送の記録先を設定します。Noneの場合は記録をやめます。
+    pub fn set_trace(&mut self, recorder: Option<TraceRecorder>) -> &mut Self {
+        self.trace = recorder.map(RefCell::new);
+        self
+    }
+
+    /// 名前からDMAのインスタンスと1回の転送の最大の要素数を探します。
+    ///
+    /// # 返り値
+    /// * DMAと最大の要素数。名前が一致しない場合はエラー
+    fn dma_mut(&mut self, name: &str) -> Result<(&mut AxiDma, usize)> {
+        match name {
+            "dma0" => Ok((&mut self.dma0, self.dma0_max_len)),
+            "dma1" => Ok((&mut self.dma1, self.dma1_max_len)),
+            _ => bail!("Unknown DMA \"{}\"", name),
+        }
     }
 
     /// レイヤーグループの処理を開始します。
     ///
+    /// IPかDMAが設定した時間内に完了しなかった場合は、スイッチとDMAをリセットして
+    /// レイヤーグループを1度だけ再実行します。再実行でも完了しなかった場合は `Stalled` のエラーを返します。
+    ///
     /// # Args
     /// * `grp_idx` - 処理を開始するレイヤーグループのインデックス
     ///
     /// # 返り値
-    /// * Result。処理に失敗した場合はエラー
+    /// * Result。処理に失敗した場合や、`shutdown` の後に呼び出した場合はエラー
     pub fn start_layer_processing(&mut self, grp_idx: usize) -> Result<()> {
-        for off in 0..self.layer_groups[grp_idx].output_fold_factor {
-            let mut acc_output_buff = vec![];
-            let mut acc_input_buff = vec![0i16; self.layer_groups[grp_idx].acc_size as usize];
-            // 最大32チャネルのサブチャネルを処理する
-            for iff in 0..self.layer_groups[grp_idx].input_fold_factor {
-                // 最後のチャネルか？
-                let is_last_input_ch = iff == self.layer_groups[grp_idx].input_fold_factor - 1;
-
-                if is_last_input_ch {
-                    // 最後のチャネルならば，IPたちに設定値を送信してスタート
-                    self.configure_all_ips(grp_idx, off);
-                } else {
-                    // 最後のチャネルではなければ，畳み込みだけを実行
-                    self.configure_conv_and_acc_ips(grp_idx);
-                }
+        self.ensure_running()?;
+        let err = match self.run_layer_group(grp_idx) {
+            Err(e) if e.is::<Timeout>() => e,
+            result => return result.map(|()| self.count_saturation(grp_idx)),
+        };
+        let target = err.downcast_ref::<Timeout>().map_or("unknown", |t| t.0);
+        self.stats.timeouts += 1;
+        warn!(
+            "{} stalled in layer group {}. Resetting and replaying the layer group",
+            target, grp_idx
+        );
+        for line in self.dump_state().to_string().lines() {
+            warn!("{}", line);
+        }
+        self.reset_hardware();
 
-                // 重みパラメータをDMAでFPGA (PL) に転送する
-                if !self.layer_groups[grp_idx].conv_disable {
-                    self.transfer_weights(grp_idx, off, iff)?;
+        // リセットできないIPが処理の途中で止まっている場合は、再実行してもデータがずれる
+        let ips = [
+            &self.yolo_conv,
+            &self.yolo_acc,
+            &self.yolo_mp,
+            &self.yolo_yolo,
+            &self.yolo_upsamp,
+        ];
+        let recovered = ips.iter().all(|ip| ip.is_idle() || ip.is_done());
+        if recovered {
+            // set_outputsは既存の出力に追加するため、途中までの出力を捨ててから再実行する
+            self.layer_groups[grp_idx].outputs = None;
+            self.stats.retries += 1;
+            match self.run_layer_group(grp_idx) {
+                Err(e) if e.is::<Timeout>() => self.stats.timeouts += 1,
+                result => {
+                    if result.is_ok() {
+                        self.stats.recoveries += 1;
+                        self.count_saturation(grp_idx);
+                    }
+                    return result;
                 }
+            }
+        }
 
-                // データの送受信
-                if is_last_input_ch {
-                    self.transfer_last_channel_data(grp_idx, off, iff, &acc_input_buff)?;
-                } else {
-                    self.transfer_subchannel_data(
-                        grp_idx,
-                        iff,
-                        &acc_input_buff,
-                        &mut acc_output_buff,
-                    )?;
-                }
+        let state = self.dump_state();
+        self.stats.stalls += 1;
+        self.reset();
+        Err(Stalled {
+            target,
+            grp_idx,
+            state,
+        }
+        .into())
+    }
+
+    /// 1つのレイヤーグループだけを処理し、出力を返します。
+    ///
+    /// どのレイヤーグループでデータが壊れるかを切り分けるためのデバッグ用の関数です。
+    ///
+    /// # Args
+    /// * `grp_idx` - 処理するレイヤーグループのインデックス
+    /// * `input` - レイヤーグループの入力 (全ての入力チャネル)
+    ///
+    /// # 返り値
+    /// * レイヤーグループの出力 (全ての出力チャネル)
+    pub fn run_layer(&mut self, grp_idx: usize, input: Vec<i16>) -> Result<Vec<i16>> {
+        let l = self
+            .layer_groups
+            .get_mut(grp_idx)
+            .with_context(|| format!("Layer group {} does not exist", grp_idx))?;
+        let expected = (l.input_size * l.input_fold_factor) as usize;
+        if input.len() != expected {
+            bail!(
+                "Layer group {} expects {} input values, but {} were given",
+                grp_idx,
+                expected,
+                input.len()
+            );
+        }
+        l.inputs = Some(input);
+        l.outputs = None;
 
-                std::mem::swap(&mut acc_input_buff, &mut acc_output_buff);
+        self.start_layer_processing(grp_idx)?;
+        let l = &mut self.layer_groups[grp_idx];
+        l.inputs = None;
+        l.outputs
+            .take()
+            .with_context(|| format!("layer_groups[{}].outputs not set", grp_idx))
+    }
+
+    /// レイヤーグループを処理します。
+    ///
+    /// 各データ転送のIPの完了後に次のデータ転送 (次に処理するレイヤーグループの最初の転送を含む) のレジスタを設定します。
+    ///
+    /// 出力の受信と、次の転送のレジスタの設定や重みの送信は重ねられません。IPは処理中にレジスタを書き換えられず、
+    /// 次の転送の重みはスイッチを切り替えてIPをスタートするまで受け取られないためです。
+    /// 出力の受信はIPの完了とほぼ同時に終わるため、レジスタを先に設定しても処理時間は短くなりません。
+    /// 先に設定しておくのは、設定済みの転送を `dump_state` で確認できるようにするためです。
+    ///
+    /// # Args
+    /// * `grp_idx` - 処理するレイヤーグループのインデックス
+    ///
+    /// # 返り値
+    /// * Result。処理に失敗した場合はエラー
+    fn run_layer_group(&mut self, grp_idx: usize) -> Result<()> {
+        let mut acc_output_buff = vec![];
+        let mut acc_input_buff = vec![];
+
+        let mut step = Some(self.layer_step(grp_idx, 0, 0));
+        while let Some(cur) = step.filter(|s| s.grp_idx == grp_idx) {
+            // 前の転送とIPの処理は完了しているので，ここで中断すれば状態を戻せる
+            if self.cancel.is_cancelled() {
+                self.reset();
+                return Err(Cancelled.into());
+            }
+
+            let LayerStep { off, iff, .. } = cur;
+            if iff == 0 {
+                acc_output_buff = vec![];
+                acc_input_buff = vec![0i16; self.layer_groups[grp_idx].acc_size as usize];
+            }
+
+            // 前の転送の完了後に設定済みでなければ，IPたちに設定値を送信
+            self.current_step = Some(cur);
+            self.trace(|| TraceEvent::Step {
+                grp_idx,
+                off,
+                iff,
+                is_last: cur.is_last,
+            });
+            if self.preconfigured.take() != Some(cur) {
+                self.set_ip_registers(cur);
+            }
+            self.start_ips(cur);
+
+            // 重みパラメータをDMAでFPGA (PL) に転送する
+            if !self.layer_groups[grp_idx].conv_disable {
+                self.transfer_weights(grp_idx, off, iff)?;
+            }
+
+            // データの送受信
+            if cur.is_last {
+                self.transfer_last_channel_data(grp_idx, off, iff, &acc_input_buff)?;
+            } else {
+                self.transfer_subchannel_data(grp_idx, iff, &acc_input_buff, &mut acc_output_buff)?;
             }
+
+            if cur.is_last {
+                self.wait_ips(grp_idx)?;
+            } else {
+                self.wait_acc_ip()?;
+            }
+
+            // IPは完了しているので，次の転送のレジスタを設定しておく (処理時間は変わらない)
+            step = self.next_step(cur);
+            if let Some(next) = step {
+                self.set_ip_registers(next);
+                self.preconfigured = Some(next);
+            }
+
+            std::mem::swap(&mut acc_input_buff, &mut acc_output_buff);
         }
         Ok(())
     }
@@ -589,70 +1572,338 @@ impl YoloController {
         }
     }
 
-    /// 重みとバイアスデータを読み込みます。
+    /// DMAを停止します
+    pub fn stop_dmas(&mut self) {
+        self.dma0.stop();
+        self.dma1.stop();
+    }
+
+    /// スイッチの全ての出力を切り離し、DMAをソフトリセットしてから再起動します。
+    ///
+    /// 設定済みのレジスタは無効になるため、次のデータ転送の前に再設定されます。
+    fn reset_hardware(&mut self) {
+        self.stats.resets += 1;
+        self.preconfigured = None;
+        self.switch_ports.set(None);
+        self.trace(|| TraceEvent::Reset);
+        for sw in [&self.sw0, &self.sw1, &self.sw2] {
+            sw.reg_update_disable();
+            sw.disable_all_mi_ports();
+            sw.reg_update_enable();
+        }
+        // 止まった転送はRun/Stopでは破棄されないため、DMACRのResetで両方のチャネルを初期化する
+        let timeout = self.watchdog_timeout.unwrap_or(Duration::from_secs(1));
+        for dma in [&mut self.dma0, &mut self.dma1] {
+            if let Err(e) = dma.reset(timeout) {
+                warn!("{:#}", e);
+            }
+            dma.start();
+        }
+    }
+
+    /// スイッチ・DMA・YOLOのIPのレジスタと、処理中のレイヤーグループの設定を取得します。
     ///
-    /// # Args
-    /// * `path` - 重みとバイアスデータが格納されているgzipアーカイブへのパス
+    /// スイッチのレジスタは読み出せないため、最後に設定した接続を返します。
+    /// IPのレジスタが次のデータ転送のために設定済みの場合は、`HardwareState::preconfigured` にその転送を返します。
     ///
-    /// # 注意
-    /// この関数は各レイヤーグループの重みとバイアスデータを読み込みます。データは16ビット整数として解釈されます。
-    /// * ファイル名が "biases" で始まる場合、バイアスデータとして解釈されます。
-    /// * ファイル名が "weights" で始まる場合、重みデータとして解釈されます。
-    /// * それ以外のファイル名の場合、警告がログに出力され、そのファイルは無視されます。
-    pub fn read_weights_and_biases<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        let file = File::open(path)?;
-        let mut archive = Archive::new(GzDecoder::new(file));
-
-        for file in archive.entries()? {
-            let mut file = file?;
-            let file_path = file.path()?;
-            let file_name = file_path
-                .file_name()
-                .context("file_name error")?
-                .to_str()
-                .context("to_str error")?
-                .to_string();
-
-            // Skip files that start with '._'
-            if file_name.starts_with("._") {
-                continue;
+    /// # 返り値
+    /// * ハードウェアの状態。`HardwareState::log` でログに出力できます
+    pub fn dump_state(&self) -> HardwareState {
+        let ips = Ip::ALL
+            .into_iter()
+            .map(|ip| IpState {
+                name: ip.name(),
+                done: self.ip(ip).is_done(),
+                idle: self.ip(ip).is_idle(),
+                registers: ip
+                    .registers()
+                    .iter()
+                    .map(|&r| (r, self.read_reg(ip, r)))
+                    .collect(),
+            })
+            .collect();
+
+        let dma = |name, dma: &AxiDma, max_len| DmaState {
+            name,
+            mm2s_idle: dma.is_mm2s_idle().ok(),
+            s2mm_idle: dma.is_s2mm_idle().ok(),
+            max_len,
+        };
+        let dmas = vec![
+            dma("axi_dma_0", &self.dma0, self.dma0_max_len),
+            dma("axi_dma_1", &self.dma1, self.dma1_max_len),
+        ];
+
+        let switches = self
+            .switch_ports
+            .get()
+            .map(|ports| {
+                ["axis_switch_0", "axis_switch_1", "axis_switch_2"]
+                    .into_iter()
+                    .zip(ports)
+                    .map(|(name, (slave, master))| SwitchState {
+                        name,
+                        slave,
+                        master,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let layer_group = self.current_step.and_then(|step| {
+            let l = self.layer_groups.get(step.grp_idx)?;
+            Some(LayerGroupState {
+                grp_idx: step.grp_idx,
+                off: step.off,
+                iff: step.iff,
+                input: (l.input_width, l.input_height, l.input_ch),
+                output: (l.output_width, l.output_height, l.output_ch),
+                fold_factor: (l.input_fold_factor, l.output_fold_factor),
+                activation: activation_name(l.activate_type),
+                post_process: post_process_name(l.post_process_type),
+                conv_disable: l.conv_disable,
+                has_weights: l.weights.is_some(),
+                has_biases: l.biases.is_some(),
+            })
+        });
+
+        // 処理中の転送が完了した後は、次の転送のレジスタが設定されている
+        let preconfigured = self.preconfigured.map(|step| StepState {
+            grp_idx: step.grp_idx,
+            off: step.off,
+            iff: step.iff,
+        });
+
+        HardwareState {
+            ips,
+            preconfigured,
+            dmas,
+            switches,
+            layer_group,
+        }
+    }
+
+    /// パイプラインを順序立てて停止します。
+    ///
+    /// 1. 受信中 (S2MM) の転送とIPの処理の完了を待つ
+    /// 2. Axi4-Stream Switchの全ての出力を切り離す
+    /// 3. DMAを停止する
+    ///
+    /// 完了を待つ時間はウォッチドッグと同じで、時間内に完了しなかった場合も停止は続行します。
+    /// 2回目以降の呼び出しでは何もしません。破棄されるときにも自動で呼び出されます。
+    /// 停止した後にレイヤーグループを処理しようとするとエラーを返します。
+    ///
+    /// # 返り値
+    /// * Result。転送かIPが完了しないまま停止した場合はエラー
+    pub fn shutdown(&mut self) -> Result<()> {
+        if self.shut_down {
+            return Ok(());
+        }
+        self.shut_down = true;
+
+        // 次に起動したときに途中まで設定されたパイプラインが残らないよう、処理中のものを終わらせる
+        let timeout = self.watchdog_timeout.or(Some(Duration::from_secs(1)));
+        let mut pending = vec![];
+        for (name, dma) in [("dma0", &self.dma0), ("dma1", &self.dma1)] {
+            if wait_until(timeout, name, || dma.is_s2mm_idle()).is_err() {
+                pending.push(format!("{} S2MM", name));
             }
+        }
+        for (name, ip) in [
+            ("yolo_conv", &self.yolo_conv),
+            ("yolo_acc", &self.yolo_acc),
+            ("yolo_max_pool", &self.yolo_mp),
+            ("yolo_yolo", &self.yolo_yolo),
+            ("yolo_upsamp", &self.yolo_upsamp),
+        ] {
+            if wait_until(timeout, name, || Ok(ip.is_idle())).is_err() {
+                pending.push(name.to_string());
+            }
+        }
 
-            let mut buf = vec![];
-            file.read_to_end(&mut buf).unwrap();
-            let data: Vec<i16> = buf
-                .chunks(2)
-                .map(|chunk| {
-                    let bytes = [chunk[0], chunk[1]];
-                    i16::from_le_bytes(bytes)
-                })
-                .collect();
-
-            if &file_name[..6] == "biases" {
-                let gnum: usize = file_name[6..].parse()?;
-                info!("Loading bias {}", gnum);
-                self.layer_groups[gnum].biases = Some(data);
-            } else if &file_name[..7] == "weights" {
-                let gnum: usize = file_name[7..].parse()?;
-                info!("Loading weight {}", gnum);
-                self.layer_groups[gnum].weights = Some(data);
-            } else {
-                warn!("{} is not biases or weights file", file_name);
+        let state = self.dump_state();
+        self.preconfigured = None;
+        self.switch_ports.set(None);
+        for sw in [&self.sw0, &self.sw1, &self.sw2] {
+            sw.reg_update_disable();
+            sw.disable_all_mi_ports();
+            sw.reg_update_enable();
+        }
+        self.stop_dmas();
+
+        if !pending.is_empty() {
+            for line in state.to_string().lines() {
+                warn!("{}", line);
             }
+            bail!("Shut down with unfinished {}", pending.join(", "));
         }
         Ok(())
     }
 
-    /// DMAを停止します
-    pub fn stop_dmas(&self) {
-        self.dma0.stop();
-        self.dma1.stop();
+    /// `shutdown` の後はエラーを返します。
+    pub(crate) fn ensure_running(&self) -> Result<()> {
+        if self.shut_down {
+            bail!("The YOLO pipeline has been shut down");
+        }
+        Ok(())
+    }
+
+    /// DMAとIPのエラーの統計を返します。
+    pub fn hw_stats(&self) -> HwStats {
+        self.stats
+    }
+
+    /// DMAとIPのエラーの統計を0に戻します。
+    pub fn reset_hw_stats(&mut self) {
+        self.stats = HwStats::default();
+    }
+
+    /// レイヤーグループの出力の飽和の監視を有効または無効にします。
+    ///
+    /// 有効にすると、各レイヤーグループの出力を走査するため処理時間が少し増えます。
+    /// 無効にすると、それまでの統計は破棄されます。
+    pub fn set_saturation_monitor(&mut self, enable: bool) {
+        if !enable {
+            self.saturation = None;
+        } else if self.saturation.is_none() {
+            self.saturation = Some(vec![LayerSaturation::default(); self.layer_groups.len()]);
+        }
+    }
+
+    /// レイヤーグループごとの出力の飽和の統計を返します。監視していない場合はNone
+    pub fn saturation_stats(&self) -> Option<&[LayerSaturation]> {
+        self.saturation.as_deref()
+    }
+
+    /// 出力の飽和の統計を0に戻します。
+    pub fn reset_saturation_stats(&mut self) {
+        if let Some(stats) = &mut self.saturation {
+            stats.fill(LayerSaturation::default());
+        }
+    }
+
+    /// 監視が有効な場合に、レイヤーグループの出力の飽和した値を数えます。
+    fn count_saturation(&mut self, grp_idx: usize) {
+        let (Some(stats), Some(output)) =
+            (&mut self.saturation, &self.layer_groups[grp_idx].outputs)
+        else {
+            return;
+        };
+        if stats.len() <= grp_idx {
+            stats.resize(grp_idx + 1, LayerSaturation::default());
+        }
+        stats[grp_idx].count(output);
+    }
+
+    /// DMAの転送に失敗した場合に、統計に数えます。
+    ///
+    /// 時間切れは `start_layer_processing` で数えるため、ここでは数えません。
+    fn count_dma_error<T>(&mut self, result: Result<T>) -> Result<T> {
+        if result.as_ref().is_err_and(|e| !e.is::<Timeout>()) {
+            self.stats.dma_errors += 1;
+        }
+        result
+    }
+
+    /// YOLO層で活性化関数を適用するチャネルのマスクを返します。
+    pub(crate) fn active_en(&self) -> &[u32] {
+        &self.active_en
+    }
+
+    /// YOLO層で活性化関数を適用するチャネルのマスクを設定します。
+    ///
+    /// # Args
+    /// * `masks` - 出力の分割ごとのマスク (`postprocess::active_en_masks`)
+    pub(crate) fn set_active_en(&mut self, masks: Vec<u32>) {
+        self.active_en = masks;
+    }
+
+    /// 中断のハンドルを返します。
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// 中断のハンドルを差し替えます。
+    ///
+    /// # Args
+    /// * `cancel` - 他のコントローラと共有するハンドル
+    pub(crate) fn set_cancel_handle(&mut self, cancel: CancelHandle) {
+        self.cancel = cancel;
+    }
+
+    /// 中断した処理の状態を破棄し、スイッチとDMAをリセットします。
+    ///
+    /// データ転送とIPの処理が完了している状態で呼び出す必要があります。
+    pub fn reset(&mut self) {
+        self.reset_hardware();
+        for l in self.layer_groups.iter_mut() {
+            l.inputs = None;
+            l.outputs = None;
+        }
     }
 }
 
 impl Drop for YoloController {
     // デストラクタ (スレッドを停止)
     fn drop(&mut self) {
-        self.stop_dmas();
+        if let Err(e) = self.shutdown() {
+            warn!("{:#}", e);
+        }
+    }
+}
+
+/// 実機でトレースを再生します。
+///
+/// DMAで送信するデータは記録されていないため、同じ要素数の0を送ります。
+/// 記録時は完了を待たずに次の送信を始めることがあるため、送信の前に前の送信の完了を待ちます。
+impl ReplayTarget for YoloController {
+    fn apply(&mut self, event: &TraceEvent) -> Result<Option<TraceEvent>> {
+        self.ensure_running()?;
+        let timeout = self.watchdog_timeout;
+        match event {
+            TraceEvent::Step { .. } => {}
+            TraceEvent::Write { ip, reg, value } => {
+                let ip = Ip::from_name(ip)?;
+                self.write_reg(ip, ip.register(reg)?, *value);
+            }
+            TraceEvent::Read { ip: name, reg, .. } => {
+                let ip = Ip::from_name(name)?;
+                return Ok(Some(TraceEvent::Read {
+                    ip: name.clone(),
+                    reg: reg.clone(),
+                    value: self.read_reg(ip, ip.register(reg)?),
+                }));
+            }
+            TraceEvent::Start { ip } => self.start_ip(Ip::from_name(ip)?),
+            TraceEvent::Switch { ports } => {
+                let [(s0, m0), (s1, m1), (s2, m2)] = *ports;
+                self.set_axis_switch_internal(s0, m0, s1, m1, s2, m2);
+            }
+            TraceEvent::DmaWrite { dma: name, len, .. } => {
+                let data = vec![0; *len];
+                let (dma, max_len) = self.dma_mut(name)?;
+                dma_write(dma, &data, max_len, false, timeout)?;
+                self.trace_dma_write(name, &data);
+            }
+            TraceEvent::DmaRead { dma: name, len, .. } => {
+                let (dma, max_len) = self.dma_mut(name)?;
+                let data = dma_read(dma, *len, max_len, timeout)?;
+                self.trace_dma_read(name, &data);
+                return Ok(Some(TraceEvent::DmaRead {
+                    dma: name.clone(),
+                    len: data.len(),
+                    checksum: trace::checksum(&data),
+                }));
+            }
+            TraceEvent::Wait { target, .. } => {
+                let ip = Ip::from_name(target)?;
+                return Ok(Some(TraceEvent::Wait {
+                    target: target.clone(),
+                    ok: self.wait_ip(ip).is_ok(),
+                }));
+            }
+            TraceEvent::Reset => self.reset_hardware(),
+        }
+        Ok(None)
     }
 }
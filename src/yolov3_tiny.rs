@@ -1,29 +1,476 @@
 //! YOLOv3-Tiny のモデルをコントロールするモジュール
 
-use std::path::Path;
-use anyhow::{bail, ensure, Context, Result};
-use image::DynamicImage;
-use color_space;
+use std::borrow::Cow;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{bail, Context, Result};
+use image::{DynamicImage, RgbImage};
+use log::{debug, info, warn};
 
-use crate::detection_result::DetectionData;
+use crate::anchors::ANCHOR_NUM;
+use crate::config::Config;
+use crate::day_night::ProfileConfig;
+use crate::debug::{DebugSink, DirSink, DisabledSink};
+use crate::detection_result::{
+    DetectionData, DetectionDataExt, FrameResult, LetterboxSpace, NormalizedSpace,
+};
+use crate::enhance::{self, Enhancement, ToneMapping};
+use crate::features::FeatureMap;
+use crate::hooks::Hooks;
+use crate::hw_state::{HardwareState, HwStats, LayerSaturation};
 use crate::img_proc;
 use crate::layer_group::{Activation, LayerGroup, PostProcess};
-use crate::postprocess;
-use crate::yolo::YoloController;
+use crate::mining::CropSaver;
+use crate::nms;
+use crate::npy::{self, NpyArray};
+use crate::postprocess::{self, OutputLayout, PostprocessStats};
+use crate::preprocess::{AutoZoom, Letterbox, Mosaic, PatialEnlargement, Preprocessor};
+use crate::session::{PostprocessParams, SessionRecorder};
+use crate::sim::SimBackend;
+use crate::trace::{self, ReplayReport, TraceRecorder};
+use crate::tta::Augmentation;
+use crate::validator::{TrafficLightValidator, Validator};
+use crate::yolo::{self, YoloController};
+pub use crate::yolo::{CancelHandle, Cancelled, Stalled};
+
+/// YOLOv3-Tiny のレイヤーグループの構成を返します。
+#[rustfmt::skip]
+fn layer_groups() -> Vec<LayerGroup> {
+    vec![
+        LayerGroup::new(416, 416,  3,  1, 208, 208, 16,  1, false,  Activation::Leaky,  PostProcess::MaxPool, 2),
+        LayerGroup::new(208, 208, 16,  1, 104, 104, 32,  1, false,  Activation::Leaky,  PostProcess::MaxPool, 2),
+        LayerGroup::new(104, 104, 32,  1,  52,  52, 32,  2, false,  Activation::Leaky,  PostProcess::MaxPool, 2),
+        LayerGroup::new( 52,  52, 32,  2,  26,  26, 32,  4, false,  Activation::Leaky,  PostProcess::MaxPool, 2),
+        LayerGroup::new( 26,  26, 32,  4,  26,  26, 32,  8, false,  Activation::Leaky,     PostProcess::None, 2),
+        LayerGroup::new( 26,  26, 32,  1,  13,  13, 32,  8,  true, Activation::Linear,  PostProcess::MaxPool, 2),
+        LayerGroup::new( 13,  13, 32,  8,  13,  13, 32, 16, false,  Activation::Leaky,  PostProcess::MaxPool, 1),
+        LayerGroup::new( 13,  13, 32, 16,  13,  13, 32, 32, false,  Activation::Leaky,     PostProcess::None, 2),
+        LayerGroup::new( 13,  13, 32, 32,  13,  13, 32,  8, false,  Activation::Leaky,     PostProcess::None, 2),
+        LayerGroup::new( 13,  13, 32,  8,  13,  13, 32, 16, false,  Activation::Leaky,     PostProcess::None, 2),
+        LayerGroup::new( 13,  13, 32, 16,  13,  13, 32,  8, false, Activation::Linear,     PostProcess::Yolo, 2),
+        LayerGroup::new( 13,  13, 32,  8,  26,  26, 32,  4, false,  Activation::Leaky, PostProcess::Upsample, 2),
+        LayerGroup::new( 26,  26, 32, 12,  26,  26, 32,  8, false,  Activation::Leaky,     PostProcess::None, 2),
+        LayerGroup::new( 26,  26, 32,  8,  26,  26, 32,  8, false, Activation::Linear,     PostProcess::Yolo, 2),
+    ]
+}
+
+/// レイヤーグループの入力になる出力のレイヤーグループを、連結する順に返します。
+///
+/// # Args
+/// * `grp_idx` - レイヤーグループのインデックス
+pub(crate) fn layer_sources(grp_idx: usize) -> &'static [usize] {
+    const SOURCES: [&[usize]; 14] = [
+        &[],
+        &[0],
+        &[1],
+        &[2],
+        &[3],
+        &[4],
+        &[5],
+        &[6],
+        &[7],
+        &[8],
+        &[9],
+        // レイヤ11の入力はレイヤ8
+        &[8],
+        // レイヤ12の入力はレイヤ11とレイヤ4をconcatしたもの
+        &[11, 4],
+        &[12],
+    ];
+    SOURCES[grp_idx]
+}
+
+/// 2つ目のパイプラインで処理する26×26のヘッドのレイヤーグループ
+const SECOND_HEAD: std::ops::RangeInclusive<usize> = 11..=13;
+
+/// レイヤーグループの重みとバイアス
+pub(crate) type LayerParams = (Option<Vec<i16>>, Option<Vec<i16>>);
+
+/// 2つ目のYOLOのIPのワーカースレッドへの依頼
+enum Job {
+    /// レイヤーグループ8と4の出力から、レイヤーグループ11-13を処理する
+    Run(Vec<i16>, Vec<i16>),
+    /// レイヤーグループ11-13の重みとバイアスを置き換える
+    SetParams(Vec<LayerParams>),
+    /// DMAとIPのエラーの統計を0に戻す
+    ResetStats,
+}
+
+/// 26×26のヘッドを2つ目のYOLOのIPで処理するワーカースレッド
+struct SecondPipeline {
+    /// 依頼の送信先
+    job_tx: Option<mpsc::Sender<Job>>,
+    /// 出力 (レイヤーグループ13の出力) の受信元
+    result_rx: mpsc::Receiver<Result<Vec<i16>>>,
+    /// 2つ目のIPのDMAとIPのエラーの統計 (依頼を処理するたびに更新)
+    stats: Arc<Mutex<HwStats>>,
+    /// スレッドハンドル
+    thread_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl SecondPipeline {
+    /// ワーカースレッドを起動します。
+    ///
+    /// # Args
+    /// * `hwinfo_path` - HW情報のパス
+    /// * `yolo_hier` - 2つ目のYOLO階層のパス
+    /// * `params` - レイヤーグループ11-13の重みとバイアス
+    /// * `active_en` - YOLO層で活性化関数を適用するチャネルのマスク
+    /// * `cancel` - 1つ目のIPと共有する中断のハンドル
+    /// * `watchdog_timeout` - IPとDMAの完了を待つ最大の時間
+    fn spawn(
+        hwinfo_path: &str,
+        yolo_hier: &str,
+        params: Vec<LayerParams>,
+        active_en: Vec<u32>,
+        cancel: CancelHandle,
+        watchdog_timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let (job_tx, job_rx) = mpsc::channel::<Job>();
+        let (result_tx, result_rx) = mpsc::channel();
+        let (init_tx, init_rx) = mpsc::channel();
+        let hwinfo_path = hwinfo_path.to_string();
+        let yolo_hier = yolo_hier.to_string();
+        let stats = Arc::new(Mutex::new(HwStats::default()));
+        let worker_stats = stats.clone();
+
+        let thread_handle = thread::spawn(move || {
+            // YoloControllerはスレッド間で移動できないため、ワーカースレッド内で作成する
+            let mut yc = match YoloController::new(&hwinfo_path, &yolo_hier) {
+                Ok(yc) => {
+                    let _ = init_tx.send(Ok(()));
+                    yc
+                }
+                Err(e) => {
+                    let _ = init_tx.send(Err(e));
+                    return;
+                }
+            };
+            yc.set_active_en(active_en);
+            yc.set_cancel_handle(cancel);
+            yc.set_watchdog_timeout(watchdog_timeout);
+            yc.layer_groups = layer_groups();
+            Self::set_params(&mut yc, params);
+
+            while let Ok(job) = job_rx.recv() {
+                match job {
+                    Job::Run(output8, output4) => {
+                        let _ = result_tx.send(Self::run(&mut yc, output8, output4));
+                    }
+                    Job::SetParams(params) => Self::set_params(&mut yc, params),
+                    Job::ResetStats => yc.reset_hw_stats(),
+                }
+                *worker_stats.lock().unwrap() = yc.hw_stats();
+            }
+        });
+
+        init_rx
+            .recv()
+            .context("Second pipeline worker has terminated during initialization")??;
+
+        Ok(Self {
+            job_tx: Some(job_tx),
+            result_rx,
+            stats,
+            thread_handle: Some(thread_handle),
+        })
+    }
+
+    /// レイヤーグループ11-13の重みとバイアスを置き換えます。
+    fn set_params(yc: &mut YoloController, params: Vec<LayerParams>) {
+        for (grp_idx, (weights, biases)) in SECOND_HEAD.zip(params) {
+            yc.layer_groups[grp_idx].weights = weights;
+            yc.layer_groups[grp_idx].biases = biases;
+        }
+    }
+
+    /// レイヤーグループ11-13を処理します。
+    ///
+    /// # Args
+    /// * `yc` - 2つ目のYOLOのIP
+    /// * `output8` - レイヤーグループ8の出力
+    /// * `output4` - レイヤーグループ4の出力
+    ///
+    /// # Return
+    /// * レイヤーグループ13の出力
+    fn run(yc: &mut YoloController, output8: Vec<i16>, output4: Vec<i16>) -> Result<Vec<i16>> {
+        yc.layer_groups[11].inputs = Some(output8);
+        yc.start_layer_processing(11)?;
+
+        // レイヤ12の入力はレイヤ11とレイヤ4をconcatしたもの
+        let mut inputs = yc.layer_groups[11]
+            .outputs
+            .take()
+            .context("layer_groups[11].outputs not set")?;
+        inputs.extend(output4);
+        yc.layer_groups[12].inputs = Some(inputs);
+        yc.start_layer_processing(12)?;
+
+        yc.layer_groups[13].inputs = yc.layer_groups[12].outputs.take();
+        yc.start_layer_processing(13)?;
+        yc.layer_groups[13]
+            .outputs
+            .take()
+            .context("layer_groups[13].outputs not set")
+    }
+
+    /// ワーカースレッドに依頼を送ります。
+    fn send(&self, job: Job) -> Result<()> {
+        self.job_tx
+            .as_ref()
+            .context("Second pipeline is stopped")?
+            .send(job)
+            .map_err(|_| anyhow::anyhow!("Second pipeline worker has terminated"))
+    }
+
+    /// レイヤーグループ11-13の処理を依頼します。
+    fn submit(&self, output8: Vec<i16>, output4: Vec<i16>) -> Result<()> {
+        self.send(Job::Run(output8, output4))
+    }
+
+    /// 2つ目のIPのDMAとIPのエラーの統計を返します。
+    fn hw_stats(&self) -> HwStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// 依頼した処理の結果を受け取ります。
+    fn recv(&self) -> Result<Vec<i16>> {
+        self.result_rx
+            .recv()
+            .context("Second pipeline worker has terminated")?
+    }
+}
+
+impl Drop for SecondPipeline {
+    fn drop(&mut self) {
+        // 送信側を閉じるとワーカースレッドのループが終了する
+        self.job_tx.take();
+        if let Some(thread_handle) = self.thread_handle.take() {
+            let _ = thread_handle.join();
+        }
+    }
+}
+
+/// 重みやバイアスの形状が、レイヤーグループの構成やクラス数と合わないことを表すエラー
+///
+/// 別のクラス数で書き出した重みを読み込んだ場合などに返されます。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShapeMismatch {
+    /// 合わなかったもの (例: `weights3`, `classes`)
+    pub name: String,
+    /// 期待した大きさ
+    pub expected: usize,
+    /// 実際の大きさ
+    pub actual: usize,
+}
+
+impl fmt::Display for ShapeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Shape mismatch in {}: expected {}, found {}",
+            self.name, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ShapeMismatch {}
+
+/// 形状が合わないことを表すエラーを返します。
+fn mismatch(name: String, expected: usize, actual: usize) -> Result<()> {
+    Err(ShapeMismatch {
+        name,
+        expected,
+        actual,
+    }
+    .into())
+}
+
+/// 重みとバイアスの形状が、レイヤーグループの構成と出力の並びに合うかを確認します。
+///
+/// # Args
+/// * `layers` - 重みとバイアスを読み込んだレイヤーグループ
+/// * `cls_num` - クラス数
+/// * `class_slots` - 1つのアンカーボックスあたりのクラスの枠の数
+///
+/// # Return
+/// * 合わない場合は `ShapeMismatch` のエラー
+fn check_shapes(layers: &[LayerGroup], cls_num: usize, class_slots: usize) -> Result<()> {
+    for (i, l) in layers.iter().enumerate() {
+        if l.conv_disable {
+            continue;
+        }
+        let weights = l.weights.as_ref().map_or(0, Vec::len);
+        if weights != l.weights_len() {
+            return mismatch(format!("weights{}", i), l.weights_len(), weights);
+        }
+        let biases = l.biases.as_ref().map_or(0, Vec::len);
+        if biases != l.biases_len() {
+            return mismatch(format!("biases{}", i), l.biases_len(), biases);
+        }
+    }
+    check_layout(layers, cls_num, class_slots)
+}
+
+/// クラス数と出力の並びが、YOLO層のレイヤーグループの出力チャネルに収まるかを確認します。
+///
+/// # Args
+/// * `layers` - レイヤーグループの構成
+/// * `cls_num` - クラス数
+/// * `class_slots` - 1つのアンカーボックスあたりのクラスの枠の数
+///
+/// # Return
+/// * 収まらない場合は `ShapeMismatch` のエラー
+fn check_layout(layers: &[LayerGroup], cls_num: usize, class_slots: usize) -> Result<()> {
+    if cls_num == 0 || cls_num > class_slots {
+        return mismatch("classes".to_string(), class_slots, cls_num);
+    }
+    let used = (5 + class_slots) * postprocess::ANCHOR_BOX_NUM;
+    for (i, l) in layers.iter().enumerate() {
+        if l.post_process_type == PostProcess::Yolo && used > l.biases_len() {
+            return mismatch(format!("channels{}", i), l.biases_len(), used);
+        }
+    }
+    Ok(())
+}
+
+/// `.npz` ファイルから、レイヤーグループの重みとバイアスを読み込みます。
+///
+/// # Args
+/// * `path` - 読み込むファイルのパス
+/// * `layers` - 読み込み先のレイヤーグループ
+fn read_npz_params<P: AsRef<Path>>(path: P, layers: &mut [LayerGroup]) -> Result<()> {
+    let num_groups = layers.len();
+    for (name, array) in npy::load_npz::<_, i16>(path)? {
+        let (is_weights, gnum) = if let Some(n) = name.strip_prefix("weights") {
+            (true, n)
+        } else if let Some(n) = name.strip_prefix("biases") {
+            (false, n)
+        } else {
+            warn!("{} is not biases or weights array", name);
+            continue;
+        };
+        let gnum: usize = gnum
+            .parse()
+            .with_context(|| format!("Invalid array name: {}", name))?;
+        if gnum >= num_groups {
+            bail!("Layer group {} of {} does not exist", gnum, name);
+        }
+        let l = &mut layers[gnum];
+        if is_weights {
+            l.weights = Some(array.data);
+        } else {
+            l.biases = Some(array.data);
+        }
+    }
+    Ok(())
+}
+
+/// YOLOの処理を行うバックエンド
+enum Backend {
+    /// FPGAのYOLOのIP
+    Hardware(Box<YoloController>),
+    /// 登録した物体を検出した出力を生成するシミュレーション
+    Sim {
+        sim: SimBackend,
+        /// レイヤーグループの構成と読み込んだ重みとバイアス
+        layer_groups: Vec<LayerGroup>,
+        cancel: CancelHandle,
+    },
+}
+
+impl Backend {
+    /// YOLOのIPのコントローラを返します。シミュレーションの場合はNone
+    fn hardware(&self) -> Option<&YoloController> {
+        match self {
+            Backend::Hardware(yc) => Some(yc),
+            Backend::Sim { .. } => None,
+        }
+    }
+
+    /// YOLOのIPのコントローラを返します。シミュレーションの場合はNone
+    fn hardware_mut(&mut self) -> Option<&mut YoloController> {
+        match self {
+            Backend::Hardware(yc) => Some(yc),
+            Backend::Sim { .. } => None,
+        }
+    }
+
+    /// IPを直接操作する関数のために、YOLOのIPのコントローラを返します。シミュレーションの場合はエラー
+    fn hw(&self) -> Result<&YoloController> {
+        self.hardware()
+            .context("The simulator backend has no hardware")
+    }
+
+    /// IPを直接操作する関数のために、YOLOのIPのコントローラを返します。シミュレーションの場合はエラー
+    fn hw_mut(&mut self) -> Result<&mut YoloController> {
+        self.hardware_mut()
+            .context("The simulator backend has no hardware")
+    }
+
+    /// レイヤーグループの構成を返します。
+    fn layer_groups(&self) -> &[LayerGroup] {
+        match self {
+            Backend::Hardware(yc) => &yc.layer_groups,
+            Backend::Sim { layer_groups, .. } => layer_groups,
+        }
+    }
+
+    /// レイヤーグループの構成を返します。
+    fn layer_groups_mut(&mut self) -> &mut [LayerGroup] {
+        match self {
+            Backend::Hardware(yc) => &mut yc.layer_groups,
+            Backend::Sim { layer_groups, .. } => layer_groups,
+        }
+    }
+
+    /// 推論を中断するためのハンドルを返します。
+    fn cancel_handle(&self) -> CancelHandle {
+        match self {
+            Backend::Hardware(yc) => yc.cancel_handle(),
+            Backend::Sim { cancel, .. } => cancel.clone(),
+        }
+    }
+
+    /// YOLO層の活性化関数のマスクを設定します。シミュレーションは出力の並びから直接出力を生成するため、何もしません。
+    fn set_active_en(&mut self, masks: Vec<u32>) {
+        if let Backend::Hardware(yc) = self {
+            yc.set_active_en(masks);
+        }
+    }
+}
 
 /// YOLOv3-Tiny のモデルをコントロールする構造体
 pub struct YoloV3Tiny {
-    yc: YoloController,
+    backend: Backend,
     cls_num: usize,
+    layout: OutputLayout,
+    class_names: Vec<String>,
     obj_threshold: f32,
     nms_threshold: f32,
-    n_regions: u32,
-    trim_rate: f32,
+    fixed_point_postprocess: bool,
+    postprocess_stats_en: bool,
+    postprocess_stats: Option<PostprocessStats>,
+    tone_mapping: ToneMapping,
+    enhancements: Vec<Enhancement>,
+    validators: Vec<Box<dyn Validator>>,
+    debug_sink: Box<dyn DebugSink>,
+    hooks: Hooks,
+    frame_id: u64,
+    crop_saver: Option<CropSaver>,
+    session: Option<SessionRecorder>,
+    second_pipeline: Option<SecondPipeline>,
 }
 
 impl YoloV3Tiny {
     /// 新しい `YoloV3Tiny` インスタンスを作成します。
     ///
+    /// 重みは既定の出力の並び (80クラスの枠) で読み込みます。クラスの枠の数を変える場合は、
+    /// `uninit` で作成して `set_class_slots` を呼び出してから `init` してください。
+    ///
     /// # Args
     /// * `hwinfo_path` - HW情報のパス
     /// * `yolo_hier` - YOLO階層のパス
@@ -43,46 +490,683 @@ impl YoloV3Tiny {
         nms_threshold: f32,
         weights_path: P,
     ) -> Result<Self> {
-        let yc = YoloController::new(hwinfo_path, yolo_hier)?;
-
-        let mut s = Self {
-            yc,
+        let mut s = Self::uninit(
+            hwinfo_path,
+            yolo_hier,
             cls_num,
             obj_threshold,
             nms_threshold,
-            n_regions: 2,
-            trim_rate: 0.12,
-        };
+        )?;
         s.init(weights_path)?;
 
         Ok(s)
     }
 
+    /// 設定ファイルの内容から `YoloV3Tiny` インスタンスを作成します。
+    ///
+    /// 出力の並びとアンカーボックスを設定してから重みを読み込むため、
+    /// クラス数に合わせて詰めて書き出した重みもそのまま使えます。
+    ///
+    /// # Args
+    /// * `config` - `Config::from_toml` で読み込んだ設定
+    ///
+    /// # Return
+    /// * 新たな `YoloV3Tiny` インスタンス
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let hw = &config.hardware;
+        let model = &config.model;
+        let mut s = Self::uninit(
+            &hw.hwinfo_path,
+            &hw.hierarchy,
+            model.cls_num()?,
+            model.obj_threshold,
+            model.nms_threshold,
+        )?;
+        let layout = model.layout();
+        s.backend
+            .set_active_en(layout.active_en_masks(s.output_folds())?);
+        s.layout = layout;
+        s.class_names = model.class_names.clone();
+        s.set_fixed_point_postprocess(model.fixed_point_postprocess);
+        s.set_watchdog_timeout(config.watchdog_timeout());
+
+        if model.weights.extension().is_some_and(|e| e == "npz") {
+            s.read_weights_npz(&model.weights)?;
+        } else {
+            s.read_weights_and_biases(&model.weights)?;
+        }
+
+        s.clear_validators();
+        if let Some(tl_config) = config.validator.traffic_light_config()? {
+            s.add_validator(TrafficLightValidator::new(tl_config));
+        }
+        if let Some(dir) = &config.debug.dir {
+            s.set_debug_sink(DirSink::new(dir)?);
+        }
+        s.set_crop_saver(config.debug.crop_saver()?);
+        s.set_saturation_monitor(config.debug.saturation_monitor);
+        s.set_postprocess_stats(config.debug.postprocess_stats);
+        s.set_session_record(config.debug.session.as_ref())?;
+        s.set_tone_mapping(config.tone_mapping)?;
+        s.set_enhancements(config.enhancement.clone())?;
+        if let Some(hier) = &hw.second_hierarchy {
+            s.enable_second_pipeline(&hw.hwinfo_path, hier)?;
+        }
+        Ok(s)
+    }
+
+    /// 重みを読み込む前の `YoloV3Tiny` インスタンスを作成します。
+    ///
+    /// `set_class_slots` などで出力の並びを設定してから、`init` で重みを読み込んでください。
+    /// 重みの形状は読み込むときに、その時点の出力の並びで確認されます。
+    ///
+    /// # Args
+    /// * `hwinfo_path` - HW情報のパス
+    /// * `yolo_hier` - YOLO階層のパス
+    /// * `cls_num` - クラス数
+    /// * `obj_threshold` - オブジェクトの閾値
+    /// * `nms_threshold` - NMSの閾値
+    ///
+    /// # Return
+    /// * 重みを読み込む前の `YoloV3Tiny` インスタンス
+    pub fn uninit(
+        hwinfo_path: &str,
+        yolo_hier: &str,
+        cls_num: usize,
+        obj_threshold: f32,
+        nms_threshold: f32,
+    ) -> Result<Self> {
+        let mut yc = YoloController::new(hwinfo_path, yolo_hier)?;
+        yc.layer_groups = layer_groups();
+
+        Ok(Self::with_backend(
+            Backend::Hardware(Box::new(yc)),
+            cls_num,
+            obj_threshold,
+            nms_threshold,
+        ))
+    }
+
+    /// ハードウェアの代わりに `SimBackend` でYOLO層の出力を生成する `YoloV3Tiny` インスタンスを作成します。
+    ///
+    /// 入力画像の補正・前処理・後処理・バリデータ・コールバック・セッションの記録はハードウェアの場合と同じく行われるため、
+    /// ボードがない環境でもソフトウェアで行う処理を確認できます。
+    /// クラス数・出力の並び・閾値・後処理の方法は `sim` の設定で初期化します。
+    /// IPを直接操作する関数 (`run_layer`, `self_test`, `set_trace` など) はエラーを返します。
+    ///
+    /// # Args
+    /// * `sim` - 出力する物体を登録した `SimBackend`
+    ///
+    /// # Return
+    /// * 新たな `YoloV3Tiny` インスタンス
+    pub fn with_sim(sim: SimBackend) -> Self {
+        let params = sim.postprocess_params();
+        let mut s = Self::with_backend(
+            Backend::Sim {
+                sim,
+                layer_groups: layer_groups(),
+                cancel: CancelHandle::default(),
+            },
+            params.cls_num,
+            params.obj_threshold,
+            params.nms_threshold,
+        );
+        s.layout = params.layout;
+        s.fixed_point_postprocess = params.fixed_point_postprocess;
+        s
+    }
+
+    /// バックエンドと後処理の設定から、その他を既定値にした `YoloV3Tiny` インスタンスを作成します。
+    fn with_backend(
+        backend: Backend,
+        cls_num: usize,
+        obj_threshold: f32,
+        nms_threshold: f32,
+    ) -> Self {
+        Self {
+            backend,
+            cls_num,
+            layout: OutputLayout::default(),
+            class_names: vec![],
+            obj_threshold,
+            nms_threshold,
+            fixed_point_postprocess: false,
+            postprocess_stats_en: false,
+            postprocess_stats: None,
+            tone_mapping: ToneMapping::default(),
+            enhancements: vec![],
+            validators: vec![Box::new(TrafficLightValidator::default())],
+            debug_sink: Box::new(DisabledSink),
+            hooks: Hooks::default(),
+            frame_id: 0,
+            crop_saver: None,
+            session: None,
+            second_pipeline: None,
+        }
+    }
+
+    /// ハードウェア情報に含まれるYOLOの階層 (例: `yolo0`, `yolo1`) を列挙します。
+    ///
+    /// 異なる階層を指定すれば、同じハードウェア情報から複数の `YoloV3Tiny` を作成できます。
+    /// 同じDMAを使う階層を重ねて指定した場合、`new` はエラーを返します。
+    ///
+    /// # Args
+    /// * `hwinfo_path` - HW情報のパス
+    pub fn hierarchies(hwinfo_path: &str) -> Result<Vec<String>> {
+        yolo::yolo_hierarchies(hwinfo_path)
+    }
+
+    /// 検出結果のバリデータを追加します。
+    ///
+    /// バリデータは `start_with_patial_enlargement` で `yolo_en` が false のとき、追加した順に適用されます。
+    ///
+    /// # Args
+    /// * `validator` - 追加するバリデータ
+    pub fn add_validator<V: Validator + 'static>(&mut self, validator: V) {
+        self.validators.push(Box::new(validator));
+    }
+
+    /// 登録されている全てのバリデータを削除します。
+    pub fn clear_validators(&mut self) {
+        self.validators.clear();
+    }
+
+    /// デバッグ用の画像やログの出力先を設定します。
+    ///
+    /// # Args
+    /// * `sink` - デバッグ出力先。無効にする場合は `DisabledSink` を指定してください
+    pub fn set_debug_sink<S: DebugSink + 'static>(&mut self, sink: S) {
+        self.debug_sink = Box::new(sink);
+    }
+
+    /// 検出結果の切り抜き画像の保存先を設定します。
+    ///
+    /// `start_with_preprocessor` (`start_with_img_proc` を含む)、`start_with_patial_enlargement`、
+    /// `start_tta`、`start_batch`、`start_dir` の検出結果が、検出結果と同じ座標系の補正後の画像から保存されます。
+    /// 保存に失敗した場合は警告を出力し、検出結果はそのまま返します。
+    ///
+    /// # Args
+    /// * `saver` - 切り抜き画像の保存先。保存しない場合はNone
+    pub fn set_crop_saver(&mut self, saver: Option<CropSaver>) {
+        self.crop_saver = saver;
+    }
+
+    /// フレームの処理開始時に呼び出されるコールバックを登録します。
+    ///
+    /// # Args
+    /// * `hook` - フレーム番号を受け取るコールバック
+    pub fn on_frame_start<F: FnMut(u64) + 'static>(&mut self, hook: F) {
+        self.hooks.add_frame_start(Box::new(hook));
+    }
+
+    /// 後処理直後 (座標変換・検証前) の検出結果に対して呼び出されるコールバックを登録します。
+    ///
+    /// # Args
+    /// * `hook` - フレーム番号と検出結果を受け取るコールバック
+    pub fn on_raw_detections<F: FnMut(u64, &[DetectionData<LetterboxSpace>]) + 'static>(
+        &mut self,
+        hook: F,
+    ) {
+        self.hooks.add_raw_detections(Box::new(hook));
+    }
+
+    /// 最終的な検出結果に対して呼び出されるコールバックを登録します。
+    ///
+    /// # Args
+    /// * `hook` - フレーム番号と検出結果を受け取るコールバック
+    pub fn on_final_detections<F: FnMut(u64, &[DetectionData]) + 'static>(&mut self, hook: F) {
+        self.hooks.add_final_detections(Box::new(hook));
+    }
+
+    /// 登録されている全てのコールバックを削除します。
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear();
+    }
+
     /// YOLOv3-Tiny モデルを初期化します。
     ///
     /// # Args
     /// * `weights_dir` - 重みのディレクトリ
     /// * `biases_dir` - バイアスのディレクトリ
-    #[rustfmt::skip]
     pub fn init<P: AsRef<Path>>(&mut self, weights_path: P) -> Result<()> {
-        self.yc.layer_groups.push(LayerGroup::new(416, 416,  3,  1, 208, 208, 16,  1, false,  Activation::Leaky,  PostProcess::MaxPool, 2));
-        self.yc.layer_groups.push(LayerGroup::new(208, 208, 16,  1, 104, 104, 32,  1, false,  Activation::Leaky,  PostProcess::MaxPool, 2));
-        self.yc.layer_groups.push(LayerGroup::new(104, 104, 32,  1,  52,  52, 32,  2, false,  Activation::Leaky,  PostProcess::MaxPool, 2));
-        self.yc.layer_groups.push(LayerGroup::new( 52,  52, 32,  2,  26,  26, 32,  4, false,  Activation::Leaky,  PostProcess::MaxPool, 2));
-        self.yc.layer_groups.push(LayerGroup::new( 26,  26, 32,  4,  26,  26, 32,  8, false,  Activation::Leaky,     PostProcess::None, 2));
-        self.yc.layer_groups.push(LayerGroup::new( 26,  26, 32,  1,  13,  13, 32,  8,  true, Activation::Linear,  PostProcess::MaxPool, 2));
-        self.yc.layer_groups.push(LayerGroup::new( 13,  13, 32,  8,  13,  13, 32, 16, false,  Activation::Leaky,  PostProcess::MaxPool, 1));
-        self.yc.layer_groups.push(LayerGroup::new( 13,  13, 32, 16,  13,  13, 32, 32, false,  Activation::Leaky,     PostProcess::None, 2));
-        self.yc.layer_groups.push(LayerGroup::new( 13,  13, 32, 32,  13,  13, 32,  8, false,  Activation::Leaky,     PostProcess::None, 2));
-        self.yc.layer_groups.push(LayerGroup::new( 13,  13, 32,  8,  13,  13, 32, 16, false,  Activation::Leaky,     PostProcess::None, 2));
-        self.yc.layer_groups.push(LayerGroup::new( 13,  13, 32, 16,  13,  13, 32,  8, false, Activation::Linear,     PostProcess::Yolo, 2));
-        self.yc.layer_groups.push(LayerGroup::new( 13,  13, 32,  8,  26,  26, 32,  4, false,  Activation::Leaky, PostProcess::Upsample, 2));
-        self.yc.layer_groups.push(LayerGroup::new( 26,  26, 32, 12,  26,  26, 32,  8, false,  Activation::Leaky,     PostProcess::None, 2));
-        self.yc.layer_groups.push(LayerGroup::new( 26,  26, 32,  8,  26,  26, 32,  8, false, Activation::Linear,     PostProcess::Yolo, 2));
-
         self.read_weights_and_biases(weights_path)
     }
 
+    /// 2つ目のYOLOのIPを有効にします。
+    ///
+    /// ビットストリームにYOLOのパイプラインが2つある場合、レイヤーグループ8以降の
+    /// 13×13のヘッド (9-10) と26×26のヘッド (11-13) を2つのIPで並列に処理します。
+    /// 重みとバイアスは読み込み済みのものが使われるため、`init` の後に呼び出してください。
+    /// 有効にした後に読み込んだ重みとバイアスは、2つ目のIPにも反映されます。
+    ///
+    /// # Args
+    /// * `hwinfo_path` - HW情報のパス
+    /// * `yolo_hier` - 2つ目のYOLO階層のパス
+    pub fn enable_second_pipeline(&mut self, hwinfo_path: &str, yolo_hier: &str) -> Result<()> {
+        let yc = self.backend.hw()?;
+        let params = SECOND_HEAD
+            .map(|i| {
+                let l = &yc.layer_groups[i];
+                (l.weights.clone(), l.biases.clone())
+            })
+            .collect();
+        self.second_pipeline = Some(SecondPipeline::spawn(
+            hwinfo_path,
+            yolo_hier,
+            params,
+            yc.active_en().to_vec(),
+            yc.cancel_handle(),
+            yc.watchdog_timeout(),
+        )?);
+        Ok(())
+    }
+
+    /// 2つ目のYOLOのIPを無効にし、全てのレイヤーグループを1つのIPで処理します。
+    pub fn disable_second_pipeline(&mut self) {
+        self.second_pipeline = None;
+    }
+
+    /// 1つのアンカーボックスあたりのクラスの枠の数を設定し、YOLO層の活性化関数のマスクを求め直します。
+    ///
+    /// 既定では、重みはCOCOと同じ80クラスの枠 (アンカーボックスあたり85チャネル) で並んでいるものとします。
+    /// クラス数に合わせて詰めて書き出した重みを使う場合は、クラス数を指定してください。
+    /// 2つ目のIPを使う場合は、`enable_second_pipeline` の前に呼び出してください。
+    ///
+    /// # Args
+    /// * `class_slots` - クラスの枠の数
+    pub fn set_class_slots(&mut self, class_slots: usize) -> Result<&mut Self> {
+        if self.second_pipeline.is_some() {
+            bail!("Disable the second pipeline before changing the class slots");
+        }
+        let masks = OutputLayout {
+            class_slots,
+            ..self.layout
+        }
+        .active_en_masks(self.output_folds())?;
+        check_layout(self.layer_groups(), self.cls_num, class_slots)?;
+        self.layout.class_slots = class_slots;
+        self.backend.set_active_en(masks);
+        Ok(self)
+    }
+
+    /// x, y, 物体確率, クラス確率のシグモイドを後処理で適用するかを設定し、YOLO層の活性化関数のマスクを求め直します。
+    ///
+    /// 有効にすると、YOLOのIPの活性化関数は無効 (線形) になります。
+    /// YOLOのIPをバイパスしたビットストリームや、IPで活性化関数を適用しない場合に使います。
+    /// 2つ目のIPを使う場合は、`enable_second_pipeline` の前に呼び出してください。
+    ///
+    /// # Args
+    /// * `enable` - シグモイドを後処理で適用するか
+    pub fn set_software_sigmoid(&mut self, enable: bool) -> Result<&mut Self> {
+        if self.second_pipeline.is_some() {
+            bail!("Disable the second pipeline before changing the activation");
+        }
+        let layout = OutputLayout {
+            software_sigmoid: enable,
+            ..self.layout
+        };
+        self.backend
+            .set_active_en(layout.active_en_masks(self.output_folds())?);
+        self.layout = layout;
+        Ok(self)
+    }
+
+    /// 後処理で使うアンカーボックスを設定します。
+    ///
+    /// # Args
+    /// * `anchors` - 6つのアンカーボックスの幅と高さ (面積の小さい順)。`Anchors::sizes` をそのまま渡せます
+    pub fn set_anchors(&mut self, anchors: [[f32; 2]; ANCHOR_NUM]) -> &mut Self {
+        self.layout.anchors = anchors;
+        self
+    }
+
+    /// 出力の並びとアンカーボックスを返します。
+    pub fn output_layout(&self) -> &OutputLayout {
+        &self.layout
+    }
+
+    /// 後処理を固定小数点数のまま行うかを設定します (`postprocess::post_process_fixed`)。
+    ///
+    /// FPUの遅いZynq-7000で後処理の時間を減らせます。上位k個のクラス候補を求める `start_top_k` には影響しません。
+    pub fn set_fixed_point_postprocess(&mut self, enable: bool) -> &mut Self {
+        self.fixed_point_postprocess = enable;
+        self
+    }
+
+    /// 後処理の統計の記録を有効または無効にします。
+    ///
+    /// 有効にすると、フレームごとにNMSの前の候補の数・クラスごとに抑制された数・デコードとNMSの時間を記録し、
+    /// debugレベルでログに出力します。閾値を調整するときに使います。
+    pub fn set_postprocess_stats(&mut self, enable: bool) -> &mut Self {
+        self.postprocess_stats_en = enable;
+        if !enable {
+            self.postprocess_stats = None;
+        }
+        self
+    }
+
+    /// 直前のフレームの後処理の統計を返します。記録していない場合はNone
+    pub fn postprocess_stats(&self) -> Option<&PostprocessStats> {
+        self.postprocess_stats.as_ref()
+    }
+
+    /// オブジェクトの閾値とNMSの閾値を設定します。
+    ///
+    /// # Args
+    /// * `obj_threshold` - オブジェクトの閾値
+    /// * `nms_threshold` - NMSの閾値
+    pub fn set_thresholds(&mut self, obj_threshold: f32, nms_threshold: f32) -> &mut Self {
+        self.obj_threshold = obj_threshold;
+        self.nms_threshold = nms_threshold;
+        self
+    }
+
+    /// オブジェクトの閾値とNMSの閾値を返します。
+    pub fn thresholds(&self) -> (f32, f32) {
+        (self.obj_threshold, self.nms_threshold)
+    }
+
+    /// 昼または夜のプロファイルを適用します (`DayNightSwitcher`)。
+    ///
+    /// プロファイルで省略した項目は `config` の値に戻します。
+    /// バリデータは全て削除し、プロファイルまたは `config` の信号機のバリデータに置き換えます。
+    ///
+    /// # Args
+    /// * `config` - 基準となる設定
+    /// * `profile` - 適用するプロファイル
+    pub fn apply_profile(&mut self, config: &Config, profile: &ProfileConfig) -> Result<&mut Self> {
+        self.set_enhancements(
+            profile
+                .enhancement
+                .clone()
+                .unwrap_or_else(|| config.enhancement.clone()),
+        )?;
+        self.set_thresholds(
+            profile.obj_threshold.unwrap_or(config.model.obj_threshold),
+            profile.nms_threshold.unwrap_or(config.model.nms_threshold),
+        );
+        self.clear_validators();
+        let validator = profile.validator.as_ref().unwrap_or(&config.validator);
+        if let Some(tl_config) = validator.traffic_light_config()? {
+            self.add_validator(TrafficLightValidator::new(tl_config));
+        }
+        Ok(self)
+    }
+
+    /// 16ビットなど8ビットを超える階調の入力画像を、8ビットに変換する方法を設定します。
+    ///
+    /// 変換は入力画像の補正処理の前に行われます。8ビットの入力画像には影響しません。
+    ///
+    /// # Args
+    /// * `tone_mapping` - 変換方法。パラメータが正しくない場合はエラー
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) -> Result<&mut Self> {
+        tone_mapping.validate()?;
+        self.tone_mapping = tone_mapping;
+        Ok(self)
+    }
+
+    /// 入力画像の補正処理を設定します。
+    ///
+    /// 補正処理は画像をYOLOの入力データに変換する前に順に適用され、バリデータにも補正後の画像が渡されます。
+    /// 空にすると補正を行いません。
+    ///
+    /// # Args
+    /// * `enhancements` - 補正処理。パラメータが正しくない場合はエラー
+    pub fn set_enhancements(&mut self, enhancements: Vec<Enhancement>) -> Result<&mut Self> {
+        for e in &enhancements {
+            e.validate()?;
+        }
+        self.enhancements = enhancements;
+        Ok(self)
+    }
+
+    /// 入力画像の補正処理を返します。
+    pub fn enhancements(&self) -> &[Enhancement] {
+        &self.enhancements
+    }
+
+    /// 入力画像にトーンマッピングと補正処理を適用します。
+    fn enhance<'a>(&self, img: &'a DynamicImage) -> Cow<'a, DynamicImage> {
+        enhance::enhance(img, self.tone_mapping, &self.enhancements)
+    }
+
+    /// クラスIDの順に並べたクラス名を設定します。
+    ///
+    /// # Args
+    /// * `class_names` - クラス名。数がクラス数と合わない場合はエラー
+    pub fn set_class_names(&mut self, class_names: Vec<String>) -> Result<&mut Self> {
+        if class_names.len() != self.cls_num {
+            bail!(
+                "{} class names are given, but the model has {} classes",
+                class_names.len(),
+                self.cls_num
+            );
+        }
+        self.class_names = class_names;
+        Ok(self)
+    }
+
+    /// クラスIDに対応するクラス名を返します。クラス名が設定されていない場合はNoneを返します。
+    pub fn class_name(&self, cls_id: u8) -> Option<&str> {
+        self.class_names.get(cls_id as usize).map(String::as_str)
+    }
+
+    /// 実行中の推論を中断します。
+    ///
+    /// 推論はデータ転送の区切りで中断され、`Cancelled` のエラーを返します。
+    /// スイッチとDMAはリセットされるため、中断した後もそのまま次の推論に使えます。
+    /// 推論を実行していないときに呼び出した場合は何もしません。
+    pub fn cancel(&self) {
+        self.backend.cancel_handle().cancel();
+    }
+
+    /// IPとDMAの完了を待つ最大の時間を設定します。Noneの場合はウォッチドッグを無効にします。
+    ///
+    /// 時間内に完了しなかった場合はスイッチとDMAをリセットしてレイヤーグループを再実行し、
+    /// それでも完了しなければ `Stalled` のエラーを返します。
+    /// 2つ目のIPには、`enable_second_pipeline` を呼び出した時点の値が使われます。
+    pub fn set_watchdog_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        if let Some(yc) = self.backend.hardware_mut() {
+            yc.set_watchdog_timeout(timeout);
+        }
+        self
+    }
+
+    /// レジスタの読み書き・IPのスタート・DMAの転送を、ファイルに1行ずつ記録します。Noneの場合は記録をやめます。
+    ///
+    /// 2つ目のIPの操作は記録しません。記録したファイルは `replay_trace` か `trace::MockBackend` で再生できます。
+    ///
+    /// # Args
+    /// * `path` - トレースを書き出すパス。既存のファイルは上書きします
+    pub fn set_trace<P: AsRef<Path>>(&mut self, path: Option<P>) -> Result<&mut Self> {
+        let recorder = path.map(TraceRecorder::create).transpose()?;
+        self.backend.hw_mut()?.set_trace(recorder);
+        Ok(self)
+    }
+
+    /// フレームごとの入力データ・YOLO層の出力・後処理の結果を、ファイルに記録します。Noneの場合は記録をやめます。
+    ///
+    /// `start` と、`start` を使う画像の処理を記録します。`start_top_k` と2つ目のIPの処理は記録しません。
+    /// 記録したファイルは `session::replay_session` で再生できます。
+    ///
+    /// # Args
+    /// * `path` - セッションを書き出すパス。既存のファイルは上書きします
+    pub fn set_session_record<P: AsRef<Path>>(&mut self, path: Option<P>) -> Result<&mut Self> {
+        self.session = path.map(SessionRecorder::create).transpose()?;
+        Ok(self)
+    }
+
+    /// 現在の後処理の設定を返します。
+    pub fn postprocess_params(&self) -> PostprocessParams {
+        PostprocessParams {
+            cls_num: self.cls_num,
+            layout: self.layout,
+            obj_threshold: self.obj_threshold,
+            nms_threshold: self.nms_threshold,
+            fixed_point_postprocess: self.fixed_point_postprocess,
+        }
+    }
+
+    /// 記録したトレースを実機で再生し、完了待ちの結果が記録と同じになるかを調べます。
+    ///
+    /// 現場で起きた時間切れを机上で再現するための関数です。再生した後はスイッチとDMAをリセットします。
+    ///
+    /// # Args
+    /// * `path` - トレースのパス
+    ///
+    /// # 返り値
+    /// * 再生の結果
+    pub fn replay_trace<P: AsRef<Path>>(&mut self, path: P) -> Result<ReplayReport> {
+        let events = trace::read_trace(path)?;
+        let yc = self.backend.hw_mut()?;
+        let report = trace::replay(&events, &mut *yc);
+        // 途中で終わったトレースでも、次の推論を最初から設定し直せるようにする
+        yc.reset();
+        report
+    }
+
+    /// スイッチ・DMA・YOLOのIPのレジスタと、処理中のレイヤーグループの設定を取得します。
+    ///
+    /// 推論結果がおかしいときに、`HardwareState::log` でログに出力して不具合の報告に添付してください。
+    /// シミュレーションの場合は空の状態を返します。
+    pub fn dump_state(&self) -> HardwareState {
+        match self.backend.hardware() {
+            Some(yc) => yc.dump_state(),
+            None => HardwareState {
+                ips: vec![],
+                preconfigured: None,
+                dmas: vec![],
+                switches: vec![],
+                layer_group: None,
+            },
+        }
+    }
+
+    /// 組み込みの既知のデータでハードウェアを検査します。
+    ///
+    /// 読み込んだ重みを使わないため、検査に成功して推論結果がおかしい場合は重みを疑ってください。
+    pub fn self_test(&mut self) -> Result<()> {
+        self.backend.hw_mut()?.self_test()
+    }
+
+    /// 2つ目のIPを含め、パイプラインを順序立てて停止します。
+    ///
+    /// 受信中の転送とIPの処理の完了を待ち、スイッチを切り離してからDMAを停止します。
+    /// 停止した後に推論しようとするとエラーを返します。破棄されるときにも自動で行われます。
+    pub fn shutdown(&mut self) -> Result<()> {
+        // 2つ目のIPはワーカースレッドの終了時に停止する
+        self.second_pipeline = None;
+        self.backend
+            .hardware_mut()
+            .map_or(Ok(()), YoloController::shutdown)
+    }
+
+    /// DMAとIPのエラーの統計を返します。2つ目のIPを使っている場合は、2つ目のIPの統計を合計します。
+    pub fn hw_stats(&self) -> HwStats {
+        let mut stats = self
+            .backend
+            .hardware()
+            .map_or_else(HwStats::default, YoloController::hw_stats);
+        if let Some(second) = &self.second_pipeline {
+            stats += second.hw_stats();
+        }
+        stats
+    }
+
+    /// DMAとIPのエラーの統計を0に戻します。
+    pub fn reset_hw_stats(&mut self) {
+        if let Some(yc) = self.backend.hardware_mut() {
+            yc.reset_hw_stats();
+        }
+        if let Some(second) = &self.second_pipeline {
+            *second.stats.lock().unwrap() = HwStats::default();
+            if let Err(e) = second.send(Job::ResetStats) {
+                warn!("{:#}", e);
+            }
+        }
+    }
+
+    /// レイヤーグループの出力の飽和の監視を有効または無効にします。
+    ///
+    /// 監視している間は、全てのレイヤーグループの出力を数えるため、2つ目のIPは使わずに処理します。
+    pub fn set_saturation_monitor(&mut self, enable: bool) -> &mut Self {
+        if let Some(yc) = self.backend.hardware_mut() {
+            yc.set_saturation_monitor(enable);
+        }
+        self
+    }
+
+    /// レイヤーグループごとの出力の飽和の統計を返します。監視していない場合は空
+    pub fn saturation_stats(&self) -> Vec<LayerSaturation> {
+        self.backend
+            .hardware()
+            .and_then(YoloController::saturation_stats)
+            .map_or(vec![], <[_]>::to_vec)
+    }
+
+    /// 出力の飽和の統計を0に戻します。
+    pub fn reset_saturation_stats(&mut self) {
+        if let Some(yc) = self.backend.hardware_mut() {
+            yc.reset_saturation_stats();
+        }
+    }
+
+    /// 出力の飽和の統計をログに出力します。飽和した値があるレイヤーグループはwarnレベルで出力します。
+    pub fn log_saturation(&self) {
+        for (grp_idx, s) in self.saturation_stats().iter().enumerate() {
+            if s.positive + s.negative > 0 {
+                warn!("Layer group {}: {}", grp_idx, s);
+            } else {
+                info!("Layer group {}: {}", grp_idx, s);
+            }
+        }
+    }
+
+    /// 他のスレッドから推論を中断するためのハンドルを返します。
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.backend.cancel_handle()
+    }
+
+    /// レイヤーグループの構成を返します。
+    pub fn layer_groups(&self) -> &[LayerGroup] {
+        self.backend.layer_groups()
+    }
+
+    /// YOLO層の出力の分割数を返します。
+    fn output_folds(&self) -> usize {
+        self.layer_groups()[10].output_fold_factor as usize
+    }
+
+    /// 最後に処理を開始したフレームの番号を返します。フックや `FrameResult` に渡す番号と同じです。
+    pub fn frame_id(&self) -> u64 {
+        self.frame_id
+    }
+
+    /// `with_sim` で作成した場合は、出力する物体を変更するための `SimBackend` を返します。ハードウェアの場合はNone
+    pub fn sim_mut(&mut self) -> Option<&mut SimBackend> {
+        match &mut self.backend {
+            Backend::Hardware(_) => None,
+            Backend::Sim { sim, .. } => Some(sim),
+        }
+    }
+
+    /// 各レイヤーグループの重みとバイアスを返します。
+    pub(crate) fn layer_params(&self) -> Vec<LayerParams> {
+        self.layer_groups()
+            .iter()
+            .map(|l| (l.weights.clone(), l.biases.clone()))
+            .collect()
+    }
+
+    /// 各レイヤーグループの重みとバイアスを置き換えます。2つ目のIPを使っている場合は、2つ目のIPの分も置き換えます。
+    pub(crate) fn set_layer_params(&mut self, params: Vec<LayerParams>) -> Result<()> {
+        for (l, (weights, biases)) in self.backend.layer_groups_mut().iter_mut().zip(params) {
+            l.weights = weights;
+            l.biases = biases;
+        }
+        self.update_second_params()
+    }
+
     /// 重みとバイアスデータを読み込みます。
     ///
     /// # Args
@@ -93,8 +1177,221 @@ impl YoloV3Tiny {
     /// * ファイル名が "biases" で始まる場合、バイアスデータとして解釈されます。
     /// * ファイル名が "weights" で始まる場合、重みデータとして解釈されます。
     /// * それ以外のファイル名の場合、警告がログに出力され、そのファイルは無視されます。
+    ///
+    /// 形状が合わない場合はエラーを返し、読み込まれている重みとバイアスは変更しません。
     pub fn read_weights_and_biases<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        self.yc.read_weights_and_biases(path)
+        let mut layers = layer_groups();
+        yolo::read_weight_archive(path, &mut layers)?;
+        self.replace_params(layers)
+    }
+
+    /// 読み込んだ重みとバイアスの形状を確認してから、現在の重みとバイアスと置き換えます。
+    ///
+    /// # Args
+    /// * `layers` - 重みとバイアスを読み込んだレイヤーグループ
+    fn replace_params(&mut self, layers: Vec<LayerGroup>) -> Result<()> {
+        check_shapes(&layers, self.cls_num, self.layout.class_slots)?;
+        self.set_layer_params(layers.into_iter().map(|l| (l.weights, l.biases)).collect())
+    }
+
+    /// 読み込まれている重みとバイアスの形状が、レイヤーグループの構成と出力の並びに合うかを確認します。
+    ///
+    /// YOLO層の出力チャネルに、クラスの枠の数 (`set_class_slots`) で並べたアンカーボックスが収まる必要があります。
+    ///
+    /// # Return
+    /// * 合わない場合は `ShapeMismatch` のエラー
+    pub fn validate_shapes(&self) -> Result<()> {
+        check_shapes(self.layer_groups(), self.cls_num, self.layout.class_slots)
+    }
+
+    /// ハードウェアを使わずに、重みとバイアスのファイルがレイヤーグループの構成とクラス数に合うかを確認します。
+    ///
+    /// # Args
+    /// * `path` - 重みとバイアスのパス。拡張子が `.npz` の場合はNumPy形式、それ以外はtar.gzとして読み込みます
+    /// * `cls_num` - クラス数
+    /// * `class_slots` - 1つのアンカーボックスあたりのクラスの枠の数
+    ///
+    /// # Return
+    /// * 読み込めない場合はエラー、形状が合わない場合は `ShapeMismatch` のエラー
+    pub fn verify_weights<P: AsRef<Path>>(
+        path: P,
+        cls_num: usize,
+        class_slots: usize,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let mut layers = layer_groups();
+        if path.extension().is_some_and(|e| e == "npz") {
+            read_npz_params(path, &mut layers)?;
+        } else {
+            yolo::read_weight_archive(path, &mut layers)
+                .with_context(|| format!("Can't read {}", path.display()))?;
+        }
+        check_shapes(&layers, cls_num, class_slots)
+    }
+
+    /// 読み込まれている重みとバイアスを `.npz` ファイルに保存します。
+    ///
+    /// 配列の名前は重みのアーカイブと同じく `weights{n}` と `biases{n}` です。
+    ///
+    /// # Args
+    /// * `path` - 保存先のパス
+    pub fn save_weights_npz<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut arrays = vec![];
+        for (i, l) in self.layer_groups().iter().enumerate() {
+            if let Some(w) = &l.weights {
+                arrays.push((format!("weights{}", i), NpyArray::from_vec(w.clone())));
+            }
+            if let Some(b) = &l.biases {
+                arrays.push((format!("biases{}", i), NpyArray::from_vec(b.clone())));
+            }
+        }
+        let arrays: Vec<_> = arrays.iter().map(|(name, a)| (name.as_str(), a)).collect();
+        npy::save_npz(path, &arrays)
+    }
+
+    /// `.npz` ファイルから重みとバイアスを読み込みます。
+    ///
+    /// 配列の名前は `weights{n}` と `biases{n}` で、値はQ8.8の固定小数点数 (`<i2`) である必要があります。
+    /// 形状が合わない場合はエラーを返し、読み込まれている重みとバイアスは変更しません。
+    ///
+    /// # Args
+    /// * `path` - 読み込むファイルのパス
+    pub fn read_weights_npz<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let mut layers = layer_groups();
+        read_npz_params(path, &mut layers)?;
+        self.replace_params(layers)
+    }
+
+    /// 2つ目のIPを使っている場合に、26×26のヘッドの重みとバイアスを読み込み済みのものに置き換えます。
+    ///
+    /// 置き換えは次に依頼する処理より前に行われます。
+    fn update_second_params(&self) -> Result<()> {
+        let Some(second) = &self.second_pipeline else {
+            return Ok(());
+        };
+        let params = SECOND_HEAD
+            .map(|i| {
+                let l = &self.layer_groups()[i];
+                (l.weights.clone(), l.biases.clone())
+            })
+            .collect();
+        second.send(Job::SetParams(params))
+    }
+
+    /// ゼロのテンソルで1回推論し、キャッシュやDMAのマッピングを準備します。
+    ///
+    /// 全てのIP (2つ目のIPを含む) を1度動かすため、最初のフレームの処理時間が長く見えるのを防げます。
+    /// フックやフレーム番号には影響しません。
+    ///
+    /// # Return
+    /// * 推論にかかった時間
+    pub fn warmup(&mut self) -> Result<Duration> {
+        let input_data = vec![0; self.layer_groups()[0].input_size as usize];
+        let start = Instant::now();
+        self.start_processing(&input_data)?;
+        let elapsed = start.elapsed();
+        info!("Warmup inference took {:?}", elapsed);
+        Ok(elapsed)
+    }
+
+    /// 1つのレイヤーグループだけを処理し、出力を返します。
+    ///
+    /// どのレイヤーグループでデータが壊れるかを切り分けるためのデバッグ用の関数です。
+    ///
+    /// # Args
+    /// * `grp_idx` - 処理するレイヤーグループのインデックス (0-13)
+    /// * `input` - レイヤーグループの入力
+    ///
+    /// # Return
+    /// * レイヤーグループの出力
+    pub fn run_layer(&mut self, grp_idx: usize, input: &[i16]) -> Result<Vec<i16>> {
+        self.backend.hw_mut()?.run_layer(grp_idx, input.to_vec())
+    }
+
+    /// 連続したレイヤーグループを順に処理し、それぞれの出力を返します。
+    ///
+    /// レイヤーグループ11と12は範囲外のレイヤーグループ (8と4) の出力を使うため、
+    /// それらを範囲に含める必要があります。
+    ///
+    /// # Args
+    /// * `range` - 処理するレイヤーグループの範囲 (例: `0..=8`)
+    /// * `input` - 範囲の最初のレイヤーグループの入力
+    ///
+    /// # Return
+    /// * 範囲の各レイヤーグループの出力
+    pub fn run_layers(
+        &mut self,
+        range: std::ops::RangeInclusive<usize>,
+        input: &[i16],
+    ) -> Result<Vec<Vec<i16>>> {
+        if range.is_empty() || *range.end() >= self.layer_groups().len() {
+            bail!("Invalid layer group range: {:?}", range);
+        }
+        let first = *range.start();
+        let mut outputs: Vec<Vec<i16>> = vec![];
+        for grp_idx in range {
+            let input = if grp_idx == first {
+                input.to_vec()
+            } else {
+                let mut concat = vec![];
+                for &src in layer_sources(grp_idx) {
+                    let output = src
+                        .checked_sub(first)
+                        .and_then(|i| outputs.get(i))
+                        .with_context(|| {
+                            format!(
+                                "Layer group {} needs the output of layer group {}. Include it in the range",
+                                grp_idx, src
+                            )
+                        })?;
+                    concat.extend_from_slice(output);
+                }
+                concat
+            };
+            outputs.push(self.backend.hw_mut()?.run_layer(grp_idx, input)?);
+        }
+        Ok(outputs)
+    }
+
+    /// 指定したレイヤーグループまで処理し、その出力を特徴マップとして返します。
+    ///
+    /// 検出は行わないため、バックボーンを分類や再識別の埋め込みの抽出に使えます。
+    /// フックやフレーム番号には影響しません。
+    ///
+    /// # Args
+    /// * `input_data` - 入力データ
+    /// * `grp_idx` - 出力を取り出すレイヤーグループのインデックス (0-13)
+    ///
+    /// # Return
+    /// * 浮動小数点数に戻した特徴マップ
+    pub fn extract_features(&mut self, input_data: &[i16], grp_idx: usize) -> Result<FeatureMap> {
+        let output = self
+            .run_layers(0..=grp_idx, input_data)?
+            .pop()
+            .context("No layer group was processed")?;
+        let l = &self.layer_groups()[grp_idx];
+        FeatureMap::from_output(&output, l.output_width as usize, l.output_height as usize)
+    }
+
+    /// 画像を前処理し、指定したレイヤーグループの出力を特徴マップとして返します。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    /// * `rotate_angle` - 回転角度
+    /// * `grp_idx` - 出力を取り出すレイヤーグループのインデックス (0-13)
+    ///
+    /// # Return
+    /// * 浮動小数点数に戻した特徴マップ
+    pub fn extract_features_with_img_proc(
+        &mut self,
+        img: &DynamicImage,
+        rotate_angle: u32,
+        grp_idx: usize,
+    ) -> Result<FeatureMap> {
+        let img_size = self.layer_groups()[0].input_width;
+        let img = self.enhance(img);
+        let input_data = Letterbox::new(rotate_angle).prepare(&img, img_size);
+        self.extract_features(&input_data, grp_idx)
     }
 
     /// 入力データの処理を開始します。
@@ -105,33 +1402,76 @@ impl YoloV3Tiny {
     /// # Return
     /// * YOLOの出力 (scale1, scale2)
     pub fn start_processing(&mut self, input_data: &[i16]) -> Result<(Vec<i16>, Vec<i16>)> {
-        self.yc.layer_groups[0].inputs = Some(Vec::from(input_data));
+        // 処理を始める前に依頼された中断は無視する
+        self.backend.cancel_handle().clear();
+        let yc = match &mut self.backend {
+            Backend::Hardware(yc) => yc,
+            Backend::Sim { sim, .. } => {
+                sim.set_layout(self.layout);
+                return sim.start_processing(input_data);
+            }
+        };
+        yc.layer_groups[0].inputs = Some(Vec::from(input_data));
 
+        // 飽和を監視している場合は、全てのレイヤーグループを1つ目のIPで処理する
+        let monitoring = yc.saturation_stats().is_some();
+        // 2つ目のIPで処理するレイヤーグループのレジスタは1つ目のIPに設定しない
+        let skipped: Vec<usize> = match &self.second_pipeline {
+            Some(_) if !monitoring => SECOND_HEAD.collect(),
+            _ => vec![],
+        };
+        yc.set_skipped_groups(&skipped);
+        let mut second_head = false;
         for grp_idx in 0..=13 {
-            self.yc.start_layer_processing(grp_idx)?;
+            if grp_idx == 9 && !monitoring {
+                if let Some(second) = &self.second_pipeline {
+                    // 26×26のヘッドは2つ目のIPで並列に処理する
+                    let output8 = yc.layer_groups[8]
+                        .outputs
+                        .take()
+                        .context("layer_groups[8].outputs not set")?;
+                    let output4 = yc.layer_groups[4]
+                        .outputs
+                        .take()
+                        .context("layer_groups[4].outputs not set")?;
+                    second.submit(output8, output4)?;
+                    second_head = true;
+                }
+            }
+            if second_head && SECOND_HEAD.contains(&grp_idx) {
+                continue;
+            }
+
+            if let Err(e) = yc.start_layer_processing(grp_idx) {
+                if second_head {
+                    // 次の処理で古い結果を受け取らないよう、2つ目のIPの処理の終了を待つ
+                    if let Some(second) = &self.second_pipeline {
+                        let _ = second.recv();
+                    }
+                }
+                return Err(e);
+            }
 
             if grp_idx == 4 || grp_idx == 8 {
                 // あとで使うため，cloneする
-                self.yc.layer_groups[grp_idx + 1].inputs =
-                    self.yc.layer_groups[grp_idx].outputs.clone();
+                yc.layer_groups[grp_idx + 1].inputs = yc.layer_groups[grp_idx].outputs.clone();
             } else if grp_idx == 10 {
                 // レイヤ11の入力はレイヤ8
-                self.yc.layer_groups[11].inputs = self.yc.layer_groups[8].outputs.take();
+                yc.layer_groups[11].inputs = yc.layer_groups[8].outputs.take();
             } else if grp_idx != 13 {
                 // あとで使わないものはmoveして高速化
-                self.yc.layer_groups[grp_idx + 1].inputs =
-                    self.yc.layer_groups[grp_idx].outputs.take();
+                yc.layer_groups[grp_idx + 1].inputs = yc.layer_groups[grp_idx].outputs.take();
             }
 
             if grp_idx == 11 {
                 // レイヤ12の入力はレイヤ11とレイヤ4をconcatしたもの
                 // レイヤ11のデータはすでに上でmoveしているので，レイヤ4のデータを結合してあげる
-                let output4 = self.yc.layer_groups[4]
+                let output4 = yc.layer_groups[4]
                     .outputs
                     .take()
                     .context("layer_groups[4].outputs not set")?;
 
-                match &mut self.yc.layer_groups[12].inputs {
+                match &mut yc.layer_groups[12].inputs {
                     Some(inputs) => inputs.extend(output4),
                     None => {
                         bail!("layer_groups[12].inputs not set");
@@ -140,12 +1480,18 @@ impl YoloV3Tiny {
             }
         }
 
+        if second_head {
+            if let Some(second) = &self.second_pipeline {
+                yc.layer_groups[13].outputs = Some(second.recv()?);
+            }
+        }
+
         // CNNの結果たち
-        let output10 = self.yc.layer_groups[10]
+        let output10 = yc.layer_groups[10]
             .outputs
             .take()
             .context("layer_groups[10].inputs not set")?;
-        let output13 = self.yc.layer_groups[13]
+        let output13 = yc.layer_groups[13]
             .outputs
             .take()
             .context("layer_groups[13].inputs not set")?;
@@ -160,20 +1506,162 @@ impl YoloV3Tiny {
     /// * `rotate_angle` - 回転角度
     ///
     /// # Return
+    /// * YOLOの入力データの座標系の物体検出結果
+    pub fn start(&mut self, input_data: &[i16]) -> Result<Vec<DetectionData<LetterboxSpace>>> {
+        let pp = self.infer(input_data)?;
+        // 前処理を行っていないため、入力データの座標系をそのまま最終的な座標系とみなす
+        let data: Vec<DetectionData> = pp.iter().map(|d| d.assume_space()).collect();
+        self.hooks.final_detections(self.frame_id, &data);
+        Ok(pp)
+    }
+
+    /// 新しいフレームとしてYOLOの処理と後処理を行い、フレーム開始時と後処理直後のコールバックを呼び出します。
+    ///
+    /// # Args
+    /// * `input_data` - 入力データ
+    ///
+    /// # Return
+    /// * 物体検出結果
+    fn infer(&mut self, input_data: &[i16]) -> Result<Vec<DetectionData<LetterboxSpace>>> {
+        self.begin_frame()?;
+        self.infer_in_frame(input_data)
+    }
+
+    /// フレーム番号を進め、フレーム開始時のコールバックを呼び出します。
+    ///
+    /// # Return
+    /// * Result。`shutdown` の後はフレームを始めずにエラーを返します
+    fn begin_frame(&mut self) -> Result<()> {
+        if let Some(yc) = self.backend.hardware() {
+            yc.ensure_running()?;
+        }
+        self.frame_id += 1;
+        self.hooks.frame_start(self.frame_id);
+        Ok(())
+    }
+
+    /// 現在のフレームの中でYOLOの処理と後処理を行い、後処理直後のコールバックを呼び出します。
+    ///
+    /// TTAのように1フレームで複数回推論する場合に使います。
+    ///
+    /// # Args
+    /// * `input_data` - 入力データ
+    ///
+    /// # Return
     /// * 物体検出結果
-    pub fn start(&mut self, input_data: &[i16]) -> Result<Vec<DetectionData>> {
+    fn infer_in_frame(&mut self, input_data: &[i16]) -> Result<Vec<DetectionData<LetterboxSpace>>> {
+        let (yolo_out_0, yolo_out_1) = self.start_processing(input_data)?;
+
+        let pp = self.post_process(&yolo_out_0, &yolo_out_1);
+        let params = self.postprocess_params();
+        if let Some(session) = &mut self.session {
+            session.record(
+                self.frame_id,
+                &params,
+                input_data,
+                &yolo_out_0,
+                &yolo_out_1,
+                &pp,
+            );
+        }
+        self.hooks.raw_detections(self.frame_id, &pp);
+        Ok(pp)
+    }
+
+    /// 設定した方法でYOLOの出力を後処理します。
+    ///
+    /// # Args
+    /// * `yolo_out_0` - 13×13のYOLO層の出力
+    /// * `yolo_out_1` - 26×26のYOLO層の出力
+    ///
+    /// # Return
+    /// * YOLOの入力データの座標系の物体検出結果
+    fn post_process(
+        &mut self,
+        yolo_out_0: &[i16],
+        yolo_out_1: &[i16],
+    ) -> Vec<DetectionData<LetterboxSpace>> {
+        let post_process = if self.fixed_point_postprocess {
+            postprocess::post_process_fixed_with_stats
+        } else {
+            postprocess::post_process_with_stats
+        };
+        let (pp, stats) = post_process(
+            yolo_out_0,
+            yolo_out_1,
+            self.cls_num,
+            &self.layout,
+            self.obj_threshold,
+            self.nms_threshold,
+        );
+        if self.postprocess_stats_en {
+            debug!("Frame {} post-process: {}", self.frame_id, stats);
+            self.postprocess_stats = Some(stats);
+        }
+        pp
+    }
+
+    /// 入力データの処理を開始し、上位k個のクラス候補付きの検出結果を返します。
+    ///
+    /// # Args
+    /// * `input_data` - 入力データ
+    /// * `k` - 保持するクラス候補の数
+    ///
+    /// # Return
+    /// * YOLOの入力データの座標系のクラス候補付きの物体検出結果
+    pub fn start_top_k(
+        &mut self,
+        input_data: &[i16],
+        k: usize,
+    ) -> Result<Vec<DetectionDataExt<LetterboxSpace>>> {
+        self.begin_frame()?;
+
         let (yolo_out_0, yolo_out_1) = self.start_processing(input_data)?;
 
-        let pp = postprocess::post_process(
+        let pp = postprocess::post_process_top_k(
             &yolo_out_0,
             &yolo_out_1,
             self.cls_num,
+            &self.layout,
             self.obj_threshold,
             self.nms_threshold,
+            k,
         );
+        let data: Vec<DetectionData<LetterboxSpace>> = pp.iter().map(|d| d.data).collect();
+        self.hooks.raw_detections(self.frame_id, &data);
+        // 前処理を行っていないため、入力データの座標系をそのまま最終的な座標系とみなす
+        let data: Vec<DetectionData> = data.iter().map(|d| d.assume_space()).collect();
+        self.hooks.final_detections(self.frame_id, &data);
         Ok(pp)
     }
 
+    /// 画像の処理を開始し、上位k個のクラス候補付きの検出結果を返します。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    /// * `rotate_angle` - 回転角度
+    /// * `k` - 保持するクラス候補の数
+    ///
+    /// # Return
+    /// * クラス候補付きの物体検出結果
+    pub fn start_with_img_proc_top_k(
+        &mut self,
+        img: &DynamicImage,
+        rotate_angle: u32,
+        k: usize,
+    ) -> Result<Vec<DetectionDataExt>> {
+        let img_size = self.layer_groups()[0].input_width;
+        let input_data = img_proc::letterbox(&self.enhance(img), img_size, rotate_angle);
+
+        let objs_rev = self
+            .start_top_k(&input_data, k)?
+            .iter()
+            .map(|d| d.reverse_transform(img.width(), img.height(), rotate_angle, false, img_size))
+            .collect();
+
+        Ok(objs_rev)
+    }
+
     /// 画像の処理を開始します。
     ///
     /// # Args
@@ -187,18 +1675,345 @@ impl YoloV3Tiny {
         img: &DynamicImage,
         rotate_angle: u32,
     ) -> Result<Vec<DetectionData>> {
-        let img_size = self.yc.layer_groups[0].input_width;
-        let input_data = img_proc::letterbox(img, img_size, rotate_angle);
+        self.start_with_preprocessor(img, &Letterbox::new(rotate_angle))
+    }
+
+    /// 画像の処理を行い、検出結果をフレーム番号や処理時間と合わせて返します。
+    ///
+    /// 検出結果は `start_with_img_proc` と同じです。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    /// * `rotate_angle` - 回転角度
+    ///
+    /// # Return
+    /// * 1フレームの検出結果とメタデータ
+    pub fn detect_frame(&mut self, img: &DynamicImage, rotate_angle: u32) -> Result<FrameResult> {
+        let timestamp = SystemTime::now();
+        let start = Instant::now();
+        let detections = self.start_with_img_proc(img, rotate_angle)?;
+        Ok(FrameResult {
+            frame_id: self.frame_id,
+            timestamp,
+            detections,
+            latency: start.elapsed(),
+            input_size: (img.width(), img.height()),
+        })
+    }
+
+    /// 画像の処理を開始し、座標を [0, 1] の範囲に正規化した検出結果を返します。
+    ///
+    /// 座標は回転後の画像の幅と高さで正規化されます。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    /// * `rotate_angle` - 回転角度
+    ///
+    /// # Return
+    /// * 正規化した物体検出結果
+    pub fn start_with_img_proc_normalized(
+        &mut self,
+        img: &DynamicImage,
+        rotate_angle: u32,
+    ) -> Result<Vec<DetectionData<NormalizedSpace>>> {
+        let (width, height) = img_proc::rotated_size(img, rotate_angle, true);
+        let objs = self.start_with_img_proc(img, rotate_angle)?;
+        Ok(objs.iter().map(|d| d.normalize(width, height)).collect())
+    }
+
+    /// 反転・回転した画像でも推論を行い、検出結果を統合します (Test-Time Augmentation)。
+    ///
+    /// 変換の数だけ推論を行うためFPSは低下しますが、検出漏れを減らすことができます。
+    /// 各変換の検出結果は回転後の画像の座標系に戻した後、集合をまたいだNMS (`nms::nms_cross_sets`) で統合されるため、
+    /// 複数の変換で検出された物体はコンフィデンスの高い方だけが残ります。
+    /// 全ての変換の推論を1フレームとして扱い、フレーム開始時と最終的な検出結果のコールバックは1回だけ呼び出します。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    /// * `rotate_angle` - 回転角度
+    /// * `augmentations` - 推論に使う画像の変換
+    ///
+    /// # Return
+    /// * 統合した物体検出結果
+    pub fn start_tta(
+        &mut self,
+        img: &DynamicImage,
+        rotate_angle: u32,
+        augmentations: &[Augmentation],
+    ) -> Result<Vec<DetectionData>> {
+        let rotated = img_proc::rotate_img(img, rotate_angle);
+        // 全ての変換に同じ補正を使うため、補正は変換前に1度だけ行う
+        let rotated = self.enhance(&rotated).into_owned();
+        let letterbox = Letterbox::new(0);
+        let img_size = self.layer_groups()[0].input_width;
+
+        self.begin_frame()?;
+        let mut sets = vec![];
+        for aug in augmentations {
+            let augmented = aug.apply(&rotated);
+            let input_data = letterbox.prepare(&augmented, img_size);
+            let objs: Vec<DetectionData> = self
+                .infer_in_frame(&input_data)?
+                .iter()
+                .map(|d| {
+                    letterbox.inverse_transform(d, augmented.width(), augmented.height(), img_size)
+                })
+                .map(|d| aug.invert(&d, rotated.width(), rotated.height()))
+                .collect();
+            sets.push(objs);
+        }
+
+        let sets: Vec<&[DetectionData]> = sets.iter().map(Vec::as_slice).collect();
+        let fused = nms::nms_cross_sets(&sets, self.nms_threshold);
+        if self.crop_saver.is_some() {
+            self.save_crops(&rotated.to_rgb8(), &fused, "accepted");
+        }
+        self.hooks.final_detections(self.frame_id, &fused);
+        Ok(fused)
+    }
+
+    /// 同じ画像を0度と90度に回転させて推論し、2つの検出結果を元の画像の座標系で統合します。
+    ///
+    /// 推論を2回行うためFPSは低下しますが、縦長の物体など一方の向きでは縮小されて検出できない物体を
+    /// もう一方の向きの結果から補うことができます。統合には集合をまたいだNMS (`nms::nms_cross_sets`) を使うため、
+    /// 両方の向きで検出された物体はコンフィデンスの高い方だけが残ります。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    ///
+    /// # Return
+    /// * 元の画像の座標系の統合した物体検出結果
+    pub fn start_rotated_pair(&mut self, img: &DynamicImage) -> Result<Vec<DetectionData>> {
+        self.start_tta(img, 0, &[Augmentation::Identity, Augmentation::Rotate90])
+    }
+
+    /// 最大4台のカメラの画像を2×2のモザイクに並べて1回だけ推論し、検出結果をカメラごとに分けます。
+    ///
+    /// 各画像の解像度は1/2以下になるため小さな物体は検出しにくくなりますが、複数の視点を1回の推論の時間で監視できます。
+    /// 検出結果の座標系がカメラごとに異なるため、最終的な検出結果のコールバックは呼び出しません。
+    ///
+    /// # Args
+    /// * `imgs` - カメラごとの画像 (4枚以下)。タイルは左上から行優先で割り当てます
+    ///
+    /// # Return
+    /// * カメラごとの、そのカメラの画像の座標系の物体検出結果
+    pub fn start_mosaic(&mut self, imgs: &[&DynamicImage]) -> Result<Vec<Vec<DetectionData>>> {
+        let mosaic = Mosaic::from_images(imgs)?;
+        let img_size = self.layer_groups()[0].input_width;
+        // カメラごとに明るさが異なるため、タイルに並べる前に画像ごとに補正する
+        let enhanced: Vec<_> = imgs.iter().map(|img| self.enhance(img)).collect();
+        let enhanced: Vec<&DynamicImage> = enhanced.iter().map(|img| img.as_ref()).collect();
+        let input_data = mosaic.prepare(&enhanced, img_size)?;
+
+        let mut objs = vec![vec![]; mosaic.cameras()];
+        for d in self.infer(&input_data)? {
+            if let Some((camera, d)) = mosaic.demultiplex(&d, img_size) {
+                objs[camera].push(d);
+            }
+        }
+        Ok(objs)
+    }
+
+    /// 任意の前処理を使って画像の処理を開始します。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    /// * `preprocessor` - 入力データの生成と座標の逆変換を行う前処理
+    ///
+    /// # Return
+    /// * 元の画像の座標系に変換した物体検出結果
+    pub fn start_with_preprocessor<P: Preprocessor + ?Sized>(
+        &mut self,
+        img: &DynamicImage,
+        preprocessor: &P,
+    ) -> Result<Vec<DetectionData>> {
+        let img_size = self.layer_groups()[0].input_width;
+        let enhanced = self.enhance(img);
+        let input_data = preprocessor.prepare(&enhanced, img_size);
+        let objs_rev = self.run_prepared(&input_data, preprocessor, img.width(), img.height())?;
+        if self.crop_saver.is_some() {
+            // 検出結果は回転後などの座標系にあるため、同じ座標系に変換した補正後の画像から切り抜く
+            let crop_img = preprocessor.output_image(&enhanced).to_rgb8();
+            self.save_crops(&crop_img, &objs_rev, "accepted");
+        }
+        self.hooks.final_detections(self.frame_id, &objs_rev);
+        Ok(objs_rev)
+    }
+
+    /// 検出結果の切り抜き画像を保存します。
+    ///
+    /// 保存に失敗してもフレームの検出結果は失わないよう、警告を出力して続行します。
+    ///
+    /// # Args
+    /// * `img` - 検出結果の座標系に対応する画像
+    /// * `detections` - 検出結果
+    /// * `label` - 保存先のサブディレクトリ名
+    fn save_crops(&self, img: &RgbImage, detections: &[DetectionData], label: &str) {
+        if let Some(saver) = &self.crop_saver {
+            if let Err(e) = saver.save(img, self.frame_id, detections, label) {
+                warn!("Can't save crops of frame {}: {:#}", self.frame_id, e);
+            }
+        }
+    }
 
+    /// 前処理済みの入力データの処理を行い、検出結果を元の画像の座標系に戻します。
+    ///
+    /// # Args
+    /// * `input_data` - `preprocessor` で生成した入力データ
+    /// * `preprocessor` - 座標の逆変換を行う前処理
+    /// * `width` - 元の画像の幅
+    /// * `height` - 元の画像の高さ
+    ///
+    /// # Return
+    /// * 元の画像の座標系に変換した物体検出結果
+    fn run_prepared<P: Preprocessor + ?Sized>(
+        &mut self,
+        input_data: &[i16],
+        preprocessor: &P,
+        width: u32,
+        height: u32,
+    ) -> Result<Vec<DetectionData>> {
+        let img_size = self.layer_groups()[0].input_width;
         let objs_rev = self
-            .start(&input_data)?
+            .infer(input_data)?
             .iter()
-            .map(|d| d.reverse_transform(img.width(), img.height(), rotate_angle, false))
+            .map(|d| preprocessor.inverse_transform(d, width, height, img_size))
             .collect();
 
         Ok(objs_rev)
     }
 
+    /// 画像の処理を行います。フックやフレーム番号、デバッグ出力には影響しません。
+    ///
+    /// 2段階目の再推論など、1フレームの中で補助的に推論するときに使います。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    ///
+    /// # Return
+    /// * 元の画像の座標系に変換した物体検出結果
+    pub(crate) fn detect_without_hooks(
+        &mut self,
+        img: &DynamicImage,
+    ) -> Result<Vec<DetectionData>> {
+        let letterbox = Letterbox::new(0);
+        let img_size = self.layer_groups()[0].input_width;
+        let input_data = letterbox.prepare(&self.enhance(img), img_size);
+        let (yolo_out_0, yolo_out_1) = self.start_processing(&input_data)?;
+        Ok(self
+            .post_process(&yolo_out_0, &yolo_out_1)
+            .iter()
+            .map(|d| letterbox.inverse_transform(d, img.width(), img.height(), img_size))
+            .collect())
+    }
+
+    /// 複数の画像の処理を行います。
+    ///
+    /// 次の画像の前処理を別スレッドで行い、YOLOの処理と並行させます。
+    ///
+    /// # Args
+    /// * `imgs` - 入力画像
+    /// * `rotate_angle` - 回転角度
+    ///
+    /// # Return
+    /// * 画像ごとの物体検出結果
+    pub fn start_batch(
+        &mut self,
+        imgs: &[DynamicImage],
+        rotate_angle: u32,
+    ) -> Result<Vec<Vec<DetectionData>>> {
+        let letterbox = Letterbox::new(rotate_angle);
+        let img_size = self.layer_groups()[0].input_width;
+        let enhancements = self.enhancements.clone();
+        let tone_mapping = self.tone_mapping;
+        let save_crops = self.crop_saver.is_some();
+
+        thread::scope(|s| {
+            let (input_tx, input_rx) = mpsc::sync_channel(1);
+            s.spawn(move || {
+                for img in imgs {
+                    let img = enhance::enhance(img, tone_mapping, &enhancements);
+                    let crop_img = save_crops.then(|| letterbox.output_image(&img).to_rgb8());
+                    if input_tx
+                        .send((letterbox.prepare(&img, img_size), crop_img))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+
+            imgs.iter()
+                .zip(input_rx.iter())
+                .map(|(img, (input_data, crop_img))| {
+                    let objs =
+                        self.run_prepared(&input_data, &letterbox, img.width(), img.height())?;
+                    if let Some(crop_img) = &crop_img {
+                        self.save_crops(crop_img, &objs, "accepted");
+                    }
+                    self.hooks.final_detections(self.frame_id, &objs);
+                    Ok(objs)
+                })
+                .collect()
+        })
+    }
+
+    /// ディレクトリ内の全ての画像の処理を行います。
+    ///
+    /// 次の画像の読み込みと前処理を別スレッドで行い、YOLOの処理と並行させます。
+    ///
+    /// # Args
+    /// * `dir` - 画像のあるディレクトリ
+    /// * `rotate_angle` - 回転角度
+    ///
+    /// # Return
+    /// * ファイル名順に並べた (画像のパス, 物体検出結果)
+    pub fn start_dir<P: AsRef<Path>>(
+        &mut self,
+        dir: P,
+        rotate_angle: u32,
+    ) -> Result<Vec<(PathBuf, Vec<DetectionData>)>> {
+        let paths = img_proc::list_images(dir)?;
+        let letterbox = Letterbox::new(rotate_angle);
+        let img_size = self.layer_groups()[0].input_width;
+        let enhancements = self.enhancements.clone();
+        let tone_mapping = self.tone_mapping;
+        let save_crops = self.crop_saver.is_some();
+
+        thread::scope(|s| {
+            let (input_tx, input_rx) = mpsc::sync_channel(1);
+            s.spawn(move || {
+                for path in paths {
+                    let input = image::open(&path)
+                        .with_context(|| format!("Can't open {}", path.display()))
+                        .map(|img| {
+                            let img = enhance::enhance(&img, tone_mapping, &enhancements);
+                            let input_data = letterbox.prepare(&img, img_size);
+                            let crop_img =
+                                save_crops.then(|| letterbox.output_image(&img).to_rgb8());
+                            (path, img.width(), img.height(), input_data, crop_img)
+                        });
+                    if input_tx.send(input).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            input_rx
+                .iter()
+                .map(|input| {
+                    let (path, width, height, input_data, crop_img) = input?;
+                    let objs = self.run_prepared(&input_data, &letterbox, width, height)?;
+                    if let Some(crop_img) = &crop_img {
+                        self.save_crops(crop_img, &objs, "accepted");
+                    }
+                    self.hooks.final_detections(self.frame_id, &objs);
+                    Ok((path, objs))
+                })
+                .collect()
+        })
+    }
+
     /// 画像の処理を開始します。
     ///
     /// # Args
@@ -207,7 +2022,7 @@ impl YoloV3Tiny {
     /// * `rotate_en` - 画像を回転させるか。事前に回転させている場合はfalseを指定してください
     ///
     /// # Return
-    /// * 物体検出結果
+    /// * 回転後の元の画像の座標系の物体検出結果。拡大した領域内の検出結果は切り取り位置に戻します
     pub fn start_with_patial_enlargement(
         &mut self,
         img: &DynamicImage,
@@ -219,123 +2034,104 @@ impl YoloV3Tiny {
         crop_h: u32,
         yolo_en: bool,
     ) -> Result<Vec<DetectionData>> {
-        let img_size = self.yc.layer_groups[0].input_width;
-        let input_data = img_proc::letterbox_with_patial_enlargement(
-            img,
-            img_size,
-            rotate_angle,
-            rotate_en,
-            crop_x,
-            crop_y,
-            crop_w,
-            crop_h,
-        );
-
-
-        let mut objs_rev : Vec<_> = self
-            .start(&input_data)?
-            .iter()
-            .map(|d| d.reverse_transform(img.width(), img.height(), rotate_angle, true))
-            .collect::<Vec<_>>();
-
+        let preprocessor =
+            PatialEnlargement::new(rotate_angle, rotate_en, crop_x, crop_y, crop_w, crop_h);
+        // バリデータにも補正後の画像を渡すため、補正は1度だけ行う
+        let img = self.enhance(img);
+        let img_size = self.layer_groups()[0].input_width;
+        let input_data = preprocessor.prepare(&img, img_size);
+        let mut objs_rev =
+            self.run_prepared(&input_data, &preprocessor, img.width(), img.height())?;
 
-        if !yolo_en {
+        if !yolo_en || self.crop_saver.is_some() {
             let letterbox_img = img_proc::letterbox_img_with_patial_enlargement(
-                img, 
-                rotate_angle, 
-                rotate_en, 
+                &img,
+                rotate_angle,
+                rotate_en,
                 crop_x,
                 crop_y,
                 crop_w,
-                crop_h
+                crop_h,
             );
+            let candidates = objs_rev.clone();
 
-            let letterbox_vec = letterbox_img.pixels().collect::<Vec<_>>();
-
-            for d_data in objs_rev.iter_mut() {
-                if d_data.class <= 2 {
-                    let trim_w: f32 = (d_data.x2 - d_data.x1) * self.trim_rate;
-                    let bbox = Region::new((d_data.x1 + trim_w, d_data.y1), (d_data.x2 - trim_w, d_data.y2))?;
-                    let region_w = bbox.width() / self.n_regions;
-                    let region_h = bbox.height();
-
-                    let mut regions = Vec::new();
-                    for idx in 0..self.n_regions {
-                        let start_x = bbox.start.0 + idx * region_w;
-                        let start_y = bbox.start.1;
-                        let end_x = start_x + region_w;
-                        let end_y = start_y + region_h;
-                        let new_region = Region::new((start_x as f32, start_y as f32), (end_x as f32, end_y as f32))?;
-                        regions.push(new_region);
-                    }
-
-                    let mut x = bbox.start.0;
-                    let mut y = bbox.start.1;
-
-                    while y < bbox.end.1.into() {
-                        let pixel_idx = (y * letterbox_img.width() + x) as usize;
-
-                        let rgb = color_space::Rgb::new(letterbox_vec[pixel_idx][0] as f64,
-                                                        letterbox_vec[pixel_idx][1] as f64,
-                                                        letterbox_vec[pixel_idx][2] as f64);
-                        let hsv = color_space::Hsv::from(rgb);
-                        for region in regions.iter_mut() {
-                            if region.is_in((x, y)) { region.add_brightness(hsv.v) }
-                        }
+            if !yolo_en {
+                if self.debug_sink.is_enabled() {
+                    self.debug_sink.save_image("letterbox", &letterbox_img)?;
+                    self.debug_sink
+                        .log(&format!("before validation: {:?}", objs_rev))?;
+                }
 
-                        x = x + 1;
-                        if x == bbox.end.0 { x = bbox.start.0; y = y + 1;}
-                    }
+                for validator in self.validators.iter_mut() {
+                    validator.validate(&letterbox_img, &mut objs_rev)?;
+                }
 
-                    let class = regions.iter().enumerate()
-                        .max_by(|(_, r1), (_, r2)| r1.total_brightness.total_cmp(&r2.total_brightness))
-                        .map(|(idx, _)|{
-                            if idx == 0 { 2 }
-                            else if idx == (self.n_regions - 1) as usize { 0 }
-                            else { 1 }
-                        })
-                        .unwrap();
-                    d_data.class = class;
+                if self.debug_sink.is_enabled() {
+                    self.debug_sink
+                        .log(&format!("after validation: {:?}", objs_rev))?;
                 }
-                    
+            }
+
+            self.save_crops(&letterbox_img, &objs_rev, "accepted");
+            if self
+                .crop_saver
+                .as_ref()
+                .is_some_and(CropSaver::save_rejected)
+            {
+                // バリデータは残した信号機のクラスを補正するため、座標だけで残ったかを判定する
+                let rejected: Vec<DetectionData> = candidates
+                    .into_iter()
+                    .filter(|c| {
+                        !objs_rev
+                            .iter()
+                            .any(|d| (d.x1, d.y1, d.x2, d.y2) == (c.x1, c.y1, c.x2, c.y2))
+                    })
+                    .collect();
+                self.save_crops(&letterbox_img, &rejected, "rejected");
             }
         }
+        self.hooks.final_detections(self.frame_id, &objs_rev);
         Ok(objs_rev)
     }
-}
-
-pub struct Region {
-    start: (u32, u32),
-    end: (u32, u32),
-    total_brightness: f64
-}
-
-impl Region {
-    pub fn new(s : (f32, f32), e : (f32, f32)) -> Result<Self> {
-        let values = [s.0, s.1, e.0, e.1];
-        ensure!(values.iter().all(|f| f.is_sign_positive()), "Coordinates must be positive");
-        let start = (s.0.floor() as u32, s.1.floor() as u32);
-        let end = (e.0.floor() as u32, e.1.floor() as u32);
-        let total_brightness = 0.0;
-        Ok(Self { start, end, total_brightness} )
-    }
-
-    pub fn width(&self) -> u32 {
-        self.end.0.abs_diff(self.start.0)
-    }
-
-
-    pub fn height(&self) -> u32 {
-        self.end.1.abs_diff(self.start.1)
-    }
 
-    pub fn is_in(&self, p: (u32, u32)) -> bool {
-        self.start.0 < p.0 && self.start.1 < p.1 && self.end.0 > p.0 && self.end.1 > p.1
-    }
+    /// 前フレームの検出結果から自動的に決定した領域を部分拡大し、画像の処理を開始します。
+    ///
+    /// 処理後、検出結果から次のフレームの切り取り位置を更新します。
+    ///
+    /// # Args
+    /// * `img` - 入力画像
+    /// * `zoom` - 切り取り位置を管理する `AutoZoom`
+    /// * `rotate_angle` - 回転角度
+    /// * `rotate_en` - 画像を回転させるか。事前に回転させている場合はfalseを指定してください
+    /// * `yolo_en` - falseのときはバリデータを適用します
+    ///
+    /// # Return
+    /// * 物体検出結果
+    pub fn start_with_auto_zoom(
+        &mut self,
+        img: &DynamicImage,
+        zoom: &mut AutoZoom,
+        rotate_angle: u32,
+        rotate_en: bool,
+        yolo_en: bool,
+    ) -> Result<Vec<DetectionData>> {
+        let p = zoom.preprocessor(rotate_angle, rotate_en);
+        let objs_rev = self.start_with_patial_enlargement(
+            img,
+            rotate_angle,
+            rotate_en,
+            p.crop_x,
+            p.crop_y,
+            p.crop_w,
+            p.crop_h,
+            yolo_en,
+        )?;
 
-    pub fn add_brightness(&mut self, value : f64) {
-        self.total_brightness = self.total_brightness + value;
+        let (width, height) = match rotate_angle {
+            90 | 270 if rotate_en => (img.height(), img.width()),
+            _ => (img.width(), img.height()),
+        };
+        zoom.update(&objs_rev, width, height);
+        Ok(objs_rev)
     }
 }
-
-
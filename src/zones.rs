@@ -0,0 +1,257 @@
+//! 画像上の多角形の領域への物体の出入りを判定するモジュール
+//!
+//! 名前を付けた多角形の領域を登録し、追跡中の物体 (`tracker::Tracker`) が領域に入ったときと
+//! 出たときにイベントを発生させます。物体の位置はバウンディングボックスの下端の中央 (足元) で判定します。
+//!
+//! ```ignore
+//! let mut tracker = Tracker::new(0.3, 5);
+//! let mut monitor = ZoneMonitor::new();
+//! let mut zone = Zone::new("crosswalk", vec![(100., 300.), (500., 300.), (540., 420.), (60., 420.)])?;
+//! zone.set_classes(vec![0]);
+//! monitor.add_zone(zone)?;
+//!
+//! let detections = yolo.start_with_img_proc(&img, 0)?;
+//! for event in monitor.update(tracker.update(&detections)) {
+//!     println!("{}", event);
+//! }
+//! ```
+
+use std::collections::HashSet;
+use std::fmt;
+
+use anyhow::{ensure, Result};
+
+use crate::detection_result::DetectionData;
+use crate::tracker::TrackedDetection;
+
+/// 名前を付けた多角形の領域
+#[derive(Debug, Clone)]
+pub struct Zone {
+    name: String,
+    /// 頂点 (画像の座標系)
+    polygon: Vec<(f32, f32)>,
+    /// 対象のクラス。Noneの場合は全てのクラス
+    classes: Option<Vec<u8>>,
+}
+
+impl Zone {
+    /// 新しい `Zone` インスタンスを作成します。
+    ///
+    /// # Args
+    /// * `name` - 領域の名前
+    /// * `polygon` - 多角形の頂点 (画像の座標系)。時計回りと反時計回りのどちらでも構いません
+    ///
+    /// # Return
+    /// * 頂点が3つ未満の場合はエラー
+    pub fn new(name: &str, polygon: Vec<(f32, f32)>) -> Result<Self> {
+        ensure!(
+            polygon.len() >= 3,
+            "Zone {} needs at least 3 vertices, but {} are given",
+            name,
+            polygon.len()
+        );
+        Ok(Self {
+            name: name.to_string(),
+            polygon,
+            classes: None,
+        })
+    }
+
+    /// 対象のクラスを設定します。他のクラスの物体はイベントを発生させません。
+    pub fn set_classes(&mut self, classes: Vec<u8>) -> &mut Self {
+        self.classes = Some(classes);
+        self
+    }
+
+    /// 領域の名前を返します。
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 多角形の頂点を返します。
+    pub fn polygon(&self) -> &[(f32, f32)] {
+        &self.polygon
+    }
+
+    /// クラスが対象かを判定します。
+    pub fn accepts(&self, class: u8) -> bool {
+        self.classes.as_ref().is_none_or(|c| c.contains(&class))
+    }
+
+    /// 点が多角形の内側にあるかを判定します (交差数判定)。
+    ///
+    /// # Args
+    /// * `x` - x座標
+    /// * `y` - y座標
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        let mut inside = false;
+        let n = self.polygon.len();
+        for i in 0..n {
+            let (x1, y1) = self.polygon[i];
+            let (x2, y2) = self.polygon[(i + 1) % n];
+            if (y1 > y) != (y2 > y) && x < x1 + (y - y1) * (x2 - x1) / (y2 - y1) {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+
+    /// 検出結果が領域の内側にあるかを、バウンディングボックスの下端の中央で判定します。
+    pub fn contains_detection(&self, d: &DetectionData) -> bool {
+        let (cx, _) = d.center();
+        self.contains(cx, d.y2)
+    }
+}
+
+/// 領域への出入りの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneEventKind {
+    /// 領域に入った
+    Enter,
+    /// 領域から出た (追跡が終了した場合を含む)
+    Exit,
+}
+
+/// 領域への出入りのイベント
+#[derive(Debug, Clone)]
+pub struct ZoneEvent {
+    /// 領域の名前
+    pub zone: String,
+    /// 追跡ID
+    pub track_id: u64,
+    /// 出入りの種類
+    pub kind: ZoneEventKind,
+    /// イベントが発生したときの検出結果 (追跡が終了した場合は最後の検出結果)
+    pub detection: DetectionData,
+}
+
+impl fmt::Display for ZoneEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            ZoneEventKind::Enter => "entered",
+            ZoneEventKind::Exit => "exited",
+        };
+        write!(
+            f,
+            "Track {} (class {}) {} zone {}",
+            self.track_id, self.detection.class, kind, self.zone
+        )
+    }
+}
+
+/// 登録した領域ごとに、内側にいる追跡中の物体を管理する構造体
+#[derive(Default)]
+pub struct ZoneMonitor {
+    zones: Vec<Zone>,
+    /// 領域ごとの内側にいる物体 (追跡ID, 最後の検出結果)
+    occupants: Vec<Vec<(u64, DetectionData)>>,
+}
+
+impl ZoneMonitor {
+    /// 新しい `ZoneMonitor` インスタンスを作成します。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 領域を追加します。
+    ///
+    /// # Args
+    /// * `zone` - 領域
+    ///
+    /// # Return
+    /// * 同じ名前の領域が既にある場合はエラー
+    pub fn add_zone(&mut self, zone: Zone) -> Result<&mut Self> {
+        ensure!(
+            self.zones.iter().all(|z| z.name != zone.name),
+            "Zone {} already exists",
+            zone.name
+        );
+        self.zones.push(zone);
+        self.occupants.push(vec![]);
+        Ok(self)
+    }
+
+    /// 領域を削除します。内側にいた物体の退出イベントは発生しません。
+    ///
+    /// # Return
+    /// * 削除した領域。見つからない場合はNone
+    pub fn remove_zone(&mut self, name: &str) -> Option<Zone> {
+        let idx = self.zones.iter().position(|z| z.name == name)?;
+        self.occupants.remove(idx);
+        Some(self.zones.remove(idx))
+    }
+
+    /// 登録した領域を返します。
+    pub fn zones(&self) -> &[Zone] {
+        &self.zones
+    }
+
+    /// 領域の内側にいる物体の追跡IDを返します。
+    ///
+    /// # Return
+    /// * 追跡ID。領域が見つからない場合はNone
+    pub fn occupants(&self, name: &str) -> Option<Vec<u64>> {
+        let idx = self.zones.iter().position(|z| z.name == name)?;
+        Some(self.occupants[idx].iter().map(|(id, _)| *id).collect())
+    }
+
+    /// 内側にいる物体を全て忘れます。イベントは発生しません。
+    pub fn reset(&mut self) {
+        for o in &mut self.occupants {
+            o.clear();
+        }
+    }
+
+    /// 1フレーム分の追跡結果で領域への出入りを判定します。
+    ///
+    /// 追跡が続いている間に検出されなかった物体 (`missed` が1以上) は、最後の位置にいるものとみなします。
+    /// 追跡結果に含まれなくなった物体は、内側にいた領域から出たものとします。
+    ///
+    /// # Args
+    /// * `tracks` - 追跡中の全ての物体 (`Tracker::update` の戻り値)
+    ///
+    /// # Return
+    /// * 発生したイベント (領域の登録順)
+    pub fn update(&mut self, tracks: &[TrackedDetection]) -> Vec<ZoneEvent> {
+        let mut events = vec![];
+        for (zone, occupants) in self.zones.iter().zip(self.occupants.iter_mut()) {
+            let inside: Vec<&TrackedDetection> = tracks
+                .iter()
+                .filter(|t| zone.accepts(t.detection.class))
+                .filter(|t| zone.contains_detection(&t.detection))
+                .collect();
+            let inside_ids: HashSet<u64> = inside.iter().map(|t| t.track_id).collect();
+
+            for (track_id, last) in occupants.iter() {
+                if inside_ids.contains(track_id) {
+                    continue;
+                }
+                let detection = tracks
+                    .iter()
+                    .find(|t| t.track_id == *track_id)
+                    .map_or(*last, |t| t.detection);
+                events.push(ZoneEvent {
+                    zone: zone.name.clone(),
+                    track_id: *track_id,
+                    kind: ZoneEventKind::Exit,
+                    detection,
+                });
+            }
+
+            let known: HashSet<u64> = occupants.iter().map(|(id, _)| *id).collect();
+            for t in &inside {
+                if !known.contains(&t.track_id) {
+                    events.push(ZoneEvent {
+                        zone: zone.name.clone(),
+                        track_id: t.track_id,
+                        kind: ZoneEventKind::Enter,
+                        detection: t.detection,
+                    });
+                }
+            }
+
+            *occupants = inside.iter().map(|t| (t.track_id, t.detection)).collect();
+        }
+        events
+    }
+}
@@ -0,0 +1,70 @@
+//! アンカーボックスのk-meansのテスト
+
+use yolo_v3_tiny_zynq::anchors::{compute_anchors, ANCHOR_NUM};
+
+/// 6つの大きさの周りに少しずつずらしたボックスを並べます。
+fn clustered_sizes() -> (Vec<[f32; 2]>, Vec<(f32, f32)>) {
+    let centers = vec![
+        [10., 14.],
+        [23., 27.],
+        [37., 58.],
+        [81., 82.],
+        [135., 169.],
+        [344., 319.],
+    ];
+    let sizes = centers
+        .iter()
+        .flat_map(|&[w, h]| {
+            [-0.04f32, -0.02, 0., 0.02, 0.04]
+                .into_iter()
+                .map(move |d| (w * (1. + d), h * (1. - d)))
+        })
+        .collect();
+    (centers, sizes)
+}
+
+#[test]
+fn finds_clustered_sizes() {
+    let (centers, sizes) = clustered_sizes();
+    let anchors = compute_anchors(&sizes).unwrap();
+    for (a, c) in anchors.sizes.iter().zip(&centers) {
+        assert!(
+            (a[0] - c[0]).abs() < c[0] * 0.01 && (a[1] - c[1]).abs() < c[1] * 0.01,
+            "{:?} is not near {:?}",
+            a,
+            c
+        );
+    }
+    assert!(anchors.avg_iou > 0.95, "avg_iou = {}", anchors.avg_iou);
+    assert_eq!(
+        anchors.to_string(),
+        "anchors = 10,14,  23,27,  37,58,  81,82,  135,169,  344,319"
+    );
+    assert_eq!(
+        anchors.head13(),
+        [anchors.sizes[3], anchors.sizes[4], anchors.sizes[5]]
+    );
+    assert_eq!(
+        anchors.head26(),
+        [anchors.sizes[1], anchors.sizes[2], anchors.sizes[3]]
+    );
+}
+
+#[test]
+fn sorted_by_area_and_deterministic() {
+    let (_, mut sizes) = clustered_sizes();
+    sizes.reverse();
+    let anchors = compute_anchors(&sizes).unwrap();
+    let areas: Vec<f32> = anchors.sizes.iter().map(|[w, h]| w * h).collect();
+    assert!(areas.windows(2).all(|w| w[0] <= w[1]), "{:?}", areas);
+    assert_eq!(compute_anchors(&sizes).unwrap(), anchors);
+}
+
+#[test]
+fn rejects_too_few_sizes() {
+    assert!(compute_anchors(&[]).is_err());
+    // 大きさが0のボックスや重複したボックスは数えない
+    let mut sizes: Vec<(f32, f32)> = (1..ANCHOR_NUM).map(|i| (i as f32, i as f32)).collect();
+    sizes.extend([(0., 10.), (3., 3.), (3., 3.)]);
+    assert!(compute_anchors(&sizes).is_err());
+}
@@ -0,0 +1,151 @@
+//! レンズの歪みの補正のテスト
+//!
+//! 歪みを加えてから補正して元の座標に戻ることと、OpenCVのYAMLとTOMLのパラメータの読み込みを確認します。
+
+mod common;
+
+use yolo_v3_tiny_zynq::calibration::CameraCalibration;
+use yolo_v3_tiny_zynq::detection_result::DetectionData;
+
+use common::temp_path;
+
+/// 樽型の歪みがある640×480の広角カメラ
+fn wide_angle() -> CameraCalibration {
+    CameraCalibration::new(
+        612.3,
+        611.8,
+        320.5,
+        241.2,
+        [-0.31, 0.09, 0.0005, -0.0002, -0.011],
+    )
+}
+
+const OPENCV_YAML: &str = "%YAML:1.0
+---
+image_width: 640
+image_height: 480
+camera_matrix: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 6.1230e+02, 0., 3.2050e+02, 0., 6.1180e+02,
+       2.4120e+02, 0., 0., 1. ]
+distortion_coefficients: !!opencv-matrix
+   rows: 1
+   cols: 5
+   dt: d
+   data: [ -3.1e-01, 9.0e-02, 5.0e-04, -2.0e-04, -1.1e-02 ]
+";
+
+#[test]
+fn distort_and_undistort_roundtrip() {
+    let calib = wide_angle();
+    // 主点は歪まない
+    assert_eq!(calib.distort_point(320.5, 241.2), (320.5, 241.2));
+    for (x, y) in [
+        (0., 0.),
+        (639., 0.),
+        (100., 400.),
+        (320., 479.),
+        (500., 120.),
+    ] {
+        let (xd, yd) = calib.distort_point(x, y);
+        // 樽型の歪みでは、画像の端の点は主点に近づく
+        assert!(
+            (xd - 320.5).abs() <= (x - 320.5f32).abs()
+                && (yd - 241.2).abs() <= (y - 241.2f32).abs(),
+            "({}, {}) -> ({}, {})",
+            x,
+            y,
+            xd,
+            yd
+        );
+        let (xu, yu) = calib.undistort_point(xd, yd);
+        assert!(
+            (xu - x).abs() < 0.5 && (yu - y).abs() < 0.5,
+            "({}, {}) -> ({}, {}) -> ({}, {})",
+            x,
+            y,
+            xd,
+            yd,
+            xu,
+            yu
+        );
+    }
+}
+
+#[test]
+fn undistort_detection_encloses_corners() {
+    let calib = wide_angle();
+    let d = DetectionData::new(3, 40., 30., 200., 180., 0.8);
+    let u = calib.undistort_detection(&d);
+    assert_eq!((u.class, u.confidence), (3, 0.8));
+    for (x, y) in [(d.x1, d.y1), (d.x2, d.y1), (d.x1, d.y2), (d.x2, d.y2)] {
+        let (x, y) = calib.undistort_point(x, y);
+        assert!(u.x1 <= x && x <= u.x2 && u.y1 <= y && y <= u.y2, "{:?}", u);
+    }
+}
+
+#[test]
+fn reads_opencv_yaml() {
+    let calib = CameraCalibration::from_opencv_yaml(OPENCV_YAML).unwrap();
+    assert_eq!(calib, wide_angle());
+
+    // 別名の行列と、係数が4個の場合
+    let yaml = OPENCV_YAML
+        .replace("distortion_coefficients", "dist_coeffs")
+        .replace("cols: 5", "cols: 4")
+        .replace(", -1.1e-02 ]", " ]");
+    let calib = CameraCalibration::from_opencv_yaml(&yaml).unwrap();
+    assert_eq!(calib.distortion, [-0.31, 0.09, 0.0005, -0.0002, 0.]);
+}
+
+#[test]
+fn opencv_yaml_rejects_malformed_matrices() {
+    let cases = [
+        // 行列がない
+        OPENCV_YAML.replace("camera_matrix", "projection_matrix"),
+        // 要素数が合わない
+        OPENCV_YAML.replace("0., 0., 1. ]", "0., 0. ]"),
+        // 数値でない要素
+        OPENCV_YAML.replace("-3.1e-01", "k1"),
+        // 閉じていないリスト
+        OPENCV_YAML.replace("-1.1e-02 ]", "-1.1e-02"),
+        // 歪み係数が足りない
+        OPENCV_YAML.replace("5.0e-04, -2.0e-04, -1.1e-02", "5.0e-04"),
+        // 対応していない高次の係数
+        OPENCV_YAML.replace("-1.1e-02 ]", "-1.1e-02, 0.5, 0., 0. ]"),
+    ];
+    for (i, yaml) in cases.iter().enumerate() {
+        assert!(
+            CameraCalibration::from_opencv_yaml(yaml).is_err(),
+            "case {} was accepted",
+            i
+        );
+    }
+}
+
+#[test]
+fn from_file_reads_yaml_and_toml() {
+    let yaml = temp_path("camera.yaml");
+    std::fs::write(&yaml, OPENCV_YAML).unwrap();
+    let result = CameraCalibration::from_file(&yaml);
+    std::fs::remove_file(&yaml).unwrap();
+    assert_eq!(result.unwrap(), wide_angle());
+
+    let toml = temp_path("camera.toml");
+    std::fs::write(
+        &toml,
+        "fx = 612.3\nfy = 611.8\ncx = 320.5\ncy = 241.2\ndistortion = [-0.31, 0.09, 0.0005, -0.0002]\n",
+    )
+    .unwrap();
+    let result = CameraCalibration::from_file(&toml);
+    std::fs::write(&toml, "fx = 0.0\nfy = 611.8\ncx = 320.5\ncy = 241.2\n").unwrap();
+    let zero_focal = CameraCalibration::from_file(&toml);
+    std::fs::remove_file(&toml).unwrap();
+    assert_eq!(
+        result.unwrap().distortion,
+        [-0.31, 0.09, 0.0005, -0.0002, 0.]
+    );
+    assert!(zero_focal.is_err());
+}
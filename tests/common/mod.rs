@@ -0,0 +1,18 @@
+//! 結合テストで共通して使う補助関数
+
+use std::path::PathBuf;
+
+/// テストごとに重ならない一時ファイルのパスを返します。
+///
+/// 並列に実行される他のテストのファイルと重ならないよう、テストのクレート名とプロセスIDを含めます。
+///
+/// # Args
+/// * `name` - ファイル名
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "yolo_{}_{}_{}",
+        env!("CARGO_CRATE_NAME"),
+        std::process::id(),
+        name
+    ))
+}
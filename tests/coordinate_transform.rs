@@ -0,0 +1,300 @@
+//! 前処理と座標の逆変換のプロパティテスト
+//!
+//! 前処理が実際に作ったYOLOの入力データから画像の配置位置や目印の位置を測り、
+//! `Preprocessor::inverse_transform` (モザイクは `Mosaic::demultiplex`) で元の画像 (回転後) の座標系に戻したときの誤差を確認します。
+//! 誤差はYOLOの入力データ (または拡大した領域) のピクセル単位で評価します。
+
+use image::{DynamicImage, Rgb, RgbImage};
+use proptest::prelude::*;
+
+use yolo_v3_tiny_zynq::detection_result::{DetectionData, LetterboxSpace};
+use yolo_v3_tiny_zynq::img_proc::{self, enlargement_slots, CropRect};
+use yolo_v3_tiny_zynq::preprocess::{
+    Letterbox, Mosaic, MultiPatialEnlargement, PatialEnlargement, Preprocessor,
+};
+
+/// YOLOの入力サイズ
+const SIZE: u32 = 416;
+/// 背景の画素値
+const BACKGROUND: u8 = 40;
+/// 目印の画素値
+const MARKER: u8 = 220;
+/// 目印とみなす画素値の閾値 (背景と目印の中間)
+const MARKER_THRESHOLD: i16 = 130;
+/// 配置位置から計算した変換の許容誤差 (YOLOの入力データのピクセル)
+const GEOMETRY_TOLERANCE: f32 = 1.0;
+/// 目印の位置から計算した変換の許容誤差。リサイズで目印の端がぼけるため、0.5ピクセル分を加えています
+const MARKER_TOLERANCE: f32 = 1.5;
+
+/// 背景の中に目印の矩形を描いた画像を作ります。
+///
+/// # Args
+/// * `width` - 画像の幅
+/// * `height` - 画像の高さ
+/// * `marker` - 目印の矩形 (x1, y1, x2, y2)。右下は含みません
+fn marker_image(width: u32, height: u32, marker: Option<[u32; 4]>) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        let on_marker =
+            marker.is_some_and(|[x1, y1, x2, y2]| (x1..x2).contains(&x) && (y1..y2).contains(&y));
+        Rgb([if on_marker { MARKER } else { BACKGROUND }; 3])
+    }))
+}
+
+/// YOLOの入力データの指定した領域で、条件を満たす画素を囲む矩形を返します。
+///
+/// # Return
+/// * 矩形 (x1, y1, x2, y2)。右下は含みません。条件を満たす画素がない場合はNone
+fn extent(data: &[i16], region: CropRect, pred: impl Fn(i16) -> bool) -> Option<[f32; 4]> {
+    let mut rect: Option<[u32; 4]> = None;
+    for y in region.y..region.y + region.h {
+        for x in region.x..region.x + region.w {
+            if !pred(data[4 * (x + y * SIZE) as usize]) {
+                continue;
+            }
+            let r = rect.get_or_insert([x, y, x + 1, y + 1]);
+            *r = [r[0].min(x), r[1].min(y), r[2].max(x + 1), r[3].max(y + 1)];
+        }
+    }
+    rect.map(|r| r.map(|v| v as f32))
+}
+
+/// 元の画像の矩形を、画像を回転させたときの座標系に変換します。
+///
+/// # Args
+/// * `b` - 矩形 (x1, y1, x2, y2)
+/// * `width` - 回転前の画像の幅
+/// * `height` - 回転前の画像の高さ
+/// * `angle` - 回転角度
+fn rotate_box(b: [u32; 4], width: u32, height: u32, angle: u32) -> [f32; 4] {
+    let [x1, y1, x2, y2] = b;
+    let r = match angle {
+        90 => [height - y2, x1, height - y1, x2],
+        180 => [width - x2, height - y2, width - x1, height - y1],
+        270 => [y1, width - x2, y2, width - x1],
+        _ => b,
+    };
+    r.map(|v| v as f32)
+}
+
+/// [0, 1) の2つの値から、長さの範囲 `len` の中に最小幅 `min` 以上の区間を作ります。
+fn span(a: f32, b: f32, len: u32, min: u32) -> (u32, u32) {
+    let w = min + ((len - min) as f32 * a) as u32;
+    let start = ((len - w) as f32 * b) as u32;
+    (start, start + w)
+}
+
+fn to_letterbox_space(b: [f32; 4]) -> DetectionData<LetterboxSpace> {
+    DetectionData::new(0, b[0], b[1], b[2], b[3], 1.)
+}
+
+/// 逆変換の結果と期待する矩形の誤差が、`scale` 倍した単位で `tolerance` 以下であることを確認します。
+fn assert_close(
+    actual: &DetectionData,
+    expected: [f32; 4],
+    scale: f32,
+    tolerance: f32,
+) -> Result<(), TestCaseError> {
+    let actual = [actual.x1, actual.y1, actual.x2, actual.y2];
+    for (a, e) in actual.iter().zip(expected) {
+        prop_assert!(
+            (a - e).abs() * scale <= tolerance,
+            "{:?} differs from {:?} (scale {})",
+            actual,
+            expected,
+            scale
+        );
+    }
+    Ok(())
+}
+
+fn angle() -> impl Strategy<Value = u32> {
+    prop::sample::select(vec![0, 90, 180, 270])
+}
+
+/// [0, 1) の値4つ
+fn unit4() -> impl Strategy<Value = [f32; 4]> {
+    [0f32..1., 0f32..1., 0f32..1., 0f32..1.]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(48))]
+
+    /// 画像の配置位置と縮小率から求めた座標が、逆変換で元の座標に戻ることを確認します。
+    #[test]
+    fn placement_roundtrip(
+        width in 16u32..=1280,
+        height in 16u32..=1280,
+        angle in angle(),
+        rotate_en in any::<bool>(),
+        f in unit4(),
+    ) {
+        let img = marker_image(width, height, None);
+        let (rw, rh) = match angle {
+            90 | 270 => (height, width),
+            _ => (width, height),
+        };
+        let full = CropRect::new(0, 0, SIZE, SIZE);
+
+        // 部分拡大を行う前処理は、拡大する領域がないときの配置位置で比べる
+        let no_crops = MultiPatialEnlargement::new(angle, rotate_en, vec![]);
+        let enlarged = no_crops.prepare(&img, SIZE);
+        let (ew, eh) = if rotate_en { (rw, rh) } else { (width, height) };
+        let preprocessors: [(&dyn Preprocessor, Vec<i16>, u32, u32); 3] = [
+            (&Letterbox::new(angle), Letterbox::new(angle).prepare(&img, SIZE), rw, rh),
+            (&PatialEnlargement::new(angle, rotate_en, None, None, 1, 1), enlarged.clone(), ew, eh),
+            (&no_crops, enlarged, ew, eh),
+        ];
+        for (pre, data, cw, ch) in preprocessors {
+            let content = extent(&data, full, |v| v > 0).unwrap();
+            let sx = (content[2] - content[0]) / cw as f32;
+            let sy = (content[3] - content[1]) / ch as f32;
+            let (x1, x2) = (f[0].min(f[1]) * cw as f32, f[0].max(f[1]) * cw as f32);
+            let (y1, y2) = (f[2].min(f[3]) * ch as f32, f[2].max(f[3]) * ch as f32);
+            let d = to_letterbox_space([
+                content[0] + x1 * sx,
+                content[1] + y1 * sy,
+                content[0] + x2 * sx,
+                content[1] + y2 * sy,
+            ]);
+            let back = pre.inverse_transform(&d, width, height, SIZE);
+            assert_close(&back, [x1, y1, x2, y2], sx.max(sy), GEOMETRY_TOLERANCE)?;
+        }
+    }
+
+    /// レターボックスで目印を描いた画像を前処理し、YOLOの入力データ上の目印が元の位置に戻ることを確認します。
+    #[test]
+    fn letterbox_marker_roundtrip(
+        width in 16u32..=1280,
+        height in 16u32..=1280,
+        angle in angle(),
+        f in unit4(),
+    ) {
+        let ratio = SIZE as f32 / width.max(height) as f32;
+        // リサイズ後に4ピクセル以上になる目印
+        let min = ((4. / ratio).ceil() as u32).min(width.min(height));
+        let (x1, x2) = span(f[0], f[1], width, min);
+        let (y1, y2) = span(f[2], f[3], height, min);
+        let img = marker_image(width, height, Some([x1, y1, x2, y2]));
+
+        let pre = Letterbox::new(angle);
+        let data = pre.prepare(&img, SIZE);
+        let found = extent(&data, CropRect::new(0, 0, SIZE, SIZE), |v| v > MARKER_THRESHOLD);
+        prop_assert!(found.is_some(), "marker is lost");
+        let back = pre.inverse_transform(&to_letterbox_space(found.unwrap()), width, height, SIZE);
+        let expected = rotate_box([x1, y1, x2, y2], width, height, angle);
+        assert_close(&back, expected, ratio, MARKER_TOLERANCE)?;
+    }
+
+    /// 拡大した領域の中の目印が、切り取り前の画像 (回転後) の位置に戻ることを確認します。
+    #[test]
+    fn enlargement_marker_roundtrip(
+        width in 64u32..=1280,
+        height in 64u32..=1280,
+        angle in angle(),
+        rotate_en in any::<bool>(),
+        n_crops in 1usize..=3,
+        fc in unit4(),
+        fm in unit4(),
+    ) {
+        let (rw, rh) = match angle {
+            90 | 270 if rotate_en => (height, width),
+            _ => (width, height),
+        };
+        // 1つ目の切り取り領域の中に目印を置く (回転後の画像の座標系)
+        let (cx1, cx2) = span(fc[0], fc[1], rw, 16);
+        let (cy1, cy2) = span(fc[2], fc[3], rh, 16);
+        let crop = CropRect::new(cx1, cy1, cx2 - cx1, cy2 - cy1);
+        let crops = vec![crop; n_crops];
+
+        // 拡大した領域の配置先は、拡大する領域がないときの配置位置から求める
+        let no_crops = MultiPatialEnlargement::new(angle, rotate_en, vec![]);
+        let content = extent(
+            &no_crops.prepare(&marker_image(width, height, None), SIZE),
+            CropRect::new(0, 0, SIZE, SIZE),
+            |v| v > 0,
+        )
+        .unwrap();
+        let slot = enlargement_slots(content[2] as u32, content[3] as u32, SIZE, n_crops)[0];
+        prop_assume!(slot.w >= 16 && slot.h >= 16);
+        let r = f32::min(slot.w as f32 / crop.w as f32, slot.h as f32 / crop.h as f32);
+
+        let min = ((4. / r).ceil() as u32).min(crop.w.min(crop.h));
+        let (mx1, mx2) = span(fm[0], fm[1], crop.w, min);
+        let (my1, my2) = span(fm[2], fm[3], crop.h, min);
+        let marker = [cx1 + mx1, cy1 + my1, cx1 + mx2, cy1 + my2];
+        // 目印は回転前の画像に描く
+        let unrotated = match (rotate_en, angle) {
+            (true, 90) => rotate_box(marker, rw, rh, 270),
+            (true, 180) => rotate_box(marker, rw, rh, 180),
+            (true, 270) => rotate_box(marker, rw, rh, 90),
+            _ => marker.map(|v| v as f32),
+        };
+        let img = marker_image(width, height, Some(unrotated.map(|v| v as u32)));
+        let expected = marker.map(|v| v as f32);
+
+        let multi = MultiPatialEnlargement::new(angle, rotate_en, crops);
+        let data = multi.prepare(&img, SIZE);
+        let found = extent(&data, slot, |v| v > MARKER_THRESHOLD);
+        prop_assert!(found.is_some(), "marker is lost");
+        let d = to_letterbox_space(found.unwrap());
+        let back = multi.inverse_transform(&d, width, height, SIZE);
+        assert_close(&back, expected, r, MARKER_TOLERANCE)?;
+
+        if n_crops == 1 {
+            let single = PatialEnlargement::new(angle, rotate_en, Some(cx1), Some(cy1), crop.w, crop.h);
+            prop_assert_eq!(single.prepare(&img, SIZE), data);
+            let back = single.inverse_transform(&d, width, height, SIZE);
+            assert_close(&back, expected, r, MARKER_TOLERANCE)?;
+        }
+    }
+
+    /// モザイクのタイルの中の目印が、そのカメラの画像の位置に戻ることを確認します。
+    #[test]
+    fn mosaic_marker_roundtrip(
+        sizes in prop::collection::vec((16u32..=1280, 16u32..=1280), 1..=4),
+        camera in 0usize..4,
+        f in unit4(),
+    ) {
+        let camera = camera % sizes.len();
+        let (width, height) = sizes[camera];
+        let content = img_proc::mosaic_content(SIZE, camera, width, height);
+        let ratio = content.w as f32 / width as f32;
+        let min = ((4. / ratio).ceil() as u32).min(width.min(height));
+        let (x1, x2) = span(f[0], f[1], width, min);
+        let (y1, y2) = span(f[2], f[3], height, min);
+        let imgs: Vec<DynamicImage> = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &(w, h))| marker_image(w, h, (i == camera).then_some([x1, y1, x2, y2])))
+            .collect();
+        let refs: Vec<&DynamicImage> = imgs.iter().collect();
+
+        let mosaic = Mosaic::from_images(&refs).unwrap();
+        let data = mosaic.prepare(&refs, SIZE).unwrap();
+        let tile = img_proc::mosaic_tile(SIZE, camera);
+        let found = extent(&data, tile, |v| v > MARKER_THRESHOLD);
+        prop_assert!(found.is_some(), "marker is lost");
+        let back = mosaic.demultiplex(&to_letterbox_space(found.unwrap()), SIZE);
+        prop_assert!(back.is_some(), "marker is not assigned to a camera");
+        let (i, back) = back.unwrap();
+        prop_assert_eq!(i, camera);
+        assert_close(&back, [x1, y1, x2, y2].map(|v| v as f32), ratio, MARKER_TOLERANCE)?;
+    }
+}
+
+#[test]
+fn enlargement_larger_than_image() {
+    // 切り取り位置を省略したとき、画像より大きい切り取り範囲でもpanicしない
+    let img = marker_image(120, 80, None);
+    for angle in [0, 90, 180, 270] {
+        let pe = PatialEnlargement::new(angle, true, None, None, 200, 150);
+        let crop = pe.crop_rect(img.width(), img.height());
+        assert_eq!((crop.x, crop.y), (0, 0));
+
+        let data = img_proc::letterbox_with_patial_enlargement(
+            &img, SIZE, angle, true, None, None, 200, 150,
+        );
+        assert_eq!(data, pe.prepare(&img, SIZE));
+        img_proc::letterbox_img_with_patial_enlargement(&img, angle, true, None, None, 200, 150);
+    }
+}
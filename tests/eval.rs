@@ -0,0 +1,82 @@
+//! 検出精度の評価のテスト
+//!
+//! 検出結果と正解データの対応付けがPASCAL VOCと同じ規則になることと、
+//! 手計算したAP・mAPと一致することを確認します。
+
+use yolo_v3_tiny_zynq::detection_result::DetectionData;
+use yolo_v3_tiny_zynq::eval::Evaluator;
+
+const EPS: f32 = 1e-5;
+
+fn bbox(class: u8, x: f32, y: f32, confidence: f32) -> DetectionData {
+    DetectionData::new(class, x, y, x + 10., y + 10., confidence)
+}
+
+#[test]
+fn matched_ground_truth_is_false_positive() {
+    // 正解A (0,0)-(10,10) と正解B (2,0)-(12,10)
+    // 2つ目の検出結果はAとのIoUが 95/105、BとのIoUが 85/115 で、どちらも閾値を超えるが、
+    // IoUが最大のAは1つ目の検出結果と対応付け済みなので誤検出になる
+    let ground_truth = [bbox(0, 0., 0., 1.), bbox(0, 2., 0., 1.)];
+    let predictions = [bbox(0, 0., 0., 0.9), bbox(0, 0.5, 0., 0.8)];
+
+    let mut evaluator = Evaluator::new(1, 0.5);
+    evaluator.add_image(&predictions, &ground_truth);
+
+    let curve = evaluator.pr_curve(0);
+    assert_eq!(curve.len(), 2);
+    assert!((curve[0].precision - 1.).abs() < EPS);
+    assert!((curve[0].recall - 0.5).abs() < EPS);
+    assert!((curve[1].precision - 0.5).abs() < EPS);
+    assert!((curve[1].recall - 0.5).abs() < EPS);
+
+    // AP = 0.5 * 1
+    let ap = evaluator.average_precision(0).unwrap();
+    assert!((ap - 0.5).abs() < EPS, "AP {}", ap);
+}
+
+#[test]
+fn mean_average_precision_matches_hand_computed() {
+    let mut evaluator = Evaluator::new(3, 0.5);
+
+    // クラス0: 上のテストと同じで AP = 1/2
+    evaluator.add_image(
+        &[bbox(0, 0., 0., 0.9), bbox(0, 0.5, 0., 0.8)],
+        &[bbox(0, 0., 0., 1.), bbox(0, 2., 0., 1.)],
+    );
+
+    // クラス1: 正解3つのうち2つを検出し、誤検出が2つ
+    // コンフィデンス順に TP, FP, TP, FP となり
+    //   適合率 1, 1/2, 2/3, 1/2
+    //   再現率 1/3, 1/3, 2/3, 2/3
+    // 補間後の適合率は 1, 2/3, 2/3, 1/2 なので AP = 1/3 * 1 + 1/3 * 2/3 = 5/9
+    evaluator.add_image(
+        &[
+            bbox(1, 100., 100., 0.95),
+            bbox(1, 500., 500., 0.7),
+            bbox(1, 200., 100., 0.6),
+            // 同じ正解への2つ目の検出結果
+            bbox(1, 100., 101., 0.4),
+            // 正解データのないクラスの検出結果はmAPに影響しない
+            bbox(2, 0., 0., 0.99),
+        ],
+        &[bbox(1, 100., 100., 1.), bbox(1, 200., 100., 1.)],
+    );
+    // 検出されなかった正解
+    evaluator.add_image(&[], &[bbox(1, 300., 100., 1.)]);
+
+    let ap1 = evaluator.average_precision(1).unwrap();
+    assert!((ap1 - 5. / 9.).abs() < EPS, "AP {}", ap1);
+    assert_eq!(evaluator.average_precision(2), None);
+
+    let map = evaluator.mean_average_precision();
+    assert!((map - 19. / 36.).abs() < EPS, "mAP {}", map);
+
+    let evals = evaluator.class_evals();
+    assert_eq!(evals[1].n_ground_truth, 3);
+    assert_eq!(evals[1].n_predictions, 4);
+    assert_eq!(evals[2].n_predictions, 1);
+
+    evaluator.reset();
+    assert_eq!(evaluator.mean_average_precision(), 0.);
+}
@@ -0,0 +1,85 @@
+//! 地面への射影変換のテスト
+//!
+//! 基準点が射影変換で対応する座標に移ることと、画像と地面の間を往復して元の座標に戻ること、
+//! 射影変換が定まらない基準点をエラーにすることを確認します。
+
+use yolo_v3_tiny_zynq::detection_result::DetectionData;
+use yolo_v3_tiny_zynq::ground_plane::{GroundPlane, GroundPlaneConfig};
+
+/// 道路を斜め上から写した画像の基準点
+const IMAGE_POINTS: [[f32; 2]; 4] = [[412., 690.], [868., 690.], [760., 420.], [520., 420.]];
+/// 基準点の地面上の座標 (メートル)
+const WORLD_POINTS: [[f32; 2]; 4] = [[-1.75, 5.], [1.75, 5.], [1.75, 20.], [-1.75, 20.]];
+
+fn assert_near((x, y): (f32, f32), [ex, ey]: [f32; 2], tolerance: f32) {
+    assert!(
+        (x - ex).abs() <= tolerance && (y - ey).abs() <= tolerance,
+        "({}, {}) is not near ({}, {})",
+        x,
+        y,
+        ex,
+        ey
+    );
+}
+
+#[test]
+fn reference_points_roundtrip() {
+    let plane = GroundPlane::from_points(IMAGE_POINTS, WORLD_POINTS).unwrap();
+    for (image, world) in IMAGE_POINTS.iter().zip(&WORLD_POINTS) {
+        let projected = plane.to_world(image[0], image[1]).unwrap();
+        assert_near(projected, *world, 1e-3);
+        assert_near(
+            plane.to_image(projected.0, projected.1).unwrap(),
+            *image,
+            1e-2,
+        );
+        assert_near(plane.to_image(world[0], world[1]).unwrap(), *image, 1e-2);
+    }
+
+    // 基準点の内側の点も往復して元に戻る
+    let (x, y) = plane.to_world(640., 600.).unwrap();
+    assert!(x.abs() < 1.75 && 5. < y && y < 20., "({}, {})", x, y);
+    assert_near(plane.to_image(x, y).unwrap(), [640., 600.], 1e-2);
+}
+
+#[test]
+fn projects_detection_feet() {
+    let config = GroundPlaneConfig {
+        image_points: IMAGE_POINTS,
+        world_points: WORLD_POINTS,
+    };
+    let plane = GroundPlane::from_config(&config).unwrap();
+    // 足元が基準点の左下と右下にある検出結果
+    let left = DetectionData::new(0, 392., 600., 432., 690., 0.9);
+    let right = DetectionData::new(0, 848., 600., 888., 690., 0.9);
+    assert_near(
+        plane.project_detection(&left).unwrap(),
+        WORLD_POINTS[0],
+        1e-3,
+    );
+    assert_near(
+        plane.project_detection(&right).unwrap(),
+        WORLD_POINTS[1],
+        1e-3,
+    );
+    let distance = plane.distance(&left, &right).unwrap();
+    assert!((distance - 3.5).abs() < 1e-3, "{}", distance);
+}
+
+#[test]
+fn above_horizon_is_none() {
+    let plane = GroundPlane::from_points(IMAGE_POINTS, WORLD_POINTS).unwrap();
+    // 奥に向かって狭まる道路の消失点より上は地面と交わらない
+    assert_eq!(plane.to_world(640., 0.), None);
+}
+
+#[test]
+fn rejects_degenerate_points() {
+    // 3点が同一直線上にある
+    let collinear = [[0., 0.], [100., 0.], [200., 0.], [0., 100.]];
+    assert!(GroundPlane::from_points(collinear, WORLD_POINTS).is_err());
+    assert!(GroundPlane::from_points(IMAGE_POINTS, collinear).is_err());
+    // 同じ点が重なっている
+    let duplicated = [[0., 0.], [0., 0.], [100., 100.], [0., 100.]];
+    assert!(GroundPlane::from_points(duplicated, WORLD_POINTS).is_err());
+}
@@ -0,0 +1,80 @@
+//! NMSのテスト
+//!
+//! グリッドで分割したNMS (`nms_grid`) が、全ての組み合わせを比べるNMSと同じ結果になることを確認します。
+//! `nms` は候補が `GRID_NMS_MIN_CANDIDATES` 個未満の場合に全ての組み合わせを比べます。
+
+use proptest::prelude::*;
+
+use yolo_v3_tiny_zynq::detection_result::DetectionData;
+use yolo_v3_tiny_zynq::nms::{self, GRID_NMS_MIN_CANDIDATES};
+
+/// 面積がないもの、グリッドのセルを多くまたぐ大きなもの、負の座標を含むバウンディングボックス
+fn bbox() -> impl Strategy<Value = DetectionData> {
+    let size = prop_oneof![
+        2 => Just(0f32),
+        10 => 4f32..60.,
+        1 => 400f32..1200.,
+    ];
+    (
+        0u8..3,
+        -300f32..600.,
+        -300f32..600.,
+        size.clone(),
+        size,
+        0f32..1.,
+    )
+        .prop_map(|(class, x, y, w, h, confidence)| {
+            DetectionData::new(class, x, y, x + w, y + h, confidence)
+        })
+}
+
+/// 比較のため、検出結果を座標とコンフィデンスの組に変換します。
+fn key(detections: &[DetectionData]) -> Vec<(u8, [f32; 5])> {
+    detections
+        .iter()
+        .map(|d| (d.class, [d.x1, d.y1, d.x2, d.y2, d.confidence]))
+        .collect()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(256))]
+
+    #[test]
+    fn grid_matches_pairwise(
+        boxes in prop::collection::vec(bbox(), 1..GRID_NMS_MIN_CANDIDATES),
+        threshold in 0.05f32..0.95,
+    ) {
+        let pairwise = nms::nms(&boxes, threshold);
+        let grid = nms::nms_grid(&boxes, threshold);
+        prop_assert_eq!(key(&grid), key(&pairwise));
+    }
+}
+
+#[test]
+fn grid_matches_pairwise_on_clusters() {
+    // 同じ位置に重なった候補が多い場合と、全体を覆う候補がある場合
+    let mut boxes = vec![];
+    for i in 0..(GRID_NMS_MIN_CANDIDATES - 1) {
+        let (cx, cy) = ((i % 5) as f32 * 30. - 50., (i / 50) as f32 * 25. - 40.);
+        let jitter = (i % 7) as f32;
+        boxes.push(DetectionData::new(
+            0,
+            cx + jitter,
+            cy - jitter,
+            cx + 20. + jitter,
+            cy + 15.,
+            (i * 37 % 101) as f32 / 101.,
+        ));
+    }
+    boxes[10] = DetectionData::new(0, -500., -500., 500., 500., 0.99);
+    boxes[20] = DetectionData::new(0, 5., 5., 5., 30., 0.98);
+
+    for threshold in [0.1, 0.45, 0.9] {
+        assert_eq!(
+            key(&nms::nms_grid(&boxes, threshold)),
+            key(&nms::nms(&boxes, threshold)),
+            "threshold {}",
+            threshold
+        );
+    }
+}
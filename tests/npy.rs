@@ -0,0 +1,126 @@
+//! `.npy` / `.npz` の読み書きのテスト
+//!
+//! 書き出したテンソルを読み戻して一致することと、壊れたヘッダや対応していない配列をエラーにすることを確認します。
+
+mod common;
+
+use yolo_v3_tiny_zynq::npy::{self, NpyArray};
+
+use common::temp_path;
+
+/// 4要素の `i16` の配列のヘッダ
+const I16_HEADER: &str = "{'descr': '<i2', 'fortran_order': False, 'shape': (4,), }";
+
+/// 指定したヘッダの `.npy` のバイト列を作ります。
+fn npy_bytes(header: &str, data: &[u8]) -> Vec<u8> {
+    let mut buf = b"\x93NUMPY\x01\x00".to_vec();
+    buf.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    buf.extend_from_slice(header.as_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+#[test]
+fn npy_roundtrip() {
+    let array = NpyArray::new(vec![2, 3, 4], (0..24).map(|v| v * 100 - 1200).collect()).unwrap();
+    let path = temp_path("i16.npy");
+    array.save(&path).unwrap();
+    let loaded = NpyArray::<i16>::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded, array);
+
+    let array = NpyArray::from_vec(vec![0.5f32, -1.25, f32::MAX, f32::MIN_POSITIVE]);
+    let mut buf = Vec::new();
+    array.write(&mut buf).unwrap();
+    // データの先頭は64バイト境界に揃う
+    assert_eq!((buf.len() - 4 * 4) % 64, 0);
+    assert_eq!(NpyArray::<f32>::read(buf.as_slice()).unwrap(), array);
+}
+
+#[test]
+fn npz_roundtrip() {
+    let weights = NpyArray::new(vec![4, 3], (0..12).map(|v| v as f32 * 0.25).collect()).unwrap();
+    let biases = NpyArray::from_vec(vec![1.0f32, -2.0, 3.0]);
+    let path = temp_path("params.npz");
+    npy::save_npz(&path, &[("weights0", &weights), ("biases0", &biases)]).unwrap();
+    let loaded = npy::load_npz::<_, f32>(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        loaded,
+        vec![
+            ("weights0".to_string(), weights),
+            ("biases0".to_string(), biases)
+        ]
+    );
+}
+
+#[test]
+fn new_rejects_mismatched_shape() {
+    assert!(NpyArray::new(vec![2, 3], vec![0i16; 5]).is_err());
+}
+
+#[test]
+fn read_rejects_malformed_headers() {
+    let data = [0u8; 8];
+    let cases = [
+        // マジックナンバーが異なる
+        {
+            let mut buf = npy_bytes(I16_HEADER, &data);
+            buf[1] = b'X';
+            buf
+        },
+        // 対応していないバージョン
+        {
+            let mut buf = npy_bytes(I16_HEADER, &data);
+            buf[6] = 9;
+            buf
+        },
+        // ヘッダが途中で終わっている
+        npy_bytes(I16_HEADER, &[])[..20].to_vec(),
+        // 型が異なる
+        npy_bytes(
+            "{'descr': '<f4', 'fortran_order': False, 'shape': (2,), }",
+            &data,
+        ),
+        // Fortran順
+        npy_bytes(
+            "{'descr': '<i2', 'fortran_order': True, 'shape': (2, 2), }",
+            &data,
+        ),
+        // 形状がない
+        npy_bytes("{'descr': '<i2', 'fortran_order': False, }", &data),
+        // 形状が数値でない
+        npy_bytes(
+            "{'descr': '<i2', 'fortran_order': False, 'shape': (a,), }",
+            &data,
+        ),
+        // データが形状より短い
+        npy_bytes(
+            "{'descr': '<i2', 'fortran_order': False, 'shape': (8,), }",
+            &data,
+        ),
+    ];
+    for (i, buf) in cases.iter().enumerate() {
+        assert!(
+            NpyArray::<i16>::read(buf.as_slice()).is_err(),
+            "case {} was accepted",
+            i
+        );
+    }
+
+    let buf = npy_bytes(
+        "{'descr': '<i2', 'fortran_order': False, 'shape': (2, 2), }",
+        &data,
+    );
+    let array = NpyArray::<i16>::read(buf.as_slice()).unwrap();
+    assert_eq!(array.shape, vec![2, 2]);
+}
+
+#[test]
+fn load_npz_rejects_non_zip() {
+    let path = temp_path("broken.npz");
+    std::fs::write(&path, b"not a zip archive").unwrap();
+    let result = npy::load_npz::<_, f32>(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert!(result.is_err());
+}
@@ -0,0 +1,134 @@
+//! 重みの量子化のテスト
+//!
+//! キャリブレーションにはIPが必要なため、スケールが等倍のときの量子化と、重みの読み込み・書き出しを確認します。
+
+mod common;
+
+use std::io::Read;
+use std::path::PathBuf;
+
+use flate2::read::GzDecoder;
+use tar::Archive;
+
+use yolo_v3_tiny_zynq::layer_group::{Activation, LayerGroup, PostProcess};
+use yolo_v3_tiny_zynq::npy::{self, NpyArray};
+use yolo_v3_tiny_zynq::quant::Quantizer;
+
+use common::temp_path;
+
+/// 畳み込みのレイヤーグループとYOLO層の2つからなる小さな構成を返します。
+#[rustfmt::skip]
+fn layers() -> Vec<LayerGroup> {
+    vec![
+        LayerGroup::new(4, 4, 4, 1, 4, 4, 4, 1, false,  Activation::Leaky, PostProcess::None, 1),
+        LayerGroup::new(4, 4, 4, 1, 4, 4, 4, 1, false, Activation::Linear, PostProcess::Yolo, 1),
+    ]
+}
+
+/// 量子化した重みのアーカイブを名前とQ8.8の値の組として読み込みます。
+fn read_bundle(path: &PathBuf) -> Vec<(String, Vec<i16>)> {
+    let mut archive = Archive::new(GzDecoder::new(std::fs::File::open(path).unwrap()));
+    archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes).unwrap();
+            let data = bytes
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect();
+            (name, data)
+        })
+        .collect()
+}
+
+#[test]
+fn write_bundle_quantizes_to_q8_8() {
+    let layers = layers();
+    let weights: Vec<f32> = (0..layers[0].weights_len())
+        .map(|i| (i as f32 - 96.) / 64.)
+        .collect();
+    let mut saturated = weights.clone();
+    saturated[0] = 200.;
+    saturated[1] = -200.;
+    let biases = vec![0.5, -0.25, 1. / 512., 127.99];
+    let quantizer = Quantizer::new(
+        &layers,
+        vec![Some(saturated), None],
+        vec![Some(biases.clone()), Some(vec![0.; 4])],
+    )
+    .unwrap();
+    assert_eq!(quantizer.scale_exponents(), &[0, 0]);
+
+    let path = temp_path("bundle.tar.gz");
+    quantizer.write_bundle(&path).unwrap();
+    let bundle = read_bundle(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    let names: Vec<&str> = bundle.iter().map(|(n, _)| n.as_str()).collect();
+    assert_eq!(names, ["weights0", "biases0", "biases1"]);
+    let q_weights = &bundle[0].1;
+    assert_eq!(q_weights[0], i16::MAX);
+    assert_eq!(q_weights[1], i16::MIN);
+    for (&q, &w) in q_weights.iter().zip(&weights).skip(2) {
+        assert_eq!(q, (w * 256.).round() as i16);
+    }
+    assert_eq!(bundle[1].1, vec![128, -64, 1, 32765]);
+    assert_eq!(bundle[2].1, vec![0; 4]);
+}
+
+#[test]
+fn new_rejects_mismatched_lengths() {
+    let layers = layers();
+    let len = layers[0].weights_len();
+    // レイヤーグループの数が合わない
+    assert!(Quantizer::new(&layers, vec![None], vec![None]).is_err());
+    // 重みの長さが合わない
+    assert!(Quantizer::new(
+        &layers,
+        vec![Some(vec![0.; len - 1]), None],
+        vec![None, None]
+    )
+    .is_err());
+    // バイアスの長さが合わない
+    assert!(Quantizer::new(&layers, vec![None, None], vec![None, Some(vec![0.; 5])]).is_err());
+}
+
+#[test]
+fn from_npz_reads_named_arrays() {
+    let layers = layers();
+    let weights = NpyArray::from_vec(vec![0.125f32; layers[0].weights_len()]);
+    let biases = NpyArray::from_vec(vec![-1.5f32; 4]);
+    let path = temp_path("params.npz");
+    npy::save_npz(&path, &[("weights0", &weights), ("biases1", &biases)]).unwrap();
+    let quantizer = Quantizer::from_npz(&layers, &path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let path = temp_path("from_npz.tar.gz");
+    quantizer.write_bundle(&path).unwrap();
+    let bundle = read_bundle(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        bundle,
+        vec![
+            ("weights0".to_string(), vec![32; layers[0].weights_len()]),
+            ("biases1".to_string(), vec![-384; 4]),
+        ]
+    );
+}
+
+#[test]
+fn from_npz_rejects_unknown_arrays() {
+    let layers = layers();
+    let array = NpyArray::from_vec(vec![0f32; 4]);
+    for name in ["scales0", "biases2", "biasesX"] {
+        let path = temp_path(&format!("{}.npz", name));
+        npy::save_npz(&path, &[(name, &array)]).unwrap();
+        let result = Quantizer::from_npz(&layers, &path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err(), "{} was accepted", name);
+    }
+}
@@ -0,0 +1,258 @@
+//! シミュレーションのバックエンドを使った前処理から後処理までの回帰テスト
+//!
+//! `SimBackend` を使う `YoloV3Tiny` で `tests/data/regression.png` をレターボックスで前処理し、
+//! `tests/data/regression.json` に書いた物体をYOLO層の出力として後処理します。後処理や並べ替えを変更して検出結果が変わった場合に失敗します。
+//! 期待値を更新する場合は、変更が意図したものであることを確認してからJSONを書き換えてください。
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use serde::Deserialize;
+
+use yolo_v3_tiny_zynq::detection_result::{DetectionData, LetterboxSpace};
+use yolo_v3_tiny_zynq::nms;
+use yolo_v3_tiny_zynq::postprocess::{self, OutputLayout};
+use yolo_v3_tiny_zynq::preprocess::Letterbox;
+use yolo_v3_tiny_zynq::session::{self, ReplayMode};
+use yolo_v3_tiny_zynq::sim::SimBackend;
+use yolo_v3_tiny_zynq::tta::Augmentation;
+use yolo_v3_tiny_zynq::yolov3_tiny::YoloV3Tiny;
+
+/// 座標の許容誤差 (ピクセル)
+const BBOX_TOLERANCE: f32 = 0.5;
+/// コンフィデンスの許容誤差
+const CONFIDENCE_TOLERANCE: f32 = 0.01;
+
+/// 物体または検出結果
+#[derive(Debug, Deserialize)]
+struct Object {
+    class: u8,
+    bbox: [f32; 4],
+    confidence: f32,
+}
+
+/// 回転角度ごとのテストケース
+#[derive(Debug, Deserialize)]
+struct Case {
+    rotate_angle: u32,
+    /// YOLO層が出力する物体 (YOLOの入力データの座標系)
+    objects: Vec<Object>,
+    /// 期待する検出結果 (回転後の画像の座標系)
+    expected: Vec<Object>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Fixture {
+    cls_num: usize,
+    obj_threshold: f32,
+    nms_threshold: f32,
+    cases: Vec<Case>,
+}
+
+fn data_path(name: &str) -> std::path::PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("data")
+        .join(name)
+}
+
+fn load_fixture() -> Fixture {
+    serde_json::from_str(&std::fs::read_to_string(data_path("regression.json")).unwrap()).unwrap()
+}
+
+/// 出力する物体をテストケースの物体に置き換えます。
+fn set_objects(sim: &mut SimBackend, case: &Case) {
+    sim.clear_objects();
+    for o in &case.objects {
+        let [x1, y1, x2, y2] = o.bbox;
+        sim.add_object(DetectionData::<LetterboxSpace>::new(
+            o.class,
+            x1,
+            y1,
+            x2,
+            y2,
+            o.confidence,
+        ))
+        .unwrap();
+    }
+}
+
+/// テストケースの物体を登録した `SimBackend` を使う `YoloV3Tiny` を作ります。
+fn sim_yolo(fixture: &Fixture, case: &Case, layout: OutputLayout) -> YoloV3Tiny {
+    let mut sim = SimBackend::new(
+        fixture.cls_num,
+        fixture.obj_threshold,
+        fixture.nms_threshold,
+    );
+    sim.set_layout(layout);
+    set_objects(&mut sim, case);
+    YoloV3Tiny::with_sim(sim)
+}
+
+/// 全てのテストケースを実行し、期待する検出結果と比べます。
+///
+/// # Args
+/// * `fixed_point_postprocess` - 後処理を固定小数点数のまま行うか
+/// * `software_sigmoid` - シグモイドを後処理で適用するか (YOLO層の出力はシグモイドの適用前の値になります)
+fn check_fixture(fixed_point_postprocess: bool, software_sigmoid: bool) {
+    let img = image::open(data_path("regression.png")).unwrap();
+    let fixture = load_fixture();
+
+    for case in &fixture.cases {
+        let layout = OutputLayout {
+            software_sigmoid,
+            ..OutputLayout::default()
+        };
+        let mut yolo = sim_yolo(&fixture, case, layout);
+        yolo.set_fixed_point_postprocess(fixed_point_postprocess);
+
+        let result = yolo
+            .start_with_preprocessor(&img, &Letterbox::new(case.rotate_angle))
+            .unwrap();
+        assert_eq!(
+            result.len(),
+            case.expected.len(),
+            "rotate {}: {:?}",
+            case.rotate_angle,
+            result
+        );
+        for (d, e) in result.iter().zip(&case.expected) {
+            assert_eq!(d.class, e.class, "rotate {}: {:?}", case.rotate_angle, d);
+            for (actual, expected) in [d.x1, d.y1, d.x2, d.y2].into_iter().zip(e.bbox) {
+                assert!(
+                    (actual - expected).abs() <= BBOX_TOLERANCE,
+                    "rotate {}: {:?} differs from {:?}",
+                    case.rotate_angle,
+                    d,
+                    e
+                );
+            }
+            assert!(
+                (d.confidence - e.confidence).abs() <= CONFIDENCE_TOLERANCE,
+                "rotate {}: {:?} differs from {:?}",
+                case.rotate_angle,
+                d,
+                e
+            );
+        }
+    }
+}
+
+#[test]
+fn letterbox_sim_postprocess_matches_expected() {
+    check_fixture(false, false);
+}
+
+#[test]
+fn fixed_point_postprocess_matches_expected() {
+    check_fixture(true, false);
+}
+
+#[test]
+fn software_sigmoid_matches_expected() {
+    check_fixture(false, true);
+    check_fixture(true, true);
+}
+
+/// 後処理の統計が、閾値を超えた候補とNMSで抑制された数を数えることを確認します。
+#[test]
+fn postprocess_stats_count_candidates() {
+    let fixture = load_fixture();
+    for case in &fixture.cases {
+        let mut yolo = sim_yolo(&fixture, case, OutputLayout::default());
+        let (out13, out26) = yolo.start_processing(&vec![0; 416 * 416 * 4]).unwrap();
+        let candidates = case
+            .objects
+            .iter()
+            .filter(|o| o.confidence > fixture.obj_threshold)
+            .count();
+        let post_process_with_stats = [
+            postprocess::post_process_with_stats,
+            postprocess::post_process_fixed_with_stats,
+        ];
+        for post_process in post_process_with_stats {
+            let (result, stats) = post_process(
+                &out13,
+                &out26,
+                fixture.cls_num,
+                &OutputLayout::default(),
+                fixture.obj_threshold,
+                fixture.nms_threshold,
+            );
+            assert_eq!(result.len(), case.expected.len());
+            assert_eq!(stats.candidates, candidates);
+            assert_eq!(stats.suppressed, vec![1, 0, 0]);
+            assert_eq!(stats.suppressed_total(), candidates - result.len());
+        }
+    }
+}
+
+/// 0度と90度のテストケースは同じ物体を写しているため、90度の結果を元の画像の座標系に戻して
+/// 集合をまたいだNMSで統合すると、0度の検出結果と同じになることを確認します。
+#[test]
+fn rotated_pair_merges_to_upright_result() {
+    let img = image::open(data_path("regression.png")).unwrap();
+    let fixture = load_fixture();
+    let detect = |angle: u32| {
+        let case = fixture
+            .cases
+            .iter()
+            .find(|c| c.rotate_angle == angle)
+            .unwrap();
+        sim_yolo(&fixture, case, OutputLayout::default())
+            .start_with_preprocessor(&img, &Letterbox::new(angle))
+            .unwrap()
+    };
+    let upright = detect(0);
+    let rotated: Vec<DetectionData> = detect(90)
+        .iter()
+        .map(|d| Augmentation::Rotate90.invert(d, img.width(), img.height()))
+        .collect();
+
+    let merged = nms::nms_cross_sets(&[&upright, &rotated], fixture.nms_threshold);
+    assert_eq!(merged.len(), upright.len(), "{:?}", merged);
+    for d in &upright {
+        assert!(
+            merged.iter().any(|m| m.class == d.class && m.iou(d) > 0.99),
+            "{:?} is not in {:?}",
+            d,
+            merged
+        );
+    }
+}
+
+#[test]
+fn session_replay_reproduces_recorded_detections() {
+    let img = image::open(data_path("regression.png")).unwrap();
+    let fixture = load_fixture();
+    let path = std::env::temp_dir().join(format!("regression_{}.session", std::process::id()));
+
+    let mut yolo = sim_yolo(&fixture, &fixture.cases[0], OutputLayout::default());
+    let final_frames = Rc::new(RefCell::new(vec![]));
+    let hook_frames = Rc::clone(&final_frames);
+    yolo.on_final_detections(move |frame_id, _| hook_frames.borrow_mut().push(frame_id));
+    yolo.set_session_record(Some(&path)).unwrap();
+    let mut frames = 0;
+    for fixed_point_postprocess in [false, true] {
+        yolo.set_fixed_point_postprocess(fixed_point_postprocess);
+        for case in &fixture.cases {
+            set_objects(yolo.sim_mut().unwrap(), case);
+            let result = yolo
+                .start_with_preprocessor(&img, &Letterbox::new(case.rotate_angle))
+                .unwrap();
+            assert_eq!(result.len(), case.expected.len());
+            frames += 1;
+        }
+    }
+    // 記録を終えてファイルを閉じる
+    yolo.set_session_record(None::<&Path>).unwrap();
+    assert_eq!(*final_frames.borrow(), (1..=frames).collect::<Vec<u64>>());
+
+    for mode in [ReplayMode::Postprocess, ReplayMode::Sim] {
+        let report = session::replay_session(&path, mode).unwrap();
+        assert_eq!(report.frames as u64, frames, "{:?}", mode);
+        assert!(report.is_reproduced(), "{:?}: {}", mode, report);
+    }
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,203 @@
+//! レジスタとDMAのトレースの記録と再生のテスト
+//!
+//! トレースの各行が文字列との変換で元に戻ることと、`MockBackend` での再生が手順の誤りや
+//! 記録との違いを検出することを確認します。
+
+mod common;
+
+use yolo_v3_tiny_zynq::trace::{
+    self, MockBackend, ReplayMismatch, TraceEvent, TraceRecorder, IP_REGISTERS,
+};
+
+use common::temp_path;
+
+fn write(ip: &str, reg: &str, value: u32) -> TraceEvent {
+    TraceEvent::Write {
+        ip: ip.to_string(),
+        reg: reg.to_string(),
+        value,
+    }
+}
+
+fn read(ip: &str, reg: &str, value: u32) -> TraceEvent {
+    TraceEvent::Read {
+        ip: ip.to_string(),
+        reg: reg.to_string(),
+        value,
+    }
+}
+
+fn start(ip: &str) -> TraceEvent {
+    TraceEvent::Start { ip: ip.to_string() }
+}
+
+fn wait(target: &str, ok: bool) -> TraceEvent {
+    TraceEvent::Wait {
+        target: target.to_string(),
+        ok,
+    }
+}
+
+/// 畳み込みIPの1回のデータ転送のトレースを返します。
+fn conv_step() -> Vec<TraceEvent> {
+    let mut events = vec![TraceEvent::Step {
+        grp_idx: 0,
+        off: 0,
+        iff: 0,
+        is_last: true,
+    }];
+    let (ip, regs) = IP_REGISTERS[0];
+    events.extend(
+        regs.iter()
+            .enumerate()
+            .map(|(i, reg)| write(ip, reg, i as u32 + 1)),
+    );
+    events.extend([
+        start(ip),
+        TraceEvent::Switch {
+            ports: [(0, 0), (0, 1), (1, 0)],
+        },
+        TraceEvent::DmaWrite {
+            dma: "dma0".to_string(),
+            len: 432,
+            checksum: 0x3c9a51e2,
+        },
+        TraceEvent::DmaRead {
+            dma: "dma0".to_string(),
+            len: 64,
+            checksum: trace::checksum(&[0; 64]),
+        },
+        wait(ip, true),
+        read(ip, regs[0], 1),
+    ]);
+    events
+}
+
+#[test]
+fn display_and_parse_roundtrip() {
+    let mut events = conv_step();
+    events.extend([
+        TraceEvent::Step {
+            grp_idx: 13,
+            off: 7,
+            iff: 11,
+            is_last: false,
+        },
+        write("yolo_yolo", "ACTIVATE_EN", u32::MAX),
+        wait("yolo_max_pool", false),
+        TraceEvent::Reset,
+    ]);
+    for event in events {
+        let line = event.to_string();
+        let parsed: TraceEvent = line.parse().unwrap();
+        assert_eq!(parsed, event, "{}", line);
+    }
+    assert_eq!(
+        read("yolo_conv", "OUTPUT_CH", 16).to_string(),
+        "read yolo_conv OUTPUT_CH 16"
+    );
+}
+
+#[test]
+fn parse_rejects_malformed_lines() {
+    for line in [
+        "",
+        "jump yolo_conv",
+        "write yolo_conv OUTPUT_CH",
+        "read yolo_conv OUTPUT_CH x",
+        "step 0 0 0 first",
+        "switch 0:0 0:0",
+        "switch 0:0 0-0 0:0",
+        "dma_read dma0 16 zz",
+        "wait yolo_conv maybe",
+        "start yolo_conv yolo_acc",
+        "reset now",
+    ] {
+        assert!(
+            line.parse::<TraceEvent>().is_err(),
+            "\"{}\" was accepted",
+            line
+        );
+    }
+}
+
+#[test]
+fn recorded_trace_replays_on_mock() {
+    let events = conv_step();
+    let path = temp_path("conv.trace");
+    {
+        let mut recorder = TraceRecorder::create(&path).unwrap();
+        for event in &events {
+            recorder.record(event);
+        }
+        assert_eq!(recorder.events(), events.len() as u64);
+    }
+    let loaded = trace::read_trace(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded, events);
+
+    let mut mock = MockBackend::new();
+    let report = trace::replay(&loaded, &mut mock).unwrap();
+    assert!(report.is_reproduced(), "{:?}", report.mismatches);
+    assert_eq!(report.events, events.len());
+    assert_eq!(mock.register("yolo_conv", "INPUT_CH"), Some(2));
+    assert_eq!(mock.start_count("yolo_conv"), 1);
+    assert_eq!(mock.switch_ports(), Some([(0, 0), (0, 1), (1, 0)]));
+    assert_eq!(mock.written_len("dma0"), 432);
+    assert_eq!(mock.read_len("dma0"), 64);
+
+    trace::replay(&[TraceEvent::Reset], &mut mock).unwrap();
+    assert_eq!(mock.resets(), 1);
+    assert_eq!(mock.switch_ports(), None);
+}
+
+#[test]
+fn replay_reports_mismatches() {
+    let mut events = conv_step();
+    // 記録と異なるレジスタの値と、スタートしていないIPの完了待ち
+    let read_index = events.len() - 1;
+    events[read_index] = read("yolo_conv", "OUTPUT_CH", 5);
+    events.push(wait("yolo_acc", true));
+
+    let report = trace::replay(&events, &mut MockBackend::new()).unwrap();
+    assert_eq!(
+        report.mismatches,
+        vec![
+            ReplayMismatch {
+                index: read_index,
+                expected: read("yolo_conv", "OUTPUT_CH", 5),
+                actual: read("yolo_conv", "OUTPUT_CH", 1),
+            },
+            ReplayMismatch {
+                index: events.len() - 1,
+                expected: wait("yolo_acc", true),
+                actual: wait("yolo_acc", false),
+            },
+        ]
+    );
+}
+
+#[test]
+fn replay_rejects_invalid_procedures() {
+    let cases = [
+        // 設定していないレジスタがある状態でスタート
+        vec![write("yolo_conv", "OUTPUT_CH", 16), start("yolo_conv")],
+        // 存在しないレジスタ
+        vec![write("yolo_conv", "LEAKY", 1)],
+        vec![read("yolo_acc", "OUTPUT_CH", 0)],
+        // 存在しないIPとDMA
+        vec![start("yolo_route")],
+        vec![TraceEvent::DmaWrite {
+            dma: "dma2".to_string(),
+            len: 1,
+            checksum: 0,
+        }],
+    ];
+    for events in cases {
+        assert!(
+            trace::replay(&events, &mut MockBackend::new()).is_err(),
+            "{:?} was accepted",
+            events
+        );
+    }
+}